@@ -0,0 +1,13 @@
+fn main() {
+    // Regenerates the C header for the `ffi` feature; nothing to build otherwise.
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).expect("cbindgen.toml is invalid");
+        cbindgen::generate_with_config(&dir, config)
+            .expect("failed to generate the C header")
+            .write_to_file(format!("{}/include/rsheet.h", dir));
+    }
+}
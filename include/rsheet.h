@@ -0,0 +1,85 @@
+/* Generated by cbindgen from src/ffi.rs when built with the ffi feature; do not edit. */
+
+#ifndef RSHEET_H
+#define RSHEET_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * What an `rsheet_*` call returns.
+ */
+typedef enum RSheetStatus {
+  RSheetStatus_Ok = 0,
+  /**
+   * A null pointer, or a string that is not UTF-8.
+   */
+  RSheetStatus_InvalidArgument = 1,
+  /**
+   * The sheet refused the call; `out` holds why.
+   */
+  RSheetStatus_Error = 2,
+} RSheetStatus;
+
+typedef struct RSheet RSheet;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * A new empty sheet, to be released with `rsheet_free`.
+ */
+RSheet *rsheet_new(void);
+
+/**
+ * Releases a sheet from `rsheet_new`. Null is ignored.
+ *
+ * # Safety
+ * `sheet` must come from `rsheet_new` and not be used again.
+ */
+void rsheet_free(RSheet *sheet);
+
+/**
+ * Sets `cell` to `expr`, as the `set` command does. On `Error`, `out` (if
+ * not null) receives the message.
+ *
+ * # Safety
+ * `sheet` must be live, `cell` and `expr` null-terminated, and `out` null
+ * or writable.
+ */
+RSheetStatus rsheet_set(const RSheet *sheet, const char *cell, const char *expr, char **out);
+
+/**
+ * Writes the value of `cell` to `out` as the text protocol shows it, or
+ * null if the cell is empty.
+ *
+ * # Safety
+ * `sheet` must be live, `cell` null-terminated, and `out` writable.
+ */
+RSheetStatus rsheet_get(const RSheet *sheet, const char *cell, char **out);
+
+/**
+ * Evaluates `expr` against the sheet without storing it, writing the
+ * result to `out`.
+ *
+ * # Safety
+ * `sheet` must be live, `expr` null-terminated, and `out` writable.
+ */
+RSheetStatus rsheet_eval(const RSheet *sheet, const char *expr, char **out);
+
+/**
+ * Releases a string an `rsheet_*` call wrote to `out`. Null is ignored.
+ *
+ * # Safety
+ * `text` must come from this library and not be used again.
+ */
+void rsheet_string_free(char *text);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RSHEET_H */
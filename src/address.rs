@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// A single cell reference such as `B7`. Columns and rows are zero-based internally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CellAddress {
+    pub col: u32,
+    pub row: u32,
+}
+
+impl CellAddress {
+    pub fn new(col: u32, row: u32) -> Self {
+        CellAddress { col, row }
+    }
+}
+
+/// Converts a column index to its letter form: 0 -> A, 25 -> Z, 26 -> AA.
+pub fn column_name(mut col: u32) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'A' + (col % 26) as u8);
+        if col < 26 {
+            break;
+        }
+        col = col / 26 - 1;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap()
+}
+
+/// Converts column letters to an index: A -> 0, AA -> 26.
+pub fn column_index(letters: &str) -> Option<u32> {
+    if letters.is_empty() {
+        return None;
+    }
+    let mut col: u32 = 0;
+    for c in letters.chars() {
+        if !c.is_ascii_alphabetic() {
+            return None;
+        }
+        let digit = c.to_ascii_uppercase() as u32 - 'A' as u32 + 1;
+        col = col.checked_mul(26)?.checked_add(digit)?;
+    }
+    Some(col - 1)
+}
+
+impl fmt::Display for CellAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", column_name(self.col), self.row + 1)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AddressError(pub String);
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid cell reference: {}", self.0)
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+impl FromStr for CellAddress {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.find(|c: char| c.is_ascii_digit()).unwrap_or(s.len());
+        let (letters, digits) = s.split_at(split);
+        let col = column_index(letters).ok_or_else(|| AddressError(s.to_string()))?;
+        let row: u32 = digits.parse().map_err(|_| AddressError(s.to_string()))?;
+        if row == 0 || row > MAX_ROWS || col >= MAX_COLS {
+            return Err(AddressError(s.to_string()));
+        }
+        Ok(CellAddress { col, row: row - 1 })
+    }
+}
+
+/// A parsed storage key: the sheet, `None` for the default one, and the address.
+/// Displays as the storage key (`A1`, `Budget!A1`).
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CellKey {
+    pub sheet: Option<String>,
+    pub addr: CellAddress,
+}
+
+impl fmt::Display for CellKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.sheet {
+            Some(sheet) => write!(f, "{}!{}", sheet, self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+impl FromStr for CellKey {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sheet, reference) = split_sheet(s);
+        Ok(CellKey { sheet: sheet.map(str::to_string), addr: reference.parse()? })
+    }
+}
+
+/// Parses a storage key into its sheet and address, like [`CellKey`] but
+/// borrowing the sheet name instead of allocating it.
+pub fn parse_key(key: &str) -> Result<(Option<&str>, CellAddress), AddressError> {
+    let (sheet, reference) = split_sheet(key);
+    Ok((sheet, reference.parse()?))
+}
+
+/// Rows covered by a whole-column range, as in Excel.
+pub const MAX_ROWS: u32 = 1_048_576;
+
+/// Columns covered by a whole-row range, as in Excel.
+pub const MAX_COLS: u32 = 16_384;
+
+/// An inclusive rectangle of cells, written `A1:B10`. A single address is a 1x1 range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CellRange {
+    pub start: CellAddress,
+    pub end: CellAddress,
+}
+
+impl CellRange {
+    pub fn new(a: CellAddress, b: CellAddress) -> Self {
+        CellRange {
+            start: CellAddress::new(a.col.min(b.col), a.row.min(b.row)),
+            end: CellAddress::new(a.col.max(b.col), a.row.max(b.row)),
+        }
+    }
+
+    pub fn contains(&self, addr: &CellAddress) -> bool {
+        (self.start.col..=self.end.col).contains(&addr.col)
+            && (self.start.row..=self.end.row).contains(&addr.row)
+    }
+
+    /// Parses `A1:B10` or a whole-column range such as `A:A` or `B:D`,
+    /// which spans rows 1 to [`MAX_ROWS`].
+    pub fn parse_with_columns(s: &str) -> Result<Self, AddressError> {
+        let columns = s.split_once(':').and_then(|(a, b)| Some((column_index(a)?, column_index(b)?)));
+        match columns {
+            Some((a, b)) => Ok(CellRange::new(CellAddress::new(a, 0), CellAddress::new(b, MAX_ROWS - 1))),
+            None => s.parse(),
+        }
+    }
+
+    /// Addresses in row-major order.
+    pub fn iter(&self) -> impl Iterator<Item = CellAddress> {
+        let (start, end) = (self.start, self.end);
+        (start.row..=end.row).flat_map(move |row| (start.col..=end.col).map(move |col| CellAddress::new(col, row)))
+    }
+}
+
+impl fmt::Display for CellRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}:{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for CellRange {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((a, b)) => Ok(CellRange::new(a.parse()?, b.parse()?)),
+            None => {
+                let addr: CellAddress = s.parse()?;
+                Ok(CellRange::new(addr, addr))
+            }
+        }
+    }
+}
+
+/// Sheet that unqualified references belong to. Its cells are stored under bare addresses.
+pub const DEFAULT_SHEET: &str = "Sheet1";
+
+/// Splits `Budget!A1` into `(Some("Budget"), "A1")`. The default sheet yields `None`.
+pub fn split_sheet(reference: &str) -> (Option<&str>, &str) {
+    match reference.split_once('!') {
+        Some((sheet, rest)) if sheet != DEFAULT_SHEET => (Some(sheet), rest),
+        Some((_, rest)) => (None, rest),
+        None => (None, reference),
+    }
+}
+
+/// Storage key for `reference` as seen from `sheet`. Already qualified references are kept.
+pub fn qualify(sheet: Option<&str>, reference: &str) -> String {
+    match (split_sheet(reference), sheet) {
+        ((Some(sheet), rest), _) => format!("{}!{}", sheet, rest),
+        ((None, rest), _) if reference.contains('!') => rest.to_string(),
+        (_, Some(sheet)) if sheet != DEFAULT_SHEET => format!("{}!{}", sheet, reference),
+        _ => reference.to_string(),
+    }
+}
+
+/// `reference` in canonical form, so `b7` and `B07` are both `B7`. A sheet
+/// name is kept as written, `Sheet1!` included; only the address changes.
+pub fn normalize(reference: &str) -> Result<String, AddressError> {
+    match reference.split_once('!') {
+        Some((sheet, addr)) if is_valid_sheet_name(sheet) => Ok(format!("{}!{}", sheet, addr.parse::<CellAddress>()?)),
+        Some(_) => Err(AddressError(reference.to_string())),
+        None => Ok(reference.parse::<CellAddress>()?.to_string()),
+    }
+}
+
+pub fn is_valid_sheet_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,177 @@
+use crate::connect::SharedWriter;
+use crate::numeric;
+use crate::{CellValue, Message};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// How an alert rule compares its cell with the threshold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    Above,
+    AtLeast,
+    Below,
+    AtMost,
+}
+
+impl Comparison {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        let Some(ordering) = numeric::compare(value, threshold) else {
+            return false;
+        };
+        match self {
+            Comparison::Above => ordering.is_gt(),
+            Comparison::AtLeast => ordering.is_ge(),
+            Comparison::Below => ordering.is_lt(),
+            Comparison::AtMost => ordering.is_le(),
+        }
+    }
+
+    /// Whether a firing rule has cleared: the value has moved back past the
+    /// threshold by more than `hysteresis`.
+    fn clears(self, value: f64, threshold: f64, hysteresis: f64) -> bool {
+        let threshold = match self {
+            Comparison::Above | Comparison::AtLeast => threshold - hysteresis,
+            Comparison::Below | Comparison::AtMost => threshold + hysteresis,
+        };
+        !self.holds(value, threshold)
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            ">" => Ok(Comparison::Above),
+            ">=" => Ok(Comparison::AtLeast),
+            "<" => Ok(Comparison::Below),
+            "<=" => Ok(Comparison::AtMost),
+            _ => Err(format!("Unknown comparison: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Comparison::Above => ">",
+            Comparison::AtLeast => ">=",
+            Comparison::Below => "<",
+            Comparison::AtMost => "<=",
+        })
+    }
+}
+
+/// `alert when <cell> <comparison> <threshold>`. Fires when the condition
+/// turns true, then stays quiet until the cell has moved back past the
+/// threshold by more than `hysteresis`, so a value hovering at the
+/// threshold does not flap.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub id: u64,
+    pub cell: String,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    pub hysteresis: f64,
+}
+
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.cell, self.comparison, self.threshold)?;
+        if self.hysteresis > 0.0 {
+            write!(f, " hysteresis {}", self.hysteresis)?;
+        }
+        Ok(())
+    }
+}
+
+/// Pushed to the connection that set the rule when it fires.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Alert {
+    pub rule: AlertRule,
+    /// The cell's value that set the rule off.
+    pub value: f64,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} value {}", self.rule.id, self.rule, self.value)
+    }
+}
+
+struct Armed {
+    rule: AlertRule,
+    connection: u64,
+    /// `None` for in-process sessions, whose alerts only reach webhooks.
+    writer: Option<SharedWriter>,
+    firing: bool,
+}
+
+/// Alert rules of every connection.
+#[derive(Default)]
+pub struct Alerts {
+    rules: Mutex<Vec<Armed>>,
+    next_id: AtomicU64,
+}
+
+impl Alerts {
+    /// Arms `rule` under a new id, which the returned copy carries. A rule
+    /// whose condition already holds for `current` counts as firing, so it
+    /// alerts only once the condition clears and holds again.
+    pub fn add(&self, connection: u64, writer: Option<SharedWriter>, mut rule: AlertRule, current: Option<&CellValue>) -> AlertRule {
+        rule.id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let firing = matches!(current, Some(CellValue::Number(n)) if rule.comparison.holds(*n, rule.threshold));
+        self.rules.lock().unwrap().push(Armed { rule: rule.clone(), connection, writer, firing });
+        rule
+    }
+
+    /// False if the connection has no rule with that id.
+    pub fn remove(&self, connection: u64, id: u64) -> bool {
+        let mut rules = self.rules.lock().unwrap();
+        let before = rules.len();
+        rules.retain(|armed| !(armed.connection == connection && armed.rule.id == id));
+        rules.len() != before
+    }
+
+    pub fn list(&self, connection: u64) -> Vec<AlertRule> {
+        self.rules.lock().unwrap().iter().filter(|armed| armed.connection == connection).map(|armed| armed.rule.clone()).collect()
+    }
+
+    pub fn remove_connection(&self, connection: u64) {
+        self.rules.lock().unwrap().retain(|armed| armed.connection != connection);
+    }
+
+    /// Checks the rules on `cell` against its new value, pushing an alert
+    /// for each one that fires. Returns the alerts that fired.
+    pub fn check(&self, cell: &str, value: &CellValue) -> Vec<Alert> {
+        let mut fired = Vec::new();
+        let mut rules = self.rules.lock().unwrap();
+        for armed in rules.iter_mut().filter(|armed| armed.rule.cell == cell) {
+            let AlertRule { comparison, threshold, hysteresis, .. } = armed.rule;
+            let CellValue::Number(n) = *value else {
+                // A cell that is no longer a number cannot meet the condition.
+                armed.firing = false;
+                continue;
+            };
+            if armed.firing {
+                armed.firing = !comparison.clears(n, threshold, hysteresis);
+                continue;
+            }
+            if !comparison.holds(n, threshold) {
+                continue;
+            }
+            armed.firing = true;
+            let alert = Alert { rule: armed.rule.clone(), value: n };
+            if let Some(writer) = &armed.writer {
+                if let Err(e) = writer.lock().unwrap().send(&Message::Alert(alert.clone())) {
+                    tracing::warn!(cell, error = %e, "failed to push alert");
+                }
+            }
+            fired.push(alert);
+        }
+        fired
+    }
+}
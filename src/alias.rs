@@ -0,0 +1,58 @@
+use crate::address::CellAddress;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Names for cells, from `alias <name> <cell>`, which formulas and commands
+/// may use wherever they take a cell. Each name maps to a storage key such as
+/// `B10` or `Budget!B10`, and follows the cell when rows move under it.
+#[derive(Debug, Default)]
+pub struct Aliases {
+    names: Mutex<BTreeMap<String, String>>,
+}
+
+impl Aliases {
+    /// The cell `name` stands for, if it is an alias.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.names.lock().unwrap().get(name).cloned()
+    }
+
+    /// Points `name` at `cell`, returning the cell it stood for before.
+    pub fn define(&self, name: &str, cell: String) -> Option<String> {
+        self.names.lock().unwrap().insert(name.to_string(), cell)
+    }
+
+    pub fn remove(&self, name: &str) -> Option<String> {
+        self.names.lock().unwrap().remove(name)
+    }
+
+    pub fn all(&self) -> BTreeMap<String, String> {
+        self.names.lock().unwrap().clone()
+    }
+
+    pub fn replace(&self, names: BTreeMap<String, String>) {
+        *self.names.lock().unwrap() = names;
+    }
+
+    /// Re-points every alias whose cell `moved` gives a new place for,
+    /// returning how many moved.
+    pub fn repoint(&self, moved: impl Fn(&str) -> Option<String>) -> usize {
+        let mut names = self.names.lock().unwrap();
+        let mut count = 0;
+        for cell in names.values_mut() {
+            if let Some(to) = moved(cell) {
+                *cell = to;
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+/// Whether `name` may be an alias: a letter or `_`, then letters, digits or
+/// `_`, and not itself a cell address such as `AB12`.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.parse::<CellAddress>().is_err()
+}
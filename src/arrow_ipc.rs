@@ -0,0 +1,53 @@
+use crate::address::{column_name, CellAddress, CellRange};
+use crate::replies::value_text;
+use crate::store::Snapshot;
+use crate::CellValue;
+use arrow::array::{ArrayRef, Float64Array, StringArray};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::error::ArrowError;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// `range` of `cells` as one record batch with a column per sheet column,
+/// named by its letters, and a row per sheet row. The range itself is kept in
+/// the schema metadata under `range`.
+///
+/// A column holding only numbers is nullable `Float64`; any other column is
+/// nullable `Utf8`, with numbers in their text form. Empty cells and error
+/// values are null.
+pub fn record_batch(range: CellRange, cells: &Snapshot) -> Result<RecordBatch, ArrowError> {
+    let rows = range.start.row..=range.end.row;
+    let mut fields = Vec::new();
+    let mut columns: Vec<ArrayRef> = Vec::new();
+    for col in range.start.col..=range.end.col {
+        let values: Vec<Option<&CellValue>> =
+            rows.clone().map(|row| cells.get(CellAddress::new(col, row)).map(|value| &**value)).collect();
+        let numeric = values.iter().flatten().all(|value| matches!(value, CellValue::Number(_) | CellValue::Error(_)));
+        let (data_type, array): (DataType, ArrayRef) = if numeric {
+            let numbers = values.iter().map(|value| match value {
+                Some(CellValue::Number(n)) => Some(*n),
+                _ => None,
+            });
+            (DataType::Float64, Arc::new(numbers.collect::<Float64Array>()))
+        } else {
+            let texts = values.iter().map(|value| match value {
+                Some(CellValue::Error(_)) | None => None,
+                Some(value) => Some(value_text(value)),
+            });
+            (DataType::Utf8, Arc::new(texts.collect::<StringArray>()))
+        };
+        fields.push(Field::new(column_name(col), data_type, true));
+        columns.push(array);
+    }
+    let metadata = HashMap::from([("range".to_string(), range.to_string())]);
+    RecordBatch::try_new(Arc::new(Schema::new_with_metadata(fields, metadata)), columns)
+}
+
+/// Encodes `batch` in the Arrow IPC streaming format.
+pub fn ipc_stream(batch: &RecordBatch) -> Result<Vec<u8>, ArrowError> {
+    let mut writer = StreamWriter::try_new(Vec::new(), &batch.schema())?;
+    writer.write(batch)?;
+    writer.into_inner()
+}
@@ -0,0 +1,86 @@
+use crate::CellValue;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Entries kept in memory for the `audit` command when no size is given.
+pub const DEFAULT_AUDIT_CAPACITY: usize = 1000;
+
+/// One mutating command, as it was applied.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub connection: u64,
+    /// Client address; `None` for in-process callers.
+    pub peer: Option<String>,
+    pub command: String,
+    pub cell: Option<String>,
+    pub old: Option<CellValue>,
+    pub new: Option<CellValue>,
+}
+
+impl AuditEntry {
+    pub fn new(connection: u64, peer: Option<String>, command: impl Into<String>) -> Self {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        AuditEntry { timestamp_ms, connection, peer, command: command.into(), cell: None, old: None, new: None }
+    }
+
+    pub fn with_change(mut self, cell: &str, old: Option<CellValue>, new: Option<CellValue>) -> Self {
+        self.cell = Some(cell.to_string());
+        self.old = old;
+        self.new = new;
+        self
+    }
+}
+
+/// Bounded in-memory history of mutating commands, optionally mirrored to an
+/// append-only file with one JSON entry per line.
+pub struct AuditLog {
+    recent: Mutex<VecDeque<AuditEntry>>,
+    capacity: usize,
+    file: Option<Mutex<File>>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        AuditLog::new(DEFAULT_AUDIT_CAPACITY)
+    }
+}
+
+impl AuditLog {
+    pub fn new(capacity: usize) -> Self {
+        AuditLog { recent: Mutex::new(VecDeque::new()), capacity, file: None }
+    }
+
+    /// Also appends every entry to `path`, creating it if needed.
+    pub fn with_file(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.file = Some(Mutex::new(file));
+        Ok(self)
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        if let Some(file) = &self.file {
+            let line = serde_json::to_string(&entry).expect("audit entries always serialize");
+            if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                tracing::error!(error = %e, "failed to write audit log");
+            }
+        }
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back(entry);
+        while recent.len() > self.capacity {
+            recent.pop_front();
+        }
+    }
+
+    /// The last `n` entries, oldest first.
+    pub fn recent(&self, n: usize) -> Vec<AuditEntry> {
+        let recent = self.recent.lock().unwrap();
+        recent.iter().skip(recent.len().saturating_sub(n)).cloned().collect()
+    }
+}
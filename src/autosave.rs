@@ -0,0 +1,84 @@
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    crate::RSheet,
+    std::path::{Path, PathBuf},
+    std::sync::{Arc, Condvar, Mutex},
+    std::thread::JoinHandle,
+    std::time::Instant,
+};
+
+/// How long edits must pause before autosave writes, unless `interval` runs out first.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Background thread that saves the workbook while it has unsaved changes.
+///
+/// A save happens once edits have paused for `debounce`, or once the workbook
+/// has been dirty for `interval`, whichever comes first, so a steady stream of
+/// edits is still saved without writing after every one. Not on wasm32,
+/// which has no threads.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Autosave {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: JoinHandle<()>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Autosave {
+    pub fn start(rsheet: Arc<RSheet>, path: impl Into<PathBuf>, interval: Duration, debounce: Duration) -> Self {
+        let path = path.into();
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = Arc::clone(&stop);
+        let mut saved = rsheet.generation();
+        let thread = std::thread::spawn(move || {
+            let tick = interval.min(debounce).max(Duration::from_millis(1));
+            let mut seen = saved;
+            let mut last_change = Instant::now();
+            let mut dirty_since = None;
+            loop {
+                let (stopped, wake) = &*thread_stop;
+                let stopped = wake.wait_timeout(stopped.lock().unwrap(), tick).unwrap().0;
+                if *stopped {
+                    break;
+                }
+                drop(stopped);
+
+                let now = Instant::now();
+                let generation = rsheet.generation();
+                if generation != seen {
+                    seen = generation;
+                    last_change = now;
+                    dirty_since.get_or_insert(now);
+                }
+                let due = dirty_since.is_some_and(|since: Instant| {
+                    now.duration_since(last_change) >= debounce || now.duration_since(since) >= interval
+                });
+                if due {
+                    save(&rsheet, &path);
+                    saved = generation;
+                    dirty_since = None;
+                }
+            }
+            if rsheet.generation() != saved {
+                save(&rsheet, &path);
+            }
+        });
+        Autosave { stop, thread }
+    }
+
+    /// Stops the thread, saving first if anything changed since the last save.
+    pub fn stop(self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        wake.notify_all();
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn save(rsheet: &RSheet, path: &Path) {
+    match rsheet.save(path) {
+        Ok(()) => tracing::debug!(path = %path.display(), "autosaved workbook"),
+        Err(e) => tracing::error!(path = %path.display(), error = %e, "autosave failed"),
+    }
+}
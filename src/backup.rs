@@ -0,0 +1,60 @@
+use crate::workbook::Workbook;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Version written by [`Backup::new`]. Bump it when the layout changes.
+pub const BACKUP_VERSION: u32 = 1;
+
+/// A `backup` archive: the workbook plus enough metadata to tell what it
+/// holds and which server wrote it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    /// Version of the server that wrote the archive.
+    pub server_version: String,
+    /// Cells in `workbook`, checked on restore to catch truncated archives.
+    pub cells: usize,
+    pub workbook: Workbook,
+}
+
+impl Backup {
+    pub fn new(workbook: Workbook) -> Self {
+        Backup {
+            version: BACKUP_VERSION,
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            cells: workbook.cells.len(),
+            workbook,
+        }
+    }
+
+    /// Writes the archive beside `path` and renames it into place, so a crash
+    /// never leaves a half-written backup under the real name.
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let staging = path.with_extension("partial");
+        let mut file = File::create(&staging)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.flush()?;
+        file.sync_all()?;
+        std::fs::rename(&staging, path)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let backup: Backup = serde_json::from_slice(&std::fs::read(path)?)?;
+        if backup.version > BACKUP_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("backup version {} is newer than this server supports", backup.version),
+            ));
+        }
+        if backup.cells != backup.workbook.cells.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("backup lists {} cells but holds {}", backup.cells, backup.workbook.cells.len()),
+            ));
+        }
+        Ok(backup)
+    }
+}
@@ -0,0 +1,115 @@
+use clap::Parser;
+use rsheet::address::CellAddress;
+use rsheet::client::RSheetClient;
+use rsheet::replies::Reply;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+const COMMANDS: &[&str] = &[
+    "get", "set", "delete", "dump", "watch", "unwatch", "watches", "use", "session", "idem", "save", "load",
+    "backup", "restore", "import", "export", "audit", "auth", "admin", "begin", "commit",
+    "rollback", "meminfo", "slowlog", "select", "presence", "lock", "unlock", "alert", "alerts", "query", "filter",
+    "pivot", "groupby", "transpose", "dedupe", "quit",
+];
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Interactive client for an rsheet server")]
+struct Args {
+    #[arg(default_value = "127.0.0.1:8080")]
+    address: String,
+}
+
+/// Completes command names in the first word and previously used cell addresses after it.
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ReplHelper {
+    cells: Rc<RefCell<BTreeSet<String>>>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let pair = |s: &str| Pair { display: s.to_string(), replacement: s.to_string() };
+        let candidates = if start == 0 {
+            COMMANDS.iter().filter(|c| c.starts_with(word)).map(|c| pair(c)).collect()
+        } else {
+            let word = word.to_ascii_uppercase();
+            self.cells.borrow().iter().filter(|c| c.starts_with(&word)).map(|c| pair(c)).collect()
+        };
+        Ok((start, candidates))
+    }
+}
+
+fn print_reply(reply: &Reply) {
+    match reply {
+        Reply::Ok => println!("ok"),
+        Reply::Value(value) => println!("{}", value),
+        Reply::Error(e) => println!("error: {}", e),
+        Reply::Range { range, values } => {
+            println!("{}", range);
+            for row in values {
+                let row: Vec<String> = row.iter().map(|value| value.to_string()).collect();
+                println!("{}", row.join("\t"));
+            }
+        }
+        other => println!("{:?}", other),
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let mut client = RSheetClient::connect(args.address.as_str())?;
+    println!("Connected to {}. Type `quit` or Ctrl-D to exit.", args.address);
+
+    let cells = Rc::new(RefCell::new(BTreeSet::new()));
+    let mut editor: Editor<ReplHelper, _> = Editor::new()?;
+    editor.set_helper(Some(ReplHelper { cells: cells.clone() }));
+    let history = std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".rsheet_history"));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let line = match editor.readline("rsheet> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "quit" {
+            break;
+        }
+        editor.add_history_entry(line)?;
+
+        // Remember every address typed so far for completion.
+        for word in line.split_whitespace().skip(1) {
+            for part in word.split(':') {
+                if part.parse::<CellAddress>().is_ok() {
+                    cells.borrow_mut().insert(part.to_ascii_uppercase());
+                }
+            }
+        }
+
+        match client.command(line) {
+            Ok(reply) => print_reply(&reply),
+            Err(e) => {
+                println!("{}", e);
+                break;
+            }
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
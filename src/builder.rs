@@ -0,0 +1,99 @@
+use crate::diagnostics::DiagnosticsSink;
+use crate::quota::SheetQuotas;
+use crate::scheduler::Recalc;
+use crate::store::CellStore;
+use crate::wal::WriteAheadLog;
+use crate::{ImportLimits, RSheet};
+use std::error::Error;
+use std::path::PathBuf;
+
+/// How large the sheet and what is brought into it may grow.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// Estimated bytes of cells, formulas and history; see [`RSheet::with_memory_limit`].
+    pub memory: Option<u64>,
+    pub quotas: SheetQuotas,
+    pub import: ImportLimits,
+}
+
+/// Options for a new [`RSheet`], from [`RSheet::builder`]. Anything not set
+/// is as [`RSheet::new`] has it.
+#[derive(Default)]
+pub struct RSheetBuilder {
+    storage: Option<Box<dyn CellStore>>,
+    persistence_path: Option<PathBuf>,
+    wal_path: Option<PathBuf>,
+    limits: Limits,
+    recalc: Recalc,
+    rng_seed: Option<u64>,
+    diagnostics: Option<Box<dyn DiagnosticsSink>>,
+}
+
+impl RSheetBuilder {
+    /// Where cell values live; in memory by default.
+    pub fn with_storage(mut self, store: impl CellStore + 'static) -> Self {
+        self.storage = Some(Box::new(store));
+        self
+    }
+
+    /// Where the bare `save` and `load` commands read and write.
+    pub fn with_persistence_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.persistence_path = Some(path.into());
+        self
+    }
+
+    /// Replays the write-ahead log at `path`, then logs every change to it.
+    pub fn with_write_ahead_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.wal_path = Some(path.into());
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// See [`RSheet::start_recalc`].
+    pub fn with_recalc(mut self, recalc: Recalc) -> Self {
+        self.recalc = recalc;
+        self
+    }
+
+    /// See [`RSheet::with_rng_seed`].
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// See [`RSheet::with_diagnostics`].
+    pub fn with_diagnostics(mut self, sink: impl DiagnosticsSink + 'static) -> Self {
+        self.diagnostics = Some(Box::new(sink));
+        self
+    }
+
+    /// Fails only if the write-ahead log cannot be replayed or opened.
+    pub fn build(self) -> Result<RSheet, Box<dyn Error>> {
+        let mut rsheet = match self.storage {
+            Some(store) => RSheet::with_boxed_store(store),
+            None => RSheet::new(),
+        };
+        rsheet = rsheet.with_import_limits(self.limits.import).with_quotas(self.limits.quotas).with_recalc(self.recalc);
+        if let Some(bytes) = self.limits.memory {
+            rsheet = rsheet.with_memory_limit(bytes);
+        }
+        if let Some(path) = self.persistence_path {
+            rsheet = rsheet.with_persistence_path(path);
+        }
+        if let Some(seed) = self.rng_seed {
+            rsheet = rsheet.with_rng_seed(seed);
+        }
+        if let Some(sink) = self.diagnostics {
+            rsheet.diagnostics = sink;
+        }
+        if let Some(path) = self.wal_path {
+            rsheet.replay(&path)?;
+            rsheet = rsheet.with_write_ahead_log(WriteAheadLog::open(path)?);
+        }
+        Ok(rsheet)
+    }
+}
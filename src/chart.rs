@@ -0,0 +1,130 @@
+use crate::address::{split_sheet, CellRange};
+use crate::replies::{ErrorCode, ReplyError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChartKind {
+    Line,
+    Bar,
+    Pie,
+    Scatter,
+}
+
+impl FromStr for ChartKind {
+    type Err = ReplyError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "line" => Ok(ChartKind::Line),
+            "bar" => Ok(ChartKind::Bar),
+            "pie" => Ok(ChartKind::Pie),
+            "scatter" => Ok(ChartKind::Scatter),
+            other => Err(ReplyError::new(ErrorCode::ParseError, format!("Unknown chart type: {}", other))),
+        }
+    }
+}
+
+impl fmt::Display for ChartKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChartKind::Line => "line",
+            ChartKind::Bar => "bar",
+            ChartKind::Pie => "pie",
+            ChartKind::Scatter => "scatter",
+        })
+    }
+}
+
+/// A named chart, kept with the workbook: what to draw and which ranges to
+/// draw it from. Ranges are storage keys such as `B2:B13` or `Budget!B2:B13`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Chart {
+    pub kind: ChartKind,
+    /// One range per series.
+    pub series: Vec<String>,
+    /// Where the category labels are, if anywhere.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<String>,
+}
+
+impl Chart {
+    /// Reads `<type> <range>... [labels <range>]`, e.g. `line B2:B13 C2:C13 labels A2:A13`.
+    pub fn parse(args: &[&str]) -> Result<Self, ReplyError> {
+        let (kind, rest) = args.split_first().ok_or_else(|| ReplyError::new(ErrorCode::ParseError, "Missing chart type"))?;
+        let (series, labels) = match rest {
+            [series @ .., "labels", labels] => (series, Some(labels.to_string())),
+            series => (series, None),
+        };
+        if series.is_empty() {
+            return Err(ReplyError::new(ErrorCode::ParseError, "A chart needs at least one series range"));
+        }
+        let chart = Chart { kind: kind.parse()?, series: series.iter().map(|range| range.to_string()).collect(), labels };
+        chart.ranges().try_for_each(|range| parse_range(range).map(|_| ()))?;
+        Ok(chart)
+    }
+
+    /// Every range the chart reads: its series, then its labels.
+    pub fn ranges(&self) -> impl Iterator<Item = &str> {
+        self.series.iter().chain(&self.labels).map(String::as_str)
+    }
+
+    /// The chart with each range passed through `resolve`.
+    pub fn resolved(self, resolve: impl Fn(&str) -> String) -> Self {
+        Chart {
+            series: self.series.iter().map(|range| resolve(range)).collect(),
+            labels: self.labels.as_deref().map(&resolve),
+            ..self
+        }
+    }
+}
+
+/// As [`Chart::parse`] reads it.
+impl fmt::Display for Chart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.series.join(" "))?;
+        if let Some(labels) = &self.labels {
+            write!(f, " labels {}", labels)?;
+        }
+        Ok(())
+    }
+}
+
+/// `range` split into its sheet and cells.
+pub fn parse_range(range: &str) -> Result<(Option<&str>, CellRange), ReplyError> {
+    let (sheet, cells) = split_sheet(range);
+    let cells = cells.parse().map_err(|e| ReplyError::new(ErrorCode::ParseError, format!("{}", e)))?;
+    Ok((sheet, cells))
+}
+
+/// A chart's series as they stand, for `chart data`: one value per cell
+/// of each range, in row order, with `None` where there is no number.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChartData {
+    pub name: String,
+    pub kind: ChartKind,
+    pub labels: Option<Vec<String>>,
+    pub series: Vec<Series>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Series {
+    pub range: String,
+    pub values: Vec<Option<f64>>,
+}
+
+/// A chart in the `charts` listing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NamedChart {
+    pub name: String,
+    pub chart: Chart,
+}
+
+/// Whether `name` may name a chart: a letter or `_`, then letters, digits, `_` or `-`.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
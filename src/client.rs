@@ -0,0 +1,483 @@
+use crate::command::{quote_text, Command};
+use crate::connect::{Capability, Reader, Writer, ARROW_FRAME, DEFAULT_MAX_FRAME_SIZE, PROTOCOL_VERSION};
+use crate::crdt::{Op, Replica};
+use crate::error::RSheetError;
+use crate::replies::{Reply, ReplyError};
+use crate::{CellValue, Message};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    /// The server sent something that does not fit the protocol.
+    Protocol(String),
+    /// The server handled the command and rejected it.
+    Server(ReplyError),
+    /// A well-formed reply of the wrong kind for the command sent.
+    UnexpectedReply(Reply),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "I/O error: {}", e),
+            ClientError::Protocol(e) => write!(f, "Protocol error: {}", e),
+            ClientError::Server(e) => write!(f, "Server error: {}", e),
+            ClientError::UnexpectedReply(reply) => write!(f, "Unexpected reply: {:?}", reply),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<RSheetError> for ClientError {
+    fn from(e: RSheetError) -> Self {
+        match e {
+            RSheetError::Io(e) => ClientError::Io(e),
+            e => ClientError::Protocol(e.to_string()),
+        }
+    }
+}
+
+/// Blocking client speaking the length-prefixed JSON protocol.
+pub struct RSheetClient {
+    reader: Reader,
+    writer: Writer,
+    notifications: VecDeque<(String, CellValue)>,
+}
+
+impl RSheetClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, ClientError> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(RSheetClient {
+            reader: Reader::new(stream.try_clone()?).with_arrow_frames(),
+            writer: Writer::new(stream),
+            notifications: VecDeque::new(),
+        })
+    }
+
+    /// Negotiates the protocol version and `capabilities`. Without this the
+    /// server treats the client as version 1 with every capability enabled.
+    pub fn hello(&mut self, capabilities: &[Capability]) -> Result<(u32, Vec<Capability>), ClientError> {
+        let hello = Message::Hello { version: PROTOCOL_VERSION, capabilities: capabilities.to_vec() };
+        self.writer.send(&hello)?;
+        loop {
+            match self.reader.read_message()? {
+                Message::Hello { version, capabilities } => return Ok((version, capabilities)),
+                Message::Reply(Reply::Error(e)) => return Err(ClientError::Server(e)),
+                Message::Notify { cell, value } => self.notifications.push_back((cell, value)),
+                Message::Broadcast(text) => tracing::info!(%text, "server broadcast"),
+                Message::Presence(presence) => tracing::debug!(?presence, "presence changed"),
+                Message::Alert(alert) => tracing::warn!(%alert, "alert fired"),
+                Message::Pong => {}
+                other => return Err(ClientError::Protocol(format!("Unexpected message: {:?}", other))),
+            }
+        }
+    }
+
+    /// Sends a raw command and returns the server's reply, errors included.
+    pub fn command(&mut self, command: &str) -> Result<Reply, ClientError> {
+        self.writer.send(&Message::Command(command.into()))?;
+        let mut chunks = Vec::new();
+        loop {
+            match self.reader.read_message()? {
+                Message::Reply(reply) => return Ok(assemble(reply, &mut chunks)),
+                Message::Chunk(rows) => chunks.extend(rows),
+                Message::Notify { cell, value } => self.notifications.push_back((cell, value)),
+                Message::Broadcast(text) => tracing::info!(%text, "server broadcast"),
+                Message::Presence(presence) => tracing::debug!(?presence, "presence changed"),
+                Message::Alert(alert) => tracing::warn!(%alert, "alert fired"),
+                Message::Pong => {}
+                other => return Err(ClientError::Protocol(format!("Unexpected message: {:?}", other))),
+            }
+        }
+    }
+
+    pub fn set_number(&mut self, cell: &str, value: f64) -> Result<(), ClientError> {
+        self.expect_ok(&format!("set {} {}", cell, value))
+    }
+
+    pub fn set_formula(&mut self, cell: &str, formula: &str) -> Result<(), ClientError> {
+        self.expect_ok(&format!("set {} {}", cell, formula))
+    }
+
+    /// Sets `cell` to `text`, quoted so that spaces and cell-like words stay text.
+    pub fn set_text(&mut self, cell: &str, text: &str) -> Result<(), ClientError> {
+        self.expect_ok(&format!("set {} {}", cell, quote_text(text)))
+    }
+
+    pub fn get(&mut self, cell: &str) -> Result<CellValue, ClientError> {
+        expect_value(self.command(&format!("get {}", cell))?)
+    }
+
+    /// The value of `cell` with its version, for [`RSheetClient::set_if_version`].
+    pub fn get_versioned(&mut self, cell: &str) -> Result<(CellValue, u64), ClientError> {
+        expect_versioned(self.command(&format!("get {} version", cell))?)
+    }
+
+    /// Sends `replica`'s offline writes and merges back what it missed,
+    /// returning the writes that changed its cells.
+    pub fn sync(&mut self, replica: &mut Replica) -> Result<Vec<Op>, ClientError> {
+        let request = serde_json::to_string(&replica.sync_request()).map_err(|e| ClientError::Protocol(e.to_string()))?;
+        match self.command(&format!("sync\n{}", request))? {
+            Reply::Synced(response) => Ok(replica.merge(response)),
+            Reply::Error(e) => Err(ClientError::Server(e)),
+            other => Err(ClientError::UnexpectedReply(other)),
+        }
+    }
+
+    /// Sets `cell` only if nobody changed it since it was at `version`;
+    /// otherwise the server answers with an `ErrorCode::Conflict` error.
+    pub fn set_if_version(&mut self, cell: &str, expr: &str, version: u64) -> Result<(), ClientError> {
+        self.expect_ok(&format!("set {} {} if-version {}", cell, expr, version))
+    }
+
+    /// Values of `cells`, all read at the same moment.
+    pub fn get_many(&mut self, cells: &[&str]) -> Result<Vec<CellValue>, ClientError> {
+        expect_values(self.command(&format!("get {}", cells.join(" ")))?)
+    }
+
+    /// Values of `range` (e.g. `A1:C3`), one inner `Vec` per row.
+    pub fn get_range(&mut self, range: &str) -> Result<Vec<Vec<CellValue>>, ClientError> {
+        expect_range(self.command(&format!("get {}", range))?)
+    }
+
+    /// `range` as an Arrow IPC stream. Needs the [`Capability::Arrow`] capability from [`RSheetClient::hello`].
+    pub fn get_range_arrow(&mut self, range: &str) -> Result<Vec<u8>, ClientError> {
+        match self.command(&format!("get {} arrow", range))? {
+            Reply::Arrow(bytes) => Ok(bytes),
+            Reply::Error(e) => Err(ClientError::Server(e)),
+            other => Err(ClientError::UnexpectedReply(other)),
+        }
+    }
+
+    pub fn watch(&mut self, range: &str) -> Result<(), ClientError> {
+        self.expect_ok(&format!("watch {}", range))
+    }
+
+    /// Blocks until the server pushes a change to a watched cell.
+    pub fn next_notification(&mut self) -> Result<(String, CellValue), ClientError> {
+        if let Some(notification) = self.notifications.pop_front() {
+            return Ok(notification);
+        }
+        loop {
+            match self.reader.read_message()? {
+                Message::Notify { cell, value } => return Ok((cell, value)),
+                Message::Broadcast(text) => tracing::info!(%text, "server broadcast"),
+                Message::Presence(presence) => tracing::debug!(?presence, "presence changed"),
+                Message::Alert(alert) => tracing::warn!(%alert, "alert fired"),
+                Message::Pong => {}
+                other => return Err(ClientError::Protocol(format!("Unexpected message: {:?}", other))),
+            }
+        }
+    }
+
+    fn expect_ok(&mut self, command: &str) -> Result<(), ClientError> {
+        expect_ok(self.command(command)?)
+    }
+}
+
+/// Turns the end of a streamed range back into a plain [`Reply::Range`].
+fn assemble(reply: Reply, chunks: &mut Vec<Vec<CellValue>>) -> Reply {
+    match reply {
+        Reply::Streamed { range } => Reply::Range { range, values: std::mem::take(chunks) },
+        // A stream cut short by an error: drop the partial rows.
+        reply => {
+            chunks.clear();
+            reply
+        }
+    }
+}
+
+fn expect_ok(reply: Reply) -> Result<(), ClientError> {
+    match reply {
+        Reply::Ok => Ok(()),
+        Reply::Error(e) => Err(ClientError::Server(e)),
+        other => Err(ClientError::UnexpectedReply(other)),
+    }
+}
+
+fn expect_value(reply: Reply) -> Result<CellValue, ClientError> {
+    match reply {
+        Reply::Value(value) => Ok(Arc::unwrap_or_clone(value)),
+        Reply::Error(e) => Err(ClientError::Server(e)),
+        other => Err(ClientError::UnexpectedReply(other)),
+    }
+}
+
+fn expect_versioned(reply: Reply) -> Result<(CellValue, u64), ClientError> {
+    match reply {
+        Reply::Versioned { value, version } => Ok((Arc::unwrap_or_clone(value), version)),
+        Reply::Error(e) => Err(ClientError::Server(e)),
+        other => Err(ClientError::UnexpectedReply(other)),
+    }
+}
+
+fn expect_values(reply: Reply) -> Result<Vec<CellValue>, ClientError> {
+    match reply {
+        Reply::Values(values) => Ok(values.into_iter().map(Arc::unwrap_or_clone).collect()),
+        Reply::Error(e) => Err(ClientError::Server(e)),
+        other => Err(ClientError::UnexpectedReply(other)),
+    }
+}
+
+fn expect_range(reply: Reply) -> Result<Vec<Vec<CellValue>>, ClientError> {
+    match reply {
+        Reply::Range { values, .. } => Ok(values),
+        Reply::Error(e) => Err(ClientError::Server(e)),
+        other => Err(ClientError::UnexpectedReply(other)),
+    }
+}
+
+/// Waiters for in-flight replies; `None` once the reader has stopped.
+type Pending = Arc<Mutex<Option<VecDeque<oneshot::Sender<Result<Reply, ClientError>>>>>>;
+
+/// Async client on tokio. Methods take `&self`, so several commands can be in
+/// flight at once; the server answers each connection in order, so replies are
+/// matched to requests first-in first-out.
+pub struct AsyncRSheetClient {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    pending: Pending,
+    notifications: tokio::sync::Mutex<mpsc::UnboundedReceiver<(String, CellValue)>>,
+    reader_task: tokio::task::JoinHandle<()>,
+}
+
+impl AsyncRSheetClient {
+    pub async fn connect(addr: impl tokio::net::ToSocketAddrs) -> Result<Self, ClientError> {
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let (read_half, write_half) = stream.into_split();
+        let pending: Pending = Arc::new(Mutex::new(Some(VecDeque::new())));
+        let (notify_tx, notify_rx) = mpsc::unbounded_channel();
+        let reader_task = tokio::spawn(Self::read_loop(read_half, pending.clone(), notify_tx));
+        Ok(AsyncRSheetClient {
+            writer: tokio::sync::Mutex::new(write_half),
+            pending,
+            notifications: tokio::sync::Mutex::new(notify_rx),
+            reader_task,
+        })
+    }
+
+    async fn read_loop(
+        mut stream: OwnedReadHalf,
+        pending: Pending,
+        notifications: mpsc::UnboundedSender<(String, CellValue)>,
+    ) {
+        let mut chunks = Vec::new();
+        let error = loop {
+            match Self::read_frame(&mut stream).await {
+                Ok(Message::Chunk(rows)) => chunks.extend(rows),
+                Ok(Message::Reply(reply)) => match pending.lock().unwrap().as_mut().and_then(|p| p.pop_front()) {
+                    Some(waiter) => {
+                        let _ = waiter.send(Ok(assemble(reply, &mut chunks)));
+                    }
+                    None => break format!("Reply with no request in flight: {:?}", reply),
+                },
+                Ok(Message::Notify { cell, value }) => {
+                    let _ = notifications.send((cell, value));
+                }
+                Ok(Message::Broadcast(text)) => tracing::info!(%text, "server broadcast"),
+                Ok(Message::Presence(presence)) => tracing::debug!(?presence, "presence changed"),
+                Ok(Message::Alert(alert)) => tracing::warn!(%alert, "alert fired"),
+                Ok(Message::Pong) => {}
+                Ok(other) => break format!("Unexpected message: {:?}", other),
+                Err(e) => break e.to_string(),
+            }
+        };
+        for waiter in pending.lock().unwrap().take().into_iter().flatten() {
+            let _ = waiter.send(Err(ClientError::Protocol(error.clone())));
+        }
+    }
+
+    async fn read_frame(stream: &mut OwnedReadHalf) -> Result<Message, ClientError> {
+        let header = stream.read_u32().await?;
+        let len = (header & !ARROW_FRAME) as usize;
+        if len > DEFAULT_MAX_FRAME_SIZE {
+            return Err(ClientError::Protocol(format!("Frame of {} bytes is too large", len)));
+        }
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).await?;
+        if header & ARROW_FRAME != 0 {
+            return Ok(Message::Reply(Reply::Arrow(buf)));
+        }
+        serde_json::from_slice(&buf).map_err(|e| ClientError::Protocol(e.to_string()))
+    }
+
+    /// Sends a raw command and returns the server's reply, errors included.
+    pub async fn command(&self, command: &str) -> Result<Reply, ClientError> {
+        let msg_json = serde_json::to_vec(&Message::Command(command.into()))
+            .map_err(|e| ClientError::Protocol(e.to_string()))?;
+        let (tx, rx) = oneshot::channel();
+        {
+            // Queue the waiter while holding the writer so queue order matches wire order.
+            let mut writer = self.writer.lock().await;
+            match self.pending.lock().unwrap().as_mut() {
+                Some(pending) => pending.push_back(tx),
+                None => return Err(ClientError::Protocol("Connection closed".to_string())),
+            }
+            writer.write_u32(msg_json.len() as u32).await?;
+            writer.write_all(&msg_json).await?;
+        }
+        rx.await
+            .map_err(|_| ClientError::Protocol("Connection closed".to_string()))?
+    }
+
+    pub async fn set_number(&self, cell: &str, value: f64) -> Result<(), ClientError> {
+        self.expect_ok(&format!("set {} {}", cell, value)).await
+    }
+
+    pub async fn set_formula(&self, cell: &str, formula: &str) -> Result<(), ClientError> {
+        self.expect_ok(&format!("set {} {}", cell, formula)).await
+    }
+
+    pub async fn get(&self, cell: &str) -> Result<CellValue, ClientError> {
+        expect_value(self.command(&format!("get {}", cell)).await?)
+    }
+
+    pub async fn get_range(&self, range: &str) -> Result<Vec<Vec<CellValue>>, ClientError> {
+        expect_range(self.command(&format!("get {}", range)).await?)
+    }
+
+    pub async fn watch(&self, range: &str) -> Result<(), ClientError> {
+        self.expect_ok(&format!("watch {}", range)).await
+    }
+
+    /// Waits for the server to push a change to a watched cell.
+    pub async fn next_notification(&self) -> Option<(String, CellValue)> {
+        self.notifications.lock().await.recv().await
+    }
+
+    async fn expect_ok(&self, command: &str) -> Result<(), ClientError> {
+        expect_ok(self.command(command).await?)
+    }
+}
+
+impl Drop for AsyncRSheetClient {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+    }
+}
+
+/// Whether re-sending `command` after a lost reply cannot change the outcome.
+/// Formulas are evaluated when set, so `set A1 A1+1` is not safe to repeat
+/// unless it carries an idempotency key (`idem <key> set A1 A1+1`).
+pub fn is_idempotent(command: &str) -> bool {
+    match Command::parse(command) {
+        Command::Get { .. }
+        | Command::GetRange { .. }
+        | Command::GetMany { .. }
+        | Command::GetVersion { .. }
+        | Command::GetArrow { .. }
+        | Command::GetAsOf { .. }
+        | Command::Watches
+        | Command::Idem { .. } => true,
+        Command::Set { expr, if_version: None, .. } => expr.parse::<f64>().is_ok(),
+        _ => false,
+    }
+}
+
+/// Blocking client over several servers. Connections are reused, opened
+/// round-robin across `addresses`, replaced when they break, and idempotent
+/// commands are retried on the next address.
+pub struct ClientPool {
+    addresses: Vec<String>,
+    idle: Mutex<Vec<RSheetClient>>,
+    next_address: AtomicUsize,
+    max_retries: usize,
+}
+
+impl ClientPool {
+    pub fn new(addresses: Vec<String>) -> Self {
+        ClientPool {
+            addresses,
+            idle: Mutex::new(Vec::new()),
+            next_address: AtomicUsize::new(0),
+            max_retries: 2,
+        }
+    }
+
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    fn checkout(&self) -> Result<RSheetClient, ClientError> {
+        if let Some(client) = self.idle.lock().unwrap().pop() {
+            return Ok(client);
+        }
+        if self.addresses.is_empty() {
+            return Err(ClientError::Protocol("No server addresses configured".to_string()));
+        }
+        let mut last_error = None;
+        for _ in 0..self.addresses.len() {
+            let i = self.next_address.fetch_add(1, Ordering::Relaxed) % self.addresses.len();
+            match RSheetClient::connect(self.addresses[i].as_str()) {
+                Ok(client) => return Ok(client),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    /// Sends a raw command, reconnecting and retrying if it is idempotent.
+    pub fn command(&self, command: &str) -> Result<Reply, ClientError> {
+        let retries = if is_idempotent(command) { self.max_retries } else { 0 };
+        let mut attempt = 0;
+        loop {
+            let result = self.checkout().and_then(|mut client| {
+                let reply = client.command(command)?;
+                self.idle.lock().unwrap().push(client);
+                Ok(reply)
+            });
+            match result {
+                Err(ClientError::Io(_) | ClientError::Protocol(_)) if attempt < retries => attempt += 1,
+                result => return result,
+            }
+        }
+    }
+
+    pub fn set_number(&self, cell: &str, value: f64) -> Result<(), ClientError> {
+        expect_ok(self.command(&format!("set {} {}", cell, value))?)
+    }
+
+    pub fn set_formula(&self, cell: &str, formula: &str) -> Result<(), ClientError> {
+        expect_ok(self.command(&format!("set {} {}", cell, formula))?)
+    }
+
+    pub fn set_text(&self, cell: &str, text: &str) -> Result<(), ClientError> {
+        expect_ok(self.command(&format!("set {} {}", cell, quote_text(text)))?)
+    }
+
+    pub fn get(&self, cell: &str) -> Result<CellValue, ClientError> {
+        expect_value(self.command(&format!("get {}", cell))?)
+    }
+
+    pub fn get_versioned(&self, cell: &str) -> Result<(CellValue, u64), ClientError> {
+        expect_versioned(self.command(&format!("get {} version", cell))?)
+    }
+
+    pub fn set_if_version(&self, cell: &str, expr: &str, version: u64) -> Result<(), ClientError> {
+        expect_ok(self.command(&format!("set {} {} if-version {}", cell, expr, version))?)
+    }
+
+    pub fn get_many(&self, cells: &[&str]) -> Result<Vec<CellValue>, ClientError> {
+        expect_values(self.command(&format!("get {}", cells.join(" ")))?)
+    }
+
+    pub fn get_range(&self, range: &str) -> Result<Vec<Vec<CellValue>>, ClientError> {
+        expect_range(self.command(&format!("get {}", range))?)
+    }
+}
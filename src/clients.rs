@@ -0,0 +1,282 @@
+use crate::connect::SharedWriter;
+use crate::{CellValue, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use web_time::Instant;
+
+/// What the `admin clients` command reports about one connection.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientSummary {
+    pub id: u64,
+    pub peer: Option<String>,
+    pub connected_secs: u64,
+    pub idle_secs: u64,
+    pub commands: u64,
+    pub admin: bool,
+}
+
+/// Which connections hear about every committed change, beyond their watches.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeBroadcast {
+    /// Only watches are notified.
+    #[default]
+    Off,
+    /// Every client that accepts notifications.
+    All,
+    /// Clients that sent `session broadcast on`.
+    Subscribed,
+}
+
+impl FromStr for ChangeBroadcast {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ChangeBroadcast::Off),
+            "all" => Ok(ChangeBroadcast::All),
+            "subscribed" => Ok(ChangeBroadcast::Subscribed),
+            _ => Err(format!("Unknown change broadcast mode: {}", s)),
+        }
+    }
+}
+
+/// Who a connection is and where it is working, for `presence` and the
+/// [`Message::Presence`] pushes that follow changes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Presence {
+    pub connection: u64,
+    /// Set by `session name`, otherwise `client-<connection>`.
+    pub name: String,
+    /// The cell or range last touched or `select`ed.
+    pub focus: Option<String>,
+    /// False once, in the push sent when the connection closes.
+    pub connected: bool,
+}
+
+struct Client {
+    peer: Option<SocketAddr>,
+    writer: SharedWriter,
+    connected_at: Instant,
+    last_active: Instant,
+    commands: u64,
+    admin: bool,
+    /// Whether the client accepts server pushes such as broadcasts.
+    notifications: bool,
+    /// Whether the client asked for every change under `ChangeBroadcast::Subscribed`.
+    changes: bool,
+    /// Whether the client asked for presence changes with `session presence on`.
+    watching_presence: bool,
+    name: Option<String>,
+    focus: Option<String>,
+}
+
+impl Client {
+    fn presence(&self, connection: u64) -> Presence {
+        Presence {
+            connection,
+            name: self.name.clone().unwrap_or_else(|| format!("client-{}", connection)),
+            focus: self.focus.clone(),
+            connected: true,
+        }
+    }
+}
+
+/// Every connected client, for the admin commands.
+#[derive(Default)]
+pub struct Clients {
+    clients: Mutex<BTreeMap<u64, Client>>,
+}
+
+impl Clients {
+    pub fn register(&self, connection: u64, peer: Option<SocketAddr>, writer: SharedWriter) {
+        let now = Instant::now();
+        let client = Client {
+            peer,
+            writer,
+            connected_at: now,
+            last_active: now,
+            commands: 0,
+            admin: false,
+            notifications: true,
+            changes: false,
+            watching_presence: false,
+            name: None,
+            focus: None,
+        };
+        self.clients.lock().unwrap().insert(connection, client);
+    }
+
+    /// Tells the others a client has gone, if it ever named itself or touched a cell.
+    pub fn remove(&self, connection: u64) {
+        let removed = self.clients.lock().unwrap().remove(&connection);
+        if let Some(client) = removed.filter(|c| c.name.is_some() || c.focus.is_some()) {
+            self.push_presence(Presence { connected: false, ..client.presence(connection) });
+        }
+    }
+
+    /// Counts a command against the client and resets its idle time.
+    pub fn touch(&self, connection: u64) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&connection) {
+            client.last_active = Instant::now();
+            client.commands += 1;
+        }
+    }
+
+    pub fn set_admin(&self, connection: u64) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&connection) {
+            client.admin = true;
+        }
+    }
+
+    pub fn set_notifications(&self, connection: u64, enabled: bool) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&connection) {
+            client.notifications = enabled;
+        }
+    }
+
+    /// Returns false if no such client is connected.
+    pub fn set_changes(&self, connection: u64, enabled: bool) -> bool {
+        match self.clients.lock().unwrap().get_mut(&connection) {
+            Some(client) => {
+                client.changes = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns false if no such client is connected.
+    pub fn set_watching_presence(&self, connection: u64, enabled: bool) -> bool {
+        match self.clients.lock().unwrap().get_mut(&connection) {
+            Some(client) => {
+                client.watching_presence = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn name(&self, connection: u64) -> Option<String> {
+        self.clients.lock().unwrap().get(&connection).and_then(|client| client.name.clone())
+    }
+
+    pub fn set_name(&self, connection: u64, name: &str) -> bool {
+        self.update_presence(connection, |client| client.name.replace(name.to_string()).as_deref() != Some(name))
+    }
+
+    /// Records what the client is working on, telling the others if it moved.
+    pub fn focus(&self, connection: u64, target: &str) -> bool {
+        self.update_presence(connection, |client| client.focus.replace(target.to_string()).as_deref() != Some(target))
+    }
+
+    /// Applies `change` and pushes the client's presence if it reports a
+    /// difference. Returns false if no such client is connected.
+    fn update_presence(&self, connection: u64, change: impl FnOnce(&mut Client) -> bool) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        let Some(client) = clients.get_mut(&connection) else {
+            return false;
+        };
+        let changed = change(client).then(|| client.presence(connection));
+        drop(clients);
+        if let Some(presence) = changed {
+            self.push_presence(presence);
+        }
+        true
+    }
+
+    /// Every connected client, by connection id.
+    pub fn presence(&self) -> Vec<Presence> {
+        self.clients.lock().unwrap().iter().map(|(id, c)| c.presence(*id)).collect()
+    }
+
+    /// Pushes `presence` to every other client watching presence.
+    fn push_presence(&self, presence: Presence) {
+        let writers: Vec<SharedWriter> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, c)| c.notifications && c.watching_presence && **id != presence.connection)
+            .map(|(_, c)| c.writer.clone())
+            .collect();
+        let msg = Message::Presence(presence);
+        for writer in writers {
+            if let Err(e) = writer.lock().unwrap().send(&msg) {
+                tracing::warn!(error = %e, "failed to push presence");
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<ClientSummary> {
+        let now = Instant::now();
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, c)| ClientSummary {
+                id: *id,
+                peer: c.peer.map(|p| p.to_string()),
+                connected_secs: now.duration_since(c.connected_at).as_secs(),
+                idle_secs: now.duration_since(c.last_active).as_secs(),
+                commands: c.commands,
+                admin: c.admin,
+            })
+            .collect()
+    }
+
+    /// Closes the client's socket. Returns false if no such client is connected.
+    pub fn kick(&self, connection: u64) -> bool {
+        let writer = match self.clients.lock().unwrap().get(&connection) {
+            Some(client) => client.writer.clone(),
+            None => return false,
+        };
+        if let Err(e) = writer.lock().unwrap().shutdown() {
+            tracing::warn!(connection, error = %e, "failed to close kicked client");
+        }
+        true
+    }
+
+    /// Pushes `text` to every connected client that accepts notifications.
+    pub fn broadcast(&self, text: &str) {
+        let writers: Vec<SharedWriter> = self
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|c| c.notifications)
+            .map(|c| c.writer.clone())
+            .collect();
+        let msg = Message::Broadcast(text.to_string());
+        for writer in writers {
+            if let Err(e) = writer.lock().unwrap().send(&msg) {
+                tracing::warn!(error = %e, "failed to deliver broadcast");
+            }
+        }
+    }
+
+    /// Pushes a `Message::Notify` for a committed change to every client
+    /// `mode` includes, except those in `notified` that already heard of it.
+    pub fn notify_change(&self, mode: ChangeBroadcast, cell: &str, value: &CellValue, notified: &[u64]) {
+        if mode == ChangeBroadcast::Off {
+            return;
+        }
+        let writers: Vec<SharedWriter> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, c)| c.notifications && (mode == ChangeBroadcast::All || c.changes) && !notified.contains(id))
+            .map(|(_, c)| c.writer.clone())
+            .collect();
+        let msg = Message::Notify { cell: cell.to_string(), value: value.clone() };
+        for writer in writers {
+            if let Err(e) = writer.lock().unwrap().send(&msg) {
+                tracing::warn!(cell, error = %e, "failed to broadcast change");
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::address::DEFAULT_SHEET;
+use crate::client::{ClientError, RSheetClient};
+use crate::replies::{ErrorCode, Reply};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Which node serves each sheet that does not live here. Sheets it does not
+/// name are served locally, so each node's map lists only the others'.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ShardMap {
+    sheets: BTreeMap<String, String>,
+}
+
+impl ShardMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `sheet` from the node listening at `node`.
+    pub fn assign(mut self, sheet: impl Into<String>, node: impl Into<String>) -> Self {
+        self.sheets.insert(sheet.into(), node.into());
+        self
+    }
+
+    /// The node serving `sheet`, or `None` if it is served here.
+    pub fn owner(&self, sheet: Option<&str>) -> Option<&str> {
+        self.sheets.get(sheet.unwrap_or(DEFAULT_SHEET)).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sheets.is_empty()
+    }
+}
+
+impl FromIterator<(String, String)> for ShardMap {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        ShardMap { sheets: iter.into_iter().collect() }
+    }
+}
+
+/// Forwards commands for sheets on other nodes, reusing one idle connection
+/// per node where it can.
+pub struct Router {
+    map: ShardMap,
+    idle: Mutex<HashMap<String, Vec<RSheetClient>>>,
+}
+
+impl Router {
+    pub fn new(map: ShardMap) -> Self {
+        Router { map, idle: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn owner(&self, sheet: Option<&str>) -> Option<&str> {
+        self.map.owner(sheet)
+    }
+
+    /// Runs `command` on `node` as if sent by a client using `sheet`, so
+    /// unqualified references resolve there as they would have here.
+    pub fn forward(&self, node: &str, sheet: Option<&str>, command: &str) -> Reply {
+        let client = self.idle.lock().unwrap().get_mut(node).and_then(Vec::pop);
+        let result = client.map_or_else(|| RSheetClient::connect(node), Ok).and_then(|mut client| {
+            match client.command(&format!("use {}", sheet.unwrap_or(DEFAULT_SHEET)))? {
+                Reply::Ok => {}
+                reply => return Ok(reply),
+            }
+            let reply = client.command(command)?;
+            self.idle.lock().unwrap().entry(node.to_string()).or_default().push(client);
+            Ok(reply)
+        });
+        match result {
+            Ok(reply) => reply,
+            Err(ClientError::Server(e)) => Reply::Error(e),
+            Err(e) => {
+                tracing::warn!(node, error = %e, "failed to forward command");
+                Reply::error(ErrorCode::NodeUnavailable, format!("Node {} is unavailable: {}", node, e))
+            }
+        }
+    }
+}
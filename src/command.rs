@@ -0,0 +1,795 @@
+use crate::infer::InferOptions;
+use crate::chart::Chart;
+use crate::replies::{ErrorCode, ReplyError};
+use crate::reshape::{Direction, TransposeMode};
+use crate::subscriptions::WatchFilter;
+use crate::view::ViewChange;
+use crate::{CsvOptions, ExportContent};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+/// A parsed command. Cells and ranges are kept as written; the session
+/// resolves them against its sheet when the command runs.
+///
+/// Sent as its text, so the wire format is the same whether a client
+/// builds commands or writes them out. Text that does not parse arrives as
+/// [`Command::Invalid`] and is answered with a parse error.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Command {
+    /// `set <cell> <expr> [if-version <n>] [--force]`; `--force` overwrites a
+    /// formula on a sheet whose formulas are protected.
+    Set { cell: String, expr: String, if_version: Option<u64>, force: bool },
+    Get { cell: String },
+    /// `get <range>` with a `:` in it.
+    GetRange { range: String },
+    /// `get <cell> <cell> ...`
+    GetMany { cells: Vec<String> },
+    GetVersion { cell: String },
+    GetArrow { range: String },
+    /// `get <cell> asof <time>`
+    GetAsOf { cell: String, time: String },
+    Delete { cell: String },
+    Dump,
+    Begin,
+    Commit,
+    Rollback,
+    Use { sheet: String },
+    Session,
+    SessionOption { key: String, value: String },
+    Watch { range: String, filter: WatchFilter },
+    /// `unwatch <range>`, or `unwatch all` with no range.
+    Unwatch { range: Option<String> },
+    Watches,
+    Auth { token: String },
+    Admin(AdminCommand),
+    /// `idem <key> set ...` or `idem <key> delete ...`
+    Idem { key: String, command: Box<Command> },
+    /// `export <format> <path>`
+    Export { format: String, path: String },
+    /// `export <format> <range> <path>`
+    ExportRange { format: String, range: String, path: String },
+    /// `export csv <range> [values|formulas] [delimiter]`
+    ExportCsv { range: String, options: CsvOptions },
+    /// `import <format> <path>`
+    Import { format: String, path: String },
+    /// `import url <url> <anchor> [text] [<column>:<type>...]`
+    ImportUrl { url: String, anchor: String, options: InferOptions },
+    /// `import csv <anchor> [text] [<column>:<type>...]` with the CSV on the
+    /// lines after it.
+    ImportCsv { anchor: String, data: String, options: InferOptions },
+    /// `sync` with a JSON sync request on the lines after it.
+    Sync { body: String },
+    Backup { path: String },
+    Restore { path: String },
+    /// `run <path>`: a script of commands from a file on the server.
+    Run { path: String },
+    Save { path: Option<String> },
+    Load { path: Option<String> },
+    Meminfo,
+    /// `lock <cell> for <duration>`
+    Lock { cell: String, duration: String },
+    Unlock { cell: String },
+    Select { target: String },
+    Presence,
+    Replicate,
+    /// `alert when <cell> <comparison> <threshold> [hysteresis <n>]`
+    AlertWhen { args: Vec<String> },
+    AlertRemove { id: u64 },
+    Alerts,
+    /// `query SELECT ...`, spacing kept so quoted text survives.
+    Query { text: String },
+    Filter { range: String, conditions: String },
+    Pivot { range: String, spec: String },
+    GroupBy { range: String, spec: String },
+    Dedupe { range: String, spec: String },
+    /// `view [sheet]`: how the sheet, or the session's, is shown.
+    View { sheet: Option<String> },
+    /// `view <change>` on the session's sheet, e.g. `view freeze 1 0`.
+    ViewSet { change: ViewChange },
+    /// `chart define <name> <type> <range>... [labels <range>]`
+    ChartDefine { name: String, chart: Chart },
+    ChartRemove { name: String },
+    Charts,
+    /// `chart data <name>`: the chart's series as they stand.
+    ChartData { name: String },
+    /// `alias <name> <cell>`
+    Alias { name: String, cell: String },
+    AliasRemove { name: String },
+    Aliases,
+    /// `graph export dot [range]`: how formulas read one another, in Graphviz DOT.
+    GraphExport { range: Option<String> },
+    /// `whereused <cell> [depth <n>]`
+    WhereUsed { cell: String, depth: Option<usize> },
+    /// `clear <range>`: empties the range, formulas and all.
+    Clear { range: String },
+    /// `describe <range> [bins <n>]`
+    Describe { range: String, bins: Option<usize> },
+    Transpose { range: String, anchor: String, mode: TransposeMode },
+    /// `shift <range> <up|down|left|right> <n>`
+    Shift { range: String, direction: Direction, by: u32 },
+    /// `scenario create <name>`
+    ScenarioCreate { name: String },
+    /// `scenario set <name> <cell> <expr>`
+    ScenarioSet { name: String, cell: String, expr: String },
+    ScenarioRemove { name: String },
+    Scenarios,
+    /// `eval <cell> in <scenario>...`: the cell as each scenario would have it.
+    Eval { cell: String, scenarios: Vec<String> },
+    /// `macro record <name> [param...]`: keeps the session's commands for the
+    /// macro, instead of running them, until `macro stop`.
+    MacroRecord { name: String, params: Vec<String> },
+    MacroStop,
+    /// `macro run <name> [arg...]`
+    MacroRun { name: String, args: Vec<String> },
+    MacroRemove { name: String },
+    Macros,
+    /// `protect formulas [sheet]` or `unprotect formulas [sheet]`, for the
+    /// session's sheet if none is named.
+    ProtectFormulas { sheet: Option<String>, on: bool },
+    /// `append <column> <expr>...`: fills the next row that is empty in the
+    /// column and the columns to its right, with one value each.
+    Append { column: String, values: Vec<String> },
+    /// `goalseek <cell> to <target> by changing <input>`
+    GoalSeek { cell: String, target: f64, input: String },
+    /// `slowlog [n]`
+    SlowLog { count: Option<usize> },
+    SlowLogReset,
+    /// `audit [n]`
+    Audit { count: Option<usize> },
+    /// Text that is not a command, with why and the code to answer it with.
+    Invalid { text: String, code: ErrorCode, reason: String },
+}
+
+/// `admin ...`, open only to sessions that sent `auth <token>`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdminCommand {
+    /// `admin snapshot now`
+    Snapshot,
+    Clients,
+    Webhooks,
+    WebhooksDead,
+    WebhookAdd { url: String, range: Option<String> },
+    WebhookRemove { id: String },
+    Promote,
+    Kick { target: String },
+    Broadcast { text: String },
+    /// `admin quota <user>`
+    Quota { user: String },
+    /// `admin quota <user> <cells|bytes|commands> <n|off>`
+    QuotaSet { user: String, limit: String, value: Option<u64> },
+}
+
+const INVALID_FORMAT: &str = "Invalid command format";
+
+/// Ends a `set` that may overwrite a formula on a sheet that protects them.
+const FORCE: &str = "--force";
+
+impl Command {
+    /// Parses `text`, answering text that is not a command with [`Command::Invalid`].
+    pub fn parse(text: &str) -> Self {
+        text.parse().unwrap_or_else(|e: ReplyError| Command::Invalid { text: text.to_string(), code: e.code, reason: e.message })
+    }
+
+    /// The name metrics count the command under; `unknown` if it did not parse.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set { .. } => "set",
+            Command::Get { .. }
+            | Command::GetRange { .. }
+            | Command::GetMany { .. }
+            | Command::GetVersion { .. }
+            | Command::GetArrow { .. }
+            | Command::GetAsOf { .. } => "get",
+            Command::Delete { .. } => "delete",
+            Command::Dump => "dump",
+            Command::Begin => "begin",
+            Command::Commit => "commit",
+            Command::Rollback => "rollback",
+            Command::Use { .. } => "use",
+            Command::Session | Command::SessionOption { .. } => "session",
+            Command::Watch { .. } => "watch",
+            Command::Unwatch { .. } => "unwatch",
+            Command::Watches => "watches",
+            Command::Auth { .. } => "auth",
+            Command::Admin(_) => "admin",
+            Command::Idem { .. } => "idem",
+            Command::Export { .. } | Command::ExportRange { .. } | Command::ExportCsv { .. } => "export",
+            Command::Import { .. } | Command::ImportUrl { .. } | Command::ImportCsv { .. } => "import",
+            Command::Sync { .. } => "sync",
+            Command::Backup { .. } => "backup",
+            Command::Restore { .. } => "restore",
+            Command::Run { .. } => "run",
+            Command::Save { .. } => "save",
+            Command::Load { .. } => "load",
+            Command::Meminfo => "meminfo",
+            Command::Lock { .. } => "lock",
+            Command::Unlock { .. } => "unlock",
+            Command::Select { .. } => "select",
+            Command::Presence => "presence",
+            Command::Replicate => "replicate",
+            Command::AlertWhen { .. } | Command::AlertRemove { .. } => "alert",
+            Command::Alerts => "alerts",
+            Command::Query { .. } => "query",
+            Command::Filter { .. } => "filter",
+            Command::Pivot { .. } => "pivot",
+            Command::GroupBy { .. } => "groupby",
+            Command::Dedupe { .. } => "dedupe",
+            Command::Describe { .. } => "describe",
+            Command::View { .. } | Command::ViewSet { .. } => "view",
+            Command::ChartDefine { .. } | Command::ChartRemove { .. } | Command::Charts | Command::ChartData { .. } => "chart",
+            Command::Alias { .. } | Command::AliasRemove { .. } => "alias",
+            Command::Aliases => "aliases",
+            Command::GraphExport { .. } => "graph",
+            Command::WhereUsed { .. } => "whereused",
+            Command::Clear { .. } => "clear",
+            Command::Transpose { .. } => "transpose",
+            Command::Shift { .. } => "shift",
+            Command::GoalSeek { .. } => "goalseek",
+            Command::ScenarioCreate { .. } | Command::ScenarioSet { .. } | Command::ScenarioRemove { .. } => "scenario",
+            Command::Scenarios => "scenarios",
+            Command::Eval { .. } => "eval",
+            Command::MacroRecord { .. } | Command::MacroStop | Command::MacroRun { .. } | Command::MacroRemove { .. } => "macro",
+            Command::Macros => "macros",
+            Command::Append { .. } => "append",
+            Command::ProtectFormulas { on: true, .. } => "protect",
+            Command::ProtectFormulas { on: false, .. } => "unprotect",
+            Command::SlowLog { .. } | Command::SlowLogReset => "slowlog",
+            Command::Audit { .. } => "audit",
+            Command::Invalid { .. } => "unknown",
+        }
+    }
+
+    /// Whether a replica or a read-only server turns the command away, as it
+    /// would change cells.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Delete { .. }
+                | Command::Begin
+                | Command::Commit
+                | Command::Load { .. }
+                | Command::Restore { .. }
+                | Command::Run { .. }
+                | Command::Import { .. }
+                | Command::ImportUrl { .. }
+                | Command::ImportCsv { .. }
+                | Command::Sync { .. }
+                | Command::Lock { .. }
+                | Command::Transpose { .. }
+                | Command::Shift { .. }
+                | Command::Dedupe { .. }
+                | Command::ViewSet { .. }
+                | Command::ChartDefine { .. }
+                | Command::ChartRemove { .. }
+                | Command::Alias { .. }
+                | Command::AliasRemove { .. }
+                | Command::Clear { .. }
+                | Command::GoalSeek { .. }
+                | Command::ScenarioCreate { .. }
+                | Command::ScenarioSet { .. }
+                | Command::ScenarioRemove { .. }
+                | Command::MacroRecord { .. }
+                | Command::MacroStop
+                | Command::MacroRun { .. }
+                | Command::MacroRemove { .. }
+                | Command::ProtectFormulas { .. }
+                | Command::Append { .. }
+        )
+    }
+
+    /// The cell or range a `set`, `get` or `delete` names, which becomes the session's focus.
+    pub fn focus(&self) -> Option<&str> {
+        match self {
+            Command::Set { cell, .. }
+            | Command::Get { cell }
+            | Command::GetVersion { cell }
+            | Command::GetAsOf { cell, .. }
+            | Command::Delete { cell } => Some(cell),
+            Command::GetRange { range } | Command::GetArrow { range } => Some(range),
+            Command::GetMany { cells } => cells.first().map(String::as_str),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = ReplyError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || ReplyError::new(ErrorCode::ParseError, INVALID_FORMAT);
+        // Imports and syncs carry their data on the lines after the command.
+        if let Some((header, body)) = text.split_once('\n') {
+            return match tokenize(header)?[..] {
+                ["sync"] => Ok(Command::Sync { body: body.to_string() }),
+                ["import", "csv", anchor, ref options @ ..] => Ok(Command::ImportCsv {
+                    anchor: anchor.to_string(),
+                    data: body.to_string(),
+                    options: InferOptions::parse(options)?,
+                }),
+                _ => Err(invalid()),
+            };
+        }
+        let parts = tokenize(text)?;
+        let name = parts[0];
+        let arg = |i: usize| parts[i].to_string();
+        // Paths and other free text may be quoted to hold spaces.
+        let word = |i: usize| unquote(parts[i]).into_owned();
+        // Cells are checked and written canonically here, so `a1` and `A1` are one key.
+        // Anything shaped like an alias is kept as written, for the sheet to look up.
+        let cell = |i: usize| match crate::address::normalize(parts[i]) {
+            Err(_) if crate::alias::is_valid_name(parts[i]) => Ok(arg(i)),
+            normalized => normalized.map_err(|e| ReplyError::new(ErrorCode::InvalidReference, e.to_string())),
+        };
+        let count = |n: &str| {
+            n.parse().map_err(|_| ReplyError::new(ErrorCode::ParseError, format!("Invalid entry count: {}", n)))
+        };
+        let command = match (name, parts.len()) {
+            ("set", 3) => Command::Set { cell: cell(1)?, expr: arg(2), if_version: None, force: false },
+            ("set", 4) if parts[3] == FORCE => Command::Set { cell: cell(1)?, expr: arg(2), if_version: None, force: true },
+            ("set", n @ 5..=6) if parts[3] == "if-version" && (n == 5 || parts[5] == FORCE) => {
+                let version = parts[4]
+                    .parse()
+                    .map_err(|_| ReplyError::new(ErrorCode::ParseError, format!("Invalid version: {}", parts[4])))?;
+                Command::Set { cell: cell(1)?, expr: arg(2), if_version: Some(version), force: n == 6 }
+            }
+            ("get", 3) if parts[2] == "version" => Command::GetVersion { cell: cell(1)? },
+            ("get", 3) if parts[2] == "arrow" => Command::GetArrow { range: arg(1) },
+            ("get", 2) if parts[1].contains(':') => Command::GetRange { range: arg(1) },
+            ("get", 2) => Command::Get { cell: cell(1)? },
+            ("get", 4) if parts[2] == "asof" => Command::GetAsOf { cell: cell(1)?, time: arg(3) },
+            ("get", n) if n >= 3 => Command::GetMany { cells: (1..n).map(cell).collect::<Result<_, _>>()? },
+            ("dump", 1) => Command::Dump,
+            ("delete", 2) => Command::Delete { cell: cell(1)? },
+            ("begin", 1) => Command::Begin,
+            ("commit", 1) => Command::Commit,
+            ("rollback", 1) => Command::Rollback,
+            ("use", 2) => Command::Use { sheet: arg(1) },
+            ("session", 1) => Command::Session,
+            ("session", 3) => Command::SessionOption { key: arg(1), value: word(2) },
+            ("watch", n) if n >= 2 => Command::Watch { range: arg(1), filter: watch_filter(&parts[2..])? },
+            ("unwatch", 2) if parts[1] == "all" => Command::Unwatch { range: None },
+            ("unwatch", 2) => Command::Unwatch { range: Some(arg(1)) },
+            ("watches", 1) => Command::Watches,
+            ("auth", 2) => Command::Auth { token: arg(1) },
+            ("admin", _) => Command::Admin(admin_command(&parts[1..]).ok_or_else(invalid)?),
+            ("idem", n) if n >= 3 && matches!(parts[2], "set" | "delete") => {
+                let command = parts[2..].join(" ").parse()?;
+                Command::Idem { key: arg(1), command: Box::new(command) }
+            }
+            ("export", 4) if parts[1] != "csv" => Command::ExportRange { format: arg(1), range: arg(2), path: word(3) },
+            ("export", 3) if parts[1] != "csv" => Command::Export { format: arg(1), path: word(2) },
+            ("export", 3..=5) if parts[1] == "csv" => Command::ExportCsv { range: arg(2), options: CsvOptions::parse(&parts[3..])? },
+            ("import", n) if n >= 4 && parts[1] == "url" => {
+                Command::ImportUrl { url: arg(2), anchor: arg(3), options: InferOptions::parse(&parts[4..])? }
+            }
+            ("import", 3) => Command::Import { format: arg(1), path: word(2) },
+            ("backup", 2) => Command::Backup { path: word(1) },
+            ("restore", 2) => Command::Restore { path: word(1) },
+            ("run", 2) => Command::Run { path: word(1) },
+            ("save", 1..=2) => Command::Save { path: (parts.len() == 2).then(|| word(1)) },
+            ("load", 1..=2) => Command::Load { path: (parts.len() == 2).then(|| word(1)) },
+            ("meminfo", 1) => Command::Meminfo,
+            ("lock", 4) if parts[2] == "for" => Command::Lock { cell: cell(1)?, duration: arg(3) },
+            ("unlock", 2) => Command::Unlock { cell: cell(1)? },
+            ("select", 2) => Command::Select { target: arg(1) },
+            ("presence", 1) => Command::Presence,
+            ("replicate", 1) => Command::Replicate,
+            ("alert", n) if n >= 5 && parts[1] == "when" => {
+                Command::AlertWhen { args: parts[2..].iter().map(|arg| arg.to_string()).collect() }
+            }
+            ("alert", 3) if parts[1] == "remove" => match parts[2].parse() {
+                Ok(id) => Command::AlertRemove { id },
+                Err(_) => return Err(ReplyError::new(ErrorCode::ParseError, format!("No alert {}", parts[2]))),
+            },
+            ("alerts", 1) => Command::Alerts,
+            ("query", n) if n >= 2 => Command::Query { text: text.trim_start()["query".len()..].trim().to_string() },
+            ("filter", n) if n >= 3 => Command::Filter { range: arg(1), conditions: after_range(text, &parts) },
+            ("pivot", n) if n >= 3 => Command::Pivot { range: arg(1), spec: after_range(text, &parts) },
+            ("groupby", n) if n >= 3 => Command::GroupBy { range: arg(1), spec: after_range(text, &parts) },
+            ("dedupe", n) if n >= 3 => Command::Dedupe { range: arg(1), spec: after_range(text, &parts) },
+            ("view", 1) => Command::View { sheet: None },
+            ("view", 2) => Command::View { sheet: Some(arg(1)) },
+            ("view", _) => Command::ViewSet { change: ViewChange::parse(&parts[1..])? },
+            ("chart", n) if n >= 5 && parts[1] == "define" => Command::ChartDefine { name: arg(2), chart: Chart::parse(&parts[3..])? },
+            ("chart", 3) if parts[1] == "remove" => Command::ChartRemove { name: arg(2) },
+            ("chart", 3) if parts[1] == "data" => Command::ChartData { name: arg(2) },
+            ("charts", 1) => Command::Charts,
+            ("alias", 3) if parts[1] == "remove" => Command::AliasRemove { name: arg(2) },
+            ("alias", 3) if crate::alias::is_valid_name(parts[1]) => Command::Alias { name: arg(1), cell: cell(2)? },
+            ("alias", 3) => return Err(ReplyError::new(ErrorCode::ParseError, format!("Invalid alias name: {}", parts[1]))),
+            ("aliases", 1) => Command::Aliases,
+            ("graph", 3..=4) if parts[1] == "export" && parts[2] == "dot" => {
+                Command::GraphExport { range: parts.get(3).map(|range| range.to_string()) }
+            }
+            ("clear", 2) => Command::Clear { range: arg(1) },
+            ("whereused", 2) => Command::WhereUsed { cell: cell(1)?, depth: None },
+            ("whereused", 4) if parts[2] == "depth" => match parts[3].parse() {
+                Ok(depth @ 1..) => Command::WhereUsed { cell: cell(1)?, depth: Some(depth) },
+                _ => return Err(ReplyError::new(ErrorCode::ParseError, format!("Invalid depth: {}", parts[3]))),
+            },
+            ("describe", 2) => Command::Describe { range: arg(1), bins: None },
+            ("describe", 4) if parts[2] == "bins" => match parts[3].parse() {
+                Ok(bins @ 1..=crate::stats::MAX_BINS) => Command::Describe { range: arg(1), bins: Some(bins) },
+                _ => {
+                    let message = format!("Invalid bin count: {} (expected 1 to {})", parts[3], crate::stats::MAX_BINS);
+                    return Err(ReplyError::new(ErrorCode::ParseError, message));
+                }
+            },
+            ("transpose", 3..=4) => {
+                let mode = match parts.get(3) {
+                    Some(mode) => mode.parse().map_err(|e: String| ReplyError::new(ErrorCode::ParseError, e))?,
+                    None => TransposeMode::default(),
+                };
+                Command::Transpose { range: arg(1), anchor: arg(2), mode }
+            }
+            ("shift", 4) => {
+                let direction = parts[2].parse().map_err(|e: String| ReplyError::new(ErrorCode::ParseError, e))?;
+                match parts[3].parse() {
+                    Ok(by @ 1..) => Command::Shift { range: arg(1), direction, by },
+                    _ => return Err(ReplyError::new(ErrorCode::ParseError, format!("Invalid shift: {}", parts[3]))),
+                }
+            }
+            ("goalseek", 7) if parts[2] == "to" && parts[4] == "by" && parts[5] == "changing" => match parts[3].parse::<f64>() {
+                Ok(target) if target.is_finite() => Command::GoalSeek { cell: cell(1)?, target, input: cell(6)? },
+                _ => return Err(ReplyError::new(ErrorCode::ParseError, format!("Invalid target: {}", parts[3]))),
+            },
+            ("scenario", 3) if parts[1] == "create" => Command::ScenarioCreate { name: arg(2) },
+            ("scenario", 3) if parts[1] == "remove" => Command::ScenarioRemove { name: arg(2) },
+            ("scenario", 5) if parts[1] == "set" => Command::ScenarioSet { name: arg(2), cell: cell(3)?, expr: arg(4) },
+            ("scenarios", 1) => Command::Scenarios,
+            ("eval", n) if n >= 4 && parts[2] == "in" => Command::Eval { cell: cell(1)?, scenarios: (3..n).map(arg).collect() },
+            ("macro", n) if n >= 3 && parts[1] == "record" => Command::MacroRecord { name: arg(2), params: (3..n).map(arg).collect() },
+            ("macro", 2) if parts[1] == "stop" => Command::MacroStop,
+            ("macro", n) if n >= 3 && parts[1] == "run" => Command::MacroRun { name: arg(2), args: (3..n).map(arg).collect() },
+            ("macro", 3) if parts[1] == "remove" => Command::MacroRemove { name: arg(2) },
+            ("macros", 1) => Command::Macros,
+            ("append", n) if n >= 3 => Command::Append { column: arg(1), values: (2..n).map(arg).collect() },
+            (action @ ("protect" | "unprotect"), 2..=3) if parts[1] == "formulas" => {
+                Command::ProtectFormulas { sheet: parts.get(2).map(|sheet| sheet.to_string()), on: action == "protect" }
+            }
+            ("slowlog", 1) => Command::SlowLog { count: None },
+            ("slowlog", 2) if parts[1] == "reset" => Command::SlowLogReset,
+            ("slowlog", 2) => Command::SlowLog { count: Some(count(parts[1])?) },
+            ("audit", 1) => Command::Audit { count: None },
+            ("audit", 2) => Command::Audit { count: Some(count(parts[1])?) },
+            _ => return Err(invalid()),
+        };
+        Ok(command)
+    }
+}
+
+/// Why command text could not be split into words.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Empty,
+    /// A `"` that is never closed, at this byte offset.
+    UnterminatedQuote { at: usize },
+    /// A `\` inside quotes with nothing after it.
+    TrailingEscape,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Empty => f.write_str("Empty command"),
+            TokenError::UnterminatedQuote { at } => write!(f, "Unterminated quote at column {}", at + 1),
+            TokenError::TrailingEscape => f.write_str("Escape at the end of the command"),
+        }
+    }
+}
+
+impl From<TokenError> for ReplyError {
+    fn from(e: TokenError) -> Self {
+        ReplyError::new(ErrorCode::ParseError, e.to_string())
+    }
+}
+
+/// Splits `text` into words at whitespace outside double quotes. Words come
+/// back as written, quotes included, since a formula keeps its quoted text;
+/// [`unquote`] reads one as a plain value. Inside quotes `\` escapes the
+/// next character; outside them it is an ordinary character.
+pub fn tokenize(text: &str) -> Result<Vec<&str>, TokenError> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() && quote.is_none() => {
+                if let Some(start) = start.take() {
+                    words.push(&text[start..i]);
+                }
+                continue;
+            }
+            '"' => quote = if quote.is_some() { None } else { Some(i) },
+            '\\' if quote.is_some() && chars.next().is_none() => return Err(TokenError::TrailingEscape),
+            _ => {}
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(at) = quote {
+        return Err(TokenError::UnterminatedQuote { at });
+    }
+    if let Some(start) = start {
+        words.push(&text[start..]);
+    }
+    if words.is_empty() {
+        return Err(TokenError::Empty);
+    }
+    Ok(words)
+}
+
+/// `word` with its quotes removed and escapes resolved.
+pub fn unquote(word: &str) -> Cow<'_, str> {
+    if !word.contains('"') {
+        return Cow::Borrowed(word);
+    }
+    let mut out = String::with_capacity(word.len());
+    let mut quoted = false;
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// `word` written so that [`tokenize`] and [`unquote`] read it back as one
+/// word: quoted if it is empty or holds whitespace, a quote or a backslash.
+pub fn quote(word: &str) -> Cow<'_, str> {
+    if !word.is_empty() && !word.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return Cow::Borrowed(word);
+    }
+    let mut out = String::with_capacity(word.len() + 2);
+    out.push('"');
+    for c in word.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    Cow::Owned(out)
+}
+
+/// `text` as a `set` expression for a text value: always quoted, with the
+/// escapes of [`quote`], so `Total` is not read as a cell name.
+pub fn quote_text(text: &str) -> String {
+    match quote(text) {
+        Cow::Borrowed(word) => format!("\"{}\"", word),
+        Cow::Owned(quoted) => quoted,
+    }
+}
+
+/// The text of an expression that is one quoted string, such as
+/// `"Quarterly Report 2024"`; `None` for anything else.
+pub fn unquote_text(expr: &str) -> Option<String> {
+    let inner = expr.strip_prefix('"')?.strip_suffix('"')?;
+    let mut text = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return None,
+            '\\' => text.push(chars.next()?),
+            c => text.push(c),
+        }
+    }
+    Some(text)
+}
+
+/// What follows the range of a `<name> <range> ...` command, spacing kept.
+fn after_range(text: &str, parts: &[&str]) -> String {
+    text.trim_start()[parts[0].len()..].trim_start()[parts[1].len()..].trim().to_string()
+}
+
+fn watch_filter(options: &[&str]) -> Result<WatchFilter, ReplyError> {
+    match options {
+        [] => Ok(WatchFilter::All),
+        ["errors"] => Ok(WatchFilter::Errors),
+        ["delta", n] => match n.parse::<f64>() {
+            Ok(n) if n >= 0.0 => Ok(WatchFilter::Delta(n)),
+            _ => Err(ReplyError::new(ErrorCode::ParseError, format!("Invalid delta: {}", n))),
+        },
+        _ => Err(ReplyError::new(ErrorCode::ParseError, "Invalid watch filter")),
+    }
+}
+
+fn admin_command(args: &[&str]) -> Option<AdminCommand> {
+    let command = match args {
+        ["snapshot", "now"] => AdminCommand::Snapshot,
+        ["clients"] => AdminCommand::Clients,
+        ["webhooks"] => AdminCommand::Webhooks,
+        ["webhooks", "dead"] => AdminCommand::WebhooksDead,
+        ["webhook", "add", url] => AdminCommand::WebhookAdd { url: url.to_string(), range: None },
+        ["webhook", "add", url, range] => AdminCommand::WebhookAdd { url: url.to_string(), range: Some(range.to_string()) },
+        ["webhook", "remove", id] => AdminCommand::WebhookRemove { id: id.to_string() },
+        ["promote"] => AdminCommand::Promote,
+        ["kick", target] => AdminCommand::Kick { target: target.to_string() },
+        ["quota", user] => AdminCommand::Quota { user: user.to_string() },
+        ["quota", user, limit @ ("cells" | "bytes" | "commands"), value] => AdminCommand::QuotaSet {
+            user: user.to_string(),
+            limit: limit.to_string(),
+            value: match *value {
+                "off" => None,
+                n => Some(n.parse().ok()?),
+            },
+        },
+        ["broadcast", text @ ..] if !text.is_empty() => {
+            AdminCommand::Broadcast { text: text.iter().map(|word| unquote(word)).collect::<Vec<_>>().join(" ") }
+        }
+        _ => return None,
+    };
+    Some(command)
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Set { cell, expr, if_version, force } => {
+                write!(f, "set {} {}", cell, expr)?;
+                if let Some(version) = if_version {
+                    write!(f, " if-version {}", version)?;
+                }
+                if *force {
+                    write!(f, " {}", FORCE)?;
+                }
+                Ok(())
+            }
+            Command::Get { cell } => write!(f, "get {}", cell),
+            Command::GetRange { range } => write!(f, "get {}", range),
+            Command::GetMany { cells } => write!(f, "get {}", cells.join(" ")),
+            Command::GetVersion { cell } => write!(f, "get {} version", cell),
+            Command::GetArrow { range } => write!(f, "get {} arrow", range),
+            Command::GetAsOf { cell, time } => write!(f, "get {} asof {}", cell, time),
+            Command::Delete { cell } => write!(f, "delete {}", cell),
+            Command::Dump => f.write_str("dump"),
+            Command::Begin => f.write_str("begin"),
+            Command::Commit => f.write_str("commit"),
+            Command::Rollback => f.write_str("rollback"),
+            Command::Use { sheet } => write!(f, "use {}", sheet),
+            Command::Session => f.write_str("session"),
+            Command::SessionOption { key, value } => write!(f, "session {} {}", key, quote(value)),
+            Command::Watch { range, filter: WatchFilter::All } => write!(f, "watch {}", range),
+            Command::Watch { range, filter: WatchFilter::Errors } => write!(f, "watch {} errors", range),
+            Command::Watch { range, filter: WatchFilter::Delta(n) } => write!(f, "watch {} delta {}", range, n),
+            Command::Unwatch { range } => write!(f, "unwatch {}", range.as_deref().unwrap_or("all")),
+            Command::Watches => f.write_str("watches"),
+            Command::Auth { token } => write!(f, "auth {}", token),
+            Command::Admin(admin) => write!(f, "admin {}", admin),
+            Command::Idem { key, command } => write!(f, "idem {} {}", key, command),
+            Command::Export { format, path } => write!(f, "export {} {}", format, quote(path)),
+            Command::ExportRange { format, range, path } => write!(f, "export {} {} {}", format, range, quote(path)),
+            Command::ExportCsv { range, options } => {
+                let content = match options.content {
+                    ExportContent::Values => "values",
+                    ExportContent::Formulas => "formulas",
+                };
+                let delimiter = match options.delimiter {
+                    b'\t' => "tab".to_string(),
+                    c => (c as char).to_string(),
+                };
+                write!(f, "export csv {} {} {}", range, content, delimiter)
+            }
+            Command::Import { format, path } => write!(f, "import {} {}", format, quote(path)),
+            Command::ImportUrl { url, anchor, options } => write!(f, "import url {} {}{}", url, anchor, options),
+            Command::ImportCsv { anchor, data, options } => write!(f, "import csv {}{}\n{}", anchor, options, data),
+            Command::Sync { body } => write!(f, "sync\n{}", body),
+            Command::Backup { path } => write!(f, "backup {}", quote(path)),
+            Command::Restore { path } => write!(f, "restore {}", quote(path)),
+            Command::Run { path } => write!(f, "run {}", quote(path)),
+            Command::Save { path: None } => f.write_str("save"),
+            Command::Save { path: Some(path) } => write!(f, "save {}", quote(path)),
+            Command::Load { path: None } => f.write_str("load"),
+            Command::Load { path: Some(path) } => write!(f, "load {}", quote(path)),
+            Command::Meminfo => f.write_str("meminfo"),
+            Command::Lock { cell, duration } => write!(f, "lock {} for {}", cell, duration),
+            Command::Unlock { cell } => write!(f, "unlock {}", cell),
+            Command::Select { target } => write!(f, "select {}", target),
+            Command::Presence => f.write_str("presence"),
+            Command::Replicate => f.write_str("replicate"),
+            Command::AlertWhen { args } => write!(f, "alert when {}", args.join(" ")),
+            Command::AlertRemove { id } => write!(f, "alert remove {}", id),
+            Command::Alerts => f.write_str("alerts"),
+            Command::Query { text } => write!(f, "query {}", text),
+            Command::Filter { range, conditions } => write!(f, "filter {} {}", range, conditions),
+            Command::Pivot { range, spec } => write!(f, "pivot {} {}", range, spec),
+            Command::GroupBy { range, spec } => write!(f, "groupby {} {}", range, spec),
+            Command::Dedupe { range, spec } => write!(f, "dedupe {} {}", range, spec),
+            Command::View { sheet: None } => f.write_str("view"),
+            Command::View { sheet: Some(sheet) } => write!(f, "view {}", sheet),
+            Command::ViewSet { change } => write!(f, "view {}", change),
+            Command::ChartDefine { name, chart } => write!(f, "chart define {} {}", name, chart),
+            Command::ChartRemove { name } => write!(f, "chart remove {}", name),
+            Command::Charts => f.write_str("charts"),
+            Command::ChartData { name } => write!(f, "chart data {}", name),
+            Command::Alias { name, cell } => write!(f, "alias {} {}", name, cell),
+            Command::AliasRemove { name } => write!(f, "alias remove {}", name),
+            Command::Aliases => f.write_str("aliases"),
+            Command::GraphExport { range: None } => f.write_str("graph export dot"),
+            Command::GraphExport { range: Some(range) } => write!(f, "graph export dot {}", range),
+            Command::Clear { range } => write!(f, "clear {}", range),
+            Command::WhereUsed { cell, depth: None } => write!(f, "whereused {}", cell),
+            Command::WhereUsed { cell, depth: Some(depth) } => write!(f, "whereused {} depth {}", cell, depth),
+            Command::Describe { range, bins: None } => write!(f, "describe {}", range),
+            Command::Describe { range, bins: Some(bins) } => write!(f, "describe {} bins {}", range, bins),
+            Command::Shift { range, direction, by } => write!(f, "shift {} {} {}", range, direction, by),
+            Command::Transpose { range, anchor, mode: TransposeMode::Values } => write!(f, "transpose {} {}", range, anchor),
+            Command::Transpose { range, anchor, mode: TransposeMode::Formulas } => {
+                write!(f, "transpose {} {} formulas", range, anchor)
+            }
+            Command::GoalSeek { cell, target, input } => write!(f, "goalseek {} to {} by changing {}", cell, target, input),
+            Command::ScenarioCreate { name } => write!(f, "scenario create {}", name),
+            Command::ScenarioSet { name, cell, expr } => write!(f, "scenario set {} {} {}", name, cell, expr),
+            Command::ScenarioRemove { name } => write!(f, "scenario remove {}", name),
+            Command::Scenarios => f.write_str("scenarios"),
+            Command::Eval { cell, scenarios } => write!(f, "eval {} in {}", cell, scenarios.join(" ")),
+            Command::MacroRecord { name, params } if params.is_empty() => write!(f, "macro record {}", name),
+            Command::MacroRecord { name, params } => write!(f, "macro record {} {}", name, params.join(" ")),
+            Command::MacroStop => f.write_str("macro stop"),
+            Command::MacroRun { name, args } if args.is_empty() => write!(f, "macro run {}", name),
+            Command::MacroRun { name, args } => write!(f, "macro run {} {}", name, args.join(" ")),
+            Command::MacroRemove { name } => write!(f, "macro remove {}", name),
+            Command::Macros => f.write_str("macros"),
+            Command::Append { column, values } => write!(f, "append {} {}", column, values.join(" ")),
+            Command::ProtectFormulas { sheet, on } => {
+                f.write_str(if *on { "protect formulas" } else { "unprotect formulas" })?;
+                match sheet {
+                    Some(sheet) => write!(f, " {}", sheet),
+                    None => Ok(()),
+                }
+            }
+            Command::SlowLog { count: None } => f.write_str("slowlog"),
+            Command::SlowLog { count: Some(n) } => write!(f, "slowlog {}", n),
+            Command::SlowLogReset => f.write_str("slowlog reset"),
+            Command::Audit { count: None } => f.write_str("audit"),
+            Command::Audit { count: Some(n) } => write!(f, "audit {}", n),
+            Command::Invalid { text, .. } => f.write_str(text),
+        }
+    }
+}
+
+impl fmt::Display for AdminCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminCommand::Snapshot => f.write_str("snapshot now"),
+            AdminCommand::Clients => f.write_str("clients"),
+            AdminCommand::Webhooks => f.write_str("webhooks"),
+            AdminCommand::WebhooksDead => f.write_str("webhooks dead"),
+            AdminCommand::WebhookAdd { url, range: None } => write!(f, "webhook add {}", url),
+            AdminCommand::WebhookAdd { url, range: Some(range) } => write!(f, "webhook add {} {}", url, range),
+            AdminCommand::WebhookRemove { id } => write!(f, "webhook remove {}", id),
+            AdminCommand::Promote => f.write_str("promote"),
+            AdminCommand::Kick { target } => write!(f, "kick {}", target),
+            AdminCommand::Quota { user } => write!(f, "quota {}", user),
+            AdminCommand::QuotaSet { user, limit, value: None } => write!(f, "quota {} {} off", user, limit),
+            AdminCommand::QuotaSet { user, limit, value: Some(n) } => write!(f, "quota {} {} {}", user, limit, n),
+            AdminCommand::Broadcast { text } if text.contains(['"', '\\']) => write!(f, "broadcast {}", quote(text)),
+            AdminCommand::Broadcast { text } => write!(f, "broadcast {}", text),
+        }
+    }
+}
+
+impl From<String> for Command {
+    fn from(text: String) -> Self {
+        Command::parse(&text)
+    }
+}
+
+impl From<&str> for Command {
+    fn from(text: &str) -> Self {
+        Command::parse(text)
+    }
+}
+
+impl From<Command> for String {
+    fn from(command: Command) -> Self {
+        command.to_string()
+    }
+}
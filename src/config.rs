@@ -0,0 +1,579 @@
+use crate::connect::{Manager, OverloadPolicy, ProtocolErrorPolicy, RateLimit, DEFAULT_MAX_FRAME_SIZE};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Everything needed to start a server. Implements [`Manager`], so it can be
+/// passed straight to [`crate::start_server`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: Vec<String>,
+    pub idle_timeout_secs: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub overload_policy: OverloadPolicy,
+    pub rate_limit: Option<RateLimit>,
+    pub max_frame_size: usize,
+    pub protocol_error_policy: ProtocolErrorPolicy,
+    /// Where the workbook is saved to and loaded from.
+    pub persistence_path: Option<PathBuf>,
+    /// Address for the Prometheus `/metrics` endpoint.
+    pub metrics_bind: Option<String>,
+    /// Append-only file that every mutating command is recorded to.
+    pub audit_path: Option<PathBuf>,
+    /// Token a client sends with `auth` to unlock the `admin` commands.
+    pub admin_token: Option<String>,
+    /// Tokens users authenticate with, by user name.
+    pub users: BTreeMap<String, String>,
+    /// Limits on each user unless `admin quota` sets their own; unset, users are not limited.
+    pub user_quotas: Option<crate::quota::UserQuotas>,
+    pub command_timeout_ms: Option<u64>,
+    /// Threads that run commands for all connections; unset runs each on its connection's thread.
+    pub command_workers: Option<usize>,
+    /// Threads that serve connections, capping how many are open at once; unset spawns one per connection.
+    pub connection_threads: Option<usize>,
+    /// Queue replies and pushes per connection and send them together at least this often.
+    pub coalesce_window_ms: Option<u64>,
+    /// Save to `persistence_path` at most this long after the first unsaved change.
+    pub autosave_interval_secs: Option<u64>,
+    /// Quiet period after an edit before autosave writes.
+    pub autosave_debounce_ms: u64,
+    /// Write-ahead log replayed at startup and appended to before every change.
+    pub wal_path: Option<PathBuf>,
+    /// Compact the write-ahead log into a snapshot once it holds more entries than this.
+    pub wal_compact_after: Option<u64>,
+    /// SQLite database holding cell values, for builds with the `sqlite` feature.
+    pub sqlite_path: Option<PathBuf>,
+    /// Redis holding cell values, shared with other servers, for builds with the `redis` feature.
+    pub redis_url: Option<String>,
+    /// sled database holding cell values, for builds with the `sled` feature.
+    pub sled_path: Option<PathBuf>,
+    /// Keep in-memory cells column by column, for sheets dominated by column aggregates.
+    pub columnar: bool,
+    /// Spread in-memory cells over this many shards, so writes to different cells run in parallel.
+    pub cell_shards: Option<usize>,
+    /// Publish in-memory cells as immutable versions, so reads never wait on writes.
+    pub read_mostly: bool,
+    /// Reject sets once cells, formulas and history are estimated to take this many bytes.
+    pub memory_limit_bytes: Option<u64>,
+    /// Round numbers in replies to this many significant digits; sessions may override it.
+    pub precision: Option<u32>,
+    /// Rows, columns, cells and formula length any one sheet may use.
+    pub quotas: crate::quota::SheetQuotas,
+    /// Keep commands that take at least this long for `slowlog`.
+    pub slowlog_threshold_ms: u64,
+    /// Push every committed change to all clients, or to those that opt in.
+    pub change_broadcast: crate::clients::ChangeBroadcast,
+    /// Accept `sync` from replicas that edit offline.
+    pub crdt_sync: bool,
+    /// How racing writes to a cell are settled. Unset, transactions fail on
+    /// a conflict and syncs keep the later stamp.
+    pub conflict_policy: Option<crate::conflict::ConflictPolicy>,
+    /// Follow the primary at this address, serving reads only, until `admin promote`.
+    pub replica_of: Option<String>,
+    /// Reject every command that would change cells, serving reads, watches and exports.
+    pub read_only: bool,
+    /// Sheets other nodes serve, by name, with the address of each node.
+    pub remote_sheets: BTreeMap<String, String>,
+    /// Endpoints posted every change to a cell in their range.
+    pub webhooks: Vec<crate::webhooks::Webhook>,
+    /// Where webhook deliveries that failed every try are appended, one JSON object per line.
+    pub webhook_dead_letter_path: Option<PathBuf>,
+    /// MQTT broker, as `host:port`, to publish every change to.
+    pub mqtt_broker: Option<String>,
+    /// Topic per change; `{sheet}` and `{cell}` are filled in.
+    pub mqtt_topic: String,
+    pub mqtt_client_id: String,
+    /// 0, 1 or 2.
+    pub mqtt_qos: u8,
+    /// Have the broker keep each cell's latest value for late subscribers.
+    pub mqtt_retain: bool,
+    /// Kafka brokers, as `host:port`, to produce every committed change to.
+    pub kafka_brokers: Vec<String>,
+    pub kafka_topic: String,
+    /// When formulas calling `NOW()`, `RAND()` or `FETCH()` are re-evaluated:
+    /// `every 30s` or a five-field cron expression in UTC. Unset, `FETCH`
+    /// cells are refreshed every `fetch_refresh_secs` and others only when set.
+    pub recalc_schedule: Option<String>,
+    /// Keep this many timestamped versions of each cell, for `get <cell> asof <time>`.
+    pub history_max_versions: Option<usize>,
+    /// Drop cell versions superseded longer ago than this.
+    pub history_max_age_secs: Option<u64>,
+    /// Hosts `import url` may fetch CSV from, for builds with the `import-url` feature.
+    pub import_url_hosts: Vec<String>,
+    pub import_url_timeout_secs: u64,
+    /// Largest CSV body `import url` reads.
+    pub import_url_max_bytes: u64,
+    /// Directories `run` may read scripts from.
+    pub script_dirs: Vec<PathBuf>,
+    /// Hosts `FETCH` cells may read from, for builds with the `import-url` feature.
+    pub fetch_hosts: Vec<String>,
+    pub fetch_timeout_secs: u64,
+    /// Fetch each URL at most this often; in between, cells share the cached body.
+    pub fetch_refresh_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: vec!["127.0.0.1:8080".to_string()],
+            idle_timeout_secs: None,
+            max_connections: None,
+            overload_policy: OverloadPolicy::Reject,
+            rate_limit: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            protocol_error_policy: ProtocolErrorPolicy::Recover,
+            persistence_path: None,
+            metrics_bind: None,
+            audit_path: None,
+            admin_token: None,
+            users: BTreeMap::new(),
+            user_quotas: None,
+            command_timeout_ms: None,
+            command_workers: None,
+            connection_threads: None,
+            coalesce_window_ms: None,
+            autosave_interval_secs: None,
+            autosave_debounce_ms: crate::autosave::DEFAULT_DEBOUNCE.as_millis() as u64,
+            wal_path: None,
+            wal_compact_after: None,
+            sqlite_path: None,
+            redis_url: None,
+            sled_path: None,
+            columnar: false,
+            cell_shards: None,
+            read_mostly: false,
+            memory_limit_bytes: None,
+            precision: None,
+            quotas: crate::quota::SheetQuotas::default(),
+            slowlog_threshold_ms: crate::slowlog::DEFAULT_SLOWLOG_THRESHOLD.as_millis() as u64,
+            change_broadcast: crate::clients::ChangeBroadcast::Off,
+            crdt_sync: false,
+            conflict_policy: None,
+            replica_of: None,
+            read_only: false,
+            remote_sheets: BTreeMap::new(),
+            webhooks: Vec::new(),
+            webhook_dead_letter_path: None,
+            mqtt_broker: None,
+            mqtt_topic: "rsheet/{sheet}/{cell}".to_string(),
+            mqtt_client_id: "rsheet".to_string(),
+            mqtt_qos: 1,
+            mqtt_retain: false,
+            kafka_brokers: Vec::new(),
+            kafka_topic: "rsheet-changes".to_string(),
+            recalc_schedule: None,
+            history_max_versions: None,
+            history_max_age_secs: None,
+            import_url_hosts: Vec::new(),
+            import_url_timeout_secs: 30,
+            import_url_max_bytes: 10 * 1024 * 1024,
+            script_dirs: Vec::new(),
+            fetch_hosts: Vec::new(),
+            fetch_timeout_secs: 10,
+            fetch_refresh_secs: crate::feeds::DEFAULT_REFRESH.as_secs(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder { config: ServerConfig { bind: Vec::new(), ..ServerConfig::default() } }
+    }
+
+    pub fn from_toml_str(text: &str) -> Result<Self, Box<dyn Error>> {
+        let config: ServerConfig = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::from_toml_str(&std::fs::read_to_string(path)?)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.bind.is_empty() {
+            return Err("config needs at least one bind address".into());
+        }
+        if self.max_connections == Some(0) {
+            return Err("max_connections must be at least 1".into());
+        }
+        if matches!(self.precision, Some(digits) if !(1..=crate::numeric::MAX_PRECISION).contains(&digits)) {
+            return Err(format!("precision must be 1 to {}", crate::numeric::MAX_PRECISION).into());
+        }
+        if self.autosave_interval_secs.is_some() && self.persistence_path.is_none() {
+            return Err("autosave_interval_secs needs persistence_path".into());
+        }
+        if self.sqlite_path.is_some() && !cfg!(feature = "sqlite") {
+            return Err("sqlite_path needs a build with the sqlite feature".into());
+        }
+        if self.redis_url.is_some() && !cfg!(feature = "redis") {
+            return Err("redis_url needs a build with the redis feature".into());
+        }
+        if self.sled_path.is_some() && !cfg!(feature = "sled") {
+            return Err("sled_path needs a build with the sled feature".into());
+        }
+        if !self.import_url_hosts.is_empty() && !cfg!(feature = "import-url") {
+            return Err("import_url_hosts needs a build with the import-url feature".into());
+        }
+        if !self.fetch_hosts.is_empty() && !cfg!(feature = "import-url") {
+            return Err("fetch_hosts needs a build with the import-url feature".into());
+        }
+        if self.fetch_refresh_secs == 0 {
+            return Err("fetch_refresh_secs must be at least 1".into());
+        }
+        if self.command_workers == Some(0) {
+            return Err("command_workers must be at least 1".into());
+        }
+        if self.connection_threads == Some(0) {
+            return Err("connection_threads must be at least 1".into());
+        }
+        if self.coalesce_window_ms == Some(0) {
+            return Err("coalesce_window_ms must be at least 1".into());
+        }
+        if !self.webhooks.is_empty() && !cfg!(feature = "webhooks") {
+            return Err("webhooks needs a build with the webhooks feature".into());
+        }
+        if self.mqtt_broker.is_some() && !cfg!(feature = "mqtt") {
+            return Err("mqtt_broker needs a build with the mqtt feature".into());
+        }
+        if !self.kafka_brokers.is_empty() && !cfg!(feature = "kafka") {
+            return Err("kafka_brokers needs a build with the kafka feature".into());
+        }
+        if self.mqtt_qos > 2 {
+            return Err("mqtt_qos must be 0, 1 or 2".into());
+        }
+        if let Some(schedule) = &self.recalc_schedule {
+            schedule.parse::<crate::scheduler::Schedule>()?;
+        }
+        for webhook in &self.webhooks {
+            webhook.validate()?;
+        }
+        if let Some(sheet) = self.remote_sheets.keys().find(|sheet| !crate::address::is_valid_sheet_name(sheet)) {
+            return Err(format!("remote_sheets names an invalid sheet: {}", sheet).into());
+        }
+        if self.cell_shards == Some(0) {
+            return Err("cell_shards must be at least 1".into());
+        }
+        let stores = [
+            self.sqlite_path.is_some(),
+            self.redis_url.is_some(),
+            self.sled_path.is_some(),
+            self.columnar,
+            self.cell_shards.is_some(),
+            self.read_mostly,
+        ];
+        if stores.iter().filter(|&&set| set).count() > 1 {
+            return Err("only one of sqlite_path, redis_url, sled_path, columnar, cell_shards and read_mostly can be set".into());
+        }
+        Ok(())
+    }
+}
+
+impl Manager for ServerConfig {
+    fn address(&self) -> &str {
+        &self.bind[0]
+    }
+
+    fn addresses(&self) -> Vec<String> {
+        self.bind.clone()
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs.map(Duration::from_secs)
+    }
+
+    fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    fn overload_policy(&self) -> OverloadPolicy {
+        self.overload_policy
+    }
+
+    fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit
+    }
+
+    fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    fn protocol_error_policy(&self) -> ProtocolErrorPolicy {
+        self.protocol_error_policy
+    }
+
+    fn metrics_address(&self) -> Option<String> {
+        self.metrics_bind.clone()
+    }
+
+    fn command_timeout(&self) -> Option<Duration> {
+        self.command_timeout_ms.map(Duration::from_millis)
+    }
+
+    fn command_workers(&self) -> Option<usize> {
+        self.command_workers
+    }
+
+    fn connection_threads(&self) -> Option<usize> {
+        self.connection_threads
+    }
+
+    fn coalesce_window(&self) -> Option<Duration> {
+        self.coalesce_window_ms.map(Duration::from_millis)
+    }
+}
+
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    pub fn bind(mut self, address: impl Into<String>) -> Self {
+        self.config.bind.push(address.into());
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout_secs = Some(timeout.as_secs());
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize, policy: OverloadPolicy) -> Self {
+        self.config.max_connections = Some(max);
+        self.config.overload_policy = policy;
+        self
+    }
+
+    pub fn rate_limit(mut self, per_second: f64, burst: u32) -> Self {
+        self.config.rate_limit = Some(RateLimit { per_second, burst });
+        self
+    }
+
+    pub fn max_frame_size(mut self, max: usize) -> Self {
+        self.config.max_frame_size = max;
+        self
+    }
+
+    pub fn protocol_error_policy(mut self, policy: ProtocolErrorPolicy) -> Self {
+        self.config.protocol_error_policy = policy;
+        self
+    }
+
+    pub fn persistence_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.persistence_path = Some(path.into());
+        self
+    }
+
+    pub fn metrics_bind(mut self, address: impl Into<String>) -> Self {
+        self.config.metrics_bind = Some(address.into());
+        self
+    }
+
+    pub fn audit_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.audit_path = Some(path.into());
+        self
+    }
+
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.config.admin_token = Some(token.into());
+        self
+    }
+
+    pub fn user(mut self, user: impl Into<String>, token: impl Into<String>) -> Self {
+        self.config.users.insert(user.into(), token.into());
+        self
+    }
+
+    pub fn user_quotas(mut self, quotas: crate::quota::UserQuotas) -> Self {
+        self.config.user_quotas = Some(quotas);
+        self
+    }
+
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.config.command_timeout_ms = Some(timeout.as_millis() as u64);
+        self
+    }
+
+    pub fn command_workers(mut self, workers: usize) -> Self {
+        self.config.command_workers = Some(workers);
+        self
+    }
+
+    pub fn connection_threads(mut self, threads: usize) -> Self {
+        self.config.connection_threads = Some(threads);
+        self
+    }
+
+    pub fn coalesce_window(mut self, window: Duration) -> Self {
+        self.config.coalesce_window_ms = Some(window.as_millis() as u64);
+        self
+    }
+
+    pub fn autosave(mut self, interval: Duration, debounce: Duration) -> Self {
+        self.config.autosave_interval_secs = Some(interval.as_secs());
+        self.config.autosave_debounce_ms = debounce.as_millis() as u64;
+        self
+    }
+
+    pub fn wal_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.wal_path = Some(path.into());
+        self
+    }
+
+    pub fn wal_compact_after(mut self, entries: u64) -> Self {
+        self.config.wal_compact_after = Some(entries);
+        self
+    }
+
+    pub fn sqlite_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.sqlite_path = Some(path.into());
+        self
+    }
+
+    pub fn redis_url(mut self, url: impl Into<String>) -> Self {
+        self.config.redis_url = Some(url.into());
+        self
+    }
+
+    pub fn sled_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.sled_path = Some(path.into());
+        self
+    }
+
+    pub fn columnar(mut self) -> Self {
+        self.config.columnar = true;
+        self
+    }
+
+    pub fn memory_limit_bytes(mut self, bytes: u64) -> Self {
+        self.config.memory_limit_bytes = Some(bytes);
+        self
+    }
+
+    pub fn precision(mut self, digits: u32) -> Self {
+        self.config.precision = Some(digits);
+        self
+    }
+
+    pub fn quotas(mut self, quotas: crate::quota::SheetQuotas) -> Self {
+        self.config.quotas = quotas;
+        self
+    }
+
+    pub fn slowlog_threshold(mut self, threshold: Duration) -> Self {
+        self.config.slowlog_threshold_ms = threshold.as_millis() as u64;
+        self
+    }
+
+    pub fn change_broadcast(mut self, mode: crate::clients::ChangeBroadcast) -> Self {
+        self.config.change_broadcast = mode;
+        self
+    }
+
+    pub fn crdt_sync(mut self) -> Self {
+        self.config.crdt_sync = true;
+        self
+    }
+
+    pub fn conflict_policy(mut self, policy: crate::conflict::ConflictPolicy) -> Self {
+        self.config.conflict_policy = Some(policy);
+        self
+    }
+
+    pub fn replica_of(mut self, primary: impl Into<String>) -> Self {
+        self.config.replica_of = Some(primary.into());
+        self
+    }
+
+    pub fn read_only(mut self) -> Self {
+        self.config.read_only = true;
+        self
+    }
+
+    pub fn webhook(mut self, webhook: crate::webhooks::Webhook) -> Self {
+        self.config.webhooks.push(webhook);
+        self
+    }
+
+    pub fn webhook_dead_letter_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.webhook_dead_letter_path = Some(path.into());
+        self
+    }
+
+    pub fn mqtt(mut self, broker: impl Into<String>, topic: impl Into<String>) -> Self {
+        self.config.mqtt_broker = Some(broker.into());
+        self.config.mqtt_topic = topic.into();
+        self
+    }
+
+    pub fn kafka(mut self, brokers: Vec<String>, topic: impl Into<String>) -> Self {
+        self.config.kafka_brokers = brokers;
+        self.config.kafka_topic = topic.into();
+        self
+    }
+
+    /// Serves `sheet` from the node at `node` instead of this one.
+    pub fn remote_sheet(mut self, sheet: impl Into<String>, node: impl Into<String>) -> Self {
+        self.config.remote_sheets.insert(sheet.into(), node.into());
+        self
+    }
+
+    pub fn read_mostly(mut self) -> Self {
+        self.config.read_mostly = true;
+        self
+    }
+
+    pub fn cell_shards(mut self, shards: usize) -> Self {
+        self.config.cell_shards = Some(shards);
+        self
+    }
+
+    pub fn recalc_schedule(mut self, schedule: impl Into<String>) -> Self {
+        self.config.recalc_schedule = Some(schedule.into());
+        self
+    }
+
+    pub fn history(mut self, max_versions: usize, max_age: Option<Duration>) -> Self {
+        self.config.history_max_versions = Some(max_versions);
+        self.config.history_max_age_secs = max_age.map(|age| age.as_secs());
+        self
+    }
+
+    /// Lets `import url` fetch from `host`.
+    pub fn import_url_host(mut self, host: impl Into<String>) -> Self {
+        self.config.import_url_hosts.push(host.into());
+        self
+    }
+
+    pub fn import_url_limits(mut self, timeout: Duration, max_bytes: u64) -> Self {
+        self.config.import_url_timeout_secs = timeout.as_secs();
+        self.config.import_url_max_bytes = max_bytes;
+        self
+    }
+
+    /// Lets `run` read scripts from `dir`.
+    pub fn script_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.script_dirs.push(dir.into());
+        self
+    }
+
+    /// Lets `FETCH` cells read from `host`.
+    pub fn fetch_host(mut self, host: impl Into<String>) -> Self {
+        self.config.fetch_hosts.push(host.into());
+        self
+    }
+
+    pub fn fetch_limits(mut self, timeout: Duration, refresh: Duration) -> Self {
+        self.config.fetch_timeout_secs = timeout.as_secs();
+        self.config.fetch_refresh_secs = refresh.as_secs();
+        self
+    }
+
+    pub fn build(self) -> Result<ServerConfig, Box<dyn Error>> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
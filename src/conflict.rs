@@ -0,0 +1,67 @@
+use crate::CellValue;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// How a write that raced another to the same cell is settled: a
+/// transaction's write to a cell committed by someone else after it began,
+/// or a synced write made without seeing the cell's current write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// The write being committed or synced replaces the other.
+    LastWriterWins,
+    /// The write already in place stays; the later one is dropped.
+    FirstWriterWins,
+    /// A transaction fails with `ErrorCode::Conflict`; a synced write is
+    /// dropped and listed in the reply's conflicts.
+    Reject,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last-writer-wins" => Ok(ConflictPolicy::LastWriterWins),
+            "first-writer-wins" => Ok(ConflictPolicy::FirstWriterWins),
+            "reject" => Ok(ConflictPolicy::Reject),
+            _ => Err(format!("Unknown conflict policy: {}", s)),
+        }
+    }
+}
+
+impl ConflictPolicy {
+    pub fn resolve(&self) -> Resolution {
+        match self {
+            ConflictPolicy::LastWriterWins => Resolution::Ours,
+            ConflictPolicy::FirstWriterWins => Resolution::Theirs,
+            ConflictPolicy::Reject => Resolution::Reject,
+        }
+    }
+}
+
+/// Two writes to one cell, as passed to a [`MergeHook`]. `None` is an empty cell.
+#[derive(Clone, Copy, Debug)]
+pub struct Conflict<'a> {
+    pub cell: &'a str,
+    /// What the cell held when the transaction began; `None` for synced
+    /// writes, whose base is not known.
+    pub base: Option<&'a CellValue>,
+    /// What the cell holds now.
+    pub theirs: Option<&'a CellValue>,
+    /// What the incoming write would leave.
+    pub ours: Option<&'a CellValue>,
+}
+
+/// What a [`MergeHook`] decides.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Resolution {
+    Ours,
+    Theirs,
+    Reject,
+    /// Store this instead, evaluated as `set` would.
+    Expr(String),
+}
+
+/// Settles each conflict in place of a [`ConflictPolicy`].
+pub type MergeHook = Box<dyn Fn(&Conflict) -> Resolution + Send + Sync>;
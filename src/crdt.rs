@@ -0,0 +1,172 @@
+use crate::CellValue;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Replica id the server stamps ordinary commands with. Clients must pick another.
+pub const SERVER_REPLICA: &str = "server";
+
+/// Orders writes to a cell: the later time wins and the replica id breaks
+/// ties, so every replica settles on the same winner.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Stamp {
+    /// Hybrid clock: milliseconds since the epoch, bumped past anything seen.
+    pub time: u64,
+    pub replica: String,
+}
+
+/// What a write leaves in its cell.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Content {
+    /// As `set` takes it: a number or a formula.
+    Expr(String),
+    /// A literal, e.g. imported text.
+    Value(CellValue),
+    Deleted,
+}
+
+/// One write to one cell.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Op {
+    pub cell: String,
+    pub content: Content,
+    pub stamp: Stamp,
+}
+
+/// Highest stamp time seen from each replica. A replica's own writes are
+/// stamped in increasing order, so this says which of them a peer lacks.
+pub type VectorClock = BTreeMap<String, u64>;
+
+/// A last-writer-wins register per cell, tombstones included, and the clock
+/// of everything merged so far.
+#[derive(Debug, Default)]
+pub struct Registers {
+    time: u64,
+    cells: HashMap<String, Op>,
+    seen: VectorClock,
+}
+
+impl Registers {
+    /// Stamps a write made by `replica` itself and applies it.
+    pub fn write(&mut self, replica: &str, cell: &str, content: Content) -> Op {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        self.time = (self.time + 1).max(now);
+        let op = Op { cell: cell.to_string(), content, stamp: Stamp { time: self.time, replica: replica.to_string() } };
+        self.merge(op.clone());
+        op
+    }
+
+    /// Whether `op` would replace what the cell holds.
+    pub fn wins(&self, op: &Op) -> bool {
+        self.cells.get(&op.cell).is_none_or(|current| current.stamp < op.stamp)
+    }
+
+    /// Merges `op`, returning whether it won.
+    pub fn merge(&mut self, op: Op) -> bool {
+        if !self.wins(&op) {
+            self.observe(&op.stamp);
+            return false;
+        }
+        self.replace(op);
+        true
+    }
+
+    /// Makes `op` the cell's write even if a later one was stamped meanwhile,
+    /// e.g. by the server while applying `op`.
+    pub fn replace(&mut self, op: Op) {
+        self.observe(&op.stamp);
+        self.cells.insert(op.cell.clone(), op);
+    }
+
+    fn observe(&mut self, stamp: &Stamp) {
+        self.time = self.time.max(stamp.time);
+        let seen = self.seen.entry(stamp.replica.clone()).or_default();
+        *seen = (*seen).max(stamp.time);
+    }
+
+    pub fn get(&self, cell: &str) -> Option<&Op> {
+        self.cells.get(cell)
+    }
+
+    /// Winning writes `clock` has not seen, oldest first.
+    pub fn since(&self, clock: &VectorClock) -> Vec<Op> {
+        let mut ops: Vec<Op> = self
+            .cells
+            .values()
+            .filter(|op| op.stamp.time > clock.get(&op.stamp.replica).copied().unwrap_or(0))
+            .cloned()
+            .collect();
+        ops.sort_by(|a, b| a.stamp.cmp(&b.stamp));
+        ops
+    }
+
+    pub fn clock(&self) -> &VectorClock {
+        &self.seen
+    }
+}
+
+/// What a replica sends on reconnect: who it is, what it has seen, and the
+/// writes it made since it last synced.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub replica: String,
+    pub seen: VectorClock,
+    pub ops: Vec<Op>,
+}
+
+/// The winning writes the replica had not seen.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub ops: Vec<Op>,
+    /// The replica's writes turned away under `ConflictPolicy::Reject`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<Op>,
+}
+
+/// A client's copy of the cells, edited offline and merged with the server
+/// by [`crate::client::RSheetClient::sync`].
+#[derive(Debug)]
+pub struct Replica {
+    id: String,
+    registers: Registers,
+    pending: Vec<Op>,
+}
+
+impl Replica {
+    /// `id` must be unique among the server's replicas and not [`SERVER_REPLICA`].
+    pub fn new(id: impl Into<String>) -> Self {
+        Replica { id: id.into(), registers: Registers::default(), pending: Vec::new() }
+    }
+
+    pub fn set(&mut self, cell: &str, expr: &str) {
+        let op = self.registers.write(&self.id, cell, Content::Expr(expr.to_string()));
+        self.pending.push(op);
+    }
+
+    pub fn delete(&mut self, cell: &str) {
+        let op = self.registers.write(&self.id, cell, Content::Deleted);
+        self.pending.push(op);
+    }
+
+    /// What `cell` holds as far as this replica knows.
+    pub fn get(&self, cell: &str) -> Option<&Content> {
+        self.registers.get(cell).map(|op| &op.content).filter(|content| **content != Content::Deleted)
+    }
+
+    /// Writes not yet sent to the server.
+    pub fn pending(&self) -> &[Op] {
+        &self.pending
+    }
+
+    pub fn sync_request(&self) -> SyncRequest {
+        SyncRequest { replica: self.id.clone(), seen: self.registers.clock().clone(), ops: self.pending.clone() }
+    }
+
+    /// Merges the server's answer to [`Replica::sync_request`], returning the
+    /// writes that changed this replica's cells.
+    pub fn merge(&mut self, response: SyncResponse) -> Vec<Op> {
+        self.pending.clear();
+        response.ops.into_iter().filter(|op| self.registers.merge(op.clone())).collect()
+    }
+}
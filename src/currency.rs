@@ -0,0 +1,83 @@
+use crate::replies::{ErrorCode, ReplyError};
+use crate::CellValue;
+
+/// ISO 4217 codes of the currencies in circulation, in order.
+const CODES: &[&str] = &[
+    "AED", "AFN", "ALL", "AMD", "ANG", "AOA", "ARS", "AUD", "AWG", "AZN", "BAM", "BBD", "BDT", "BGN", "BHD", "BIF",
+    "BMD", "BND", "BOB", "BRL", "BSD", "BTN", "BWP", "BYN", "BZD", "CAD", "CDF", "CHF", "CLP", "CNY", "COP", "CRC",
+    "CUP", "CVE", "CZK", "DJF", "DKK", "DOP", "DZD", "EGP", "ERN", "ETB", "EUR", "FJD", "FKP", "GBP", "GEL", "GHS",
+    "GIP", "GMD", "GNF", "GTQ", "GYD", "HKD", "HNL", "HTG", "HUF", "IDR", "ILS", "INR", "IQD", "IRR", "ISK", "JMD",
+    "JOD", "JPY", "KES", "KGS", "KHR", "KMF", "KPW", "KRW", "KWD", "KYD", "KZT", "LAK", "LBP", "LKR", "LRD", "LSL",
+    "LYD", "MAD", "MDL", "MGA", "MKD", "MMK", "MNT", "MOP", "MRU", "MUR", "MVR", "MWK", "MXN", "MYR", "MZN", "NAD",
+    "NGN", "NIO", "NOK", "NPR", "NZD", "OMR", "PAB", "PEN", "PGK", "PHP", "PKR", "PLN", "PYG", "QAR", "RON", "RSD",
+    "RUB", "RWF", "SAR", "SBD", "SCR", "SDG", "SEK", "SGD", "SHP", "SLE", "SOS", "SRD", "SSP", "STN", "SVC", "SYP",
+    "SZL", "THB", "TJS", "TMT", "TND", "TOP", "TRY", "TTD", "TWD", "TZS", "UAH", "UGX", "USD", "UYU", "UZS", "VES",
+    "VND", "VUV", "WST", "XAF", "XCD", "XOF", "XPF", "YER", "ZAR", "ZMW", "ZWL",
+];
+
+/// Whether `code` is an ISO 4217 currency code, such as `USD`.
+pub fn is_code(code: &str) -> bool {
+    CODES.binary_search(&code).is_ok()
+}
+
+/// A currency literal: an amount followed by its code, as in `19.99USD`
+/// or `-5EUR`. `None` for anything else.
+pub fn parse(text: &str) -> Option<CellValue> {
+    let split = text.len().checked_sub(3).filter(|&i| text.is_char_boundary(i))?;
+    let (amount, code) = text.split_at(split);
+    // `f64` also reads `inf` and `NaN`, which are not amounts.
+    if amount.is_empty() || !amount.bytes().all(|b| b.is_ascii_digit() || b == b'.' || b == b'-') || !is_code(code) {
+        return None;
+    }
+    let amount = amount.parse().ok()?;
+    Some(CellValue::Currency { amount, code: code.to_string() })
+}
+
+/// `lhs <op> rhs` when either side is money; `None` when neither is.
+/// Sums and differences need one currency on both sides. Money multiplies
+/// and divides by plain numbers, and money divided by money of the same
+/// currency is their ratio.
+pub fn arithmetic(op: char, lhs: &CellValue, rhs: &CellValue) -> Option<Result<CellValue, ReplyError>> {
+    use CellValue::{Currency, Number};
+
+    let money = |amount: f64, code: &str| Ok(Currency { amount, code: code.to_string() });
+    let result = match (op, lhs, rhs) {
+        ('+', Currency { amount: a, code }, Currency { amount: b, code: other }) if code == other => money(a + b, code),
+        ('-', Currency { amount: a, code }, Currency { amount: b, code: other }) if code == other => money(a - b, code),
+        ('*', Currency { amount, code }, Number(n)) | ('*', Number(n), Currency { amount, code }) => money(amount * n, code),
+        ('/', Currency { .. }, Number(0.0) | Currency { amount: 0.0, .. }) => {
+            Err(ReplyError::new(ErrorCode::DivByZero, "Division by zero"))
+        }
+        ('/', Currency { amount, code }, Number(n)) => money(amount / n, code),
+        ('/', Currency { amount: a, code }, Currency { amount: b, code: other }) if code == other => Ok(Number(a / b)),
+        (_, Currency { code, .. }, Currency { code: other, .. }) if code != other => {
+            let message = format!("Cannot mix {} and {}; CONVERT one of them first", code, other);
+            Err(ReplyError::new(ErrorCode::TypeMismatch, message))
+        }
+        (_, Currency { .. }, _) | (_, _, Currency { .. }) => {
+            Err(ReplyError::new(ErrorCode::TypeMismatch, format!("Invalid operands for {} with money", op)))
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// `CONVERT(amount, "<from>", "<to>", rate)`: `amount`, plain or already in
+/// `from`, in `to` at `rate` units of `to` per unit of `from`.
+pub fn convert(amount: &CellValue, from: &str, to: &str, rate: &CellValue) -> Result<CellValue, ReplyError> {
+    if let Some(code) = [from, to].into_iter().find(|code| !is_code(code)) {
+        return Err(ReplyError::new(ErrorCode::ParseError, format!("Unknown currency: {}", code)));
+    }
+    let amount = match amount {
+        CellValue::Number(n) => *n,
+        CellValue::Currency { amount, code } if code == from => *amount,
+        CellValue::Currency { code, .. } => {
+            return Err(ReplyError::new(ErrorCode::TypeMismatch, format!("Amount is in {}, not {}", code, from)));
+        }
+        other => return Err(ReplyError::new(ErrorCode::TypeMismatch, format!("Not an amount: {}", other))),
+    };
+    match rate {
+        CellValue::Number(rate) => Ok(CellValue::Currency { amount: amount * rate, code: to.to_string() }),
+        other => Err(ReplyError::new(ErrorCode::TypeMismatch, format!("Exchange rate must be a number, got {}", other))),
+    }
+}
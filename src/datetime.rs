@@ -0,0 +1,90 @@
+use crate::replies::{ErrorCode, ReplyError};
+use crate::CellValue;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, SecondsFormat, Utc};
+use regex::Regex;
+
+const UNITS: [(char, u64); 4] = [('d', 86_400_000), ('h', 3_600_000), ('m', 60_000), ('s', 1000)];
+
+/// A duration literal such as `2h30m`, `1d`, `-15m` or `1.5s`, in seconds.
+pub fn parse_duration(text: &str) -> Option<f64> {
+    let re = Regex::new(r"^(-)?(?:(\d+(?:\.\d+)?)d)?(?:(\d+(?:\.\d+)?)h)?(?:(\d+(?:\.\d+)?)m)?(?:(\d+(?:\.\d+)?)s)?$").unwrap();
+    let caps = re.captures(text)?;
+    let mut seconds = 0.0;
+    let mut any = false;
+    for (i, (_, ms)) in UNITS.iter().enumerate() {
+        if let Some(n) = caps.get(i + 2) {
+            seconds += n.as_str().parse::<f64>().ok()? * (*ms as f64 / 1000.0);
+            any = true;
+        }
+    }
+    let sign = if caps.get(1).is_some() { -1.0 } else { 1.0 };
+    any.then_some(sign * seconds)
+}
+
+/// `seconds` as [`parse_duration`] reads it back, e.g. `2h30m` or `-1.25s`,
+/// to the millisecond.
+pub fn duration_text(seconds: f64) -> String {
+    if !seconds.is_finite() {
+        return seconds.to_string();
+    }
+    let mut rest = (seconds.abs() * 1000.0).round() as u64;
+    let mut text = String::from(if seconds < 0.0 && rest > 0 { "-" } else { "" });
+    for (unit, ms) in UNITS {
+        let n = rest / ms;
+        rest %= ms;
+        match unit {
+            's' if rest > 0 => {
+                let fraction = format!("{:03}", rest);
+                text.push_str(&format!("{}.{}s", n, fraction.trim_end_matches('0')));
+            }
+            's' if n > 0 || text.trim_start_matches('-').is_empty() => text.push_str(&format!("{}s", n)),
+            _ if n > 0 => text.push_str(&format!("{}{}", n, unit)),
+            _ => {}
+        }
+    }
+    text
+}
+
+/// An RFC 3339 time such as `2024-06-01T09:00:00Z`, in Unix milliseconds.
+pub fn parse_datetime(text: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(text).ok().map(|time| time.timestamp_millis())
+}
+
+/// `ms` as an RFC 3339 time in UTC, or with a `strftime` format if it is
+/// a valid one.
+pub fn datetime_text(ms: i64, format: Option<&str>) -> String {
+    let Some(time) = DateTime::<Utc>::from_timestamp_millis(ms) else {
+        return ms.to_string();
+    };
+    match format.map(|format| StrftimeItems::new(format).collect::<Vec<_>>()) {
+        Some(items) if !items.contains(&Item::Error) => time.format_with_items(items.iter()).to_string(),
+        _ => time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+    }
+}
+
+/// `lhs <op> rhs` when either side is a time or a duration; `None` when
+/// neither is. Times subtract to durations and move by durations;
+/// durations add up and scale by plain numbers.
+pub fn arithmetic(op: char, lhs: &CellValue, rhs: &CellValue) -> Option<Result<CellValue, ReplyError>> {
+    use CellValue::{DateTime, Duration, Number};
+
+    let shifted = |ms: i64, seconds: f64| Ok(DateTime(ms + (seconds * 1000.0).round() as i64));
+    let result = match (op, lhs, rhs) {
+        ('-', DateTime(a), DateTime(b)) => Ok(Duration((a - b) as f64 / 1000.0)),
+        ('+', DateTime(ms), Duration(s)) | ('+', Duration(s), DateTime(ms)) => shifted(*ms, *s),
+        ('-', DateTime(ms), Duration(s)) => shifted(*ms, -s),
+        ('+', Duration(a), Duration(b)) => Ok(Duration(a + b)),
+        ('-', Duration(a), Duration(b)) => Ok(Duration(a - b)),
+        ('*', Duration(s), Number(n)) | ('*', Number(n), Duration(s)) => Ok(Duration(s * n)),
+        ('/', Duration(_), Number(0.0) | Duration(0.0)) => Err(ReplyError::new(ErrorCode::DivByZero, "Division by zero")),
+        ('/', Duration(s), Number(n)) => Ok(Duration(s / n)),
+        ('/', Duration(a), Duration(b)) => Ok(Number(a / b)),
+        (_, DateTime(_) | Duration(_), _) | (_, _, DateTime(_) | Duration(_)) => {
+            let message = format!("Invalid operands for {} with a time: {} and {}", op, lhs, rhs);
+            Err(ReplyError::new(ErrorCode::TypeMismatch, message))
+        }
+        _ => return None,
+    };
+    Some(result)
+}
@@ -0,0 +1,78 @@
+/// How much a [`Diagnostic`] matters, as in `tracing`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// An operational message from the sheet, such as a get, a failed set or a
+/// compaction of the write-ahead log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Diagnostic<'a> {
+    pub level: Level,
+    pub message: &'a str,
+    /// The cell or range it concerns, if any.
+    pub cell: Option<&'a str>,
+    /// The expression, value or error behind it; empty if there is none.
+    pub detail: &'a str,
+}
+
+/// Where the sheet's operational messages go, set with
+/// [`crate::RSheet::with_diagnostics`].
+pub trait DiagnosticsSink: Send + Sync {
+    fn emit(&self, diagnostic: &Diagnostic);
+
+    /// Whether messages at `level` are wanted at all, so that the sheet can
+    /// skip formatting them.
+    fn enabled(&self, _level: Level) -> bool {
+        true
+    }
+}
+
+/// Passes messages on as `tracing` events. The default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TracingSink;
+
+impl DiagnosticsSink for TracingSink {
+    fn emit(&self, d: &Diagnostic) {
+        let detail = Some(d.detail).filter(|detail| !detail.is_empty());
+        match d.level {
+            Level::Trace => tracing::trace!(cell = d.cell, detail, "{}", d.message),
+            Level::Debug => tracing::debug!(cell = d.cell, detail, "{}", d.message),
+            Level::Info => tracing::info!(cell = d.cell, detail, "{}", d.message),
+            Level::Warn => tracing::warn!(cell = d.cell, detail, "{}", d.message),
+            Level::Error => tracing::error!(cell = d.cell, detail, "{}", d.message),
+        }
+    }
+
+    fn enabled(&self, level: Level) -> bool {
+        match level {
+            Level::Trace => tracing::enabled!(tracing::Level::TRACE),
+            Level::Debug => tracing::enabled!(tracing::Level::DEBUG),
+            Level::Info => tracing::enabled!(tracing::Level::INFO),
+            Level::Warn => tracing::enabled!(tracing::Level::WARN),
+            Level::Error => tracing::enabled!(tracing::Level::ERROR),
+        }
+    }
+}
+
+/// Drops every message.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopSink;
+
+impl DiagnosticsSink for NoopSink {
+    fn emit(&self, _diagnostic: &Diagnostic) {}
+
+    fn enabled(&self, _level: Level) -> bool {
+        false
+    }
+}
+
+impl<F: Fn(&Diagnostic) + Send + Sync> DiagnosticsSink for F {
+    fn emit(&self, diagnostic: &Diagnostic) {
+        self(diagnostic)
+    }
+}
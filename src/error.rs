@@ -0,0 +1,80 @@
+use crate::connect::ProtocolError;
+use crate::replies::{ErrorCode, ReplyError};
+#[cfg(feature = "net")]
+use crate::BindError;
+
+/// Why the server, a connection or the evaluator failed. Every variant
+/// turns into the [`ReplyError`] a client is sent.
+#[derive(Debug, thiserror::Error)]
+pub enum RSheetError {
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    /// A command, range or value that does not read as one.
+    #[error("{0}")]
+    Parse(String),
+    #[error("{0}")]
+    Storage(String),
+    /// A formula that could not be evaluated, with the code saying why
+    /// (`DivByZero`, `UnknownCell`, ...).
+    #[error("{message}")]
+    Eval { code: ErrorCode, message: String },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// A message that could not be encoded or decoded as JSON.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "net")]
+    #[error(transparent)]
+    Bind(#[from] BindError),
+    /// Turned away with a reply carrying more than a message, such as the
+    /// quota hit or the lease holder.
+    #[error(transparent)]
+    Rejected(ReplyError),
+}
+
+impl RSheetError {
+    /// The code the client is sent.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RSheetError::Protocol(_) | RSheetError::Json(_) => ErrorCode::ProtocolError,
+            RSheetError::Parse(_) => ErrorCode::ParseError,
+            // The server's own I/O failing is a storage failure as far as a client can tell.
+            RSheetError::Storage(_) | RSheetError::Io(_) => ErrorCode::StorageError,
+            #[cfg(feature = "net")]
+            RSheetError::Bind(_) => ErrorCode::StorageError,
+            RSheetError::Eval { code, .. } => *code,
+            RSheetError::Rejected(e) => e.code,
+        }
+    }
+
+    /// Whether this is the read timeout firing on an idle connection.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            RSheetError::Io(e) => matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut),
+            _ => false,
+        }
+    }
+}
+
+impl From<ReplyError> for RSheetError {
+    fn from(e: ReplyError) -> Self {
+        match e.code {
+            _ if e.quota.is_some() || e.lease.is_some() => RSheetError::Rejected(e),
+            ErrorCode::ParseError => RSheetError::Parse(e.message),
+            ErrorCode::StorageError => RSheetError::Storage(e.message),
+            ErrorCode::DivByZero | ErrorCode::UnknownCell | ErrorCode::TypeMismatch | ErrorCode::CircularRef => {
+                RSheetError::Eval { code: e.code, message: e.message }
+            }
+            _ => RSheetError::Rejected(e),
+        }
+    }
+}
+
+impl From<RSheetError> for ReplyError {
+    fn from(e: RSheetError) -> Self {
+        match e {
+            RSheetError::Rejected(e) => e,
+            e => ReplyError::new(e.code(), e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use crate::CellValue;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use web_time::Instant;
+
+/// How long a fetched body is served from the cache before it is fetched again.
+pub const DEFAULT_REFRESH: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq)]
+pub enum FeedError {
+    /// The URL is not allowed; see [`crate::fetch::UrlPolicy`].
+    NotAllowed(String),
+    /// The fetch failed and nothing was cached to fall back on.
+    Unavailable(String),
+    /// The body was fetched but the selector found nothing in it.
+    NotFound(String),
+}
+
+impl std::fmt::Display for FeedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FeedError::NotAllowed(reason) | FeedError::Unavailable(reason) | FeedError::NotFound(reason) => {
+                f.write_str(reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FeedError {}
+
+struct Cached {
+    fetched: Instant,
+    body: String,
+}
+
+/// Bodies fetched for `FETCH` cells, kept for `refresh` so that cells reading
+/// one URL, and recalculations within the interval, share a single request.
+pub struct Feeds {
+    #[cfg(feature = "import-url")]
+    policy: crate::fetch::UrlPolicy,
+    refresh: Duration,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+impl Default for Feeds {
+    fn default() -> Self {
+        Self::new(DEFAULT_REFRESH)
+    }
+}
+
+impl Feeds {
+    /// Fetches nothing until given a policy with [`Feeds::with_policy`].
+    pub fn new(refresh: Duration) -> Self {
+        Feeds {
+            #[cfg(feature = "import-url")]
+            policy: crate::fetch::UrlPolicy::default(),
+            refresh,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Which hosts may be fetched from, and the timeout and size limit for each fetch.
+    #[cfg(feature = "import-url")]
+    pub fn with_policy(mut self, policy: crate::fetch::UrlPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn refresh(&self) -> Duration {
+        self.refresh
+    }
+
+    /// The value `selector` picks from the body at `url`: a JSON path such
+    /// as `$.usd` or `$.rates[0].bid`, a cell of a CSV body such as `B2`, or,
+    /// with no selector, the whole body.
+    pub fn value(&self, url: &str, selector: Option<&str>) -> Result<CellValue, FeedError> {
+        let body = self.body(url)?;
+        match selector {
+            None => Ok(text_value(body.trim())),
+            Some(path) if path.starts_with('$') => json_value(&body, path),
+            Some(cell) => csv_value(&body, cell),
+        }
+    }
+
+    /// The cached body while it is fresh, else a new fetch. A failed fetch
+    /// falls back on the stale body, so a flaky feed keeps its last value.
+    fn body(&self, url: &str) -> Result<String, FeedError> {
+        if let Some(cached) = self.cache.lock().unwrap().get(url) {
+            if cached.fetched.elapsed() < self.refresh {
+                return Ok(cached.body.clone());
+            }
+        }
+        // Fetched without the cache locked, so one slow feed does not hold up the others.
+        match self.fetch(url) {
+            Ok(body) => {
+                self.cache.lock().unwrap().insert(url.to_string(), Cached { fetched: Instant::now(), body: body.clone() });
+                Ok(body)
+            }
+            Err(e @ FeedError::NotAllowed(_)) => Err(e),
+            Err(e) => match self.cache.lock().unwrap().get(url) {
+                Some(stale) => {
+                    tracing::warn!(url, error = %e, "feed fetch failed, keeping the last value");
+                    Ok(stale.body.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    #[cfg(feature = "import-url")]
+    fn fetch(&self, url: &str) -> Result<String, FeedError> {
+        use std::io::Read;
+
+        let mut body = String::new();
+        let result = self.policy.open(url).and_then(|mut reader| {
+            reader.read_to_string(&mut body).map_err(|e| crate::fetch::FetchError::Transport(e.to_string()))
+        });
+        match result {
+            Ok(_) => Ok(body),
+            Err(e @ crate::fetch::FetchError::NotAllowed(_)) => Err(FeedError::NotAllowed(e.to_string())),
+            Err(e) => Err(FeedError::Unavailable(format!("Failed to fetch {}: {}", url, e))),
+        }
+    }
+
+    #[cfg(not(feature = "import-url"))]
+    fn fetch(&self, _url: &str) -> Result<String, FeedError> {
+        Err(FeedError::NotAllowed("This server was built without URL fetch support".to_string()))
+    }
+}
+
+/// A number if the text reads as one, else the text.
+fn text_value(text: &str) -> CellValue {
+    match text.parse::<f64>() {
+        Ok(n) => CellValue::Number(n),
+        Err(_) => CellValue::Text(text.to_string()),
+    }
+}
+
+/// Follows `$`, then `.key` and `[index]` steps, through a JSON body.
+fn json_value(body: &str, path: &str) -> Result<CellValue, FeedError> {
+    let not_found = || FeedError::NotFound(format!("Nothing at {} in the fetched JSON", path));
+    let mut value: Value = serde_json::from_str(body).map_err(|e| FeedError::NotFound(format!("Fetched body is not JSON: {}", e)))?;
+    let mut rest = &path[1..];
+    while !rest.is_empty() {
+        value = if let Some(after) = rest.strip_prefix('[') {
+            let (index, after) = after.split_once(']').ok_or_else(not_found)?;
+            rest = after;
+            value.get(index.parse::<usize>().map_err(|_| not_found())?).cloned().ok_or_else(not_found)?
+        } else if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            rest = &after[end..];
+            value.get(&after[..end]).cloned().ok_or_else(not_found)?
+        } else {
+            return Err(not_found());
+        };
+    }
+    match value {
+        Value::Number(n) => n.as_f64().map(CellValue::Number).ok_or_else(not_found),
+        Value::String(text) => Ok(CellValue::Text(text)),
+        Value::Bool(b) => Ok(CellValue::Text(b.to_string())),
+        Value::Null | Value::Array(_) | Value::Object(_) => Err(not_found()),
+    }
+}
+
+/// The field of a CSV body at `cell`, counting its first record as row 1.
+fn csv_value(body: &str, cell: &str) -> Result<CellValue, FeedError> {
+    let addr = cell.parse::<crate::address::CellAddress>().map_err(|e| FeedError::NotFound(format!("Invalid selector {}: {}", cell, e.0)))?;
+    let not_found = || FeedError::NotFound(format!("Nothing at {} in the fetched CSV", cell));
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(body.as_bytes());
+    let record = reader.records().nth(addr.row as usize).ok_or_else(not_found)?.map_err(|_| not_found())?;
+    record.get(addr.col as usize).map(|field| text_value(field.trim())).ok_or_else(not_found)
+}
@@ -0,0 +1,92 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::time::Duration;
+
+/// Which URLs `import url` and `FETCH` cells may fetch, and how much of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UrlPolicy {
+    /// Hosts that may be fetched from, e.g. `data.example.com`. Matched
+    /// exactly, ignoring case. Empty allows nothing.
+    pub allowed_hosts: Vec<String>,
+    /// Whole-request limit, from connecting to reading the last byte.
+    pub timeout: Duration,
+    /// Largest body read before the import is abandoned.
+    pub max_bytes: u64,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        UrlPolicy { allowed_hosts: Vec::new(), timeout: Duration::from_secs(30), max_bytes: 10 * 1024 * 1024 }
+    }
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// The URL is malformed, not http(s), or its host is not allowed.
+    NotAllowed(String),
+    /// The server answered, but not with the data: an error status or a redirect.
+    Status(u16),
+    /// The body is larger than [`UrlPolicy::max_bytes`].
+    TooLarge(u64),
+    Transport(String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::NotAllowed(reason) => write!(f, "{}", reason),
+            FetchError::Status(status) => write!(f, "server answered with status {}", status),
+            FetchError::TooLarge(max) => write!(f, "response exceeds {} bytes", max),
+            FetchError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+impl UrlPolicy {
+    /// Starts a GET of `url` and returns its body as a stream capped at
+    /// `max_bytes`. Redirects are not followed, since they could lead to a
+    /// host outside the allowlist.
+    pub fn open(&self, url: &str) -> Result<impl Read, FetchError> {
+        let agent = ureq::AgentBuilder::new().timeout(self.timeout).redirects(0).build();
+        let request = agent.get(url);
+        let target = request.request_url().map_err(|e| FetchError::NotAllowed(format!("Invalid URL {}: {}", url, e)))?;
+        if !matches!(target.scheme(), "http" | "https") {
+            return Err(FetchError::NotAllowed(format!("Unsupported URL scheme: {}", target.scheme())));
+        }
+        if !self.allowed_hosts.iter().any(|host| host.eq_ignore_ascii_case(target.host())) {
+            return Err(FetchError::NotAllowed(format!("Host {} is not in the allowlist", target.host())));
+        }
+        let response = match request.call() {
+            Ok(response) if response.status() == 200 => response,
+            Ok(response) => return Err(FetchError::Status(response.status())),
+            Err(ureq::Error::Status(status, _)) => return Err(FetchError::Status(status)),
+            Err(e) => return Err(FetchError::Transport(e.to_string())),
+        };
+        let length = response.header("Content-Length").and_then(|len| len.parse::<u64>().ok());
+        if length.is_some_and(|len| len > self.max_bytes) {
+            return Err(FetchError::TooLarge(self.max_bytes));
+        }
+        Ok(Capped { inner: response.into_reader().take(self.max_bytes + 1), max: self.max_bytes, read: 0 })
+    }
+}
+
+/// Fails the read once more than `max` bytes have come through, so an
+/// oversized body without a `Content-Length` is still cut off.
+struct Capped<R> {
+    inner: R,
+    max: u64,
+    read: u64,
+}
+
+impl<R: Read> Read for Capped<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.read > self.max {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, FetchError::TooLarge(self.max)));
+        }
+        Ok(n)
+    }
+}
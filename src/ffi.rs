@@ -0,0 +1,117 @@
+use crate::replies::value_text;
+use crate::RSheet;
+use std::ffi::{c_char, CStr, CString};
+
+/// What an `rsheet_*` call returns.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RSheetStatus {
+    Ok = 0,
+    /// A null pointer, or a string that is not UTF-8.
+    InvalidArgument = 1,
+    /// The sheet refused the call; `out` holds why.
+    Error = 2,
+}
+
+/// A new empty sheet, to be released with `rsheet_free`.
+#[no_mangle]
+pub extern "C" fn rsheet_new() -> *mut RSheet {
+    Box::into_raw(Box::new(RSheet::new()))
+}
+
+/// Releases a sheet from `rsheet_new`. Null is ignored.
+///
+/// # Safety
+/// `sheet` must come from `rsheet_new` and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn rsheet_free(sheet: *mut RSheet) {
+    if !sheet.is_null() {
+        drop(Box::from_raw(sheet));
+    }
+}
+
+/// Sets `cell` to `expr`, as the `set` command does. On `Error`, `out` (if
+/// not null) receives the message.
+///
+/// # Safety
+/// `sheet` must be live, `cell` and `expr` null-terminated, and `out` null
+/// or writable.
+#[no_mangle]
+pub unsafe extern "C" fn rsheet_set(
+    sheet: *const RSheet,
+    cell: *const c_char,
+    expr: *const c_char,
+    out: *mut *mut c_char,
+) -> RSheetStatus {
+    let (Some(sheet), Some(cell), Some(expr)) = (sheet.as_ref(), arg(cell), arg(expr)) else {
+        return RSheetStatus::InvalidArgument;
+    };
+    match sheet.set_formula(cell, expr) {
+        Ok(()) => RSheetStatus::Ok,
+        Err(e) => reply(out, e.message, RSheetStatus::Error),
+    }
+}
+
+/// Writes the value of `cell` to `out` as the text protocol shows it, or
+/// null if the cell is empty.
+///
+/// # Safety
+/// `sheet` must be live, `cell` null-terminated, and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn rsheet_get(sheet: *const RSheet, cell: *const c_char, out: *mut *mut c_char) -> RSheetStatus {
+    let (Some(sheet), Some(cell), false) = (sheet.as_ref(), arg(cell), out.is_null()) else {
+        return RSheetStatus::InvalidArgument;
+    };
+    match sheet.get_value(cell) {
+        Ok(Some(value)) => reply(out, value_text(&value), RSheetStatus::Ok),
+        Ok(None) => {
+            *out = std::ptr::null_mut();
+            RSheetStatus::Ok
+        }
+        Err(e) => reply(out, e.message, RSheetStatus::Error),
+    }
+}
+
+/// Evaluates `expr` against the sheet without storing it, writing the
+/// result to `out`.
+///
+/// # Safety
+/// `sheet` must be live, `expr` null-terminated, and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn rsheet_eval(sheet: *const RSheet, expr: *const c_char, out: *mut *mut c_char) -> RSheetStatus {
+    let (Some(sheet), Some(expr), false) = (sheet.as_ref(), arg(expr), out.is_null()) else {
+        return RSheetStatus::InvalidArgument;
+    };
+    match sheet.run(expr) {
+        Ok(value) => reply(out, value_text(&value), RSheetStatus::Ok),
+        Err(e) => reply(out, e.to_string(), RSheetStatus::Error),
+    }
+}
+
+/// Releases a string an `rsheet_*` call wrote to `out`. Null is ignored.
+///
+/// # Safety
+/// `text` must come from this library and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn rsheet_string_free(text: *mut c_char) {
+    if !text.is_null() {
+        drop(CString::from_raw(text));
+    }
+}
+
+unsafe fn arg<'a>(text: *const c_char) -> Option<&'a str> {
+    if text.is_null() {
+        return None;
+    }
+    CStr::from_ptr(text).to_str().ok()
+}
+
+/// Hands `text` to the caller through `out`, if there is one.
+unsafe fn reply(out: *mut *mut c_char, text: String, status: RSheetStatus) -> RSheetStatus {
+    if !out.is_null() {
+        // C strings end at the first NUL, so one inside a value cannot be passed on.
+        let text = CString::new(text.replace('\0', "")).unwrap_or_default();
+        *out = text.into_raw();
+    }
+    status
+}
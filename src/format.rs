@@ -0,0 +1,122 @@
+use crate::{datetime, CellValue};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{NaiveDate, TimeDelta};
+use std::fmt::{self, Write};
+
+/// How values are written out for people to read, by the CLI and anything
+/// else that shows a cell rather than stores it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FormatOptions {
+    /// Digits after the point; `None` writes as many as the number needs.
+    pub decimals: Option<usize>,
+    /// Written between each group of three digits before the point, as `,` in `1,234`.
+    pub thousands_separator: Option<char>,
+    /// A `strftime` format such as `%Y-%m-%d`. Numbers are then shown as
+    /// dates, counted in days from 1899-12-30 as spreadsheets count them.
+    pub date_format: Option<String>,
+}
+
+impl FormatOptions {
+    pub fn with_decimals(mut self, decimals: usize) -> Self {
+        self.decimals = Some(decimals);
+        self
+    }
+
+    pub fn with_thousands_separator(mut self, separator: char) -> Self {
+        self.thousands_separator = Some(separator);
+        self
+    }
+
+    pub fn with_date_format(mut self, format: impl Into<String>) -> Self {
+        self.date_format = Some(format.into());
+        self
+    }
+}
+
+/// A value shown with [`FormatOptions`], from [`CellValue::formatted`].
+pub struct Formatted<'a> {
+    value: &'a CellValue,
+    options: &'a FormatOptions,
+}
+
+impl CellValue {
+    pub fn formatted<'a>(&'a self, options: &'a FormatOptions) -> Formatted<'a> {
+        Formatted { value: self, options }
+    }
+}
+
+/// As [`crate::replies::value_text`] writes it, with no formatting.
+impl fmt::Display for CellValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.formatted(&FormatOptions::default()).fmt(f)
+    }
+}
+
+impl fmt::Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            CellValue::Number(n) => f.write_str(&number_text(*n, self.options)),
+            CellValue::Text(text) => f.write_str(text),
+            CellValue::Error(e) => write!(f, "#ERROR {}", e),
+            // An amount is never a date, and its code follows it as `set` takes it.
+            CellValue::Currency { amount, code } => write!(f, "{}{}", decimal_text(*amount, self.options), code),
+            CellValue::Duration(seconds) => f.write_str(&datetime::duration_text(*seconds)),
+            CellValue::DateTime(ms) => f.write_str(&datetime::datetime_text(*ms, self.options.date_format.as_deref())),
+            CellValue::Empty => Ok(()),
+        }
+    }
+}
+
+fn number_text(n: f64, options: &FormatOptions) -> String {
+    if let Some(date) = options.date_format.as_deref().and_then(|format| date_text(n, format)) {
+        return date;
+    }
+    decimal_text(n, options)
+}
+
+fn decimal_text(n: f64, options: &FormatOptions) -> String {
+    let text = match options.decimals {
+        Some(decimals) => format!("{:.*}", decimals, n),
+        None => n.to_string(),
+    };
+    match options.thousands_separator {
+        Some(separator) if n.is_finite() => group_thousands(&text, separator),
+        _ => text,
+    }
+}
+
+fn group_thousands(text: &str, separator: char) -> String {
+    let (sign, digits) = text.strip_prefix('-').map_or(("", text), |digits| ("-", digits));
+    let (whole, fraction) = digits.split_once('.').map_or((digits, None), |(whole, fraction)| (whole, Some(fraction)));
+    let mut out = String::from(sign);
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i) % 3 == 0 {
+            out.push(separator);
+        }
+        out.push(digit);
+    }
+    if let Some(fraction) = fraction {
+        out.push('.');
+        out.push_str(fraction);
+    }
+    out
+}
+
+/// `n` as a date in `format`. `None`, so the number is shown as one, if
+/// the format is invalid or the date out of range.
+fn date_text(n: f64, format: &str) -> Option<String> {
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.contains(&Item::Error) {
+        return None;
+    }
+    let ms = (n * 86_400_000.0).round();
+    if !ms.is_finite() || ms.abs() > i64::MAX as f64 {
+        return None;
+    }
+    let epoch = NaiveDate::from_ymd_opt(1899, 12, 30)?.and_hms_opt(0, 0, 0)?;
+    let time = epoch.checked_add_signed(TimeDelta::try_milliseconds(ms as i64)?)?;
+    let mut text = String::new();
+    // Time zone fields fail to format a naive time, and fall back the same way.
+    write!(text, "{}", time.format_with_items(items.iter())).ok()?;
+    Some(text)
+}
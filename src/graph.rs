@@ -0,0 +1,173 @@
+use crate::address::{parse_key, split_sheet, CellRange};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// A cell or range a formula reads, as a storage key such as `B2` or
+/// `Budget!A1:A10`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reference {
+    Cell(String),
+    /// The range of a `SUM` or `AVERAGE`, which may be whole columns (`A:A`).
+    Range(String),
+}
+
+impl Reference {
+    pub fn key(&self) -> &str {
+        match self {
+            Reference::Cell(key) | Reference::Range(key) => key,
+        }
+    }
+
+    /// The sheet and cells the reference covers.
+    pub fn area(&self) -> Option<(Option<&str>, CellRange)> {
+        let (sheet, cells) = split_sheet(self.key());
+        Some((sheet, CellRange::parse_with_columns(cells).ok()?))
+    }
+
+    fn overlaps(&self, sheet: Option<&str>, range: CellRange) -> bool {
+        self.area().is_some_and(|(of, area)| {
+            of == sheet
+                && area.start.col <= range.end.col
+                && range.start.col <= area.end.col
+                && area.start.row <= range.end.row
+                && range.start.row <= area.end.row
+        })
+    }
+}
+
+/// A formula cell: what it computes and what it reads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub expr: String,
+    pub reads: Vec<Reference>,
+}
+
+/// A formula that reads a cell, from `whereused`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Dependent {
+    pub cell: String,
+    pub expr: String,
+    /// 1 for a formula that reads the cell itself, 2 for one that reads
+    /// such a formula, and so on.
+    pub depth: usize,
+}
+
+/// How the stored formulas are wired: each formula cell, by storage key,
+/// with the cells and ranges it reads.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DependencyGraph {
+    pub nodes: BTreeMap<String, Node>,
+}
+
+impl DependencyGraph {
+    pub fn new(nodes: BTreeMap<String, Node>) -> Self {
+        DependencyGraph { nodes }
+    }
+
+    /// Only the formulas in `range` of `sheet`, or that read anything in it.
+    pub fn scoped(self, sheet: Option<&str>, range: CellRange) -> Self {
+        let in_scope = |cell: &str| Reference::Cell(cell.to_string()).overlaps(sheet, range);
+        let nodes = self
+            .nodes
+            .into_iter()
+            .filter(|(cell, node)| in_scope(cell) || node.reads.iter().any(|read| read.overlaps(sheet, range)))
+            .collect();
+        DependencyGraph { nodes }
+    }
+
+    /// Every formula that reads `cell`, a storage key, directly or through
+    /// other formulas up to `depth` steps away; nearest first, then by cell.
+    pub fn dependents(&self, cell: &str, depth: Option<usize>) -> Vec<Dependent> {
+        match parse_key(cell) {
+            Ok((sheet, addr)) => self.range_dependents(sheet, CellRange::new(addr, addr), depth),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Like [`DependencyGraph::dependents`], for any cell of `range` on
+    /// `sheet`. Formulas inside the range are followed but not listed.
+    pub fn range_dependents(&self, sheet: Option<&str>, range: CellRange, depth: Option<usize>) -> Vec<Dependent> {
+        let mut found: Vec<Dependent> = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut areas = vec![(sheet.map(str::to_string), range)];
+        let mut level = 1;
+        while !areas.is_empty() && level <= depth.unwrap_or(usize::MAX) {
+            let reads_any = |node: &Node| {
+                node.reads.iter().any(|read| areas.iter().any(|(sheet, area)| read.overlaps(sheet.as_deref(), *area)))
+            };
+            let next: Vec<&String> =
+                self.nodes.iter().filter(|(key, node)| !seen.contains(*key) && reads_any(node)).map(|(key, _)| key).collect();
+            areas = Vec::with_capacity(next.len());
+            for key in next {
+                seen.insert(key.clone());
+                let Ok((of, addr)) = parse_key(key) else {
+                    continue;
+                };
+                if of != sheet || !range.contains(&addr) {
+                    found.push(Dependent { cell: key.clone(), expr: self.nodes[key].expr.clone(), depth: level });
+                }
+                areas.push((of.map(str::to_string), CellRange::new(addr, addr)));
+            }
+            level += 1;
+        }
+        found
+    }
+
+    /// The formulas `cell` is computed from, directly or through other
+    /// formulas, each after the ones it reads and ending with `cell` itself
+    /// if it is a formula. A formula in a cycle is listed once, where the
+    /// cycle is first met.
+    pub fn precedents(&self, cell: &str) -> Vec<String> {
+        let mut order = Vec::new();
+        self.visit(cell, &mut BTreeSet::new(), &mut order);
+        order
+    }
+
+    fn visit(&self, cell: &str, seen: &mut BTreeSet<String>, order: &mut Vec<String>) {
+        let Some(node) = self.nodes.get(cell) else {
+            return;
+        };
+        if !seen.insert(cell.to_string()) {
+            return;
+        }
+        let reads = |key: &str| match parse_key(key) {
+            Ok((sheet, addr)) => node.reads.iter().any(|read| read.overlaps(sheet, CellRange::new(addr, addr))),
+            Err(_) => false,
+        };
+        let read: Vec<&String> = self.nodes.keys().filter(|key| reads(key)).collect();
+        for key in read {
+            self.visit(key, seen, order);
+        }
+        order.push(cell.to_string());
+    }
+}
+
+/// The graph in Graphviz DOT, with an edge from each cell or range to the
+/// formulas that read it. Formula cells are labelled with their formulas
+/// and ranges are drawn as boxes.
+impl fmt::Display for DependencyGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "digraph rsheet {{")?;
+        writeln!(f, "    rankdir=LR;")?;
+        let mut ranges = BTreeSet::new();
+        for (cell, node) in &self.nodes {
+            writeln!(f, "    {} [label={}];", quoted(cell), quoted(&format!("{} = {}", cell, node.expr)))?;
+            ranges.extend(node.reads.iter().filter(|read| matches!(read, Reference::Range(_))).map(Reference::key));
+        }
+        for range in ranges {
+            writeln!(f, "    {} [shape=box];", quoted(range))?;
+        }
+        for (cell, node) in &self.nodes {
+            for read in &node.reads {
+                writeln!(f, "    {} -> {};", quoted(read.key()), quoted(cell))?;
+            }
+        }
+        write!(f, "}}")
+    }
+}
+
+/// `text` as a DOT string.
+fn quoted(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,177 @@
+use crate::meminfo;
+use crate::CellValue;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Records between full sweeps of every cell's history.
+const SWEEP_EVERY: u64 = 1024;
+
+/// How much history is kept. A cell's history is trimmed whenever it changes,
+/// and every cell's is swept periodically.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Versions kept per cell, the current one included.
+    pub max_versions: usize,
+    /// Versions superseded longer ago than this are dropped.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy { max_versions: 100, max_age: None }
+    }
+}
+
+#[derive(Default)]
+struct CellHistory {
+    /// `(timestamp_ms, value)`, oldest first. `None` records a delete.
+    versions: VecDeque<(u64, Option<CellValue>)>,
+    /// Earliest time this cell's value is still known for.
+    known_from: u64,
+    /// Estimated size of `versions`.
+    bytes: u64,
+}
+
+struct Versions {
+    cells: HashMap<String, CellHistory>,
+    /// Earliest time any cell's value is still known for.
+    horizon: u64,
+    records: u64,
+    /// Estimated size of every cell's history.
+    bytes: u64,
+}
+
+/// Timestamped values of every cell, for `get <cell> asof <time>`.
+pub struct History {
+    versions: Mutex<Versions>,
+    policy: RetentionPolicy,
+}
+
+/// Why a historical value cannot be given.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HistoryError {
+    /// The time is before the retained history begins, at this many milliseconds.
+    Expired { horizon_ms: u64 },
+}
+
+impl History {
+    pub fn new(policy: RetentionPolicy) -> Self {
+        let versions = Versions { cells: HashMap::new(), horizon: now_ms(), records: 0, bytes: 0 };
+        History { versions: Mutex::new(versions), policy }
+    }
+
+    /// Notes that `cell` became `value` (`None` once deleted) now.
+    pub fn record(&self, cell: &str, value: Option<CellValue>) {
+        self.record_at(cell, value, now_ms());
+    }
+
+    fn record_at(&self, cell: &str, value: Option<CellValue>, at: u64) {
+        let mut versions = self.versions.lock().unwrap();
+        let cutoff = self.cutoff(at);
+        let horizon = versions.horizon;
+        let mut added = meminfo::version_bytes(value.as_ref());
+        if !versions.cells.contains_key(cell) {
+            added += meminfo::history_bytes(cell, 0);
+        }
+        let history = versions.cells.entry(cell.to_string()).or_insert_with(|| CellHistory {
+            versions: VecDeque::new(),
+            known_from: horizon,
+            bytes: 0,
+        });
+        history.bytes += meminfo::version_bytes(value.as_ref());
+        history.versions.push_back((at, value));
+        let dropped = self.trim(history, cutoff);
+        versions.bytes = versions.bytes + added - dropped;
+        versions.records += 1;
+        if versions.records.is_multiple_of(SWEEP_EVERY) {
+            self.sweep_locked(&mut versions, at);
+        }
+    }
+
+    /// Value of `cell` at `at_ms`, `Ok(None)` if it was empty then.
+    pub fn value_at(&self, cell: &str, at_ms: u64) -> Result<Option<CellValue>, HistoryError> {
+        let versions = self.versions.lock().unwrap();
+        let history = versions.cells.get(cell);
+        let known_from = history.map_or(versions.horizon, |h| h.known_from.max(versions.horizon));
+        if at_ms < known_from {
+            return Err(HistoryError::Expired { horizon_ms: known_from });
+        }
+        let value = history
+            .and_then(|h| h.versions.iter().rev().find(|(ts, _)| *ts <= at_ms))
+            .and_then(|(_, value)| value.clone());
+        Ok(value)
+    }
+
+    /// Estimated size of every cell's history.
+    pub fn bytes(&self) -> u64 {
+        self.versions.lock().unwrap().bytes
+    }
+
+    /// Estimated size of each cell's history.
+    pub fn bytes_by_cell(&self) -> Vec<(String, u64)> {
+        let versions = self.versions.lock().unwrap();
+        versions.cells.iter().map(|(cell, history)| (cell.clone(), meminfo::history_bytes(cell, history.bytes))).collect()
+    }
+
+    /// Applies the retention policy to every cell.
+    pub fn sweep(&self) {
+        let mut versions = self.versions.lock().unwrap();
+        self.sweep_locked(&mut versions, now_ms());
+    }
+
+    fn sweep_locked(&self, versions: &mut Versions, now: u64) {
+        let Some(cutoff) = self.cutoff(now) else {
+            return;
+        };
+        versions.horizon = versions.horizon.max(cutoff);
+        let mut dropped = 0;
+        versions.cells.retain(|cell, history| {
+            dropped += self.trim(history, Some(cutoff));
+            // A cell long since deleted needs no entry: the horizon covers it.
+            let expired = matches!(history.versions.make_contiguous(), [(ts, None)] if *ts < cutoff);
+            if expired {
+                dropped += meminfo::history_bytes(cell, history.bytes);
+            }
+            !expired
+        });
+        versions.bytes -= dropped;
+    }
+
+    fn cutoff(&self, now: u64) -> Option<u64> {
+        self.policy.max_age.map(|age| now.saturating_sub(age.as_millis() as u64))
+    }
+
+    /// Drops versions beyond the policy, keeping the one in force at
+    /// `cutoff`. Returns the estimated bytes freed.
+    fn trim(&self, history: &mut CellHistory, cutoff: Option<u64>) -> u64 {
+        let before = history.bytes;
+        let pop = |history: &mut CellHistory| {
+            if let Some((_, value)) = history.versions.pop_front() {
+                history.bytes -= meminfo::version_bytes(value.as_ref());
+            }
+        };
+        while history.versions.len() > self.policy.max_versions.max(1) {
+            pop(history);
+            history.known_from = history.versions.front().map_or(history.known_from, |(ts, _)| *ts);
+        }
+        if let Some(cutoff) = cutoff {
+            while history.versions.get(1).is_some_and(|(ts, _)| *ts <= cutoff) {
+                pop(history);
+            }
+            history.known_from = history.known_from.max(cutoff);
+        }
+        before - history.bytes
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Parses an RFC 3339 time such as `2024-06-01T00:00:00Z` into Unix milliseconds.
+pub fn parse_time(text: &str) -> Option<u64> {
+    let time = chrono::DateTime::parse_from_rfc3339(text.trim_matches('"')).ok()?;
+    u64::try_from(time.timestamp_millis()).ok()
+}
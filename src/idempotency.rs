@@ -0,0 +1,51 @@
+use crate::replies::Reply;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Keys remembered before the oldest is forgotten.
+pub const DEFAULT_KEY_CAPACITY: usize = 10_000;
+
+#[derive(Default)]
+struct Entries {
+    replies: HashMap<String, Reply>,
+    order: VecDeque<String>,
+}
+
+/// Replies to recently seen idempotency keys. Keys are shared by all
+/// connections so a client that reconnects can still retry safely; clients
+/// are expected to pick keys that will not collide, such as UUIDs.
+pub struct IdempotencyKeys {
+    entries: Mutex<Entries>,
+    capacity: usize,
+}
+
+impl Default for IdempotencyKeys {
+    fn default() -> Self {
+        IdempotencyKeys::new(DEFAULT_KEY_CAPACITY)
+    }
+}
+
+impl IdempotencyKeys {
+    pub fn new(capacity: usize) -> Self {
+        IdempotencyKeys { entries: Mutex::new(Entries::default()), capacity }
+    }
+
+    /// Returns the reply first recorded for `key`, running `apply` only if the
+    /// key is new. Keyed commands run one at a time so two retries racing each
+    /// other cannot both apply.
+    pub fn run(&self, key: &str, apply: impl FnOnce() -> Reply) -> Reply {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(reply) = entries.replies.get(key) {
+            return reply.clone();
+        }
+        let reply = apply();
+        entries.replies.insert(key.to_string(), reply.clone());
+        entries.order.push_back(key.to_string());
+        while entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.replies.remove(&oldest);
+            }
+        }
+        reply
+    }
+}
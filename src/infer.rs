@@ -0,0 +1,184 @@
+use crate::address::{column_index, column_name};
+use crate::replies::{ErrorCode, ReplyError};
+use crate::CellValue;
+use chrono::{NaiveDate, NaiveDateTime};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Currency symbols read in front of an amount, as in `$12.50`.
+const SYMBOLS: [(char, &str); 5] = [('$', "USD"), ('€', "EUR"), ('£', "GBP"), ('¥', "JPY"), ('₹', "INR")];
+
+/// What an imported field is read as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    /// Whatever the field looks like, trying each of the others in turn.
+    Auto,
+    Text,
+    Number,
+    /// `true`/`false` or `yes`/`no`, as 1 and 0.
+    Boolean,
+    /// `12.5%`, as 0.125.
+    Percent,
+    /// `19.99USD`, `USD 19.99` or `$19.99`.
+    Currency,
+    /// `2024-06-01`, `2024-06-01 09:30:00` or RFC 3339, as a time in UTC.
+    Date,
+}
+
+impl ColumnType {
+    fn name(self) -> &'static str {
+        match self {
+            ColumnType::Auto => "auto",
+            ColumnType::Text => "text",
+            ColumnType::Number => "number",
+            ColumnType::Boolean => "bool",
+            ColumnType::Percent => "percent",
+            ColumnType::Currency => "currency",
+            ColumnType::Date => "date",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        let types = [
+            ColumnType::Auto,
+            ColumnType::Text,
+            ColumnType::Number,
+            ColumnType::Boolean,
+            ColumnType::Percent,
+            ColumnType::Currency,
+            ColumnType::Date,
+        ];
+        types.into_iter().find(|t| t.name().eq_ignore_ascii_case(name))
+    }
+
+    /// `field` read as this type; `None` if it is not one.
+    pub fn read(self, field: &str) -> Option<CellValue> {
+        match self {
+            ColumnType::Auto => [ColumnType::Number, ColumnType::Percent, ColumnType::Currency, ColumnType::Date, ColumnType::Boolean]
+                .into_iter()
+                .find_map(|t| t.read(field))
+                .or_else(|| Some(CellValue::Text(crate::text::nfc(field.to_string())))),
+            ColumnType::Text => Some(CellValue::Text(crate::text::nfc(field.to_string()))),
+            ColumnType::Number => number(field).map(CellValue::Number),
+            ColumnType::Boolean => match field.to_ascii_lowercase().as_str() {
+                "true" | "yes" => Some(CellValue::Number(1.0)),
+                "false" | "no" => Some(CellValue::Number(0.0)),
+                _ => None,
+            },
+            ColumnType::Percent => number(field.strip_suffix('%')?.trim_end()).map(|n| CellValue::Number(n / 100.0)),
+            ColumnType::Currency => currency(field),
+            ColumnType::Date => date(field).map(CellValue::DateTime),
+        }
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How `import csv` and `import url` turn fields into values: by guessing
+/// each field's type, keeping everything as text, or as hinted per column.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InferOptions {
+    /// Read every field without a hint as text.
+    pub text_only: bool,
+    /// Types for particular sheet columns, by index.
+    pub columns: BTreeMap<u32, ColumnType>,
+}
+
+impl InferOptions {
+    /// Reads the trailing `[text] [<column>:<type>...]` arguments of an
+    /// import, e.g. `text C:number` or `B:date D:percent`.
+    pub fn parse(args: &[&str]) -> Result<Self, ReplyError> {
+        let mut options = InferOptions::default();
+        let mut args = args.iter().peekable();
+        if args.next_if(|arg| **arg == "text").is_some() {
+            options.text_only = true;
+        }
+        for arg in args {
+            let hint = arg.split_once(':').and_then(|(col, name)| Some((column_index(col)?, ColumnType::parse(name)?)));
+            let Some((col, column_type)) = hint else {
+                return Err(ReplyError::new(ErrorCode::ParseError, format!("Invalid column type: {}", arg)));
+            };
+            options.columns.insert(col, column_type);
+        }
+        Ok(options)
+    }
+
+    /// The type fields in sheet column `col` are read as.
+    pub fn column_type(&self, col: u32) -> ColumnType {
+        match self.columns.get(&col) {
+            Some(column_type) => *column_type,
+            None if self.text_only => ColumnType::Text,
+            None => ColumnType::Auto,
+        }
+    }
+
+    /// `field`, from sheet column `col`, as a value. A field that does not
+    /// fit its column's hint is an error rather than text.
+    pub fn read(&self, col: u32, field: &str) -> Result<CellValue, String> {
+        let column_type = self.column_type(col);
+        column_type.read(field).ok_or_else(|| format!("Column {} is {}, but {:?} is not", column_name(col), column_type, field))
+    }
+}
+
+impl fmt::Display for InferOptions {
+    /// The arguments [`InferOptions::parse`] reads back, each with a leading space.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.text_only {
+            f.write_str(" text")?;
+        }
+        for (col, column_type) in &self.columns {
+            write!(f, " {}:{}", column_name(*col), column_type)?;
+        }
+        Ok(())
+    }
+}
+
+/// A plain number, allowing `,` between groups of three digits.
+fn number(field: &str) -> Option<f64> {
+    let digits = field.trim_start_matches(['-', '+']);
+    let whole = digits.split('.').next().unwrap_or_default();
+    let grouped = whole.contains(',');
+    if grouped && !whole.split(',').enumerate().all(|(i, group)| group.len() == 3 || (i == 0 && (1..=3).contains(&group.len()))) {
+        return None;
+    }
+    // `f64` also reads `inf` and `NaN`, which imports keep as text.
+    if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    match grouped {
+        true => field.replace(',', "").parse().ok(),
+        false => field.parse().ok(),
+    }
+}
+
+fn currency(field: &str) -> Option<CellValue> {
+    let (amount, code) = match field.char_indices().next()? {
+        (_, c) if c.is_ascii_alphabetic() => {
+            let (code, amount) = field.split_at(field.find(' ')?);
+            (amount.trim_start(), code.to_string())
+        }
+        (_, symbol) => match SYMBOLS.iter().find(|(s, _)| *s == symbol) {
+            Some((s, code)) => (&field[s.len_utf8()..], code.to_string()),
+            None => return crate::currency::parse(&field.replace(' ', "")),
+        },
+    };
+    if !crate::currency::is_code(&code) {
+        return None;
+    }
+    Some(CellValue::Currency { amount: number(amount)?, code })
+}
+
+fn date(field: &str) -> Option<i64> {
+    if let Some(ms) = crate::datetime::parse_datetime(field) {
+        return Some(ms);
+    }
+    let time = NaiveDateTime::parse_from_str(field, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(field, "%Y-%m-%dT%H:%M:%S"))
+        .or_else(|_| NaiveDate::parse_from_str(field, "%Y-%m-%d").map(|date| date.and_hms_opt(0, 0, 0).unwrap()))
+        .ok()?;
+    Some(time.and_utc().timestamp_millis())
+}
@@ -0,0 +1,85 @@
+use crate::CellValue;
+use kafka::producer::{Producer, Record, RequiredAcks};
+use serde::{Deserialize, Serialize};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Topic changes are produced to unless configured otherwise.
+pub const DEFAULT_TOPIC: &str = "rsheet-changes";
+
+/// Changes held for the producer thread; past this, new ones are dropped and logged.
+const QUEUE_CAPACITY: usize = 10_000;
+
+/// One committed change, as produced to Kafka. Keyed by cell, so a
+/// consumer sees each cell's changes in order.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub cell: String,
+    pub old: Option<CellValue>,
+    pub new: Option<CellValue>,
+    pub actor: Actor,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+}
+
+/// The connection a change came from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Actor {
+    /// `u64::MAX` for in-process callers and for changes the server makes
+    /// itself, such as recalculating `NOW()` or applying replication.
+    pub connection: u64,
+    /// Client address; `None` for in-process callers.
+    pub peer: Option<String>,
+}
+
+impl ChangeEvent {
+    /// A change to `cell` made now; `new` is `None` when the cell was emptied.
+    pub fn new(cell: &str, old: Option<CellValue>, new: Option<CellValue>, actor: Actor) -> Self {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        ChangeEvent { cell: cell.to_string(), old, new, actor, timestamp_ms }
+    }
+}
+
+/// Produces every committed change to a Kafka topic from a background
+/// thread, so a slow broker never holds up a write.
+pub struct KafkaSink {
+    queue: Mutex<mpsc::SyncSender<ChangeEvent>>,
+}
+
+impl KafkaSink {
+    /// Connects to `brokers` (`host:port` each) and produces to `topic`.
+    pub fn connect(brokers: Vec<String>, topic: impl Into<String>) -> Result<Self, kafka::Error> {
+        let producer = Producer::from_hosts(brokers)
+            .with_ack_timeout(Duration::from_secs(5))
+            .with_required_acks(RequiredAcks::One)
+            .create()?;
+        let topic = topic.into();
+        let (queue, events) = mpsc::sync_channel(QUEUE_CAPACITY);
+        std::thread::spawn(move || produce(producer, &topic, events));
+        Ok(KafkaSink { queue: Mutex::new(queue) })
+    }
+
+    pub fn send(&self, event: ChangeEvent) {
+        match self.queue.lock().unwrap().try_send(event) {
+            Ok(()) => {}
+            Err(mpsc::TrySendError::Full(event)) => tracing::warn!(cell = %event.cell, "Kafka queue is full, dropping change"),
+            Err(mpsc::TrySendError::Disconnected(_)) => tracing::error!("Kafka producer thread has stopped"),
+        }
+    }
+}
+
+/// Runs until the [`KafkaSink`] is dropped.
+fn produce(mut producer: Producer, topic: &str, events: mpsc::Receiver<ChangeEvent>) {
+    for event in events {
+        let value = match serde_json::to_vec(&event) {
+            Ok(value) => value,
+            Err(e) => {
+                tracing::error!(cell = %event.cell, error = %e, "failed to encode change");
+                continue;
+            }
+        };
+        if let Err(e) = producer.send(&Record::from_key_value(topic, event.cell.as_bytes(), value)) {
+            tracing::warn!(topic, cell = %event.cell, error = %e, "failed to produce change to Kafka");
+        }
+    }
+}
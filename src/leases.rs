@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+use web_time::Instant;
+
+/// Longest lease `lock` grants, so a forgotten lock cannot hold a cell for good.
+pub const MAX_LEASE: Duration = Duration::from_secs(3600);
+
+/// Who holds a cell, as reported in a `Locked` error.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeaseHolder {
+    pub cell: String,
+    pub connection: u64,
+    /// The holder's `session name`, if it set one.
+    pub name: Option<String>,
+    pub remaining_ms: u64,
+}
+
+impl fmt::Display for LeaseHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let holder = self.name.clone().unwrap_or_else(|| format!("client-{}", self.connection));
+        write!(f, "{} is locked by {} for another {} ms", self.cell, holder, self.remaining_ms)
+    }
+}
+
+struct Lease {
+    connection: u64,
+    name: Option<String>,
+    expires: Instant,
+}
+
+/// Exclusive edit leases taken with `lock <cell> for <duration>`. A lease
+/// ends when its holder unlocks the cell or disconnects, or when it expires.
+#[derive(Default)]
+pub struct Leases {
+    leases: Mutex<HashMap<String, Lease>>,
+}
+
+impl Leases {
+    /// Takes or renews the lease on `cell`, failing if another connection holds it.
+    pub fn acquire(&self, cell: &str, connection: u64, name: Option<String>, duration: Duration) -> Result<(), LeaseHolder> {
+        let mut leases = self.leases.lock().unwrap();
+        let now = Instant::now();
+        if let Some(lease) = leases.get(cell).filter(|lease| lease.expires > now && lease.connection != connection) {
+            return Err(holder(cell, lease, now));
+        }
+        leases.insert(cell.to_string(), Lease { connection, name, expires: now + duration });
+        Ok(())
+    }
+
+    /// Returns false if `connection` did not hold `cell`.
+    pub fn release(&self, cell: &str, connection: u64) -> bool {
+        let mut leases = self.leases.lock().unwrap();
+        let held = leases.get(cell).is_some_and(|lease| lease.connection == connection && lease.expires > Instant::now());
+        if held {
+            leases.remove(cell);
+        }
+        held
+    }
+
+    pub fn release_all(&self, connection: u64) {
+        self.leases.lock().unwrap().retain(|_, lease| lease.connection != connection);
+    }
+
+    /// Fails if a connection other than `connection` holds any of `cells`.
+    pub fn check<'a>(&self, connection: u64, cells: impl IntoIterator<Item = &'a str>) -> Result<(), LeaseHolder> {
+        let mut leases = self.leases.lock().unwrap();
+        if leases.is_empty() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        leases.retain(|_, lease| lease.expires > now);
+        for cell in cells {
+            if let Some(lease) = leases.get(cell).filter(|lease| lease.connection != connection) {
+                return Err(holder(cell, lease, now));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn holder(cell: &str, lease: &Lease, now: Instant) -> LeaseHolder {
+    LeaseHolder {
+        cell: cell.to_string(),
+        connection: lease.connection,
+        name: lease.name.clone(),
+        remaining_ms: lease.expires.saturating_duration_since(now).as_millis() as u64,
+    }
+}
+
+/// Parses a lease length such as `30s`, `500ms` or `5m`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let (number, unit) = text.split_at(text.find(|c: char| !c.is_ascii_digit())?);
+    let number: u64 = number.parse().ok()?;
+    let duration = match unit {
+        "ms" => Duration::from_millis(number),
+        "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number.checked_mul(60)?),
+        _ => return None,
+    };
+    Some(duration)
+}
@@ -3,385 +3,8861 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
 use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
-use crate::replies::Reply;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use web_time::Instant;
+use crate::error::RSheetError;
+use crate::replies::{ErrorCode, Reply, ReplyError};
+use crate::store::CellStore as _;
 
+pub mod address;
+pub mod alerts;
+pub mod alias;
+#[cfg(feature = "arrow")]
+pub mod arrow_ipc;
+pub mod audit;
+pub mod autosave;
+pub mod backup;
+pub mod builder;
+pub mod chart;
+pub mod clients;
+pub mod command;
+#[cfg(feature = "net")]
+pub mod cluster;
+pub mod history;
+pub mod idempotency;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod leases;
+pub mod locks;
+pub mod macros;
+pub mod matrix;
+#[cfg(feature = "net")]
+pub mod client;
+pub mod config;
+pub mod conflict;
+pub mod crdt;
+pub mod currency;
+pub mod datetime;
+pub mod diagnostics;
+pub mod error;
+pub mod feeds;
+#[cfg(feature = "ffi")]
+mod ffi;
+pub mod format;
+#[cfg(feature = "import-url")]
+pub mod fetch;
+pub mod graph;
+pub mod infer;
+pub mod meminfo;
+pub mod messages;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod mvcc;
+pub mod numeric;
+pub mod pool;
+#[cfg(feature = "python")]
+mod python;
+pub mod query;
+pub mod quota;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod replication;
+pub mod reshape;
+pub mod scenario;
+pub mod scheduler;
+pub mod script;
+#[cfg(feature = "net")]
+mod server;
+pub mod slowlog;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "sled")]
+pub mod sled;
+pub mod stats;
+pub mod store;
+pub mod subscriptions;
+pub mod text;
+pub mod view;
+pub mod wal;
+pub mod webhooks;
+pub mod workbook;
+#[cfg(feature = "ods")]
+pub mod ods;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+#[cfg(feature = "xlsx")]
+pub mod xlsx;
+
+#[cfg(all(feature = "net", target_arch = "wasm32"))]
+compile_error!("the `net` feature needs sockets and threads; build for wasm32 with `--no-default-features`");
 
 pub mod connect {
     use super::*;
+    use bytes::{BufMut, BytesMut};
+    use std::io::IoSlice;
+    #[cfg(feature = "net")]
+    use std::io::{BufRead, BufReader};
+    #[cfg(feature = "net")]
+    use std::net::TcpStream;
+
+    pub trait Manager {
+        fn address(&self) -> &str;
+
+        /// Every address to listen on. Defaults to just [`Manager::address`].
+        fn addresses(&self) -> Vec<String> {
+            vec![self.address().to_string()]
+        }
+
+        /// How long a connection may stay silent before the server drops it.
+        /// `None` keeps idle connections open forever.
+        fn idle_timeout(&self) -> Option<Duration> {
+            None
+        }
+
+        /// Upper bound on simultaneously open connections. `None` is unlimited.
+        fn max_connections(&self) -> Option<usize> {
+            None
+        }
+
+        fn overload_policy(&self) -> OverloadPolicy {
+            OverloadPolicy::Reject
+        }
+
+        /// Per-connection command rate limit. `None` disables throttling.
+        fn rate_limit(&self) -> Option<RateLimit> {
+            None
+        }
+
+        /// Largest frame payload, in bytes, a client may send.
+        fn max_frame_size(&self) -> usize {
+            DEFAULT_MAX_FRAME_SIZE
+        }
+
+        fn protocol_error_policy(&self) -> ProtocolErrorPolicy {
+            ProtocolErrorPolicy::Recover
+        }
+
+        /// Where to serve Prometheus metrics over HTTP. `None` disables the endpoint.
+        fn metrics_address(&self) -> Option<String> {
+            None
+        }
+
+        /// Longest a single command may run before it is abandoned with
+        /// `ErrorCode::Timeout`. `None` means no limit.
+        fn command_timeout(&self) -> Option<Duration> {
+            None
+        }
+
+        /// Threads that run commands for every connection. `None` runs each
+        /// connection's commands on that connection's own thread.
+        fn command_workers(&self) -> Option<usize> {
+            None
+        }
+
+        /// Threads that serve connections, each holding one for as long as
+        /// it stays open. Also caps open connections at this many, whatever
+        /// `max_connections` says. `None` spawns a thread per connection.
+        fn connection_threads(&self) -> Option<usize> {
+            None
+        }
+
+        /// Queue each connection's replies and pushes, sending them together
+        /// at least this often. `None` writes every message as it is sent.
+        fn coalesce_window(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    /// How the server reacts to a well-framed but invalid message.
+    /// Framing errors always close the connection since the stream can no longer be trusted.
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ProtocolErrorPolicy {
+        /// Reply with an `ErrorCode::ProtocolError` and keep reading.
+        Recover,
+        /// Reply with an `ErrorCode::ProtocolError` and close.
+        Close,
+    }
+
+    pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+    /// Newest protocol version this server speaks. Version 1 is the original
+    /// protocol without a handshake; clients that never say hello get it.
+    pub const PROTOCOL_VERSION: u32 = 2;
+    pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+    /// Optional features a connection opts into with [`super::Message::Hello`].
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Capability {
+        /// Server pushes: watch notifications and admin broadcasts.
+        Notifications,
+        /// Large ranges arrive as chunks rather than one frame.
+        Streaming,
+        /// `get <range> arrow` answers with an Arrow IPC stream in a binary
+        /// frame (see [`ARROW_FRAME`]). Only granted to clients that ask, and
+        /// only by builds with the `arrow` feature.
+        Arrow,
+    }
+
+    /// Everything a connection gets when it skips the handshake.
+    pub const ALL_CAPABILITIES: &[Capability] = &[Capability::Notifications, Capability::Streaming];
+
+    /// Capabilities granted only when a client asks for them in its hello.
+    pub const OPT_IN_CAPABILITIES: &[Capability] = if cfg!(feature = "arrow") { &[Capability::Arrow] } else { &[] };
+
+    /// Set in a framed length to mark a payload of raw Arrow IPC bytes rather
+    /// than JSON. Frames never come near 2 GiB, so the bit is otherwise unused.
+    pub const ARROW_FRAME: u32 = 1 << 31;
+
+    #[derive(Debug, PartialEq, thiserror::Error)]
+    pub enum ProtocolError {
+        #[error("Frame of {len} bytes exceeds the {max} byte limit")]
+        FrameTooLarge { len: usize, max: usize },
+        /// The frame was read completely but did not decode to a `Message`.
+        #[error("Malformed message: {0}")]
+        Malformed(String),
+        /// A valid `Message` that clients are not allowed to send.
+        #[error("Unexpected message: {0}")]
+        UnexpectedMessage(String),
+    }
+
+    impl ProtocolError {
+        /// Whether the stream is still aligned on a frame boundary after this error.
+        pub fn is_recoverable(&self) -> bool {
+            !matches!(self, ProtocolError::FrameTooLarge { .. })
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct RateLimit {
+        pub per_second: f64,
+        pub burst: u32,
+    }
+
+    /// Token bucket refilled continuously at `per_second`, holding at most `burst` tokens.
+    pub struct TokenBucket {
+        limit: RateLimit,
+        tokens: f64,
+        last_refill: Instant,
+    }
+
+    impl TokenBucket {
+        pub fn new(limit: RateLimit) -> Self {
+            TokenBucket {
+                limit,
+                tokens: limit.burst as f64,
+                last_refill: Instant::now(),
+            }
+        }
+
+        /// Takes one token if available.
+        pub fn try_acquire(&mut self) -> bool {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.last_refill = now;
+            self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+            if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    /// What the server does with a new client once `max_connections` is reached.
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum OverloadPolicy {
+        /// Stop accepting until a slot frees up; clients wait in the OS backlog.
+        Queue,
+        /// Accept, reply with an `ErrorCode::ServerBusy` error and close.
+        Reject,
+    }
+
+    pub struct TcpManager {
+        addresses: Vec<String>,
+        idle_timeout: Option<Duration>,
+        max_connections: Option<usize>,
+        overload_policy: OverloadPolicy,
+        rate_limit: Option<RateLimit>,
+        max_frame_size: usize,
+        protocol_error_policy: ProtocolErrorPolicy,
+        metrics_address: Option<String>,
+        command_timeout: Option<Duration>,
+        command_workers: Option<usize>,
+        connection_threads: Option<usize>,
+        coalesce_window: Option<Duration>,
+    }
+
+    impl TcpManager {
+        pub fn new(address: String) -> Self {
+            Self::bind_all(vec![address])
+        }
+
+        /// Listens on every address, e.g. `["127.0.0.1:8080", "[::1]:8080"]`.
+        pub fn bind_all(addresses: Vec<String>) -> Self {
+            TcpManager {
+                addresses,
+                idle_timeout: None,
+                max_connections: None,
+                overload_policy: OverloadPolicy::Reject,
+                rate_limit: None,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                protocol_error_policy: ProtocolErrorPolicy::Recover,
+                metrics_address: None,
+                command_timeout: None,
+                command_workers: None,
+                connection_threads: None,
+                coalesce_window: None,
+            }
+        }
+
+        pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+            self.idle_timeout = Some(timeout);
+            self
+        }
+
+        pub fn with_max_connections(mut self, max: usize, policy: OverloadPolicy) -> Self {
+            self.max_connections = Some(max);
+            self.overload_policy = policy;
+            self
+        }
+
+        pub fn with_rate_limit(mut self, per_second: f64, burst: u32) -> Self {
+            self.rate_limit = Some(RateLimit { per_second, burst });
+            self
+        }
+
+        pub fn with_max_frame_size(mut self, max: usize) -> Self {
+            self.max_frame_size = max;
+            self
+        }
+
+        pub fn with_protocol_error_policy(mut self, policy: ProtocolErrorPolicy) -> Self {
+            self.protocol_error_policy = policy;
+            self
+        }
+
+        pub fn with_metrics_address(mut self, address: String) -> Self {
+            self.metrics_address = Some(address);
+            self
+        }
+
+        pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+            self.command_timeout = Some(timeout);
+            self
+        }
+
+        pub fn with_command_workers(mut self, workers: usize) -> Self {
+            self.command_workers = Some(workers);
+            self
+        }
+
+        pub fn with_connection_threads(mut self, threads: usize) -> Self {
+            self.connection_threads = Some(threads);
+            self
+        }
+
+        pub fn with_coalesce_window(mut self, window: Duration) -> Self {
+            self.coalesce_window = Some(window);
+            self
+        }
+    }
+
+    impl Manager for TcpManager {
+        fn address(&self) -> &str {
+            &self.addresses[0]
+        }
+
+        fn addresses(&self) -> Vec<String> {
+            self.addresses.clone()
+        }
+
+        fn idle_timeout(&self) -> Option<Duration> {
+            self.idle_timeout
+        }
+
+        fn max_connections(&self) -> Option<usize> {
+            self.max_connections
+        }
+
+        fn overload_policy(&self) -> OverloadPolicy {
+            self.overload_policy
+        }
+
+        fn rate_limit(&self) -> Option<RateLimit> {
+            self.rate_limit
+        }
+
+        fn max_frame_size(&self) -> usize {
+            self.max_frame_size
+        }
+
+        fn protocol_error_policy(&self) -> ProtocolErrorPolicy {
+            self.protocol_error_policy
+        }
+
+        fn metrics_address(&self) -> Option<String> {
+            self.metrics_address.clone()
+        }
+
+        fn command_timeout(&self) -> Option<Duration> {
+            self.command_timeout
+        }
+
+        fn command_workers(&self) -> Option<usize> {
+            self.command_workers
+        }
+
+        fn connection_threads(&self) -> Option<usize> {
+            self.connection_threads
+        }
+
+        fn coalesce_window(&self) -> Option<Duration> {
+            self.coalesce_window
+        }
+    }
+
+    /// How messages are laid out on the wire.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum WireMode {
+        /// 4-byte big-endian length followed by a JSON `Message`.
+        Framed,
+        /// One command per line in, plain-text replies out, for `nc`/`telnet` and scripts.
+        Text,
+    }
+
+    /// Picks the wire mode from the first byte a client sends. A framed message
+    /// starts with the high byte of its length, which is far below any printable
+    /// character for frames within the size limit.
+    #[cfg(feature = "net")]
+    pub fn detect_mode(stream: &TcpStream) -> std::io::Result<WireMode> {
+        let mut first = [0u8; 1];
+        stream.peek(&mut first)?;
+        Ok(if first[0].is_ascii_graphic() || first[0].is_ascii_whitespace() {
+            WireMode::Text
+        } else {
+            WireMode::Framed
+        })
+    }
+
+    /// Capacity a frame buffer keeps between messages. A larger frame grows
+    /// it for that message only, so one big range does not pin its memory.
+    const RETAINED_BUFFER: usize = 64 * 1024;
+
+    #[cfg(feature = "net")]
+    pub struct Reader {
+        stream: BufReader<TcpStream>,
+        max_frame_size: usize,
+        mode: WireMode,
+        arrow_frames: bool,
+        /// Reused for every frame's payload.
+        buf: BytesMut,
+    }
+    
+    #[cfg(feature = "net")]
+    impl Reader {
+        pub fn new(stream: TcpStream) -> Self {
+            Reader {
+                stream: BufReader::new(stream),
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                mode: WireMode::Framed,
+                arrow_frames: false,
+                buf: BytesMut::new(),
+            }
+        }
+
+        pub fn text(stream: TcpStream) -> Self {
+            Reader { mode: WireMode::Text, ..Self::new(stream) }
+        }
+
+        pub fn with_max_frame_size(mut self, max: usize) -> Self {
+            self.max_frame_size = max;
+            self
+        }
+
+        /// Reads frames flagged with [`ARROW_FRAME`] as [`super::Reply::Arrow`].
+        /// Only clients do this; a server has no use for binary frames.
+        pub fn with_arrow_frames(mut self) -> Self {
+            self.arrow_frames = true;
+            self
+        }
+
+        /// True if input has already arrived that the next read consumes without waiting.
+        pub fn has_buffered(&self) -> bool {
+            !self.stream.buffer().is_empty()
+        }
+    
+        pub fn read_message(&mut self) -> Result<super::Message, RSheetError> {
+            if self.mode == WireMode::Text {
+                return self.read_line();
+            }
+            let mut len_buf = [0; 4];
+            self.stream.read_exact(&mut len_buf)?;
+            let header = u32::from_be_bytes(len_buf);
+            let arrow = self.arrow_frames && header & ARROW_FRAME != 0;
+            let len = if arrow { header & !ARROW_FRAME } else { header } as usize;
+            if len > self.max_frame_size {
+                return Err(ProtocolError::FrameTooLarge { len, max: self.max_frame_size }.into());
+            }
+    
+            if arrow {
+                // The payload is the reply itself, so it is read straight into it.
+                let mut bytes = vec![0; len];
+                self.stream.read_exact(&mut bytes)?;
+                return Ok(super::Message::Reply(super::Reply::Arrow(bytes)));
+            }
+
+            self.buf.clear();
+            self.buf.resize(len, 0);
+            self.stream.read_exact(&mut self.buf)?;
+            let msg = serde_json::from_slice::<super::Message>(&self.buf);
+            if self.buf.capacity() > RETAINED_BUFFER {
+                self.buf = BytesMut::new();
+            }
+            msg.map_err(|e| ProtocolError::Malformed(e.to_string()).into())
+        }
+
+        /// Reads the next non-blank line as a command; `ping` maps to `Message::Ping`.
+        fn read_line(&mut self) -> Result<super::Message, RSheetError> {
+            loop {
+                let mut line = Vec::new();
+                let limit = self.max_frame_size as u64 + 1;
+                let read = (&mut self.stream).take(limit).read_until(b'\n', &mut line)?;
+                if read == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+                if line.len() > self.max_frame_size {
+                    return Err(ProtocolError::FrameTooLarge { len: line.len(), max: self.max_frame_size }.into());
+                }
+                let line = String::from_utf8(line).map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+                match line.trim() {
+                    "" => continue,
+                    "ping" => return Ok(super::Message::Ping),
+                    text => return Ok(super::Message::Command(super::command::Command::parse(text))),
+                }
+            }
+        }
+    }
+    
+    /// Where a [`Writer`] sends. A `TcpStream` is one with the `net`
+    /// feature; an embedder serving clients some other way supplies its own.
+    pub trait Transport: Write + Send {
+        /// Closes both directions, as shutting down a socket does.
+        fn shutdown(&self) -> std::io::Result<()>;
+    }
+
+    #[cfg(feature = "net")]
+    impl Transport for TcpStream {
+        fn shutdown(&self) -> std::io::Result<()> {
+            TcpStream::shutdown(self, std::net::Shutdown::Both)
+        }
+    }
+
+    pub struct Writer {
+        stream: Box<dyn Transport>,
+        mode: WireMode,
+        /// Reused to serialize every message.
+        buf: BytesMut,
+        /// Frames a coalescing writer has queued for the next flush.
+        out: Option<BytesMut>,
+    }
+
+    /// A writer shared between a connection's reply path and server pushes.
+    pub type SharedWriter = Arc<Mutex<Writer>>;
+    
+    impl Writer {
+        pub fn new(stream: impl Transport + 'static) -> Self {
+            Writer { stream: Box::new(stream), mode: WireMode::Framed, buf: BytesMut::new(), out: None }
+        }
+
+        pub fn text(stream: impl Transport + 'static) -> Self {
+            Writer { mode: WireMode::Text, ..Self::new(stream) }
+        }
+    
+        pub fn write_message(&mut self, reply: super::Reply) -> Result<(), RSheetError> {
+            self.send(&super::Message::Reply(reply))
+        }
+
+        /// Queues frames instead of sending each one, so a burst goes out in
+        /// one write on [`Writer::flush`]. A queue past [`RETAINED_BUFFER`]
+        /// bytes is flushed by the send that filled it.
+        pub fn coalescing(mut self) -> Self {
+            self.out = Some(BytesMut::new());
+            self
+        }
+
+        /// True if queued frames are waiting for a flush.
+        pub fn has_pending(&self) -> bool {
+            self.out.as_ref().is_some_and(|out| !out.is_empty())
+        }
+
+        /// Sends every queued frame. Does nothing for a writer that is not coalescing.
+        pub fn flush(&mut self) -> std::io::Result<()> {
+            let out = match self.out.as_mut() {
+                Some(out) if !out.is_empty() => out,
+                _ => return Ok(()),
+            };
+            let written = self.stream.write_all(out);
+            out.clear();
+            if out.capacity() > RETAINED_BUFFER {
+                *out = BytesMut::new();
+            }
+            written
+        }
+
+        /// Sends what is queued, then closes both directions of the underlying socket.
+        pub fn shutdown(&mut self) -> std::io::Result<()> {
+            let _ = self.flush();
+            self.stream.shutdown()
+        }
+
+        pub fn send(&mut self, msg: &super::Message) -> Result<(), RSheetError> {
+            if let Some(out) = self.out.as_mut() {
+                encode(out, self.mode, msg)?;
+                if out.len() >= RETAINED_BUFFER {
+                    self.flush()?;
+                }
+                return Ok(());
+            }
+            if self.mode == WireMode::Text {
+                let text = msg.to_text();
+                write_all_vectored(&mut self.stream, &mut [IoSlice::new(text.as_bytes()), IoSlice::new(b"\n")])?;
+                return Ok(());
+            }
+            if let super::Message::Reply(super::Reply::Arrow(bytes)) = msg {
+                let header = (bytes.len() as u32 | ARROW_FRAME).to_be_bytes();
+                write_all_vectored(&mut self.stream, &mut [IoSlice::new(&header), IoSlice::new(bytes)])?;
+                return Ok(());
+            }
+            self.buf.clear();
+            serde_json::to_writer((&mut self.buf).writer(), msg)?;
+            let header = (self.buf.len() as u32).to_be_bytes();
+            let written = write_all_vectored(&mut self.stream, &mut [IoSlice::new(&header), IoSlice::new(&self.buf)]);
+            if self.buf.capacity() > RETAINED_BUFFER {
+                self.buf = BytesMut::new();
+            }
+            Ok(written?)
+        }
+    }
+
+    /// Appends `msg` to `out` as it goes on the wire.
+    fn encode(out: &mut BytesMut, mode: WireMode, msg: &super::Message) -> Result<(), serde_json::Error> {
+        match msg {
+            _ if mode == WireMode::Text => {
+                out.put_slice(msg.to_text().as_bytes());
+                out.put_u8(b'\n');
+            }
+            super::Message::Reply(super::Reply::Arrow(bytes)) => {
+                out.put_u32(bytes.len() as u32 | ARROW_FRAME);
+                out.put_slice(bytes);
+            }
+            _ => {
+                // The length goes in front once the payload is written.
+                let start = out.len();
+                out.put_u32(0);
+                if let Err(e) = serde_json::to_writer((&mut *out).writer(), msg) {
+                    out.truncate(start);
+                    return Err(e);
+                }
+                let len = (out.len() - start - 4) as u32;
+                out[start..start + 4].copy_from_slice(&len.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every slice, header and payload in as few calls as the socket allows.
+    fn write_all_vectored(stream: &mut impl Write, mut slices: &mut [IoSlice<'_>]) -> std::io::Result<()> {
+        while !slices.is_empty() {
+            match stream.write_vectored(slices) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut slices, n),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+}
+
+pub mod replies {
+    use super::*;
+
+    #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+    pub enum Reply {
+        Ok,
+        /// A cell's value, shared with the store it was read from.
+        Value(Arc<CellValue>),
+        /// The values a multi-cell `get` named, in the order it named them.
+        Values(Vec<Arc<CellValue>>),
+        /// Every connected client and where it is working.
+        Presence(Vec<crate::clients::Presence>),
+        /// The writes a `sync`ing replica had not seen.
+        Synced(crate::crdt::SyncResponse),
+        /// `get <cell> version`: the value and the version to pass to `set ... if-version`.
+        Versioned { value: Arc<CellValue>, version: u64 },
+        Error(ReplyError),
+        Watches(Vec<crate::subscriptions::Watch>),
+        /// Values of a range, one inner `Vec` per row.
+        Range { range: crate::address::CellRange, values: Vec<Vec<CellValue>> },
+        /// Ends a range whose rows were sent ahead of it as [`crate::Message::Chunk`]s.
+        Streamed { range: crate::address::CellRange },
+        Session(crate::SessionState),
+        /// Recent mutating commands, oldest first.
+        Audit(Vec<crate::audit::AuditEntry>),
+        Clients(Vec<crate::clients::ClientSummary>),
+        /// Where an import, `transpose` or `shift` landed and how many cells it filled.
+        Imported { range: crate::address::CellRange, cells: usize },
+        /// A rendered export, e.g. CSV text.
+        Exported(String),
+        /// A range as an Arrow IPC stream; sent as a binary frame, not JSON.
+        Arrow(Vec<u8>),
+        /// Estimated memory per sheet, in name order.
+        Memory(Vec<crate::meminfo::SheetMemory>),
+        /// Commands past the slow log's threshold, newest first.
+        SlowLog(Vec<crate::slowlog::SlowEntry>),
+        Webhooks(Vec<crate::webhooks::Registered>),
+        /// A connection's alert rules; `alert when` answers with the new one.
+        Alerts(Vec<crate::alerts::AlertRule>),
+        /// Webhook deliveries that failed every try, oldest first.
+        DeadLetters(Vec<crate::webhooks::DeadLetter>),
+        /// Rows computed from a range, e.g. by `query`, under named columns.
+        Table(crate::query::Table),
+        /// The rows `filter` matched, in row order.
+        Rows(Vec<crate::query::RowMatch>),
+        /// How many duplicate rows `dedupe` dropped from a range.
+        Removed { range: crate::address::CellRange, rows: usize },
+        /// A user's quotas and usage, from `admin quota`.
+        Quota(crate::quota::UserUsage),
+        /// Statistics of a range's numbers, from `describe`.
+        Described(crate::stats::Description),
+        /// How a sheet is shown, from `view`.
+        View(crate::view::SheetView),
+        /// Every chart, in name order.
+        Charts(Vec<crate::chart::NamedChart>),
+        /// A chart's series as they stand, from `chart data`.
+        ChartData(crate::chart::ChartData),
+        /// Every alias and the cell it names, in name order.
+        Aliases(std::collections::BTreeMap<String, String>),
+        /// The formulas that read a cell, from `whereused`, nearest first.
+        WhereUsed(Vec<crate::graph::Dependent>),
+        /// How many cells `clear` emptied, and the formulas outside the range
+        /// that read them, whose values are now stale.
+        Cleared { range: crate::address::CellRange, cells: usize, dependents: Vec<String> },
+        /// Every scenario and its overrides, in name order.
+        Scenarios(Vec<crate::scenario::NamedScenario>),
+        /// Every macro and its commands, in name order.
+        Macros(Vec<crate::macros::NamedMacro>),
+        /// The row `append` filled, one-based, and the cells it wrote.
+        Appended { row: u32, range: crate::address::CellRange },
+    }
+
+    /// Machine-readable error kind; clients should branch on this rather than the message.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    pub enum ErrorCode {
+        ParseError,
+        UnknownCell,
+        TypeMismatch,
+        DivByZero,
+        CircularRef,
+        Unauthorized,
+        ServerBusy,
+        Throttled,
+        ProtocolError,
+        UnknownClient,
+        Timeout,
+        StorageError,
+        /// History is off, or the requested time is before what is retained.
+        HistoryUnavailable,
+        /// Another commit changed a cell the transaction wrote since it began.
+        Conflict,
+        /// A sheet has reached one of its quotas, or the server its memory limit.
+        QuotaExceeded,
+        /// Another client holds an edit lease on the cell.
+        Locked,
+        /// The server does not take changes, e.g. because it is a replica.
+        ReadOnly,
+        /// The node serving the sheet could not be reached.
+        NodeUnavailable,
+        /// Not a cell reference, such as `1A`, `foo` or a row past the last.
+        InvalidReference,
+        /// `goalseek` found no input that brings the cell to its target.
+        NoConvergence,
+        /// `set` would replace a formula with a value on a sheet that
+        /// protects its formulas; `--force` overwrites it anyway.
+        FormulaProtected,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct ReplyError {
+        pub code: ErrorCode,
+        pub message: String,
+        /// Which quota was hit, for `QuotaExceeded`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub quota: Option<crate::quota::QuotaViolation>,
+        /// Who holds the cell, for `Locked`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub lease: Option<Box<crate::leases::LeaseHolder>>,
+        /// The message in English, when `message` has been translated for
+        /// the session's language.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub detail: Option<String>,
+    }
+
+    impl ReplyError {
+        pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+            ReplyError { code, message: message.into(), quota: None, lease: None, detail: None }
+        }
+
+        pub fn quota(violation: crate::quota::QuotaViolation) -> Self {
+            ReplyError { quota: Some(violation.clone()), ..ReplyError::new(ErrorCode::QuotaExceeded, violation.to_string()) }
+        }
+
+        pub fn locked(holder: crate::leases::LeaseHolder) -> Self {
+            ReplyError { message: holder.to_string(), lease: Some(Box::new(holder)), ..ReplyError::new(ErrorCode::Locked, "") }
+        }
+    }
+
+    impl std::fmt::Display for ReplyError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}: {}", self.code, self.message)
+        }
+    }
+
+    impl Error for ReplyError {}
+
+    impl Reply {
+        pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+            Reply::Error(ReplyError::new(code, message))
+        }
+
+        /// Plain-text rendering used by the line protocol. Multi-line for ranges and lists.
+        pub fn to_text(&self) -> String {
+            match self {
+                Reply::Ok => "ok".to_string(),
+                Reply::Value(value) => value_text(value),
+                Reply::Values(values) => values.iter().map(|value| value_text(value)).collect::<Vec<_>>().join("\t"),
+                Reply::Presence(clients) => clients.iter().map(presence_text).collect::<Vec<_>>().join("\n"),
+                Reply::Synced(response) => serde_json::to_string(response).unwrap_or_default(),
+                Reply::Versioned { value, version } => format!("{} version {}", value_text(value), version),
+                Reply::Error(e) => match &e.detail {
+                    Some(detail) => format!("error {:?}: {} ({})", e.code, e.message, detail),
+                    None => format!("error {:?}: {}", e.code, e.message),
+                },
+                Reply::Watches(watches) => watches
+                    .iter()
+                    .map(|w| match &w.sheet {
+                        Some(sheet) => format!("{}!{} {:?}", sheet, w.range, w.filter),
+                        None => format!("{} {:?}", w.range, w.filter),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Reply::Range { values, .. } => rows_text(values),
+                Reply::Streamed { range } => format!("end {}", range),
+                Reply::Session(state) => format!(
+                    "sheet={} locale={} language={} format={} precision={}",
+                    state.sheet.as_deref().unwrap_or(crate::address::DEFAULT_SHEET),
+                    state.locale.as_deref().unwrap_or("-"),
+                    state.language().unwrap_or("-"),
+                    state.format.as_deref().unwrap_or("-"),
+                    state.precision.map_or("-".to_string(), |digits| digits.to_string()),
+                ),
+                Reply::Audit(entries) => entries
+                    .iter()
+                    .map(|e| {
+                        let value = |v: &Option<CellValue>| v.as_ref().map_or("-".to_string(), value_text);
+                        format!(
+                            "{} {} {} {} {} -> {}",
+                            e.timestamp_ms,
+                            e.connection,
+                            e.peer.as_deref().unwrap_or("-"),
+                            e.command,
+                            value(&e.old),
+                            value(&e.new),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Reply::Imported { range, cells } => format!("imported {} cells into {}", cells, range),
+                Reply::Exported(text) => text.trim_end().to_string(),
+                Reply::Arrow(bytes) => format!("arrow {} bytes", bytes.len()),
+                Reply::Clients(clients) => clients
+                    .iter()
+                    .map(|c| {
+                        format!(
+                            "{} {} connected={}s idle={}s commands={}{}",
+                            c.id,
+                            c.peer.as_deref().unwrap_or("-"),
+                            c.connected_secs,
+                            c.idle_secs,
+                            c.commands,
+                            if c.admin { " admin" } else { "" },
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Reply::Memory(sheets) => sheets
+                    .iter()
+                    .map(|m| {
+                        format!(
+                            "{} cells={} history={} formulas={} total={}",
+                            m.sheet,
+                            m.cells,
+                            m.history,
+                            m.formulas,
+                            m.total()
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Reply::SlowLog(entries) => entries
+                    .iter()
+                    .map(|e| format!("{} {} {} {}us cells={} {}", e.id, e.timestamp_ms, e.connection, e.duration_us, e.cells, e.command))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Reply::Webhooks(hooks) => hooks
+                    .iter()
+                    .map(|h| format!("{} {} {}", h.id, h.webhook.url, h.webhook.range.as_deref().unwrap_or("*")))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Reply::Alerts(rules) => rules.iter().map(|r| format!("{} {}", r.id, r)).collect::<Vec<_>>().join("\n"),
+                Reply::DeadLetters(letters) => letters
+                    .iter()
+                    .map(|l| format!("{} {} {} attempts={} {}", l.timestamp_ms, l.url, l.payload.cell, l.attempts, l.error))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Reply::Table(table) if table.rows.is_empty() => table.columns.join("\t"),
+                Reply::Table(table) => format!("{}\n{}", table.columns.join("\t"), rows_text(&table.rows)),
+                Reply::Rows(rows) => rows
+                    .iter()
+                    .map(|row| format!("{}\t{}", row.cells, rows_text(std::slice::from_ref(&row.values))))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Reply::Removed { range, rows } => format!("removed {} rows from {}", rows, range),
+                Reply::View(view) => view.to_string(),
+                Reply::Charts(charts) => charts.iter().map(|c| format!("{} {}", c.name, c.chart)).collect::<Vec<_>>().join("\n"),
+                Reply::Scenarios(scenarios) => {
+                    scenarios.iter().map(|s| format!("{} {}", s.name, s.scenario).trim_end().to_string()).collect::<Vec<_>>().join("\n")
+                }
+                Reply::Appended { row, range } => format!("appended row {} ({})", row, range),
+                Reply::Macros(macros) => macros.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"),
+                Reply::Aliases(aliases) => aliases.iter().map(|(name, cell)| format!("{} {}", name, cell)).collect::<Vec<_>>().join("\n"),
+                Reply::Cleared { range, cells, dependents } if dependents.is_empty() => format!("cleared {} cells from {}", cells, range),
+                Reply::Cleared { range, cells, dependents } => {
+                    format!("cleared {} cells from {}\ndependents {}", cells, range, dependents.join(" "))
+                }
+                Reply::WhereUsed(dependents) => dependents
+                    .iter()
+                    .map(|d| format!("{}\t{}\t{}", d.depth, d.cell, d.expr))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Reply::ChartData(data) => {
+                    let number = |n: &Option<f64>| n.map_or(String::new(), |n| CellValue::Number(n).to_string());
+                    let mut lines = vec![format!("{} {}", data.name, data.kind)];
+                    if let Some(labels) = &data.labels {
+                        lines.push(format!("labels\t{}", labels.join("\t")));
+                    }
+                    for series in &data.series {
+                        let values: Vec<String> = series.values.iter().map(number).collect();
+                        lines.push(format!("{}\t{}", series.range, values.join("\t")));
+                    }
+                    lines.join("\n")
+                }
+                Reply::Described(description) => {
+                    let number = |n: f64| CellValue::Number(n).to_string();
+                    let optional = |n: Option<f64>| n.map_or("-".to_string(), number);
+                    let mut lines = vec![
+                        format!("count {}", description.count),
+                        format!("sum {}", number(description.sum)),
+                        format!("mean {}", optional(description.mean)),
+                        format!("min {}", optional(description.min)),
+                        format!("max {}", optional(description.max)),
+                        format!("stddev {}", optional(description.stddev)),
+                    ];
+                    for bin in description.histogram.iter().flatten() {
+                        lines.push(format!("bin {} {} {}", number(bin.lower), number(bin.upper), bin.count));
+                    }
+                    lines.join("\n")
+                }
+                Reply::Quota(usage) => {
+                    let limit = |limit: Option<u64>| limit.map_or("-".to_string(), |limit| limit.to_string());
+                    let quotas = usage.quotas;
+                    format!(
+                        "{} cells={}/{} bytes={}/{} commands={}/{}",
+                        usage.user,
+                        usage.cells,
+                        limit(quotas.max_cells.map(|n| n as u64)),
+                        usage.bytes,
+                        limit(quotas.max_bytes),
+                        usage.commands,
+                        limit(quotas.max_commands_per_minute.map(u64::from)),
+                    )
+                }
+            }
+        }
+    }
+
+    /// Tab-separated values, one line per row.
+    pub fn rows_text(rows: &[Vec<CellValue>]) -> String {
+        rows.iter()
+            .map(|row| row.iter().map(value_text).collect::<Vec<_>>().join("\t"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn value_text(value: &CellValue) -> String {
+        value.to_string()
+    }
+
+    /// `reply` with every number in its values rounded to `digits`
+    /// significant digits, as `precision` asks.
+    pub fn round_numbers(reply: Reply, digits: u32) -> Reply {
+        let round = |value: CellValue| match value {
+            CellValue::Number(n) => CellValue::Number(crate::numeric::round_significant(n, digits)),
+            CellValue::Currency { amount, code } => {
+                CellValue::Currency { amount: crate::numeric::round_significant(amount, digits), code }
+            }
+            value => value,
+        };
+        let round_shared = |value: Arc<CellValue>| match *value {
+            CellValue::Number(_) | CellValue::Currency { .. } => Arc::new(round(CellValue::clone(&value))),
+            _ => value,
+        };
+        let round_rows = |rows: Vec<Vec<CellValue>>| -> Vec<Vec<CellValue>> {
+            rows.into_iter().map(|row| row.into_iter().map(round).collect()).collect()
+        };
+        match reply {
+            Reply::Value(value) => Reply::Value(round_shared(value)),
+            Reply::Values(values) => Reply::Values(values.into_iter().map(round_shared).collect()),
+            Reply::Versioned { value, version } => Reply::Versioned { value: round_shared(value), version },
+            Reply::Range { range, values } => Reply::Range { range, values: round_rows(values) },
+            Reply::Table(table) => Reply::Table(crate::query::Table { rows: round_rows(table.rows), ..table }),
+            Reply::Described(description) => {
+                let round = |n: f64| crate::numeric::round_significant(n, digits);
+                Reply::Described(crate::stats::Description {
+                    sum: round(description.sum),
+                    mean: description.mean.map(round),
+                    min: description.min.map(round),
+                    max: description.max.map(round),
+                    stddev: description.stddev.map(round),
+                    histogram: description.histogram.map(|bins| {
+                        bins.into_iter().map(|bin| crate::stats::Bin { lower: round(bin.lower), upper: round(bin.upper), ..bin }).collect()
+                    }),
+                    ..description
+                })
+            }
+            Reply::Rows(rows) => Reply::Rows(
+                rows.into_iter()
+                    .map(|row| crate::query::RowMatch { values: row.values.into_iter().map(round).collect(), ..row })
+                    .collect(),
+            ),
+            reply => reply,
+        }
+    }
+
+    pub fn presence_text(presence: &crate::clients::Presence) -> String {
+        let state = if presence.connected { "" } else { " left" };
+        format!("{} {} {}{}", presence.connection, presence.name, presence.focus.as_deref().unwrap_or("-"), state)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Message {
+    Command(command::Command),
+    Reply(replies::Reply),
+    Ping,
+    Pong,
+    /// Pushed by the server when a watched cell changes.
+    Notify { cell: String, value: CellValue },
+    /// Server-wide announcement sent by an admin.
+    Broadcast(String),
+    /// Rows of a large range, sent ahead of its [`replies::Reply::Streamed`].
+    Chunk(Vec<Vec<CellValue>>),
+    /// Pushed, to clients that sent `session presence on`, when another
+    /// client names itself, moves its focus or leaves.
+    Presence(clients::Presence),
+    /// Handshake. The client sends the newest version it speaks and the
+    /// capabilities it wants; the server answers with the version and
+    /// capabilities the connection will use.
+    Hello { version: u32, capabilities: Vec<connect::Capability> },
+    /// Streamed by a primary to a replica that sent `replicate`.
+    Replicate(replication::Event),
+    /// Pushed to the connection that set an alert rule when it fires.
+    Alert(alerts::Alert),
+}
+
+impl Message {
+    /// Plain-text rendering used by the line protocol.
+    pub fn to_text(&self) -> String {
+        match self {
+            Message::Command(cmd) => cmd.to_string(),
+            Message::Reply(reply) => reply.to_text(),
+            Message::Ping => "ping".to_string(),
+            Message::Pong => "pong".to_string(),
+            Message::Notify { cell, value } => format!("notify {} {}", cell, replies::value_text(value)),
+            Message::Broadcast(text) => format!("broadcast {}", text),
+            Message::Chunk(rows) => replies::rows_text(rows),
+            Message::Presence(presence) => format!("presence {}", replies::presence_text(presence)),
+            Message::Hello { version, capabilities } => format!("hello {} {:?}", version, capabilities),
+            Message::Alert(alert) => format!("alert {}", alert),
+            Message::Replicate(event) => format!("replicate {}", serde_json::to_string(event).unwrap_or_default()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CellValue {
+    Number(f64),
+    Text(String),
+    Error(String),
+    /// An amount of money in the currency with ISO 4217 `code`, as `set A1 19.99USD` stores.
+    Currency { amount: f64, code: String },
+    /// A length of time in seconds, as `set A1 2h30m` stores.
+    Duration(f64),
+    /// A moment in Unix milliseconds, as `set A1 2024-06-01T09:00:00Z` stores.
+    DateTime(i64),
+    /// What an unset cell reads as. It is never stored, and a formula
+    /// takes it as `0`.
+    Empty,
+}
+
+impl From<f64> for CellValue {
+    fn from(n: f64) -> Self {
+        CellValue::Number(n)
+    }
+}
+
+impl From<i32> for CellValue {
+    fn from(n: i32) -> Self {
+        CellValue::Number(n.into())
+    }
+}
+
+/// `1` or `0`, as the comparison operators give.
+impl From<bool> for CellValue {
+    fn from(b: bool) -> Self {
+        CellValue::Number(if b { 1.0 } else { 0.0 })
+    }
+}
+
+impl From<&str> for CellValue {
+    fn from(text: &str) -> Self {
+        CellValue::Text(text.to_string())
+    }
+}
+
+impl From<String> for CellValue {
+    fn from(text: String) -> Self {
+        CellValue::Text(text)
+    }
+}
+
+impl CellValue {
+    fn mismatch(&self, wanted: &str) -> RSheetError {
+        RSheetError::Eval { code: ErrorCode::TypeMismatch, message: format!("Expected {}, got {}", wanted, self) }
+    }
+}
+
+impl TryFrom<CellValue> for f64 {
+    type Error = RSheetError;
+
+    fn try_from(value: CellValue) -> Result<Self, Self::Error> {
+        match value {
+            CellValue::Number(n) => Ok(n),
+            other => Err(other.mismatch("a number")),
+        }
+    }
+}
+
+/// Only numbers with no fractional part that fit.
+impl TryFrom<CellValue> for i64 {
+    type Error = RSheetError;
+
+    fn try_from(value: CellValue) -> Result<Self, Self::Error> {
+        match value {
+            CellValue::Number(n) if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 => Ok(n as i64),
+            other => Err(other.mismatch("a whole number")),
+        }
+    }
+}
+
+/// Any number other than `0` is true.
+impl TryFrom<CellValue> for bool {
+    type Error = RSheetError;
+
+    fn try_from(value: CellValue) -> Result<Self, Self::Error> {
+        match value {
+            CellValue::Number(n) => Ok(n != 0.0),
+            other => Err(other.mismatch("a number")),
+        }
+    }
+}
+
+/// Text only; use [`ToString`] to render a number.
+impl TryFrom<CellValue> for String {
+    type Error = RSheetError;
+
+    fn try_from(value: CellValue) -> Result<Self, Self::Error> {
+        match value {
+            CellValue::Text(text) => Ok(text),
+            other => Err(other.mismatch("text")),
+        }
+    }
+}
+
+/// Source of a computed cell, kept so the cell can be saved and re-evaluated.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Formula {
+    pub expr: String,
+    /// Sheet that unqualified references in `expr` resolve against; `None` is the default sheet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sheet: Option<String>,
+}
+
+/// The connection a command arrived on. In-process callers use [`Session::detached`].
+pub struct Session {
+    id: u64,
+    peer: Option<SocketAddr>,
+    writer: Option<connect::SharedWriter>,
+    admin: AtomicBool,
+    /// The user a `auth <token>` named, whose quotas the session's work counts against.
+    user: Mutex<Option<String>>,
+    capabilities: Mutex<Vec<connect::Capability>>,
+    command_timeout: Option<Duration>,
+    /// When the command being handled must finish by.
+    deadline: Mutex<Option<Instant>>,
+    state: Mutex<SessionState>,
+    /// Opened by `begin`, closed by `commit` or `rollback`.
+    transaction: Mutex<Option<mvcc::Transaction>>,
+    /// Started by `macro record`, saved by `macro stop`.
+    recording: Mutex<Option<macros::Recording>>,
+}
+
+/// Per-connection preferences kept on the server.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionState {
+    /// Sheet that unqualified addresses resolve against; `None` is the default sheet.
+    pub sheet: Option<String>,
+    pub locale: Option<String>,
+    /// Language for error messages; the locale's language if unset.
+    pub language: Option<String>,
+    pub format: Option<String>,
+    /// Significant digits for numbers in replies, over the server's.
+    pub precision: Option<u32>,
+}
+
+impl SessionState {
+    /// The language error messages are given in, if it is a supported one.
+    pub fn language(&self) -> Option<&'static str> {
+        self.language.as_deref().or(self.locale.as_deref()).and_then(messages::language)
+    }
+}
+
+impl Session {
+    pub fn new(id: u64, writer: connect::SharedWriter) -> Self {
+        Session {
+            id,
+            peer: None,
+            writer: Some(writer),
+            admin: AtomicBool::new(false),
+            user: Mutex::new(None),
+            capabilities: Mutex::new(connect::ALL_CAPABILITIES.to_vec()),
+            command_timeout: None,
+            deadline: Mutex::new(None),
+            state: Mutex::new(SessionState::default()),
+            transaction: Mutex::new(None),
+            recording: Mutex::new(None),
+        }
+    }
+
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails once the current command has run past its timeout. Long-running
+    /// work calls this between steps so it can stop without half-applying.
+    pub fn check_deadline(&self) -> Result<(), ReplyError> {
+        match *self.deadline.lock().unwrap() {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(ReplyError::new(ErrorCode::Timeout, "Command exceeded its time limit"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// A session with nowhere to push notifications to.
+    pub fn detached() -> Self {
+        Session {
+            id: u64::MAX,
+            peer: None,
+            writer: None,
+            admin: AtomicBool::new(false),
+            user: Mutex::new(None),
+            capabilities: Mutex::new(connect::ALL_CAPABILITIES.to_vec()),
+            command_timeout: None,
+            deadline: Mutex::new(None),
+            state: Mutex::new(SessionState::default()),
+            transaction: Mutex::new(None),
+            recording: Mutex::new(None),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// Whether the connection negotiated `capability`, or skipped the handshake.
+    pub fn supports(&self, capability: connect::Capability) -> bool {
+        self.capabilities.lock().unwrap().contains(&capability)
+    }
+
+    /// Whether this session has authenticated with the admin token.
+    pub fn is_admin(&self) -> bool {
+        self.admin.load(Ordering::SeqCst)
+    }
+
+    /// The user this session has authenticated as, if any.
+    pub fn user(&self) -> Option<String> {
+        self.user.lock().unwrap().clone()
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn sheet(&self) -> Option<String> {
+        self.state.lock().unwrap().sheet.clone()
+    }
+
+    /// Storage key for a cell reference typed on this session.
+    pub fn resolve(&self, reference: &str) -> String {
+        address::qualify(self.sheet().as_deref(), reference)
+    }
+}
+
+/// Canonical storage key for a resolved reference, so `a1` and `A1` are one cell.
+fn cell_key(reference: &str) -> Result<String, ReplyError> {
+    reference.parse::<address::CellKey>().map(|key| key.to_string()).map_err(|e| {
+        // Commands let names through as aliases; one no alias has is no cell.
+        let name = address::split_sheet(reference).1;
+        match alias::is_valid_name(name) {
+            true => ReplyError::new(ErrorCode::InvalidReference, format!("No cell or alias {}", name)),
+            false => ReplyError::new(ErrorCode::ParseError, format!("{}", e)),
+        }
+    })
+}
+
+/// The cells `writes` set, with their expressions, as quotas check them.
+fn quota_writes(writes: &[mvcc::TxWrite]) -> impl Iterator<Item = (&str, Option<&str>)> {
+    writes.iter().filter_map(|write| match write {
+        mvcc::TxWrite::Set { cell, expr, .. } => Some((cell.as_str(), Some(expr.as_str()))),
+        mvcc::TxWrite::Delete { .. } => None,
+    })
+}
+
+/// `Ok` unless `reply` is an error, for library calls that only succeed or fail.
+fn reply_result(reply: replies::Reply) -> Result<(), ReplyError> {
+    match reply {
+        replies::Reply::Error(e) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `expr` is a value rather than a formula to recalculate: a
+/// number, an amount of money, a duration, a date or quoted text.
+fn is_literal(expr: &str) -> bool {
+    expr.parse::<f64>().is_ok()
+        || currency::parse(expr).is_some()
+        || datetime::parse_duration(expr).is_some()
+        || datetime::parse_datetime(expr).is_some()
+        || command::unquote_text(expr).is_some()
+}
+
+fn storage_error(e: std::io::Error) -> ReplyError {
+    ReplyError::new(ErrorCode::StorageError, format!("Cell store failed: {}", e))
+}
+
+/// Guards against imports that would flood the sheet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImportLimits {
+    pub max_rows: usize,
+    pub max_cols: usize,
+}
+
+impl Default for ImportLimits {
+    fn default() -> Self {
+        ImportLimits { max_rows: 100_000, max_cols: 1_000 }
+    }
+}
+
+/// What an export writes for each cell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportContent {
+    /// Computed values only.
+    #[default]
+    Values,
+    /// `=` and the formula text for computed cells, values for the rest.
+    Formulas,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CsvOptions {
+    pub content: ExportContent,
+    pub delimiter: u8,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions { content: ExportContent::Values, delimiter: b',' }
+    }
+}
+
+impl CsvOptions {
+    /// Reads the optional `values|formulas` and delimiter arguments of
+    /// `export csv`. The delimiter is one ASCII character or `tab`.
+    fn parse(args: &[&str]) -> Result<Self, ReplyError> {
+        let mut options = CsvOptions::default();
+        let mut args = args.iter();
+        if let Some(content) = args.next() {
+            options.content = match *content {
+                "values" => ExportContent::Values,
+                "formulas" => ExportContent::Formulas,
+                other => return Err(ReplyError::new(ErrorCode::ParseError, format!("Unknown export content: {}", other))),
+            };
+        }
+        if let Some(delimiter) = args.next() {
+            options.delimiter = match delimiter.as_bytes() {
+                b"tab" => b'\t',
+                [c] if c.is_ascii() && *c != b'"' => *c,
+                _ => return Err(ReplyError::new(ErrorCode::ParseError, format!("Invalid delimiter: {}", delimiter))),
+            };
+        }
+        Ok(options)
+    }
+}
+
+/// Ranges with more cells than this are streamed as chunks, each holding at most this many.
+pub const STREAM_THRESHOLD_CELLS: u64 = 4096;
+
+/// Who sessions that have not sent `auth` count as for per-user quotas.
+pub const ANONYMOUS_USER: &str = "anonymous";
+
+/// Entries returned by a bare `audit` command.
+const DEFAULT_AUDIT_ENTRIES: usize = 20;
+
+/// Entries returned by a bare `slowlog` command.
+const DEFAULT_SLOWLOG_ENTRIES: usize = 10;
+
+/// Guesses `goalseek` tries before giving up.
+pub const GOAL_SEEK_ITERATIONS: usize = 100;
+
+/// How close, relative to the target, `goalseek` has to come.
+pub const GOAL_SEEK_TOLERANCE: f64 = 1e-9;
+
+/// Cells behind a read-write lock: gets and range reads share it, changes take it alone.
+type SharedStore = Arc<RwLock<Box<dyn store::CellStore>>>;
+
+/// A cell a change wrote, with its old and new values.
+type Change = (String, Option<CellValue>, Option<CellValue>);
+
+/// Called with each changed cell; see [`RSheet::on_change`].
+pub type ChangeHook = Box<dyn Fn(&address::CellKey, &CellValue) + Send + Sync>;
+
+pub struct RSheet {
+    cells: SharedStore,
+    formulas: Mutex<HashMap<String, Formula>>,
+    subscriptions: subscriptions::Subscriptions,
+    alerts: alerts::Alerts,
+    metrics: metrics::Metrics,
+    audit: audit::AuditLog,
+    clients: clients::Clients,
+    admin_token: Option<String>,
+    /// User names by the token each authenticates with.
+    user_tokens: HashMap<String, String>,
+    /// Kept only once per-user quotas are set.
+    user_accounts: Option<quota::UserAccounts>,
+    idempotency: idempotency::IdempotencyKeys,
+    import_limits: ImportLimits,
+    #[cfg(feature = "import-url")]
+    url_policy: fetch::UrlPolicy,
+    feeds: Arc<feeds::Feeds>,
+    /// Directories `run` may read scripts from; empty, it reads none.
+    script_dirs: Vec<std::path::PathBuf>,
+    rand: Arc<Rand>,
+    /// How volatile cells keep current once [`RSheet::start_recalc`] is called.
+    recalc: scheduler::Recalc,
+    persistence_path: Option<std::path::PathBuf>,
+    /// Bumped by every change to the cells, so savers can tell whether they are behind.
+    generation: AtomicU64,
+    wal: Option<wal::WriteAheadLog>,
+    history: Option<history::History>,
+    /// Held by a `set` for its cell and every cell its formula reads, so the
+    /// operands cannot change between evaluation and storing. A `SUM` locks
+    /// only the sheet it reads.
+    cell_locks: locks::CellLocks,
+    versions: Arc<mvcc::Versions>,
+    /// Estimated bytes in cells and formulas; history keeps its own count.
+    memory: meminfo::Usage,
+    memory_limit: Option<u64>,
+    /// Significant digits numbers are rounded to in replies, unless a
+    /// session sets its own.
+    precision: Option<u32>,
+    quotas: quota::SheetQuotas,
+    /// Kept only while `quotas` limits cells per sheet.
+    cell_counts: Option<quota::CellCounts>,
+    slow_log: slowlog::SlowLog,
+    command_hooks: Vec<slowlog::CommandHook>,
+    /// Registered with [`RSheet::on_change`], so behind a lock rather than set up front.
+    change_hooks: RwLock<Vec<ChangeHook>>,
+    diagnostics: Box<dyn diagnostics::DiagnosticsSink>,
+    change_broadcast: clients::ChangeBroadcast,
+    cell_versions: mvcc::CellVersions,
+    leases: leases::Leases,
+    /// Unset, transactions fail on a conflict and syncs keep the later stamp.
+    conflict_policy: Option<conflict::ConflictPolicy>,
+    merge_hook: Option<conflict::MergeHook>,
+    /// Registers for `sync`, kept only once enabled.
+    crdt: Option<Mutex<crdt::Registers>>,
+    replicas: replication::Replicas,
+    /// Set while following a primary: changes then arrive only from it.
+    replica: AtomicBool,
+    /// Set when publishing: clients may read and watch but change nothing.
+    read_only: bool,
+    /// How each sheet is shown, by sheet name, for sheets set with `view`.
+    views: Mutex<std::collections::BTreeMap<String, view::SheetView>>,
+    /// Charts by name, from `chart define`.
+    charts: Mutex<std::collections::BTreeMap<String, chart::Chart>>,
+    /// Shared with formula evaluation, which reads aliases as cells.
+    aliases: Arc<alias::Aliases>,
+    /// What-if layers by name, from `scenario create`.
+    scenarios: Mutex<std::collections::BTreeMap<String, scenario::Scenario>>,
+    /// Macros by name, from `macro record`.
+    macros: Mutex<std::collections::BTreeMap<String, macros::Macro>>,
+    /// Sheets whose formulas `set` only overwrites with `--force`.
+    protected: Mutex<std::collections::BTreeSet<String>>,
+    /// Forwards commands for sheets other nodes serve, once sharded.
+    #[cfg(feature = "net")]
+    router: Option<cluster::Router>,
+    webhooks: Option<webhooks::Webhooks>,
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<mqtt::MqttPublisher>,
+    #[cfg(feature = "kafka")]
+    kafka: Option<kafka::KafkaSink>,
+}
+
+impl Default for RSheet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Written as its [`workbook::Workbook`], in whatever format the serializer
+/// speaks. Only the cells and formulas go; storage, limits and hooks are the
+/// embedder's to set up again.
+impl Serialize for RSheet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.workbook().serialize(serializer)
+    }
+}
+
+/// Read back into a new in-memory sheet; see [`RSheet::from_workbook`].
+impl<'de> Deserialize<'de> for RSheet {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let workbook = workbook::Workbook::deserialize(deserializer)?;
+        RSheet::from_workbook(workbook).map_err(serde::de::Error::custom)
+    }
+}
+
+impl RSheet {
+    pub fn new() -> Self {
+        Self::with_store(store::MemoryStore::default())
+    }
+
+    /// Starts a builder, for setting several options without a chain of
+    /// `with_*` calls or a constructor per storage backend.
+    pub fn builder() -> builder::RSheetBuilder {
+        builder::RSheetBuilder::default()
+    }
+
+    /// A sheet whose cell values live in `store` instead of in memory.
+    pub fn with_store(store: impl store::CellStore + 'static) -> Self {
+        Self::with_boxed_store(Box::new(store))
+    }
+
+    fn with_boxed_store(store: Box<dyn store::CellStore>) -> Self {
+        RSheet {
+            cells: Arc::new(RwLock::new(store)),
+            formulas: Mutex::new(HashMap::new()),
+            subscriptions: subscriptions::Subscriptions::default(),
+            alerts: alerts::Alerts::default(),
+            metrics: metrics::Metrics::default(),
+            audit: audit::AuditLog::default(),
+            clients: clients::Clients::default(),
+            admin_token: None,
+            user_tokens: HashMap::new(),
+            user_accounts: None,
+            idempotency: idempotency::IdempotencyKeys::default(),
+            import_limits: ImportLimits::default(),
+            #[cfg(feature = "import-url")]
+            url_policy: fetch::UrlPolicy::default(),
+            feeds: Arc::default(),
+            script_dirs: Vec::new(),
+            rand: Arc::default(),
+            recalc: scheduler::Recalc::default(),
+            persistence_path: None,
+            generation: AtomicU64::new(0),
+            wal: None,
+            history: None,
+            cell_locks: locks::CellLocks::default(),
+            versions: Arc::default(),
+            memory: meminfo::Usage::default(),
+            memory_limit: None,
+            precision: None,
+            quotas: quota::SheetQuotas::default(),
+            cell_counts: None,
+            slow_log: slowlog::SlowLog::default(),
+            command_hooks: Vec::new(),
+            change_hooks: RwLock::default(),
+            diagnostics: Box::new(diagnostics::TracingSink),
+            change_broadcast: clients::ChangeBroadcast::Off,
+            cell_versions: mvcc::CellVersions::default(),
+            leases: leases::Leases::default(),
+            conflict_policy: None,
+            merge_hook: None,
+            crdt: None,
+            replicas: replication::Replicas::default(),
+            replica: AtomicBool::new(false),
+            read_only: false,
+            views: Mutex::default(),
+            charts: Mutex::default(),
+            aliases: Arc::default(),
+            scenarios: Mutex::default(),
+            macros: Mutex::default(),
+            protected: Mutex::default(),
+            #[cfg(feature = "net")]
+            router: None,
+            webhooks: None,
+            #[cfg(feature = "mqtt")]
+            mqtt: None,
+            #[cfg(feature = "kafka")]
+            kafka: None,
+        }
+    }
+
+    /// Lets sessions that send `auth <token>` use the `admin` commands.
+    /// Without a token nobody can.
+    pub fn with_admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Lets sessions that send `auth <token>` act as `user`, whose per-user
+    /// quotas then apply to them.
+    pub fn with_user(mut self, user: impl Into<String>, token: impl Into<String>) -> Self {
+        self.user_tokens.insert(token.into(), user.into());
+        self
+    }
+
+    /// Limits every user to `defaults` unless `admin quota` gives them their
+    /// own. Sessions that have not authenticated count as the user
+    /// `anonymous`; admins and in-process calls are not limited.
+    pub fn with_user_quotas(mut self, defaults: quota::UserQuotas) -> Self {
+        self.user_accounts = Some(quota::UserAccounts::new(defaults));
+        self
+    }
+
+    /// Records mutating commands to `log` instead of the default in-memory buffer.
+    pub fn with_audit_log(mut self, log: audit::AuditLog) -> Self {
+        self.audit = log;
+        self
+    }
+
+    pub fn metrics(&self) -> &metrics::Metrics {
+        &self.metrics
+    }
+
+    /// Current metrics in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(self.cells.read().unwrap().len())
+    }
+
+    pub async fn handle_command(&self, command: String) -> replies::Reply {
+        self.handle_session_command(&Session::detached(), command).await
+    }
+
+    /// Where the bare `save` and `load` commands read and write.
+    pub fn with_persistence_path(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.persistence_path = Some(path.into());
+        self
+    }
+
+    /// Logs every change to `log` before applying it. Call [`RSheet::replay`]
+    /// first to recover what the log already holds.
+    pub fn with_write_ahead_log(mut self, log: wal::WriteAheadLog) -> Self {
+        self.wal = Some(log);
+        self
+    }
+
+    /// Re-applies every change in the write-ahead log at `path`, without
+    /// logging them again. Returns how many entries were replayed.
+    pub fn replay(&self, path: impl AsRef<std::path::Path>) -> Result<usize, Box<dyn Error>> {
+        let entries = wal::WriteAheadLog::read(path)?;
+        let count = entries.len();
+        for entry in entries {
+            self.apply_entry(entry, false)?;
+        }
+        Ok(count)
+    }
+
+    /// Applies a logged change, logging it again if `log`. Returns each cell
+    /// it wrote with the old and new values; a restore reports none.
+    fn apply_entry(&self, entry: wal::WalEntry, log: bool) -> Result<Vec<Change>, ReplyError> {
+        Ok(match entry {
+            wal::WalEntry::Set { cell, expr, sheet, value } => {
+                let value = match value {
+                    Some(value) => value,
+                    None => match self.runner(sheet.clone()).run(&expr) {
+                        Ok(value) => value,
+                        Err(e) => {
+                            tracing::warn!(cell = %cell, expr = %expr, error = %e, "skipping logged set that no longer evaluates");
+                            self.metrics.record_replay_failure();
+                            return Ok(Vec::new());
+                        }
+                    },
+                };
+                let old = self.store(&cell, &expr, sheet, value.clone(), log)?;
+                vec![(cell, old, Some(value))]
+            }
+            wal::WalEntry::Delete { cell } => {
+                let old = self.remove(&cell, log)?;
+                vec![(cell, old, None)]
+            }
+            wal::WalEntry::Put(values) => {
+                let olds = self.put(values.clone(), log)?;
+                values.into_iter().zip(olds).map(|((cell, value), old)| (cell, old, Some(value))).collect()
+            }
+            wal::WalEntry::Restore(workbook) => {
+                self.restore(workbook, log)?;
+                Vec::new()
+            }
+            wal::WalEntry::Commit(writes) => {
+                let olds = self.commit_writes(&writes, None, log)?;
+                writes
+                    .into_iter()
+                    .zip(olds)
+                    .map(|(write, old)| match write {
+                        mvcc::TxWrite::Set { cell, value, .. } => (cell, old, Some(value)),
+                        mvcc::TxWrite::Delete { cell } => (cell, old, None),
+                    })
+                    .collect()
+            }
+        })
+    }
+
+    /// Takes changes only from a primary, through [`RSheet::apply_replicated`];
+    /// commands that would change cells fail with `ReadOnly` until `admin promote`.
+    pub fn as_replica(self) -> Self {
+        self.replica.store(true, Ordering::SeqCst);
+        self
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.replica.load(Ordering::SeqCst)
+    }
+
+    /// Turns away every command that would change cells, such as `set`,
+    /// `delete`, imports and `run`, with `ReadOnly`, while gets, watches,
+    /// queries and exports go on as before. Changes made through this API,
+    /// or streamed from a primary, are not affected.
+    pub fn with_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Stops following the primary and starts taking changes from clients.
+    pub fn promote(&self) {
+        if self.replica.swap(false, Ordering::SeqCst) {
+            self.diagnose(diagnostics::Level::Info, "promoted replica to primary", None, String::new);
+        }
+    }
+
+    /// Applies what a primary streamed, telling this server's watchers.
+    /// Changes are logged here too, so a replica recovers like a primary
+    /// and can feed replicas of its own.
+    pub fn apply_replicated(&self, event: replication::Event) -> Result<(), ReplyError> {
+        if !self.is_replica() {
+            return Err(ReplyError::new(ErrorCode::ReadOnly, "Not a replica"));
+        }
+        let entry = match event {
+            replication::Event::Snapshot { workbook, .. } => wal::WalEntry::Restore(workbook),
+            replication::Event::Change { entry, .. } => entry,
+        };
+        for (cell, old, new) in self.apply_entry(entry, true)? {
+            self.notify_write(None, &cell, old.as_ref(), new.as_ref());
+        }
+        Ok(())
+    }
+
+    /// Re-evaluates every formula that calls a volatile function such as
+    /// `NOW()`, storing and notifying the cells whose value changed. A
+    /// replica leaves this to its primary. Returns how many cells changed.
+    pub fn recalculate_volatile(&self) -> usize {
+        if self.is_replica() {
+            return 0;
+        }
+        let volatile: Vec<(String, Formula)> = self
+            .formulas
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, formula)| scheduler::is_volatile(&formula.expr))
+            .map(|(cell, formula)| (cell.clone(), formula.clone()))
+            .collect();
+        let mut changed = 0;
+        for (cell, formula) in volatile {
+            let runner = self.runner(formula.sheet.clone());
+            let mut scopes = runner.lock_scopes(&formula.expr);
+            scopes.push(locks::LockScope::Cell(cell.clone()));
+            let _locked = self.cell_locks.lock(scopes);
+            // The cell may have been overwritten since the formulas were read.
+            if self.formulas.lock().unwrap().get(&cell) != Some(&formula) {
+                continue;
+            }
+            let value = match runner.run(&formula.expr) {
+                Ok(value) => value,
+                Err(e) => {
+                    let detail = || format!("{}: {}", formula.expr, e);
+                    self.diagnose(diagnostics::Level::Debug, "recalculation failed", Some(&cell), detail);
+                    continue;
+                }
+            };
+            if self.cells.read().unwrap().get(&cell).as_deref() == Some(&value) {
+                continue;
+            }
+            match self.store(&cell, &formula.expr, formula.sheet, value.clone(), true) {
+                Ok(old) => {
+                    self.notify(None, &cell, old.as_ref(), &value);
+                    changed += 1;
+                }
+                Err(e) => self.diagnose(diagnostics::Level::Warn, "failed to store recalculated cell", Some(&cell), || e.to_string()),
+            }
+        }
+        changed
+    }
+
+    /// Keeps timestamped versions of every cell for `get <cell> asof <time>`.
+    pub fn with_history(mut self, policy: history::RetentionPolicy) -> Self {
+        self.history = Some(history::History::new(policy));
+        self
+    }
+
+    /// Rejects sets and imports that would take the estimated memory of
+    /// cells, formulas and history past `bytes`.
+    pub fn with_memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Rounds numbers in replies to `digits` significant digits, so `2/3`
+    /// reads `0.666667` at 6. Values are stored in full either way.
+    pub fn with_precision(mut self, digits: u32) -> Self {
+        self.precision = Some(digits.clamp(1, numeric::MAX_PRECISION));
+        self
+    }
+
+    /// Keeps commands past `log`'s threshold for `slowlog`, instead of the default log.
+    pub fn with_slow_log(mut self, log: slowlog::SlowLog) -> Self {
+        self.slow_log = log;
+        self
+    }
+
+    /// Calls `hook` after every command with how long it took and how many cells it touched.
+    pub fn with_command_hook(mut self, hook: impl Fn(&slowlog::CommandTiming) + Send + Sync + 'static) -> Self {
+        self.command_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Calls `hook` with every cell that changes and its new value, on the
+    /// thread that changed it. A deleted cell is reported as
+    /// [`CellValue::Empty`], as watchers see it.
+    pub fn on_change(&self, hook: impl Fn(&address::CellKey, &CellValue) + Send + Sync + 'static) {
+        self.change_hooks.write().unwrap().push(Box::new(hook));
+    }
+
+    /// Where gets, sets and other operational messages go; `tracing` by default.
+    pub fn with_diagnostics(mut self, sink: impl diagnostics::DiagnosticsSink + 'static) -> Self {
+        self.diagnostics = Box::new(sink);
+        self
+    }
+
+    /// Hands a message to the diagnostics sink, formatting `detail` only if it is wanted.
+    fn diagnose(&self, level: diagnostics::Level, message: &str, cell: Option<&str>, detail: impl FnOnce() -> String) {
+        if self.diagnostics.enabled(level) {
+            let detail = detail();
+            self.diagnostics.emit(&diagnostics::Diagnostic { level, message, cell, detail: &detail });
+        }
+    }
+
+    /// Rejects sets and imports that would take a sheet past `quotas`.
+    pub fn with_quotas(mut self, quotas: quota::SheetQuotas) -> Self {
+        self.cell_counts = quotas.max_cells.map(|_| {
+            let cells: Vec<String> = self.cells.read().unwrap().iter_all().map(|(cell, _)| cell).collect();
+            quota::CellCounts::count(cells.iter().map(String::as_str))
+        });
+        self.quotas = quotas;
+        self
+    }
+
+    /// Pushes every committed change to the clients `mode` includes, not just
+    /// to those watching the cell.
+    pub fn with_change_broadcast(mut self, mode: clients::ChangeBroadcast) -> Self {
+        self.change_broadcast = mode;
+        self
+    }
+
+    /// Lets replicas edit offline and merge on reconnect with `sync`. Every
+    /// change from then on, and every cell already set, is stamped as the
+    /// server's write.
+    pub fn with_crdt_sync(mut self) -> Self {
+        let mut registers = crdt::Registers::default();
+        let formulas = self.formulas.lock().unwrap();
+        for (cell, value) in self.cells.read().unwrap().iter_all() {
+            let content = match formulas.get(&cell) {
+                Some(formula) => crdt::Content::Expr(formula.expr.clone()),
+                None => crdt::Content::Value(value),
+            };
+            registers.write(crdt::SERVER_REPLICA, &cell, content);
+        }
+        drop(formulas);
+        self.crdt = Some(Mutex::new(registers));
+        self
+    }
+
+    /// Serves only the sheets `shards` does not assign to other nodes; gets,
+    /// sets and deletes of theirs are forwarded. A formula may read only
+    /// sheets on its own node.
+    #[cfg(feature = "net")]
+    pub fn with_shards(mut self, shards: cluster::ShardMap) -> Self {
+        self.router = (!shards.is_empty()).then(|| cluster::Router::new(shards));
+        self
+    }
+
+    /// Posts changes to the webhooks registered with `webhooks`, and lets
+    /// admins add and remove them with `admin webhook`.
+    pub fn with_webhooks(mut self, webhooks: webhooks::Webhooks) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Publishes every change to an MQTT broker through `publisher`.
+    #[cfg(feature = "mqtt")]
+    pub fn with_mqtt(mut self, publisher: mqtt::MqttPublisher) -> Self {
+        self.mqtt = Some(publisher);
+        self
+    }
+
+    /// Produces every committed change, with who made it, through `sink`.
+    #[cfg(feature = "kafka")]
+    pub fn with_kafka(mut self, sink: kafka::KafkaSink) -> Self {
+        self.kafka = Some(sink);
+        self
+    }
+
+    /// Settles writes that raced another to the same cell by `policy`.
+    pub fn with_conflict_policy(mut self, policy: conflict::ConflictPolicy) -> Self {
+        self.conflict_policy = Some(policy);
+        self
+    }
+
+    /// Settles writes that raced another to the same cell with `hook`, in
+    /// place of any conflict policy.
+    pub fn with_merge_hook(mut self, hook: impl Fn(&conflict::Conflict) -> conflict::Resolution + Send + Sync + 'static) -> Self {
+        self.merge_hook = Some(Box::new(hook));
+        self
+    }
+
+    fn settles_conflicts(&self) -> bool {
+        self.conflict_policy.is_some() || self.merge_hook.is_some()
+    }
+
+    fn settle(&self, conflict: &conflict::Conflict) -> conflict::Resolution {
+        match (&self.merge_hook, self.conflict_policy) {
+            (Some(hook), _) => hook(conflict),
+            (None, Some(policy)) => policy.resolve(),
+            (None, None) => conflict::Resolution::Reject,
+        }
+    }
+
+    /// Stamps a change made here rather than merged from a replica.
+    fn stamp(&self, cell: &str, content: crdt::Content) {
+        if let Some(registers) = &self.crdt {
+            registers.lock().unwrap().write(crdt::SERVER_REPLICA, cell, content);
+        }
+    }
+
+    /// Tells watchers, then any broadcast clients that were not watching,
+    /// then webhooks, the MQTT broker and Kafka, about a change, and fires
+    /// the alert rules it sets off. `session` made the change, if a client did.
+    #[cfg_attr(not(feature = "kafka"), allow(unused_variables))]
+    fn notify(&self, session: Option<&Session>, cell: &str, old: Option<&CellValue>, value: &CellValue) {
+        let hooks = self.change_hooks.read().unwrap();
+        if !hooks.is_empty() {
+            if let Ok(key) = cell.parse::<address::CellKey>() {
+                for hook in hooks.iter() {
+                    hook(&key, value);
+                }
+            }
+        }
+        drop(hooks);
+        let notified = self.subscriptions.notify(cell, old, value);
+        self.clients.notify_change(self.change_broadcast, cell, value, &notified);
+        let fired = self.alerts.check(cell, value);
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(cell, old, value);
+            for alert in &fired {
+                webhooks.alert(alert, old);
+            }
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish(cell, value);
+        }
+        #[cfg(feature = "kafka")]
+        if let Some(sink) = &self.kafka {
+            let actor = kafka::Actor {
+                connection: session.map_or(u64::MAX, Session::id),
+                peer: session.and_then(Session::peer).map(|p| p.to_string()),
+            };
+            let new = (*value != CellValue::Empty).then(|| value.clone());
+            sink.send(kafka::ChangeEvent::new(cell, old.cloned(), new, actor));
+        }
+    }
+
+    /// [`RSheet::notify`] for a write that may have emptied the cell.
+    fn notify_write(&self, session: Option<&Session>, cell: &str, old: Option<&CellValue>, new: Option<&CellValue>) {
+        match new {
+            Some(value) => self.notify(session, cell, old, value),
+            None if old.is_some() => self.notify(session, cell, old, &CellValue::Empty),
+            None => {}
+        }
+    }
+
+    /// Estimated memory held for each sheet.
+    pub fn memory_usage(&self) -> Vec<meminfo::SheetMemory> {
+        let mut tally = meminfo::Tally::default();
+        for (cell, value) in self.cells.read().unwrap().iter_all() {
+            tally.cell(&cell, &value);
+        }
+        for (cell, formula) in self.formulas.lock().unwrap().iter() {
+            tally.formula(cell, &formula.expr);
+        }
+        if let Some(history) = &self.history {
+            for (cell, bytes) in history.bytes_by_cell() {
+                tally.history(&cell, bytes);
+            }
+        }
+        tally.into_sheets()
+    }
+
+    /// Fails if growing by `adding` bytes while freeing `freeing` would pass
+    /// the memory limit. Changes that shrink the sheet always pass.
+    fn check_memory(&self, adding: u64, freeing: u64) -> Result<(), ReplyError> {
+        let Some(limit) = self.memory_limit else {
+            return Ok(());
+        };
+        let used = self.memory.bytes() + self.history.as_ref().map_or(0, |history| history.bytes());
+        if adding > freeing && used + adding - freeing > limit {
+            return Err(ReplyError::quota(quota::QuotaViolation::Memory { limit, used }));
+        }
+        Ok(())
+    }
+
+    /// Rejects `writes`, `(cell, expr)` pairs with no `expr` for imported
+    /// values, if they would take a sheet past its quotas.
+    fn check_quotas<'a>(&self, writes: impl IntoIterator<Item = (&'a str, Option<&'a str>)>) -> Result<(), ReplyError> {
+        let cells = self.cells.read().unwrap();
+        let mut new = Vec::new();
+        for (cell, expr) in writes {
+            self.quotas.check_cell(cell, expr).map_err(ReplyError::quota)?;
+            if self.cell_counts.is_some() && cells.get(cell).is_none() {
+                new.push(cell);
+            }
+        }
+        drop(cells);
+        match (&self.cell_counts, self.quotas.max_cells) {
+            (Some(counts), Some(limit)) => counts.check(limit, new).map_err(ReplyError::quota),
+            _ => Ok(()),
+        }
+    }
+
+    /// The user `session`'s work counts against, unless per-user quotas are
+    /// off or the session is an admin's or in-process.
+    fn quota_user(&self, session: &Session) -> Option<String> {
+        if self.user_accounts.is_none() || session.is_admin() {
+            return None;
+        }
+        match session.user() {
+            Some(user) => Some(user),
+            None if session.writer.is_some() => Some(ANONYMOUS_USER.to_string()),
+            None => None,
+        }
+    }
+
+    /// Fails if `writes`, each a cell and the bytes of its new value or
+    /// `None` to delete it, would take the session's user past their quotas.
+    fn check_user_quotas<'a>(
+        &self,
+        session: &Session,
+        writes: impl IntoIterator<Item = (&'a str, Option<u64>)>,
+    ) -> Result<(), ReplyError> {
+        match (&self.user_accounts, self.quota_user(session)) {
+            (Some(accounts), Some(user)) => accounts.check(&user, writes).map_err(ReplyError::quota),
+            _ => Ok(()),
+        }
+    }
+
+    /// Gives `cell`, now holding `value` or deleted, to the session's user.
+    fn charge_user(&self, session: &Session, cell: &str, value: Option<&CellValue>) {
+        if let Some(accounts) = &self.user_accounts {
+            let bytes = value.map(|value| meminfo::cell_bytes(cell, value));
+            accounts.record(self.quota_user(session).as_deref(), cell, bytes);
+        }
+    }
+
+    fn count_cell(&self, cell: &str, had: bool, has: bool) {
+        if let Some(counts) = &self.cell_counts {
+            counts.change(cell, had, has);
+        }
+    }
+
+    pub fn with_import_limits(mut self, limits: ImportLimits) -> Self {
+        self.import_limits = limits;
+        self
+    }
+
+    /// Lets `import url` fetch from the policy's hosts. By default it fetches nothing.
+    #[cfg(feature = "import-url")]
+    pub fn with_url_policy(mut self, policy: fetch::UrlPolicy) -> Self {
+        self.url_policy = policy;
+        self
+    }
+
+    /// Lets `run` read scripts from `dir` and the directories under it.
+    pub fn with_script_dir(mut self, dir: impl Into<std::path::PathBuf>) -> Self {
+        self.script_dirs.push(dir.into());
+        self
+    }
+
+    /// Where `FETCH` cells get their data. By default they fetch nothing.
+    pub fn with_feeds(mut self, feeds: feeds::Feeds) -> Self {
+        self.feeds = Arc::new(feeds);
+        self
+    }
+
+    /// Makes `RAND()` repeat the same sequence from run to run, e.g. for tests.
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        self.rand = Arc::new(Rand::seeded(seed));
+        self
+    }
+
+    /// Whether volatile cells recalculate by themselves; see [`RSheet::start_recalc`].
+    pub fn with_recalc(mut self, recalc: scheduler::Recalc) -> Self {
+        self.recalc = recalc;
+        self
+    }
+
+    /// Starts recalculating volatile cells as set by [`RSheet::with_recalc`].
+    /// `None` if that is [`scheduler::Recalc::Manual`]; stop the scheduler
+    /// before dropping the sheet.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn start_recalc(self: &Arc<Self>) -> Option<scheduler::Scheduler> {
+        let schedule = match &self.recalc {
+            scheduler::Recalc::Manual => return None,
+            scheduler::Recalc::Auto => scheduler::Schedule::Every(self.feeds.refresh()),
+            scheduler::Recalc::On(schedule) => schedule.clone(),
+        };
+        Some(scheduler::Scheduler::start(Arc::clone(self), schedule))
+    }
+
+    /// Evaluates formulas with unqualified references resolved against `sheet`.
+    fn runner(&self, sheet: Option<String>) -> CommandRunner {
+        CommandRunner::new(self.cells.clone())
+            .with_sheet(sheet)
+            .with_feeds(self.feeds.clone())
+            .with_rand(self.rand.clone())
+            .with_aliases(self.aliases.clone())
+    }
+
+    /// The storage key `reference` names for `session`: the cell of an
+    /// alias, or the reference qualified by the session's sheet.
+    fn resolve(&self, session: &Session, reference: &str) -> String {
+        self.aliases.get(reference).unwrap_or_else(|| session.resolve(reference))
+    }
+
+    /// Fills cells from CSV `reader`, its first field landing on `anchor`
+    /// (a storage key such as `B2` or `Budget!B2`). Numbers, percentages,
+    /// currency amounts, dates and booleans are recognized as such, empty
+    /// fields are skipped, anything else is text. Nothing is written if the data is malformed or exceeds the import limits.
+    /// Returns the range covered.
+    pub fn import_csv(&self, reader: impl Read, anchor: &str) -> Result<address::CellRange, ReplyError> {
+        self.import_csv_with(reader, anchor, &infer::InferOptions::default())
+    }
+
+    /// Like [`RSheet::import_csv`], but reading fields as `options` says:
+    /// everything may be kept as text, and a column may be given a type, in
+    /// which case a field that is not of that type fails the import.
+    pub fn import_csv_with(
+        &self,
+        reader: impl Read,
+        anchor: &str,
+        options: &infer::InferOptions,
+    ) -> Result<address::CellRange, ReplyError> {
+        self.import_csv_as(&Session::detached(), reader, anchor, options, "import csv").map(|(range, _)| range)
+    }
+
+    /// Imports CSV, auditing it as `source` (the command that brought the data in).
+    fn import_csv_as(
+        &self,
+        session: &Session,
+        reader: impl Read,
+        anchor: &str,
+        options: &infer::InferOptions,
+        source: &str,
+    ) -> Result<(address::CellRange, usize), ReplyError> {
+        let (sheet, start) = address::split_sheet(anchor);
+        let start: address::CellAddress =
+            start.parse().map_err(|e| ReplyError::new(ErrorCode::ParseError, format!("{}", e)))?;
+        let limits = self.import_limits;
+        let too_big = || {
+            ReplyError::new(
+                ErrorCode::ParseError,
+                format!("Import exceeds {} rows or {} columns", limits.max_rows, limits.max_cols),
+            )
+        };
+
+        let mut csv = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(reader);
+        let mut values = Vec::new();
+        let mut end = start;
+        for (row, record) in csv.records().enumerate() {
+            let record = record.map_err(|e| ReplyError::new(ErrorCode::ParseError, format!("Invalid CSV: {}", e)))?;
+            if row >= limits.max_rows || record.len() > limits.max_cols {
+                return Err(too_big());
+            }
+            session.check_deadline()?;
+            let row = start.row.checked_add(row as u32).ok_or_else(too_big)?;
+            for (col, field) in record.iter().enumerate() {
+                let col = start.col.checked_add(col as u32).ok_or_else(too_big)?;
+                let field = field.trim();
+                if field.is_empty() {
+                    continue;
+                }
+                let value = options.read(col, field).map_err(|e| {
+                    let cell = address::CellAddress::new(col, row);
+                    ReplyError::new(ErrorCode::TypeMismatch, format!("{}: {}", cell, e))
+                })?;
+                end = address::CellAddress::new(end.col.max(col), end.row.max(row));
+                values.push((address::qualify(sheet, &address::CellAddress::new(col, row).to_string()), value));
+            }
+        }
+
+        let count = values.len();
+        self.leases.check(session.id, values.iter().map(|(cell, _)| cell.as_str())).map_err(ReplyError::locked)?;
+        self.check_quotas(values.iter().map(|(cell, _)| (cell.as_str(), None)))?;
+        self.check_user_quotas(session, values.iter().map(|(cell, value)| (cell.as_str(), Some(meminfo::cell_bytes(cell, value)))))?;
+        self.check_memory(values.iter().map(|(cell, value)| meminfo::cell_bytes(cell, value)).sum(), 0)?;
+        let olds = self.put(values.clone(), true)?;
+        for ((cell, value), old) in values.iter().zip(olds) {
+            self.charge_user(session, cell, Some(value));
+            self.notify(Some(session), cell, old.as_ref(), value);
+        }
+        let peer = session.peer().map(|p| p.to_string());
+        let range = address::CellRange::new(start, end);
+        self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("{} {} ({} cells)", source, anchor, count)));
+        Ok((range, count))
+    }
+
+    /// Sets many cells as one change: `(cell, expr)` pairs as `set` takes
+    /// them, e.g. `("Budget!A1", "4")` or `("A3", "A1+A2")`. Every literal is
+    /// stored before any formula is evaluated, so formulas may read cells
+    /// loaded after them; unqualified references resolve against the
+    /// formula's own sheet. Other writers wait for the whole load, which takes
+    /// each lock once and logs one entry. Nothing is written if a cell or
+    /// formula is invalid. Returns how many cells were set.
+    pub fn bulk_load(&self, cells: impl IntoIterator<Item = (String, String)>) -> Result<usize, ReplyError> {
+        let mut literals = Vec::new();
+        let mut pending = Vec::new();
+        for (cell, expr) in cells {
+            let cell = cell_key(&cell)?;
+            match expr.parse::<f64>() {
+                Ok(n) => literals.push(mvcc::TxWrite::Set { cell, expr, sheet: None, value: CellValue::Number(n) }),
+                Err(_) => pending.push((cell, expr)),
+            }
+        }
+        let _locked = self.cell_locks.lock(vec![locks::LockScope::All]);
+        let writes = {
+            let cells = self.cells.read().unwrap();
+            let mut staged = store::Overlay::new(cells.as_ref());
+            for write in &literals {
+                if let mvcc::TxWrite::Set { cell, value, .. } = write {
+                    staged.set(cell, value.clone()).map_err(storage_error)?;
+                }
+            }
+            let mut writes = literals;
+            for (cell, expr) in pending {
+                let sheet = address::split_sheet(&cell).0.map(str::to_string);
+                let runner = self.runner(sheet);
+                let value = runner
+                    .run_in(&staged, &expr)
+                    .map_err(ReplyError::from)
+                    .map_err(|e| ReplyError::new(e.code, format!("{}: {}", cell, e.message)))?;
+                staged.set(&cell, value.clone()).map_err(storage_error)?;
+                writes.push(mvcc::TxWrite::Set { cell, expr, sheet: runner.sheet, value });
+            }
+            writes
+        };
+        let adding = writes
+            .iter()
+            .map(|write| match write {
+                mvcc::TxWrite::Set { cell, value, .. } => meminfo::cell_bytes(cell, value),
+                mvcc::TxWrite::Delete { .. } => 0,
+            })
+            .sum();
+        self.check_quotas(quota_writes(&writes))?;
+        self.check_memory(adding, 0)?;
+        let olds = self.commit_writes(&writes, None, true)?;
+        for (write, old) in writes.iter().zip(olds) {
+            if let mvcc::TxWrite::Set { cell, value, .. } = write {
+                self.notify(None, cell, old.as_ref(), value);
+            }
+        }
+        let session = Session::detached();
+        let entry = format!("bulk load ({} cells)", writes.len());
+        self.audit.record(audit::AuditEntry::new(session.id(), None, entry));
+        Ok(writes.len())
+    }
+
+    /// Sets `cell` (a storage key such as `A1` or `Budget!A1`) to `n`, as
+    /// `set` would, with no command to format or reply to pick apart.
+    pub fn set_number(&self, cell: &str, n: f64) -> Result<(), ReplyError> {
+        let cell = cell_key(cell)?;
+        reply_result(self.set_cell(&Session::detached(), &cell, n.to_string(), None, None))
+    }
+
+    /// Sets `cell` to `text`, as `set <cell> "<text>"` would.
+    pub fn set_text(&self, cell: &str, text: &str) -> Result<(), ReplyError> {
+        let cell = cell_key(cell)?;
+        reply_result(self.set_cell(&Session::detached(), &cell, command::quote_text(text), None, None))
+    }
+
+    /// Sets `cell` to a formula such as `A1+B1` or `SUM(A1:A9)`. Unqualified
+    /// references resolve against the cell's own sheet.
+    pub fn set_formula(&self, cell: &str, expr: &str) -> Result<(), ReplyError> {
+        let cell = cell_key(cell)?;
+        let sheet = address::split_sheet(&cell).0.map(str::to_string);
+        reply_result(self.set_cell(&Session::detached(), &cell, expr.to_string(), sheet, None))
+    }
+
+    /// Has `set` on `sheet` refuse to overwrite a formula with a value unless
+    /// given `--force`, or lets it again. These library setters are not held
+    /// back.
+    pub fn protect_formulas(&self, sheet: &str, on: bool) {
+        let mut protected = self.protected.lock().unwrap();
+        if on {
+            protected.insert(sheet.to_string());
+        } else {
+            protected.remove(sheet);
+        }
+    }
+
+    /// Whether `cell` holds a formula on a sheet that protects them.
+    fn is_protected_formula(&self, cell: &str) -> bool {
+        let sheet = address::split_sheet(cell).0.unwrap_or(address::DEFAULT_SHEET);
+        self.protected.lock().unwrap().contains(sheet) && self.formulas.lock().unwrap().contains_key(cell)
+    }
+
+    /// The value of `cell`, or `None` if it is empty.
+    pub fn get_value(&self, cell: &str) -> Result<Option<CellValue>, ReplyError> {
+        let cell = cell_key(cell)?;
+        Ok(self.cells.read().unwrap().get(&cell).map(|value| CellValue::clone(&value)))
+    }
+
+    /// Values of `range` (a storage key such as `A1:C3` or `Budget!A1:C3`) as of one moment.
+    pub fn range(&self, range: &str) -> Result<matrix::Matrix, ReplyError> {
+        let (sheet, cells) = address::split_sheet(range);
+        let cells: address::CellRange =
+            cells.parse().map_err(|e| ReplyError::new(ErrorCode::ParseError, format!("{}", e)))?;
+        let snapshot = self.cells.read().unwrap().snapshot(sheet, cells);
+        slowlog::touch(snapshot.len() as u64);
+        Ok(matrix::Matrix::from_snapshot(cells, &snapshot))
+    }
+
+    /// Every cell holding a value as of one moment: the default sheet, then
+    /// the others by name, each row by row.
+    pub fn iter_cells(&self) -> impl Iterator<Item = (address::CellKey, CellValue)> {
+        let mut cells: Vec<(address::CellKey, CellValue)> = self
+            .cells
+            .read()
+            .unwrap()
+            .iter_all()
+            .filter_map(|(key, value)| Some((key.parse().ok()?, value)))
+            .collect();
+        cells.sort_by(|(a, _), (b, _)| (&a.sheet, a.addr.row, a.addr.col).cmp(&(&b.sheet, b.addr.row, b.addr.col)));
+        slowlog::touch(cells.len() as u64);
+        cells.into_iter()
+    }
+
+    /// The cells of `range` (`A1:C3`, `B:B` or `Budget!A1:C3`) holding a
+    /// value as of one moment, row by row.
+    pub fn iter_range(&self, range: &str) -> Result<impl Iterator<Item = (address::CellAddress, CellValue)>, ReplyError> {
+        let (sheet, cells) = address::split_sheet(range);
+        let cells = address::CellRange::parse_with_columns(cells)
+            .map_err(|e| ReplyError::new(ErrorCode::ParseError, format!("{}", e)))?;
+        Ok(self.iter_block(sheet, cells))
+    }
+
+    /// The cells of a row, `3` or `Budget!3`, holding a value, left to right.
+    pub fn iter_row(&self, row: &str) -> Result<impl Iterator<Item = (address::CellAddress, CellValue)>, ReplyError> {
+        let (sheet, number) = address::split_sheet(row);
+        let Some(row) = number.parse::<u32>().ok().filter(|row| (1..=address::MAX_ROWS).contains(row)) else {
+            return Err(ReplyError::new(ErrorCode::ParseError, format!("Invalid row: {}", number)));
+        };
+        let cells = address::CellRange::new(
+            address::CellAddress::new(0, row - 1),
+            address::CellAddress::new(address::MAX_COLS - 1, row - 1),
+        );
+        Ok(self.iter_block(sheet, cells))
+    }
+
+    /// The cells of a column, `B` or `Budget!B`, holding a value, top to bottom.
+    pub fn iter_col(&self, col: &str) -> Result<impl Iterator<Item = (address::CellAddress, CellValue)>, ReplyError> {
+        let (sheet, letters) = address::split_sheet(col);
+        let Some(col) = address::column_index(letters) else {
+            return Err(ReplyError::new(ErrorCode::ParseError, format!("Invalid column: {}", letters)));
+        };
+        let cells = address::CellRange::new(address::CellAddress::new(col, 0), address::CellAddress::new(col, address::MAX_ROWS - 1));
+        Ok(self.iter_block(sheet, cells))
+    }
+
+    fn iter_block(&self, sheet: Option<&str>, range: address::CellRange) -> impl Iterator<Item = (address::CellAddress, CellValue)> {
+        let snapshot = self.cells.read().unwrap().snapshot(sheet, range);
+        slowlog::touch(snapshot.len() as u64);
+        let cells: Vec<_> = snapshot.iter_range(range).map(|(addr, value)| (addr, CellValue::clone(value))).collect();
+        cells.into_iter()
+    }
+
+    /// Writes `range` (a storage key such as `A1:C3` or `Budget!A1:C3`)
+    /// turned so its rows become columns, its top left cell landing on
+    /// `anchor`. Formulas are written as their values unless `mode` is
+    /// [`reshape::TransposeMode::Formulas`]. Empty cells are skipped, as by
+    /// an import. Returns the range covered and how many cells were set.
+    pub fn transpose(
+        &self,
+        range: &str,
+        anchor: &str,
+        mode: reshape::TransposeMode,
+    ) -> Result<(address::CellRange, usize), ReplyError> {
+        self.transpose_as(&Session::detached(), range, anchor, mode)
+    }
+
+    fn transpose_as(
+        &self,
+        session: &Session,
+        range: &str,
+        anchor: &str,
+        mode: reshape::TransposeMode,
+    ) -> Result<(address::CellRange, usize), ReplyError> {
+        let parse_error = |e: address::AddressError| ReplyError::new(ErrorCode::ParseError, format!("{}", e));
+        let (sheet, source) = address::split_sheet(range);
+        let source: address::CellRange = source.parse().map_err(parse_error)?;
+        let (target_sheet, start) = address::split_sheet(anchor);
+        let start: address::CellAddress = start.parse().map_err(parse_error)?;
+        let transposition = reshape::Transposition::new(sheet, source, target_sheet, start)
+            .map_err(|e| ReplyError::new(ErrorCode::ParseError, e))?;
+        // Read in full before anything is written, so the two blocks may overlap.
+        let (snapshot, formulas) = {
+            let cells = self.cells.read().unwrap();
+            let formulas: HashMap<String, Formula> = match mode {
+                reshape::TransposeMode::Formulas => self
+                    .formulas
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(cell, _)| {
+                        let (cell_sheet, addr) = address::split_sheet(cell);
+                        cell_sheet == sheet && addr.parse().is_ok_and(|addr| source.contains(&addr))
+                    })
+                    .map(|(cell, formula)| (cell.clone(), formula.clone()))
+                    .collect(),
+                reshape::TransposeMode::Values => HashMap::new(),
+            };
+            (self.read_snapshot(session, cells.as_ref(), sheet, source), formulas)
+        };
+
+        let mut values = Vec::new();
+        let mut rewritten = Vec::new();
+        for (addr, value) in snapshot.iter_range(source) {
+            session.check_deadline()?;
+            let cell = address::qualify(target_sheet, &transposition.target(addr).to_string());
+            match formulas.get(&address::qualify(sheet, &addr.to_string())) {
+                Some(formula) => rewritten.push((cell, transposition.rewrite(&formula.expr, formula.sheet.as_deref()))),
+                None => values.push((cell, CellValue::clone(value))),
+            }
+        }
+
+        let count = values.len() + rewritten.len();
+        let cells = values.iter().map(|(cell, _)| cell.as_str()).chain(rewritten.iter().map(|(cell, _)| cell.as_str()));
+        self.leases.check(session.id, cells).map_err(ReplyError::locked)?;
+        self.check_quotas(
+            values
+                .iter()
+                .map(|(cell, _)| (cell.as_str(), None))
+                .chain(rewritten.iter().map(|(cell, expr)| (cell.as_str(), Some(expr.as_str())))),
+        )?;
+        self.check_memory(values.iter().map(|(cell, value)| meminfo::cell_bytes(cell, value)).sum(), 0)?;
+        if !values.is_empty() {
+            let olds = self.put(values.clone(), true)?;
+            for ((cell, value), old) in values.iter().zip(olds) {
+                self.notify(Some(session), cell, old.as_ref(), value);
+            }
+        }
+        // Formulas go in after the values, which they may read.
+        if !rewritten.is_empty() {
+            self.bulk_load(rewritten)?;
+        }
+        let peer = session.peer().map(|p| p.to_string());
+        let entry = format!("transpose {} {} ({} cells)", range, anchor, count);
+        self.audit.record(audit::AuditEntry::new(session.id(), peer, entry));
+        Ok((transposition.target_range(), count))
+    }
+
+    /// `shift <range> <direction> <n>`: moves a block `n` cells up, down,
+    /// left or right over whatever was there, leaving behind empty cells.
+    /// Formulas move with their cells, and references into the block from
+    /// any formula on any sheet, and aliases of its cells, follow it.
+    /// Returns where the block landed and how many cells moved.
+    pub fn shift(&self, range: &str, direction: reshape::Direction, by: u32) -> Result<(address::CellRange, usize), ReplyError> {
+        self.shift_as(&Session::detached(), range, direction, by)
+    }
+
+    fn shift_as(
+        &self,
+        session: &Session,
+        range: &str,
+        direction: reshape::Direction,
+        by: u32,
+    ) -> Result<(address::CellRange, usize), ReplyError> {
+        let (sheet, source) = address::split_sheet(range);
+        let source: address::CellRange =
+            source.parse().map_err(|e| ReplyError::new(ErrorCode::ParseError, format!("{}", e)))?;
+        let shift = reshape::Shift::new(sheet, source, direction, by).map_err(|e| ReplyError::new(ErrorCode::ParseError, e))?;
+        let target = shift.target_range();
+        let key = |addr: address::CellAddress| address::qualify(sheet, &addr.to_string());
+
+        // Formulas anywhere may be rewritten, so every cell is held.
+        let _locked = self.cell_locks.lock(vec![locks::LockScope::All]);
+        let both = address::CellRange::new(
+            address::CellAddress::new(source.start.col.min(target.start.col), source.start.row.min(target.start.row)),
+            address::CellAddress::new(source.end.col.max(target.end.col), source.end.row.max(target.end.row)),
+        );
+        let snapshot = self.cells.read().unwrap().snapshot(sheet, both);
+        let formulas = self.formulas.lock().unwrap().clone();
+
+        let mut values = Vec::new();
+        let mut moved_formulas = Vec::new();
+        for (addr, value) in snapshot.iter_range(source) {
+            let cell = key(shift.target(addr));
+            match formulas.get(&key(addr)) {
+                Some(formula) => {
+                    let expr = shift.rewrite(&formula.expr, formula.sheet.as_deref());
+                    moved_formulas.push((cell, Formula { expr, sheet: formula.sheet.clone() }, CellValue::clone(value)));
+                }
+                None => values.push((cell, CellValue::clone(value))),
+            }
+            session.check_deadline()?;
+        }
+        // Whatever is left in either block once the moved cells land is emptied.
+        let covered = |addr: &address::CellAddress| source.contains(addr) || target.contains(addr);
+        let landed: std::collections::HashSet<address::CellAddress> =
+            snapshot.iter_range(source).map(|(addr, _)| shift.target(addr)).collect();
+        let emptied: Vec<String> = snapshot
+            .iter_range(both)
+            .filter(|(addr, _)| covered(addr) && !landed.contains(addr))
+            .map(|(addr, _)| key(addr))
+            .collect();
+        // Formulas outside the block keep their cells and values; only what they read moves.
+        let mut rewritten = Vec::new();
+        for (cell, formula) in &formulas {
+            let inside = address::parse_key(cell).is_ok_and(|(of, addr)| of == sheet && covered(&addr));
+            let expr = shift.rewrite(&formula.expr, formula.sheet.as_deref());
+            if !inside && expr != formula.expr {
+                rewritten.push((cell.clone(), Formula { expr, sheet: formula.sheet.clone() }));
+            }
+        }
+
+        let written = values.iter().map(|(cell, _)| cell).chain(moved_formulas.iter().map(|(cell, ..)| cell));
+        let cells = written.chain(&emptied).chain(rewritten.iter().map(|(cell, _)| cell));
+        self.leases.check(session.id, cells.map(String::as_str)).map_err(ReplyError::locked)?;
+        self.check_quotas(
+            values
+                .iter()
+                .map(|(cell, _)| (cell.as_str(), None))
+                .chain(moved_formulas.iter().map(|(cell, formula, _)| (cell.as_str(), Some(formula.expr.as_str())))),
+        )?;
+        let adding = values.iter().map(|(cell, value)| meminfo::cell_bytes(cell, value)).sum::<u64>()
+            + moved_formulas.iter().map(|(cell, _, value)| meminfo::cell_bytes(cell, value)).sum::<u64>();
+        self.check_memory(adding, 0)?;
+
+        for cell in &emptied {
+            let old = self.remove(cell, true)?;
+            self.notify_write(Some(session), cell, old.as_ref(), None);
+        }
+        let count = values.len() + moved_formulas.len();
+        if !values.is_empty() {
+            let olds = self.put_locked(values.clone(), true)?;
+            for ((cell, value), old) in values.iter().zip(olds) {
+                self.notify(Some(session), cell, old.as_ref(), value);
+            }
+        }
+        for (cell, formula, value) in moved_formulas {
+            let old = self.store(&cell, &formula.expr, formula.sheet, value.clone(), true)?;
+            self.notify(Some(session), &cell, old.as_ref(), &value);
+        }
+        for (cell, formula) in rewritten {
+            let Some(value) = self.cells.read().unwrap().get(&cell) else {
+                continue;
+            };
+            self.store(&cell, &formula.expr, formula.sheet, CellValue::clone(&value), true)?;
+        }
+        self.repoint_aliases(sheet, |addr| source.contains(&addr).then(|| shift.target(addr)));
+
+        let peer = session.peer().map(|p| p.to_string());
+        let entry = format!("shift {} {} {} ({} cells)", range, direction, by, count);
+        self.audit.record(audit::AuditEntry::new(session.id(), peer, entry));
+        Ok((target, count))
+    }
+
+    /// Writes `range` (a storage key such as `A1:C3` or `Budget!A1:C3`) to
+    /// `writer` as CSV, one record per row. Empty cells become empty fields.
+    pub fn export_csv(&self, writer: impl Write, range: &str, options: CsvOptions) -> Result<(), ReplyError> {
+        self.export_csv_as(&Session::detached(), writer, range, options)
+    }
+
+    fn export_csv_as(
+        &self,
+        session: &Session,
+        writer: impl Write,
+        range: &str,
+        options: CsvOptions,
+    ) -> Result<(), ReplyError> {
+        let (sheet, range) = address::split_sheet(range);
+        let range: address::CellRange =
+            range.parse().map_err(|e| ReplyError::new(ErrorCode::ParseError, format!("{}", e)))?;
+        let storage_error = |e: csv::Error| ReplyError::new(ErrorCode::StorageError, format!("Failed to write CSV: {}", e));
+        // Values and formulas are taken together, then written without any lock.
+        let (snapshot, formulas) = {
+            let cells = self.cells.read().unwrap();
+            let formulas: HashMap<String, String> = match options.content {
+                ExportContent::Formulas => self
+                    .formulas
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(cell, _)| {
+                        let (cell_sheet, addr) = address::split_sheet(cell);
+                        cell_sheet == sheet && addr.parse().is_ok_and(|addr| range.contains(&addr))
+                    })
+                    .map(|(cell, formula)| (cell.clone(), formula.expr.clone()))
+                    .collect(),
+                ExportContent::Values => HashMap::new(),
+            };
+            (self.read_snapshot(session, cells.as_ref(), sheet, range), formulas)
+        };
+        let mut csv = csv::WriterBuilder::new().delimiter(options.delimiter).flexible(true).from_writer(writer);
+        for row in range.start.row..=range.end.row {
+            session.check_deadline()?;
+            let record: Vec<String> = (range.start.col..=range.end.col)
+                .map(|col| {
+                    let addr = address::CellAddress::new(col, row);
+                    match (formulas.get(&address::qualify(sheet, &addr.to_string())), snapshot.get(addr)) {
+                        (Some(expr), _) => format!("={}", expr),
+                        (_, Some(value)) => replies::value_text(value),
+                        (_, None) => String::new(),
+                    }
+                })
+                .collect();
+            csv.write_record(&record).map_err(storage_error)?;
+        }
+        csv.flush().map_err(|e| storage_error(e.into()))
+    }
+
+    /// Runs the commands `reader` holds, one per line, as one transaction:
+    /// either every set and delete is applied or, if a line fails, none is.
+    /// Blank lines and lines starting with `#` are skipped. Returns how many
+    /// commands ran.
+    pub fn execute_script(&self, reader: impl std::io::BufRead) -> Result<usize, script::ScriptError> {
+        self.execute_script_as(&Session::detached(), reader)
+    }
+
+    fn execute_script_as(&self, session: &Session, reader: impl std::io::BufRead) -> Result<usize, script::ScriptError> {
+        use command::Command;
+
+        if let replies::Reply::Error(error) = self.begin(session) {
+            return Err(script::ScriptError { line: 0, error });
+        }
+        let mut commands = 0;
+        for (i, line) in reader.lines().enumerate() {
+            let failed = |error| {
+                session.transaction.lock().unwrap().take();
+                Err(script::ScriptError { line: i + 1, error })
+            };
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return failed(ReplyError::new(ErrorCode::StorageError, format!("Failed to read script: {}", e))),
+            };
+            if script::is_skipped(&line) {
+                continue;
+            }
+            let command = Command::parse(line.trim());
+            if !script::allows(&command) {
+                let message = format!("{} cannot run in a script", command.name());
+                return failed(ReplyError::new(ErrorCode::ParseError, message));
+            }
+            if let replies::Reply::Error(e) = self.dispatch(session, command) {
+                return failed(e);
+            }
+            commands += 1;
+        }
+        match self.commit(session) {
+            replies::Reply::Error(error) => Err(script::ScriptError { line: 0, error }),
+            _ => Ok(commands),
+        }
+    }
+
+    /// While the session records a macro, keeps `command` for it instead of
+    /// running it. `None` if the command is to run as usual: macro commands
+    /// always are, and so are others that do not parse, unless they name a
+    /// parameter, so they can answer with their error.
+    fn record(&self, session: &Session, command: &command::Command) -> Option<replies::Reply> {
+        use command::Command;
+
+        if matches!(
+            command,
+            Command::MacroRecord { .. } | Command::MacroStop | Command::MacroRun { .. } | Command::MacroRemove { .. } | Command::Macros
+        ) {
+            return None;
+        }
+        let mut recording = session.recording.lock().unwrap();
+        let recording = recording.as_mut()?;
+        let text = match command {
+            Command::Invalid { text, .. } if recording.recorded.takes(text) => text.clone(),
+            Command::Invalid { .. } => return None,
+            command if !script::allows(command) => {
+                let message = format!("{} cannot run in a macro", command.name());
+                return Some(replies::Reply::error(ErrorCode::ParseError, message));
+            }
+            command => command.to_string(),
+        };
+        recording.recorded.commands.push(text);
+        Some(replies::Reply::Ok)
+    }
+
+    /// `macro record <name> [param...]`
+    fn start_recording(&self, session: &Session, name: String, params: Vec<String>) -> replies::Reply {
+        if let Some(invalid) = std::iter::once(&name).chain(&params).find(|name| !chart::is_valid_name(name)) {
+            return replies::Reply::error(ErrorCode::ParseError, format!("Invalid macro or parameter name: {}", invalid));
+        }
+        let mut recording = session.recording.lock().unwrap();
+        if let Some(recording) = recording.as_ref() {
+            return replies::Reply::error(ErrorCode::ParseError, format!("Already recording {}", recording.name));
+        }
+        *recording = Some(macros::Recording { name, recorded: macros::Macro::new(params) });
+        replies::Reply::Ok
+    }
+
+    /// `macro stop`: saves the macro being recorded, over any of its name.
+    fn stop_recording(&self, session: &Session) -> replies::Reply {
+        let Some(recording) = session.recording.lock().unwrap().take() else {
+            return replies::Reply::error(ErrorCode::ParseError, "Not recording a macro");
+        };
+        let peer = session.peer().map(|p| p.to_string());
+        let text = format!("macro record {} ({} commands)", recording.name, recording.recorded.commands.len());
+        self.macros.lock().unwrap().insert(recording.name, recording.recorded);
+        self.audit.record(audit::AuditEntry::new(session.id(), peer, text));
+        replies::Reply::Ok
+    }
+
+    /// `macro run <name> [arg...]`: runs the macro's commands with its
+    /// parameters filled in, as one transaction, like a script.
+    fn run_macro(&self, session: &Session, name: &str, args: &[String]) -> replies::Reply {
+        if let Some(recording) = session.recording.lock().unwrap().as_ref() {
+            return replies::Reply::error(ErrorCode::ParseError, format!("Cannot run a macro while recording {}", recording.name));
+        }
+        let Some(definition) = self.macros.lock().unwrap().get(name).cloned() else {
+            return replies::Reply::error(ErrorCode::ParseError, format!("No macro {}", name));
+        };
+        let commands = match definition.expand(args) {
+            Ok(commands) => commands,
+            Err(e) => return replies::Reply::Error(e),
+        };
+        match self.execute_script_as(session, std::io::Cursor::new(commands)) {
+            Ok(_) => replies::Reply::Ok,
+            Err(e) => replies::Reply::Error(e.into_reply_error()),
+        }
+    }
+
+    /// `run <path>`: runs a script from one of the script directories.
+    fn run_script(&self, session: &Session, path: &str) -> replies::Reply {
+        let opened = script::resolve(&self.script_dirs, path).and_then(|path| {
+            std::fs::File::open(&path)
+                .map_err(|e| ReplyError::new(ErrorCode::StorageError, format!("Failed to open {}: {}", path.display(), e)))
+        });
+        let file = match opened {
+            Ok(file) => file,
+            Err(e) => return replies::Reply::Error(e),
+        };
+        match self.execute_script_as(session, std::io::BufReader::new(file)) {
+            Ok(_) => replies::Reply::Ok,
+            Err(e) => replies::Reply::Error(e.into_reply_error()),
+        }
+    }
+
+    /// Writes every sheet to an Excel workbook at `path`.
+    #[cfg(feature = "xlsx")]
+    pub fn export_xlsx(&self, path: impl AsRef<std::path::Path>) -> Result<(), Box<dyn Error>> {
+        Ok(xlsx::write(&self.workbook(), path)?)
+    }
+
+    /// Replaces every cell with the contents of an Excel workbook, like `load`.
+    #[cfg(feature = "xlsx")]
+    pub fn import_xlsx(&self, path: impl AsRef<std::path::Path>) -> Result<(), Box<dyn Error>> {
+        Ok(self.restore(xlsx::read(path)?, true)?)
+    }
+
+    /// Writes `range` (e.g. `Budget!A1:C100`) to a Parquet file at `path`.
+    /// The file is written from one snapshot of the range.
+    #[cfg(feature = "parquet")]
+    pub fn export_parquet(&self, range: &str, path: impl AsRef<std::path::Path>) -> Result<(), Box<dyn Error>> {
+        let (sheet, range) = address::split_sheet(range);
+        let range: address::CellRange = range.parse()?;
+        let snapshot = self.cells.read().unwrap().snapshot(sheet, range);
+        Ok(parquet::write(range, &snapshot, path)?)
+    }
+
+    /// Writes every sheet to an OpenDocument spreadsheet at `path`.
+    #[cfg(feature = "ods")]
+    pub fn export_ods(&self, path: impl AsRef<std::path::Path>) -> Result<(), Box<dyn Error>> {
+        ods::write(&self.workbook(), path)
+    }
+
+    /// Replaces every cell with the contents of an OpenDocument spreadsheet, like `load`.
+    #[cfg(feature = "ods")]
+    pub fn import_ods(&self, path: impl AsRef<std::path::Path>) -> Result<(), Box<dyn Error>> {
+        Ok(self.restore(ods::read(path)?, true)?)
+    }
+
+    /// Flushes the cell store's buffered writes.
+    pub fn flush(&self) -> Result<(), ReplyError> {
+        self.cells.write().unwrap().flush().map_err(storage_error)
+    }
+
+    /// Changes whenever any cell does.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// A new in-memory sheet holding the workbook's cells and formulas.
+    pub fn from_workbook(workbook: workbook::Workbook) -> Result<Self, ReplyError> {
+        let rsheet = RSheet::new();
+        rsheet.restore(workbook, false)?;
+        Ok(rsheet)
+    }
+
+    /// Snapshot of every cell, formula and sheet. The cells stay locked
+    /// exclusively until the formulas are read, so the two always agree.
+    pub fn workbook(&self) -> workbook::Workbook {
+        let cells = self.cells.write().unwrap();
+        let values: HashMap<String, CellValue> = cells.iter_all().collect();
+        workbook::Workbook::new(&values, &self.formulas.lock().unwrap())
+            .with_views(self.views.lock().unwrap().clone())
+            .with_charts(self.charts.lock().unwrap().clone())
+            .with_aliases(self.aliases.all())
+            .with_scenarios(self.scenarios.lock().unwrap().clone())
+            .with_macros(self.macros.lock().unwrap().clone())
+            .with_protected(self.protected.lock().unwrap().clone())
+    }
+
+    /// Which cells each stored formula reads, as written in its formula.
+    pub fn dependency_graph(&self) -> graph::DependencyGraph {
+        let formulas = self.formulas.lock().unwrap().clone();
+        let nodes = formulas
+            .into_iter()
+            .map(|(cell, formula)| {
+                let mut reads = self.runner(formula.sheet).references(&formula.expr);
+                reads.sort();
+                reads.dedup();
+                (cell, graph::Node { expr: formula.expr, reads })
+            })
+            .collect();
+        graph::DependencyGraph::new(nodes)
+    }
+
+    /// The dependency graph in Graphviz DOT, only as much of it as touches
+    /// `range` (a storage key such as `A1:C10` or `Budget!A:B`) if given.
+    pub fn export_dot(&self, range: Option<&str>) -> Result<String, ReplyError> {
+        let graph = self.dependency_graph();
+        let graph = match range {
+            Some(range) => {
+                let (sheet, cells) = address::split_sheet(range);
+                let cells = address::CellRange::parse_with_columns(cells)
+                    .map_err(|e| ReplyError::new(ErrorCode::ParseError, format!("{}", e)))?;
+                graph.scoped(sheet, cells)
+            }
+            None => graph,
+        };
+        Ok(graph.to_string())
+    }
+
+    /// Every cell whose formula reads `cell`, directly or through other
+    /// formulas no more than `depth` steps away, going by the stored
+    /// formulas of every sheet.
+    pub fn where_used(&self, cell: &str, depth: Option<usize>) -> Result<Vec<graph::Dependent>, ReplyError> {
+        let cell = cell_key(cell)?;
+        Ok(self.dependency_graph().dependents(&cell, depth))
+    }
+
+    /// The stored formulas `cell` is computed from, in an order they can be
+    /// evaluated in, ending with `cell` itself if it is a formula.
+    fn precedent_formulas(&self, cell: &str) -> Vec<(String, Formula)> {
+        let order = self.dependency_graph().precedents(cell);
+        let formulas = self.formulas.lock().unwrap();
+        order.into_iter().filter_map(|key| Some((key.clone(), formulas.get(&key)?.clone()))).collect()
+    }
+
+    /// Re-evaluates `precedents` in order as if the cells of `overrides` held
+    /// those values instead, returning each formula's value. Nothing is written,
+    /// and a formula cell that is overridden keeps its override.
+    fn recalculate_over(
+        &self,
+        precedents: &[(String, Formula)],
+        overrides: &[(String, CellValue)],
+    ) -> Result<Vec<(String, CellValue)>, ReplyError> {
+        let cells = self.cells.read().unwrap();
+        let mut staged = store::Overlay::new(cells.as_ref());
+        for (cell, value) in overrides {
+            staged.set(cell, value.clone()).map_err(storage_error)?;
+        }
+        let mut values = Vec::with_capacity(precedents.len());
+        for (cell, formula) in precedents {
+            if overrides.iter().any(|(overridden, _)| overridden == cell) {
+                continue;
+            }
+            let value = self
+                .runner(formula.sheet.clone())
+                .run_in(&staged, &formula.expr)
+                .map_err(ReplyError::from)
+                .map_err(|e| ReplyError::new(e.code, format!("{}: {}", cell, e.message)))?;
+            staged.set(cell, value.clone()).map_err(storage_error)?;
+            values.push((cell.clone(), value));
+        }
+        Ok(values)
+    }
+
+    /// `goalseek <cell> to <target> by changing <input>`: searches for the
+    /// number in `input` that brings the formula in `cell` to `target`,
+    /// re-evaluating the formulas between them for each guess. On success
+    /// `input` is set to it and those formulas are stored with their new
+    /// values; otherwise nothing changes and the error is `NoConvergence`.
+    /// Returns the number found.
+    pub fn goal_seek(&self, cell: &str, target: f64, input: &str) -> Result<f64, ReplyError> {
+        self.goal_seek_as(&Session::detached(), cell, target, input)
+    }
+
+    fn goal_seek_as(&self, session: &Session, cell: &str, target: f64, input: &str) -> Result<f64, ReplyError> {
+        let (cell, input) = (cell_key(cell)?, cell_key(input)?);
+        // The input and everything computed from it stay put while guessing.
+        let _locked = self.cell_locks.lock(vec![locks::LockScope::All]);
+        if self.formulas.lock().unwrap().contains_key(&input) {
+            return Err(ReplyError::new(ErrorCode::TypeMismatch, format!("{} holds a formula, not an input", input)));
+        }
+        let start = match self.cells.read().unwrap().get(&input).as_deref() {
+            Some(CellValue::Number(n)) => *n,
+            None => 0.0,
+            Some(other) => {
+                return Err(ReplyError::new(ErrorCode::TypeMismatch, format!("{} is not a number: {}", input, other)))
+            }
+        };
+        let graph = self.dependency_graph();
+        if !graph.dependents(&input, None).iter().any(|dependent| dependent.cell == cell) {
+            return Err(ReplyError::new(ErrorCode::ParseError, format!("{} does not depend on {}", cell, input)));
+        }
+        let precedents = self.precedent_formulas(&cell);
+        let attempt = |guess: f64| -> Result<(f64, Vec<(String, CellValue)>), ReplyError> {
+            let values = self.recalculate_over(&precedents, &[(input.clone(), CellValue::Number(guess))])?;
+            let miss = match values.last() {
+                Some((_, CellValue::Number(n))) => n - target,
+                Some((_, other)) => {
+                    return Err(ReplyError::new(ErrorCode::TypeMismatch, format!("{} is not a number: {}", cell, other)))
+                }
+                None => return Err(ReplyError::new(ErrorCode::TypeMismatch, format!("{} holds no formula", cell))),
+            };
+            Ok((miss, values))
+        };
+        let tolerance = GOAL_SEEK_TOLERANCE * target.abs().max(1.0);
+
+        // The secant method, from the input as it stands and a guess beside it.
+        let (mut previous, mut guess) = (start, if start == 0.0 { 1.0 } else { start * 1.01 });
+        let (mut previous_miss, mut values) = attempt(previous)?;
+        let mut found = (previous_miss.abs() <= tolerance).then_some(previous);
+        let mut tries = 1;
+        while found.is_none() && tries < GOAL_SEEK_ITERATIONS {
+            let (miss, guessed) = attempt(guess)?;
+            tries += 1;
+            values = guessed;
+            if miss.abs() <= tolerance {
+                found = Some(guess);
+                break;
+            }
+            let next = guess - miss * (guess - previous) / (miss - previous_miss);
+            if !next.is_finite() {
+                break;
+            }
+            (previous, previous_miss, guess) = (guess, miss, next);
+            session.check_deadline()?;
+        }
+        let Some(found) = found else {
+            let message = format!("{} did not reach {} by changing {} after {} tries", cell, target, input, tries);
+            return Err(ReplyError::new(ErrorCode::NoConvergence, message));
+        };
+
+        let formulas = self.formulas.lock().unwrap().clone();
+        let changed: Vec<(String, CellValue)> = values
+            .into_iter()
+            .filter(|(cell, value)| self.cells.read().unwrap().get(cell).as_deref() != Some(value))
+            .collect();
+        let value = CellValue::Number(found);
+        let cells = std::iter::once(&input).chain(changed.iter().map(|(cell, _)| cell));
+        self.leases.check(session.id, cells.map(String::as_str)).map_err(ReplyError::locked)?;
+        self.check_quotas([(input.as_str(), None)])?;
+        let freeing = self.cells.read().unwrap().get(&input).map_or(0, |old| meminfo::cell_bytes(&input, &old));
+        self.check_memory(meminfo::cell_bytes(&input, &value), freeing)?;
+
+        let old = self.put_locked(vec![(input.clone(), value.clone())], true)?.pop().flatten();
+        self.charge_user(session, &input, Some(&value));
+        self.notify(Some(session), &input, old.as_ref(), &value);
+        for (cell, value) in changed {
+            let Some(formula) = formulas.get(&cell) else {
+                continue;
+            };
+            let old = self.store(&cell, &formula.expr, formula.sheet.clone(), value.clone(), true)?;
+            self.notify(Some(session), &cell, old.as_ref(), &value);
+        }
+        let peer = session.peer().map(|p| p.to_string());
+        let entry = format!("goalseek {} to {} by changing {} ({})", cell, target, input, found);
+        self.audit.record(audit::AuditEntry::new(session.id(), peer, entry));
+        Ok(found)
+    }
+
+    /// What `cell` (a storage key) would hold in scenario `name`: its
+    /// overrides stand in for the stored values and the formulas `cell` is
+    /// computed from are evaluated over them. No cell is changed.
+    pub fn evaluate_in(&self, cell: &str, name: &str) -> Result<CellValue, ReplyError> {
+        let cell = cell_key(cell)?;
+        let Some(scenario) = self.scenarios.lock().unwrap().get(name).cloned() else {
+            return Err(ReplyError::new(ErrorCode::ParseError, format!("No scenario {}", name)));
+        };
+        if let Some(value) = scenario.overrides.get(&cell) {
+            return Ok(value.clone());
+        }
+        let values = self.recalculate_over(&self.precedent_formulas(&cell), &scenario.pairs())?;
+        match values.into_iter().last() {
+            Some((key, value)) if key == cell => Ok(value),
+            _ => Ok(self
+                .cells
+                .read()
+                .unwrap()
+                .get(&cell)
+                .map_or(CellValue::Empty, Arc::unwrap_or_clone)),
+        }
+    }
+
+    /// `scenario set <name> <cell> <expr>`: evaluates `expr` now, against the
+    /// stored cells, and has the scenario use its value for `cell`.
+    fn set_scenario(&self, session: &Session, name: &str, cell: &str, expr: &str) -> Result<(), ReplyError> {
+        let cell = cell_key(&self.resolve(session, cell))?;
+        let value = self.runner(session.sheet()).run(expr)?;
+        let mut scenarios = self.scenarios.lock().unwrap();
+        let Some(scenario) = scenarios.get_mut(name) else {
+            return Err(ReplyError::new(ErrorCode::ParseError, format!("No scenario {}", name)));
+        };
+        scenario.overrides.insert(cell.clone(), value);
+        drop(scenarios);
+        let peer = session.peer().map(|p| p.to_string());
+        self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("scenario set {} {} {}", name, cell, expr)));
+        Ok(())
+    }
+
+    /// Writes a backup archive to `path`. Only taking the snapshot blocks
+    /// other commands; writing it does not.
+    pub fn backup(&self, path: impl AsRef<std::path::Path>) -> Result<(), Box<dyn Error>> {
+        backup::Backup::new(self.workbook()).write(path)?;
+        Ok(())
+    }
+
+    /// Replaces all cells and formulas with a backup archive's.
+    pub fn restore_backup(&self, path: impl AsRef<std::path::Path>) -> Result<(), Box<dyn Error>> {
+        self.restore(backup::Backup::read(path)?.workbook, true)?;
+        Ok(())
+    }
+
+    /// Writes the workbook to `path` as JSON.
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), Box<dyn Error>> {
+        let json = serde_json::to_string_pretty(&self.workbook())?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Replaces all cells and formulas with the ones saved at `path`.
+    pub fn load(&self, path: impl AsRef<std::path::Path>) -> Result<(), Box<dyn Error>> {
+        let workbook = workbook::Workbook::from_json(&std::fs::read(path)?)?;
+        self.restore(workbook, true)?;
+        Ok(())
+    }
+
+    pub async fn handle_session_command(&self, session: &Session, command: String) -> replies::Reply {
+        self.execute(session, command.into()).await
+    }
+
+    /// Runs an already parsed command for `session`.
+    pub async fn execute(&self, session: &Session, command: command::Command) -> replies::Reply {
+        let started = Instant::now();
+        let kind = command.name().to_string();
+        let span = tracing::debug_span!("command", session = session.id(), command = %kind);
+        self.clients.touch(session.id());
+        *session.deadline.lock().unwrap() = session.command_timeout.map(|timeout| started + timeout);
+        // Imports are logged without their data, and tokens not at all.
+        let logged = match &command {
+            command::Command::Auth { .. } => "auth ...".to_string(),
+            command => command.to_string().lines().next().unwrap_or_default().to_string(),
+        };
+        let counted = match (&self.user_accounts, self.quota_user(session)) {
+            (Some(accounts), Some(user)) => accounts.count_command(&user).map_err(ReplyError::quota),
+            _ => Ok(()),
+        };
+        let (reply, cells) = span.in_scope(|| {
+            slowlog::counting(|| match counted {
+                Ok(()) => self.dispatch(session, command),
+                Err(e) => replies::Reply::Error(e),
+            })
+        });
+        let state = session.state();
+        let reply = match state.precision.or(self.precision) {
+            Some(digits) => replies::round_numbers(reply, digits),
+            None => reply,
+        };
+        let reply = match (reply, state.language()) {
+            (replies::Reply::Error(e), Some(language)) => replies::Reply::Error(messages::localize(e, language)),
+            (reply, _) => reply,
+        };
+        let duration = started.elapsed();
+        self.metrics.record_command(&kind, duration);
+        if let replies::Reply::Error(e) = &reply {
+            self.metrics.record_error(e.code);
+        }
+        let timing = slowlog::CommandTiming { connection: session.id(), kind: &kind, command: &logged, duration, cells };
+        self.slow_log.record(&timing);
+        for hook in &self.command_hooks {
+            hook(&timing);
+        }
+        reply
+    }
+
+    fn dispatch(&self, session: &Session, command: command::Command) -> replies::Reply {
+        use command::Command;
+
+        if self.is_replica() && command.is_write() {
+            return replies::Reply::error(ErrorCode::ReadOnly, "This server is a replica; send changes to the primary");
+        }
+        if self.read_only && command.is_write() {
+            return replies::Reply::error(ErrorCode::ReadOnly, format!("This server is read-only; {} is not allowed", command.name()));
+        }
+        if let Some(reply) = self.record(session, &command) {
+            return reply;
+        }
+        #[cfg(feature = "net")]
+        if let Some(reply) = self.route(session, &command) {
+            return reply;
+        }
+        if let Some(target) = command.focus() {
+            self.focus(session, &self.resolve(session, target));
+        }
+        match command {
+            Command::Set { cell, expr, if_version, force } => {
+                let cell = match cell_key(&self.resolve(session, &cell)) {
+                    Ok(cell) => cell,
+                    Err(e) => return replies::Reply::Error(e),
+                };
+                if !force && is_literal(&expr) && self.is_protected_formula(&cell) {
+                    let message = format!("{} holds a formula; use set {} {} --force to overwrite it", cell, cell, expr);
+                    return replies::Reply::error(ErrorCode::FormulaProtected, message);
+                }
+                // A number has no references to resolve against the session's sheet.
+                let sheet = if expr.parse::<f64>().is_ok() { None } else { session.sheet() };
+                self.set_cell(session, &cell, expr, sheet, if_version)
+            }
+            Command::GetVersion { cell } => match cell_key(&self.resolve(session, &cell)) {
+                Ok(cell) => self.get_cell_versioned(session, &cell),
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::GetArrow { range } => self.get_range_arrow(session, &self.resolve(session, &range)),
+            Command::GetRange { range } => self.get_range(session, &self.resolve(session, &range)),
+            Command::Dump => self.dump(session),
+            Command::Get { cell } => match cell_key(&self.resolve(session, &cell)) {
+                Ok(cell) => self.get_cell(session, &cell),
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::GetAsOf { cell, time } => match cell_key(&self.resolve(session, &cell)) {
+                Ok(cell) => self.get_cell_asof(&cell, &time),
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::GetMany { cells } => self.get_cells(session, &cells),
+            Command::Delete { cell } => match cell_key(&self.resolve(session, &cell)) {
+                Ok(cell) => self.delete_cell(session, &cell),
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::Begin => self.begin(session),
+            Command::Commit => self.commit(session),
+            Command::Rollback => match session.transaction.lock().unwrap().take() {
+                Some(_) => replies::Reply::Ok,
+                None => replies::Reply::error(ErrorCode::ParseError, "No transaction is open"),
+            },
+            Command::Use { sheet } => self.use_sheet(session, &sheet),
+            Command::Session => replies::Reply::Session(session.state()),
+            Command::SessionOption { key, value } => self.set_session_option(session, &key, &value),
+            Command::Watch { range, filter } => self.watch(session, &self.resolve(session, &range), filter),
+            Command::Unwatch { range: None } => self.unwatch(session, "all"),
+            Command::Unwatch { range: Some(range) } => self.unwatch(session, &self.resolve(session, &range)),
+            Command::Watches => replies::Reply::Watches(self.subscriptions.list(session.id)),
+            Command::Auth { token } => self.authenticate(session, &token),
+            Command::Admin(_) if !session.is_admin() => {
+                replies::Reply::error(ErrorCode::Unauthorized, "Admin commands need `auth <token>` first")
+            }
+            Command::Admin(admin) => self.admin(session, admin),
+            Command::Idem { key, command } => self.idempotency.run(&key, || self.dispatch(session, *command)),
+            Command::ExportRange { format, range, path } => {
+                self.export_range_file(session, &format, &self.resolve(session, &range), &path)
+            }
+            Command::Export { format, path } => self.export_file(session, &format, &path),
+            #[cfg(feature = "import-url")]
+            Command::ImportUrl { url, anchor, options } => self.import_url(session, &url, &self.resolve(session, &anchor), &options),
+            #[cfg(not(feature = "import-url"))]
+            Command::ImportUrl { .. } => {
+                replies::Reply::error(ErrorCode::ParseError, "This server was built without URL import support")
+            }
+            Command::Import { format, path } => self.import_file(session, &format, &path),
+            Command::ImportCsv { anchor, data, options } => {
+                match self.import_csv_as(session, data.as_bytes(), &self.resolve(session, &anchor), &options, "import csv") {
+                    Ok((range, cells)) => replies::Reply::Imported { range, cells },
+                    Err(e) => replies::Reply::Error(e),
+                }
+            }
+            Command::ExportCsv { range, options } => {
+                let mut out = Vec::new();
+                match self.export_csv_as(session, &mut out, &self.resolve(session, &range), options) {
+                    Ok(()) => replies::Reply::Exported(String::from_utf8_lossy(&out).into_owned()),
+                    Err(e) => replies::Reply::Error(e),
+                }
+            }
+            Command::Sync { body } => self.sync(session, &body),
+            Command::Backup { path } => self.backup_command(session, "backup", &path),
+            Command::Restore { path } => self.backup_command(session, "restore", &path),
+            Command::Run { path } => self.run_script(session, &path),
+            Command::Save { path } => self.persist(session, "save", path.as_deref()),
+            Command::Load { path } => self.persist(session, "load", path.as_deref()),
+            Command::Meminfo => replies::Reply::Memory(self.memory_usage()),
+            Command::Lock { cell, duration } => match cell_key(&self.resolve(session, &cell)) {
+                Ok(cell) => self.lock_cell(session, &cell, &duration),
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::Unlock { cell } => match cell_key(&self.resolve(session, &cell)) {
+                Ok(cell) if self.leases.release(&cell, session.id) => replies::Reply::Ok,
+                Ok(cell) => replies::Reply::error(ErrorCode::ParseError, format!("No lock held on {}", cell)),
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::Select { target } => match self.focus(session, &self.resolve(session, &target)) {
+                true => replies::Reply::Ok,
+                false => replies::Reply::error(ErrorCode::ParseError, format!("Invalid cell or range: {}", target)),
+            },
+            Command::Presence => replies::Reply::Presence(self.clients.presence()),
+            Command::Replicate => self.replicate(session),
+            Command::AlertWhen { args } => self.add_alert(session, &args.iter().map(String::as_str).collect::<Vec<_>>()),
+            Command::AlertRemove { id } => match self.alerts.remove(session.id, id) {
+                true => replies::Reply::Ok,
+                false => replies::Reply::error(ErrorCode::ParseError, format!("No alert {}", id)),
+            },
+            Command::Alerts => replies::Reply::Alerts(self.alerts.list(session.id)),
+            Command::Query { text } => self.query(session, &text),
+            Command::Filter { range, conditions } => self.filter(session, &self.resolve(session, &range), &conditions),
+            Command::Pivot { range, spec } => self.pivot(session, &self.resolve(session, &range), &spec),
+            Command::GroupBy { range, spec } => self.group_by(session, &self.resolve(session, &range), &spec),
+            Command::Dedupe { range, spec } => self.dedupe(session, &self.resolve(session, &range), &spec),
+            Command::View { sheet } => {
+                let sheet = sheet.or_else(|| session.sheet()).unwrap_or_else(|| address::DEFAULT_SHEET.to_string());
+                replies::Reply::View(self.views.lock().unwrap().get(&sheet).cloned().unwrap_or_default())
+            }
+            Command::ViewSet { change } => {
+                let sheet = session.sheet().unwrap_or_else(|| address::DEFAULT_SHEET.to_string());
+                let mut views = self.views.lock().unwrap();
+                let view = views.entry(sheet.clone()).or_default();
+                view.apply(&change);
+                if view.is_default() {
+                    views.remove(&sheet);
+                }
+                drop(views);
+                let peer = session.peer().map(|p| p.to_string());
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("view {} on {}", change, sheet)));
+                replies::Reply::Ok
+            }
+            Command::ChartDefine { name, chart } => {
+                if !chart::is_valid_name(&name) {
+                    return replies::Reply::error(ErrorCode::ParseError, format!("Invalid chart name: {}", name));
+                }
+                let chart = chart.resolved(|range| self.resolve(session, range));
+                let peer = session.peer().map(|p| p.to_string());
+                let text = format!("chart define {} {}", name, chart);
+                self.charts.lock().unwrap().insert(name, chart);
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, text));
+                replies::Reply::Ok
+            }
+            Command::ChartRemove { name } => match self.charts.lock().unwrap().remove(&name) {
+                Some(_) => {
+                    let peer = session.peer().map(|p| p.to_string());
+                    self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("chart remove {}", name)));
+                    replies::Reply::Ok
+                }
+                None => replies::Reply::error(ErrorCode::ParseError, format!("No chart {}", name)),
+            },
+            Command::Charts => replies::Reply::Charts(
+                self.charts
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(name, chart)| chart::NamedChart { name: name.clone(), chart: chart.clone() })
+                    .collect(),
+            ),
+            Command::ChartData { name } => match self.chart_data(session, &name) {
+                Ok(data) => replies::Reply::ChartData(data),
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::Alias { name, cell } => {
+                let cell = match cell_key(&self.resolve(session, &cell)) {
+                    Ok(cell) => cell,
+                    Err(e) => return replies::Reply::Error(e),
+                };
+                let peer = session.peer().map(|p| p.to_string());
+                let text = format!("alias {} {}", name, cell);
+                self.aliases.define(&name, cell);
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, text));
+                replies::Reply::Ok
+            }
+            Command::AliasRemove { name } => match self.aliases.remove(&name) {
+                Some(_) => {
+                    let peer = session.peer().map(|p| p.to_string());
+                    self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("alias remove {}", name)));
+                    replies::Reply::Ok
+                }
+                None => replies::Reply::error(ErrorCode::ParseError, format!("No alias {}", name)),
+            },
+            Command::Aliases => replies::Reply::Aliases(self.aliases.all()),
+            Command::WhereUsed { cell, depth } => match self.where_used(&self.resolve(session, &cell), depth) {
+                Ok(dependents) => replies::Reply::WhereUsed(dependents),
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::Clear { range } => self.clear(session, &self.resolve(session, &range)),
+            Command::GraphExport { range } => match self.export_dot(range.map(|range| self.resolve(session, &range)).as_deref()) {
+                Ok(dot) => replies::Reply::Exported(dot),
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::Describe { range, bins } => match self.range_snapshot(session, &self.resolve(session, &range)) {
+                Ok((range, snapshot)) => replies::Reply::Described(stats::describe(range, &snapshot, bins)),
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::Shift { range, direction, by } => match self.shift_as(session, &self.resolve(session, &range), direction, by) {
+                Ok((range, cells)) => replies::Reply::Imported { range, cells },
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::ScenarioCreate { name } => {
+                if !chart::is_valid_name(&name) {
+                    return replies::Reply::error(ErrorCode::ParseError, format!("Invalid scenario name: {}", name));
+                }
+                let mut scenarios = self.scenarios.lock().unwrap();
+                if scenarios.contains_key(&name) {
+                    return replies::Reply::error(ErrorCode::ParseError, format!("Scenario {} already exists", name));
+                }
+                scenarios.insert(name.clone(), scenario::Scenario::default());
+                drop(scenarios);
+                let peer = session.peer().map(|p| p.to_string());
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("scenario create {}", name)));
+                replies::Reply::Ok
+            }
+            Command::ScenarioSet { name, cell, expr } => match self.set_scenario(session, &name, &cell, &expr) {
+                Ok(()) => replies::Reply::Ok,
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::ScenarioRemove { name } => match self.scenarios.lock().unwrap().remove(&name) {
+                Some(_) => {
+                    let peer = session.peer().map(|p| p.to_string());
+                    self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("scenario remove {}", name)));
+                    replies::Reply::Ok
+                }
+                None => replies::Reply::error(ErrorCode::ParseError, format!("No scenario {}", name)),
+            },
+            Command::Scenarios => replies::Reply::Scenarios(
+                self.scenarios
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(name, scenario)| scenario::NamedScenario { name: name.clone(), scenario: scenario.clone() })
+                    .collect(),
+            ),
+            Command::Eval { cell, scenarios } => {
+                let cell = self.resolve(session, &cell);
+                let values: Result<Vec<_>, _> =
+                    scenarios.iter().map(|name| self.evaluate_in(&cell, name).map(Arc::new)).collect();
+                match values {
+                    Ok(mut values) if values.len() == 1 => replies::Reply::Value(values.remove(0)),
+                    Ok(values) => replies::Reply::Values(values),
+                    Err(e) => replies::Reply::Error(e),
+                }
+            }
+            Command::ProtectFormulas { sheet, on } => {
+                let sheet = sheet.or_else(|| session.sheet()).unwrap_or_else(|| address::DEFAULT_SHEET.to_string());
+                if !address::is_valid_sheet_name(&sheet) {
+                    return replies::Reply::error(ErrorCode::ParseError, format!("Invalid sheet name: {}", sheet));
+                }
+                self.protect_formulas(&sheet, on);
+                let peer = session.peer().map(|p| p.to_string());
+                let verb = if on { "protect" } else { "unprotect" };
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("{} formulas {}", verb, sheet)));
+                replies::Reply::Ok
+            }
+            Command::Append { column, values } => match self.append_as(session, &self.resolve(session, &column), &values) {
+                Ok(range) => replies::Reply::Appended { row: range.start.row + 1, range },
+                Err(e) => replies::Reply::Error(e),
+            },
+            Command::MacroRecord { name, params } => self.start_recording(session, name, params),
+            Command::MacroStop => self.stop_recording(session),
+            Command::MacroRun { name, args } => self.run_macro(session, &name, &args),
+            Command::MacroRemove { name } => match self.macros.lock().unwrap().remove(&name) {
+                Some(_) => {
+                    let peer = session.peer().map(|p| p.to_string());
+                    self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("macro remove {}", name)));
+                    replies::Reply::Ok
+                }
+                None => replies::Reply::error(ErrorCode::ParseError, format!("No macro {}", name)),
+            },
+            Command::Macros => replies::Reply::Macros(
+                self.macros
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(name, definition)| macros::NamedMacro { name: name.clone(), definition: definition.clone() })
+                    .collect(),
+            ),
+            Command::GoalSeek { cell, target, input } => {
+                match self.goal_seek_as(session, &self.resolve(session, &cell), target, &self.resolve(session, &input)) {
+                    Ok(found) => replies::Reply::Value(Arc::new(CellValue::Number(found))),
+                    Err(e) => replies::Reply::Error(e),
+                }
+            }
+            Command::Transpose { range, anchor, mode } => {
+                match self.transpose_as(session, &self.resolve(session, &range), &self.resolve(session, &anchor), mode) {
+                    Ok((range, cells)) => replies::Reply::Imported { range, cells },
+                    Err(e) => replies::Reply::Error(e),
+                }
+            }
+            Command::SlowLog { count } => {
+                replies::Reply::SlowLog(self.slow_log.recent(count.unwrap_or(DEFAULT_SLOWLOG_ENTRIES)))
+            }
+            Command::SlowLogReset => {
+                self.slow_log.reset();
+                replies::Reply::Ok
+            }
+            Command::Audit { count } => replies::Reply::Audit(self.audit.recent(count.unwrap_or(DEFAULT_AUDIT_ENTRIES))),
+            Command::Invalid { code, reason, .. } => replies::Reply::error(code, reason),
+        }
+    }
+
+    /// `admin ...`, for a session already known to be an admin.
+    fn admin(&self, session: &Session, admin: command::AdminCommand) -> replies::Reply {
+        use command::AdminCommand;
+
+        let peer = session.peer().map(|p| p.to_string());
+        let text = format!("admin {}", admin);
+        match admin {
+            AdminCommand::Snapshot => match self.snapshot() {
+                Ok(()) => replies::Reply::Ok,
+                Err(e) => replies::Reply::Error(e),
+            },
+            AdminCommand::Clients => replies::Reply::Clients(self.clients.list()),
+            AdminCommand::Webhooks
+            | AdminCommand::WebhooksDead
+            | AdminCommand::WebhookAdd { .. }
+            | AdminCommand::WebhookRemove { .. } => self.webhook_command(session, admin, &text),
+            AdminCommand::Promote => {
+                self.promote();
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, text));
+                replies::Reply::Ok
+            }
+            AdminCommand::Kick { target } => self.kick(session, &target),
+            AdminCommand::Quota { user } => match &self.user_accounts {
+                Some(accounts) => replies::Reply::Quota(accounts.usage(&user)),
+                None => replies::Reply::error(ErrorCode::ParseError, "Per-user quotas are not enabled"),
+            },
+            AdminCommand::QuotaSet { user, limit, value } => {
+                let Some(accounts) = &self.user_accounts else {
+                    return replies::Reply::error(ErrorCode::ParseError, "Per-user quotas are not enabled");
+                };
+                let mut quotas = accounts.quotas(&user);
+                match limit.as_str() {
+                    "cells" => quotas.max_cells = value.map(|n| n as usize),
+                    "bytes" => quotas.max_bytes = value,
+                    _ => quotas.max_commands_per_minute = value.map(|n| n.min(u32::MAX as u64) as u32),
+                }
+                accounts.set_quotas(&user, quotas);
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, text));
+                replies::Reply::Quota(accounts.usage(&user))
+            }
+            AdminCommand::Broadcast { text: message } => {
+                self.clients.broadcast(&message);
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, text));
+                replies::Reply::Ok
+            }
+        }
+    }
+
+    fn get_cell(&self, session: &Session, cell: &str) -> replies::Reply {
+        let transaction = session.transaction.lock().unwrap();
+        let cells = self.cells.read().unwrap();
+        let value = match transaction.as_ref() {
+            Some(tx) => self.versions.reading().view(cells.as_ref(), tx).get(cell),
+            None => cells.get(cell),
+        };
+        slowlog::touch(1);
+        match value {
+            Some(value) => {
+                self.diagnose(diagnostics::Level::Trace, "get", Some(cell), || format!("{:?}", value));
+                replies::Reply::Value(value)
+            },
+            None => {
+                self.diagnose(diagnostics::Level::Trace, "get of empty cell", Some(cell), String::new);
+                replies::Reply::Value(Arc::new(CellValue::Empty))
+            },
+        }
+    }
+
+    /// `get A1 version`. Writers to the cell wait, so the version is the value's.
+    fn get_cell_versioned(&self, session: &Session, cell: &str) -> replies::Reply {
+        let _locked = self.cell_locks.lock(vec![locks::LockScope::Cell(cell.to_string())]);
+        let version = self.cell_versions.get(cell);
+        match self.get_cell(session, cell) {
+            replies::Reply::Value(value) => replies::Reply::Versioned { value, version },
+            other => other,
+        }
+    }
+
+    /// `get A1 B7 C9`: the values as of one moment. Writers to the cells
+    /// wait while they are read, whichever store holds them.
+    fn get_cells(&self, session: &Session, cells: &[String]) -> replies::Reply {
+        let cells = match cells.iter().map(|cell| cell_key(&self.resolve(session, cell))).collect::<Result<Vec<_>, _>>() {
+            Ok(cells) => cells,
+            Err(e) => return replies::Reply::Error(e),
+        };
+        let mut scopes: Vec<locks::LockScope> = Vec::with_capacity(cells.len());
+        for cell in &cells {
+            let scope = locks::LockScope::Cell(cell.clone());
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        let _locked = self.cell_locks.lock(scopes);
+        let transaction = session.transaction.lock().unwrap();
+        let store = self.cells.read().unwrap();
+        let records;
+        let view;
+        let values: &dyn store::CellStore = match transaction.as_ref() {
+            Some(tx) => {
+                records = self.versions.reading();
+                view = records.view(store.as_ref(), tx);
+                &view
+            }
+            None => store.as_ref(),
+        };
+        slowlog::touch(cells.len() as u64);
+        let values = cells
+            .iter()
+            .map(|cell| values.get(cell).unwrap_or_else(|| Arc::new(CellValue::Empty)))
+            .collect();
+        replies::Reply::Values(values)
+    }
+
+    fn get_cell_asof(&self, cell: &str, time: &str) -> replies::Reply {
+        let Some(history) = &self.history else {
+            return replies::Reply::error(ErrorCode::HistoryUnavailable, "History is not enabled");
+        };
+        let Some(at_ms) = history::parse_time(time) else {
+            return replies::Reply::error(ErrorCode::ParseError, format!("Invalid time: {}", time));
+        };
+        match history.value_at(cell, at_ms) {
+            Ok(Some(value)) => replies::Reply::Value(Arc::new(value)),
+            Ok(None) => replies::Reply::Value(Arc::new(CellValue::Empty)),
+            Err(history::HistoryError::Expired { horizon_ms }) => replies::Reply::error(
+                ErrorCode::HistoryUnavailable,
+                format!("History of {} starts at {} ms since the epoch", cell, horizon_ms),
+            ),
+        }
+    }
+
+    fn get_range(&self, session: &Session, range: &str) -> replies::Reply {
+        let (sheet, range) = address::split_sheet(range);
+        let range: address::CellRange = match range.parse() {
+            Ok(range) => range,
+            Err(e) => return replies::Reply::error(ErrorCode::ParseError, format!("{}", e)),
+        };
+        self.diagnose(diagnostics::Level::Trace, "get range", None, || range.to_string());
+        self.range_reply(session, sheet, range)
+    }
+
+    /// `get <range> arrow`: one snapshot of the range as an Arrow IPC stream.
+    fn get_range_arrow(&self, session: &Session, range: &str) -> replies::Reply {
+        if !session.supports(connect::Capability::Arrow) {
+            return replies::Reply::error(ErrorCode::ProtocolError, "arrow replies require the arrow capability");
+        }
+        #[cfg(feature = "arrow")]
+        {
+            let (sheet, range) = address::split_sheet(range);
+            let range: address::CellRange = match range.parse() {
+                Ok(range) => range,
+                Err(e) => return replies::Reply::error(ErrorCode::ParseError, format!("{}", e)),
+            };
+            let snapshot = self.read_snapshot(session, self.cells.read().unwrap().as_ref(), sheet, range);
+            match arrow_ipc::record_batch(range, &snapshot).and_then(|batch| arrow_ipc::ipc_stream(&batch)) {
+                Ok(bytes) => replies::Reply::Arrow(bytes),
+                Err(e) => replies::Reply::error(ErrorCode::StorageError, format!("Failed to encode {}: {}", range, e)),
+            }
+        }
+        #[cfg(not(feature = "arrow"))]
+        replies::Reply::error(ErrorCode::ParseError, format!("Cannot encode {}: built without Arrow support", range))
+    }
+
+    /// `query SELECT ...`: runs the query over one snapshot of its range.
+    fn query(&self, session: &Session, text: &str) -> replies::Reply {
+        let result = text
+            .parse::<query::Query>()
+            .map_err(|e| ReplyError::new(ErrorCode::ParseError, e.to_string()))
+            .and_then(|query| {
+                let (range, snapshot) = self.range_snapshot(session, &self.resolve(session, query.from()))?;
+                query.run(range, &snapshot).map_err(|e| ReplyError::new(ErrorCode::ParseError, e.to_string()))
+            });
+        match result {
+            Ok(table) => replies::Reply::Table(table),
+            Err(e) => replies::Reply::Error(e),
+        }
+    }
+
+    /// `filter <range> where <condition> [and ...]`: the rows of one snapshot
+    /// of the range that meet every condition.
+    fn filter(&self, session: &Session, range: &str, conditions: &str) -> replies::Reply {
+        let result = query::parse_where(conditions)
+            .map_err(|e| ReplyError::new(ErrorCode::ParseError, e.to_string()))
+            .and_then(|conditions| {
+                let (range, snapshot) = self.range_snapshot(session, range)?;
+                query::filter_rows(range, &snapshot, &conditions).map_err(|e| ReplyError::new(ErrorCode::ParseError, e.to_string()))
+            });
+        match result {
+            Ok(rows) => replies::Reply::Rows(rows),
+            Err(e) => replies::Reply::Error(e),
+        }
+    }
+
+    /// `pivot <range> rows <columns> [columns <column>] values <column> [agg <aggregate>]`.
+    fn pivot(&self, session: &Session, range: &str, spec: &str) -> replies::Reply {
+        let result = spec
+            .parse::<query::Pivot>()
+            .map_err(|e| ReplyError::new(ErrorCode::ParseError, e.to_string()))
+            .and_then(|pivot| {
+                let (range, snapshot) = self.range_snapshot(session, range)?;
+                pivot.run(range, &snapshot).map_err(|e| ReplyError::new(ErrorCode::ParseError, e.to_string()))
+            });
+        match result {
+            Ok(table) => replies::Reply::Table(table),
+            Err(e) => replies::Reply::Error(e),
+        }
+    }
+
+    /// `groupby <range> key <columns> [agg <aggregate>] [value <column>]`.
+    fn group_by(&self, session: &Session, range: &str, spec: &str) -> replies::Reply {
+        let result = spec
+            .parse::<query::GroupBy>()
+            .map_err(|e| ReplyError::new(ErrorCode::ParseError, e.to_string()))
+            .and_then(|group_by| {
+                let (range, snapshot) = self.range_snapshot(session, range)?;
+                group_by.run(range, &snapshot).map_err(|e| ReplyError::new(ErrorCode::ParseError, e.to_string()))
+            });
+        match result {
+            Ok(table) => replies::Reply::Table(table),
+            Err(e) => replies::Reply::Error(e),
+        }
+    }
+
+    /// The series of chart `name` as its ranges hold them now, read from one
+    /// snapshot so the series agree with each other.
+    fn chart_data(&self, session: &Session, name: &str) -> Result<chart::ChartData, ReplyError> {
+        let Some(chart) = self.charts.lock().unwrap().get(name).cloned() else {
+            return Err(ReplyError::new(ErrorCode::ParseError, format!("No chart {}", name)));
+        };
+        let cells = self.cells.read().unwrap();
+        let read = |range: &str| -> Result<Vec<Option<CellValue>>, ReplyError> {
+            let (sheet, range) = chart::parse_range(range)?;
+            let snapshot = self.read_snapshot(session, cells.as_ref(), sheet, range);
+            Ok(range.iter().map(|addr| snapshot.get(addr).map(|value| CellValue::clone(value))).collect())
+        };
+        let number = |value: Option<CellValue>| match value {
+            Some(CellValue::Number(n)) => Some(n),
+            Some(CellValue::Currency { amount, .. }) => Some(amount),
+            _ => None,
+        };
+        let text = |value: Option<CellValue>| value.map_or(String::new(), |value| value.to_string());
+        let labels = match &chart.labels {
+            Some(range) => Some(read(range)?.into_iter().map(text).collect()),
+            None => None,
+        };
+        let mut series = Vec::with_capacity(chart.series.len());
+        for range in &chart.series {
+            series.push(chart::Series { range: range.clone(), values: read(range)?.into_iter().map(number).collect() });
+        }
+        Ok(chart::ChartData { name: name.to_string(), kind: chart.kind, labels, series })
+    }
+
+    /// `dedupe <range> by <columns> [keep first|last]`: drops duplicate rows
+    /// and moves the rest up to close the gaps. Cells that move lose their
+    /// formulas, keeping their values; rows that stay put are untouched.
+    /// Aliases of cells that move follow them.
+    fn dedupe(&self, session: &Session, range: &str, spec: &str) -> replies::Reply {
+        let dedupe = match spec.parse::<query::Dedupe>() {
+            Ok(dedupe) => dedupe,
+            Err(e) => return replies::Reply::error(ErrorCode::ParseError, e.to_string()),
+        };
+        let (sheet, cells) = address::split_sheet(range);
+        let cells: address::CellRange = match cells.parse() {
+            Ok(cells) => cells,
+            Err(e) => return replies::Reply::error(ErrorCode::ParseError, format!("{}", e)),
+        };
+        match self.dedupe_range(session, sheet, cells, &dedupe) {
+            Ok(removed) => {
+                let peer = session.peer().map(|p| p.to_string());
+                let entry = format!("dedupe {} {} ({} rows removed)", range, spec.trim(), removed);
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, entry));
+                replies::Reply::Removed { range: cells, rows: removed }
+            }
+            Err(e) => replies::Reply::Error(e),
+        }
+    }
+
+    /// Rewrites `range` with only the rows `dedupe` keeps, returning how many it dropped.
+    fn dedupe_range(
+        &self,
+        session: &Session,
+        sheet: Option<&str>,
+        range: address::CellRange,
+        dedupe: &query::Dedupe,
+    ) -> Result<usize, ReplyError> {
+        // The sheet is held from the read to the last write, so no row changes in between.
+        let _locked = self.cell_locks.lock(vec![locks::LockScope::sheet(sheet)]);
+        let snapshot = self.cells.read().unwrap().snapshot(sheet, range);
+        let kept = dedupe.run(range, &snapshot).map_err(|e| ReplyError::new(ErrorCode::ParseError, e.to_string()))?;
+        let removed = query::rows(range, &snapshot).len() - kept.len();
+
+        let mut values = Vec::new();
+        let mut emptied = Vec::new();
+        for (i, row) in (range.start.row..=range.end.row).enumerate() {
+            let moved = match kept.get(i) {
+                Some((from, _)) if *from == row => continue,
+                Some((_, moved)) => Some(moved),
+                None => None,
+            };
+            for (j, col) in (range.start.col..=range.end.col).enumerate() {
+                let addr = address::CellAddress::new(col, row);
+                let cell = address::qualify(sheet, &addr.to_string());
+                match moved.and_then(|moved| moved[j].clone()) {
+                    Some(value) => values.push((cell, value)),
+                    None if snapshot.get(addr).is_some() => emptied.push(cell),
+                    None => {}
+                }
+            }
+            session.check_deadline()?;
+        }
+
+        let cells = values.iter().map(|(cell, _)| cell.as_str()).chain(emptied.iter().map(String::as_str));
+        self.leases.check(session.id, cells).map_err(ReplyError::locked)?;
+        self.check_quotas(values.iter().map(|(cell, _)| (cell.as_str(), None)))?;
+        self.check_memory(values.iter().map(|(cell, value)| meminfo::cell_bytes(cell, value)).sum(), 0)?;
+        if !values.is_empty() {
+            let olds = self.put_locked(values.clone(), true)?;
+            for ((cell, value), old) in values.iter().zip(olds) {
+                self.notify(Some(session), cell, old.as_ref(), value);
+            }
+        }
+        for cell in &emptied {
+            let old = self.remove(cell, true)?;
+            self.notify_write(Some(session), cell, old.as_ref(), None);
+        }
+        let moves: HashMap<u32, u32> =
+            kept.iter().zip(range.start.row..).filter(|((from, _), to)| from != to).map(|((from, _), to)| (*from, to)).collect();
+        self.repoint_aliases(sheet, |addr| {
+            let to = moves.get(&addr.row).filter(|_| range.contains(&addr))?;
+            Some(address::CellAddress::new(addr.col, *to))
+        });
+        Ok(removed)
+    }
+
+    /// `append <column> <expr>...`: writes one value per column, from `column`
+    /// (e.g. `A` or `Log!A`) rightwards, into the row below the last filled
+    /// cell of any of those columns, all at once, so nothing already there is
+    /// overwritten. Each value is read as `set` reads it.
+    /// Returns the cells written; appends racing on one sheet each get a row
+    /// of their own.
+    pub fn append(&self, column: &str, exprs: &[String]) -> Result<address::CellRange, ReplyError> {
+        self.append_as(&Session::detached(), column, exprs)
+    }
+
+    fn append_as(&self, session: &Session, column: &str, exprs: &[String]) -> Result<address::CellRange, ReplyError> {
+        let (sheet, letters) = address::split_sheet(column);
+        let col = address::column_index(letters)
+            .filter(|col| *col < address::MAX_COLS)
+            .ok_or_else(|| ReplyError::new(ErrorCode::ParseError, format!("Invalid column: {}", column)))?;
+        if exprs.is_empty() {
+            return Err(ReplyError::new(ErrorCode::ParseError, "Nothing to append"));
+        }
+        let last_col = col + exprs.len() as u32 - 1;
+        if last_col >= address::MAX_COLS {
+            let message = format!("{} values do not fit in a row from column {}", exprs.len(), letters);
+            return Err(ReplyError::new(ErrorCode::ParseError, message));
+        }
+        let runner = self.runner(sheet.map(str::to_string));
+        // The sheet is held from finding the row to filling it, so no other append takes it.
+        let mut scopes: Vec<locks::LockScope> = exprs.iter().flat_map(|expr| runner.lock_scopes(expr)).collect();
+        scopes.push(locks::LockScope::sheet(sheet));
+        let _locked = self.cell_locks.lock(scopes);
+        // Every column the row spans is scanned, so the row is empty across it.
+        let whole =
+            address::CellRange::new(address::CellAddress::new(col, 0), address::CellAddress::new(last_col, address::MAX_ROWS - 1));
+        let row = self.cells.read().unwrap().iter_range(sheet, whole).map(|(addr, _)| addr.row + 1).max().unwrap_or(0);
+        if row >= address::MAX_ROWS {
+            return Err(ReplyError::new(ErrorCode::QuotaExceeded, format!("No empty row left from column {}", letters)));
+        }
+        let range = address::CellRange::new(address::CellAddress::new(col, row), address::CellAddress::new(last_col, row));
+
+        let mut writes = Vec::with_capacity(exprs.len());
+        for (col, expr) in (col..).zip(exprs) {
+            let cell = address::qualify(sheet, &address::CellAddress::new(col, row).to_string());
+            let value = runner.run(expr).map_err(ReplyError::from)?;
+            writes.push((cell, expr, value));
+        }
+        self.leases.check(session.id, writes.iter().map(|(cell, ..)| cell.as_str())).map_err(ReplyError::locked)?;
+        self.check_quotas(writes.iter().map(|(cell, expr, _)| (cell.as_str(), Some(expr.as_str()))))?;
+        self.check_user_quotas(session, writes.iter().map(|(cell, _, value)| (cell.as_str(), Some(meminfo::cell_bytes(cell, value)))))?;
+        let adding = writes
+            .iter()
+            .map(|(cell, expr, value)| {
+                let formula = if is_literal(expr) { 0 } else { meminfo::formula_bytes(cell, expr) };
+                meminfo::cell_bytes(cell, value) + formula
+            })
+            .sum();
+        self.check_memory(adding, 0)?;
+        for (cell, expr, value) in writes {
+            let old = self.store(&cell, expr, runner.sheet.clone(), value.clone(), true)?;
+            self.charge_user(session, &cell, Some(&value));
+            self.notify(Some(session), &cell, old.as_ref(), &value);
+        }
+        let peer = session.peer().map(|p| p.to_string());
+        self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("append {} ({})", column, range)));
+        Ok(range)
+    }
+
+    /// `clear <range>`: empties every cell of the range, values and formulas
+    /// alike, in one go. Formulas elsewhere that read the cleared cells keep
+    /// their last values and are listed in the reply.
+    fn clear(&self, session: &Session, range: &str) -> replies::Reply {
+        let (sheet, cells) = address::split_sheet(range);
+        let cells = match address::CellRange::parse_with_columns(cells) {
+            Ok(cells) => cells,
+            Err(e) => return replies::Reply::error(ErrorCode::ParseError, format!("{}", e)),
+        };
+        match self.clear_range(session, sheet, cells) {
+            Ok((cleared, dependents)) => {
+                let peer = session.peer().map(|p| p.to_string());
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("clear {} ({} cells)", range, cleared)));
+                replies::Reply::Cleared { range: cells, cells: cleared, dependents }
+            }
+            Err(e) => replies::Reply::Error(e),
+        }
+    }
+
+    /// Removes every cell of `range`, returning how many there were and the
+    /// formulas outside it that read them, directly or not.
+    fn clear_range(
+        &self,
+        session: &Session,
+        sheet: Option<&str>,
+        range: address::CellRange,
+    ) -> Result<(usize, Vec<String>), ReplyError> {
+        // The sheet is held throughout, so nothing lands in the range meanwhile.
+        let _locked = self.cell_locks.lock(vec![locks::LockScope::sheet(sheet)]);
+        let snapshot = self.cells.read().unwrap().snapshot(sheet, range);
+        let cells: Vec<String> = snapshot.iter_range(range).map(|(addr, _)| address::qualify(sheet, &addr.to_string())).collect();
+        self.leases.check(session.id, cells.iter().map(String::as_str)).map_err(ReplyError::locked)?;
+        let dependents = self.dependency_graph().range_dependents(sheet, range, None).into_iter().map(|d| d.cell).collect();
+        // Past this point the cells go together, in one log entry.
+        session.check_deadline()?;
+        let deletes: Vec<mvcc::TxWrite> = cells.iter().map(|cell| mvcc::TxWrite::Delete { cell: cell.clone() }).collect();
+        let olds = if deletes.is_empty() { Vec::new() } else { self.commit_writes(&deletes, None, true)? };
+        for (cell, old) in cells.iter().zip(olds) {
+            self.charge_user(session, cell, None);
+            self.notify_write(Some(session), cell, old.as_ref(), None);
+        }
+        Ok((cells.len(), dependents))
+    }
+
+    /// Moves the aliases on `sheet` whose cells `moved` gives a new address.
+    fn repoint_aliases(&self, sheet: Option<&str>, moved: impl Fn(address::CellAddress) -> Option<address::CellAddress>) {
+        self.aliases.repoint(|cell| match address::parse_key(cell) {
+            Ok((cell_sheet, addr)) if cell_sheet == sheet => Some(address::qualify(sheet, &moved(addr)?.to_string())),
+            _ => None,
+        });
+    }
+
+    /// One snapshot of a resolved range, which may span whole columns (`A:D`).
+    fn range_snapshot(&self, session: &Session, range: &str) -> Result<(address::CellRange, store::Snapshot), ReplyError> {
+        let (sheet, range) = address::split_sheet(range);
+        let range = address::CellRange::parse_with_columns(range)
+            .map_err(|e| ReplyError::new(ErrorCode::ParseError, format!("{}", e)))?;
+        Ok((range, self.read_snapshot(session, self.cells.read().unwrap().as_ref(), sheet, range)))
+    }
+
+    /// Streams the smallest range holding every cell of the session's sheet.
+    fn dump(&self, session: &Session) -> replies::Reply {
+        let sheet = session.sheet();
+        let used = self.cells.read().unwrap().iter_all().fold(None, |used: Option<address::CellRange>, (key, _)| {
+            let (key_sheet, addr) = address::split_sheet(&key);
+            match addr.parse::<address::CellAddress>() {
+                Ok(addr) if key_sheet == sheet.as_deref() => Some(match used {
+                    Some(used) => address::CellRange::new(
+                        address::CellAddress::new(used.start.col.min(addr.col), used.start.row.min(addr.row)),
+                        address::CellAddress::new(used.end.col.max(addr.col), used.end.row.max(addr.row)),
+                    ),
+                    None => address::CellRange::new(addr, addr),
+                }),
+                _ => used,
+            }
+        });
+        if let Err(e) = session.check_deadline() {
+            return replies::Reply::Error(e);
+        }
+        match used {
+            Some(range) => self.range_reply(session, sheet.as_deref(), range),
+            None => replies::Reply::error(ErrorCode::UnknownCell, "Sheet is empty"),
+        }
+    }
+
+    /// Values of `range`, one `Vec` per row. Ranges over [`STREAM_THRESHOLD_CELLS`]
+    /// are pushed to the session as [`Message::Chunk`]s instead, and only the
+    /// closing [`replies::Reply::Streamed`] is returned. Every chunk comes
+    /// from one snapshot, so a streamed range is still a single moment's values.
+    fn range_reply(&self, session: &Session, sheet: Option<&str>, range: address::CellRange) -> replies::Reply {
+        let width = (range.end.col - range.start.col + 1) as u64;
+        let height = (range.end.row - range.start.row + 1) as u64;
+        let snapshot = self.read_snapshot(session, self.cells.read().unwrap().as_ref(), sheet, range);
+        let row_values = |rows: std::ops::RangeInclusive<u32>| -> Result<Vec<Vec<CellValue>>, ReplyError> {
+            rows.map(|row| {
+                session.check_deadline()?;
+                Ok((range.start.col..=range.end.col)
+                    .map(|col| {
+                        let addr = address::CellAddress::new(col, row);
+                        snapshot.get(addr).map_or(CellValue::Empty, |value| CellValue::clone(value))
+                    })
+                    .collect())
+            })
+            .collect()
+        };
+        let streaming = session.supports(connect::Capability::Streaming);
+        let writer = match &session.writer {
+            Some(writer) if streaming && width * height > STREAM_THRESHOLD_CELLS => writer,
+            _ => {
+                return match row_values(range.start.row..=range.end.row) {
+                    Ok(values) => replies::Reply::Range { range, values },
+                    Err(e) => replies::Reply::Error(e),
+                }
+            }
+        };
+
+        let rows_per_chunk = (STREAM_THRESHOLD_CELLS / width).max(1) as u32;
+        let mut row = range.start.row;
+        while row <= range.end.row {
+            let last = row.saturating_add(rows_per_chunk - 1).min(range.end.row);
+            let chunk = match row_values(row..=last) {
+                Ok(rows) => Message::Chunk(rows),
+                Err(e) => return replies::Reply::Error(e),
+            };
+            if let Err(e) = writer.lock().unwrap().send(&chunk) {
+                return replies::Reply::error(ErrorCode::ProtocolError, format!("Failed to stream range: {}", e));
+            }
+            row = match last.checked_add(1) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        replies::Reply::Streamed { range }
+    }
+
+    /// Snapshot of `range` in `cells`, which the caller holds locked, as the
+    /// session sees it: inside a transaction, as of when it began.
+    fn read_snapshot(
+        &self,
+        session: &Session,
+        cells: &dyn store::CellStore,
+        sheet: Option<&str>,
+        range: address::CellRange,
+    ) -> store::Snapshot {
+        let snapshot = match session.transaction.lock().unwrap().as_ref() {
+            Some(tx) => self.versions.reading().view(cells, tx).iter_range(sheet, range).collect(),
+            None => cells.snapshot(sheet, range),
+        };
+        slowlog::touch(snapshot.len() as u64);
+        snapshot
+    }
+
+    /// `begin`: later sets and deletes are held back until `commit`, and
+    /// reads see the cells as they are now plus the session's own writes.
+    /// Other commands still apply at once.
+    fn begin(&self, session: &Session) -> replies::Reply {
+        let mut transaction = session.transaction.lock().unwrap();
+        if transaction.is_some() {
+            return replies::Reply::error(ErrorCode::ParseError, "A transaction is already open");
+        }
+        // No change is half applied while the transaction's start is taken.
+        let _cells = self.cells.write().unwrap();
+        *transaction = Some(mvcc::Transaction::begin(&self.versions));
+        replies::Reply::Ok
+    }
+
+    /// `commit`: applies the session's transaction as one change, unless
+    /// another commit changed one of its cells first.
+    fn commit(&self, session: &Session) -> replies::Reply {
+        let Some(tx) = session.transaction.lock().unwrap().take() else {
+            return replies::Reply::error(ErrorCode::ParseError, "No transaction is open");
+        };
+        let adding = tx
+            .writes()
+            .iter()
+            .map(|write| match write {
+                mvcc::TxWrite::Set { cell, value, .. } => meminfo::cell_bytes(cell, value),
+                mvcc::TxWrite::Delete { .. } => 0,
+            })
+            .sum();
+        let user_writes = tx.writes().iter().map(|write| match write {
+            mvcc::TxWrite::Set { cell, value, .. } => (cell.as_str(), Some(meminfo::cell_bytes(cell, value))),
+            mvcc::TxWrite::Delete { cell } => (cell.as_str(), None),
+        });
+        let checked = self.check_quotas(quota_writes(tx.writes())).and_then(|()| self.check_user_quotas(session, user_writes));
+        if let Err(e) = checked.and_then(|()| self.check_memory(adding, 0)) {
+            return replies::Reply::Error(e);
+        }
+        let locked = self.cell_locks.lock(tx.writes().iter().map(|write| locks::LockScope::Cell(write.cell().to_string())).collect());
+        if let Err(holder) = self.leases.check(session.id, tx.writes().iter().map(mvcc::TxWrite::cell)) {
+            return replies::Reply::Error(ReplyError::locked(holder));
+        }
+        let committed = if self.settles_conflicts() {
+            self.settle_writes(&tx).and_then(|writes| self.commit_writes(&writes, None, true).map(|olds| (olds, writes)))
+        } else {
+            self.commit_writes(tx.writes(), Some(&tx), true).map(|olds| (olds, tx.writes().to_vec()))
+        };
+        drop(locked);
+        drop(tx);
+        let (olds, writes) = match committed {
+            Ok(committed) => committed,
+            Err(e) => return replies::Reply::Error(e),
+        };
+        for (write, old) in writes.into_iter().zip(olds) {
+            match write {
+                mvcc::TxWrite::Set { cell, expr, value, .. } => {
+                    self.charge_user(session, &cell, Some(&value));
+                    self.notify(Some(session), &cell, old.as_ref(), &value);
+                    self.audit(session, format!("set {} {}", cell, expr), &cell, old, Some(value));
+                }
+                mvcc::TxWrite::Delete { cell } => {
+                    self.charge_user(session, &cell, None);
+                    if let Some(old) = old {
+                        self.notify(Some(session), &cell, Some(&old), &CellValue::Empty);
+                        self.audit(session, format!("delete {}", cell), &cell, Some(old), None);
+                    }
+                }
+            }
+        }
+        replies::Reply::Ok
+    }
+
+    /// `tx`'s writes with those to cells another commit changed since it
+    /// began settled by the conflict policy or merge hook. The caller holds
+    /// the cell locks of every write, so nothing changes before they commit.
+    fn settle_writes(&self, tx: &mvcc::Transaction) -> Result<Vec<mvcc::TxWrite>, ReplyError> {
+        let conflicts = self.versions.conflicts(tx);
+        let cells = self.cells.read().unwrap();
+        let mut writes = Vec::with_capacity(tx.writes().len());
+        for write in tx.writes() {
+            let Some((cell, base)) = conflicts.iter().find(|(cell, _)| cell == write.cell()) else {
+                writes.push(write.clone());
+                continue;
+            };
+            let theirs = cells.get(cell);
+            let ours = match write {
+                mvcc::TxWrite::Set { value, .. } => Some(value),
+                mvcc::TxWrite::Delete { .. } => None,
+            };
+            let conflict = conflict::Conflict { cell, base: base.as_deref(), theirs: theirs.as_deref(), ours };
+            match self.settle(&conflict) {
+                conflict::Resolution::Ours => writes.push(write.clone()),
+                conflict::Resolution::Theirs => {}
+                conflict::Resolution::Reject => {
+                    return Err(ReplyError::new(ErrorCode::Conflict, format!("{} was changed since the transaction began", cell)));
+                }
+                conflict::Resolution::Expr(expr) => {
+                    let sheet = address::split_sheet(cell).0.map(str::to_string);
+                    let runner = self.runner(sheet);
+                    let value = runner.run_in(cells.as_ref(), &expr)?;
+                    writes.push(mvcc::TxWrite::Set { cell: cell.clone(), expr, sheet: runner.sheet, value });
+                }
+            }
+        }
+        Ok(writes)
+    }
+
+    /// Applies `writes` together and returns the old values. With `tx`, fails
+    /// instead if another commit changed one of its cells after it began.
+    /// The caller holds the cell locks of every write.
+    fn commit_writes(
+        &self,
+        writes: &[mvcc::TxWrite],
+        tx: Option<&mvcc::Transaction>,
+        log: bool,
+    ) -> Result<Vec<Option<CellValue>>, ReplyError> {
+        let mut cells = self.cells.write().unwrap();
+        if let Some(cell) = tx.and_then(|tx| self.versions.conflict(tx)) {
+            return Err(ReplyError::new(ErrorCode::Conflict, format!("{} was changed since the transaction began", cell)));
+        }
+        if log {
+            self.log(&wal::WalEntry::Commit(writes.to_vec()))?;
+        }
+        let mut versions = self.versions.writing();
+        let olds = writes
+            .iter()
+            .map(|write| match write {
+                mvcc::TxWrite::Set { cell, expr, sheet, value } => {
+                    let old = cells.set(cell, value.clone())?;
+                    self.stored(cell, expr, sheet.clone(), old.as_ref(), value.clone());
+                    Ok(old)
+                }
+                mvcc::TxWrite::Delete { cell } => {
+                    let old = cells.delete(cell)?;
+                    if old.is_some() {
+                        self.removed(cell, old.as_ref());
+                    }
+                    Ok(old)
+                }
+            })
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(storage_error)?;
+        if let Some(records) = &mut versions {
+            records.commit(writes.iter().zip(&olds).map(|(write, old)| {
+                let new = match write {
+                    mvcc::TxWrite::Set { value, .. } => Some(value),
+                    mvcc::TxWrite::Delete { .. } => None,
+                };
+                (write.cell(), old.as_ref(), new)
+            }));
+        }
+        drop(versions);
+        self.compact_if_due(cells.as_ref());
+        Ok(olds)
+    }
+
+    /// Evaluates `expr` with unqualified references resolved against `sheet`.
+    /// With `expected`, fails with a conflict unless the cell is at that version.
+    fn set_cell(
+        &self,
+        session: &Session,
+        cell: &str,
+        expr: String,
+        sheet: Option<String>,
+        expected: Option<u64>,
+    ) -> replies::Reply {
+        if let Err(e) = self.quotas.check_cell(cell, Some(&expr)) {
+            return replies::Reply::Error(ReplyError::quota(e));
+        }
+        let runner = self.runner(sheet);
+        if let Some(tx) = session.transaction.lock().unwrap().as_mut() {
+            if expected.is_some() {
+                return replies::Reply::error(ErrorCode::ParseError, "if-version is not supported inside a transaction");
+            }
+            let cells = self.cells.read().unwrap();
+            let value = runner.run_in(&self.versions.reading().view(cells.as_ref(), tx), &expr);
+            drop(cells);
+            return match value {
+                Ok(value) => {
+                    tx.write(mvcc::TxWrite::Set { cell: cell.to_string(), expr, sheet: runner.sheet, value });
+                    replies::Reply::Ok
+                }
+                Err(e) => replies::Reply::Error(e.into()),
+            };
+        }
+        let mut scopes = runner.lock_scopes(&expr);
+        scopes.push(locks::LockScope::Cell(cell.to_string()));
+        let _locked = self.cell_locks.lock(scopes);
+        if let Err(holder) = self.leases.check(session.id, [cell]) {
+            return replies::Reply::Error(ReplyError::locked(holder));
+        }
+        if let Some(expected) = expected {
+            let version = self.cell_versions.get(cell);
+            if version != expected {
+                let message = format!("{} is at version {}, not {}", cell, version, expected);
+                return replies::Reply::error(ErrorCode::Conflict, message);
+            }
+        }
+        let started = Instant::now();
+        let result = runner.run(&expr).map_err(ReplyError::from).and_then(|value| session.check_deadline().map(|()| value));
+        self.metrics.record_recalc(started.elapsed());
+        match result {
+            Err(e) => {
+                self.diagnose(diagnostics::Level::Debug, "set failed", Some(cell), || format!("{}: {}", expr, e));
+                self.subscriptions.notify_failure(cell, &e.message);
+                replies::Reply::Error(e)
+            },
+            Ok(value) => {
+                self.diagnose(diagnostics::Level::Trace, "set", Some(cell), || format!("{} = {:?}", expr, value));
+                let mut adding = meminfo::cell_bytes(cell, &value);
+                if expr.parse::<f64>().is_err() {
+                    adding += meminfo::formula_bytes(cell, &expr);
+                }
+                let freeing = self.cells.read().unwrap().get(cell).map_or(0, |old| meminfo::cell_bytes(cell, &old));
+                let checked = self.check_quotas([(cell, Some(expr.as_str()))])
+                    .and_then(|()| self.check_user_quotas(session, [(cell, Some(meminfo::cell_bytes(cell, &value)))]));
+                if let Err(e) = checked.and_then(|()| self.check_memory(adding, freeing)) {
+                    return replies::Reply::Error(e);
+                }
+                let old = match self.store(cell, &expr, runner.sheet.clone(), value.clone(), true) {
+                    Ok(old) => old,
+                    Err(e) => return replies::Reply::Error(e),
+                };
+                self.charge_user(session, cell, Some(&value));
+                self.notify(Some(session), cell, old.as_ref(), &value);
+                self.audit(session, format!("set {} {}", cell, expr), cell, old, Some(value));
+                replies::Reply::Ok
+            }
+        }
+    }
+
+    fn delete_cell(&self, session: &Session, cell: &str) -> replies::Reply {
+        if let Some(tx) = session.transaction.lock().unwrap().as_mut() {
+            let cells = self.cells.read().unwrap();
+            let exists = self.versions.reading().view(cells.as_ref(), tx).get(cell).is_some();
+            drop(cells);
+            if !exists {
+                return replies::Reply::error(ErrorCode::UnknownCell, format!("Cell {} not found", cell));
+            }
+            tx.write(mvcc::TxWrite::Delete { cell: cell.to_string() });
+            return replies::Reply::Ok;
+        }
+        let locked = self.cell_locks.lock(vec![locks::LockScope::Cell(cell.to_string())]);
+        if let Err(holder) = self.leases.check(session.id, [cell]) {
+            return replies::Reply::Error(ReplyError::locked(holder));
+        }
+        let removed = self.remove(cell, true);
+        drop(locked);
+        let old = match removed {
+            Ok(Some(old)) => old,
+            Ok(None) => return replies::Reply::error(ErrorCode::UnknownCell, format!("Cell {} not found", cell)),
+            Err(e) => return replies::Reply::Error(e),
+        };
+        self.charge_user(session, cell, None);
+        self.notify(Some(session), cell, Some(&old), &CellValue::Empty);
+        self.audit(session, format!("delete {}", cell), cell, Some(old), None);
+        replies::Reply::Ok
+    }
+
+    /// `sync` with a JSON [`crdt::SyncRequest`] on the lines after it: merges
+    /// the replica's writes, applying those that win, and answers with the
+    /// winning writes it has not seen.
+    fn sync(&self, session: &Session, body: &str) -> replies::Reply {
+        let Some(registers) = &self.crdt else {
+            return replies::Reply::error(ErrorCode::ParseError, "Sync is not enabled");
+        };
+        let request: crdt::SyncRequest = match serde_json::from_str(body) {
+            Ok(request) => request,
+            Err(e) => return replies::Reply::error(ErrorCode::ParseError, format!("Invalid sync request: {}", e)),
+        };
+        if request.replica == crdt::SERVER_REPLICA || request.ops.iter().any(|op| op.stamp.replica != request.replica) {
+            return replies::Reply::error(ErrorCode::ParseError, "Sync writes must be stamped by the syncing replica");
+        }
+        let mut ops = Vec::with_capacity(request.ops.len());
+        for mut op in request.ops {
+            op.cell = match cell_key(&op.cell) {
+                Ok(cell) => cell,
+                Err(e) => return replies::Reply::Error(e),
+            };
+            ops.push(op);
+        }
+        let checked = ops.iter().filter_map(|op| match &op.content {
+            crdt::Content::Expr(expr) => Some((op.cell.as_str(), Some(expr.as_str()))),
+            crdt::Content::Value(_) => Some((op.cell.as_str(), None)),
+            crdt::Content::Deleted => None,
+        });
+        if let Err(e) = self.check_quotas(checked) {
+            return replies::Reply::Error(e);
+        }
+
+        let mut seen = request.seen;
+        if let Some(time) = ops.iter().map(|op| op.stamp.time).max() {
+            let own = seen.entry(request.replica).or_default();
+            *own = (*own).max(time);
+        }
+        let _locked = self.cell_locks.lock(vec![locks::LockScope::All]);
+        let mut conflicts = Vec::new();
+        for op in ops {
+            let current = registers.lock().unwrap().get(&op.cell).cloned();
+            let raced = current.filter(|current| {
+                self.settles_conflicts()
+                    && current.stamp.replica != op.stamp.replica
+                    && current.stamp.time > seen.get(&current.stamp.replica).copied().unwrap_or(0)
+            });
+            if let Some(current) = raced {
+                if let Err(e) = self.settle_op(session, op, current, &mut conflicts) {
+                    return replies::Reply::Error(e);
+                }
+                continue;
+            }
+            if !registers.lock().unwrap().wins(&op) {
+                registers.lock().unwrap().merge(op);
+                continue;
+            }
+            let cell = op.cell.clone();
+            let (old, new) = match self.apply_op(&op) {
+                Ok(change) => change,
+                Err(e) => return replies::Reply::Error(e),
+            };
+            registers.lock().unwrap().replace(op);
+            self.notify_write(Some(session), &cell, old.as_ref(), new.as_ref());
+            self.audit(session, format!("sync {}", cell), &cell, old, new);
+        }
+        let ops = registers.lock().unwrap().since(&seen);
+        replies::Reply::Synced(crdt::SyncResponse { ops, conflicts })
+    }
+
+    /// Settles a synced write made without seeing `current`, the cell's
+    /// write. Whatever is kept is stamped as a new server write, so every
+    /// replica takes it whichever stamp was later.
+    fn settle_op(
+        &self,
+        session: &Session,
+        op: crdt::Op,
+        current: crdt::Op,
+        conflicts: &mut Vec<crdt::Op>,
+    ) -> Result<(), ReplyError> {
+        let Some(registers) = &self.crdt else {
+            return Ok(());
+        };
+        let resolution = match &self.merge_hook {
+            Some(hook) => {
+                let theirs = self.cells.read().unwrap().get(&op.cell);
+                let ours = match &op.content {
+                    crdt::Content::Expr(expr) => {
+                        let sheet = address::split_sheet(&op.cell).0.map(str::to_string);
+                        let runner = self.runner(sheet);
+                        Some(runner.run(expr).unwrap_or_else(|e| CellValue::Error(ReplyError::from(e).message)))
+                    }
+                    crdt::Content::Value(value) => Some(value.clone()),
+                    crdt::Content::Deleted => None,
+                };
+                hook(&conflict::Conflict { cell: &op.cell, base: None, theirs: theirs.as_deref(), ours: ours.as_ref() })
+            }
+            None => self.conflict_policy.map_or(conflict::Resolution::Ours, |policy| policy.resolve()),
+        };
+        let kept = match resolution {
+            conflict::Resolution::Ours => Some(op.content.clone()),
+            conflict::Resolution::Expr(expr) => Some(crdt::Content::Expr(expr)),
+            conflict::Resolution::Theirs => None,
+            conflict::Resolution::Reject => {
+                conflicts.push(op.clone());
+                None
+            }
+        };
+        let cell = op.cell.clone();
+        registers.lock().unwrap().merge(op.clone());
+        let content = match kept {
+            Some(content) => {
+                let (old, new) = self.apply_op(&crdt::Op { content: content.clone(), ..op })?;
+                self.notify_write(Some(session), &cell, old.as_ref(), new.as_ref());
+                self.audit(session, format!("sync {}", cell), &cell, old, new);
+                content
+            }
+            None => current.content,
+        };
+        registers.lock().unwrap().write(crdt::SERVER_REPLICA, &cell, content);
+        Ok(())
+    }
+
+    /// Applies a replica's winning write, returning the cell's old and new
+    /// values. A formula that fails to evaluate leaves its error in the cell,
+    /// so the cell still matches the write every replica agreed on.
+    fn apply_op(&self, op: &crdt::Op) -> Result<(Option<CellValue>, Option<CellValue>), ReplyError> {
+        let cell = op.cell.as_str();
+        match &op.content {
+            crdt::Content::Expr(expr) => {
+                let sheet = address::split_sheet(cell).0.map(str::to_string);
+                let runner = self.runner(sheet);
+                let value = runner.run(expr).unwrap_or_else(|e| CellValue::Error(ReplyError::from(e).message));
+                let old = self.store(cell, expr, runner.sheet, value.clone(), true)?;
+                Ok((old, Some(value)))
+            }
+            crdt::Content::Value(value) => {
+                let old = self.put_locked(vec![(cell.to_string(), value.clone())], true)?.pop().flatten();
+                Ok((old, Some(value.clone())))
+            }
+            crdt::Content::Deleted => Ok((self.remove(cell, true)?, None)),
+        }
+    }
+
+    /// Writes `entry` to the write-ahead log, if there is one, then streams
+    /// it to any replicas.
+    fn log(&self, entry: &wal::WalEntry) -> Result<(), ReplyError> {
+        if let Some(wal) = &self.wal {
+            wal.append(entry).map_err(|e| {
+                ReplyError::new(ErrorCode::StorageError, format!("Failed to write the write-ahead log: {}", e))
+            })?;
+        }
+        self.replicas.publish(entry);
+        Ok(())
+    }
+
+    /// Forwards a get, set or delete of another node's cells there. `None`
+    /// when the command is for this node.
+    #[cfg(feature = "net")]
+    fn route(&self, session: &Session, command: &command::Command) -> Option<replies::Reply> {
+        use command::Command;
+
+        let router = self.router.as_ref()?;
+        let targets: Vec<String> = match command {
+            Command::GetMany { cells } => cells.iter().map(|cell| self.resolve(session, cell)).collect(),
+            Command::Set { .. }
+            | Command::Get { .. }
+            | Command::GetRange { .. }
+            | Command::GetVersion { .. }
+            | Command::GetArrow { .. }
+            | Command::GetAsOf { .. }
+            | Command::Delete { .. } => vec![self.resolve(session, command.focus()?)],
+            _ => return None,
+        };
+        let mut owners = targets.iter().map(|target| router.owner(address::split_sheet(target).0));
+        let node = owners.next()?;
+        if owners.any(|owner| owner != node) {
+            return Some(replies::Reply::error(ErrorCode::ParseError, "A command cannot span sheets on different nodes"));
+        }
+        let Some(node) = node else {
+            // A formula reads its operands from this node's store only.
+            if let Command::Set { expr, .. } = command {
+                let runner = self.runner(session.sheet());
+                for scope in runner.lock_scopes(expr) {
+                    let sheet = match &scope {
+                        locks::LockScope::Cell(cell) => address::split_sheet(cell).0,
+                        locks::LockScope::Sheet(sheet) => sheet.as_deref(),
+                        locks::LockScope::All => continue,
+                    };
+                    if let Some(node) = router.owner(sheet) {
+                        let sheet = sheet.unwrap_or(address::DEFAULT_SHEET);
+                        return Some(replies::Reply::error(ErrorCode::ParseError, format!("{} is served by {}; formulas cannot read across nodes", sheet, node)));
+                    }
+                }
+            }
+            return None;
+        };
+        if session.transaction.lock().unwrap().is_some() {
+            return Some(replies::Reply::error(ErrorCode::ParseError, "A transaction cannot include another node's cells"));
+        }
+        Some(router.forward(node, session.sheet().as_deref(), &command.to_string()))
+    }
+
+    /// Streams a snapshot and then every change to the session's connection.
+    fn replicate(&self, session: &Session) -> replies::Reply {
+        if !session.supports(connect::Capability::Notifications) {
+            return replies::Reply::error(ErrorCode::ProtocolError, "replicate requires the notifications capability");
+        }
+        let Some(writer) = &session.writer else {
+            return replies::Reply::error(ErrorCode::ParseError, "replicate requires a network connection");
+        };
+        // Held until the replica is registered, so no change falls between the snapshot and the stream.
+        let cells = self.cells.write().unwrap();
+        let values: HashMap<String, CellValue> = cells.iter_all().collect();
+        let snapshot = workbook::Workbook::new(&values, &self.formulas.lock().unwrap());
+        match self.replicas.add(session.id, writer.clone(), snapshot) {
+            Ok(()) => replies::Reply::Ok,
+            Err(e) => replies::Reply::error(ErrorCode::StorageError, format!("Failed to send the snapshot: {}", e)),
+        }
+    }
+
+    /// Compacts the write-ahead log into a snapshot of the current workbook.
+    pub fn snapshot(&self) -> Result<(), ReplyError> {
+        let cells = self.cells.write().unwrap();
+        self.compact(cells.as_ref())
+    }
+
+    /// Snapshots `cells`, which the caller holds locked so no change slips in.
+    fn compact(&self, cells: &dyn store::CellStore) -> Result<(), ReplyError> {
+        let Some(wal) = &self.wal else {
+            return Err(ReplyError::new(ErrorCode::StorageError, "No write-ahead log configured"));
+        };
+        let values: HashMap<String, CellValue> = cells.iter_all().collect();
+        let snapshot = workbook::Workbook::new(&values, &self.formulas.lock().unwrap());
+        wal.compact(snapshot).map_err(|e| {
+            ReplyError::new(ErrorCode::StorageError, format!("Failed to compact the write-ahead log: {}", e))
+        })?;
+        self.diagnose(diagnostics::Level::Info, "compacted write-ahead log", None, || wal.path().display().to_string());
+        Ok(())
+    }
+
+    /// [`RSheet::compact_if_due`] for callers that wrote under the shared lock,
+    /// which does not keep other writers out.
+    fn compact_if_due_exclusive(&self) {
+        if self.wal.as_ref().is_some_and(|wal| wal.needs_compaction()) {
+            self.compact_if_due(self.cells.write().unwrap().as_ref());
+        }
+    }
+
+    /// Compacts once the log has grown past its limit. A failure is only
+    /// logged: the change itself is already durable.
+    fn compact_if_due(&self, cells: &dyn store::CellStore) {
+        if self.wal.as_ref().is_some_and(|wal| wal.needs_compaction()) {
+            if let Err(e) = self.compact(cells) {
+                self.diagnose(diagnostics::Level::Error, "automatic compaction failed", None, || e.to_string());
+            }
+        }
+    }
+
+    /// Stores an evaluated cell and its formula. The cell map stays locked
+    /// from logging to applying, so the log order matches the apply order.
+    fn store(
+        &self,
+        cell: &str,
+        expr: &str,
+        sheet: Option<String>,
+        value: CellValue,
+        log: bool,
+    ) -> Result<Option<CellValue>, ReplyError> {
+        let entry = || wal::WalEntry::Set {
+            cell: cell.to_string(),
+            expr: expr.to_string(),
+            sheet: sheet.clone(),
+            value: Some(value.clone()),
+        };
+        let cells = self.cells.read().unwrap();
+        let old = if cells.shared_writes().is_some() {
+            // The caller holds this cell's lock, so its writes still log in apply order.
+            if log {
+                self.log(&entry())?;
+            }
+            let mut versions = self.versions.writing();
+            let old = cells.shared_writes().unwrap().set(cell, value.clone()).map_err(storage_error)?;
+            if let Some(records) = &mut versions {
+                records.commit([(cell, old.as_ref(), Some(&value))]);
+            }
+            drop(versions);
+            self.stored(cell, expr, sheet.clone(), old.as_ref(), value);
+            drop(cells);
+            self.compact_if_due_exclusive();
+            old
+        } else {
+            drop(cells);
+            let mut cells = self.cells.write().unwrap();
+            if log {
+                self.log(&entry())?;
+            }
+            let old = cells.set(cell, value.clone()).map_err(storage_error)?;
+            if let Some(records) = &mut self.versions.writing() {
+                records.commit([(cell, old.as_ref(), Some(&value))]);
+            }
+            self.stored(cell, expr, sheet.clone(), old.as_ref(), value);
+            self.compact_if_due(cells.as_ref());
+            old
+        };
+        Ok(old)
+    }
+
+    /// Bookkeeping after `cell` was set from `old` to `value` by `expr`.
+    fn stored(&self, cell: &str, expr: &str, sheet: Option<String>, old: Option<&CellValue>, value: CellValue) {
+        slowlog::touch(1);
+        self.memory.change(cell, old, Some(&value));
+        self.count_cell(cell, old.is_some(), true);
+        self.cell_versions.bump(cell);
+        self.stamp(cell, crdt::Content::Expr(expr.to_string()));
+        self.record_history(cell, Some(value));
+        let mut formulas = self.formulas.lock().unwrap();
+        let replaced = if is_literal(expr) {
+            formulas.remove(cell)
+        } else {
+            self.memory.add(meminfo::formula_bytes(cell, expr));
+            formulas.insert(cell.to_string(), Formula { expr: expr.to_string(), sheet })
+        };
+        drop(formulas);
+        if let Some(replaced) = replaced {
+            self.memory.sub(meminfo::formula_bytes(cell, &replaced.expr));
+        }
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Stores literal values, dropping any formulas they replace. Returns the old values.
+    fn put(&self, values: Vec<(String, CellValue)>, log: bool) -> Result<Vec<Option<CellValue>>, ReplyError> {
+        let mut sheets: Vec<locks::LockScope> = Vec::new();
+        for (cell, _) in &values {
+            let sheet = locks::LockScope::sheet(address::split_sheet(cell).0);
+            if !sheets.contains(&sheet) {
+                sheets.push(sheet);
+            }
+        }
+        let _locked = self.cell_locks.lock(sheets);
+        self.put_locked(values, log)
+    }
+
+    /// [`RSheet::put`] for a caller already holding the cells' sheets.
+    fn put_locked(&self, values: Vec<(String, CellValue)>, log: bool) -> Result<Vec<Option<CellValue>>, ReplyError> {
+        let mut cells = self.cells.write().unwrap();
+        if log {
+            self.log(&wal::WalEntry::Put(values.clone()))?;
+        }
+        let mut formulas = self.formulas.lock().unwrap();
+        let mut versions = self.versions.writing();
+        let olds: Vec<Option<CellValue>> = values
+            .iter()
+            .map(|(cell, value)| {
+                if let Some(formula) = formulas.remove(cell) {
+                    self.memory.sub(meminfo::formula_bytes(cell, &formula.expr));
+                }
+                self.record_history(cell, Some(value.clone()));
+                let old = cells.set(cell, value.clone())?;
+                self.memory.change(cell, old.as_ref(), Some(value));
+                self.count_cell(cell, old.is_some(), true);
+                self.cell_versions.bump(cell);
+                self.stamp(cell, crdt::Content::Value(value.clone()));
+                Ok(old)
+            })
+            .collect::<Result<_, _>>()
+            .map_err(storage_error)?;
+        slowlog::touch(values.len() as u64);
+        if let Some(records) = &mut versions {
+            records.commit(values.iter().zip(&olds).map(|((cell, value), old)| (cell.as_str(), old.as_ref(), Some(value))));
+        }
+        drop(versions);
+        drop(formulas);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.compact_if_due(cells.as_ref());
+        Ok(olds)
+    }
+
+    fn record_history(&self, cell: &str, value: Option<CellValue>) {
+        if let Some(history) = &self.history {
+            history.record(cell, value);
+        }
+    }
+
+    /// Removes a cell. `Ok(None)` if it was already empty; nothing is logged then.
+    fn remove(&self, cell: &str, log: bool) -> Result<Option<CellValue>, ReplyError> {
+        let cells = self.cells.read().unwrap();
+        if cells.get(cell).is_none() {
+            return Ok(None);
+        }
+        let entry = || wal::WalEntry::Delete { cell: cell.to_string() };
+        let old = if let Some(shared) = cells.shared_writes() {
+            if log {
+                self.log(&entry())?;
+            }
+            let mut versions = self.versions.writing();
+            let old = shared.delete(cell).map_err(storage_error)?;
+            if let Some(records) = &mut versions {
+                records.commit([(cell, old.as_ref(), None)]);
+            }
+            drop(versions);
+            self.removed(cell, old.as_ref());
+            drop(cells);
+            self.compact_if_due_exclusive();
+            old
+        } else {
+            drop(cells);
+            let mut cells = self.cells.write().unwrap();
+            if log {
+                self.log(&entry())?;
+            }
+            let old = cells.delete(cell).map_err(storage_error)?;
+            if let Some(records) = &mut self.versions.writing() {
+                records.commit([(cell, old.as_ref(), None)]);
+            }
+            self.removed(cell, old.as_ref());
+            self.compact_if_due(cells.as_ref());
+            old
+        };
+        Ok(old)
+    }
+
+    fn removed(&self, cell: &str, old: Option<&CellValue>) {
+        slowlog::touch(1);
+        self.memory.change(cell, old, None);
+        self.count_cell(cell, old.is_some(), false);
+        self.cell_versions.remove(cell);
+        self.stamp(cell, crdt::Content::Deleted);
+        self.record_history(cell, None);
+        if let Some(formula) = self.formulas.lock().unwrap().remove(cell) {
+            self.memory.sub(meminfo::formula_bytes(cell, &formula.expr));
+        }
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Replaces every cell and formula with the workbook's.
+    fn restore(&self, mut workbook: workbook::Workbook, log: bool) -> Result<(), ReplyError> {
+        let _locked = self.cell_locks.lock(vec![locks::LockScope::All]);
+        let mut cells = self.cells.write().unwrap();
+        if log {
+            self.log(&wal::WalEntry::Restore(workbook.clone()))?;
+        }
+        *self.views.lock().unwrap() = std::mem::take(&mut workbook.views);
+        *self.charts.lock().unwrap() = std::mem::take(&mut workbook.charts);
+        self.aliases.replace(std::mem::take(&mut workbook.aliases));
+        *self.scenarios.lock().unwrap() = std::mem::take(&mut workbook.scenarios);
+        *self.macros.lock().unwrap() = std::mem::take(&mut workbook.macros);
+        *self.protected.lock().unwrap() = std::mem::take(&mut workbook.protected);
+        let (values, formulas) = workbook.into_maps();
+        let mut versions = self.versions.writing();
+        if self.history.is_some() || versions.is_some() {
+            let olds: HashMap<String, CellValue> = cells.iter_all().collect();
+            for (cell, old) in &olds {
+                if !values.contains_key(cell) {
+                    self.record_history(cell, None);
+                } else if values.get(cell) != Some(old) {
+                    self.record_history(cell, values.get(cell).cloned());
+                }
+            }
+            for (cell, value) in &values {
+                if !olds.contains_key(cell) {
+                    self.record_history(cell, Some(value.clone()));
+                }
+            }
+            if let Some(records) = &mut versions {
+                let cells = olds.keys().chain(values.keys().filter(|cell| !olds.contains_key(*cell)));
+                records.commit(
+                    cells
+                        .map(|cell| (cell.as_str(), olds.get(cell), values.get(cell)))
+                        .filter(|(_, old, new)| old != new),
+                );
+            }
+        }
+        drop(versions);
+        let bytes = values.iter().map(|(cell, value)| meminfo::cell_bytes(cell, value)).sum::<u64>()
+            + formulas.iter().map(|(cell, formula)| meminfo::formula_bytes(cell, &formula.expr)).sum::<u64>();
+        if let Some(counts) = &self.cell_counts {
+            counts.reset(values.keys().map(String::as_str));
+        }
+        self.cell_versions.reset(values.keys().map(String::as_str));
+        if self.crdt.is_some() {
+            for (cell, _) in cells.iter_all().filter(|(cell, _)| !values.contains_key(cell)) {
+                self.stamp(&cell, crdt::Content::Deleted);
+            }
+            for (cell, value) in &values {
+                let content = match formulas.get(cell) {
+                    Some(formula) => crdt::Content::Expr(formula.expr.clone()),
+                    None => crdt::Content::Value(value.clone()),
+                };
+                self.stamp(cell, content);
+            }
+        }
+        cells.replace_all(values).map_err(storage_error)?;
+        *self.formulas.lock().unwrap() = formulas;
+        self.memory.reset(bytes);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// `save`/`load` to the configured path, or to an explicit one for admins
+    /// only, since it names a file on the server.
+    fn persist(&self, session: &Session, action: &str, path: Option<&str>) -> replies::Reply {
+        let path = match (path, &self.persistence_path) {
+            (Some(_), _) if !session.is_admin() => {
+                return replies::Reply::error(ErrorCode::Unauthorized, "Only admins may name a file");
+            }
+            (Some(path), _) => std::path::PathBuf::from(path),
+            (None, Some(path)) => path.clone(),
+            (None, None) => return replies::Reply::error(ErrorCode::ParseError, "No persistence path configured"),
+        };
+        let result = if action == "save" { self.save(&path) } else { self.load(&path) };
+        match result {
+            Ok(()) => {
+                let peer = session.peer().map(|p| p.to_string());
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("{} {}", action, path.display())));
+                replies::Reply::Ok
+            }
+            Err(e) => {
+                replies::Reply::error(ErrorCode::StorageError, format!("Failed to {} {}: {}", action, path.display(), e))
+            }
+        }
+    }
+
+    /// `import url <url> <anchor>`: streams remote CSV into the sheet, if the
+    /// URL policy allows the host.
+    #[cfg(feature = "import-url")]
+    fn import_url(&self, session: &Session, url: &str, anchor: &str, options: &infer::InferOptions) -> replies::Reply {
+        let body = match self.url_policy.open(url) {
+            Ok(body) => body,
+            Err(e @ fetch::FetchError::NotAllowed(_)) => return replies::Reply::error(ErrorCode::Unauthorized, e.to_string()),
+            Err(e) => return replies::Reply::error(ErrorCode::StorageError, format!("Failed to fetch {}: {}", url, e)),
+        };
+        match self.import_csv_as(session, body, anchor, options, &format!("import url {}", url)) {
+            Ok((range, cells)) => replies::Reply::Imported { range, cells },
+            Err(e) => replies::Reply::Error(e),
+        }
+    }
+
+    /// `backup <path>` / `restore <path>`, admin-only since they name a file on the server.
+    fn backup_command(&self, session: &Session, action: &str, path: &str) -> replies::Reply {
+        if !session.is_admin() {
+            return replies::Reply::error(ErrorCode::Unauthorized, format!("Only admins may {}", action));
+        }
+        let result = if action == "backup" { self.backup(path) } else { self.restore_backup(path) };
+        match result {
+            Ok(()) => {
+                let peer = session.peer().map(|p| p.to_string());
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("{} {}", action, path)));
+                replies::Reply::Ok
+            }
+            Err(e) => replies::Reply::error(ErrorCode::StorageError, format!("Failed to {} {}: {}", action, path, e)),
+        }
+    }
+
+    /// `import <format> <path>`: replaces the workbook with a server-side file.
+    fn import_file(&self, session: &Session, format: &str, path: &str) -> replies::Reply {
+        if !session.is_admin() {
+            return replies::Reply::error(ErrorCode::Unauthorized, "Only admins may import a file");
+        }
+        let result: Option<Result<(), Box<dyn Error>>> = match format {
+            #[cfg(feature = "xlsx")]
+            "xlsx" => Some(self.import_xlsx(path)),
+            #[cfg(feature = "ods")]
+            "ods" => Some(self.import_ods(path)),
+            _ => None,
+        };
+        match result {
+            None => replies::Reply::error(ErrorCode::ParseError, format!("Unsupported import format: {}", format)),
+            Some(Ok(())) => {
+                let peer = session.peer().map(|p| p.to_string());
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("import {} {}", format, path)));
+                replies::Reply::Ok
+            }
+            Some(Err(e)) => replies::Reply::error(ErrorCode::StorageError, format!("Failed to import {}: {}", path, e)),
+        }
+    }
+
+    /// `export <format> <path>`: writes the whole workbook to a server-side file.
+    fn export_file(&self, session: &Session, format: &str, path: &str) -> replies::Reply {
+        if !session.is_admin() {
+            return replies::Reply::error(ErrorCode::Unauthorized, "Only admins may export to a file");
+        }
+        let result: Option<Result<(), Box<dyn Error>>> = match format {
+            #[cfg(feature = "xlsx")]
+            "xlsx" => Some(self.export_xlsx(path)),
+            #[cfg(feature = "ods")]
+            "ods" => Some(self.export_ods(path)),
+            _ => None,
+        };
+        match result {
+            None => replies::Reply::error(ErrorCode::ParseError, format!("Unsupported export format: {}", format)),
+            Some(Ok(())) => {
+                let peer = session.peer().map(|p| p.to_string());
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("export {} {}", format, path)));
+                replies::Reply::Ok
+            }
+            Some(Err(e)) => replies::Reply::error(ErrorCode::StorageError, format!("Failed to export {}: {}", path, e)),
+        }
+    }
+
+    /// `export <format> <range> <path>`: writes one range to a server-side file.
+    fn export_range_file(&self, session: &Session, format: &str, range: &str, path: &str) -> replies::Reply {
+        if !session.is_admin() {
+            return replies::Reply::error(ErrorCode::Unauthorized, "Only admins may export to a file");
+        }
+        let result: Option<Result<(), Box<dyn Error>>> = match format {
+            #[cfg(feature = "parquet")]
+            "parquet" => Some(self.export_parquet(range, path)),
+            _ => None,
+        };
+        match result {
+            None => replies::Reply::error(ErrorCode::ParseError, format!("Unsupported range export format: {}", format)),
+            Some(Ok(())) => {
+                let peer = session.peer().map(|p| p.to_string());
+                let command = format!("export {} {} {}", format, range, path);
+                self.audit.record(audit::AuditEntry::new(session.id(), peer, command));
+                replies::Reply::Ok
+            }
+            Some(Err(e)) => replies::Reply::error(ErrorCode::StorageError, format!("Failed to export {}: {}", path, e)),
+        }
+    }
+
+    fn audit(&self, session: &Session, command: String, cell: &str, old: Option<CellValue>, new: Option<CellValue>) {
+        let peer = session.peer().map(|p| p.to_string());
+        self.audit.record(audit::AuditEntry::new(session.id(), peer, command).with_change(cell, old, new));
+    }
+
+    /// `auth <token>`: the admin token makes the session an admin's, and a
+    /// user's token makes it that user's.
+    fn authenticate(&self, session: &Session, token: &str) -> replies::Reply {
+        if let Some(user) = self.user_tokens.get(token) {
+            *session.user.lock().unwrap() = Some(user.clone());
+            return replies::Reply::Ok;
+        }
+        if self.admin_token.as_deref() != Some(token) {
+            return replies::Reply::error(ErrorCode::Unauthorized, "Invalid token");
+        }
+        session.admin.store(true, Ordering::SeqCst);
+        self.clients.set_admin(session.id());
+        replies::Reply::Ok
+    }
+
+    /// `webhook add <url> [range]`, `webhook remove <id>`, `webhooks` and `webhooks dead`.
+    fn webhook_command(&self, session: &Session, admin: command::AdminCommand, text: &str) -> replies::Reply {
+        use command::AdminCommand;
+
+        let Some(webhooks) = &self.webhooks else {
+            return replies::Reply::error(ErrorCode::ParseError, "Webhooks are not enabled");
+        };
+        let reply = match admin {
+            AdminCommand::Webhooks => return replies::Reply::Webhooks(webhooks.list()),
+            AdminCommand::WebhooksDead => return replies::Reply::DeadLetters(webhooks.dead_letters()),
+            AdminCommand::WebhookAdd { url, range } => {
+                let range = range.map(|range| self.resolve(session, &range));
+                match webhooks::Webhook::new(url, range.as_deref()) {
+                    Ok(webhook) => {
+                        webhooks.add(webhook);
+                        replies::Reply::Ok
+                    }
+                    Err(e) => replies::Reply::error(ErrorCode::ParseError, e),
+                }
+            }
+            AdminCommand::WebhookRemove { id } => match id.parse::<u64>() {
+                Ok(id) if webhooks.remove(id) => replies::Reply::Ok,
+                _ => replies::Reply::error(ErrorCode::ParseError, format!("No webhook {}", id)),
+            },
+            _ => return replies::Reply::error(ErrorCode::ParseError, "Invalid command format"),
+        };
+        if reply == replies::Reply::Ok {
+            self.audit.record(audit::AuditEntry::new(session.id(), session.peer().map(|p| p.to_string()), text));
+        }
+        reply
+    }
+
+    fn kick(&self, session: &Session, target: &str) -> replies::Reply {
+        let Ok(target) = target.parse::<u64>() else {
+            return replies::Reply::error(ErrorCode::ParseError, format!("Invalid client id: {}", target));
+        };
+        if !self.clients.kick(target) {
+            return replies::Reply::error(ErrorCode::UnknownClient, format!("No client with id {}", target));
+        }
+        let peer = session.peer().map(|p| p.to_string());
+        self.audit.record(audit::AuditEntry::new(session.id(), peer, format!("admin kick {}", target)));
+        replies::Reply::Ok
+    }
+
+    fn use_sheet(&self, session: &Session, sheet: &str) -> replies::Reply {
+        if !address::is_valid_sheet_name(sheet) {
+            return replies::Reply::error(ErrorCode::ParseError, format!("Invalid sheet name: {}", sheet));
+        }
+        let sheet = (sheet != address::DEFAULT_SHEET).then(|| sheet.to_string());
+        session.state.lock().unwrap().sheet = sheet;
+        replies::Reply::Ok
+    }
+
+    fn set_session_option(&self, session: &Session, key: &str, value: &str) -> replies::Reply {
+        if key == "name" {
+            return match self.clients.set_name(session.id, value) {
+                true => replies::Reply::Ok,
+                false => replies::Reply::error(ErrorCode::ParseError, "name requires a network connection"),
+            };
+        }
+        if key == "broadcast" || key == "presence" {
+            let enabled = match value {
+                "on" => true,
+                "off" => false,
+                _ => return replies::Reply::error(ErrorCode::ParseError, format!("Invalid {} setting: {}", key, value)),
+            };
+            if !session.supports(connect::Capability::Notifications) {
+                let message = format!("{} requires the notifications capability", key);
+                return replies::Reply::error(ErrorCode::ProtocolError, message);
+            }
+            let connected = match key {
+                "broadcast" => self.clients.set_changes(session.id, enabled),
+                _ => self.clients.set_watching_presence(session.id, enabled),
+            };
+            if !connected {
+                return replies::Reply::error(ErrorCode::ParseError, format!("{} requires a network connection", key));
+            }
+            return replies::Reply::Ok;
+        }
+        let mut state = session.state.lock().unwrap();
+        match key {
+            "locale" => state.locale = Some(value.to_string()),
+            "format" => state.format = Some(value.to_string()),
+            "language" => match messages::language(value) {
+                Some(language) => state.language = Some(language.to_string()),
+                None => {
+                    let message = format!("Unsupported language: {} (expected one of {})", value, messages::LANGUAGES.join(", "));
+                    return replies::Reply::error(ErrorCode::ParseError, message);
+                }
+            },
+            "precision" if value == "off" => state.precision = None,
+            "precision" => match value.parse::<u32>() {
+                Ok(digits @ 1..=numeric::MAX_PRECISION) => state.precision = Some(digits),
+                _ => {
+                    let message = format!("Invalid precision: {} (expected 1 to {} or off)", value, numeric::MAX_PRECISION);
+                    return replies::Reply::error(ErrorCode::ParseError, message);
+                }
+            },
+            _ => return replies::Reply::error(ErrorCode::ParseError, format!("Unknown session option: {}", key)),
+        }
+        replies::Reply::Ok
+    }
+
+    /// `watch <range> [delta <n> | errors]`
+    /// `alert when <cell> <comparison> <threshold> [hysteresis <n>]`, from the cell on.
+    fn add_alert(&self, session: &Session, args: &[&str]) -> replies::Reply {
+        let (cell, comparison, threshold, hysteresis) = match args {
+            [cell, comparison, threshold] => (cell, comparison, threshold, "0"),
+            [cell, comparison, threshold, "hysteresis", hysteresis] => (cell, comparison, threshold, *hysteresis),
+            _ => return replies::Reply::error(ErrorCode::ParseError, "Invalid alert rule"),
+        };
+        let cell = match cell_key(&self.resolve(session, cell)) {
+            Ok(cell) => cell,
+            Err(e) => return replies::Reply::Error(e),
+        };
+        let comparison = match comparison.parse::<alerts::Comparison>() {
+            Ok(comparison) => comparison,
+            Err(e) => return replies::Reply::error(ErrorCode::ParseError, e),
+        };
+        let (Ok(threshold), Ok(hysteresis)) = (threshold.parse::<f64>(), hysteresis.parse::<f64>()) else {
+            return replies::Reply::error(ErrorCode::ParseError, "Alert thresholds must be numbers");
+        };
+        if hysteresis < 0.0 {
+            return replies::Reply::error(ErrorCode::ParseError, format!("Invalid hysteresis: {}", hysteresis));
+        }
+        let rule = alerts::AlertRule { id: 0, cell, comparison, threshold, hysteresis };
+        let current = self.cells.read().unwrap().get(&rule.cell);
+        replies::Reply::Alerts(vec![self.alerts.add(session.id, session.writer.clone(), rule, current.as_deref())])
+    }
+
+    fn watch(&self, session: &Session, range: &str, filter: subscriptions::WatchFilter) -> replies::Reply {
+        let (sheet, range) = address::split_sheet(range);
+        let range: address::CellRange = match range.parse() {
+            Ok(range) => range,
+            Err(e) => return replies::Reply::error(ErrorCode::ParseError, format!("{}", e)),
+        };
+        if !session.supports(connect::Capability::Notifications) {
+            return replies::Reply::error(ErrorCode::ProtocolError, "watch requires the notifications capability");
+        }
+        match &session.writer {
+            Some(writer) => {
+                let sheet = sheet.map(str::to_string);
+                self.subscriptions.watch(session.id, writer.clone(), sheet, range, filter);
+                replies::Reply::Ok
+            }
+            None => replies::Reply::error(ErrorCode::ParseError, "watch requires a network connection"),
+        }
+    }
+
+    fn unwatch(&self, session: &Session, range: &str) -> replies::Reply {
+        if range == "all" {
+            self.subscriptions.remove_connection(session.id);
+            return replies::Reply::Ok;
+        }
+        let (sheet, range) = address::split_sheet(range);
+        let range: address::CellRange = match range.parse() {
+            Ok(range) => range,
+            Err(e) => return replies::Reply::error(ErrorCode::ParseError, format!("{}", e)),
+        };
+        if self.subscriptions.unwatch(session.id, sheet, &range) {
+            replies::Reply::Ok
+        } else {
+            replies::Reply::error(ErrorCode::ParseError, format!("Not watching {}", range))
+        }
+    }
+
+    /// Answers a client's hello: settles on the older of the two versions and
+    /// the capabilities both sides support.
+    pub fn negotiate(&self, session: &Session, version: u32, requested: &[connect::Capability]) -> Message {
+        if version < connect::MIN_PROTOCOL_VERSION {
+            let message = format!(
+                "Protocol version {} is not supported; need {} to {}",
+                version,
+                connect::MIN_PROTOCOL_VERSION,
+                connect::PROTOCOL_VERSION
+            );
+            return Message::Reply(replies::Reply::error(ErrorCode::ProtocolError, message));
+        }
+        let supported = connect::ALL_CAPABILITIES.iter().chain(connect::OPT_IN_CAPABILITIES);
+        let mut capabilities: Vec<_> = supported.copied().filter(|c| requested.contains(c)).collect();
+        capabilities.dedup();
+        *session.capabilities.lock().unwrap() = capabilities.clone();
+        let notifications = capabilities.contains(&connect::Capability::Notifications);
+        self.clients.set_notifications(session.id, notifications);
+        if !notifications {
+            self.subscriptions.remove_connection(session.id);
+        }
+        Message::Hello { version: version.min(connect::PROTOCOL_VERSION), capabilities }
+    }
+
+    /// `lock A1 for 30s`: keeps other sessions from changing the cell until
+    /// `unlock`, the lease expiring or this session ending. Locking again renews.
+    fn lock_cell(&self, session: &Session, cell: &str, duration: &str) -> replies::Reply {
+        let duration = match leases::parse_duration(duration) {
+            Some(duration) if !duration.is_zero() && duration <= leases::MAX_LEASE => duration,
+            _ => {
+                let message = format!("Invalid lease {}; give e.g. 30s, up to {}s", duration, leases::MAX_LEASE.as_secs());
+                return replies::Reply::error(ErrorCode::ParseError, message);
+            }
+        };
+        match self.leases.acquire(cell, session.id, self.clients.name(session.id), duration) {
+            Ok(()) => replies::Reply::Ok,
+            Err(holder) => replies::Reply::Error(ReplyError::locked(holder)),
+        }
+    }
+
+    /// Records `target`, a cell or range, as where the session is working.
+    /// Returns false, changing nothing, if it is neither.
+    fn focus(&self, session: &Session, target: &str) -> bool {
+        let (sheet, range) = address::split_sheet(target);
+        match range.parse::<address::CellRange>() {
+            Ok(range) => {
+                self.clients.focus(session.id, &address::qualify(sheet, &range.to_string()));
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Makes a connected session visible to the admin commands.
+    pub fn begin_session(&self, session: &Session) {
+        if let Some(writer) = &session.writer {
+            self.clients.register(session.id, session.peer, writer.clone());
+        }
+    }
+
+    /// Drops everything tied to a session once its connection closes.
+    pub fn end_session(&self, session: &Session) {
+        session.transaction.lock().unwrap().take();
+        self.leases.release_all(session.id);
+        self.subscriptions.remove_connection(session.id);
+        self.alerts.remove_connection(session.id);
+        self.clients.remove(session.id);
+        self.replicas.remove(session.id);
+    }
+}
+
+/// `NOW()`, seconds since the Unix epoch, or `RAND()`, a number in `[0, 1)`.
+/// Cells calling either are kept current by [`scheduler::Scheduler`].
+fn volatile_call(expr: &str, rand: &Rand) -> Option<CellValue> {
+    let name = expr.strip_suffix("()")?;
+    if name.eq_ignore_ascii_case("now") {
+        let now = web_time::SystemTime::now().duration_since(web_time::UNIX_EPOCH).unwrap_or_default();
+        return Some(CellValue::Number(now.as_millis() as f64 / 1000.0));
+    }
+    if name.eq_ignore_ascii_case("rand") {
+        return Some(CellValue::Number(rand.next()));
+    }
+    None
+}
+
+/// Where `RAND()` draws from: freshly keyed each time, or, once seeded, a
+/// sequence that repeats from run to run.
+#[derive(Default)]
+struct Rand(Option<Mutex<u64>>);
+
+impl Rand {
+    fn seeded(seed: u64) -> Self {
+        Rand(Some(Mutex::new(seed)))
+    }
+
+    /// A number in `[0, 1)`.
+    fn next(&self) -> f64 {
+        use std::hash::{BuildHasher, Hasher};
+
+        let bits = match &self.0 {
+            // SplitMix64.
+            Some(state) => {
+                let mut state = state.lock().unwrap();
+                *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                z ^ (z >> 31)
+            }
+            // Each RandomState is keyed afresh, which is random enough for a sheet.
+            None => std::collections::hash_map::RandomState::new().build_hasher().finish(),
+        };
+        (bits >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// `FETCH("<url>","<selector>")`, the selector optional; see [`feeds::Feeds::value`].
+fn fetch_call() -> Regex {
+    Regex::new(r#"^(?i:fetch)\("([^"]+)"(?:\s*,\s*"([^"]+)")?\)$"#).unwrap()
+}
+
+/// `CONVERT(<amount>,"<from>","<to>",<rate>)`; see [`currency::convert`].
+fn convert_call() -> Regex {
+    Regex::new(r#"^(?i:convert)\(\s*([\w!.\-]+)\s*,\s*"(\w+)"\s*,\s*"(\w+)"\s*,\s*([\w!.]+)\s*\)$"#).unwrap()
+}
+
+/// Evaluates formulas. An evaluation copies out what it reads under one short
+/// read of the cell lock and computes without holding it, so operands come
+/// from a single moment and a slow formula never stalls writers.
+struct CommandRunner {
+    values: SharedStore,
+    sheet: Option<String>,
+    feeds: Arc<feeds::Feeds>,
+    rand: Arc<Rand>,
+    aliases: Arc<alias::Aliases>,
+}
+
+impl CommandRunner {
+    fn new(values: SharedStore) -> Self {
+        CommandRunner { values, sheet: None, feeds: Arc::default(), rand: Arc::default(), aliases: Arc::default() }
+    }
+
+    fn with_sheet(mut self, sheet: Option<String>) -> Self {
+        self.sheet = sheet;
+        self
+    }
+
+    fn with_feeds(mut self, feeds: Arc<feeds::Feeds>) -> Self {
+        self.feeds = feeds;
+        self
+    }
+
+    fn with_rand(mut self, rand: Arc<Rand>) -> Self {
+        self.rand = rand;
+        self
+    }
+
+    fn with_aliases(mut self, aliases: Arc<alias::Aliases>) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    pub fn run(&self, expr: &str) -> Result<CellValue, RSheetError> {
+        Ok(self.evaluate(expr, None)?)
+    }
+
+    /// Evaluates `expr` against `values` rather than the shared cells, e.g. a
+    /// transaction's snapshot.
+    pub fn run_in(&self, values: &dyn store::CellStore, expr: &str) -> Result<CellValue, RSheetError> {
+        Ok(self.evaluate(expr, Some(values))?)
+    }
+
+    fn evaluate(&self, expr: &str, values: Option<&dyn store::CellStore>) -> Result<CellValue, ReplyError> {
+        if let Ok(num) = expr.parse::<f64>() {
+            return Ok(CellValue::Number(num));
+        }
+        if let Some(text) = command::unquote_text(expr) {
+            return Ok(CellValue::Text(text::nfc(text)));
+        }
+        if let Some(money) = currency::parse(expr) {
+            return Ok(money);
+        }
+        if let Some(seconds) = datetime::parse_duration(expr) {
+            return Ok(CellValue::Duration(seconds));
+        }
+        if let Some(ms) = datetime::parse_datetime(expr) {
+            return Ok(CellValue::DateTime(ms));
+        }
+        if let Some(caps) = convert_call().captures(expr) {
+            let operands = |values: &dyn store::CellStore| -> Result<_, ReplyError> {
+                Ok((self.eval_operand(values, &caps[1])?, self.eval_operand(values, &caps[4])?))
+            };
+            let (amount, rate) = match values {
+                Some(values) => operands(values)?,
+                None => operands(self.values.read().unwrap().as_ref())?,
+            };
+            return currency::convert(&amount, &caps[2], &caps[3], &rate);
+        }
+        if let Some(value) = volatile_call(expr, &self.rand) {
+            return Ok(value);
+        }
+        if let Some(caps) = fetch_call().captures(expr) {
+            return self.feeds.value(&caps[1], caps.get(2).map(|m| m.as_str())).map_err(|e| match e {
+                feeds::FeedError::NotAllowed(_) => ReplyError::new(ErrorCode::Unauthorized, e.to_string()),
+                feeds::FeedError::Unavailable(_) => ReplyError::new(ErrorCode::StorageError, e.to_string()),
+                feeds::FeedError::NotFound(_) => ReplyError::new(ErrorCode::ParseError, e.to_string()),
+            });
+        }
+        let aggregate = Regex::new(r"^(?i:(sum|average))\(([\w!:]+)\)$").unwrap();
+        if let Some(caps) = aggregate.captures(expr) {
+            let average = caps[1].eq_ignore_ascii_case("average");
+            return match values {
+                Some(values) => self.aggregate(values, &caps[2], average),
+                None => self.aggregate(self.values.read().unwrap().as_ref(), &caps[2], average),
+            };
+        }
+        // Operands are cells, names or numbers, which may have decimals.
+        let re = Regex::new(r"([\w!.]+(?:\(\))?)\s*([\+\-\*\/])\s*([\w!.]+(?:\(\))?)").unwrap();
+        if let Some(caps) = re.captures(expr) {
+            let operands = |values: &dyn store::CellStore| -> Result<_, ReplyError> {
+                let left = self.eval_operand(values, caps.get(1).unwrap().as_str())?;
+                Ok((left, self.eval_operand(values, caps.get(3).unwrap().as_str())?))
+            };
+            // Both operands come from one read of the cells, which is released
+            // before anything is computed.
+            let (left, right) = match values {
+                Some(values) => operands(values)?,
+                None => operands(self.values.read().unwrap().as_ref())?,
+            };
+            let operator = caps.get(2).unwrap().as_str();
+
+            match operator {
+                "+" => self.add(&left, &right),
+                "-" => self.sub(&left, &right),
+                "*" => self.mul(&left, &right),
+                "/" => self.div(&left, &right),
+                _ => Err(ReplyError::new(ErrorCode::ParseError, "Invalid operator")),
+            }
+        } else {
+    
+            Err(ReplyError::new(ErrorCode::ParseError, "Unsupported expression format: ".to_string() + expr))
+        }
+    }
+
+    /// What `expr` reads: each cell it names, aliases included, or the range of a `SUM`.
+    pub fn references(&self, expr: &str) -> Vec<graph::Reference> {
+        if fetch_call().is_match(expr) || command::unquote_text(expr).is_some() {
+            return Vec::new();
+        }
+        if let Some(caps) = Regex::new(r"^(?i:sum|average)\(([\w!:]*)").unwrap().captures(expr) {
+            return vec![graph::Reference::Range(address::qualify(self.sheet.as_deref(), &caps[1]))];
+        }
+        // Quoted text, such as a currency code, names no cell.
+        let expr = Regex::new(r#""[^"]*""#).unwrap().replace_all(expr, "");
+        let operand = Regex::new(r"[\w!]+").unwrap();
+        operand
+            .find_iter(&expr)
+            .filter(|m| !expr[m.end()..].starts_with('('))
+            .map(|m| m.as_str())
+            .filter_map(|token| match self.aliases.get(token) {
+                Some(cell) => Some(cell),
+                None => address::normalize(token).ok().map(|token| address::qualify(self.sheet.as_deref(), &token)),
+            })
+            .map(graph::Reference::Cell)
+            .collect()
+    }
+
+    /// What evaluating `expr` locks: each cell it reads, or the whole sheet of a `SUM`.
+    pub fn lock_scopes(&self, expr: &str) -> Vec<locks::LockScope> {
+        self.references(expr)
+            .into_iter()
+            .map(|reference| match reference {
+                graph::Reference::Cell(cell) => locks::LockScope::Cell(cell),
+                graph::Reference::Range(range) => locks::LockScope::sheet(address::split_sheet(&range).0),
+            })
+            .collect()
+    }
+
+    /// `SUM` or `AVERAGE` over a range or whole columns (`A:A`), skipping
+    /// anything but numbers.
+    fn aggregate(&self, values: &dyn store::CellStore, range: &str, average: bool) -> Result<CellValue, ReplyError> {
+        let (sheet, range) = address::split_sheet(range);
+        let range = address::CellRange::parse_with_columns(range)
+            .map_err(|e| ReplyError::new(ErrorCode::ParseError, format!("{}", e)))?;
+        let sheet = if sheet.is_some() { sheet } else { self.sheet.as_deref() };
+        let total = values.aggregate(sheet, range);
+        slowlog::touch(total.count as u64);
+        // A timesheet's column of durations adds up to a duration.
+        if total.count == 0 {
+            let durations: Vec<f64> = values
+                .iter_range(sheet, range)
+                .filter_map(|(_, value)| match value {
+                    CellValue::Duration(seconds) => Some(seconds),
+                    _ => None,
+                })
+                .collect();
+            if !durations.is_empty() {
+                let sum: f64 = durations.iter().sum();
+                return Ok(CellValue::Duration(if average { sum / durations.len() as f64 } else { sum }));
+            }
+        }
+        match average {
+            false => Ok(CellValue::Number(total.sum)),
+            true if total.count == 0 => Err(ReplyError::new(ErrorCode::DivByZero, format!("No numbers to average in {}", range))),
+            true => Ok(CellValue::Number(total.sum / total.count as f64)),
+        }
+    }
+
+    /// The operand's value, shared with the store rather than copied out of it.
+    fn eval_operand(&self, values: &dyn store::CellStore, operand: &str) -> Result<Arc<CellValue>, ReplyError> {
+        if let Some(value) = volatile_call(operand, &self.rand) {
+            return Ok(Arc::new(value));
+        }
+        // An alias names its cell in full, so it never takes the formula's sheet.
+        let alias = self.aliases.get(operand);
+        let (sheet, reference) = match alias.as_deref().map(address::split_sheet) {
+            Some(split) => split,
+            None => match address::split_sheet(operand) {
+                (None, reference) if !operand.contains('!') => (self.sheet.as_deref(), reference),
+                split => split,
+            },
+        };
+        let addr = reference.parse::<address::CellAddress>().ok();
+        let value = addr.and_then(|addr| {
+            slowlog::touch(1);
+            values.get_at(sheet, addr)
+        });
+        match value {
+            Some(val) => Ok(val),
+            // An empty cell counts as 0, as in other spreadsheets.
+            None if addr.is_some() => Ok(Arc::new(CellValue::Number(0.0))),
+            None => operand
+                .parse::<f64>()
+                .ok()
+                .map(CellValue::Number)
+                .or_else(|| currency::parse(operand))
+                .map(Arc::new)
+                .ok_or_else(|| ReplyError::new(ErrorCode::UnknownCell, format!("Invalid operand: {}", operand))),
+        }
+    }
+
+    fn add(&self, lhs: &CellValue, rhs: &CellValue) -> Result<CellValue, ReplyError> {
+        if let Some(result) = currency::arithmetic('+', lhs, rhs) {
+            return result;
+        }
+        if let Some(result) = datetime::arithmetic('+', lhs, rhs) {
+            return result;
+        }
+        match (lhs, rhs) {
+            (CellValue::Number(lhs), CellValue::Number(rhs)) => Ok(CellValue::Number(lhs + rhs)),
+            _ => Err(ReplyError::new(ErrorCode::TypeMismatch, "Invalid operands for addition")),
+        }
+    }
+
+    fn sub(&self, lhs: &CellValue, rhs: &CellValue) -> Result<CellValue, ReplyError> {
+        if let Some(result) = currency::arithmetic('-', lhs, rhs) {
+            return result;
+        }
+        if let Some(result) = datetime::arithmetic('-', lhs, rhs) {
+            return result;
+        }
+        match (lhs, rhs) {
+            (CellValue::Number(lhs), CellValue::Number(rhs)) => Ok(CellValue::Number(lhs - rhs)),
+            _ => Err(ReplyError::new(ErrorCode::TypeMismatch, "Invalid operands for subtraction")),
+        }
+    }
+
+    fn mul(&self, lhs: &CellValue, rhs: &CellValue) -> Result<CellValue, ReplyError> {
+        if let Some(result) = currency::arithmetic('*', lhs, rhs) {
+            return result;
+        }
+        if let Some(result) = datetime::arithmetic('*', lhs, rhs) {
+            return result;
+        }
+        match (lhs, rhs) {
+            (CellValue::Number(lhs), CellValue::Number(rhs)) => Ok(CellValue::Number(lhs * rhs)),
+            _ => Err(ReplyError::new(ErrorCode::TypeMismatch, "Invalid operands for multiplication")),
+        }
+    }
+    fn div(&self, lhs: &CellValue, rhs: &CellValue) -> Result<CellValue, ReplyError> {
+        if let Some(result) = currency::arithmetic('/', lhs, rhs) {
+            return result;
+        }
+        if let Some(result) = datetime::arithmetic('/', lhs, rhs) {
+            return result;
+        }
+        match (lhs, rhs) {
+            (CellValue::Number(_), CellValue::Number(0.0)) => {
+                Err(ReplyError::new(ErrorCode::DivByZero, "Division by zero"))
+            }
+            (CellValue::Number(lhs), CellValue::Number(rhs)) => Ok(CellValue::Number(lhs / rhs)),
+            _ => Err(ReplyError::new(ErrorCode::TypeMismatch, "Invalid operands for division")),
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+pub use server::{start_server, BindError, ServerHandle};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_rsheet() {
+        let rsheet = RSheet::new();
+
+        let reply = rsheet.handle_command("set A1 1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Ok);
+
+        let reply = rsheet.handle_command("set B1 2".to_string()).await;
+        assert_eq!(reply, replies::Reply::Ok);
+
+        let reply = rsheet.handle_command("set C1 A1+B1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Ok);
+
+        let reply = rsheet.handle_command("get A1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Value(CellValue::Number(1.0).into()));
+
+        let reply = rsheet.handle_command("get B1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Value(CellValue::Number(2.0).into()));
+
+        let reply = rsheet.handle_command("get C1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Value(CellValue::Number(3.0).into()));
+
+        let reply = rsheet.handle_command("set D1 A1*B1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Ok);
+
+        let reply = rsheet.handle_command("get D1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Value(CellValue::Number(2.0).into()));
+
+        let reply = rsheet.handle_command("set E1 D1/C1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Ok);
+
+        let reply = rsheet.handle_command("get E1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Value(CellValue::Number(2.0 / 3.0).into()));
+
+        let reply = rsheet.handle_command("set F1 C1-A1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Ok);
+
+        let reply = rsheet.handle_command("get F1".to_string()).await;
+        assert_eq!(reply, replies::Reply::Value(CellValue::Number(2.0).into()));
+
+        let reply = rsheet.handle_command("set G1 1/0".to_string()).await;
+        assert_eq!(reply, replies::Reply::error(ErrorCode::DivByZero, "Division by zero"));
+
+        let reply = rsheet.handle_command("set J1 1+2*3".to_string()).await;
+        assert_eq!(reply, replies::Reply::Ok);
+
+       
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_ping_and_idle_timeout() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string())
+            .with_idle_timeout(Duration::from_millis(200));
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut reader = connect::Reader::new(stream.try_clone().unwrap());
+        let mut writer = connect::Writer::new(stream);
+
+        writer.send(&Message::Ping).unwrap();
+        assert!(matches!(reader.read_message().unwrap(), Message::Pong));
+
+        // Stay silent past the timeout; the server should hang up on us.
+        std::thread::sleep(Duration::from_millis(400));
+        assert!(reader.read_message().is_err());
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_graceful_shutdown() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut reader = connect::Reader::new(stream.try_clone().unwrap());
+        let mut writer = connect::Writer::new(stream);
+        writer.send(&Message::Command("set A1 1".into())).unwrap();
+        assert!(matches!(reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+
+        server.shutdown();
+        server.join();
+        assert!(reader.read_message().is_err());
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_command_worker_pool() {
+        let pool = pool::WorkerPool::new(2);
+        let (running, peak) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        std::thread::scope(|scope| {
+            for _ in 0..6 {
+                let (running, peak) = (running.clone(), peak.clone());
+                scope.spawn(|| {
+                    pool.run(move || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(20));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .unwrap()
+                });
+            }
+        });
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.run(|| panic!("bad command")), None);
+        assert_eq!(pool.run(|| 7), Some(7));
+
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string()).with_command_workers(1);
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+        let connect = || {
+            let stream = TcpStream::connect(server.local_addr()).unwrap();
+            (connect::Reader::new(stream.try_clone().unwrap()), connect::Writer::new(stream))
+        };
+        let (mut busy_reader, mut busy_writer) = connect();
+        for row in 1..=100 {
+            busy_writer.send(&Message::Command(format!("set A{} {}", row, row).into())).unwrap();
+        }
+        // Both clients share the one worker.
+        let (mut reader, mut writer) = connect();
+        writer.send(&Message::Command("use Budget".into())).unwrap();
+        writer.send(&Message::Command("set B1 5".into())).unwrap();
+        writer.send(&Message::Command("get Budget!B1".into())).unwrap();
+        assert!(matches!(reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        assert!(matches!(reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        assert!(matches!(reader.read_message().unwrap(), Message::Reply(Reply::Value(value)) if *value == CellValue::Number(5.0)));
+        for _ in 1..=100 {
+            assert!(matches!(busy_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        }
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_connection_limit_rejects() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string())
+            .with_max_connections(1, connect::OverloadPolicy::Reject);
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+
+        let first = TcpStream::connect(server.local_addr()).unwrap();
+        let mut first_writer = connect::Writer::new(first.try_clone().unwrap());
+        let mut first_reader = connect::Reader::new(first);
+        first_writer.send(&Message::Ping).unwrap();
+        assert!(matches!(first_reader.read_message().unwrap(), Message::Pong));
+
+        let second = TcpStream::connect(server.local_addr()).unwrap();
+        let mut second_reader = connect::Reader::new(second);
+        assert!(matches!(
+            second_reader.read_message().unwrap(),
+            Message::Reply(Reply::Error(ReplyError { code: ErrorCode::ServerBusy, .. }))
+        ));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_connection_threads_bound_connections() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string()).with_connection_threads(1);
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+        let connect = || {
+            let stream = TcpStream::connect(server.local_addr()).unwrap();
+            (connect::Reader::new(stream.try_clone().unwrap()), connect::Writer::new(stream))
+        };
+
+        let (mut first_reader, mut first_writer) = connect();
+        first_writer.send(&Message::Ping).unwrap();
+        assert!(matches!(first_reader.read_message().unwrap(), Message::Pong));
+
+        // The only thread is taken, so the next client is turned away rather than left waiting.
+        let (mut busy_reader, _busy_writer) = connect();
+        assert!(matches!(
+            busy_reader.read_message().unwrap(),
+            Message::Reply(Reply::Error(ReplyError { code: ErrorCode::ServerBusy, .. }))
+        ));
+
+        drop((first_reader, first_writer));
+        let (mut reader, mut writer) = loop {
+            let (mut reader, mut writer) = connect();
+            writer.send(&Message::Ping).unwrap();
+            match reader.read_message() {
+                Ok(Message::Pong) => break (reader, writer),
+                _ => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        writer.send(&Message::Command("set A1 1".into())).unwrap();
+        assert!(matches!(reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_coalescing_writer_batches_frames() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_side, _) = listener.accept().unwrap();
+        let mut writer = connect::Writer::new(client).coalescing();
+        for cmd in ["set A1 1", "set A2 2", "get A1"] {
+            writer.send(&Message::Command(cmd.into())).unwrap();
+        }
+        assert!(writer.has_pending());
+        server_side.set_nonblocking(true).unwrap();
+        assert_eq!(server_side.peek(&mut [0; 1]).unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        server_side.set_nonblocking(false).unwrap();
+        writer.flush().unwrap();
+        assert!(!writer.has_pending());
+        let mut reader = connect::Reader::new(server_side);
+        for cmd in ["set A1 1", "set A2 2", "get A1"] {
+            assert!(matches!(reader.read_message().unwrap(), Message::Command(read) if read.to_string() == cmd));
+        }
+
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string()).with_coalesce_window(Duration::from_millis(5));
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+        let connect_client = || {
+            let stream = TcpStream::connect(server.local_addr()).unwrap();
+            (connect::Reader::new(stream.try_clone().unwrap()), connect::Writer::new(stream).coalescing())
+        };
+        let (mut watch_reader, mut watch_writer) = connect_client();
+        let (mut set_reader, mut set_writer) = connect_client();
+        watch_writer.send(&Message::Command("watch A1:B2".into())).unwrap();
+        watch_writer.flush().unwrap();
+        assert!(matches!(watch_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+
+        // A pipelined batch is answered in order, and the push reaches the watcher on the next window.
+        for cmd in ["set A1 3", "set B2 4", "get A1"] {
+            set_writer.send(&Message::Command(cmd.into())).unwrap();
+        }
+        set_writer.flush().unwrap();
+        assert!(matches!(set_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        assert!(matches!(set_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        assert!(matches!(set_reader.read_message().unwrap(), Message::Reply(Reply::Value(value)) if *value == CellValue::Number(3.0)));
+        assert!(matches!(watch_reader.read_message().unwrap(), Message::Notify { cell, .. } if cell == "A1"));
+        assert!(matches!(watch_reader.read_message().unwrap(), Message::Notify { cell, .. } if cell == "B2"));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_frames_reuse_buffers() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_side, _) = listener.accept().unwrap();
+        let mut writer = connect::Writer::new(client);
+        let mut reader = connect::Reader::new(server_side).with_arrow_frames();
+
+        // A frame past the retained capacity, then smaller ones through the same buffers.
+        let big = format!("set A1 {}", "9".repeat(200_000));
+        let commands = [big.clone(), "get A1".to_string(), "set B1 2".to_string()];
+        let sent = std::thread::spawn(move || {
+            for cmd in commands {
+                writer.send(&Message::Command(cmd.into())).unwrap();
+            }
+            writer.write_message(Reply::Arrow(vec![1, 2, 3])).unwrap();
+        });
+        for cmd in [big.as_str(), "get A1", "set B1 2"] {
+            assert!(matches!(reader.read_message().unwrap(), Message::Command(read) if read.to_string() == cmd));
+        }
+        assert!(matches!(reader.read_message().unwrap(), Message::Reply(Reply::Arrow(bytes)) if bytes == [1, 2, 3]));
+        sent.join().unwrap();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_oversized_frame_rejected() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_side, _) = listener.accept().unwrap();
+
+        client.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        let mut reader = connect::Reader::new(server_side).with_max_frame_size(1024);
+        let err = reader.read_message().unwrap_err();
+        assert!(matches!(
+            err,
+            RSheetError::Protocol(connect::ProtocolError::FrameTooLarge { len, max: 1024 }) if len == u32::MAX as usize
+        ));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_malformed_frame_recovers() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut reader = connect::Reader::new(stream.try_clone().unwrap());
+        let garbage = b"not json";
+        stream.write_all(&(garbage.len() as u32).to_be_bytes()).unwrap();
+        stream.write_all(garbage).unwrap();
+        assert!(matches!(
+            reader.read_message().unwrap(),
+            Message::Reply(Reply::Error(ReplyError { code: ErrorCode::ProtocolError, .. }))
+        ));
+
+        let mut writer = connect::Writer::new(stream);
+        writer.send(&Message::Ping).unwrap();
+        assert!(matches!(reader.read_message().unwrap(), Message::Pong));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_change_broadcast_reaches_subscribed_clients() {
+        let rsheet = RSheet::new().with_change_broadcast(clients::ChangeBroadcast::Subscribed);
+        let server = start_server(Arc::new(rsheet), connect::TcpManager::new("127.0.0.1:0".to_string())).unwrap();
+        let connect_client = || {
+            let stream = TcpStream::connect(server.local_addr()).unwrap();
+            (connect::Reader::new(stream.try_clone().unwrap()), connect::Writer::new(stream))
+        };
+        let command = |reader: &mut connect::Reader, writer: &mut connect::Writer, text: &str| {
+            writer.send(&Message::Command(text.into())).unwrap();
+            reader.read_message().unwrap()
+        };
+        let (mut mirror_reader, mut mirror_writer) = connect_client();
+        let (mut quiet_reader, mut quiet_writer) = connect_client();
+        let (mut set_reader, mut set_writer) = connect_client();
+
+        let reply = command(&mut quiet_reader, &mut quiet_writer, "session broadcast maybe");
+        assert!(matches!(reply, Message::Reply(Reply::Error(e)) if e.code == ErrorCode::ParseError));
+        // Watching as well as subscribing must not deliver a change twice.
+        assert!(matches!(command(&mut mirror_reader, &mut mirror_writer, "session broadcast on"), Message::Reply(Reply::Ok)));
+        assert!(matches!(command(&mut mirror_reader, &mut mirror_writer, "watch A1"), Message::Reply(Reply::Ok)));
+
+        assert!(matches!(command(&mut set_reader, &mut set_writer, "set A1 1"), Message::Reply(Reply::Ok)));
+        assert!(matches!(command(&mut set_reader, &mut set_writer, "set Data!C9 2"), Message::Reply(Reply::Ok)));
+        for (cell, value) in [("A1", 1.0), ("Data!C9", 2.0)] {
+            match mirror_reader.read_message().unwrap() {
+                Message::Notify { cell: got, value: CellValue::Number(n) } => assert_eq!((got.as_str(), n), (cell, value)),
+                other => panic!("expected a notification, got {:?}", other),
+            }
+        }
+        assert!(matches!(command(&mut mirror_reader, &mut mirror_writer, "get A1"), Message::Reply(Reply::Value(..))));
+        assert!(matches!(command(&mut quiet_reader, &mut quiet_writer, "get A1"), Message::Reply(Reply::Value(..))));
+
+        assert!(matches!(command(&mut mirror_reader, &mut mirror_writer, "session broadcast off"), Message::Reply(Reply::Ok)));
+        assert!(matches!(command(&mut set_reader, &mut set_writer, "set B2 3"), Message::Reply(Reply::Ok)));
+        assert!(matches!(command(&mut mirror_reader, &mut mirror_writer, "get B2"), Message::Reply(Reply::Value(..))));
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_replica_follows_primary() {
+        let primary = start_server(Arc::new(RSheet::new()), connect::TcpManager::new("127.0.0.1:0".to_string())).unwrap();
+        let mut writer = client::RSheetClient::connect(primary.local_addr()).unwrap();
+        writer.set_number("A1", 5.0).unwrap();
+
+        let rsheet = Arc::new(RSheet::new().as_replica());
+        let replica = start_server(rsheet.clone(), connect::TcpManager::new("127.0.0.1:0".to_string())).unwrap();
+        let follower = replication::Follower::start(rsheet.clone(), primary.local_addr().to_string());
+        let mut reader = client::RSheetClient::connect(replica.local_addr()).unwrap();
+        writer.set_formula("B1", "A1*2").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reader.get("B1").ok() != Some(CellValue::Number(10.0)) {
+            assert!(Instant::now() < deadline, "replica never caught up");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(reader.get("A1").unwrap(), CellValue::Number(5.0));
+        let reply = reader.command("set A1 1").unwrap();
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::ReadOnly));
+
+        rsheet.promote();
+        reader.set_number("A1", 1.0).unwrap();
+        assert_eq!(reader.get("A1").unwrap(), CellValue::Number(1.0));
+
+        follower.stop();
+        replica.shutdown();
+        replica.join();
+        primary.shutdown();
+        primary.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_shards_forward_to_the_owning_node() {
+        let budget = start_server(Arc::new(RSheet::new()), connect::TcpManager::new("127.0.0.1:0".to_string())).unwrap();
+        let shards = cluster::ShardMap::new().assign("Budget", budget.local_addr().to_string());
+        let rsheet = Arc::new(RSheet::new().with_shards(shards));
+        let front = start_server(rsheet.clone(), connect::TcpManager::new("127.0.0.1:0".to_string())).unwrap();
+        let mut client = client::RSheetClient::connect(front.local_addr()).unwrap();
+
+        client.set_number("A1", 1.0).unwrap();
+        assert_eq!(client.command("use Budget").unwrap(), Reply::Ok);
+        client.set_number("B2", 4.0).unwrap();
+        client.set_formula("B3", "B2*2").unwrap();
+        assert_eq!(client.get("Budget!B3").unwrap(), CellValue::Number(8.0));
+        assert!(rsheet.workbook().into_maps().0.keys().all(|cell| !cell.starts_with("Budget!")));
+        let mut direct = client::RSheetClient::connect(budget.local_addr()).unwrap();
+        assert_eq!(direct.get("Budget!B3").unwrap(), CellValue::Number(8.0));
+
+        assert_eq!(client.command("use Sheet1").unwrap(), Reply::Ok);
+        let reply = client.command("set A2 Budget!B2+1").unwrap();
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::ParseError));
+        let reply = client.command("get A1 Budget!B2").unwrap();
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::ParseError));
+
+        front.shutdown();
+        front.join();
+        budget.shutdown();
+        budget.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_alert_fires_on_transition_with_hysteresis() {
+        let server = start_server(Arc::new(RSheet::new()), connect::TcpManager::new("127.0.0.1:0".to_string())).unwrap();
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        let (mut reader, mut writer) = (connect::Reader::new(stream.try_clone().unwrap()), connect::Writer::new(stream));
+        let command = |reader: &mut connect::Reader, writer: &mut connect::Writer, text: &str| {
+            writer.send(&Message::Command(text.into())).unwrap();
+            reader.read_message().unwrap()
+        };
+        let rule = match command(&mut reader, &mut writer, "alert when A1 > 10 hysteresis 2") {
+            Message::Reply(Reply::Alerts(mut rules)) => rules.pop().unwrap(),
+            other => panic!("expected the new rule, got {:?}", other),
+        };
+        assert_eq!(rule.to_string(), "A1 > 10 hysteresis 2");
+        assert!(matches!(command(&mut reader, &mut writer, "alert when A1 ~ 10"), Message::Reply(Reply::Error(e)) if e.code == ErrorCode::ParseError));
+
+        // Only the first rise, and the rise after the value fell past 8, alert.
+        for value in [11, 9, 12, 7, 11] {
+            match command(&mut reader, &mut writer, &format!("set A1 {}", value)) {
+                Message::Alert(alert) => {
+                    assert!(value == 11, "unexpected alert at {}", value);
+                    assert_eq!((alert.rule.id, alert.value), (rule.id, 11.0));
+                    assert!(matches!(reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+                }
+                Message::Reply(Reply::Ok) => assert!(value != 11, "no alert at {}", value),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(command(&mut reader, &mut writer, &format!("alert remove {}", rule.id)).to_text(), "ok");
+        assert!(matches!(command(&mut reader, &mut writer, "alerts"), Message::Reply(Reply::Alerts(rules)) if rules.is_empty()));
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_presence_tracks_names_and_focus() {
+        let server = start_server(Arc::new(RSheet::new()), connect::TcpManager::new("127.0.0.1:0".to_string())).unwrap();
+        let connect_client = || {
+            let stream = TcpStream::connect(server.local_addr()).unwrap();
+            (connect::Reader::new(stream.try_clone().unwrap()), connect::Writer::new(stream))
+        };
+        let (mut alice_reader, mut alice_writer) = connect_client();
+        let (mut bob_reader, mut bob_writer) = connect_client();
+        bob_writer.send(&Message::Command("session presence on".into())).unwrap();
+        assert!(matches!(bob_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        let mut alice = |text: &str| {
+            alice_writer.send(&Message::Command(text.into())).unwrap();
+            alice_reader.read_message().unwrap()
+        };
+        let pushed = |reader: &mut connect::Reader| match reader.read_message().unwrap() {
+            Message::Presence(presence) => (presence.name, presence.focus, presence.connected),
+            other => panic!("expected a presence change, got {:?}", other),
+        };
+
+        assert!(matches!(alice("session name alice"), Message::Reply(Reply::Ok)));
+        assert_eq!(pushed(&mut bob_reader), ("alice".to_string(), None, true));
+        assert!(matches!(alice("select b2:C3"), Message::Reply(Reply::Ok)));
+        assert_eq!(pushed(&mut bob_reader), ("alice".to_string(), Some("B2:C3".to_string()), true));
+        assert!(matches!(alice("select nowhere"), Message::Reply(Reply::Error(e)) if e.code == ErrorCode::ParseError));
+        assert!(matches!(alice("set Budget!A1 1"), Message::Reply(Reply::Ok)));
+        assert_eq!(pushed(&mut bob_reader), ("alice".to_string(), Some("Budget!A1".to_string()), true));
+        // Touching the same cell again is not a change.
+        assert!(matches!(alice("get Budget!A1"), Message::Reply(Reply::Value(_))));
+
+        bob_writer.send(&Message::Command("presence".into())).unwrap();
+        match bob_reader.read_message().unwrap() {
+            Message::Reply(Reply::Presence(clients)) => {
+                let names: Vec<_> = clients.iter().map(|c| (c.name.as_str(), c.focus.as_deref())).collect();
+                assert_eq!(names.len(), 2);
+                assert!(names.contains(&("alice", Some("Budget!A1"))));
+                assert!(clients.iter().any(|c| c.name.starts_with("client-") && c.focus.is_none()));
+            }
+            other => panic!("expected the presence list, got {:?}", other),
+        }
+
+        drop((alice_reader, alice_writer));
+        assert_eq!(pushed(&mut bob_reader), ("alice".to_string(), Some("Budget!A1".to_string()), false));
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_lease_blocks_other_writers() {
+        let server = start_server(Arc::new(RSheet::new()), connect::TcpManager::new("127.0.0.1:0".to_string())).unwrap();
+        let connect_client = || {
+            let stream = TcpStream::connect(server.local_addr()).unwrap();
+            let (mut reader, mut writer) = (connect::Reader::new(stream.try_clone().unwrap()), connect::Writer::new(stream));
+            move |text: &str| {
+                writer.send(&Message::Command(text.into())).unwrap();
+                match reader.read_message().unwrap() {
+                    Message::Reply(reply) => reply,
+                    other => panic!("expected a reply, got {:?}", other),
+                }
+            }
+        };
+        let mut alice = connect_client();
+        let mut bob = connect_client();
+        let locked = |reply: Reply| match reply {
+            Reply::Error(ReplyError { code: ErrorCode::Locked, lease: Some(holder), .. }) => holder,
+            other => panic!("expected a lease error, got {:?}", other),
+        };
+
+        assert_eq!(alice("session name alice"), Reply::Ok);
+        assert_eq!(alice("lock a1 for 30s"), Reply::Ok);
+        let holder = locked(bob("set A1 5"));
+        assert_eq!((holder.cell.as_str(), holder.name.as_deref()), ("A1", Some("alice")));
+        assert!(holder.remaining_ms > 0 && holder.remaining_ms <= 30_000);
+        locked(bob("lock A1 for 1s"));
+        assert_eq!(alice("set A1 1"), Reply::Ok);
+        locked(bob("delete A1"));
+        for cmd in ["begin", "set A1 2"] {
+            assert_eq!(bob(cmd), Reply::Ok);
+        }
+        locked(bob("commit"));
+        assert!(matches!(bob("unlock A1"), Reply::Error(e) if e.code == ErrorCode::ParseError));
+        assert!(matches!(alice("lock A1 for 2h"), Reply::Error(e) if e.code == ErrorCode::ParseError));
+        assert_eq!(alice("unlock A1"), Reply::Ok);
+        assert_eq!(bob("set A1 5"), Reply::Ok);
+
+        assert_eq!(bob("lock B1 for 20ms"), Reply::Ok);
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(alice("set B1 1"), Reply::Ok);
+
+        // Leases end with the connection that held them.
+        assert_eq!(bob("lock C1 for 30s"), Reply::Ok);
+        drop(bob);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while alice("set C1 1") != Reply::Ok {
+            assert!(Instant::now() < deadline, "lease outlived its connection");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_watch_pushes_notifications() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+        let connect_client = || {
+            let stream = TcpStream::connect(server.local_addr()).unwrap();
+            (connect::Reader::new(stream.try_clone().unwrap()), connect::Writer::new(stream))
+        };
+        let (mut watch_reader, mut watch_writer) = connect_client();
+        let (mut set_reader, mut set_writer) = connect_client();
+
+        watch_writer.send(&Message::Command("watch A1:B2".into())).unwrap();
+        assert!(matches!(watch_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+
+        set_writer.send(&Message::Command("set C3 1".into())).unwrap();
+        assert!(matches!(set_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        set_writer.send(&Message::Command("set B2 7".into())).unwrap();
+        assert!(matches!(set_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+
+        match watch_reader.read_message().unwrap() {
+            Message::Notify { cell, value } => {
+                assert_eq!(cell, "B2");
+                assert_eq!(value, CellValue::Number(7.0));
+            }
+            other => panic!("expected a notification, got {:?}", other),
+        }
+
+        // Narrow the watch: a change of 0.5 is below the delta and must not be pushed.
+        watch_writer.send(&Message::Command("watch A1:B2 delta 1".into())).unwrap();
+        assert!(matches!(watch_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        watch_writer.send(&Message::Command("watches".into())).unwrap();
+        match watch_reader.read_message().unwrap() {
+            Message::Reply(Reply::Watches(watches)) => {
+                assert_eq!(watches.len(), 1);
+                assert_eq!(watches[0].filter, subscriptions::WatchFilter::Delta(1.0));
+            }
+            other => panic!("expected the watch list, got {:?}", other),
+        }
+        set_writer.send(&Message::Command("set B2 7.5".into())).unwrap();
+        assert!(matches!(set_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        set_writer.send(&Message::Command("set B2 9".into())).unwrap();
+        assert!(matches!(set_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        assert!(matches!(
+            watch_reader.read_message().unwrap(),
+            Message::Notify { value: CellValue::Number(9.0), .. }
+        ));
+
+        // An errors watch skips a number but hears of a write that failed to evaluate.
+        watch_writer.send(&Message::Command("watch A1:B2 errors".into())).unwrap();
+        assert!(matches!(watch_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        set_writer.send(&Message::Command("set A1 10".into())).unwrap();
+        assert!(matches!(set_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        set_writer.send(&Message::Command("set B2 A1/0".into())).unwrap();
+        assert!(matches!(set_reader.read_message().unwrap(), Message::Reply(Reply::Error(_))));
+        match watch_reader.read_message().unwrap() {
+            Message::Notify { cell, value } => {
+                assert_eq!(cell, "B2");
+                assert!(matches!(value, CellValue::Error(_)));
+            }
+            other => panic!("expected an error notification, got {:?}", other),
+        }
+
+        watch_writer.send(&Message::Command("unwatch A1:B2".into())).unwrap();
+        assert!(matches!(watch_reader.read_message().unwrap(), Message::Reply(Reply::Ok)));
+        watch_writer.send(&Message::Command("unwatch A1:B2".into())).unwrap();
+        assert!(matches!(watch_reader.read_message().unwrap(), Message::Reply(Reply::Error(_))));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_blocking_client() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+        let mut client = client::RSheetClient::connect(server.local_addr()).unwrap();
+
+        client.set_number("A1", 4.0).unwrap();
+        client.set_formula("B1", "A1*2").unwrap();
+        assert_eq!(client.get("B1").unwrap(), CellValue::Number(8.0));
+        assert_eq!(
+            client.get_range("A1:B1").unwrap(),
+            vec![vec![CellValue::Number(4.0), CellValue::Number(8.0)]]
+        );
+        match client.set_formula("C1", "A1/0") {
+            Err(client::ClientError::Server(e)) => assert_eq!(e.code, ErrorCode::DivByZero),
+            other => panic!("expected a server error, got {:?}", other),
+        }
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_crdt_sync_merges_offline_edits() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new().with_crdt_sync()), manager).unwrap();
+        let mut client = client::RSheetClient::connect(server.local_addr()).unwrap();
+        let expr = |text: &str| Some(crdt::Content::Expr(text.to_string()));
+        let pause = || std::thread::sleep(Duration::from_millis(2));
+
+        // Carol's edit is the oldest, so it loses whenever it arrives.
+        let mut carol = crdt::Replica::new("carol");
+        carol.set("A1", "1");
+        pause();
+        client.set_number("A1", 2.0).unwrap();
+        pause();
+        let mut alice = crdt::Replica::new("alice");
+        alice.set("A1", "5");
+        alice.set("B1", "A1*2");
+        pause();
+        let mut bob = crdt::Replica::new("bob");
+        bob.set("A1", "7");
+
+        assert!(client.sync(&mut alice).unwrap().is_empty());
+        assert_eq!(client.get("B1").unwrap(), CellValue::Number(10.0));
+        let changed = client.sync(&mut bob).unwrap();
+        assert_eq!(changed.iter().map(|op| op.cell.as_str()).collect::<Vec<_>>(), ["B1"]);
+        assert_eq!(client.get("A1").unwrap(), CellValue::Number(7.0));
+
+        client.sync(&mut carol).unwrap();
+        client.sync(&mut alice).unwrap();
+        for replica in [&carol, &alice, &bob] {
+            assert_eq!(replica.get("A1").cloned(), expr("7"));
+            assert_eq!(replica.get("B1").cloned(), expr("A1*2"));
+            assert!(replica.pending().is_empty());
+        }
+        assert_eq!(client.get("A1").unwrap(), CellValue::Number(7.0));
+
+        // Ordinary commands reach replicas as the server's writes.
+        client.set_number("C1", 3.0).unwrap();
+        assert_eq!(client.sync(&mut bob).unwrap().len(), 1);
+        assert_eq!(bob.get("C1").cloned(), expr("3"));
+        match client.sync(&mut crdt::Replica::new(crdt::SERVER_REPLICA)) {
+            Err(client::ClientError::Server(e)) => assert_eq!(e.code, ErrorCode::ParseError),
+            other => panic!("expected the server's replica id to be refused, got {:?}", other),
+        }
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_large_range_is_streamed() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+        let mut client = client::RSheetClient::connect(server.local_addr()).unwrap();
+        client.set_number("A1", 1.0).unwrap();
+        client.set_number("B5000", 2.0).unwrap();
+
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut reader = connect::Reader::new(stream.try_clone().unwrap());
+        let mut writer = connect::Writer::new(stream);
+        writer.send(&Message::Command("get A1:B5000".into())).unwrap();
+        let mut chunk_rows = Vec::new();
+        let end = loop {
+            match reader.read_message().unwrap() {
+                Message::Chunk(rows) => chunk_rows.push(rows.len()),
+                Message::Reply(reply) => break reply,
+                other => panic!("unexpected message {:?}", other),
+            }
+        };
+        assert_eq!(chunk_rows, vec![2048, 2048, 904]);
+        assert_eq!(end, Reply::Streamed { range: "A1:B5000".parse().unwrap() });
+
+        // The client reassembles the chunks, for ranges and dumps alike.
+        let values = client.get_range("A1:B5000").unwrap();
+        assert_eq!(values.len(), 5000);
+        assert_eq!(values[0][0], CellValue::Number(1.0));
+        assert_eq!(values[4999][1], CellValue::Number(2.0));
+        assert!(matches!(client.command("dump").unwrap(), Reply::Range { values, .. } if values.len() == 5000));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_protocol_negotiation() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+
+        // Capabilities the client did not ask for are switched off.
+        let mut client = client::RSheetClient::connect(server.local_addr()).unwrap();
+        let (version, capabilities) = client.hello(&[connect::Capability::Streaming]).unwrap();
+        assert_eq!(version, connect::PROTOCOL_VERSION);
+        assert_eq!(capabilities, vec![connect::Capability::Streaming]);
+        assert!(matches!(client.command("watch A1").unwrap(), Reply::Error(e) if e.code == ErrorCode::ProtocolError));
+
+        // Without streaming a large range comes back as a single reply.
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut reader = connect::Reader::new(stream.try_clone().unwrap());
+        let mut writer = connect::Writer::new(stream);
+        writer.send(&Message::Hello { version: 7, capabilities: vec![] }).unwrap();
+        let answer = reader.read_message().unwrap();
+        assert!(matches!(answer, Message::Hello { version: 2, capabilities } if capabilities.is_empty()));
+        writer.send(&Message::Command("get A1:B5000".into())).unwrap();
+        assert!(matches!(reader.read_message().unwrap(), Message::Reply(Reply::Range { values, .. }) if values.len() == 5000));
+
+        writer.send(&Message::Hello { version: 0, capabilities: vec![] }).unwrap();
+        assert!(matches!(reader.read_message().unwrap(), Message::Reply(Reply::Error(e)) if e.code == ErrorCode::ProtocolError));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[tokio::test]
+    async fn test_async_client_pipelines_requests() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+        let client = client::AsyncRSheetClient::connect(server.local_addr()).await.unwrap();
+
+        let (a, b) = tokio::join!(client.set_number("A1", 1.0), client.set_number("B1", 2.0));
+        a.unwrap();
+        b.unwrap();
+        let (a, b) = tokio::join!(client.get("A1"), client.get("B1"));
+        assert_eq!(a.unwrap(), CellValue::Number(1.0));
+        assert_eq!(b.unwrap(), CellValue::Number(2.0));
+
+        drop(client);
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_client_pool_fails_over() {
+        let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead_addr = dead.local_addr().unwrap().to_string();
+        drop(dead);
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+
+        let pool = client::ClientPool::new(vec![dead_addr, server.local_addr().to_string()]);
+        pool.set_number("A1", 3.0).unwrap();
+        assert_eq!(pool.get("A1").unwrap(), CellValue::Number(3.0));
+        assert!(client::is_idempotent("set A1 3"));
+        assert!(!client::is_idempotent("set A1 A1+1"));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[tokio::test]
+    async fn test_session_sheet_and_options() {
+        let rsheet = RSheet::new();
+        let session = Session::detached();
+        let run = |cmd: &str| rsheet.handle_session_command(&session, cmd.to_string());
+
+        assert_eq!(run("set A1 1").await, Reply::Ok);
+        assert_eq!(run("use Budget").await, Reply::Ok);
+        assert_eq!(run("set A1 10").await, Reply::Ok);
+        assert_eq!(run("set A2 A1+Sheet1!A1").await, Reply::Ok);
+        assert_eq!(run("get A2").await, Reply::Value(CellValue::Number(11.0).into()));
+        assert_eq!(run("get Sheet1!A1").await, Reply::Value(CellValue::Number(1.0).into()));
+
+        assert_eq!(run("use Sheet1").await, Reply::Ok);
+        assert_eq!(run("get Budget!A2").await, Reply::Value(CellValue::Number(11.0).into()));
+
+        assert_eq!(run("session locale de-DE").await, Reply::Ok);
+        match run("session").await {
+            Reply::Session(state) => assert_eq!(state.locale.as_deref(), Some("de-DE")),
+            other => panic!("expected session state, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_bind_multiple_addresses() {
+        let manager = connect::TcpManager::bind_all(vec![
+            "127.0.0.1:0".to_string(),
+            "[::1]:0".to_string(),
+            "256.0.0.1:0".to_string(),
+        ]);
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+        assert!(!server.bind_errors().is_empty());
+
+        for addr in server.local_addrs() {
+            let mut client = client::RSheetClient::connect(addr).unwrap();
+            client.set_number("A1", 1.0).unwrap();
+        }
+
+        server.shutdown();
+        server.join();
+
+        let manager = connect::TcpManager::new("256.0.0.1:0".to_string());
+        let err = start_server(Arc::new(RSheet::new()), manager).err().unwrap();
+        assert!(matches!(err, RSheetError::Bind(_)));
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_server_config_from_toml() {
+        let config = config::ServerConfig::from_toml_str(
+            r#"
+            bind = ["127.0.0.1:0"]
+            idle_timeout_secs = 30
+            max_connections = 8
+            overload_policy = "queue"
+            persistence_path = "sheet.json"
+
+            [rate_limit]
+            per_second = 50.0
+            burst = 10
+            "#,
+        )
+        .unwrap();
+        use connect::Manager;
+        assert_eq!(config.idle_timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(config.max_connections(), Some(8));
+        assert_eq!(config.overload_policy(), connect::OverloadPolicy::Queue);
+        assert_eq!(config.max_frame_size(), connect::DEFAULT_MAX_FRAME_SIZE);
+
+        let server = start_server(Arc::new(RSheet::new()), config).unwrap();
+        server.shutdown();
+        server.join();
+
+        assert!(config::ServerConfig::from_toml_str("bind = []").is_err());
+        assert!(config::ServerConfig::from_toml_str("unknown_key = 1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("rsheet-save-{}.json", std::process::id()));
+        let rsheet = RSheet::new().with_persistence_path(&path);
+        rsheet.handle_command("set A1 4".to_string()).await;
+        rsheet.handle_command("set Budget!B2 A1*2".to_string()).await;
+        assert_eq!(rsheet.handle_command("save".to_string()).await, Reply::Ok);
+
+        let workbook = workbook::Workbook::from_json(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(workbook.version, workbook::WORKBOOK_VERSION);
+        assert_eq!(workbook.sheets, vec!["Sheet1", "Budget"]);
+        assert_eq!(workbook.cells["Budget!B2"].formula, Some(Formula { expr: "A1*2".to_string(), sheet: None }));
+        assert_eq!(workbook.cells["A1"].formula, None);
+
+        let restored = RSheet::new().with_persistence_path(&path);
+        assert_eq!(restored.handle_command("load".to_string()).await, Reply::Ok);
+        assert_eq!(restored.workbook().cells, workbook.cells);
+        assert_eq!(
+            restored.handle_command("get Budget!B2".to_string()).await,
+            Reply::Value(CellValue::Number(8.0).into())
+        );
+
+        // Naming a file is for admins; files from before workbooks were versioned still load.
+        let named = format!("load {}", path.display());
+        assert!(matches!(restored.handle_command(named).await, Reply::Error(e) if e.code == ErrorCode::Unauthorized));
+        std::fs::write(&path, r#"{"C3": {"Number": 1.5}}"#).unwrap();
+        restored.load(&path).unwrap();
+        assert_eq!(restored.handle_command("get C3".to_string()).await, Reply::Value(CellValue::Number(1.5).into()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_large_workbook_loads_by_sheet() {
+        assert_eq!(pool::parallel_map((0..100).collect(), |n: u32| n * 2), (0..100).map(|n| n * 2).collect::<Vec<_>>());
+
+        let mut values = HashMap::new();
+        let mut formulas = HashMap::new();
+        for sheet in ["Sheet1", "Budget", "Q\"1", "Totals"] {
+            for row in 1..=200 {
+                let cell = address::qualify(Some(sheet), &format!("A{}", row));
+                values.insert(cell.clone(), CellValue::Number(row as f64));
+                if row % 50 == 0 {
+                    formulas.insert(cell, Formula { expr: format!("A{}+1", row - 1), sheet: None });
+                }
+            }
+        }
+        let workbook = workbook::Workbook::new(&values, &formulas);
+        let parsed = workbook::Workbook::from_json(&serde_json::to_vec(&workbook).unwrap()).unwrap();
+        assert_eq!(parsed, workbook);
+        assert!(workbook::Workbook::from_json(br#"{"version": 1, "cells": {}}"#).is_err());
+
+        let rsheet = RSheet::new();
+        rsheet.restore(parsed, false).unwrap();
+        assert_eq!(rsheet.cells.read().unwrap().len(), 800);
+        assert_eq!(rsheet.handle_command("get Totals!A200".to_string()).await, Reply::Value(CellValue::Number(200.0).into()));
+        assert_eq!(rsheet.workbook().cells, workbook.cells);
+    }
+
+    #[tokio::test]
+    async fn test_set_if_version_detects_changes() {
+        let rsheet = RSheet::new();
+        let version = |reply: Reply| match reply {
+            Reply::Versioned { version, .. } => version,
+            other => panic!("expected a versioned value, got {:?}", other),
+        };
+        let conflict = |reply: Reply| matches!(reply, Reply::Error(e) if e.code == ErrorCode::Conflict);
+
+        // An empty cell is at version 0, so `if-version 0` only creates.
+        assert_eq!(version(rsheet.handle_command("get A1 version".to_string()).await), 0);
+        assert_eq!(rsheet.handle_command("set A1 5 if-version 0".to_string()).await, Reply::Ok);
+        assert!(conflict(rsheet.handle_command("set A1 5 if-version 0".to_string()).await));
+
+        let first = version(rsheet.handle_command("get A1 version".to_string()).await);
+        assert_eq!(rsheet.handle_command(format!("set A1 A1*2 if-version {}", first)).await, Reply::Ok);
+        assert!(conflict(rsheet.handle_command(format!("set A1 7 if-version {}", first)).await));
+        match rsheet.handle_command("get A1 version".to_string()).await {
+            Reply::Versioned { value, version } => {
+                assert_eq!(*value, CellValue::Number(10.0));
+                assert!(version > first);
+            }
+            other => panic!("expected a versioned value, got {:?}", other),
+        }
+
+        // Deleting and setting again never returns to an old version.
+        let before = version(rsheet.handle_command("get A1 version".to_string()).await);
+        rsheet.handle_command("delete A1".to_string()).await;
+        assert_eq!(version(rsheet.handle_command("get A1 version".to_string()).await), 0);
+        rsheet.handle_command("set A1 1".to_string()).await;
+        assert!(version(rsheet.handle_command("get A1 version".to_string()).await) > before);
+        assert!(matches!(
+            rsheet.handle_command("set A1 1 if-version x".to_string()).await,
+            Reply::Error(e) if e.code == ErrorCode::ParseError
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_many_reads_one_moment() {
+        let rsheet = Arc::new(RSheet::with_store(store::ShardedStore::new(4)));
+        rsheet.handle_command("set A1 0".to_string()).await;
+        rsheet.handle_command("set B7 0".to_string()).await;
+        let session = Session::detached();
+        rsheet.handle_session_command(&session, "use Budget".to_string()).await;
+        rsheet.handle_command("set Budget!C9 3".to_string()).await;
+        assert_eq!(
+            rsheet.handle_session_command(&session, "get Sheet1!A1 C9 D1".to_string()).await,
+            Reply::Values(vec![
+                CellValue::Number(0.0).into(),
+                CellValue::Number(3.0).into(),
+                CellValue::Empty.into(),
+            ])
+        );
+        // `B` could be an alias, so it is turned away as an unknown name when the command runs.
+        let reply = rsheet.handle_command("get A1 B".to_string()).await;
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::InvalidReference && e.message == "No cell or alias B"));
+
+        // A writer keeps A1 + B7 at zero by moving one unit between them in one transaction.
+        let writer = {
+            let rsheet = rsheet.clone();
+            std::thread::spawn(move || {
+                let session = Session::detached();
+                for n in 1..=200 {
+                    for cmd in ["begin".to_string(), format!("set A1 {}", n), format!("set B7 -{}", n), "commit".to_string()] {
+                        futures::executor::block_on(rsheet.handle_session_command(&session, cmd));
+                    }
+                }
+            })
+        };
+        while !writer.is_finished() {
+            let Reply::Values(values) = rsheet.handle_command("get A1 B7".to_string()).await else {
+                panic!("expected values");
+            };
+            match (&*values[0], &*values[1]) {
+                (CellValue::Number(a), CellValue::Number(b)) => assert_eq!(a + b, 0.0),
+                other => panic!("expected numbers, got {:?}", other),
+            }
+        }
+        writer.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let path = std::env::temp_dir().join(format!("rsheet-audit-{}.jsonl", std::process::id()));
+        let rsheet = RSheet::new().with_audit_log(audit::AuditLog::new(2).with_file(&path).unwrap());
+        rsheet.handle_command("set A1 4".to_string()).await;
+        rsheet.handle_command("set A1 A1*2".to_string()).await;
+        rsheet.handle_command("get A1".to_string()).await;
+        assert_eq!(rsheet.handle_command("delete A1".to_string()).await, Reply::Ok);
+        assert_eq!(rsheet.handle_command("delete A1".to_string()).await.to_text(), "error UnknownCell: Cell A1 not found");
+
+        // The buffer keeps the newest two; the file keeps everything.
+        let Reply::Audit(entries) = rsheet.handle_command("audit".to_string()).await else {
+            panic!("expected audit entries");
+        };
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].command, "set A1 A1*2");
+        assert_eq!(entries[0].old, Some(CellValue::Number(4.0)));
+        assert_eq!(entries[0].new, Some(CellValue::Number(8.0)));
+        assert_eq!(entries[1].command, "delete A1");
+        assert_eq!(entries[1].new, None);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_command_timeout() {
+        let rsheet = RSheet::new();
+        rsheet.handle_command("set A1 1".to_string()).await;
+        let session = Session::detached().with_command_timeout(Duration::ZERO);
+        let timed_out = |reply: Reply| matches!(reply, Reply::Error(e) if e.code == ErrorCode::Timeout);
+        assert!(timed_out(rsheet.handle_session_command(&session, "set A1 A1+1".to_string()).await));
+        assert!(timed_out(rsheet.handle_session_command(&session, "get A1:B2".to_string()).await));
+        // The abandoned set was never applied.
+        assert_eq!(rsheet.handle_command("get A1".to_string()).await, Reply::Value(CellValue::Number(1.0).into()));
+
+        let session = Session::detached().with_command_timeout(Duration::from_secs(60));
+        assert_eq!(rsheet.handle_session_command(&session, "set A1 A1+1".to_string()).await, Reply::Ok);
+    }
+
+    #[cfg(feature = "net")]
+    #[tokio::test]
+    async fn test_idempotency_keys() {
+        let rsheet = RSheet::new();
+        rsheet.handle_command("set A1 1".to_string()).await;
+        for _ in 0..3 {
+            assert_eq!(rsheet.handle_command("idem k1 set A1 A1+1".to_string()).await, Reply::Ok);
+        }
+        assert_eq!(rsheet.handle_command("get A1".to_string()).await, Reply::Value(CellValue::Number(2.0).into()));
+
+        // A retried failure gets the original error back, even once the cause is gone.
+        let first = rsheet.handle_command("idem k2 delete B1".to_string()).await;
+        rsheet.handle_command("set B1 5".to_string()).await;
+        assert_eq!(rsheet.handle_command("idem k2 delete B1".to_string()).await, first);
+        assert_eq!(rsheet.handle_command("get B1".to_string()).await, Reply::Value(CellValue::Number(5.0).into()));
+
+        let keyed_get = rsheet.handle_command("idem k3 get A1".to_string()).await;
+        assert!(matches!(keyed_get, Reply::Error(e) if e.code == ErrorCode::ParseError));
+        assert!(client::is_idempotent("idem k1 set A1 A1+1"));
+    }
+
+    #[tokio::test]
+    async fn test_write_ahead_log_replay() {
+        let path = std::env::temp_dir().join(format!("rsheet-wal-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let rsheet = RSheet::new().with_write_ahead_log(wal::WriteAheadLog::open(&path).unwrap());
+        rsheet.handle_command("set A1 1".to_string()).await;
+        rsheet.handle_command("set A1 A1+1".to_string()).await;
+        let session = Session::detached();
+        for command in ["use Budget", "set A1 5", "set B1 A1*10", "set B2 0", "delete B2"] {
+            rsheet.handle_session_command(&session, command.to_string()).await;
+        }
+        rsheet.handle_command("set C1 A1/0".to_string()).await;
+        rsheet.handle_command("set D1 RAND()".to_string()).await;
+        let random = rsheet.handle_command("get D1".to_string()).await;
+        drop(rsheet);
+
+        // A set logged without its value, as older logs have them, is
+        // evaluated again, and skipped and counted if that fails.
+        let mut log = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(b"{\"Set\":{\"cell\":\"E1\",\"expr\":\"1/0\",\"sheet\":null}}\n").unwrap();
+        // A crash mid-append leaves a torn line; everything before it survives.
+        log.write_all(br#"{"Set":{"cell":"Z9","#).unwrap();
+
+        let recovered = RSheet::new();
+        assert_eq!(recovered.replay(&path).unwrap(), 8);
+        assert_eq!(recovered.handle_command("get D1".to_string()).await, random);
+        assert_eq!(recovered.metrics().replay_failures(), 1);
+        assert_eq!(recovered.handle_command("get A1".to_string()).await, Reply::Value(CellValue::Number(2.0).into()));
+        assert_eq!(
+            recovered.handle_command("get Budget!B1".to_string()).await,
+            Reply::Value(CellValue::Number(50.0).into())
+        );
+        assert!(recovered.workbook().cells.keys().eq(["A1", "Budget!A1", "Budget!B1", "D1"]));
+
+        // Reopening cuts the torn line off so later appends stay readable.
+        let recovered = recovered.with_write_ahead_log(wal::WriteAheadLog::open(&path).unwrap());
+        recovered.handle_command("set A2 1".to_string()).await;
+        assert_eq!(wal::WriteAheadLog::read(&path).unwrap().len(), 9);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_ahead_log_compaction() {
+        let path = std::env::temp_dir().join(format!("rsheet-compact-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let wal = wal::WriteAheadLog::open(&path).unwrap().with_compact_after(3);
+        let rsheet = RSheet::new().with_write_ahead_log(wal).with_admin_token("secret");
+        let session = Session::detached();
+        for i in 1..=4 {
+            rsheet.handle_session_command(&session, format!("set A{} {}", i, i)).await;
+        }
+        // The fourth entry pushed the log over the limit and it was folded into one snapshot.
+        assert_eq!(wal::WriteAheadLog::read(&path).unwrap().len(), 1);
+        rsheet.handle_session_command(&session, "set B1 A4*2".to_string()).await;
+        assert_eq!(wal::WriteAheadLog::read(&path).unwrap().len(), 2);
+
+        let snapshot_now = "admin snapshot now".to_string();
+        let denied = rsheet.handle_session_command(&session, snapshot_now.clone()).await;
+        assert!(matches!(denied, Reply::Error(e) if e.code == ErrorCode::Unauthorized));
+        rsheet.handle_session_command(&session, "auth secret".to_string()).await;
+        assert_eq!(rsheet.handle_session_command(&session, snapshot_now).await, Reply::Ok);
+        let entries = wal::WriteAheadLog::read(&path).unwrap();
+        assert!(matches!(&entries[..], [wal::WalEntry::Restore(_)]));
+
+        let recovered = RSheet::new();
+        recovered.replay(&path).unwrap();
+        assert_eq!(recovered.workbook().cells, rsheet.workbook().cells);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_csv_import() {
+        let rsheet = RSheet::new().with_import_limits(ImportLimits { max_rows: 3, max_cols: 3 });
+        let range = rsheet.import_csv("name,qty\nbolts, 12\n\"nuts, hex\",,x\n".as_bytes(), "Budget!B2").unwrap();
+        assert_eq!(range.to_string(), "B2:D4");
+        assert_eq!(rsheet.handle_command("get Budget!C3".to_string()).await, Reply::Value(CellValue::Number(12.0).into()));
+        assert_eq!(
+            rsheet.handle_command("get Budget!B4".to_string()).await,
+            Reply::Value(CellValue::Text("nuts, hex".to_string()).into())
+        );
+        assert_eq!(rsheet.workbook().cells.len(), 6);
+
+        let reply = rsheet.handle_command("import csv A1\n1,2\n3,4".to_string()).await;
+        assert_eq!(reply, Reply::Imported { range: "A1:B2".parse().unwrap(), cells: 4 });
+        assert!(rsheet.import_csv("1,2,3,4\n".as_bytes(), "A1").is_err());
+        assert!(rsheet.import_csv("1\n2\n3\n4\n".as_bytes(), "Z1").is_err());
+        // A rejected import leaves the sheet untouched.
+        assert_eq!(rsheet.workbook().cells.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_csv_export() {
+        let rsheet = RSheet::new();
+        rsheet.handle_command("set A1 2".to_string()).await;
+        rsheet.handle_command("set B1 A1*3".to_string()).await;
+        rsheet.import_csv("\"a, b\"".as_bytes(), "A2").unwrap();
+
+        let mut out = Vec::new();
+        rsheet.export_csv(&mut out, "A1:C2", CsvOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "2,6,\n\"a, b\",,\n");
+        assert_eq!(
+            rsheet.handle_command("export csv A1:B1 formulas ;".to_string()).await,
+            Reply::Exported("2;=A1*3\n".to_string())
+        );
+        assert_eq!(
+            rsheet.handle_command("export csv A1:B1 values tab".to_string()).await.to_text(),
+            "2\t6"
+        );
+        assert!(matches!(
+            rsheet.handle_command("export csv A1:B1 everything".to_string()).await,
+            Reply::Error(ReplyError { code: ErrorCode::ParseError, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let rsheet = RSheet::new().with_admin_token("secret");
+        let session = Session::detached();
+        let run = |cmd: &str| rsheet.handle_session_command(&session, cmd.to_string());
+        run("set A1 2").await;
+        run("set Budget!B2 A1*3").await;
+        let path = std::env::temp_dir().join(format!("rsheet-backup-{}.json", std::process::id()));
+        let backup = format!("backup {}", path.display());
+        let restore = format!("restore {}", path.display());
+        assert!(matches!(run(&backup).await, Reply::Error(ReplyError { code: ErrorCode::Unauthorized, .. })));
+        run("auth secret").await;
+        assert_eq!(run(&backup).await, Reply::Ok);
+        let saved = rsheet.workbook().cells;
+
+        run("set A1 5").await;
+        run("delete Budget!B2").await;
+        run("set C3 1").await;
+        assert_eq!(run(&restore).await, Reply::Ok);
+        assert_eq!(rsheet.workbook().cells, saved);
+        assert_eq!(run("get Budget!B2").await, Reply::Value(CellValue::Number(6.0).into()));
+        assert!(matches!(run("get C3").await, Reply::Value(value) if *value == CellValue::Empty));
+
+        let mut archive: backup::Backup = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(archive.cells, 2);
+        archive.cells = 3;
+        std::fs::write(&path, serde_json::to_vec(&archive).unwrap()).unwrap();
+        assert!(matches!(run(&restore).await, Reply::Error(ReplyError { code: ErrorCode::StorageError, .. })));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "xlsx")]
+    #[tokio::test]
+    async fn test_xlsx_round_trip() {
+        let rsheet = RSheet::new().with_admin_token("secret");
+        let session = Session::detached();
+        let run = |cmd: &str| rsheet.handle_session_command(&session, cmd.to_string());
+        run("set A1 2").await;
+        run("set Budget!B2 A1*3").await;
+        run("use Budget").await;
+        run("set C1 B2+1").await;
+        let path = std::env::temp_dir().join(format!("rsheet-export-{}.xlsx", std::process::id()));
+        let command = format!("export xlsx {}", path.display());
+        assert!(matches!(run(&command).await, Reply::Error(ReplyError { code: ErrorCode::Unauthorized, .. })));
+        run("auth secret").await;
+        assert_eq!(run(&command).await, Reply::Ok);
+        assert!(std::fs::read(&path).unwrap().starts_with(b"PK"));
+
+        let copy = RSheet::new().with_admin_token("secret");
+        copy.import_xlsx(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let values = |rsheet: &RSheet| rsheet.workbook().cells.into_iter().map(|(k, c)| (k, c.value)).collect::<Vec<_>>();
+        assert_eq!(values(&copy), values(&rsheet));
+        let formula = |key: &str| copy.workbook().cells[key].formula.clone().unwrap();
+        assert_eq!(formula("Budget!C1"), Formula { expr: "B2+1".to_string(), sheet: Some("Budget".to_string()) });
+        assert_eq!(formula("Budget!B2").expr, "Sheet1!A1*3");
+        assert!(matches!(run("export pdf out.pdf").await, Reply::Error(ReplyError { code: ErrorCode::ParseError, .. })));
+    }
+
+    #[cfg(feature = "ods")]
+    #[tokio::test]
+    async fn test_ods_round_trip() {
+        let rsheet = RSheet::new();
+        let session = Session::detached();
+        let run = |cmd: &str| rsheet.handle_session_command(&session, cmd.to_string());
+        run("set A1 2").await;
+        run("set Budget!B2 A1*3").await;
+        run("use Budget").await;
+        run("set C1 B2+1").await;
+        rsheet.import_csv("label".as_bytes(), "Budget!A1").unwrap();
+        let path = std::env::temp_dir().join(format!("rsheet-export-{}.ods", std::process::id()));
+        rsheet.export_ods(&path).unwrap();
+
+        let copy = RSheet::new();
+        copy.import_ods(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let values = |rsheet: &RSheet| rsheet.workbook().cells.into_iter().map(|(k, c)| (k, c.value)).collect::<Vec<_>>();
+        assert_eq!(values(&copy), values(&rsheet));
+        let formula = |key: &str| copy.workbook().cells[key].formula.clone().unwrap();
+        assert_eq!(formula("Budget!C1"), Formula { expr: "B2+1".to_string(), sheet: Some("Budget".to_string()) });
+        assert_eq!(formula("Budget!B2").expr, "Sheet1!A1*3");
+    }
+
+    #[tokio::test]
+    async fn test_cell_keys_are_parsed() {
+        let rsheet = RSheet::new();
+        assert_eq!(rsheet.handle_command("set b2 7".to_string()).await, Reply::Ok);
+        assert_eq!(rsheet.handle_command("get B2".to_string()).await, Reply::Value(CellValue::Number(7.0).into()));
+        assert_eq!(rsheet.handle_command("get b02".to_string()).await, Reply::Value(CellValue::Number(7.0).into()));
+        for command in ["set total 1", "get 1A", "get foo", "delete A0", "get A1048577", "get B1 2B"] {
+            assert!(
+                matches!(rsheet.handle_command(command.to_string()).await, Reply::Error(ReplyError { code: ErrorCode::InvalidReference, .. })),
+                "{}",
+                command
+            );
+        }
+        assert_eq!(command::Command::parse("set budget!c3 1"), command::Command::Set { cell: "budget!C3".to_string(), expr: "1".to_string(), if_version: None, force: false });
+        rsheet.handle_command("set Budget!C3 1".to_string()).await;
+        rsheet.handle_command("set AA1000 2".to_string()).await;
+        let Reply::Range { values, .. } = rsheet.handle_command("get A2:C3".to_string()).await else {
+            panic!("expected a range");
+        };
+        assert_eq!(values[0][1], CellValue::Number(7.0));
+        assert_eq!(values.concat().iter().filter(|v| **v == CellValue::Empty).count(), 5);
+        let keys: Vec<String> = rsheet.workbook().cells.into_keys().collect();
+        assert_eq!(keys, ["AA1000", "B2", "Budget!C3"]);
+        assert_eq!(rsheet.handle_command("delete b2".to_string()).await, Reply::Ok);
+        assert!(rsheet.workbook().cells.keys().eq(["AA1000", "Budget!C3"]));
+    }
+
+    #[tokio::test]
+    async fn test_sum_over_columns() {
+        for rsheet in [RSheet::new(), RSheet::with_store(store::ColumnarStore::default())] {
+            let values: String = (1..=1000).map(|n| format!("{}\n", n)).collect();
+            rsheet.import_csv(values.as_bytes(), "A1").unwrap();
+            rsheet.handle_command("set A5 5000".to_string()).await;
+            rsheet.handle_command("delete A6".to_string()).await;
+            rsheet.import_csv("note".as_bytes(), "A2000").unwrap();
+            rsheet.handle_command("set Budget!A1 7".to_string()).await;
+
+            assert_eq!(rsheet.handle_command("set B1 SUM(A:A)".to_string()).await, Reply::Ok);
+            let total = (1..=1000).sum::<u32>() - 5 - 6 + 5000;
+            assert_eq!(rsheet.handle_command("get B1".to_string()).await, Reply::Value(CellValue::Number(total as f64).into()));
+            rsheet.handle_command("set B2 sum(A4:A6)".to_string()).await;
+            assert_eq!(rsheet.handle_command("get B2".to_string()).await, Reply::Value(CellValue::Number(5004.0).into()));
+            rsheet.handle_command("set B3 SUM(Budget!A:B)".to_string()).await;
+            assert_eq!(rsheet.handle_command("get B3".to_string()).await, Reply::Value(CellValue::Number(7.0).into()));
+            assert_eq!(rsheet.handle_command("get A1999:A2000".to_string()).await.to_text(), "\nnote");
+            assert_eq!(rsheet.workbook().cells.len(), 1004);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_average_over_ranges() {
+        let stores: Vec<Box<dyn store::CellStore>> = vec![
+            Box::new(store::MemoryStore::default()),
+            Box::new(store::ColumnarStore::default()),
+            Box::new(store::ShardedStore::new(4)),
+        ];
+        for cells in stores {
+            let rsheet = RSheet { cells: Arc::new(RwLock::new(cells)), ..RSheet::new() };
+            // 1..=1003 skipping A500: more than a whole number of lanes, with a gap.
+            let values: String = (1..=1003).map(|n| if n == 500 { "\n".to_string() } else { format!("{}\n", n) }).collect();
+            rsheet.import_csv(values.as_bytes(), "A1").unwrap();
+            rsheet.import_csv("note".as_bytes(), "A1004").unwrap();
+            let (sum, count) = ((1..=1003).sum::<u32>() - 500, 1002);
+
+            rsheet.handle_command("set B1 SUM(A1:A2000)".to_string()).await;
+            assert_eq!(rsheet.handle_command("get B1".to_string()).await, Reply::Value(CellValue::Number(sum as f64).into()));
+            rsheet.handle_command("set B2 average(A:A)".to_string()).await;
+            let average = sum as f64 / count as f64;
+            assert_eq!(rsheet.handle_command("get B2".to_string()).await, Reply::Value(CellValue::Number(average).into()));
+            let empty = rsheet.handle_command("set B3 AVERAGE(C1:C9)".to_string()).await;
+            assert!(matches!(empty, Reply::Error(ReplyError { code: ErrorCode::DivByZero, .. })));
+        }
+    }
+
+    #[test]
+    fn test_reads_share_the_cell_lock() {
+        let rsheet = Arc::new(RSheet::new());
+        futures::executor::block_on(rsheet.handle_command("set A1 7".to_string()));
+        let held = rsheet.cells.read().unwrap();
+
+        let reader = rsheet.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(futures::executor::block_on(reader.handle_command("get A1".to_string())));
+        });
+        let reply = rx.recv_timeout(Duration::from_secs(5)).expect("get blocked behind another reader");
+        assert_eq!(reply, Reply::Value(CellValue::Number(7.0).into()));
+
+        let writer = rsheet.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(futures::executor::block_on(writer.handle_command("set A1 8".to_string())));
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err(), "set ran while a reader held the lock");
+        drop(held);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), Reply::Ok);
+    }
+
+    #[test]
+    fn test_sharded_store_concurrent_writes() {
+        let path = std::env::temp_dir().join(format!("rsheet-sharded-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let wal = wal::WriteAheadLog::open(&path).unwrap().with_compact_after(30);
+        let rsheet = Arc::new(RSheet::with_store(store::ShardedStore::new(8)).with_write_ahead_log(wal));
+        let run = |rsheet: &RSheet, cmd: String| futures::executor::block_on(rsheet.handle_command(cmd));
+        run(&rsheet, "set C1 0".to_string());
+
+        // Shard writes need only the shared lock.
+        let held = rsheet.cells.read().unwrap();
+        let writer = rsheet.clone();
+        std::thread::spawn(move || run(&writer, "set D1 1".to_string())).join().unwrap();
+        drop(held);
+
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let rsheet = rsheet.clone();
+                std::thread::spawn(move || {
+                    for i in 1..=10 {
+                        let row = t * 10 + i;
+                        assert_eq!(run(&rsheet, format!("set A{} {}", row, i)), Reply::Ok);
+                        assert_eq!(run(&rsheet, format!("set B{} A{}*2", row, row)), Reply::Ok);
+                        assert_eq!(run(&rsheet, "set C1 C1+1".to_string()), Reply::Ok);
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+
+        // Each increment held C1's lock from read to write, so none was lost.
+        assert_eq!(run(&rsheet, "get C1".to_string()), Reply::Value(CellValue::Number(40.0).into()));
+        assert_eq!(run(&rsheet, "get B40".to_string()), Reply::Value(CellValue::Number(20.0).into()));
+        let recovered = RSheet::new();
+        recovered.replay(&path).unwrap();
+        assert_eq!(recovered.workbook().cells, rsheet.workbook().cells);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sheet_locks_are_independent() {
+        let rsheet = Arc::new(RSheet::new());
+        let run = |rsheet: &RSheet, cmd: &str| futures::executor::block_on(rsheet.handle_command(cmd.to_string()));
+        run(&rsheet, "set A1 1");
+
+        // As if a long SUM were running on Budget.
+        let held = rsheet.cell_locks.lock(vec![locks::LockScope::sheet(Some("Budget"))]);
+        let other = rsheet.clone();
+        std::thread::spawn(move || assert_eq!(run(&other, "set A2 A1+1"), Reply::Ok)).join().unwrap();
+
+        let (done, finished) = std::sync::mpsc::channel();
+        let writer = rsheet.clone();
+        std::thread::spawn(move || done.send(run(&writer, "set Budget!A2 2")).unwrap());
+        assert!(finished.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(held);
+        assert_eq!(finished.recv().unwrap(), Reply::Ok);
+        assert_eq!(run(&rsheet, "get Budget!A2"), Reply::Value(CellValue::Number(2.0).into()));
+    }
+
+    #[tokio::test]
+    async fn test_get_shares_stored_value() {
+        let rsheet = RSheet::new();
+        rsheet.handle_command("import csv A1\nrent".to_string()).await;
+        let Reply::Value(value) = rsheet.handle_command("get A1".to_string()).await else {
+            panic!("expected a value");
+        };
+        assert_eq!(*value, CellValue::Text("rent".to_string()));
+        assert!(Arc::ptr_eq(&value, &rsheet.cells.read().unwrap().get("A1").unwrap()));
+    }
+
+    #[test]
+    fn test_snapshot_ignores_later_writes() {
+        let stores: Vec<Box<dyn store::CellStore>> =
+            vec![Box::new(store::MemoryStore::default()), Box::new(store::ShardedStore::new(4)), Box::new(store::SwapStore::default())];
+        let range: address::CellRange = "A1:B2".parse().unwrap();
+        let a1 = address::CellAddress::new(0, 0);
+        for mut cells in stores {
+            cells.set("A1", CellValue::Number(1.0)).unwrap();
+            cells.set("B2", CellValue::Number(4.0)).unwrap();
+            let snapshot = cells.snapshot(None, range);
+            cells.set("A1", CellValue::Number(10.0)).unwrap();
+            cells.set("A2", CellValue::Number(3.0)).unwrap();
+            cells.delete("B2").unwrap();
+
+            assert_eq!(snapshot.get(a1).map(|value| CellValue::clone(value)), Some(CellValue::Number(1.0)));
+            assert_eq!(snapshot.iter_range(range).count(), 2);
+            assert_eq!(cells.get("A1").as_deref(), Some(&CellValue::Number(10.0)));
+            assert_eq!(cells.snapshot(None, range).iter_range(range).count(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transaction_snapshot_and_conflict() {
+        let rsheet = RSheet::new();
+        let (tx, other) = (Session::detached(), Session::detached());
+        rsheet.handle_command("set A1 1".to_string()).await;
+        rsheet.handle_command("set B1 5".to_string()).await;
+
+        assert_eq!(rsheet.handle_session_command(&tx, "begin".to_string()).await, Reply::Ok);
+        assert_eq!(rsheet.handle_session_command(&other, "set A1 2".to_string()).await, Reply::Ok);
+        // The transaction reads the cells as they were when it began, plus its own writes.
+        assert_eq!(rsheet.handle_session_command(&tx, "set C1 A1+B1".to_string()).await, Reply::Ok);
+        assert_eq!(rsheet.handle_session_command(&tx, "get C1".to_string()).await, Reply::Value(CellValue::Number(6.0).into()));
+        assert_eq!(rsheet.handle_session_command(&tx, "get A1".to_string()).await, Reply::Value(CellValue::Number(1.0).into()));
+        assert!(matches!(rsheet.handle_command("get C1".to_string()).await, Reply::Value(value) if *value == CellValue::Empty));
+        assert_eq!(rsheet.handle_session_command(&tx, "commit".to_string()).await, Reply::Ok);
+        assert_eq!(rsheet.handle_command("get C1".to_string()).await, Reply::Value(CellValue::Number(6.0).into()));
+
+        // First committer wins.
+        rsheet.handle_session_command(&tx, "begin".to_string()).await;
+        rsheet.handle_session_command(&tx, "set A1 10".to_string()).await;
+        rsheet.handle_session_command(&other, "set A1 3".to_string()).await;
+        assert!(matches!(
+            rsheet.handle_session_command(&tx, "commit".to_string()).await,
+            Reply::Error(ReplyError { code: ErrorCode::Conflict, .. })
+        ));
+        assert_eq!(rsheet.handle_command("get A1".to_string()).await, Reply::Value(CellValue::Number(3.0).into()));
+
+        rsheet.handle_session_command(&tx, "begin".to_string()).await;
+        rsheet.handle_session_command(&tx, "delete B1".to_string()).await;
+        assert_eq!(rsheet.handle_session_command(&tx, "rollback".to_string()).await, Reply::Ok);
+        assert_eq!(rsheet.handle_command("get B1".to_string()).await, Reply::Value(CellValue::Number(5.0).into()));
+    }
+
+    #[tokio::test]
+    async fn test_conflict_policies() {
+        // A1 starts at 1; another session adds 2 while the transaction adds 10.
+        async fn race(rsheet: &RSheet) -> Reply {
+            let (tx, other) = (Session::detached(), Session::detached());
+            rsheet.handle_command("set A1 1".to_string()).await;
+            rsheet.handle_session_command(&tx, "begin".to_string()).await;
+            rsheet.handle_session_command(&tx, "set A1 A1+10".to_string()).await;
+            rsheet.handle_session_command(&tx, "set B1 7".to_string()).await;
+            rsheet.handle_session_command(&other, "set A1 3".to_string()).await;
+            rsheet.handle_session_command(&tx, "commit".to_string()).await
+        }
+        let get = |rsheet: &RSheet, cell: &str| match futures::executor::block_on(rsheet.handle_command(format!("get {}", cell))) {
+            Reply::Value(value) => Arc::unwrap_or_clone(value),
+            other => panic!("expected a value, got {:?}", other),
+        };
+
+        let rsheet = RSheet::new().with_conflict_policy(conflict::ConflictPolicy::LastWriterWins);
+        assert_eq!(race(&rsheet).await, Reply::Ok);
+        assert_eq!((get(&rsheet, "A1"), get(&rsheet, "B1")), (CellValue::Number(11.0), CellValue::Number(7.0)));
+        let rsheet = RSheet::new().with_conflict_policy(conflict::ConflictPolicy::FirstWriterWins);
+        assert_eq!(race(&rsheet).await, Reply::Ok);
+        assert_eq!((get(&rsheet, "A1"), get(&rsheet, "B1")), (CellValue::Number(3.0), CellValue::Number(7.0)));
+        let rsheet = RSheet::new().with_conflict_policy(conflict::ConflictPolicy::Reject);
+        assert!(matches!(race(&rsheet).await, Reply::Error(e) if e.code == ErrorCode::Conflict));
+        assert_eq!(get(&rsheet, "B1"), CellValue::Empty);
+
+        // A hook can merge both changes, here as increments to a counter.
+        let rsheet = RSheet::new().with_merge_hook(|conflict| match (conflict.base, conflict.theirs, conflict.ours) {
+            (Some(CellValue::Number(base)), Some(CellValue::Number(theirs)), Some(CellValue::Number(ours))) => {
+                conflict::Resolution::Expr((theirs + ours - base).to_string())
+            }
+            _ => conflict::Resolution::Reject,
+        });
+        assert_eq!(race(&rsheet).await, Reply::Ok);
+        assert_eq!(get(&rsheet, "A1"), CellValue::Number(13.0));
+
+        // A synced write made without seeing the cell's latest is turned away,
+        // and the replica is handed the server's value in its place.
+        let rsheet = RSheet::new().with_crdt_sync().with_conflict_policy(conflict::ConflictPolicy::Reject);
+        rsheet.handle_command("set A1 1".to_string()).await;
+        let mut alice = crdt::Replica::new("alice");
+        alice.set("A1", "5");
+        let sync = |replica: &crdt::Replica| format!("sync\n{}", serde_json::to_string(&replica.sync_request()).unwrap());
+        let response = match rsheet.handle_command(sync(&alice)).await {
+            Reply::Synced(response) => response,
+            other => panic!("expected a sync reply, got {:?}", other),
+        };
+        assert_eq!(response.conflicts.len(), 1);
+        alice.merge(response);
+        assert_eq!(alice.get("A1"), Some(&crdt::Content::Expr("1".to_string())));
+        assert_eq!(get(&rsheet, "A1"), CellValue::Number(1.0));
+        alice.set("A1", "6");
+        assert!(matches!(rsheet.handle_command(sync(&alice)).await, Reply::Synced(r) if r.conflicts.is_empty()));
+        assert_eq!(get(&rsheet, "A1"), CellValue::Number(6.0));
+    }
+
+    #[tokio::test]
+    async fn test_bulk_load() {
+        let rsheet = RSheet::new();
+        let mut cells: Vec<(String, String)> = vec![
+            ("C1".to_string(), "A1+B1".to_string()),
+            ("Budget!B1".to_string(), "A1*2".to_string()),
+        ];
+        cells.extend((1..=1000).map(|row| (format!("A{}", row), row.to_string())));
+        cells.push(("B1".to_string(), "5".to_string()));
+        cells.push(("Budget!A1".to_string(), "21".to_string()));
+        assert_eq!(rsheet.bulk_load(cells), Ok(1004));
+        // Formulas see literals loaded after them, on their own sheet.
+        assert_eq!(rsheet.handle_command("get C1".to_string()).await, Reply::Value(CellValue::Number(6.0).into()));
+        assert_eq!(rsheet.handle_command("get Budget!B1".to_string()).await, Reply::Value(CellValue::Number(42.0).into()));
+        assert_eq!(rsheet.handle_command("get A1000".to_string()).await, Reply::Value(CellValue::Number(1000.0).into()));
+
+        let error = rsheet.bulk_load(vec![("D1".to_string(), "1".to_string()), ("D2".to_string(), "Total+1".to_string())]);
+        assert_eq!(error.unwrap_err().code, ErrorCode::UnknownCell);
+        assert!(matches!(rsheet.handle_command("get D1".to_string()).await, Reply::Value(value) if *value == CellValue::Empty));
+    }
+
+    #[tokio::test]
+    async fn test_memory_usage_and_limit() {
+        let rsheet = RSheet::new().with_history(history::RetentionPolicy::default()).with_memory_limit(4096);
+        rsheet.handle_command("set A1 1".to_string()).await;
+        rsheet.handle_command("set A2 A1+1".to_string()).await;
+        rsheet.handle_command("set Budget!A1 3".to_string()).await;
+        let Reply::Memory(sheets) = rsheet.handle_command("meminfo".to_string()).await else {
+            panic!("expected memory usage");
+        };
+        assert_eq!(sheets.iter().map(|m| m.sheet.as_str()).collect::<Vec<_>>(), ["Budget", "Sheet1"]);
+        assert!(sheets[1].cells > sheets[0].cells && sheets[1].formulas > 0 && sheets[1].history > 0);
+        assert_eq!(sheets[0].formulas, 0);
+        // The running estimate the limit is checked against agrees with a full count.
+        let counted: u64 = sheets.iter().map(|m| m.cells + m.formulas).sum();
+        assert_eq!(rsheet.memory.bytes(), counted);
+
+        let mut row = 3;
+        let error = loop {
+            match rsheet.handle_command(format!("set A{} {}", row, row)).await {
+                Reply::Ok => row += 1,
+                Reply::Error(e) => break e,
+                other => panic!("unexpected reply {:?}", other),
+            }
+        };
+        assert_eq!(error.code, ErrorCode::QuotaExceeded);
+        // Changes that do not grow the sheet still go through.
+        assert_eq!(rsheet.handle_command("set A3 7".to_string()).await, Reply::Ok);
+        assert_eq!(rsheet.handle_command("delete A3".to_string()).await, Reply::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_slowlog_records_timing_and_cells() {
+        let timings = Arc::new(Mutex::new(Vec::new()));
+        let seen = timings.clone();
+        let rsheet = RSheet::new()
+            .with_slow_log(slowlog::SlowLog::new(Duration::ZERO, 2))
+            .with_command_hook(move |timing| seen.lock().unwrap().push((timing.kind.to_string(), timing.cells)));
+        rsheet.handle_command("set A1 1".to_string()).await;
+        rsheet.handle_command("set A2 2".to_string()).await;
+        rsheet.handle_command("set A3 SUM(A1:A2)".to_string()).await;
+        rsheet.handle_command("auth secret".to_string()).await;
+        assert_eq!(timings.lock().unwrap()[..3], [("set".to_string(), 1), ("set".to_string(), 1), ("set".to_string(), 3)]);
+
+        // Capacity two: the oldest sets have gone, and the token is not kept.
+        let Reply::SlowLog(entries) = rsheet.handle_command("slowlog".to_string()).await else {
+            panic!("expected the slow log");
+        };
+        assert_eq!(entries.iter().map(|e| e.command.as_str()).collect::<Vec<_>>(), ["auth ...", "set A3 SUM(A1:A2)"]);
+        assert_eq!((entries[0].id, entries[1].id, entries[1].cells), (3, 2, 3));
+
+        assert_eq!(rsheet.handle_command("slowlog reset".to_string()).await, Reply::Ok);
+        let Reply::SlowLog(entries) = rsheet.handle_command("slowlog 5".to_string()).await else {
+            panic!("expected the slow log");
+        };
+        assert_eq!(entries.iter().map(|e| e.command.as_str()).collect::<Vec<_>>(), ["slowlog reset"]);
+    }
+
+    #[tokio::test]
+    async fn test_sheet_quotas() {
+        let quotas = quota::SheetQuotas { max_rows: Some(10), max_cols: Some(2), max_cells: Some(3), max_formula_len: Some(8) };
+        let rsheet = RSheet::new().with_quotas(quotas);
+        let violation = |reply: Reply| match reply {
+            Reply::Error(ReplyError { code: ErrorCode::QuotaExceeded, quota: Some(violation), .. }) => violation,
+            other => panic!("expected a quota error, got {:?}", other),
+        };
+        assert!(matches!(violation(rsheet.handle_command("set A11 1".to_string()).await), quota::QuotaViolation::Rows { limit: 10, .. }));
+        assert!(matches!(violation(rsheet.handle_command("set C1 1".to_string()).await), quota::QuotaViolation::Columns { limit: 2, .. }));
+
+        for cmd in ["set A1 1", "set A2 2", "set B1 A1+A2", "set A1 5", "set Budget!A1 1"] {
+            assert_eq!(rsheet.handle_command(cmd.to_string()).await, Reply::Ok, "{}", cmd);
+        }
+        assert!(matches!(
+            violation(rsheet.handle_command("set B2 1".to_string()).await),
+            quota::QuotaViolation::Cells { sheet, limit: 3 } if sheet == "Sheet1"
+        ));
+        assert!(matches!(
+            violation(rsheet.handle_command("set B2 SUM(A1:A2)".to_string()).await),
+            quota::QuotaViolation::FormulaLength { len: 10, limit: 8, .. }
+        ));
+        rsheet.handle_command("delete A2".to_string()).await;
+        assert_eq!(rsheet.handle_command("set B2 1".to_string()).await, Reply::Ok);
+
+        let import = "import csv Budget!A1\n1,2\n3,4".to_string();
+        assert!(matches!(violation(rsheet.handle_command(import).await), quota::QuotaViolation::Cells { limit: 3, .. }));
+        // Inside a transaction the count is checked when it commits.
+        let session = Session::detached();
+        for cmd in ["begin", "set Budget!A2 1", "set Budget!B2 1", "set Budget!B1 1"] {
+            assert_eq!(rsheet.handle_session_command(&session, cmd.to_string()).await, Reply::Ok);
+        }
+        let committed = rsheet.handle_session_command(&session, "commit".to_string()).await;
+        assert!(matches!(violation(committed), quota::QuotaViolation::Cells { .. }));
+    }
+
+    #[test]
+    fn test_get_at_matches_storage_keys() {
+        let stores: Vec<Box<dyn store::CellStore>> = vec![
+            Box::new(store::MemoryStore::default()),
+            Box::new(store::ColumnarStore::default()),
+            Box::new(store::ShardedStore::new(4)),
+            Box::new(store::SwapStore::default()),
+        ];
+        let b2 = address::CellAddress::new(1, 1);
+        for mut cells in stores {
+            cells.set("Sheet1!B2", CellValue::Number(1.0)).unwrap();
+            cells.set("Budget!B2", CellValue::Number(2.0)).unwrap();
+            assert_eq!(cells.get("B2").as_deref(), Some(&CellValue::Number(1.0)));
+            assert_eq!(cells.get_at(None, b2).as_deref(), Some(&CellValue::Number(1.0)));
+            assert_eq!(cells.get_at(Some("Sheet1"), b2).as_deref(), Some(&CellValue::Number(1.0)));
+            assert_eq!(cells.get_at(Some("Budget"), b2).as_deref(), Some(&CellValue::Number(2.0)));
+            assert_eq!(cells.get_at(Some("Other"), b2), None);
+            let mut keys: Vec<String> = cells.iter_all().map(|(key, _)| key).collect();
+            keys.sort();
+            assert_eq!(keys, ["B2", "Budget!B2"]);
+            assert_eq!(cells.delete("b2").unwrap(), Some(CellValue::Number(1.0)));
+            assert_eq!(cells.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_swap_store_reads_published_versions() {
+        let cells = Arc::new(store::SwapStore::default());
+        cells.as_ref().shared_writes().unwrap().set("A1", CellValue::Number(0.0)).unwrap();
+        let writer = {
+            let cells = Arc::clone(&cells);
+            std::thread::spawn(move || {
+                for n in 1..=200 {
+                    let writes = cells.shared_writes().unwrap();
+                    writes.set("A2", CellValue::Number(n as f64)).unwrap();
+                    writes.set("A1", CellValue::Number(n as f64)).unwrap();
+                }
+            })
+        };
+        // A1 is written after A2, so any version shows A2 at least as far along.
+        let range: address::CellRange = "A1:A2".parse().unwrap();
+        while !writer.is_finished() {
+            let snapshot = cells.snapshot(None, range);
+            let value = |row| match snapshot.get(address::CellAddress::new(0, row)).map(|value| CellValue::clone(value)) {
+                Some(CellValue::Number(n)) => n,
+                _ => 0.0,
+            };
+            assert!(value(1) >= value(0));
+        }
+        writer.join().unwrap();
+        assert_eq!(cells.get("A1").as_deref(), Some(&CellValue::Number(200.0)));
+        assert_eq!(cells.sum(None, range), 400.0);
+    }
+
+    #[tokio::test]
+    async fn test_custom_cell_store() {
+        /// Accepts reads and deletes but refuses to store anything over 100.
+        #[derive(Default)]
+        struct CappedStore(store::MemoryStore);
+
+        impl store::CellStore for CappedStore {
+            fn get(&self, key: &str) -> Option<Arc<CellValue>> {
+                self.0.get(key)
+            }
+            fn set(&mut self, key: &str, value: CellValue) -> std::io::Result<Option<CellValue>> {
+                match value {
+                    CellValue::Number(n) if n > 100.0 => Err(std::io::Error::other("value too large")),
+                    value => self.0.set(key, value),
+                }
+            }
+            fn delete(&mut self, key: &str) -> std::io::Result<Option<CellValue>> {
+                self.0.delete(key)
+            }
+            fn iter_range<'a>(
+                &'a self,
+                sheet: Option<&'a str>,
+                range: address::CellRange,
+            ) -> Box<dyn Iterator<Item = (address::CellAddress, CellValue)> + 'a> {
+                self.0.iter_range(sheet, range)
+            }
+            fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_> {
+                self.0.iter_all()
+            }
+        }
+
+        let rsheet = RSheet::with_store(CappedStore::default());
+        assert_eq!(rsheet.handle_command("set A1 60".to_string()).await, Reply::Ok);
+        assert_eq!(rsheet.handle_command("set B1 A1+1".to_string()).await, Reply::Ok);
+        assert!(matches!(
+            rsheet.handle_command("set C1 A1*2".to_string()).await,
+            Reply::Error(ReplyError { code: ErrorCode::StorageError, .. })
+        ));
+        assert_eq!(
+            rsheet.handle_command("get A1:C1".to_string()).await.to_text(),
+            "60\t61\t"
+        );
+        assert!(rsheet.render_metrics().contains("rsheet_cells 2"));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[tokio::test]
+    async fn test_parquet_export() {
+        use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+        use arrow::array::{Array, Float64Array, StringArray};
+        use arrow::datatypes::DataType;
+
+        let rsheet = RSheet::new().with_admin_token("secret");
+        let session = Session::detached();
+        let run = |cmd: &str| rsheet.handle_session_command(&session, cmd.to_string());
+        run("auth secret").await;
+        run("use Budget").await;
+        run("import csv A1\n1.5,rent\n,4").await;
+        run("set A3 A1*2").await;
+        let path = std::env::temp_dir().join(format!("rsheet-export-{}.parquet", std::process::id()));
+        assert_eq!(run(&format!("export parquet A1:C3 {}", path.display())).await, Reply::Ok);
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap()).unwrap().build().unwrap();
+        let batches: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 3);
+        let schema = batch.schema();
+        let types: Vec<_> = schema.fields().iter().map(|f| (f.name().as_str(), f.data_type().clone())).collect();
+        assert_eq!(types, [("A", DataType::Float64), ("B", DataType::Utf8), ("C", DataType::Float64)]);
+        let a = batch.column(0).as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(a.iter().collect::<Vec<_>>(), [Some(1.5), None, Some(3.0)]);
+        let b = batch.column(1).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(b.iter().collect::<Vec<_>>(), [Some("rent"), Some("4"), None]);
+        assert_eq!(batch.column(2).null_count(), 3);
+    }
+
+    #[cfg(feature = "net")]
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn test_arrow_range_reply() {
+        use arrow::array::{Float64Array, StringArray};
+        use arrow::ipc::reader::StreamReader;
+
+        let rsheet = Arc::new(RSheet::new());
+        futures::executor::block_on(rsheet.handle_command("import csv A1\nqty,name\n2,nuts\n3.5,".to_string()));
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(rsheet, manager).unwrap();
+
+        // Old clients never get binary frames they cannot parse.
+        let mut client = client::RSheetClient::connect(server.local_addr()).unwrap();
+        assert!(matches!(client.get_range_arrow("A2:B3"), Err(client::ClientError::Server(e)) if e.code == ErrorCode::ProtocolError));
+
+        let (_, capabilities) = client.hello(&[connect::Capability::Arrow]).unwrap();
+        assert_eq!(capabilities, vec![connect::Capability::Arrow]);
+        let bytes = client.get_range_arrow("A2:B3").unwrap();
+        let mut reader = StreamReader::try_new(bytes.as_slice(), None).unwrap();
+        assert_eq!(reader.schema().metadata()["range"], "A2:B3");
+        let batch = reader.next().unwrap().unwrap();
+        let qty = batch.column(0).as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(qty.values().to_vec(), [2.0, 3.5]);
+        let name = batch.column(1).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(name.iter().collect::<Vec<_>>(), [Some("nuts"), None]);
+        assert!(matches!(client.get("A2").unwrap(), CellValue::Number(n) if n == 2.0));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "import-url")]
+    #[test]
+    fn test_import_url() {
+        use std::io::BufRead;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                std::io::BufReader::new(&stream).read_line(&mut request).unwrap();
+                let response = match request.split_whitespace().nth(1) {
+                    Some("/data.csv") => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", 10, "1,2\nfig,4\n"),
+                    Some("/moved") => "HTTP/1.1 302 Found\r\nLocation: http://example.com/\r\nContent-Length: 0\r\n\r\n".to_string(),
+                    // No Content-Length, so only the running count catches it.
+                    _ => format!("HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{}", "9,".repeat(64)),
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        let policy = fetch::UrlPolicy { allowed_hosts: vec!["127.0.0.1".to_string()], max_bytes: 64, ..Default::default() };
+        let rsheet = RSheet::new().with_url_policy(policy);
+        let run = |cmd: String| futures::executor::block_on(rsheet.handle_command(cmd));
+
+        let reply = run(format!("import url {}/data.csv B2", base));
+        assert_eq!(reply, Reply::Imported { range: "B2:C3".parse().unwrap(), cells: 4 });
+        assert_eq!(run("get B3".to_string()), Reply::Value(CellValue::Text("fig".to_string()).into()));
+
+        let rejected = run("import url http://localhost/data.csv A1".to_string());
+        assert!(matches!(rejected, Reply::Error(ReplyError { code: ErrorCode::Unauthorized, .. })));
+        let redirected = run(format!("import url {}/moved A1", base));
+        assert!(matches!(redirected, Reply::Error(ReplyError { code: ErrorCode::StorageError, .. })));
+        let oversized = run(format!("import url {}/big.csv A10", base));
+        assert!(matches!(&oversized, Reply::Error(e) if e.message.contains("exceeds 64 bytes")), "{:?}", oversized);
+        assert!(matches!(run("get A10".to_string()), Reply::Value(value) if *value == CellValue::Empty));
+    }
+
+    #[cfg(feature = "import-url")]
+    #[test]
+    fn test_fetch_cells_cache_and_refresh() {
+        use std::io::BufRead;
+
+        // Each fetch of /price sees the price one higher than the last.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let fetches = Arc::new(AtomicU64::new(0));
+        let counted = fetches.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                std::io::BufReader::new(&stream).read_line(&mut request).unwrap();
+                let body = match request.split_whitespace().nth(1) {
+                    Some("/price") => {
+                        let n = counted.fetch_add(1, Ordering::SeqCst);
+                        format!(r#"{{"usd": {}, "rates": [{{"bid": 1.5}}]}}"#, 40 + n)
+                    }
+                    _ => "sym,px\nabc,7\n".to_string(),
+                };
+                let response = format!("HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        let policy = fetch::UrlPolicy { allowed_hosts: vec!["127.0.0.1".to_string()], ..Default::default() };
+        let rsheet = RSheet::new().with_feeds(feeds::Feeds::new(Duration::from_millis(50)).with_policy(policy));
+        let run = |cmd: String| futures::executor::block_on(rsheet.handle_command(cmd));
+
+        assert_eq!(run(format!(r#"set A1 FETCH("{}/price","$.usd")"#, base)), Reply::Ok);
+        assert_eq!(run(format!(r#"set A2 FETCH("{}/price","$.rates[0].bid")"#, base)), Reply::Ok);
+        assert_eq!(run(format!(r#"set A3 FETCH("{}/prices.csv","B2")"#, base)), Reply::Ok);
+        assert_eq!(run("get A1".to_string()), Reply::Value(CellValue::Number(40.0).into()));
+        assert_eq!(run("get A2".to_string()), Reply::Value(CellValue::Number(1.5).into()));
+        assert_eq!(run("get A3".to_string()), Reply::Value(CellValue::Number(7.0).into()));
+        // A1 and A2 shared one fetch, and recalculating inside the interval fetches nothing.
+        assert_eq!(rsheet.recalculate_volatile(), 0);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(rsheet.recalculate_volatile(), 1);
+        assert_eq!(run("get A1".to_string()), Reply::Value(CellValue::Number(41.0).into()));
+
+        let rejected = run(r#"set B1 FETCH("http://localhost/price","$.usd")"#.to_string());
+        assert!(matches!(rejected, Reply::Error(ReplyError { code: ErrorCode::Unauthorized, .. })));
+        let missing = run(format!(r#"set B1 FETCH("{}/price","$.eur")"#, base));
+        assert!(matches!(missing, Reply::Error(ReplyError { code: ErrorCode::ParseError, .. })));
+    }
+
+    #[cfg(feature = "webhooks")]
+    #[test]
+    fn test_webhooks_retry_and_dead_letter() {
+        use std::io::BufRead;
+
+        // Fails each delivery once, then accepts it and passes its body on.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}/hook", listener.local_addr().unwrap().port());
+        let (bodies, received) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(&stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let status = if i % 2 == 0 { "500 Internal Server Error" } else { "200 OK" };
+                let _ = (&stream).write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).as_bytes());
+                if i % 2 == 1 {
+                    bodies.send(String::from_utf8(body).unwrap()).unwrap();
+                }
+            }
+        });
+        let policy = webhooks::RetryPolicy { max_attempts: 2, initial_backoff: Duration::from_millis(10), ..Default::default() };
+        let hooks = webhooks::Webhooks::start(policy, None);
+        hooks.add(webhooks::Webhook::new(url, Some("A1:A10")).unwrap());
+        let rsheet = RSheet::new().with_admin_token("secret").with_webhooks(hooks);
+        let session = Session::detached();
+        let run = |cmd: &str| futures::executor::block_on(rsheet.handle_session_command(&session, cmd.to_string()));
+
+        run("set B1 1");
+        run("set A2 5");
+        let payload: webhooks::Payload = serde_json::from_str(&received.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+        assert_eq!((payload.cell.as_str(), payload.old, payload.new), ("A2", None, CellValue::Number(5.0)));
+
+        assert_eq!(run("auth secret"), Reply::Ok);
+        assert!(matches!(run("admin webhook add ftp://example.com"), Reply::Error(e) if e.code == ErrorCode::ParseError));
+        assert_eq!(run("admin webhook add http://127.0.0.1:1/down B1"), Reply::Ok);
+        assert!(matches!(run("admin webhooks"), Reply::Webhooks(hooks) if hooks.len() == 2));
+        run("set B1 2");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let letters = loop {
+            match run("admin webhooks dead") {
+                Reply::DeadLetters(letters) if !letters.is_empty() => break letters,
+                _ => assert!(Instant::now() < deadline, "delivery never dead-lettered"),
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!((letters[0].payload.cell.as_str(), letters[0].attempts), ("B1", 2));
+        assert_eq!(run("admin webhook remove 2"), Reply::Ok);
+        assert!(matches!(run("admin webhooks"), Reply::Webhooks(hooks) if hooks.len() == 1));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let path = std::env::temp_dir().join(format!("rsheet-cells-{}.db", std::process::id()));
+        let open = || sqlite::SqliteStore::open(&path).unwrap().with_batch_size(2).with_cache_capacity(1);
+        {
+            let rsheet = RSheet::with_store(open());
+            let session = Session::detached();
+            let run = |cmd: &str| rsheet.handle_session_command(&session, cmd.to_string());
+            run("set A1 4").await;
+            run("set Budget!B2 A1*2").await;
+            run("set C3 5").await;
+            run("delete C3").await;
+            assert_eq!(run("get Budget!B2").await, Reply::Value(CellValue::Number(8.0).into()));
+            assert_eq!(run("get A1:B1").await.to_text(), "4\t");
+            assert!(rsheet.render_metrics().contains("rsheet_cells 2"));
+            rsheet.handle_command("set D4 1".to_string()).await;
+            rsheet.flush().unwrap();
+        }
+
+        let reopened = RSheet::with_store(open());
+        std::fs::remove_file(&path).unwrap();
+        let keys: Vec<String> = reopened.workbook().cells.into_keys().collect();
+        assert_eq!(keys, ["A1", "Budget!B2", "D4"]);
+        assert_eq!(reopened.handle_command("get Budget!B2".to_string()).await, Reply::Value(CellValue::Number(8.0).into()));
+    }
+
+    /// Needs an MQTT broker: set `RSHEET_TEST_MQTT_BROKER` (`host:port`) to run it.
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn test_mqtt_publishes_changes() {
+        let Ok(broker) = std::env::var("RSHEET_TEST_MQTT_BROKER") else {
+            return;
+        };
+        let (host, port) = broker.rsplit_once(':').unwrap();
+        let prefix = format!("rsheet-test-{}", std::process::id());
+        let mut options = rumqttc::MqttOptions::new(format!("{}-sub", prefix), host, port.parse().unwrap());
+        options.set_keep_alive(Duration::from_secs(5));
+        let (subscriber, mut connection) = rumqttc::Client::new(options, 16);
+        subscriber.subscribe(format!("{}/#", prefix), rumqttc::QoS::AtLeastOnce).unwrap();
+        let mut events = connection.iter();
+        while !matches!(events.next(), Some(Ok(rumqttc::Event::Incoming(rumqttc::Packet::SubAck(_))))) {}
+
+        let publisher = mqtt::MqttPublisher::connect(&broker, &format!("{}-pub", prefix)).unwrap();
+        let rsheet = RSheet::new().with_mqtt(publisher.with_topic(format!("{}/{{sheet}}/{{cell}}", prefix)));
+        futures::executor::block_on(rsheet.handle_command("set Budget!A1 42".to_string()));
+        let publish = loop {
+            if let Some(Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)))) = events.next() {
+                break publish;
+            }
+        };
+        assert_eq!(publish.topic, format!("{}/Budget/A1", prefix));
+        assert_eq!(&publish.payload[..], b"42");
+    }
+
+    /// Needs a Kafka broker: set `RSHEET_TEST_KAFKA_BROKER` (`host:port`) to run it.
+    #[cfg(feature = "kafka")]
+    #[test]
+    fn test_kafka_sink() {
+        let Ok(broker) = std::env::var("RSHEET_TEST_KAFKA_BROKER") else {
+            return;
+        };
+        let topic = format!("rsheet-test-{}", std::process::id());
+        let sink = kafka::KafkaSink::connect(vec![broker.clone()], topic.clone()).unwrap();
+        let rsheet = RSheet::new().with_kafka(sink);
+        let run = |cmd: &str| futures::executor::block_on(rsheet.handle_command(cmd.to_string()));
+        run("set A1 1");
+        run("set A1 2");
+
+        let mut consumer = ::kafka::consumer::Consumer::from_hosts(vec![broker])
+            .with_topic(topic)
+            .with_fallback_offset(::kafka::consumer::FetchOffset::Earliest)
+            .create()
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            assert!(Instant::now() < deadline, "changes never reached Kafka");
+            for set in consumer.poll().unwrap().iter() {
+                for message in set.messages() {
+                    events.push(serde_json::from_slice::<kafka::ChangeEvent>(message.value).unwrap());
+                }
+            }
+        }
+        assert_eq!(events[1].cell, "A1");
+        assert_eq!((events[1].old.clone(), events[1].new.clone()), (Some(CellValue::Number(1.0)), Some(CellValue::Number(2.0))));
+        assert_eq!(events[1].actor.connection, u64::MAX);
+    }
+
+    /// Needs a Redis server: set `RSHEET_TEST_REDIS_URL` to run it.
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    async fn test_redis_store() {
+        let Ok(url) = std::env::var("RSHEET_TEST_REDIS_URL") else {
+            return;
+        };
+        let prefix = format!("rsheet-test-{}", std::process::id());
+        let open = || redis::RedisStore::open(&url).unwrap().with_prefix(&prefix);
+        let first = RSheet::with_store(open());
+        let second = RSheet::with_store(open());
+        first.handle_command("set A1 4".to_string()).await;
+        first.handle_command("set Budget!B2 A1*2".to_string()).await;
+        assert_eq!(second.handle_command("get Budget!B2".to_string()).await, Reply::Value(CellValue::Number(8.0).into()));
+        second.handle_command("delete A1".to_string()).await;
+        assert_eq!(first.handle_command("get A1:A2".to_string()).await.to_text(), "\n");
+        assert!(first.render_metrics().contains("rsheet_cells 1"));
+        first.restore(workbook::Workbook::new(&HashMap::new(), &HashMap::new()), false).unwrap();
+        assert_eq!(second.workbook().cells.len(), 0);
+    }
+
+    #[cfg(feature = "sled")]
+    #[tokio::test]
+    async fn test_sled_store() {
+        let path = std::env::temp_dir().join(format!("rsheet-sled-{}", std::process::id()));
+        {
+            let rsheet = RSheet::with_store(sled::SledStore::open(&path).unwrap());
+            rsheet.handle_command("set A1 4".to_string()).await;
+            rsheet.handle_command("set Budget!B2 A1*2".to_string()).await;
+            rsheet.handle_command("set C3 5".to_string()).await;
+            rsheet.handle_command("delete C3".to_string()).await;
+            assert_eq!(rsheet.handle_command("get A1:A2".to_string()).await.to_text(), "4\n");
+            assert!(rsheet.render_metrics().contains("rsheet_cells 2"));
+            rsheet.flush().unwrap();
+        }
+
+        let reopened = RSheet::with_store(sled::SledStore::open(&path).unwrap());
+        let keys: Vec<String> = reopened.workbook().cells.into_keys().collect();
+        assert_eq!(keys, ["A1", "Budget!B2"]);
+        assert_eq!(reopened.handle_command("get Budget!B2".to_string()).await, Reply::Value(CellValue::Number(8.0).into()));
+        drop(reopened);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_asof() {
+        let at = |ms: u64| {
+            let time = chrono::DateTime::from_timestamp_millis(ms as i64).unwrap();
+            format!("\"{}\"", time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+        };
+        let now = || std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let pause = || std::thread::sleep(Duration::from_millis(5));
+        let policy = history::RetentionPolicy { max_versions: 2, max_age: None };
+        let rsheet = RSheet::new().with_history(policy);
+        let run = |cmd: String| rsheet.handle_command(cmd);
+
+        let before = now();
+        pause();
+        run("set A1 1".to_string()).await;
+        pause();
+        let first = now();
+        pause();
+        run("set A1 2".to_string()).await;
+        run("set B1 A1*5".to_string()).await;
+        assert_eq!(run(format!("get A1 asof {}", at(first))).await, Reply::Value(CellValue::Number(1.0).into()));
+        pause();
+        let second = now();
+        pause();
+        run("delete A1".to_string()).await;
+
+        assert_eq!(run(format!("get a1 asof {}", at(second))).await, Reply::Value(CellValue::Number(2.0).into()));
+        assert_eq!(run(format!("get B1 asof {}", at(second))).await, Reply::Value(CellValue::Number(10.0).into()));
+        assert!(matches!(run(format!("get A1 asof {}", at(now()))).await, Reply::Value(value) if *value == CellValue::Empty));
+        assert!(matches!(run(format!("get B1 asof {}", at(before))).await, Reply::Value(value) if *value == CellValue::Empty));
+        // Only two versions of A1 are kept, so its first value is gone.
+        assert!(matches!(
+            run(format!("get A1 asof {}", at(first))).await,
+            Reply::Error(ReplyError { code: ErrorCode::HistoryUnavailable, .. })
+        ));
+        assert!(matches!(
+            run("get A1 asof yesterday".to_string()).await,
+            Reply::Error(ReplyError { code: ErrorCode::ParseError, .. })
+        ));
+        assert!(matches!(
+            RSheet::new().handle_command(format!("get A1 asof {}", at(first))).await,
+            Reply::Error(ReplyError { code: ErrorCode::HistoryUnavailable, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_autosave() {
+        let path = std::env::temp_dir().join(format!("rsheet-autosave-{}.json", std::process::id()));
+        let rsheet = Arc::new(RSheet::new());
+        let autosave =
+            autosave::Autosave::start(rsheet.clone(), &path, Duration::from_millis(200), Duration::from_millis(20));
+        rsheet.handle_command("set A1 1".to_string()).await;
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(workbook::Workbook::from_json(&std::fs::read(&path).unwrap()).unwrap().cells.contains_key("A1"));
+
+        // Stopping flushes whatever the debounce was still holding back.
+        rsheet.handle_command("set A2 2".to_string()).await;
+        autosave.stop();
+        assert!(workbook::Workbook::from_json(&std::fs::read(&path).unwrap()).unwrap().cells.contains_key("A2"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_recalculates_volatile_cells() {
+        use scheduler::Schedule;
+
+        assert_eq!("every 2m".parse::<Schedule>().unwrap(), Schedule::Every(Duration::from_secs(120)));
+        let cron: Schedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        // 2024-01-01T08:59:30Z was a Monday; the next quarter hour from nine is 09:00.
+        assert_eq!(cron.next_after(1_704_099_570_000), 1_704_099_600_000);
+        assert!("* * *".parse::<Schedule>().is_err() && "61 * * * *".parse::<Schedule>().is_err());
+
+        let rsheet = Arc::new(RSheet::new());
+        for command in ["set A1 NOW()", "set A2 A1-1", "set A3 RAND()*10"] {
+            assert_eq!(rsheet.handle_command(command.to_string()).await, Reply::Ok);
+        }
+        let (a1, a2) = (rsheet.handle_command("get A1".to_string()).await, rsheet.handle_command("get A2".to_string()).await);
+        std::thread::sleep(Duration::from_millis(5));
+        let scheduled = scheduler::Scheduler::start(rsheet.clone(), Schedule::Every(Duration::from_millis(10)));
+        std::thread::sleep(Duration::from_millis(100));
+        scheduled.stop();
+        assert_ne!(rsheet.handle_command("get A1".to_string()).await, a1);
+        // A2 reads A1 but calls nothing volatile, so it is left alone.
+        assert_eq!(rsheet.handle_command("get A2".to_string()).await, a2);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(rsheet.recalculate_volatile(), 2);
+        match rsheet.handle_command("get A3".to_string()).await {
+            Reply::Value(value) => assert!(matches!(*value, CellValue::Number(n) if (0.0..10.0).contains(&n))),
+            other => panic!("expected a value, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_text_line_protocol() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        stream.write_all(b"set A1 2\nset B1 A1*3\nget B1\nget Z9\nping\nbogus\n").unwrap();
+        let mut next = || lines.next().unwrap().unwrap();
+        assert_eq!(next(), "ok");
+        assert_eq!(next(), "ok");
+        assert_eq!(next(), "6");
+        assert_eq!(next(), "");
+        assert_eq!(next(), "pong");
+        assert_eq!(next(), "error ParseError: Invalid command format");
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_admin_commands() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new().with_admin_token("secret")), manager).unwrap();
+
+        let mut user = TcpStream::connect(server.local_addr()).unwrap();
+        let mut user_lines = BufReader::new(user.try_clone().unwrap()).lines();
+        user.write_all(b"admin clients\n").unwrap();
+        assert_eq!(
+            user_lines.next().unwrap().unwrap(),
+            "error Unauthorized: Admin commands need `auth <token>` first"
+        );
+
+        let mut admin = client::RSheetClient::connect(server.local_addr()).unwrap();
+        assert!(matches!(admin.command("auth wrong").unwrap(), Reply::Error(e) if e.code == ErrorCode::Unauthorized));
+        assert_eq!(admin.command("auth secret").unwrap(), Reply::Ok);
+        let Reply::Clients(clients) = admin.command("admin clients").unwrap() else {
+            panic!("expected client list");
+        };
+        assert_eq!(clients.len(), 2);
+        let user_addr = user.local_addr().unwrap().to_string();
+        let target = clients.iter().find(|c| c.peer.as_deref() == Some(user_addr.as_str())).unwrap();
+        assert_eq!(target.commands, 1);
+        assert!(!target.admin);
+
+        assert_eq!(admin.command("admin broadcast back in 5").unwrap(), Reply::Ok);
+        assert_eq!(user_lines.next().unwrap().unwrap(), "broadcast back in 5");
+
+        assert_eq!(admin.command(&format!("admin kick {}", target.id)).unwrap(), Reply::Ok);
+        assert!(user_lines.next().is_none());
+        assert!(matches!(admin.command("admin kick 999").unwrap(), Reply::Error(e) if e.code == ErrorCode::UnknownClient));
+
+        server.shutdown();
+        server.join();
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn test_metrics_endpoint() {
+        let manager = connect::TcpManager::new("127.0.0.1:0".to_string())
+            .with_metrics_address("127.0.0.1:0".to_string());
+        let server = start_server(Arc::new(RSheet::new()), manager).unwrap();
+        let mut client = client::RSheetClient::connect(server.local_addr()).unwrap();
+        client.set_number("A1", 1.0).unwrap();
+        client.set_formula("A2", "A1/0").unwrap_err();
+
+        let mut http = TcpStream::connect(server.metrics_addr().unwrap()).unwrap();
+        http.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("rsheet_commands_total{command=\"set\"} 2"));
+        assert!(response.contains("rsheet_errors_total{code=\"DivByZero\"} 1"));
+        assert!(response.contains("rsheet_active_connections 1"));
+        assert!(response.contains("rsheet_cells 1"));
+
+        drop(client);
+        server.shutdown();
+        server.join();
+    }
+
+    #[tokio::test]
+    async fn test_query_filters_groups_and_orders() {
+        let rsheet = RSheet::new();
+        let csv = "import csv A1\nann,paid,10\nbob,due,5\nann,paid,7\ncat,paid,1\n";
+        assert!(matches!(rsheet.handle_command(csv.to_string()).await, Reply::Imported { cells: 12, .. }));
+
+        let query = r#"query SELECT A, SUM(C), COUNT(*) FROM A1:D10 WHERE B = "paid" GROUP BY A ORDER BY sum(C) DESC"#;
+        let reply = rsheet.handle_command(query.to_string()).await;
+        assert_eq!(reply.to_text(), "A\tSUM(C)\tCOUNT(*)\nann\t17\t2\ncat\t1\t1");
+        let reply = rsheet.handle_command("query select A, C from A1:C4 where C >= 7 limit 1".to_string()).await;
+        let text = |s: &str| CellValue::Text(s.to_string());
+        assert_eq!(reply, Reply::Table(query::Table { columns: vec!["A".into(), "C".into()], rows: vec![vec![text("ann"), CellValue::Number(10.0)]] }));
+
+        for bad in ["query SELECT A, SUM(C) FROM A1:C4", "query SELECT E FROM A1:C4", "query SELECT A FROM A1:C4 WHERE B = paid"] {
+            let reply = rsheet.handle_command(bad.to_string()).await;
+            assert!(matches!(reply, Reply::Error(ReplyError { code: ErrorCode::ParseError, .. })), "{}: {:?}", bad, reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_matches_rows() {
+        let rsheet = RSheet::new();
+        let csv = "import csv B2\nann,paid,60\nbob,due,5\n,paid,51\ncarl,overdue,70\n";
+        assert!(matches!(rsheet.handle_command(csv.to_string()).await, Reply::Imported { cells: 11, .. }));
+
+        let reply = rsheet.handle_command("filter B1:D9 where D > 50 and C ~ \"^(paid|over)\"".to_string()).await;
+        assert_eq!(reply.to_text(), "B2:D2\tann\tpaid\t60\nB4:D4\t\tpaid\t51\nB5:D5\tcarl\toverdue\t70");
+        let reply = rsheet.handle_command(r#"filter B1:D9 where B = "bob""#.to_string()).await;
+        let text = |s: &str| CellValue::Text(s.to_string());
+        let cells = "B3:D3".parse().unwrap();
+        assert_eq!(reply, Reply::Rows(vec![query::RowMatch { cells, values: vec![text("bob"), text("due"), CellValue::Number(5.0)] }]));
+        // Text never meets a numeric comparison.
+        assert_eq!(rsheet.handle_command("filter B1:D9 where B < 100".to_string()).await, Reply::Rows(Vec::new()));
+
+        for bad in ["filter B1:D9 D > 50", "filter B1:D9 where E > 1", "filter B1:D9 where C ~ \"(\""] {
+            let reply = rsheet.handle_command(bad.to_string()).await;
+            assert!(matches!(reply, Reply::Error(ReplyError { code: ErrorCode::ParseError, .. })), "{}: {:?}", bad, reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pivot_spreads_column_keys() {
+        let rsheet = RSheet::new();
+        let csv = "import csv A1\nnorth,apples,10\nsouth,apples,5\nnorth,pears,3\nnorth,apples,2\n";
+        assert!(matches!(rsheet.handle_command(csv.to_string()).await, Reply::Imported { cells: 12, .. }));
+
+        let reply = rsheet.handle_command("pivot A1:C9 rows A columns B values C".to_string()).await;
+        assert_eq!(reply.to_text(), "A\tapples\tpears\nnorth\t12\t3\nsouth\t5\t");
+        let reply = rsheet.handle_command("pivot A1:C9 rows A, B values C agg count".to_string()).await;
+        assert_eq!(reply.to_text(), "A\tB\tCOUNT(C)\nnorth\tapples\t2\nnorth\tpears\t1\nsouth\tapples\t1");
+
+        for bad in ["pivot A1:C9 rows A values C agg median", "pivot A1:C9 rows D values C", "pivot A1:C9 values C"] {
+            let reply = rsheet.handle_command(bad.to_string()).await;
+            assert!(matches!(reply, Reply::Error(ReplyError { code: ErrorCode::ParseError, .. })), "{}: {:?}", bad, reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_groupby_aggregates_per_key() {
+        let rsheet = RSheet::new();
+        let csv = "import csv A1\npears,3,x\napples,10,y\npears,4\n7,1,z\n";
+        assert!(matches!(rsheet.handle_command(csv.to_string()).await, Reply::Imported { cells: 11, .. }));
+
+        let reply = rsheet.handle_command("groupby A1:B9 key A agg sum".to_string()).await;
+        assert_eq!(reply.to_text(), "A\tSUM(B)\n7\t1\napples\t10\npears\t7");
+        let reply = rsheet.handle_command("groupby A1:C9 key A agg avg value B".to_string()).await;
+        assert_eq!(reply.to_text(), "A\tAVG(B)\n7\t1\napples\t10\npears\t3.5");
+        // The last column is the value column unless `value` names another.
+        let reply = rsheet.handle_command("groupby A1:C9 key A agg count".to_string()).await;
+        assert_eq!(reply.to_text(), "A\tCOUNT(C)\n7\t1\napples\t1\npears\t1");
+        assert!(matches!(rsheet.handle_command("groupby A1:B9 agg sum".to_string()).await, Reply::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn test_transpose_writes_rows_as_columns() {
+        let rsheet = RSheet::new();
+        for command in ["set A1 1", "set B1 2", "set C1 A1+B1", "set A2 4", "set C2 SUM(A1:B1)"] {
+            assert_eq!(rsheet.handle_command(command.to_string()).await, Reply::Ok);
+        }
+        let reply = rsheet.handle_command("transpose A1:C2 E1".to_string()).await;
+        assert!(matches!(reply, Reply::Imported { cells: 5, range } if range.to_string() == "E1:F3"));
+        let value = |n: f64| Reply::Value(CellValue::Number(n).into());
+        assert_eq!(rsheet.handle_command("get E2".to_string()).await, value(2.0));
+        assert_eq!(rsheet.handle_command("get F1".to_string()).await, value(4.0));
+        assert_eq!(rsheet.handle_command("get E3".to_string()).await, value(3.0));
+
+        // Kept formulas follow the cells they read; others still point where they did.
+        let reply = rsheet.handle_command("transpose A1:C2 Budget!A1 formulas".to_string()).await;
+        assert!(matches!(reply, Reply::Imported { cells: 5, .. }));
+        assert_eq!(rsheet.handle_command("get Budget!A3".to_string()).await, value(3.0));
+        let transposition = reshape::Transposition::new(None, "A1:C2".parse().unwrap(), Some("Budget"), "A1".parse().unwrap()).unwrap();
+        assert_eq!(transposition.rewrite("SUM(A1:B1)+D9", None), "SUM(A1:A2)+Sheet1!D9");
+        assert_eq!(transposition.rewrite(r#"FETCH("http://x/v1")"#, None), r#"FETCH("http://x/v1")"#);
+
+        assert!(matches!(rsheet.handle_command("transpose A1:C2 E1 links".to_string()).await, Reply::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_removes_duplicate_rows() {
+        let rsheet = RSheet::new();
+        let csv = "import csv A1\npears,3\napples,10\npears,4\nplums,1\napples,2\n";
+        assert!(matches!(rsheet.handle_command(csv.to_string()).await, Reply::Imported { cells: 10, .. }));
+
+        let reply = rsheet.handle_command("dedupe A1:B6 by A keep last".to_string()).await;
+        assert_eq!(reply.to_text(), "removed 2 rows from A1:B6");
+        let reply = rsheet.handle_command("get A1:B6".to_string()).await;
+        assert_eq!(
+            reply.to_text(),
+            "pears\t4\nplums\t1\napples\t2\n\t\n\t\n\t"
+        );
+        let reply = rsheet.handle_command("dedupe A1:B6 by A, B".to_string()).await;
+        assert_eq!(reply.to_text(), "removed 0 rows from A1:B6");
+        assert!(matches!(rsheet.handle_command("dedupe A1:B6 by C".to_string()).await, Reply::Error(_)));
+    }
+
+    #[test]
+    fn test_typed_api() {
+        let rsheet = RSheet::new();
+        rsheet.set_number("A1", 2.0).unwrap();
+        rsheet.set_number("Budget!A1", 5.0).unwrap();
+        rsheet.set_formula("Budget!B1", "A1*3").unwrap();
+        rsheet.set_formula("B1", "A1+Budget!B1").unwrap();
+        assert_eq!(rsheet.get_value("Budget!B1").unwrap(), Some(CellValue::Number(15.0)));
+        assert_eq!(rsheet.get_value("B1").unwrap(), Some(CellValue::Number(17.0)));
+        assert_eq!(rsheet.get_value("C1").unwrap(), None);
+        assert!(rsheet.get_value("1A").is_err());
+        assert!(matches!(rsheet.set_formula("C1", "A1/0"), Err(ReplyError { code: ErrorCode::DivByZero, .. })));
+
+        let matrix = rsheet.range("A1:C2").unwrap();
+        assert_eq!((matrix.width(), matrix.height()), (3, 2));
+        assert_eq!(matrix.get(0, 1), Some(&CellValue::Number(17.0)));
+        assert_eq!(matrix.at("A1".parse().unwrap()), Some(&CellValue::Number(2.0)));
+        assert_eq!(matrix.get(1, 0), None);
+    }
+
+    #[test]
+    fn test_builder_applies_options() {
+        let build = || {
+            RSheet::builder()
+                .with_storage(store::ColumnarStore::default())
+                .with_limits(builder::Limits {
+                    quotas: quota::SheetQuotas { max_rows: Some(10), ..Default::default() },
+                    ..Default::default()
+                })
+                .with_rng_seed(7)
+                .build()
+                .unwrap()
+        };
+        let (a, b) = (build(), build());
+        a.set_formula("A1", "RAND()").unwrap();
+        b.set_formula("A1", "RAND()").unwrap();
+        assert_eq!(a.get_value("A1").unwrap(), b.get_value("A1").unwrap());
+        assert!(matches!(a.set_number("A11", 1.0), Err(ReplyError { code: ErrorCode::QuotaExceeded, .. })));
+        assert!(Arc::new(a).start_recalc().is_none());
+
+        let rsheet = Arc::new(RSheet::builder().with_recalc(scheduler::Recalc::Auto).build().unwrap());
+        rsheet.start_recalc().unwrap().stop();
+    }
+
+    #[test]
+    fn test_command_parsing() {
+        use command::{AdminCommand, Command};
+
+        assert_eq!(Command::parse("set A1 1"), Command::Set { cell: "A1".to_string(), expr: "1".to_string(), if_version: None, force: false });
+        assert_eq!(Command::parse("get A1:B2"), Command::GetRange { range: "A1:B2".to_string() });
+        assert_eq!(Command::parse("admin webhooks dead"), Command::Admin(AdminCommand::WebhooksDead));
+        assert!(!Command::parse("watch A1 delta 2").is_write());
+        assert!(matches!(Command::parse("set A1"), Command::Invalid { .. }));
+        assert_eq!(Command::parse("frobnicate").name(), "unknown");
+
+        // Each command prints as text that parses back to it.
+        for text in ["set A1 B1+1 if-version 3", "get A1 asof 2024-01-01T00:00:00Z", "watch A1:B2 delta 2", "unwatch all", "export csv A1:B2 formulas tab", "import csv A1\n1,2", "idem k1 delete A1", "transpose A1:B2 D1 formulas", "slowlog 5"] {
+            let command = Command::parse(text);
+            assert!(!matches!(command, Command::Invalid { .. }), "{}", text);
+            assert_eq!(command.to_string(), text);
+            assert_eq!(Command::parse(&command.to_string()), command);
+        }
+        let json = serde_json::to_string(&Message::Command("get A1".into())).unwrap();
+        assert_eq!(json, r#"{"Command":"get A1"}"#);
+    }
+
+    #[test]
+    fn test_errors_map_to_replies() {
+        let rsheet = RSheet::new();
+        let err = rsheet.runner(None).run("rate/0").unwrap_err();
+        assert!(matches!(err, RSheetError::Eval { code: ErrorCode::UnknownCell, .. }));
+        rsheet.set_number("A1", 1.0).unwrap();
+        rsheet.set_number("B1", 0.0).unwrap();
+        let err = rsheet.runner(None).run("A1/B1").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::DivByZero);
+        assert_eq!(ReplyError::from(err).code, ErrorCode::DivByZero);
 
-    pub trait Manager {
-        fn address(&self) -> &str;
+        let err = RSheetError::from(connect::ProtocolError::Malformed("eof".to_string()));
+        assert_eq!(ReplyError::from(err), ReplyError::new(ErrorCode::ProtocolError, "Malformed message: eof"));
+        let locked = ReplyError::locked(leases::LeaseHolder { cell: "A1".to_string(), connection: 1, name: None, remaining_ms: 10 });
+        assert_eq!(ReplyError::from(RSheetError::from(locked.clone())), locked);
     }
 
-    pub struct TcpManager {
-        address: String,
+    #[test]
+    fn test_on_change_hook() {
+        let rsheet = RSheet::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = Arc::clone(&seen);
+        rsheet.on_change(move |key, value| hook_seen.lock().unwrap().push((key.to_string(), value.clone())));
+
+        rsheet.set_number("A1", 2.0).unwrap();
+        rsheet.set_formula("B2", "A1*3").unwrap();
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            *seen,
+            vec![("A1".to_string(), CellValue::Number(2.0)), ("B2".to_string(), CellValue::Number(6.0))]
+        );
     }
 
-    impl TcpManager {
-        pub fn new(address: String) -> Self {
-            TcpManager { address }
-        }
+    #[test]
+    fn test_diagnostics_sink() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let sink_messages = Arc::clone(&messages);
+        let rsheet = RSheet::new().with_diagnostics(move |d: &diagnostics::Diagnostic| {
+            sink_messages.lock().unwrap().push((d.level, d.message.to_string(), d.cell.map(str::to_string)));
+        });
+        rsheet.set_number("A1", 1.0).unwrap();
+        rsheet.get_cell(&Session::detached(), "A1");
+        rsheet.get_cell(&Session::detached(), "B1");
+        assert_eq!(
+            *messages.lock().unwrap(),
+            vec![
+                (diagnostics::Level::Trace, "set".to_string(), Some("A1".to_string())),
+                (diagnostics::Level::Trace, "get".to_string(), Some("A1".to_string())),
+                (diagnostics::Level::Trace, "get of empty cell".to_string(), Some("B1".to_string())),
+            ]
+        );
+
+        assert!(!diagnostics::DiagnosticsSink::enabled(&diagnostics::NoopSink, diagnostics::Level::Error));
     }
 
-    impl Manager for TcpManager {
-        fn address(&self) -> &str {
-            &self.address
+    #[test]
+    fn test_command_tokenizer() {
+        use command::{tokenize, unquote, Command, TokenError};
+
+        assert_eq!(tokenize("save  \"my sheet.json\"").unwrap(), vec!["save", "\"my sheet.json\""]);
+        assert_eq!(unquote(r#""say \"hi\"""#), r#"say "hi""#);
+        assert_eq!(tokenize("  "), Err(TokenError::Empty));
+        assert_eq!(tokenize("save \"a b"), Err(TokenError::UnterminatedQuote { at: 5 }));
+        assert_eq!(tokenize("save \"a\\"), Err(TokenError::TrailingEscape));
+
+        let save = Command::parse("save \"my sheet.json\"");
+        assert_eq!(save, Command::Save { path: Some("my sheet.json".to_string()) });
+        assert_eq!(Command::parse(&save.to_string()), save);
+        let empty = Command::Invalid { text: String::new(), code: ErrorCode::ParseError, reason: "Empty command".to_string() };
+        assert_eq!(Command::parse(""), empty);
+        assert!(matches!(Command::parse("set A1 \"open"), Command::Invalid { reason, .. } if reason.starts_with("Unterminated")));
+    }
+
+    #[test]
+    fn test_iterators() {
+        let rsheet = RSheet::new();
+        for (cell, n) in [("B2", 4.0), ("A2", 3.0), ("B1", 2.0), ("Budget!A1", 5.0), ("A1", 1.0)] {
+            rsheet.set_number(cell, n).unwrap();
         }
+        let keys: Vec<String> = rsheet.iter_cells().map(|(key, _)| key.to_string()).collect();
+        assert_eq!(keys, ["A1", "B1", "A2", "B2", "Budget!A1"]);
+
+        let values = |cells: Vec<(address::CellAddress, CellValue)>| -> Vec<String> {
+            cells.into_iter().map(|(addr, value)| format!("{}={}", addr, replies::value_text(&value))).collect()
+        };
+        assert_eq!(values(rsheet.iter_range("A1:B2").unwrap().collect()), ["A1=1", "B1=2", "A2=3", "B2=4"]);
+        assert_eq!(values(rsheet.iter_row("2").unwrap().collect()), ["A2=3", "B2=4"]);
+        assert_eq!(values(rsheet.iter_col("B").unwrap().collect()), ["B1=2", "B2=4"]);
+        assert_eq!(values(rsheet.iter_col("Budget!A").unwrap().collect()), ["A1=5"]);
+        assert!(rsheet.iter_row("0").is_err());
     }
 
-    pub struct Reader {
-        stream: TcpStream,
+    #[test]
+    fn test_serde_round_trip() {
+        let rsheet = RSheet::new();
+        rsheet.set_number("A1", 2.0).unwrap();
+        rsheet.set_formula("Budget!B1", "Sheet1!A1*4").unwrap();
+
+        let json = serde_json::to_value(&rsheet).unwrap();
+        let copy: RSheet = serde_json::from_value(json).unwrap();
+        assert_eq!(copy.get_value("Budget!B1").unwrap(), Some(CellValue::Number(8.0)));
+        assert_eq!(copy.workbook().cells, rsheet.workbook().cells);
     }
-    
-    impl Reader {
-        pub fn new(stream: TcpStream) -> Self {
-            Reader { stream }
-        }
-    
-        pub fn read_message(&mut self) -> Result<super::Message, Box<dyn Error>> {
-            let mut len_buf = [0; 4];
-            self.stream.read_exact(&mut len_buf)?;
-            let len = u32::from_be_bytes(len_buf);
-    
-            let mut msg_buf = vec![0; len as usize];
-            self.stream.read_exact(&mut msg_buf)?;
-    
-            let msg: super::Message = serde_json::from_slice(&msg_buf)?;
-            Ok(msg)
-        }
+
+    #[test]
+    fn test_cell_value_formatting() {
+        use crate::format::FormatOptions;
+
+        assert_eq!(CellValue::Number(2.5).to_string(), "2.5");
+        assert_eq!(CellValue::Text("hi".into()).to_string(), "hi");
+        assert_eq!(CellValue::Error("Division by zero".into()).to_string(), "#ERROR Division by zero");
+
+        let money = FormatOptions::default().with_decimals(2).with_thousands_separator(',');
+        assert_eq!(CellValue::Number(1234567.891).formatted(&money).to_string(), "1,234,567.89");
+        assert_eq!(CellValue::Number(-1234.0).formatted(&money).to_string(), "-1,234.00");
+        assert_eq!(CellValue::Number(12.0).formatted(&money).to_string(), "12.00");
+        assert_eq!(CellValue::Text("n/a".into()).formatted(&money).to_string(), "n/a");
+
+        let date = FormatOptions::default().with_date_format("%Y-%m-%d");
+        assert_eq!(CellValue::Number(45292.0).formatted(&date).to_string(), "2024-01-01");
+        let invalid = FormatOptions::default().with_date_format("%Q");
+        assert_eq!(CellValue::Number(45292.0).formatted(&invalid).to_string(), "45292");
     }
-    
-    pub struct Writer {
-        stream: TcpStream,
+
+    #[test]
+    fn test_cell_value_conversions() {
+        assert_eq!(CellValue::from(2.5), CellValue::Number(2.5));
+        assert_eq!(CellValue::from(3), CellValue::Number(3.0));
+        assert_eq!(CellValue::from(true), CellValue::Number(1.0));
+        assert_eq!(CellValue::from("hi"), CellValue::Text("hi".into()));
+
+        assert_eq!(f64::try_from(CellValue::Number(2.5)).unwrap(), 2.5);
+        assert_eq!(i64::try_from(CellValue::Number(7.0)).unwrap(), 7);
+        assert!(i64::try_from(CellValue::Number(7.5)).is_err());
+        assert!(bool::try_from(CellValue::Number(2.0)).unwrap());
+        assert_eq!(String::try_from(CellValue::Text("hi".into())).unwrap(), "hi");
+
+        let e = f64::try_from(CellValue::Text("hi".into())).unwrap_err();
+        assert_eq!(e.code(), ErrorCode::TypeMismatch);
+        assert_eq!(e.to_string(), "Expected a number, got hi");
     }
-    
-    impl Writer {
-        pub fn new(stream: TcpStream) -> Self {
-            Writer { stream }
+
+    #[test]
+    fn test_writer_over_custom_transport() {
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
         }
-    
-        pub fn write_message(&mut self, reply: super::Reply) -> Result<(), Box<dyn Error>> {
-            let msg = super::Message::Reply(reply);
-            let msg_json = serde_json::to_string(&msg)?;
-    
-            let len_bytes = (msg_json.len() as u32).to_be_bytes();
-            self.stream.write_all(&len_bytes)?;
-            self.stream.write_all(msg_json.as_bytes())?;
-            Ok(())
+
+        impl connect::Transport for Buffer {
+            fn shutdown(&self) -> std::io::Result<()> {
+                Ok(())
+            }
         }
+
+        let buffer = Buffer::default();
+        let writer: connect::SharedWriter = Arc::new(Mutex::new(connect::Writer::text(buffer.clone())));
+        let rsheet = RSheet::new();
+        let session = Session::new(1, writer);
+        assert_eq!(futures::executor::block_on(rsheet.handle_session_command(&session, "set A1 2".to_string())), Reply::Ok);
+        let reply = futures::executor::block_on(rsheet.handle_session_command(&session, "get A1".to_string()));
+        session.writer.as_ref().unwrap().lock().unwrap().write_message(reply).unwrap();
+        assert_eq!(String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(), "2\n");
     }
-}
 
-pub mod replies {
-    use super::*;
+    #[tokio::test]
+    async fn test_output_precision_and_tolerant_comparisons() {
+        let rsheet = RSheet::new().with_precision(6);
+        let session = Session::detached();
+        let run = |cmd: &str| rsheet.handle_session_command(&session, cmd.to_string());
 
-    #[derive(Serialize, Deserialize, Debug, PartialEq)]
-    pub enum Reply {
-        Ok,
-        Value(CellValue),
-        Error(String),
+        assert_eq!(run("set A1 2/3").await, Reply::Ok);
+        assert_eq!(run("get A1").await, Reply::Value(CellValue::Number(0.666667).into()));
+        assert_eq!(run("session precision 3").await, Reply::Ok);
+        assert_eq!(run("get A1").await, Reply::Value(CellValue::Number(0.667).into()));
+        assert_eq!(run("session precision off").await, Reply::Ok);
+        assert_eq!(run("get A1").await, Reply::Value(CellValue::Number(0.666667).into()));
+        assert!(matches!(run("session precision 0").await, Reply::Error(e) if e.code == ErrorCode::ParseError));
+
+        assert_eq!(run("set A2 0.1+0.2").await, Reply::Ok);
+        match run("filter A2:A2 where A = 0.3").await {
+            Reply::Rows(rows) => assert_eq!(rows.len(), 1),
+            other => panic!("expected rows, got {:?}", other),
+        }
+        assert!(numeric::approx_eq(0.1 + 0.2, 0.3));
+        assert!(!numeric::approx_eq(1.0, 1.0001));
+        assert_eq!(numeric::round_significant(-1234.5678, 2), -1200.0);
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Message {
-    Command(String),
-    Reply(replies::Reply),
-}
+    #[tokio::test]
+    async fn test_localized_error_messages() {
+        let rsheet = RSheet::new();
+        let session = Session::detached();
+        let run = |cmd: &str| rsheet.handle_session_command(&session, cmd.to_string());
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub enum CellValue {
-    Number(f64),
-    Text(String),
-    Error(String),
-}
+        let english = match run("get 1A").await {
+            Reply::Error(e) if e.detail.is_none() => e.message,
+            other => panic!("expected an untranslated error, got {:?}", other),
+        };
 
-pub struct RSheet {
-    cells: Arc<Mutex<HashMap<String, CellValue>>>,
-}
+        assert_eq!(run("session locale fr-CA").await, Reply::Ok);
+        let expected = format!("error InvalidReference: Référence de cellule invalide ({})", english);
+        assert_eq!(run("get 1A").await.to_text(), expected);
 
-impl RSheet {
-    pub fn new() -> Self {
-        println!("Initializing RSheet with an empty hashmap.");
-        RSheet {
-            cells: Arc::new(Mutex::new(HashMap::new())),
+        assert_eq!(run("session language DE").await, Reply::Ok);
+        match run("get 1A").await {
+            Reply::Error(e) => assert_eq!((e.code, e.message.as_str()), (ErrorCode::InvalidReference, "Ungültiger Zellbezug")),
+            other => panic!("expected an error, got {:?}", other),
         }
+        match run("session language tlh").await {
+            Reply::Error(e) => assert_eq!((e.code, e.message.as_str()), (ErrorCode::ParseError, "Befehl konnte nicht gelesen werden")),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert_eq!(run("session language en").await, Reply::Ok);
+        assert!(matches!(run("get 1A").await, Reply::Error(e) if e.detail.is_none()));
     }
 
-    pub async fn handle_command(&self, command: String) -> replies::Reply {
-        let parts: Vec<&str> = command.split_whitespace().collect();
-        match parts[0] {
-            "set" if parts.len() == 3 => {
-                let cell = parts[1];
-                let value = parts[2];
-                // Check if value is just a number or an expression
-                if value.parse::<f64>().is_ok() {
-                    self.set_cell(cell, value.to_string())
-                } else {
-                    // It's an expression
-                    let expr = parts[2..].join(" ");
-                    self.set_cell(cell, expr)
-                }
-            },
-            "get" if parts.len() == 2 => self.get_cell(parts[1]),
-            _ => replies::Reply::Error("Invalid command format".to_string()),
+    #[tokio::test]
+    async fn test_scripts() {
+        let dir = std::env::temp_dir().join(format!("rsheet-scripts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("seed.rsheet"), "# demo sheet\nset A1 2\n\nset A2 A1*3\n").unwrap();
+        let rsheet = RSheet::new().with_script_dir(&dir);
+
+        assert_eq!(rsheet.execute_script("set B1 1\nset B2 B1+1\n".as_bytes()), Ok(2));
+        assert_eq!(rsheet.get_value("B2"), Ok(Some(CellValue::Number(2.0))));
+
+        // A failing line names itself, and nothing before it is kept.
+        let e = rsheet.execute_script("set C1 5\nset C2 1\nset 1A 1\n".as_bytes()).unwrap_err();
+        assert_eq!((e.line, e.error.code), (3, ErrorCode::InvalidReference));
+        assert_eq!(rsheet.get_value("C1"), Ok(None));
+        let e = rsheet.execute_script("set C1 5\nload\n".as_bytes()).unwrap_err();
+        assert_eq!(e.to_string(), "line 2: load cannot run in a script");
+
+        assert_eq!(rsheet.handle_command("run seed.rsheet".to_string()).await, Reply::Ok);
+        assert_eq!(rsheet.get_value("A2"), Ok(Some(CellValue::Number(6.0))));
+        for path in ["../seed.rsheet", "/etc/passwd"] {
+            let reply = rsheet.handle_command(format!("run {}", path)).await;
+            assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::Unauthorized), "{}", path);
         }
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    fn set_cell_direct(&self, cell: &str, value: &str) -> replies::Reply {
-        match value.parse::<f64>() {
-            Ok(num) => {
-                self.cells.lock().unwrap().insert(cell.to_string(), CellValue::Number(num));
-                replies::Reply::Ok
-            },
-            Err(_) => replies::Reply::Error("Invalid numeric value".to_string())
-        }
+    #[tokio::test]
+    async fn test_quoted_text_values() {
+        use command::Command;
+
+        let rsheet = RSheet::new();
+        let command = Command::parse(r#"set A1 "Quarterly Report 2024""#);
+        let expr = r#""Quarterly Report 2024""#.to_string();
+        assert_eq!(command, Command::Set { cell: "A1".to_string(), expr, if_version: None, force: false });
+        assert_eq!(Command::parse(&command.to_string()), command);
+
+        assert_eq!(rsheet.execute(&Session::detached(), command).await, Reply::Ok);
+        let reply = rsheet.handle_command("get A1".to_string()).await;
+        assert_eq!(reply, Reply::Value(CellValue::Text("Quarterly Report 2024".to_string()).into()));
+        assert_eq!(reply.to_text(), "Quarterly Report 2024");
+
+        rsheet.set_text("A2", r#"say "hi" \o/"#).unwrap();
+        assert_eq!(rsheet.get_value("A2"), Ok(Some(CellValue::Text(r#"say "hi" \o/"#.to_string()))));
+        rsheet.set_text("A3", "B1").unwrap();
+        assert_eq!(rsheet.get_value("A3"), Ok(Some(CellValue::Text("B1".to_string()))));
+
+        assert_eq!(command::unquote_text(r#""a" + "b""#), None);
+        assert_eq!(command::quote_text("Total"), r#""Total""#);
     }
 
-    fn get_cell(&self, cell: &str) -> replies::Reply {
-        println!("Getting value for cell: {}", cell);
-        match self.cells.lock().unwrap().get(cell) {
-            Some(value) => {
-                println!("Found value: {:?}", value);
-                replies::Reply::Value(value.clone())
-            },
-            None => {
-                println!("No value found for cell: {}", cell);
-                replies::Reply::Value(CellValue::Error(format!("Cell {} not found", cell)))
-            },
-        }
+    #[test]
+    fn test_currency_values() {
+        let usd = |amount: f64| CellValue::Currency { amount, code: "USD".to_string() };
+        let rsheet = RSheet::new();
+        rsheet.set_formula("A1", "19.99USD").unwrap();
+        rsheet.set_formula("A2", "5USD").unwrap();
+        rsheet.set_formula("A3", "A1+A2").unwrap();
+        assert_eq!(rsheet.get_value("A3"), Ok(Some(usd(24.99))));
+        assert_eq!(usd(24.99).to_string(), "24.99USD");
+        rsheet.set_formula("A4", "A2*3").unwrap();
+        assert_eq!(rsheet.get_value("A4"), Ok(Some(usd(15.0))));
+        rsheet.set_formula("A5", "A4/A2").unwrap();
+        assert_eq!(rsheet.get_value("A5"), Ok(Some(CellValue::Number(3.0))));
+
+        rsheet.set_formula("B1", "10EUR").unwrap();
+        let e = rsheet.set_formula("B2", "A1+B1").unwrap_err();
+        assert_eq!(e.code, ErrorCode::TypeMismatch);
+        assert!(rsheet.set_formula("B2", "A1+1").is_err());
+        assert_eq!(rsheet.set_formula("B3", "12XYZ").unwrap_err().code, ErrorCode::ParseError);
+
+        rsheet.set_number("C1", 0.5).unwrap();
+        rsheet.set_formula("C2", r#"CONVERT(A2,"USD","EUR",C1)"#).unwrap();
+        assert_eq!(rsheet.get_value("C2"), Ok(Some(CellValue::Currency { amount: 2.5, code: "EUR".to_string() })));
+        let e = rsheet.set_formula("C3", r#"CONVERT(B1,"USD","EUR",C1)"#).unwrap_err();
+        assert_eq!(e.code, ErrorCode::TypeMismatch);
     }
 
-    fn set_cell(&self, cell: &str, expr: String) -> replies::Reply {
-        println!("Setting cell: {} with expr: {}", cell, expr);
-        let runner = CommandRunner::new(self.cells.clone());
-        let result = runner.run(&expr);
-        match result {
-            CellValue::Error(e) => {
-                println!("Error in expression: {}", e);
-                replies::Reply::Error(e)
-            },
-            value => {
-                println!("Updating cell: {} with value: {:?}", cell, value);
-                self.cells.lock().unwrap().insert(cell.to_string(), value);
-                replies::Reply::Ok
-            }
-        }
+    #[test]
+    fn test_durations_and_times() {
+        let rsheet = RSheet::new();
+        rsheet.set_formula("A1", "2h30m").unwrap();
+        rsheet.set_formula("A2", "45m").unwrap();
+        rsheet.set_formula("A3", "1.5s").unwrap();
+        assert_eq!(rsheet.get_value("A1"), Ok(Some(CellValue::Duration(9000.0))));
+        rsheet.set_formula("A4", "SUM(A1:A3)").unwrap();
+        assert_eq!(rsheet.get_value("A4").unwrap().unwrap().to_string(), "3h15m1.5s");
+
+        rsheet.set_formula("B1", "2024-06-01T09:00:00Z").unwrap();
+        rsheet.set_formula("B2", "2024-06-01T17:15:00Z").unwrap();
+        rsheet.set_formula("B3", "B2-B1").unwrap();
+        assert_eq!(rsheet.get_value("B3").unwrap().unwrap().to_string(), "8h15m");
+        rsheet.set_formula("B4", "B1+A1").unwrap();
+        assert_eq!(rsheet.get_value("B4").unwrap().unwrap().to_string(), "2024-06-01T11:30:00Z");
+        rsheet.set_formula("A5", "A1/A2").unwrap();
+        assert_eq!(rsheet.get_value("A5"), Ok(Some(CellValue::Number(9000.0 / 2700.0))));
+
+        assert_eq!(rsheet.set_formula("B5", "B1+B2").unwrap_err().code, ErrorCode::TypeMismatch);
+        assert_eq!(datetime::duration_text(-90.0), "-1m30s");
+        assert_eq!(datetime::parse_duration("1d2h"), Some(93_600.0));
+        assert_eq!(datetime::parse_duration("h"), None);
     }
-}
 
-struct CommandRunner {
-    values: Arc<Mutex<HashMap<String, CellValue>>>,
-}
+    #[tokio::test]
+    async fn test_text_normalization_and_folded_matching() {
+        let rsheet = RSheet::new();
+        rsheet.set_text("A1", "Jose\u{301}").unwrap();
+        assert_eq!(rsheet.get_value("A1"), Ok(Some(CellValue::Text("Jos\u{e9}".to_string()))));
+        rsheet.set_text("A2", "JOSEPH").unwrap();
+        rsheet.set_text("A3", "jose").unwrap();
 
-impl CommandRunner {
-    fn new(values: Arc<Mutex<HashMap<String, CellValue>>>) -> Self {
-        CommandRunner { values }
+        let count = |reply: Reply| match reply {
+            Reply::Rows(rows) => rows.len(),
+            other => panic!("expected rows, got {:?}", other),
+        };
+        let filter = |conditions: &str| rsheet.handle_command(format!("filter A1:A3 where {}", conditions));
+        assert_eq!(count(filter(r#"A = "José""#).await), 1);
+        assert_eq!(count(filter(r#"A = "JOSE" ignore case"#).await), 1);
+        assert_eq!(count(filter(r#"A = "JOSE" ignore case ignore accents"#).await), 2);
+        assert_eq!(count(filter(r#"A ~ "^jose" ignore case ignore accents"#).await), 3);
+        assert!(matches!(filter(r#"A = "jose" ignore spaces"#).await, Reply::Error(_)));
+
+        let text = text::TextMatch { ignore_case: true, ignore_accents: true };
+        assert_eq!(text.fold("Ångström"), "angstrom");
+        assert!(text::TextMatch::default().is_exact());
     }
 
-    pub fn run(&self, expr: &str) -> CellValue {
-        if let Ok(num) = expr.parse::<f64>() {
-            return CellValue::Number(num);
+    #[tokio::test]
+    async fn test_import_type_inference() {
+        use command::Command;
+        use infer::{ColumnType, InferOptions};
+
+        let rsheet = RSheet::new();
+        let data = "\"1,234.5\",12.5%,$19.99,2024-06-01,yes,inf\n";
+        rsheet.import_csv(data.as_bytes(), "A1").unwrap();
+        let values = ["A1", "B1", "C1", "D1", "E1", "F1"].map(|cell| rsheet.get_value(cell).unwrap().unwrap());
+        assert_eq!(values[0], CellValue::Number(1234.5));
+        assert_eq!(values[1], CellValue::Number(0.125));
+        assert_eq!(values[2], CellValue::Currency { amount: 19.99, code: "USD".to_string() });
+        assert_eq!(values[3], CellValue::DateTime(datetime::parse_datetime("2024-06-01T00:00:00Z").unwrap()));
+        assert_eq!(values[4], CellValue::Number(1.0));
+        assert_eq!(values[5], CellValue::Text("inf".to_string()));
+
+        let command = Command::parse("import csv A2 text C:bool\n00123,7,true");
+        let options = InferOptions { text_only: true, columns: [(2, ColumnType::Boolean)].into() };
+        let expected = Command::ImportCsv { anchor: "A2".to_string(), data: "00123,7,true".to_string(), options };
+        assert_eq!(command, expected);
+        assert_eq!(Command::parse(&command.to_string()), command);
+        assert!(matches!(rsheet.handle_command(command.to_string()).await, Reply::Imported { cells: 3, .. }));
+        assert_eq!(rsheet.get_value("A2"), Ok(Some(CellValue::Text("00123".to_string()))));
+        assert_eq!(rsheet.get_value("B2"), Ok(Some(CellValue::Text("7".to_string()))));
+        assert_eq!(rsheet.get_value("C2"), Ok(Some(CellValue::Number(1.0))));
+
+        let reply = rsheet.handle_command("import csv A3 B:date\nx,soon".to_string()).await;
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::TypeMismatch && e.message.starts_with("B3:")));
+        assert_eq!(rsheet.get_value("A3"), Ok(None));
+        assert!(matches!(Command::parse("import csv A3 B:colour\nx"), Command::Invalid { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_mode() {
+        let rsheet = RSheet::new().with_read_only();
+        rsheet.set_formula("A1", "5").unwrap();
+        rsheet.set_formula("A2", "A1*2").unwrap();
+        let run = |command: &str| rsheet.handle_command(command.to_string());
+
+        for command in ["set A1 6", "delete A2", "begin", "import csv B1\n1,2", "run setup.txt"] {
+            let reply = run(command).await;
+            assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::ReadOnly), "{} was allowed", command);
         }
-        let re = Regex::new(r"(\w+)\s*([\+\-\*\/])\s*(\w+)").unwrap();
-        if let Some(caps) = re.captures(expr) {
-            let left = self.eval_operand(caps.get(1).unwrap().as_str());
-            let operator = caps.get(2).unwrap().as_str();
-            let right = self.eval_operand(caps.get(3).unwrap().as_str());
+        assert_eq!(run("get A2").await, Reply::Value(CellValue::Number(10.0).into()));
+        assert!(!command::Command::parse("watch A1:A2").is_write());
+        assert!(matches!(run("export csv A1:A2").await, Reply::Exported(_)));
+        assert!(matches!(run("filter A1:A2 where A > 6").await, Reply::Rows(rows) if rows.len() == 1));
+        assert!(rsheet.is_read_only() && !RSheet::new().is_read_only());
+    }
 
-            match operator {
-                "+" => self.add(left, right),
-                "-" => self.sub(left, right),
-                "*" => self.mul(left, right),
-                "/" => self.div(left, right),
-                _ => CellValue::Error("Invalid operator".to_string()),
-            }
-        } else {
-    
-            CellValue::Error("Unsupported expression format: ".to_string() + expr)
+    #[tokio::test]
+    async fn test_per_user_quotas() {
+        async fn run(rsheet: &RSheet, session: &Session, command: &str) -> Reply {
+            rsheet.handle_session_command(session, command.to_string()).await
         }
+        let quotas = quota::UserQuotas { max_cells: Some(2), max_bytes: None, max_commands_per_minute: Some(8) };
+        let rsheet = RSheet::new().with_admin_token("secret").with_user("alice", "a-token").with_user_quotas(quotas);
+        let (alice, admin) = (Session::detached(), Session::detached());
+        let code = |reply: Reply| match reply {
+            Reply::Error(e) => Some(e.code),
+            _ => None,
+        };
+
+        assert_eq!(run(&rsheet, &alice, "auth a-token").await, Reply::Ok);
+        assert_eq!(alice.user().as_deref(), Some("alice"));
+        assert_eq!(run(&rsheet, &alice, "set A1 1").await, Reply::Ok);
+        assert_eq!(run(&rsheet, &alice, "set A2 2").await, Reply::Ok);
+        assert_eq!(run(&rsheet, &alice, "set A1 3").await, Reply::Ok);
+        assert_eq!(code(run(&rsheet, &alice, "set A3 3").await), Some(ErrorCode::QuotaExceeded));
+        assert_eq!(run(&rsheet, &alice, "delete A2").await, Reply::Ok);
+        assert_eq!(run(&rsheet, &alice, "set A3 3").await, Reply::Ok);
+
+        assert_eq!(run(&rsheet, &admin, "auth secret").await, Reply::Ok);
+        let Reply::Quota(usage) = run(&rsheet, &admin, "admin quota alice").await else {
+            panic!("expected alice's quota");
+        };
+        assert_eq!((usage.cells, usage.commands), (2, 7));
+        let reply = run(&rsheet, &admin, "admin quota alice cells off").await;
+        assert_eq!(reply.to_text(), format!("alice cells=2/- bytes={}/- commands=7/8", usage.bytes));
+        assert_eq!(run(&rsheet, &alice, "set A4 4").await, Reply::Ok);
+        assert_eq!(code(run(&rsheet, &alice, "get A4").await), Some(ErrorCode::QuotaExceeded));
+        assert_eq!(run(&rsheet, &admin, "set A5 5").await, Reply::Ok);
+        assert!(matches!(command::Command::parse("admin quota alice rows 5"), command::Command::Invalid { .. }));
     }
 
-    fn eval_operand(&self, operand: &str) -> CellValue {
-        let values = self.values.lock().unwrap();
-        match values.get(operand) {
-            Some(val) => val.clone(),
-            None => operand.parse::<f64>().map_or(
-                CellValue::Error(format!("Invalid operand: {}", operand)),
-                CellValue::Number
-            )
+    #[tokio::test]
+    async fn test_describe() {
+        let rsheet = RSheet::new();
+        for (i, n) in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0].into_iter().enumerate() {
+            rsheet.set_formula(&format!("B{}", i + 1), &n.to_string()).unwrap();
         }
+        rsheet.set_text("B9", "n/a").unwrap();
+
+        let Reply::Described(description) = rsheet.handle_command("describe B1:B10000 bins 2".to_string()).await else {
+            panic!("expected a description");
+        };
+        assert_eq!((description.count, description.sum, description.mean), (8, 40.0, Some(5.0)));
+        assert_eq!((description.min, description.max), (Some(2.0), Some(9.0)));
+        assert!(numeric::approx_eq(description.stddev.unwrap(), (32.0f64 / 7.0).sqrt()));
+        let counts: Vec<usize> = description.histogram.unwrap().iter().map(|bin| bin.count).collect();
+        assert_eq!(counts, [6, 2]);
+
+        let reply = rsheet.handle_command("describe C1:C5".to_string()).await;
+        assert_eq!(reply.to_text(), "count 0\nsum 0\nmean -\nmin -\nmax -\nstddev -");
+        let reply = rsheet.handle_command("describe B1:B9 bins 0".to_string()).await;
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::ParseError));
     }
 
-    fn eval_expr<'a, I>(&self, tokens: &mut I) -> CellValue
-    where
-        I: Iterator<Item = &'a str>,
-    {
-        let mut result = self.eval_term(tokens);
+    #[tokio::test]
+    async fn test_sheet_views() {
+        let rsheet = RSheet::new();
+        rsheet.set_formula("Budget!A1", "1").unwrap();
+        let session = Session::detached();
+        let run = |command: &str| rsheet.handle_session_command(&session, command.to_string());
 
-        while let Some(op) = tokens.next() {
-            let rhs = self.eval_term(tokens);
-            result = match op {
-                "+" => self.add(result, rhs),
-                "-" => self.sub(result, rhs),
-                _ => CellValue::Error(format!("Invalid operator: {}", op)),
-            };
+        assert_eq!(run("use Budget").await, Reply::Ok);
+        for command in ["view freeze 1 0", "view width B 12.5", "view hide row 3", "view hide col D", "view hide col E"] {
+            assert_eq!(run(command).await, Reply::Ok, "{}", command);
         }
+        assert_eq!(run("view show col E").await, Reply::Ok);
+        let expected = "freeze 1 0\nwidth B 12.5\nhide row 3\nhide col D";
+        assert_eq!(run("view").await.to_text(), expected);
+        assert_eq!(rsheet.handle_command("view Budget".to_string()).await.to_text(), expected);
+        assert_eq!(rsheet.handle_command("view".to_string()).await.to_text(), "freeze 0 0");
+        assert!(matches!(run("view width B wide").await, Reply::Error(e) if e.code == ErrorCode::ParseError));
+        assert!(command::Command::parse("view freeze 1 0").is_write());
 
-        result
+        let path = std::env::temp_dir().join(format!("rsheet-views-{}.json", std::process::id()));
+        rsheet.save(&path).unwrap();
+        let loaded = RSheet::new();
+        loaded.load(&path).unwrap();
+        assert_eq!(loaded.handle_command("view Budget".to_string()).await.to_text(), expected);
+        assert_eq!(loaded.workbook().views, rsheet.workbook().views);
+        std::fs::remove_file(path).unwrap();
     }
 
-    fn eval_term<'a, I>(&self, tokens: &mut I) -> CellValue
-    where
-        I: Iterator<Item = &'a str>,
-    {
-        let mut result = self.eval_factor(tokens);
+    #[tokio::test]
+    async fn test_charts() {
+        let rsheet = RSheet::new();
+        rsheet.import_csv("Jan,10\nFeb,20\nMar,x".as_bytes(), "Sales!A1").unwrap();
+        rsheet.set_formula("Sales!C1", "Sales!B1*2").unwrap();
+        rsheet.set_formula("Sales!C2", "Sales!B2*2").unwrap();
+        let session = Session::detached();
+        let run = |command: &str| rsheet.handle_session_command(&session, command.to_string());
+
+        assert_eq!(run("use Sales").await, Reply::Ok);
+        assert_eq!(run("chart define monthly bar B1:B3 C1:C3 labels A1:A3").await, Reply::Ok);
+        assert_eq!(rsheet.handle_command("charts".to_string()).await.to_text(), "monthly bar Sales!B1:B3 Sales!C1:C3 labels Sales!A1:A3");
+        assert_eq!(run("set B1 15").await, Reply::Ok);
 
-        while let Some(op) = tokens.next() {
-            let rhs = self.eval_factor(tokens);
-            result = match op {
-                "*" => self.mul(result, rhs),
-                "/" => self.div(result, rhs),
-                _ => {
-                    tokens.next();
-                    return result;
-                }
-            };
-        }
+        let Reply::ChartData(data) = rsheet.handle_command("chart data monthly".to_string()).await else {
+            panic!("expected chart data");
+        };
+        assert_eq!(data.kind, chart::ChartKind::Bar);
+        assert_eq!(data.labels, Some(vec!["Jan".to_string(), "Feb".to_string(), "Mar".to_string()]));
+        assert_eq!(data.series[0].values, [Some(15.0), Some(20.0), None]);
+        // Charts read the stored values, as get does.
+        assert_eq!(data.series[1].values, [Some(20.0), Some(40.0), None]);
+        assert_eq!(rsheet.get_value("Sales!C1"), Ok(Some(CellValue::Number(20.0))));
 
-        result
+        assert_eq!(rsheet.workbook().charts.len(), 1);
+        assert!(matches!(run("chart define 9lives line B1:B3").await, Reply::Error(e) if e.code == ErrorCode::ParseError));
+        assert!(matches!(run("chart define odd donut B1:B3").await, Reply::Error(e) if e.code == ErrorCode::ParseError));
+        assert_eq!(run("chart remove monthly").await, Reply::Ok);
+        assert!(matches!(run("chart data monthly").await, Reply::Error(_)));
     }
 
-    fn eval_factor<'a, I>(&self, tokens: &mut I) -> CellValue
-    where
-        I: Iterator<Item = &'a str>,
-    {
-        if let Some(token) = tokens.next() {
-            if let Ok(value) = token.parse::<f64>() {
-                CellValue::Number(value)
-            } else if let Some(value) = self.values.lock().unwrap().get(token) {
-                value.clone()
-            } else {
-                CellValue::Error(format!("Invalid reference: {}", token))
-            }
-        } else {
-            CellValue::Error("Unexpected end of expression".to_string())
-        }
+    #[tokio::test]
+    async fn test_aliases() {
+        let rsheet = RSheet::new();
+        rsheet.import_csv("x,1\nx,2\ny,3".as_bytes(), "Log!A1").unwrap();
+        let session = Session::detached();
+        let run = |command: &str| rsheet.handle_session_command(&session, command.to_string());
+        let number = |n: f64| Reply::Value(CellValue::Number(n).into());
+
+        assert_eq!(run("set B10 1200").await, Reply::Ok);
+        assert_eq!(run("alias Revenue B10").await, Reply::Ok);
+        assert_eq!(run("set C1 Revenue*2").await, Reply::Ok);
+        assert_eq!(run("get C1").await, number(2400.0));
+        assert_eq!(run("set Revenue 1500").await, Reply::Ok);
+        assert_eq!(run("get B10").await, number(1500.0));
+
+        // An alias names its cell in full, whichever sheet uses it.
+        assert_eq!(run("use Budget").await, Reply::Ok);
+        assert_eq!(run("set A1 Revenue+1").await, Reply::Ok);
+        assert_eq!(run("get A1").await, number(1501.0));
+
+        // Moving rows carries their aliases along.
+        assert_eq!(run("alias Third Log!B3").await, Reply::Ok);
+        assert!(matches!(run("dedupe Log!A1:B3 by A").await, Reply::Removed { rows: 1, .. }));
+        assert_eq!(run("aliases").await.to_text(), "Revenue B10\nThird Log!B2");
+        assert_eq!(run("get Third").await, number(3.0));
+        assert_eq!(rsheet.workbook().aliases.len(), 2);
+
+        assert!(matches!(run("alias A1 B2").await, Reply::Error(e) if e.code == ErrorCode::ParseError));
+        assert!(matches!(run("get Profit").await, Reply::Error(e) if e.code == ErrorCode::InvalidReference));
+        assert_eq!(run("alias remove Revenue").await, Reply::Ok);
+        assert!(matches!(run("get Revenue").await, Reply::Error(e) if e.code == ErrorCode::InvalidReference));
     }
-    fn add(&self, lhs: CellValue, rhs: CellValue) -> CellValue {
-        match (lhs, rhs) {
-            (CellValue::Number(lhs), CellValue::Number(rhs)) => CellValue::Number(lhs + rhs),
-            _ => CellValue::Error("Invalid operands for addition".to_string()),
+
+    #[tokio::test]
+    async fn test_dependency_graph_dot() {
+        let rsheet = RSheet::new();
+        let formulas = [("B1", "A1*2"), ("B2", "SUM(A1:A2)"), ("Budget!C1", "Sheet1!B1+1"), ("Budget!C2", "C1*2")];
+        for (cell, expr) in [("A1", "2"), ("A2", "3")].into_iter().chain(formulas) {
+            rsheet.set_formula(cell, expr).unwrap();
         }
+        let graph = rsheet.dependency_graph();
+        assert_eq!(graph.nodes.len(), 4);
+        assert_eq!(graph.nodes["Budget!C1"].reads, [graph::Reference::Cell("B1".to_string())]);
+        assert_eq!(graph.nodes["Budget!C2"].reads, [graph::Reference::Cell("Budget!C1".to_string())]);
+
+        let dot = rsheet.handle_command("graph export dot".to_string()).await.to_text();
+        assert!(dot.starts_with("digraph rsheet {"), "{}", dot);
+        assert!(dot.contains("    \"B1\" -> \"Budget!C1\";\n    \"Budget!C1\" -> \"Budget!C2\";"), "{}", dot);
+
+        let scoped = rsheet.handle_command("graph export dot A1".to_string()).await.to_text();
+        let expected = [
+            "digraph rsheet {",
+            "    rankdir=LR;",
+            "    \"B1\" [label=\"B1 = A1*2\"];",
+            "    \"B2\" [label=\"B2 = SUM(A1:A2)\"];",
+            "    \"A1:A2\" [shape=box];",
+            "    \"A1\" -> \"B1\";",
+            "    \"A1:A2\" -> \"B2\";",
+            "}",
+        ];
+        assert_eq!(scoped, expected.join("\n"));
+        let reply = rsheet.handle_command("graph export dot A1:ZZ".to_string()).await;
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::ParseError));
     }
 
-    fn sub(&self, lhs: CellValue, rhs: CellValue) -> CellValue {
-        match (lhs, rhs) {
-            (CellValue::Number(lhs), CellValue::Number(rhs)) => CellValue::Number(lhs - rhs),
-            _ => CellValue::Error("Invalid operands for subtraction".to_string()),
+    #[tokio::test]
+    async fn test_where_used() {
+        let rsheet = RSheet::new();
+        let formulas = [("B1", "A1*2"), ("C1", "B1+1"), ("D1", "SUM(A1:A3)"), ("F1", "A2*2"), ("Budget!E1", "Sheet1!C1*3")];
+        for (cell, expr) in [("A1", "2"), ("A2", "1")].into_iter().chain(formulas) {
+            rsheet.set_formula(cell, expr).unwrap();
         }
+
+        let reply = rsheet.handle_command("whereused A1".to_string()).await;
+        assert_eq!(reply.to_text(), "1\tB1\tA1*2\n1\tD1\tSUM(A1:A3)\n2\tC1\tB1+1\n3\tBudget!E1\tSheet1!C1*3");
+        let direct: Vec<String> = rsheet.where_used("A1", Some(1)).unwrap().into_iter().map(|d| d.cell).collect();
+        assert_eq!(direct, ["B1", "D1"]);
+        assert_eq!(rsheet.handle_command("whereused Budget!E1".to_string()).await, Reply::WhereUsed(Vec::new()));
+
+        let reply = rsheet.handle_command("whereused A1 depth 0".to_string()).await;
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::ParseError));
     }
 
-    fn mul(&self, lhs: CellValue, rhs: CellValue) -> CellValue {
-        match (lhs, rhs) {
-            (CellValue::Number(lhs), CellValue::Number(rhs)) => CellValue::Number(lhs * rhs),
-            _ => CellValue::Error("Invalid operands for multiplication".to_string()),
+    #[tokio::test]
+    async fn test_clear_range() {
+        let rsheet = RSheet::new();
+        for (cell, expr) in [("A1", "1"), ("A2", "2"), ("B1", "A1*2"), ("C1", "B1+1"), ("D1", "C1*2"), ("E1", "5")] {
+            rsheet.set_formula(cell, expr).unwrap();
         }
+
+        let reply = rsheet.handle_command("clear A1:B2".to_string()).await;
+        assert_eq!(reply.to_text(), "cleared 3 cells from A1:B2\ndependents C1 D1");
+        assert_eq!(rsheet.get_value("A1"), Ok(None));
+        assert_eq!(rsheet.get_value("B1"), Ok(None));
+        assert!(!rsheet.dependency_graph().nodes.contains_key("B1"));
+        // Dependents are flagged, not recalculated.
+        assert_eq!(rsheet.get_value("C1"), Ok(Some(CellValue::Number(3.0))));
+        assert_eq!(rsheet.get_value("E1"), Ok(Some(CellValue::Number(5.0))));
+
+        assert_eq!(rsheet.handle_command("clear X1:X5".to_string()).await.to_text(), "cleared 0 cells from X1:X5");
+        assert!(command::Command::parse("clear A1:B2").is_write());
     }
-    fn div(&self, lhs: CellValue, rhs: CellValue) -> CellValue {
-        match (lhs, rhs) {
-            (CellValue::Number(_), CellValue::Number(0.0)) => {
-                CellValue::Error("Division by zero".to_string())
-            }
-            (CellValue::Number(lhs), CellValue::Number(rhs)) => CellValue::Number(lhs / rhs),
-            _ => CellValue::Error("Invalid operands for division".to_string()),
+
+    #[tokio::test]
+    async fn test_shift_range() {
+        let rsheet = RSheet::new();
+        let formulas = [("B1", "A6*10"), ("C1", "SUM(A5:A7)"), ("Budget!A1", "Sheet1!A6+1")];
+        for (cell, expr) in [("A5", "1"), ("A6", "2"), ("A7", "A5+A6"), ("A9", "99")].into_iter().chain(formulas) {
+            rsheet.set_formula(cell, expr).unwrap();
+        }
+        assert_eq!(rsheet.handle_command("alias Top A5".to_string()).await, Reply::Ok);
+
+        let reply = rsheet.handle_command("shift A5:A7 down 3".to_string()).await;
+        assert_eq!(reply, Reply::Imported { range: "A8:A10".parse().unwrap(), cells: 3 });
+        let value = |cell: &str| rsheet.get_value(cell).unwrap();
+        assert_eq!([value("A5"), value("A6"), value("A7")], [None, None, None]);
+        assert_eq!(value("A9"), Some(CellValue::Number(2.0)));
+        assert_eq!(value("A10"), Some(CellValue::Number(3.0)));
+        let graph = rsheet.dependency_graph();
+        let expr = |cell: &str| graph.nodes[cell].expr.clone();
+        assert_eq!([expr("A10"), expr("B1"), expr("C1"), expr("Budget!A1")], ["A8+A9", "A9*10", "SUM(A8:A10)", "Sheet1!A9+1"]);
+        assert_eq!(value("B1"), Some(CellValue::Number(20.0)));
+        assert_eq!(rsheet.handle_command("get Top".to_string()).await, Reply::Value(CellValue::Number(1.0).into()));
+
+        // Moving it back restores the references, but not what it covered.
+        assert!(rsheet.shift("A8:A10", reshape::Direction::Up, 3).is_ok());
+        assert_eq!(rsheet.dependency_graph().nodes["B1"].expr, "A6*10");
+        assert_eq!(value("A9"), None);
+
+        for command in ["shift A1:A2 up 1", "shift A1 sideways 1", "shift A1 down 0"] {
+            let reply = rsheet.handle_command(command.to_string()).await;
+            assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::ParseError), "{}", command);
         }
     }
-}
 
+    #[tokio::test]
+    async fn test_goal_seek() {
+        let rsheet = RSheet::new();
+        for (cell, expr) in [("B2", "10"), ("B3", "B2*B2"), ("C10", "B3-4"), ("C11", "B3+1"), ("A1", "7")] {
+            rsheet.set_formula(cell, expr).unwrap();
+        }
+        let reply = rsheet.handle_command("goalseek C10 to 21 by changing B2".to_string()).await;
+        let Reply::Value(found) = reply else {
+            panic!("{:?}", reply);
+        };
+        let number = |value: Option<CellValue>| match value {
+            Some(CellValue::Number(n)) => n,
+            other => panic!("{:?}", other),
+        };
+        assert!((number(Some(CellValue::clone(&found))) - 5.0).abs() < 1e-6);
+        // The input and the formulas between it and the goal are updated.
+        assert_eq!(rsheet.get_value("B2").unwrap(), Some(CellValue::clone(&found)));
+        assert!((number(rsheet.get_value("B3").unwrap()) - 25.0).abs() < 1e-6);
+        assert!((number(rsheet.get_value("C10").unwrap()) - 21.0).abs() < 1e-6);
 
+        // A square plus one never reaches zero.
+        let reply = rsheet.handle_command("goalseek C11 to 0 by changing B2".to_string()).await;
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::NoConvergence));
+        assert_eq!(rsheet.get_value("B2").unwrap(), Some(CellValue::clone(&found)));
 
-pub fn start_server<M>(rsheet: Arc<RSheet>, manager: M) -> Result<(), Box<dyn Error>>
-where
-    M: connect::Manager + Sync,
-{
-    let address = manager.address().clone();
-    let listener = std::net::TcpListener::bind(&address)?;
-    loop {
-        let (socket, _) = listener.accept()?;
-        let rsheet = Arc::clone(&rsheet);
+        let reply = rsheet.goal_seek("C10", 1.0, "B3");
+        assert!(matches!(reply, Err(e) if e.code == ErrorCode::TypeMismatch));
+        let reply = rsheet.goal_seek("C10", 1.0, "A1");
+        assert!(matches!(reply, Err(e) if e.code == ErrorCode::ParseError));
+        let command = command::Command::parse("goalseek C10 to lots by changing B2");
+        assert!(matches!(command, command::Command::Invalid { code: ErrorCode::ParseError, .. }));
+    }
 
-        std::thread::spawn(move || {
-            let reader = socket.try_clone().expect("Failed to clone socket");
-            let writer = socket;
-            let mut reader = connect::Reader::new(reader);
-            let mut writer = connect::Writer::new(writer);
+    #[tokio::test]
+    async fn test_scenarios() {
+        let rsheet = RSheet::new();
+        for (cell, expr) in [("B2", "1"), ("B3", "100"), ("C9", "B2*B3"), ("C10", "C9+1")] {
+            rsheet.set_formula(cell, expr).unwrap();
+        }
+        let run = |command: &str| rsheet.handle_command(command.to_string());
+        for command in ["scenario create optimistic", "scenario set optimistic B2 1.5", "scenario create pessimistic", "scenario set pessimistic B2 0.5"] {
+            assert_eq!(run(command).await, Reply::Ok, "{}", command);
+        }
+        let number = |n: f64| Arc::new(CellValue::Number(n));
+        assert_eq!(run("eval C10 in optimistic").await, Reply::Value(number(151.0)));
+        assert_eq!(run("eval C10 in optimistic pessimistic").await, Reply::Values(vec![number(151.0), number(51.0)]));
+        assert_eq!(run("eval B2 in pessimistic").await, Reply::Value(number(0.5)));
+        // The sheet itself is untouched.
+        assert_eq!(run("get C10").await, Reply::Value(number(101.0)));
+        assert_eq!(run("get B2").await, Reply::Value(number(1.0)));
 
-            loop {
-                match reader.read_message() {
-                    Ok(Message::Command(cmd)) => {
-                        let reply = futures::executor::block_on(rsheet.handle_command(cmd));
-                        writer.write_message(reply).unwrap();
-                    }
-                    _ => break,
-                }
-            }
-        });
+        assert_eq!(run("scenarios").await.to_text(), "optimistic B2=1.5\npessimistic B2=0.5");
+        assert_eq!(rsheet.workbook().scenarios.len(), 2);
+        for command in ["scenario create optimistic", "eval C10 in realistic", "scenario set realistic B2 1"] {
+            assert!(matches!(run(command).await, Reply::Error(e) if e.code == ErrorCode::ParseError), "{}", command);
+        }
+        assert_eq!(run("scenario remove pessimistic").await, Reply::Ok);
+        assert_eq!(rsheet.workbook().scenarios.len(), 1);
     }
-}
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[tokio::test]
-    async fn test_rsheet() {
+    async fn test_macros() {
         let rsheet = RSheet::new();
+        let session = Session::detached();
+        let run = |command: &str| rsheet.handle_session_command(&session, command.to_string());
+        for command in ["macro record close range rate", "set B1 SUM({range})", "set B2 B1*{rate}"] {
+            assert_eq!(run(command).await, Reply::Ok, "{}", command);
+        }
+        let reply = run("clear A1:A2").await;
+        assert!(matches!(reply, Reply::Error(e) if e.message == "clear cannot run in a macro"));
+        assert_eq!(run("macro stop").await, Reply::Ok);
+        // Recorded commands wait for the macro to run.
+        assert_eq!(rsheet.get_value("B1"), Ok(None));
+        assert_eq!(run("macros").await.to_text(), "close range rate\n\tset B1 SUM({range})\n\tset B2 B1*{rate}");
 
-        let reply = rsheet.handle_command("set A1 1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Ok);
+        rsheet.set_formula("A1", "2").unwrap();
+        rsheet.set_formula("A2", "3").unwrap();
+        assert_eq!(run("macro run close A1:A2 10").await, Reply::Ok);
+        assert_eq!(rsheet.get_value("B2"), Ok(Some(CellValue::Number(50.0))));
+        let reply = run("macro run close A1:A2").await;
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::ParseError));
 
-        let reply = rsheet.handle_command("set B1 2".to_string()).await;
-        assert_eq!(reply, replies::Reply::Ok);
+        // A failing command undoes the ones before it.
+        for command in ["macro record fill cell", "set B1 0", "set {cell} 1", "macro stop"] {
+            assert_eq!(run(command).await, Reply::Ok, "{}", command);
+        }
+        let reply = run("macro run fill 1A").await;
+        assert!(matches!(reply, Reply::Error(e) if e.code == ErrorCode::InvalidReference));
+        assert_eq!(rsheet.get_value("B1"), Ok(Some(CellValue::Number(5.0))));
+        assert_eq!(rsheet.workbook().macros.len(), 2);
+        assert_eq!(run("macro stop").await.to_text(), "error ParseError: Not recording a macro");
+    }
 
-        let reply = rsheet.handle_command("set C1 A1+B1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Ok);
+    #[tokio::test]
+    async fn test_formula_protection() {
+        let rsheet = RSheet::new();
+        for (cell, expr) in [("A1", "2"), ("B1", "A1*2"), ("Budget!B1", "A1+0")] {
+            rsheet.set_formula(cell, expr).unwrap();
+        }
+        let run = |command: &str| rsheet.handle_command(command.to_string());
+        assert_eq!(run("protect formulas").await, Reply::Ok);
+        assert!(matches!(run("set B1 5").await, Reply::Error(e) if e.code == ErrorCode::FormulaProtected));
+        assert_eq!(rsheet.get_value("B1"), Ok(Some(CellValue::Number(4.0))));
+        // Formulas may still be edited, values changed, and other sheets written.
+        for command in ["set B1 A1*3", "set A1 7", "set Budget!B1 1"] {
+            assert_eq!(run(command).await, Reply::Ok, "{}", command);
+        }
+        assert_eq!(run("set B1 5 --force").await, Reply::Ok);
+        assert_eq!(rsheet.get_value("B1"), Ok(Some(CellValue::Number(5.0))));
+        assert_eq!(command::Command::parse("set B1 5 if-version 3 --force").to_string(), "set B1 5 if-version 3 --force");
+        assert_eq!(rsheet.workbook().protected.into_iter().collect::<Vec<_>>(), [address::DEFAULT_SHEET]);
 
-        let reply = rsheet.handle_command("get A1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Value(CellValue::Number(1.0)));
+        rsheet.set_formula("B1", "A1+0").unwrap();
+        assert_eq!(run("unprotect formulas").await, Reply::Ok);
+        assert_eq!(run("set B1 6").await, Reply::Ok);
+    }
 
-        let reply = rsheet.handle_command("get B1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Value(CellValue::Number(2.0)));
+    #[tokio::test]
+    async fn test_append_row() {
+        let rsheet = RSheet::new();
+        rsheet.set_text("A1", "reading").unwrap();
+        rsheet.set_number("A2", 1.0).unwrap();
+        let run = |command: &str| rsheet.handle_command(command.to_string());
 
-        let reply = rsheet.handle_command("get C1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Value(CellValue::Number(3.0)));
+        let reply = run("append A 2 \"ok\" A2*10").await;
+        assert_eq!(reply, Reply::Appended { row: 3, range: "A3:C3".parse().unwrap() });
+        assert_eq!(reply.to_text(), "appended row 3 (A3:C3)");
+        assert_eq!(rsheet.get_value("B3"), Ok(Some(CellValue::Text("ok".to_string()))));
+        assert_eq!(rsheet.get_value("C3"), Ok(Some(CellValue::Number(10.0))));
+        assert_eq!(run("append A 5").await, Reply::Appended { row: 4, range: "A4".parse().unwrap() });
+        // A value further down a column the row spans pushes the row below it.
+        rsheet.set_number("B6", 1.0).unwrap();
+        assert_eq!(run("append A 3 4").await, Reply::Appended { row: 7, range: "A7:B7".parse().unwrap() });
+        assert_eq!(rsheet.get_value("B6"), Ok(Some(CellValue::Number(1.0))));
+        // Each sheet's column has its own rows.
+        assert_eq!(rsheet.append("Log!A", &["7".to_string()]), Ok("A1".parse().unwrap()));
+        assert_eq!(rsheet.get_value("Log!A1"), Ok(Some(CellValue::Number(7.0))));
 
-        let reply = rsheet.handle_command("set D1 A1*B1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Ok);
+        for command in ["append 1 2", "append XFE 1", "append A"] {
+            assert!(matches!(run(command).await, Reply::Error(e) if e.code == ErrorCode::ParseError), "{}", command);
+        }
+    }
 
-        let reply = rsheet.handle_command("get D1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Value(CellValue::Number(2.0)));
+    #[tokio::test]
+    async fn test_empty_cells() {
+        let rsheet = RSheet::new();
+        assert_eq!(rsheet.handle_command("get A1".to_string()).await, Reply::Value(CellValue::Empty.into()));
+        assert_eq!(rsheet.handle_command("get A1".to_string()).await.to_text(), "");
 
-        let reply = rsheet.handle_command("set E1 D1/C1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Ok);
+        // An empty cell counts as 0; a real failure is still an error.
+        assert_eq!(rsheet.handle_command("set B1 A1+2".to_string()).await, Reply::Ok);
+        assert_eq!(rsheet.handle_command("get B1".to_string()).await, Reply::Value(CellValue::Number(2.0).into()));
+        let reply = rsheet.handle_command("set C1 B1/A1".to_string()).await;
+        assert!(matches!(reply, Reply::Error(ReplyError { code: ErrorCode::DivByZero, .. })));
+        let reply = rsheet.handle_command("set C1 total*2".to_string()).await;
+        assert!(matches!(reply, Reply::Error(ReplyError { code: ErrorCode::UnknownCell, .. })));
 
-        let reply = rsheet.handle_command("get E1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Value(CellValue::Number(2.0 / 3.0)));
+        rsheet.handle_command("set A2 5".to_string()).await;
+        rsheet.handle_command("delete A2".to_string()).await;
+        assert_eq!(rsheet.handle_command("get A1:B2".to_string()).await.to_text(), "\t2\n\t");
+        assert_eq!(CellValue::Empty.to_string(), "");
+    }
 
-        let reply = rsheet.handle_command("set F1 C1-A1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Ok);
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn test_ffi() {
+        use std::ffi::{CStr, CString};
 
-        let reply = rsheet.handle_command("get F1".to_string()).await;
-        assert_eq!(reply, replies::Reply::Value(CellValue::Number(2.0)));
+        let text = |s: &str| CString::new(s).unwrap();
+        unsafe {
+            let sheet = ffi::rsheet_new();
+            let mut out = std::ptr::null_mut();
+            assert_eq!(ffi::rsheet_set(sheet, text("A1").as_ptr(), text("6").as_ptr(), &mut out), ffi::RSheetStatus::Ok);
+            assert_eq!(ffi::rsheet_get(sheet, text("A2").as_ptr(), &mut out), ffi::RSheetStatus::Ok);
+            assert!(out.is_null());
 
-        let reply = rsheet.handle_command("set G1 1/0".to_string()).await;
-        assert_eq!(reply, replies::Reply::Error("Division by zero".to_string()));
+            assert_eq!(ffi::rsheet_eval(sheet, text("A1*7").as_ptr(), &mut out), ffi::RSheetStatus::Ok);
+            assert_eq!(CStr::from_ptr(out).to_str().unwrap(), "42");
+            ffi::rsheet_string_free(out);
 
-        let reply = rsheet.handle_command("set J1 1+2*3".to_string()).await;
-        assert_eq!(reply, replies::Reply::Ok);
+            assert_eq!(ffi::rsheet_set(sheet, text("A1").as_ptr(), std::ptr::null(), &mut out), ffi::RSheetStatus::InvalidArgument);
+            assert_eq!(ffi::rsheet_set(sheet, text("not a cell").as_ptr(), text("1").as_ptr(), &mut out), ffi::RSheetStatus::Error);
+            assert!(!CStr::from_ptr(out).to_str().unwrap().is_empty());
+            ffi::rsheet_string_free(out);
+            ffi::rsheet_free(sheet);
+        }
+    }
 
-       
+    #[test]
+    fn test_cell_range_parsing() {
+        let range: address::CellRange = "B10:A1".parse().unwrap();
+        assert_eq!(range.to_string(), "A1:B10");
+        assert!(range.contains(&"B3".parse().unwrap()));
+        assert!(!range.contains(&"C3".parse().unwrap()));
+        assert_eq!("AA12".parse::<address::CellAddress>().unwrap(), address::CellAddress::new(26, 11));
+        assert!("1A".parse::<address::CellAddress>().is_err());
+        assert!("A0".parse::<address::CellAddress>().is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = connect::TokenBucket::new(connect::RateLimit { per_second: 0.0, burst: 2 });
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+
+        let mut bucket = connect::TokenBucket::new(connect::RateLimit { per_second: 1000.0, burst: 1 });
+        assert!(bucket.try_acquire());
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(bucket.try_acquire());
     }
 
 }
\ No newline at end of file
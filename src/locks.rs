@@ -0,0 +1,73 @@
+use crate::address::{split_sheet, DEFAULT_SHEET};
+use std::sync::{Condvar, Mutex};
+
+/// What a writer locks: one cell by storage key, every cell of one sheet
+/// (`None` is the default sheet), or the whole workbook.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LockScope {
+    Cell(String),
+    Sheet(Option<String>),
+    All,
+}
+
+impl LockScope {
+    /// Every cell of `sheet`, with the default sheet named or not.
+    pub fn sheet(sheet: Option<&str>) -> Self {
+        LockScope::Sheet(sheet.filter(|sheet| *sheet != DEFAULT_SHEET).map(str::to_string))
+    }
+
+    fn overlaps(&self, other: &LockScope) -> bool {
+        match (self, other) {
+            (LockScope::All, _) | (_, LockScope::All) => true,
+            (LockScope::Cell(a), LockScope::Cell(b)) => a == b,
+            (LockScope::Sheet(a), LockScope::Sheet(b)) => a == b,
+            (LockScope::Sheet(sheet), LockScope::Cell(cell)) | (LockScope::Cell(cell), LockScope::Sheet(sheet)) => {
+                split_sheet(cell).0 == sheet.as_deref()
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Held {
+    next: u64,
+    entries: Vec<(u64, Vec<LockScope>)>,
+}
+
+/// Write locks over cells, sheets and the whole workbook. A caller takes all
+/// its scopes at once, waiting until none overlaps a scope someone else holds,
+/// so writers never wait on each other in a cycle and a writer locking one
+/// sheet leaves the others free.
+#[derive(Default)]
+pub struct CellLocks {
+    held: Mutex<Held>,
+    released: Condvar,
+}
+
+impl CellLocks {
+    pub fn lock(&self, scopes: Vec<LockScope>) -> CellLockGuard<'_> {
+        let mut held = self.held.lock().unwrap();
+        while held.entries.iter().any(|(_, taken)| taken.iter().any(|t| scopes.iter().any(|s| s.overlaps(t)))) {
+            held = self.released.wait(held).unwrap();
+        }
+        let id = held.next;
+        held.next += 1;
+        held.entries.push((id, scopes));
+        CellLockGuard { locks: self, id }
+    }
+}
+
+/// Releases its scopes when dropped.
+pub struct CellLockGuard<'a> {
+    locks: &'a CellLocks,
+    id: u64,
+}
+
+impl Drop for CellLockGuard<'_> {
+    fn drop(&mut self) {
+        let mut held = self.locks.held.lock().unwrap_or_else(|e| e.into_inner());
+        held.entries.retain(|(id, _)| *id != self.id);
+        drop(held);
+        self.locks.released.notify_all();
+    }
+}
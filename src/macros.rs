@@ -0,0 +1,78 @@
+use crate::replies::{ErrorCode, ReplyError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A named sequence of commands, from `macro record`, that `macro run`
+/// replays as one transaction, like a script. Each `{param}` in a command
+/// is replaced by the argument given for it, so one macro can serve any
+/// range or value.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Macro {
+    /// The parameters, in the order `macro run` takes their arguments.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub params: Vec<String>,
+    pub commands: Vec<String>,
+}
+
+impl Macro {
+    pub fn new(params: Vec<String>) -> Self {
+        Macro { params, commands: Vec::new() }
+    }
+
+    /// Whether `text` names one of the parameters, and so may only make
+    /// sense once its arguments are in.
+    pub fn takes(&self, text: &str) -> bool {
+        self.params.iter().any(|param| text.contains(&placeholder(param)))
+    }
+
+    /// The commands with every parameter replaced by its argument, one per line.
+    pub fn expand(&self, args: &[String]) -> Result<String, ReplyError> {
+        if args.len() != self.params.len() {
+            let message = format!("Expected {} arguments ({}), got {}", self.params.len(), self.params.join(" "), args.len());
+            return Err(ReplyError::new(ErrorCode::ParseError, message));
+        }
+        let lines: Vec<String> = self
+            .commands
+            .iter()
+            .map(|command| {
+                self.params.iter().zip(args).fold(command.clone(), |command, (param, arg)| command.replace(&placeholder(param), arg))
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+}
+
+fn placeholder(param: &str) -> String {
+    format!("{{{}}}", param)
+}
+
+/// A macro in the `macros` listing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NamedMacro {
+    pub name: String,
+    #[serde(rename = "macro")]
+    pub definition: Macro,
+}
+
+/// The name and parameters on the first line, then each command indented
+/// by a tab.
+impl fmt::Display for NamedMacro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        for param in &self.definition.params {
+            write!(f, " {}", param)?;
+        }
+        for command in &self.definition.commands {
+            write!(f, "\n\t{}", command)?;
+        }
+        Ok(())
+    }
+}
+
+/// A macro being recorded on a connection: commands it sends until
+/// `macro stop` are kept instead of run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recording {
+    pub name: String,
+    pub recorded: Macro,
+}
@@ -1,12 +1,351 @@
+use clap::Parser;
+use rsheet::audit::AuditLog;
+use rsheet::autosave::Autosave;
+use rsheet::clients::ChangeBroadcast;
+use rsheet::config::ServerConfig;
+use rsheet::conflict::ConflictPolicy;
+use rsheet::history::RetentionPolicy;
+use rsheet::quota::SheetQuotas;
+use rsheet::replication::Follower;
+use rsheet::scheduler::{Schedule, Scheduler};
+use rsheet::slowlog::{SlowLog, DEFAULT_SLOWLOG_CAPACITY};
+use rsheet::wal::WriteAheadLog;
 use rsheet::RSheet;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Spreadsheet server")]
+struct Args {
+    /// TOML config file; flags below override its values.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Address to listen on. Repeat to listen on several.
+    #[arg(long)]
+    bind: Vec<String>,
+
+    /// Workbook to load before accepting clients.
+    #[arg(long)]
+    load: Option<PathBuf>,
+
+    /// Save the workbook on shutdown, to --load's path unless the config names another.
+    #[arg(long)]
+    save_on_exit: bool,
+
+    /// Default log level; RUST_LOG takes precedence when set.
+    #[arg(long, default_value = "info")]
+    log_level: LevelFilter,
+
+    /// Emit one JSON object per log line instead of human-readable text.
+    #[arg(long)]
+    log_json: bool,
+
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Run commands on this many shared threads instead of one per connection.
+    #[arg(long)]
+    command_workers: Option<std::num::NonZeroUsize>,
+
+    /// Serve connections on this many threads, turning away clients past that.
+    #[arg(long)]
+    connection_threads: Option<std::num::NonZeroUsize>,
+
+    /// Send each connection's queued replies and pushes together at least this often.
+    #[arg(long)]
+    coalesce_window_ms: Option<std::num::NonZeroU64>,
+
+    /// Push every change to `all` clients or to those that opt in (`subscribed`).
+    #[arg(long)]
+    change_broadcast: Option<ChangeBroadcast>,
+
+    /// Let replicas edit offline and merge with `sync` on reconnect.
+    #[arg(long)]
+    crdt_sync: bool,
+
+    /// Settle racing writes to a cell: last-writer-wins, first-writer-wins or reject.
+    #[arg(long)]
+    conflict_policy: Option<ConflictPolicy>,
+
+    /// Follow the primary at this address, serving reads only, until `admin promote`.
+    #[arg(long)]
+    replica_of: Option<String>,
+
+    /// Reject every command that would change cells, still serving reads, watches and exports.
+    #[arg(long)]
+    read_only: bool,
+
+    /// Serve a sheet from another node, as SHEET=ADDRESS. Repeat for several.
+    #[arg(long, value_parser = parse_remote_sheet)]
+    remote_sheet: Vec<(String, String)>,
+
+    /// Re-evaluate NOW(), RAND() and FETCH() cells on this schedule: `every 30s` or a cron expression.
+    #[arg(long)]
+    recalc_schedule: Option<String>,
+
+    /// Serve Prometheus metrics at http://<addr>/metrics.
+    #[arg(long)]
+    metrics_bind: Option<String>,
+}
+
+/// Reads a `--remote-sheet SHEET=ADDRESS` argument.
+fn parse_remote_sheet(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((sheet, node)) if !sheet.is_empty() && !node.is_empty() => Ok((sheet.to_string(), node.to_string())),
+        _ => Err(format!("expected SHEET=ADDRESS, got {}", arg)),
+    }
+}
+
+/// Builds the sheet on the cell store the config names, in memory by default.
+fn open_store(config: &ServerConfig) -> Result<RSheet, Box<dyn std::error::Error>> {
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &config.sqlite_path {
+        tracing::info!("Storing cells in {}", path.display());
+        return Ok(RSheet::with_store(rsheet::sqlite::SqliteStore::open(path)?));
+    }
+    #[cfg(feature = "redis")]
+    if let Some(url) = &config.redis_url {
+        tracing::info!("Storing cells in Redis at {}", url);
+        return Ok(RSheet::with_store(rsheet::redis::RedisStore::open(url)?));
+    }
+    #[cfg(feature = "sled")]
+    if let Some(path) = &config.sled_path {
+        tracing::info!("Storing cells in {}", path.display());
+        return Ok(RSheet::with_store(rsheet::sled::SledStore::open(path)?));
+    }
+    if config.columnar {
+        return Ok(RSheet::with_store(rsheet::store::ColumnarStore::default()));
+    }
+    if config.read_mostly {
+        return Ok(RSheet::with_store(rsheet::store::SwapStore::default()));
+    }
+    if let Some(shards) = config.cell_shards {
+        return Ok(RSheet::with_store(rsheet::store::ShardedStore::new(shards)));
+    }
+    // Validation rejects stores this build lacks.
+    debug_assert!(config.sqlite_path.is_none() && config.redis_url.is_none() && config.sled_path.is_none());
+    Ok(RSheet::new())
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let rsheet = Arc::new(RSheet::new());
-    let manager = rsheet::connect::TcpManager::new("127.0.0.1:8080".to_string());
+    let args = Args::parse();
+    let filter = EnvFilter::builder().with_default_directive(args.log_level.into()).from_env_lossy();
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    if args.log_json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+
+    let mut config = match &args.config {
+        Some(path) => ServerConfig::from_toml_file(path)?,
+        None => ServerConfig::default(),
+    };
+    if !args.bind.is_empty() {
+        config.bind = args.bind.clone();
+    }
+    if let Some(max) = args.max_connections {
+        config.max_connections = Some(max);
+    }
+    if let Some(workers) = args.command_workers {
+        config.command_workers = Some(workers.get());
+    }
+    if let Some(threads) = args.connection_threads {
+        config.connection_threads = Some(threads.get());
+    }
+    if let Some(window) = args.coalesce_window_ms {
+        config.coalesce_window_ms = Some(window.get());
+    }
+    if let Some(mode) = args.change_broadcast {
+        config.change_broadcast = mode;
+    }
+    if args.crdt_sync {
+        config.crdt_sync = true;
+    }
+    if let Some(policy) = args.conflict_policy {
+        config.conflict_policy = Some(policy);
+    }
+    if let Some(primary) = &args.replica_of {
+        config.replica_of = Some(primary.clone());
+    }
+    if args.read_only {
+        config.read_only = true;
+    }
+    config.remote_sheets.extend(args.remote_sheet.iter().cloned());
+    if let Some(schedule) = &args.recalc_schedule {
+        schedule.parse::<Schedule>()?;
+        config.recalc_schedule = Some(schedule.clone());
+    }
+    if let Some(address) = &args.metrics_bind {
+        config.metrics_bind = Some(address.clone());
+    }
+    if config.persistence_path.is_none() {
+        config.persistence_path = args.load.clone();
+    }
+
+    let mut rsheet = open_store(&config)?;
+    if let Some(path) = &config.audit_path {
+        rsheet = rsheet.with_audit_log(AuditLog::default().with_file(path)?);
+    }
+    if let Some(path) = &config.persistence_path {
+        rsheet = rsheet.with_persistence_path(path);
+    }
+    if let Some(token) = &config.admin_token {
+        rsheet = rsheet.with_admin_token(token.clone());
+    }
+    for (user, token) in &config.users {
+        rsheet = rsheet.with_user(user.clone(), token.clone());
+    }
+    if let Some(quotas) = config.user_quotas {
+        rsheet = rsheet.with_user_quotas(quotas);
+    }
+    if config.history_max_versions.is_some() || config.history_max_age_secs.is_some() {
+        let mut policy = RetentionPolicy::default();
+        if let Some(versions) = config.history_max_versions {
+            policy.max_versions = versions;
+        }
+        policy.max_age = config.history_max_age_secs.map(Duration::from_secs);
+        rsheet = rsheet.with_history(policy);
+    }
+    if let Some(bytes) = config.memory_limit_bytes {
+        rsheet = rsheet.with_memory_limit(bytes);
+    }
+    if let Some(digits) = config.precision {
+        rsheet = rsheet.with_precision(digits);
+    }
+    for dir in &config.script_dirs {
+        rsheet = rsheet.with_script_dir(dir);
+    }
+    if config.quotas != SheetQuotas::default() {
+        rsheet = rsheet.with_quotas(config.quotas);
+    }
+    let threshold = Duration::from_millis(config.slowlog_threshold_ms);
+    rsheet = rsheet.with_slow_log(SlowLog::new(threshold, DEFAULT_SLOWLOG_CAPACITY));
+    rsheet = rsheet.with_change_broadcast(config.change_broadcast);
+    if config.crdt_sync {
+        rsheet = rsheet.with_crdt_sync();
+    }
+    if let Some(policy) = config.conflict_policy {
+        rsheet = rsheet.with_conflict_policy(policy);
+    }
+    if config.replica_of.is_some() {
+        rsheet = rsheet.as_replica();
+    }
+    if config.read_only {
+        tracing::info!("Serving read-only");
+        rsheet = rsheet.with_read_only();
+    }
+    rsheet = rsheet.with_shards(config.remote_sheets.clone().into_iter().collect());
+    #[cfg(feature = "mqtt")]
+    if let Some(broker) = &config.mqtt_broker {
+        let publisher = rsheet::mqtt::MqttPublisher::connect(broker, &config.mqtt_client_id)?
+            .with_topic(config.mqtt_topic.clone())
+            .with_qos(config.mqtt_qos)
+            .with_retain(config.mqtt_retain);
+        tracing::info!("Publishing changes to MQTT broker {}", broker);
+        rsheet = rsheet.with_mqtt(publisher);
+    }
+    #[cfg(feature = "kafka")]
+    if !config.kafka_brokers.is_empty() {
+        let sink = rsheet::kafka::KafkaSink::connect(config.kafka_brokers.clone(), config.kafka_topic.clone())?;
+        tracing::info!("Producing changes to Kafka topic {}", config.kafka_topic);
+        rsheet = rsheet.with_kafka(sink);
+    }
+    #[cfg(feature = "webhooks")]
+    {
+        let webhooks = rsheet::webhooks::Webhooks::start(Default::default(), config.webhook_dead_letter_path.clone());
+        for webhook in &config.webhooks {
+            webhooks.add(webhook.clone());
+        }
+        rsheet = rsheet.with_webhooks(webhooks);
+    }
+    #[cfg(feature = "import-url")]
+    if !config.import_url_hosts.is_empty() {
+        rsheet = rsheet.with_url_policy(rsheet::fetch::UrlPolicy {
+            allowed_hosts: config.import_url_hosts.clone(),
+            timeout: Duration::from_secs(config.import_url_timeout_secs),
+            max_bytes: config.import_url_max_bytes,
+        });
+    }
+    #[cfg(feature = "import-url")]
+    if !config.fetch_hosts.is_empty() {
+        let feeds = rsheet::feeds::Feeds::new(Duration::from_secs(config.fetch_refresh_secs)).with_policy(rsheet::fetch::UrlPolicy {
+            allowed_hosts: config.fetch_hosts.clone(),
+            timeout: Duration::from_secs(config.fetch_timeout_secs),
+            max_bytes: config.import_url_max_bytes,
+        });
+        rsheet = rsheet.with_feeds(feeds);
+    }
+    let mut replayed = 0;
+    if let Some(path) = &config.wal_path {
+        replayed = rsheet.replay(path)?;
+        tracing::info!("Replayed {} changes from {}", replayed, path.display());
+        let mut wal = WriteAheadLog::open(path)?;
+        if let Some(entries) = config.wal_compact_after {
+            wal = wal.with_compact_after(entries);
+        }
+        rsheet = rsheet.with_write_ahead_log(wal);
+    }
+    let rsheet = Arc::new(rsheet);
+    if let Some(path) = &args.load {
+        // Once the log holds changes it is the source of truth.
+        if replayed == 0 {
+            rsheet.load(path)?;
+            tracing::info!("Loaded workbook from {}", path.display());
+        } else {
+            tracing::warn!("Ignoring --load: the write-ahead log already holds the workbook");
+        }
+    }
+
+    let server = rsheet::start_server(rsheet.clone(), config.clone())?;
+    tracing::info!("Listening on {:?}", server.local_addrs());
+    let autosave = match (config.autosave_interval_secs, &config.persistence_path) {
+        (Some(secs), Some(path)) => {
+            let debounce = Duration::from_millis(config.autosave_debounce_ms);
+            Some(Autosave::start(rsheet.clone(), path, Duration::from_secs(secs), debounce))
+        }
+        _ => None,
+    };
+    let follower = config.replica_of.as_ref().map(|primary| {
+        tracing::info!("Following primary {}", primary);
+        Follower::start(rsheet.clone(), primary.clone())
+    });
+    let scheduler = match &config.recalc_schedule {
+        Some(schedule) => Some(Scheduler::start(rsheet.clone(), schedule.parse::<Schedule>()?)),
+        // FETCH cells need refreshing even without a schedule of their own.
+        None if !config.fetch_hosts.is_empty() => {
+            Some(Scheduler::start(rsheet.clone(), Schedule::Every(Duration::from_secs(config.fetch_refresh_secs))))
+        }
+        None => None,
+    };
+
+    tokio::signal::ctrl_c().await?;
+    if let Some(follower) = follower {
+        follower.stop();
+    }
+    if let Some(scheduler) = scheduler {
+        scheduler.stop();
+    }
+    server.shutdown();
+    server.join();
+    if let Some(autosave) = autosave {
+        autosave.stop();
+    }
 
-    rsheet::start_server(rsheet, manager)?;
+    rsheet.flush()?;
+    if args.save_on_exit {
+        match &config.persistence_path {
+            Some(path) => {
+                rsheet.save(path)?;
+                tracing::info!("Saved workbook to {}", path.display());
+            }
+            None => tracing::warn!("--save-on-exit given but no file to save to"),
+        }
+    }
 
     Ok(())
 }
\ No newline at end of file
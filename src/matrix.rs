@@ -0,0 +1,53 @@
+use crate::address::{CellAddress, CellRange};
+use crate::store::Snapshot;
+use crate::CellValue;
+
+/// A range's values as of one moment, row by row, `None` where a cell is empty.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matrix {
+    range: CellRange,
+    rows: Vec<Vec<Option<CellValue>>>,
+}
+
+impl Matrix {
+    /// The cells of `range` held by `snapshot`.
+    pub fn from_snapshot(range: CellRange, snapshot: &Snapshot) -> Self {
+        let width = (range.end.col - range.start.col + 1) as usize;
+        let height = (range.end.row - range.start.row + 1) as usize;
+        let mut rows = vec![vec![None; width]; height];
+        for (addr, value) in snapshot.iter_range(range) {
+            rows[(addr.row - range.start.row) as usize][(addr.col - range.start.col) as usize] = Some(CellValue::clone(value));
+        }
+        Matrix { range, rows }
+    }
+
+    pub fn range(&self) -> CellRange {
+        self.range
+    }
+
+    pub fn width(&self) -> usize {
+        self.rows.first().map_or(0, Vec::len)
+    }
+
+    pub fn height(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// The value `row` rows down and `col` columns across from the range's
+    /// top left cell, both counted from zero.
+    pub fn get(&self, row: usize, col: usize) -> Option<&CellValue> {
+        self.rows.get(row)?.get(col)?.as_ref()
+    }
+
+    /// The value at `addr`, a cell of the sheet rather than an offset.
+    pub fn at(&self, addr: CellAddress) -> Option<&CellValue> {
+        if !self.range.contains(&addr) {
+            return None;
+        }
+        self.get((addr.row - self.range.start.row) as usize, (addr.col - self.range.start.col) as usize)
+    }
+
+    pub fn rows(&self) -> &[Vec<Option<CellValue>>] {
+        &self.rows
+    }
+}
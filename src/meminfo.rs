@@ -0,0 +1,118 @@
+use crate::address::{split_sheet, DEFAULT_SHEET};
+use crate::CellValue;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Rough cost of a map entry beyond its key and value.
+const ENTRY_OVERHEAD: u64 = 32;
+
+/// Estimated bytes held for one sheet.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SheetMemory {
+    pub sheet: String,
+    /// Stored values and their keys.
+    pub cells: u64,
+    /// Versions kept for `get <cell> asof <time>`.
+    pub history: u64,
+    /// Formula sources kept for saving and re-evaluation.
+    pub formulas: u64,
+}
+
+impl SheetMemory {
+    pub fn total(&self) -> u64 {
+        self.cells + self.history + self.formulas
+    }
+}
+
+pub fn value_bytes(value: &CellValue) -> u64 {
+    let heap = match value {
+        CellValue::Number(_) | CellValue::Duration(_) | CellValue::DateTime(_) | CellValue::Empty => 0,
+        CellValue::Text(text) | CellValue::Error(text) | CellValue::Currency { code: text, .. } => text.len(),
+    };
+    (size_of::<CellValue>() + heap) as u64
+}
+
+pub fn cell_bytes(cell: &str, value: &CellValue) -> u64 {
+    cell.len() as u64 + value_bytes(value) + ENTRY_OVERHEAD
+}
+
+pub fn formula_bytes(cell: &str, expr: &str) -> u64 {
+    (cell.len() + expr.len() + size_of::<crate::Formula>()) as u64 + ENTRY_OVERHEAD
+}
+
+/// One retained version of a cell; `None` records a delete.
+pub fn version_bytes(value: Option<&CellValue>) -> u64 {
+    size_of::<u64>() as u64 + value.map_or(size_of::<CellValue>() as u64, value_bytes)
+}
+
+/// The history kept for one cell, key included.
+pub fn history_bytes(cell: &str, versions: u64) -> u64 {
+    cell.len() as u64 + versions + ENTRY_OVERHEAD
+}
+
+/// Adds estimates up per sheet, by storage key.
+#[derive(Default)]
+pub struct Tally {
+    sheets: BTreeMap<String, SheetMemory>,
+}
+
+impl Tally {
+    fn sheet(&mut self, cell: &str) -> &mut SheetMemory {
+        let sheet = split_sheet(cell).0.unwrap_or(DEFAULT_SHEET);
+        self.sheets.entry(sheet.to_string()).or_insert_with(|| SheetMemory { sheet: sheet.to_string(), ..SheetMemory::default() })
+    }
+
+    pub fn cell(&mut self, cell: &str, value: &CellValue) {
+        self.sheet(cell).cells += cell_bytes(cell, value);
+    }
+
+    pub fn formula(&mut self, cell: &str, expr: &str) {
+        self.sheet(cell).formulas += formula_bytes(cell, expr);
+    }
+
+    pub fn history(&mut self, cell: &str, bytes: u64) {
+        self.sheet(cell).history += bytes;
+    }
+
+    /// Sheets in name order.
+    pub fn into_sheets(self) -> Vec<SheetMemory> {
+        self.sheets.into_values().collect()
+    }
+}
+
+/// Running estimate of the bytes in cells and formulas, kept as they change
+/// so a memory limit is checked without scanning the sheet.
+#[derive(Debug, Default)]
+pub struct Usage {
+    bytes: AtomicI64,
+}
+
+impl Usage {
+    pub fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes as i64, Ordering::SeqCst);
+    }
+
+    pub fn sub(&self, bytes: u64) {
+        self.bytes.fetch_sub(bytes as i64, Ordering::SeqCst);
+    }
+
+    /// Accounts for `cell` going from `old` to `new`.
+    pub fn change(&self, cell: &str, old: Option<&CellValue>, new: Option<&CellValue>) {
+        if let Some(new) = new {
+            self.add(cell_bytes(cell, new));
+        }
+        if let Some(old) = old {
+            self.sub(cell_bytes(cell, old));
+        }
+    }
+
+    pub fn reset(&self, bytes: u64) {
+        self.bytes.store(bytes as i64, Ordering::SeqCst);
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst).max(0) as u64
+    }
+}
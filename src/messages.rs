@@ -0,0 +1,102 @@
+use crate::replies::{ErrorCode, ReplyError};
+
+/// Languages error messages can be given in, as the primary subtag of a
+/// language tag. English is what the server writes to begin with.
+pub const LANGUAGES: &[&str] = &["en", "de", "es", "fr"];
+
+/// The supported language of a tag such as `de-AT` or `fr_CA`, ignoring
+/// case and region.
+pub fn language(tag: &str) -> Option<&'static str> {
+    let primary = tag.split(['-', '_']).next()?.to_ascii_lowercase();
+    LANGUAGES.iter().copied().find(|language| *language == primary)
+}
+
+/// What an error with `code` means, in `language`. `None` for English and
+/// for languages without a catalog.
+pub fn error_message(code: ErrorCode, language: &str) -> Option<&'static str> {
+    use ErrorCode::*;
+
+    let message = match language {
+        "de" => match code {
+            ParseError => "Befehl konnte nicht gelesen werden",
+            UnknownCell => "Unbekannte Zelle",
+            TypeMismatch => "Falscher Werttyp",
+            DivByZero => "Division durch null",
+            CircularRef => "Zirkelbezug",
+            Unauthorized => "Keine Berechtigung",
+            ServerBusy => "Server ausgelastet",
+            Throttled => "Zu viele Anfragen",
+            ProtocolError => "Protokollfehler",
+            UnknownClient => "Unbekannter Client",
+            Timeout => "Zeitüberschreitung",
+            StorageError => "Speicherfehler",
+            HistoryUnavailable => "Verlauf nicht verfügbar",
+            Conflict => "Konflikt mit einer anderen Änderung",
+            QuotaExceeded => "Kontingent überschritten",
+            Locked => "Zelle ist gesperrt",
+            ReadOnly => "Server nimmt keine Änderungen an",
+            NodeUnavailable => "Knoten nicht erreichbar",
+            InvalidReference => "Ungültiger Zellbezug",
+            NoConvergence => "Keine Lösung gefunden",
+            FormulaProtected => "Zelle enthält eine geschützte Formel",
+        },
+        "es" => match code {
+            ParseError => "No se pudo leer la orden",
+            UnknownCell => "Celda desconocida",
+            TypeMismatch => "Tipo de valor incorrecto",
+            DivByZero => "División entre cero",
+            CircularRef => "Referencia circular",
+            Unauthorized => "No autorizado",
+            ServerBusy => "Servidor ocupado",
+            Throttled => "Demasiadas solicitudes",
+            ProtocolError => "Error de protocolo",
+            UnknownClient => "Cliente desconocido",
+            Timeout => "Tiempo de espera agotado",
+            StorageError => "Error de almacenamiento",
+            HistoryUnavailable => "Historial no disponible",
+            Conflict => "Conflicto con otro cambio",
+            QuotaExceeded => "Cuota superada",
+            Locked => "La celda está bloqueada",
+            ReadOnly => "El servidor no acepta cambios",
+            NodeUnavailable => "Nodo no disponible",
+            InvalidReference => "Referencia de celda no válida",
+            NoConvergence => "No se encontró una solución",
+            FormulaProtected => "La celda contiene una fórmula protegida",
+        },
+        "fr" => match code {
+            ParseError => "Commande illisible",
+            UnknownCell => "Cellule inconnue",
+            TypeMismatch => "Type de valeur incorrect",
+            DivByZero => "Division par zéro",
+            CircularRef => "Référence circulaire",
+            Unauthorized => "Non autorisé",
+            ServerBusy => "Serveur occupé",
+            Throttled => "Trop de requêtes",
+            ProtocolError => "Erreur de protocole",
+            UnknownClient => "Client inconnu",
+            Timeout => "Délai dépassé",
+            StorageError => "Erreur de stockage",
+            HistoryUnavailable => "Historique indisponible",
+            Conflict => "Conflit avec une autre modification",
+            QuotaExceeded => "Quota dépassé",
+            Locked => "La cellule est verrouillée",
+            ReadOnly => "Le serveur n'accepte pas de modifications",
+            NodeUnavailable => "Nœud injoignable",
+            InvalidReference => "Référence de cellule invalide",
+            NoConvergence => "Aucune solution trouvée",
+            FormulaProtected => "La cellule contient une formule protégée",
+        },
+        _ => return None,
+    };
+    Some(message)
+}
+
+/// `error` with its message in `language`, keeping the original, which
+/// names the cells and values involved, as its detail. The code is never
+/// translated, so clients can go on branching on it.
+pub fn localize(error: ReplyError, language: &str) -> ReplyError {
+    match error_message(error.code, language) {
+        Some(message) => ReplyError { detail: Some(error.message), message: message.to_string(), ..error },
+        None => error,
+    }
+}
@@ -0,0 +1,156 @@
+use crate::replies::ErrorCode;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(feature = "net")]
+use {
+    std::io::{BufRead, BufReader, Write as IoWrite},
+    std::net::TcpStream,
+};
+
+/// Upper bounds, in seconds, shared by all histograms.
+const BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+pub struct Histogram {
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bound, count) in BUCKETS.iter().zip(&self.counts) {
+            if secs <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in BUCKETS.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count());
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+    }
+}
+
+/// Server counters and histograms, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    commands: Mutex<BTreeMap<String, u64>>,
+    errors: Mutex<BTreeMap<String, u64>>,
+    active_connections: AtomicI64,
+    replay_failures: AtomicU64,
+    command_latency: Histogram,
+    recalc_time: Histogram,
+}
+
+impl Metrics {
+    pub fn record_command(&self, kind: &str, elapsed: Duration) {
+        *self.commands.lock().unwrap().entry(kind.to_string()).or_insert(0) += 1;
+        self.command_latency.observe(elapsed);
+    }
+
+    pub fn record_error(&self, code: ErrorCode) {
+        *self.errors.lock().unwrap().entry(format!("{:?}", code)).or_insert(0) += 1;
+    }
+
+    pub fn record_recalc(&self, elapsed: Duration) {
+        self.recalc_time.observe(elapsed);
+    }
+
+    /// A logged change that could not be applied again and was skipped.
+    pub fn record_replay_failure(&self) {
+        self.replay_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn replay_failures(&self) -> u64 {
+        self.replay_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn active_connections(&self) -> i64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn commands_total(&self, kind: &str) -> u64 {
+        self.commands.lock().unwrap().get(kind).copied().unwrap_or(0)
+    }
+
+    pub fn render(&self, cell_count: usize) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP rsheet_commands_total Commands handled, by command name.");
+        let _ = writeln!(out, "# TYPE rsheet_commands_total counter");
+        for (kind, n) in self.commands.lock().unwrap().iter() {
+            let _ = writeln!(out, "rsheet_commands_total{{command=\"{}\"}} {}", kind, n);
+        }
+        let _ = writeln!(out, "# HELP rsheet_errors_total Error replies, by error code.");
+        let _ = writeln!(out, "# TYPE rsheet_errors_total counter");
+        for (code, n) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "rsheet_errors_total{{code=\"{}\"}} {}", code, n);
+        }
+        let _ = writeln!(out, "# HELP rsheet_replay_failures_total Logged changes skipped on replay because they no longer apply.");
+        let _ = writeln!(out, "# TYPE rsheet_replay_failures_total counter");
+        let _ = writeln!(out, "rsheet_replay_failures_total {}", self.replay_failures());
+        let _ = writeln!(out, "# HELP rsheet_active_connections Currently open client connections.");
+        let _ = writeln!(out, "# TYPE rsheet_active_connections gauge");
+        let _ = writeln!(out, "rsheet_active_connections {}", self.active_connections());
+        let _ = writeln!(out, "# HELP rsheet_cells Cells currently stored.");
+        let _ = writeln!(out, "# TYPE rsheet_cells gauge");
+        let _ = writeln!(out, "rsheet_cells {}", cell_count);
+        self.command_latency.render(&mut out, "rsheet_command_seconds", "Time spent handling a command.");
+        self.recalc_time.render(&mut out, "rsheet_recalc_seconds", "Time spent evaluating formulas.");
+        out
+    }
+}
+
+/// Answers one HTTP request: `GET /metrics` gets `body`, anything else a 404.
+#[cfg(feature = "net")]
+pub fn serve_http(stream: TcpStream, body: impl FnOnce() -> String) -> std::io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let mut stream = stream;
+    let mut parts = request_line.split_whitespace();
+    if let (Some("GET"), Some("/metrics")) = (parts.next(), parts.next()) {
+        let body = body();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    }
+}
@@ -0,0 +1,81 @@
+use crate::address::{split_sheet, DEFAULT_SHEET};
+use crate::replies::value_text;
+use crate::CellValue;
+use rumqttc::{Client, MqttOptions, QoS};
+use std::time::Duration;
+
+/// Topic changes are published on unless configured otherwise.
+pub const DEFAULT_TOPIC: &str = "rsheet/{sheet}/{cell}";
+
+/// Pause after a broker error before the connection is retried.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Publishes every cell change to an MQTT broker, one topic per cell.
+///
+/// The payload is the value as text, as the line protocol shows it. With
+/// `retain` set the broker keeps each cell's latest value, so a dashboard
+/// that subscribes later still sees it.
+pub struct MqttPublisher {
+    client: Client,
+    topic: String,
+    qos: QoS,
+    retain: bool,
+}
+
+impl MqttPublisher {
+    /// Connects to `broker` (`host:port`) as `client_id`. The connection is
+    /// made, and remade after a failure, on a background thread.
+    pub fn connect(broker: &str, client_id: &str) -> Result<Self, String> {
+        let (host, port) = broker.rsplit_once(':').ok_or_else(|| format!("MQTT broker must be host:port, got {}", broker))?;
+        let port = port.parse::<u16>().map_err(|_| format!("Invalid MQTT broker port: {}", port))?;
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let (client, mut connection) = Client::new(options, 256);
+        let broker = broker.to_string();
+        std::thread::spawn(move || {
+            for event in connection.iter() {
+                if let Err(e) = event {
+                    tracing::warn!(%broker, error = %e, "MQTT connection failed, retrying");
+                    std::thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        });
+        Ok(MqttPublisher { client, topic: DEFAULT_TOPIC.to_string(), qos: QoS::AtLeastOnce, retain: false })
+    }
+
+    /// Publishes on `topic`, where `{sheet}` and `{cell}` are replaced by the
+    /// changed cell's sheet and address.
+    pub fn with_topic(mut self, topic: impl Into<String>) -> Self {
+        self.topic = topic.into();
+        self
+    }
+
+    /// 0, 1 or 2; anything higher is treated as 2.
+    pub fn with_qos(mut self, qos: u8) -> Self {
+        self.qos = match qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        };
+        self
+    }
+
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    pub fn topic(&self, cell: &str) -> String {
+        let (sheet, addr) = split_sheet(cell);
+        self.topic.replace("{sheet}", sheet.unwrap_or(DEFAULT_SHEET)).replace("{cell}", addr)
+    }
+
+    /// Queues the change without waiting for the broker; if the queue is
+    /// full the change is dropped and logged.
+    pub fn publish(&self, cell: &str, value: &CellValue) {
+        let topic = self.topic(cell);
+        if let Err(e) = self.client.try_publish(&topic, self.qos, self.retain, value_text(value)) {
+            tracing::warn!(%topic, error = %e, "failed to queue MQTT publish");
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use crate::address::{CellAddress, CellKey, CellRange};
+use crate::store::CellStore;
+use crate::CellValue;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A change made inside a transaction, applied when it commits.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TxWrite {
+    Set { cell: String, expr: String, sheet: Option<String>, value: CellValue },
+    Delete { cell: String },
+}
+
+impl TxWrite {
+    pub fn cell(&self) -> &str {
+        match self {
+            TxWrite::Set { cell, .. } | TxWrite::Delete { cell } => cell,
+        }
+    }
+
+    fn value(&self) -> Option<Arc<CellValue>> {
+        match self {
+            TxWrite::Set { value, .. } => Some(Arc::new(value.clone())),
+            TxWrite::Delete { .. } => None,
+        }
+    }
+}
+
+/// A session's open transaction: when it began and what it has changed so
+/// far. The versions it reads are kept until it is dropped.
+#[derive(Debug)]
+pub struct Transaction {
+    pin: Pin,
+    writes: Vec<TxWrite>,
+}
+
+impl Transaction {
+    /// Opens a transaction at the current state. The caller holds the cells
+    /// exclusively, so no change is half applied.
+    pub fn begin(versions: &Arc<Versions>) -> Self {
+        Transaction { pin: versions.pin(), writes: Vec::new() }
+    }
+
+    /// Commit timestamp of the state the transaction reads.
+    pub fn start(&self) -> u64 {
+        self.pin.start
+    }
+
+    /// Records `write`, replacing any earlier write to the same cell.
+    pub fn write(&mut self, write: TxWrite) {
+        self.writes.retain(|w| w.cell() != write.cell());
+        self.writes.push(write);
+    }
+
+    pub fn writes(&self) -> &[TxWrite] {
+        &self.writes
+    }
+
+    pub fn into_writes(self) -> Vec<TxWrite> {
+        self.writes
+    }
+}
+
+/// Keeps the versions visible at `start` until dropped.
+#[derive(Debug)]
+struct Pin {
+    versions: Arc<Versions>,
+    start: u64,
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        self.versions.unpin(self.start);
+    }
+}
+
+#[derive(Debug)]
+struct Version {
+    /// Commit that wrote the value; 0 for the value from before any kept commit.
+    committed: u64,
+    value: Option<Arc<CellValue>>,
+}
+
+/// Versions of the cells changed while transactions are open. Guarded by the
+/// [`Versions`] lock.
+#[derive(Debug, Default)]
+pub struct Records {
+    clock: u64,
+    /// Start timestamps of open transactions, with how many share each.
+    pins: BTreeMap<u64, usize>,
+    cells: HashMap<String, Vec<Version>>,
+}
+
+impl Records {
+    /// Records one commit that changed each cell from `old` to `new`.
+    pub fn commit<'a>(&mut self, changes: impl IntoIterator<Item = (&'a str, Option<&'a CellValue>, Option<&'a CellValue>)>) {
+        self.clock += 1;
+        let committed = self.clock;
+        for (cell, old, new) in changes {
+            let versions = self
+                .cells
+                .entry(cell.to_string())
+                .or_insert_with(|| vec![Version { committed: 0, value: old.cloned().map(Arc::new) }]);
+            versions.push(Version { committed, value: new.cloned().map(Arc::new) });
+        }
+    }
+
+    /// `cells` as `tx` sees them. Call with the cells lock held, for as long
+    /// as the view is used.
+    pub fn view<'a>(&'a self, cells: &'a dyn CellStore, tx: &'a Transaction) -> SnapshotView<'a> {
+        SnapshotView { cells, records: self, start: tx.start(), writes: &tx.writes }
+    }
+
+    fn value_at(versions: &[Version], start: u64) -> Option<Arc<CellValue>> {
+        versions.iter().rev().find(|version| version.committed <= start).and_then(|version| version.value.clone())
+    }
+}
+
+/// Multi-version cell records backing transactions. While no transaction is
+/// open nothing is kept and writers pass straight through. Once one is, every
+/// change is recorded with a commit timestamp, so the transaction keeps
+/// reading the cells as they were when it began and its commit can tell
+/// whether anyone else changed the same cells first.
+///
+/// The cells lock is always taken before this one.
+#[derive(Debug, Default)]
+pub struct Versions {
+    records: Mutex<Records>,
+    pinned: AtomicUsize,
+}
+
+impl Versions {
+    fn pin(self: &Arc<Self>) -> Pin {
+        let mut records = self.records.lock().unwrap();
+        let start = records.clock;
+        *records.pins.entry(start).or_default() += 1;
+        self.pinned.fetch_add(1, Ordering::SeqCst);
+        Pin { versions: Arc::clone(self), start }
+    }
+
+    /// Drops the versions no open transaction can read any more.
+    fn unpin(&self, start: u64) {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = records.pins.get_mut(&start) {
+            *count -= 1;
+            if *count == 0 {
+                records.pins.remove(&start);
+            }
+            self.pinned.fetch_sub(1, Ordering::SeqCst);
+        }
+        match records.pins.keys().next().copied() {
+            None => records.cells.clear(),
+            Some(oldest) => records.cells.retain(|_, versions| {
+                // Keep the newest version the oldest transaction can see, and everything after.
+                let seen = versions.iter().rposition(|version| version.committed <= oldest).unwrap_or(0);
+                versions.drain(..seen);
+                versions.iter().any(|version| version.committed > oldest)
+            }),
+        }
+    }
+
+    /// Taken around a change to the cells, with the cells lock held. `Some`
+    /// while a transaction is open: the change must then be recorded with
+    /// [`Records::commit`] before the guard is dropped.
+    pub fn writing(&self) -> Option<MutexGuard<'_, Records>> {
+        if self.pinned.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        Some(self.records.lock().unwrap())
+    }
+
+    /// Taken to read through [`Records::view`]; writers are held off until
+    /// it is dropped.
+    pub fn reading(&self) -> MutexGuard<'_, Records> {
+        self.records.lock().unwrap()
+    }
+
+    /// Cells `tx` wrote that another commit changed after it began, each with
+    /// the value it held when `tx` began.
+    pub fn conflicts(&self, tx: &Transaction) -> Vec<(String, Option<Arc<CellValue>>)> {
+        let records = self.records.lock().unwrap();
+        tx.writes
+            .iter()
+            .filter_map(|write| {
+                let versions = records.cells.get(write.cell())?;
+                versions
+                    .iter()
+                    .any(|version| version.committed > tx.start())
+                    .then(|| (write.cell().to_string(), Records::value_at(versions, tx.start())))
+            })
+            .collect()
+    }
+
+    /// The first cell `tx` wrote that another commit changed after it began.
+    pub fn conflict<'a>(&self, tx: &'a Transaction) -> Option<&'a str> {
+        let records = self.records.lock().unwrap();
+        tx.writes.iter().map(TxWrite::cell).find(|cell| {
+            records.cells.get(*cell).is_some_and(|versions| versions.iter().any(|version| version.committed > tx.start()))
+        })
+    }
+}
+
+/// Cells as a transaction sees them: its own writes, the versions it began
+/// with, and the live store for everything unchanged since.
+pub struct SnapshotView<'a> {
+    cells: &'a dyn CellStore,
+    records: &'a Records,
+    start: u64,
+    writes: &'a [TxWrite],
+}
+
+impl SnapshotView<'_> {
+    /// `Some` if the live value of `cell` is not the one this view sees.
+    fn changed(&self, cell: &str) -> Option<Option<Arc<CellValue>>> {
+        match self.writes.iter().find(|write| write.cell() == cell) {
+            Some(write) => Some(write.value()),
+            None => self.records.cells.get(cell).map(|versions| Records::value_at(versions, self.start)),
+        }
+    }
+
+    fn changed_cells(&self) -> impl Iterator<Item = (&str, Option<Arc<CellValue>>)> + '_ {
+        let written = self.writes.iter().map(|write| (write.cell(), write.value()));
+        let committed = self
+            .records
+            .cells
+            .iter()
+            .filter(|(cell, _)| !self.writes.iter().any(|write| write.cell() == cell.as_str()))
+            .map(|(cell, versions)| (cell.as_str(), Records::value_at(versions, self.start)));
+        written.chain(committed)
+    }
+}
+
+impl CellStore for SnapshotView<'_> {
+    fn get(&self, key: &str) -> Option<Arc<CellValue>> {
+        match self.changed(key) {
+            Some(value) => value,
+            None => self.cells.get(key),
+        }
+    }
+
+    fn set(&mut self, _key: &str, _value: CellValue) -> io::Result<Option<CellValue>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "snapshot views are read-only"))
+    }
+
+    fn delete(&mut self, _key: &str) -> io::Result<Option<CellValue>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "snapshot views are read-only"))
+    }
+
+    fn iter_range<'a>(
+        &'a self,
+        sheet: Option<&'a str>,
+        range: CellRange,
+    ) -> Box<dyn Iterator<Item = (CellAddress, CellValue)> + 'a> {
+        let key = move |addr: &CellAddress| CellKey { sheet: sheet.map(str::to_string), addr: *addr }.to_string();
+        let live = self.cells.iter_range(sheet, range).filter(move |(addr, _)| self.changed(&key(addr)).is_none());
+        let changed = self.changed_cells().filter_map(move |(cell, value)| {
+            let cell: CellKey = cell.parse().ok()?;
+            let value = value?;
+            (cell.sheet.as_deref() == sheet && range.contains(&cell.addr)).then(|| (cell.addr, Arc::unwrap_or_clone(value)))
+        });
+        Box::new(live.chain(changed))
+    }
+
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_> {
+        let live = self.cells.iter_all().filter(move |(cell, _)| self.changed(cell).is_none());
+        let changed = self
+            .changed_cells()
+            .filter_map(|(cell, value)| value.map(|value| (cell.to_string(), Arc::unwrap_or_clone(value))));
+        Box::new(live.chain(changed))
+    }
+}
+
+/// Version numbers of live cells, for `set ... if-version`. Every change
+/// takes the next number from one clock, so a cell deleted and set again
+/// never repeats a version a client may still hold. Empty cells are at 0.
+#[derive(Debug, Default)]
+pub struct CellVersions {
+    clock: AtomicU64,
+    cells: Mutex<HashMap<String, u64>>,
+}
+
+impl CellVersions {
+    pub fn get(&self, cell: &str) -> u64 {
+        self.cells.lock().unwrap().get(cell).copied().unwrap_or(0)
+    }
+
+    /// Records a change to `cell`, returning its new version.
+    pub fn bump(&self, cell: &str) -> u64 {
+        let version = self.clock.fetch_add(1, Ordering::SeqCst) + 1;
+        self.cells.lock().unwrap().insert(cell.to_string(), version);
+        version
+    }
+
+    pub fn remove(&self, cell: &str) {
+        self.cells.lock().unwrap().remove(cell);
+    }
+
+    /// Gives every one of `cells` a new version and forgets all others.
+    pub fn reset<'a>(&self, cells: impl IntoIterator<Item = &'a str>) {
+        let mut versions = self.cells.lock().unwrap();
+        versions.clear();
+        for cell in cells {
+            versions.insert(cell.to_string(), self.clock.fetch_add(1, Ordering::SeqCst) + 1);
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use std::cmp::Ordering;
+
+/// Relative tolerance under which two numbers count as equal, so that
+/// `0.1+0.2` equals `0.3`.
+pub const EPSILON: f64 = 1e-9;
+
+/// Most significant digits an `f64` can carry; `precision` is capped here.
+pub const MAX_PRECISION: u32 = 17;
+
+/// Whether `a` and `b` are equal within [`EPSILON`], relative to the larger
+/// of them, or absolute below 1.
+pub fn approx_eq(a: f64, b: f64) -> bool {
+    a == b || (a - b).abs() <= EPSILON * a.abs().max(b.abs()).max(1.0)
+}
+
+/// Orders `a` and `b`, counting them equal when [`approx_eq`] does. `None`
+/// if either is NaN.
+pub fn compare(a: f64, b: f64) -> Option<Ordering> {
+    if approx_eq(a, b) {
+        return Some(Ordering::Equal);
+    }
+    a.partial_cmp(&b)
+}
+
+/// `n` rounded to `digits` significant digits, so `2/3` shows as `0.666667`
+/// at 6. Zero, infinities and NaN are left as they are.
+pub fn round_significant(n: f64, digits: u32) -> f64 {
+    if n == 0.0 || !n.is_finite() || digits == 0 || digits >= MAX_PRECISION {
+        return n;
+    }
+    format!("{:.*e}", digits as usize - 1, n).parse().unwrap_or(n)
+}
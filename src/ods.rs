@@ -0,0 +1,98 @@
+use crate::address::{is_valid_sheet_name, qualify, split_sheet, CellAddress, DEFAULT_SHEET};
+use crate::currency::is_code;
+use crate::replies::value_text;
+use crate::workbook::{rewrite_refs, Workbook};
+use crate::{CellValue, Formula};
+use regex::Regex;
+use spreadsheet_ods::{Sheet, Value, WorkBook};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+/// Writes `workbook` as an OpenDocument spreadsheet, one table per sheet.
+/// Formulas are written with their last computed value. Error values become text.
+pub fn write(workbook: &Workbook, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+    let mut ods = WorkBook::new_empty();
+    let mut sheets = HashMap::new();
+    for name in &workbook.sheets {
+        sheets.insert(name.as_str(), ods.num_sheets());
+        ods.push_sheet(Sheet::new(name));
+    }
+    if workbook.sheets.is_empty() {
+        ods.push_sheet(Sheet::new(DEFAULT_SHEET));
+    }
+
+    for (key, cell) in &workbook.cells {
+        let (sheet, reference) = split_sheet(key);
+        let Ok(addr) = reference.parse::<CellAddress>() else {
+            tracing::warn!(cell = key, "skipping unaddressable cell in ods export");
+            continue;
+        };
+        let table = ods.sheet_mut(sheets[sheet.unwrap_or(DEFAULT_SHEET)]);
+        match &cell.value {
+            CellValue::Number(n) => table.set_value(addr.row, addr.col, *n),
+            CellValue::Currency { amount, code } => table.set_value(addr.row, addr.col, Value::Currency(*amount, code.as_str().into())),
+            value => table.set_value(addr.row, addr.col, value_text(value)),
+        }
+        if let Some(formula) = &cell.formula {
+            table.set_formula(addr.row, addr.col, open_formula(formula, sheet));
+        }
+    }
+    spreadsheet_ods::write_ods(&mut ods, path)?;
+    Ok(())
+}
+
+/// Reads an OpenDocument spreadsheet into a workbook, keeping formula text
+/// so the cells recompute here.
+pub fn read(path: impl AsRef<Path>) -> Result<Workbook, Box<dyn Error>> {
+    let ods = spreadsheet_ods::read_ods(path)?;
+    let mut values = HashMap::new();
+    let mut formulas = HashMap::new();
+    for table in ods.iter_sheets() {
+        let name = table.name();
+        if !is_valid_sheet_name(name) {
+            return Err(format!("Unsupported sheet name: {}", name).into());
+        }
+        let sheet = (name != DEFAULT_SHEET).then_some(name.as_str());
+        for ((row, col), cell) in table.iter() {
+            let key = qualify(sheet, &CellAddress::new(col, row).to_string());
+            let value = match cell.value {
+                Value::Currency(n, code) if is_code(code) => CellValue::Currency { amount: *n, code: code.to_string() },
+                Value::Number(n) | Value::Percentage(n) | Value::Currency(n, _) => CellValue::Number(*n),
+                Value::Boolean(b) => CellValue::Text(if *b { "TRUE" } else { "FALSE" }.to_string()),
+                Value::Text(_) | Value::TextXml(_) => CellValue::Text(cell.value.as_cow_str_or("").into_owned()),
+                Value::DateTime(date) => CellValue::Text(date.to_string()),
+                Value::TimeDuration(duration) => CellValue::Text(duration.to_string()),
+                Value::Empty if cell.formula.is_some() => CellValue::Number(0.0),
+                Value::Empty => continue,
+            };
+            if let Some(formula) = cell.formula {
+                formulas.insert(key.clone(), Formula { expr: rsheet_formula(formula), sheet: sheet.map(str::to_string) });
+            }
+            values.insert(key, value);
+        }
+    }
+    Ok(Workbook::new(&values, &formulas))
+}
+
+/// OpenFormula text for a formula on the table for `cell_sheet`: `A1` becomes
+/// `[.A1]` and `Budget!A1` becomes `[Budget.A1]`.
+fn open_formula(formula: &Formula, cell_sheet: Option<&str>) -> String {
+    let expr = rewrite_refs(formula, cell_sheet, |sheet, reference| {
+        format!("[{}.{}]", sheet.unwrap_or(""), reference)
+    });
+    format!("of:={}", expr)
+}
+
+/// The inverse of [`open_formula`]. Absolute markers (`$`) are dropped.
+fn rsheet_formula(formula: &str) -> String {
+    let reference = Regex::new(r"\[\$?([^.\]]*)\.\$?([A-Za-z]+)\$?([0-9]+)\]").unwrap();
+    let expr = formula.strip_prefix("of:").unwrap_or(formula);
+    let expr = expr.strip_prefix('=').unwrap_or(expr);
+    reference
+        .replace_all(expr, |caps: &regex::Captures| match &caps[1] {
+            "" => format!("{}{}", &caps[2], &caps[3]),
+            sheet => format!("{}!{}{}", sheet.trim_matches('\''), &caps[2], &caps[3]),
+        })
+        .into_owned()
+}
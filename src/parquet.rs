@@ -0,0 +1,18 @@
+use crate::address::CellRange;
+use crate::arrow_ipc::record_batch;
+use crate::store::Snapshot;
+use parquet::arrow::ArrowWriter;
+use parquet::errors::ParquetError;
+use std::fs::File;
+use std::path::Path;
+
+/// Writes `range` of `cells` as a Parquet file with one column per sheet
+/// column, typed as in [`record_batch`]: `DOUBLE` for columns of numbers,
+/// `UTF8` otherwise, with empty cells and errors null.
+pub fn write(range: CellRange, cells: &Snapshot, path: impl AsRef<Path>) -> Result<(), ParquetError> {
+    let batch = record_batch(range, cells)?;
+    let mut writer = ArrowWriter::try_new(File::create(path)?, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
@@ -0,0 +1,105 @@
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    std::panic::{catch_unwind, AssertUnwindSafe},
+    std::sync::mpsc::{self, Receiver, Sender},
+    std::sync::{Arc, Mutex},
+    std::thread::JoinHandle,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads running commands from one FIFO queue, so no more
+/// than `size` commands run at once. A connection queues a command and waits
+/// for its reply before reading the next, so connections take turns however
+/// many commands each one sends. Not on wasm32, which has no threads.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct WorkerPool {
+    queue: Mutex<Option<Sender<Job>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WorkerPool {
+    pub fn new(size: usize) -> Self {
+        let (queue, jobs) = mpsc::channel::<Job>();
+        let jobs = Arc::new(Mutex::new(jobs));
+        let workers = (0..size)
+            .map(|_| {
+                let jobs = Arc::clone(&jobs);
+                std::thread::spawn(move || work(&jobs))
+            })
+            .collect();
+        WorkerPool { queue: Mutex::new(Some(queue)), workers: Mutex::new(workers) }
+    }
+
+    /// Queues `job` and waits for a worker to run it. `None` if the job
+    /// panicked or the pool has shut down.
+    pub fn run<T: Send + 'static>(&self, job: impl FnOnce() -> T + Send + 'static) -> Option<T> {
+        let (done, result) = mpsc::sync_channel(1);
+        let job: Job = Box::new(move || {
+            let _ = done.send(job());
+        });
+        self.queue.lock().unwrap().as_ref()?.send(job).ok()?;
+        result.recv().ok()
+    }
+
+    /// Queues `job` without waiting for it. `false` if the pool has shut down.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) -> bool {
+        match self.queue.lock().unwrap().as_ref() {
+            Some(queue) => queue.send(Box::new(job)).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Lets the workers finish what is queued and waits for them to exit.
+    pub fn shutdown(&self) {
+        self.queue.lock().unwrap().take();
+        let workers: Vec<_> = self.workers.lock().unwrap().drain(..).collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Runs `f` over `items` on up to one scoped thread per core, keeping their
+/// order. For one-off bulk work like loading a workbook, not for commands.
+/// Runs on the calling thread alone where threads are unavailable, as on wasm32.
+pub fn parallel_map<T: Send, R: Send>(items: Vec<T>, f: impl Fn(T) -> R + Sync) -> Vec<R> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get()).min(items.len());
+    if threads <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+    let per_thread = items.len().div_ceil(threads);
+    let mut items = items.into_iter();
+    let chunks: Vec<Vec<T>> = (0..threads).map(|_| items.by_ref().take(per_thread).collect()).collect();
+    let f = &f;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || chunk.into_iter().map(f).collect::<Vec<R>>()))
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e))).collect()
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn work(jobs: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        match job {
+            // A panicking command loses its reply, not the worker.
+            Ok(job) => {
+                let _ = catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => break,
+        }
+    }
+}
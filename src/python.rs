@@ -0,0 +1,86 @@
+use crate::{CellValue, CsvOptions, RSheet};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::fmt::Display;
+use std::fs::File;
+
+/// An in-process sheet, `rsheet.Sheet()` in Python.
+///
+/// ```python
+/// import rsheet
+///
+/// sheet = rsheet.Sheet()
+/// sheet.set("A1", 2)
+/// sheet.set("A2", "A1*3")
+/// assert sheet.get("A2") == 6.0
+/// ```
+#[pyclass(name = "Sheet", frozen)]
+struct PySheet {
+    rsheet: RSheet,
+}
+
+#[pymethods]
+impl PySheet {
+    #[new]
+    fn new() -> Self {
+        PySheet { rsheet: RSheet::new() }
+    }
+
+    /// Sets `cell` to a number, or to an expression such as `"A1+B1"` as
+    /// the `set` command takes it.
+    fn set(&self, cell: &str, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        match value.extract::<f64>() {
+            Ok(n) => self.rsheet.set_number(cell, n),
+            Err(_) => self.rsheet.set_formula(cell, &value.extract::<String>()?),
+        }
+        .map_err(value_error)
+    }
+
+    /// The value of `cell`: a float, a str, or `None` if it is empty. A cell
+    /// holding an error raises `ValueError`.
+    fn get(&self, py: Python<'_>, cell: &str) -> PyResult<Option<PyObject>> {
+        match self.rsheet.get_value(cell).map_err(value_error)? {
+            Some(value) => to_python(py, value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Evaluates `expr` against the sheet without storing it anywhere.
+    fn eval(&self, py: Python<'_>, expr: &str) -> PyResult<PyObject> {
+        to_python(py, self.rsheet.run(expr).map_err(value_error)?)
+    }
+
+    /// Reads the CSV file at `path` into cells from `anchor`, returning the
+    /// range it filled, such as `"A1:C20"`.
+    #[pyo3(signature = (path, anchor = "A1"))]
+    fn import_csv(&self, path: &str, anchor: &str) -> PyResult<String> {
+        let range = self.rsheet.import_csv(File::open(path)?, anchor).map_err(value_error)?;
+        Ok(range.to_string())
+    }
+
+    /// Writes `range` to `path` as CSV.
+    fn export_csv(&self, path: &str, range: &str) -> PyResult<()> {
+        self.rsheet.export_csv(File::create(path)?, range, CsvOptions::default()).map_err(value_error)
+    }
+}
+
+fn to_python(py: Python<'_>, value: CellValue) -> PyResult<PyObject> {
+    match value {
+        CellValue::Number(n) => Ok(n.into_py(py)),
+        CellValue::Text(text) => Ok(text.into_py(py)),
+        CellValue::Error(e) => Err(PyValueError::new_err(e)),
+        CellValue::Currency { amount, code } => Ok((amount, code).into_py(py)),
+        CellValue::Duration(seconds) => Ok(seconds.into_py(py)),
+        value @ CellValue::DateTime(_) => Ok(value.to_string().into_py(py)),
+        CellValue::Empty => Ok(py.None()),
+    }
+}
+
+fn value_error(e: impl Display) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+#[pymodule]
+fn rsheet(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PySheet>()
+}
@@ -0,0 +1,878 @@
+use crate::address::{column_index, column_name, CellAddress, CellRange};
+use crate::replies::value_text;
+use crate::store::Snapshot;
+use crate::text::TextMatch;
+use crate::CellValue;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq)]
+pub struct QueryError(pub String);
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// One row `filter` matched: the row's cells within the range, and their values.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RowMatch {
+    pub cells: CellRange,
+    pub values: Vec<CellValue>,
+}
+
+/// A result with named columns, e.g. `A` and `SUM(C)`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<CellValue>>,
+}
+
+/// How the values of a column are folded into one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aggregate {
+    Sum,
+    Count,
+    Avg,
+    Min,
+    Max,
+}
+
+impl FromStr for Aggregate {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sum" => Ok(Aggregate::Sum),
+            "count" => Ok(Aggregate::Count),
+            "avg" | "average" => Ok(Aggregate::Avg),
+            "min" => Ok(Aggregate::Min),
+            "max" => Ok(Aggregate::Max),
+            _ => Err(QueryError(format!("Unknown aggregate: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Aggregate::Sum => "SUM",
+            Aggregate::Count => "COUNT",
+            Aggregate::Avg => "AVG",
+            Aggregate::Min => "MIN",
+            Aggregate::Max => "MAX",
+        })
+    }
+}
+
+/// Folds values into an [`Aggregate`]. Only numbers are summed, averaged
+/// and compared; `COUNT` counts every value.
+#[derive(Clone, Debug)]
+pub struct Accumulator {
+    aggregate: Aggregate,
+    sum: f64,
+    numbers: usize,
+    values: usize,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Accumulator {
+    pub fn new(aggregate: Aggregate) -> Self {
+        Accumulator { aggregate, sum: 0.0, numbers: 0, values: 0, min: None, max: None }
+    }
+
+    pub fn push(&mut self, value: &CellValue) {
+        self.values += 1;
+        if let CellValue::Number(n) = *value {
+            self.sum += n;
+            self.numbers += 1;
+            self.min = Some(self.min.map_or(n, |min| min.min(n)));
+            self.max = Some(self.max.map_or(n, |max| max.max(n)));
+        }
+    }
+
+    pub fn finish(&self) -> CellValue {
+        let none = || CellValue::Error(format!("No numbers to {}", self.aggregate));
+        match self.aggregate {
+            Aggregate::Sum => CellValue::Number(self.sum),
+            Aggregate::Count => CellValue::Number(self.values as f64),
+            Aggregate::Avg if self.numbers == 0 => none(),
+            Aggregate::Avg => CellValue::Number(self.sum / self.numbers as f64),
+            Aggregate::Min => self.min.map_or_else(none, CellValue::Number),
+            Aggregate::Max => self.max.map_or_else(none, CellValue::Number),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// `~`: the value, as text, matches a regular expression.
+    Matches,
+}
+
+impl FromStr for Operator {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "=" | "==" => Ok(Operator::Eq),
+            "!=" | "<>" => Ok(Operator::Ne),
+            "<" => Ok(Operator::Lt),
+            "<=" => Ok(Operator::Le),
+            ">" => Ok(Operator::Gt),
+            ">=" => Ok(Operator::Ge),
+            "~" => Ok(Operator::Matches),
+            _ => Err(QueryError(format!("Unknown operator: {}", s))),
+        }
+    }
+}
+
+/// `<column> <operator> <literal>`, e.g. `B = "paid"` or `C > 50`. Numbers
+/// compare with numbers and text with text; a value of the other kind, or
+/// an empty cell, is only ever unequal. `B ~ "^pa"` matches a pattern
+/// against any value's text. Text may be compared ignoring case or accents,
+/// as in `B = "jose" IGNORE CASE IGNORE ACCENTS`.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    pub column: u32,
+    pub operator: Operator,
+    pub literal: CellValue,
+    pub text: TextMatch,
+    /// Compiled from the literal for [`Operator::Matches`].
+    pattern: Option<Regex>,
+}
+
+impl Condition {
+    pub fn new(column: u32, operator: Operator, literal: CellValue) -> Result<Self, QueryError> {
+        let condition = Condition { column, operator, literal, text: TextMatch::default(), pattern: None };
+        condition.with_text_match(TextMatch::default())
+    }
+
+    /// The condition comparing text as `text` says.
+    pub fn with_text_match(mut self, text: TextMatch) -> Result<Self, QueryError> {
+        self.text = text;
+        self.pattern = match (self.operator, &self.literal) {
+            (Operator::Matches, CellValue::Text(pattern)) => {
+                // Case is left to the regex, so that escapes such as `\D` keep their meaning.
+                let pattern = text.without_case().fold(pattern);
+                let compiled = RegexBuilder::new(&pattern).case_insensitive(text.ignore_case).build();
+                Some(compiled.map_err(|e| QueryError(format!("Invalid pattern {}: {}", pattern, e)))?)
+            }
+            (Operator::Matches, _) => return Err(QueryError("~ needs a quoted pattern".to_string())),
+            _ => None,
+        };
+        Ok(self)
+    }
+
+    pub fn matches(&self, value: Option<&CellValue>) -> bool {
+        if let Some(pattern) = &self.pattern {
+            return value.is_some_and(|value| pattern.is_match(&self.text.without_case().fold(&value_text(value))));
+        }
+        let ordering = match (value, &self.literal) {
+            (Some(CellValue::Number(a)), CellValue::Number(b)) => crate::numeric::compare(*a, *b),
+            (Some(CellValue::Text(a)), CellValue::Text(b)) => Some(self.text.fold(a).cmp(&self.text.fold(b))),
+            _ => None,
+        };
+        match self.operator {
+            Operator::Eq => ordering == Some(Ordering::Equal),
+            Operator::Ne => ordering != Some(Ordering::Equal),
+            Operator::Lt => ordering == Some(Ordering::Less),
+            Operator::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Operator::Gt => ordering == Some(Ordering::Greater),
+            Operator::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            Operator::Matches => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Number(f64),
+    Text(String),
+    Symbol(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) | Token::Symbol(word) => f.write_str(word),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Text(text) => write!(f, "\"{}\"", text),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut literal = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, q)) if q == c => break,
+                    Some((_, ch)) => literal.push(ch),
+                    None => return Err(QueryError(format!("Unterminated string starting at {}", start))),
+                }
+            }
+            tokens.push(Token::Text(literal));
+        } else if c.is_ascii_digit() || c == '-' || c == '.' {
+            let mut end = start;
+            while let Some(&(i, ch)) = chars.peek() {
+                if !(ch.is_ascii_digit() || ch == '.' || (i == start && ch == '-')) {
+                    break;
+                }
+                end = i + ch.len_utf8();
+                chars.next();
+            }
+            let number = &text[start..end];
+            tokens.push(Token::Number(number.parse().map_err(|_| QueryError(format!("Invalid number: {}", number)))?));
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, ch)) = chars.peek() {
+                if !(ch.is_alphanumeric() || matches!(ch, '_' | '!' | ':')) {
+                    break;
+                }
+                end = i + ch.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Word(text[start..end].to_string()));
+        } else {
+            chars.next();
+            let two = chars.peek().map(|&(_, next)| format!("{}{}", c, next));
+            match two.as_deref() {
+                Some("<=" | ">=" | "!=" | "<>" | "==") => {
+                    chars.next();
+                    tokens.push(Token::Symbol(two.unwrap()));
+                }
+                _ if "(),*=<>~".contains(c) => tokens.push(Token::Symbol(c.to_string())),
+                _ => return Err(QueryError(format!("Unexpected character: {}", c))),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Reads tokens front to back; keywords match in any case.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Result<Self, QueryError> {
+        Ok(Parser { tokens: tokenize(text)?, pos: 0 })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, QueryError> {
+        let token = self.tokens.get(self.pos).cloned().ok_or_else(|| QueryError("Unexpected end of query".to_string()))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.tokens.len()
+    }
+
+    /// Consumes `keyword` if it comes next.
+    fn eat(&mut self, keyword: &str) -> bool {
+        let found = match self.peek() {
+            Some(Token::Word(word)) | Some(Token::Symbol(word)) => word.eq_ignore_ascii_case(keyword),
+            _ => false,
+        };
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, keyword: &str) -> Result<(), QueryError> {
+        if self.eat(keyword) {
+            return Ok(());
+        }
+        match self.peek() {
+            Some(token) => Err(QueryError(format!("Expected {}, found {}", keyword, token))),
+            None => Err(QueryError(format!("Expected {}", keyword))),
+        }
+    }
+
+    fn word(&mut self) -> Result<String, QueryError> {
+        match self.next()? {
+            Token::Word(word) => Ok(word),
+            token => Err(QueryError(format!("Expected a name, found {}", token))),
+        }
+    }
+
+    fn column(&mut self) -> Result<u32, QueryError> {
+        let word = self.word()?;
+        column_index(&word).ok_or_else(|| QueryError(format!("Invalid column: {}", word)))
+    }
+
+    fn literal(&mut self) -> Result<CellValue, QueryError> {
+        match self.next()? {
+            Token::Number(n) => Ok(CellValue::Number(n)),
+            Token::Text(text) => Ok(CellValue::Text(crate::text::nfc(text))),
+            token => Err(QueryError(format!("Expected a number or quoted text, found {}", token))),
+        }
+    }
+
+    fn operator(&mut self) -> Result<Operator, QueryError> {
+        match self.next()? {
+            Token::Symbol(symbol) => symbol.parse(),
+            token => Err(QueryError(format!("Expected a comparison, found {}", token))),
+        }
+    }
+
+    /// `<column> <operator> <literal> [IGNORE CASE] [IGNORE ACCENTS]`.
+    fn condition(&mut self) -> Result<Condition, QueryError> {
+        let condition = Condition::new(self.column()?, self.operator()?, self.literal()?)?;
+        let mut text = TextMatch::default();
+        while self.eat("IGNORE") {
+            if self.eat("CASE") {
+                text.ignore_case = true;
+            } else {
+                self.expect("ACCENTS")?;
+                text.ignore_accents = true;
+            }
+        }
+        match text.is_exact() {
+            true => Ok(condition),
+            false => condition.with_text_match(text),
+        }
+    }
+
+    /// `<condition> [AND <condition> ...]`.
+    fn conditions(&mut self) -> Result<Vec<Condition>, QueryError> {
+        let mut conditions = vec![self.condition()?];
+        while self.eat("AND") {
+            conditions.push(self.condition()?);
+        }
+        Ok(conditions)
+    }
+
+    fn finish(&mut self) -> Result<(), QueryError> {
+        match self.at_end() {
+            true => Ok(()),
+            false => Err(QueryError(format!("Unexpected {}", self.next()?))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Item {
+    /// `*`: every column of the range.
+    All,
+    Column(u32),
+    /// `COUNT(*)` has no column.
+    Aggregate(Aggregate, Option<u32>),
+}
+
+/// `SELECT <items> FROM <range> [WHERE <condition> [AND ...]] [GROUP BY
+/// <columns>] [ORDER BY <item> [ASC|DESC]] [LIMIT <n>]`, where items are
+/// columns such as `A`, `*`, or aggregates such as `SUM(C)` and `COUNT(*)`.
+#[derive(Clone, Debug)]
+pub struct Query {
+    items: Vec<Item>,
+    from: String,
+    filter: Vec<Condition>,
+    group_by: Vec<u32>,
+    order_by: Option<(String, bool)>,
+    limit: Option<usize>,
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s)?;
+        parser.expect("SELECT")?;
+        let mut items = Vec::new();
+        loop {
+            items.push(if parser.eat("*") {
+                Item::All
+            } else {
+                let word = parser.word()?;
+                if parser.eat("(") {
+                    let aggregate = word.parse::<Aggregate>()?;
+                    let column = match parser.eat("*") {
+                        true if aggregate == Aggregate::Count => None,
+                        true => return Err(QueryError(format!("{}(*) is not supported", aggregate))),
+                        false => Some(parser.column()?),
+                    };
+                    parser.expect(")")?;
+                    Item::Aggregate(aggregate, column)
+                } else {
+                    Item::Column(column_index(&word).ok_or_else(|| QueryError(format!("Invalid column: {}", word)))?)
+                }
+            });
+            if !parser.eat(",") {
+                break;
+            }
+        }
+        parser.expect("FROM")?;
+        let from = parser.word()?;
+        let mut filter = Vec::new();
+        if parser.eat("WHERE") {
+            filter = parser.conditions()?;
+        }
+        let mut group_by = Vec::new();
+        if parser.eat("GROUP") {
+            parser.expect("BY")?;
+            group_by.push(parser.column()?);
+            while parser.eat(",") {
+                group_by.push(parser.column()?);
+            }
+        }
+        let mut order_by = None;
+        if parser.eat("ORDER") {
+            parser.expect("BY")?;
+            let mut label = parser.word()?;
+            if parser.eat("(") {
+                let aggregate = label.parse::<Aggregate>()?;
+                let column = if parser.eat("*") { "*".to_string() } else { parser.word()? };
+                parser.expect(")")?;
+                label = format!("{}({})", aggregate, column);
+            }
+            let descending = parser.eat("DESC");
+            if !descending {
+                parser.eat("ASC");
+            }
+            order_by = Some((label.to_ascii_uppercase(), descending));
+        }
+        let mut limit = None;
+        if parser.eat("LIMIT") {
+            limit = match parser.next()? {
+                Token::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+                token => return Err(QueryError(format!("Invalid limit: {}", token))),
+            };
+        }
+        parser.finish()?;
+        Ok(Query { items, from, filter, group_by, order_by, limit })
+    }
+}
+
+/// The filled cells of each row of `range` that has any, in row order,
+/// indexed by column from the range's first.
+pub fn rows(range: CellRange, snapshot: &Snapshot) -> Vec<(u32, Vec<Option<&CellValue>>)> {
+    let width = (range.end.col - range.start.col + 1) as usize;
+    let mut rows: Vec<(u32, Vec<Option<&CellValue>>)> = Vec::new();
+    for (addr, value) in snapshot.iter_range(range) {
+        if rows.last().map(|(row, _)| *row) != Some(addr.row) {
+            rows.push((addr.row, vec![None; width]));
+        }
+        rows.last_mut().unwrap().1[(addr.col - range.start.col) as usize] = Some(value);
+    }
+    rows
+}
+
+/// `WHERE <condition> [AND ...]`, as `filter` takes after its range.
+pub fn parse_where(text: &str) -> Result<Vec<Condition>, QueryError> {
+    let mut parser = Parser::new(text)?;
+    parser.expect("WHERE")?;
+    let conditions = parser.conditions()?;
+    parser.finish()?;
+    Ok(conditions)
+}
+
+/// The rows of `range` whose cells meet every condition, with empty cells as empty text.
+pub fn filter_rows(range: CellRange, snapshot: &Snapshot, conditions: &[Condition]) -> Result<Vec<RowMatch>, QueryError> {
+    if let Some(outside) = conditions.iter().find(|c| !(range.start.col..=range.end.col).contains(&c.column)) {
+        return Err(QueryError(format!("Column {} is outside {}", column_name(outside.column), range)));
+    }
+    let matches = rows(range, snapshot)
+        .into_iter()
+        .filter(|(_, row)| conditions.iter().all(|c| c.matches(row[(c.column - range.start.col) as usize])))
+        .map(|(row, values)| RowMatch {
+            cells: CellRange::new(CellAddress::new(range.start.col, row), CellAddress::new(range.end.col, row)),
+            values: values.into_iter().map(|value| value.cloned().unwrap_or_else(|| CellValue::Text(String::new()))).collect(),
+        })
+        .collect();
+    Ok(matches)
+}
+
+/// Where column `col` falls in each row of `range`.
+fn column_offset(range: CellRange, col: u32) -> Result<usize, QueryError> {
+    match (range.start.col..=range.end.col).contains(&col) {
+        true => Ok((col - range.start.col) as usize),
+        false => Err(QueryError(format!("Column {} is outside {}", column_name(col), range))),
+    }
+}
+
+/// `rows <column>[, <column> ...] [columns <column>] values <column> [agg
+/// <aggregate>]`, as `pivot` takes after its range: one row per distinct
+/// combination of the row keys and, with a column key, one column per
+/// distinct value of it, each holding the aggregate of the value column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pivot {
+    pub rows: Vec<u32>,
+    pub columns: Option<u32>,
+    pub values: u32,
+    pub aggregate: Aggregate,
+}
+
+impl FromStr for Pivot {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s)?;
+        parser.expect("ROWS")?;
+        let mut rows = vec![parser.column()?];
+        while parser.eat(",") {
+            rows.push(parser.column()?);
+        }
+        let columns = match parser.eat("COLUMNS") || parser.eat("COLS") {
+            true => Some(parser.column()?),
+            false => None,
+        };
+        parser.expect("VALUES")?;
+        let values = parser.column()?;
+        let aggregate = match parser.eat("AGG") {
+            true => parser.word()?.parse()?,
+            false => Aggregate::Sum,
+        };
+        parser.finish()?;
+        Ok(Pivot { rows, columns, values, aggregate })
+    }
+}
+
+impl Pivot {
+    /// Runs the pivot over `snapshot`, which holds the cells of `range`.
+    /// Keys are sorted, numbers first; a combination no row has is empty.
+    pub fn run(&self, range: CellRange, snapshot: &Snapshot) -> Result<Table, QueryError> {
+        let row_keys: Vec<usize> = self.rows.iter().map(|&col| column_offset(range, col)).collect::<Result<_, _>>()?;
+        let column_key = self.columns.map(|col| column_offset(range, col)).transpose()?;
+        let value = column_offset(range, self.values)?;
+        let empty = CellValue::Text(String::new());
+        let cell = |row: &[Option<&CellValue>], i: usize| row[i].cloned().unwrap_or_else(|| empty.clone());
+
+        let mut row_list: Vec<Vec<CellValue>> = Vec::new();
+        let mut column_list: Vec<CellValue> = Vec::new();
+        let mut seen_rows = std::collections::HashSet::new();
+        let mut seen_columns = std::collections::HashSet::new();
+        let mut totals: HashMap<(Vec<String>, String), Accumulator> = HashMap::new();
+        for (_, row) in rows(range, snapshot) {
+            let key: Vec<CellValue> = row_keys.iter().map(|&i| cell(&row, i)).collect();
+            let key_text: Vec<String> = key.iter().map(value_text).collect();
+            if seen_rows.insert(key_text.clone()) {
+                row_list.push(key);
+            }
+            let column = column_key.map_or(empty.clone(), |i| cell(&row, i));
+            let column_text = value_text(&column);
+            if seen_columns.insert(column_text.clone()) {
+                column_list.push(column);
+            }
+            let total = totals.entry((key_text, column_text)).or_insert_with(|| Accumulator::new(self.aggregate));
+            if let Some(value) = row[value] {
+                total.push(value);
+            }
+        }
+        row_list.sort_by(|a, b| a.iter().zip(b).map(|(a, b)| sort_order(a, b)).find(|o| o.is_ne()).unwrap_or(Ordering::Equal));
+        column_list.sort_by(sort_order);
+
+        let mut columns: Vec<String> = self.rows.iter().map(|&col| column_name(col)).collect();
+        match self.columns {
+            Some(_) => columns.extend(column_list.iter().map(value_text)),
+            None => columns.push(format!("{}({})", self.aggregate, column_name(self.values))),
+        }
+        let rows = row_list
+            .into_iter()
+            .map(|key| {
+                let key_text: Vec<String> = key.iter().map(value_text).collect();
+                let totals = column_list.iter().map(|column| match totals.get(&(key_text.clone(), value_text(column))) {
+                    Some(total) => total.finish(),
+                    None => empty.clone(),
+                });
+                key.iter().cloned().chain(totals).collect()
+            })
+            .collect();
+        Ok(Table { columns, rows })
+    }
+}
+
+/// `key <column>[, <column> ...] [agg <aggregate>] [value <column>]`, as
+/// `groupby` takes after its range: one row per distinct key with the
+/// aggregate of the value column, by default the range's last.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupBy {
+    pub keys: Vec<u32>,
+    pub aggregate: Aggregate,
+    pub values: Option<u32>,
+}
+
+impl FromStr for GroupBy {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s)?;
+        parser.expect("KEY")?;
+        let mut keys = vec![parser.column()?];
+        while parser.eat(",") {
+            keys.push(parser.column()?);
+        }
+        let aggregate = match parser.eat("AGG") {
+            true => parser.word()?.parse()?,
+            false => Aggregate::Sum,
+        };
+        let values = match parser.eat("VALUE") {
+            true => Some(parser.column()?),
+            false => None,
+        };
+        parser.finish()?;
+        Ok(GroupBy { keys, aggregate, values })
+    }
+}
+
+impl GroupBy {
+    /// A [`Pivot`] without a column key, so the keys come out sorted the same way.
+    pub fn run(&self, range: CellRange, snapshot: &Snapshot) -> Result<Table, QueryError> {
+        let values = self.values.unwrap_or(range.end.col);
+        Pivot { rows: self.keys.clone(), columns: None, values, aggregate: self.aggregate }.run(range, snapshot)
+    }
+}
+
+/// A row `dedupe` keeps: the row it was on and its values, indexed by
+/// column from the range's first.
+pub type Row = (u32, Vec<Option<CellValue>>);
+
+/// Which of a set of duplicate rows `dedupe` keeps.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Keep {
+    #[default]
+    First,
+    Last,
+}
+
+/// `by <column>[, <column> ...] [keep first|last]`, as `dedupe` takes after
+/// its range: rows with the same values in the key columns are duplicates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dedupe {
+    pub keys: Vec<u32>,
+    pub keep: Keep,
+}
+
+impl FromStr for Dedupe {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s)?;
+        parser.expect("BY")?;
+        let mut keys = vec![parser.column()?];
+        while parser.eat(",") {
+            keys.push(parser.column()?);
+        }
+        let keep = match parser.eat("KEEP") {
+            true => match parser.word()?.to_ascii_uppercase().as_str() {
+                "FIRST" => Keep::First,
+                "LAST" => Keep::Last,
+                word => return Err(QueryError(format!("Expected FIRST or LAST, found {}", word))),
+            },
+            false => Keep::First,
+        };
+        parser.finish()?;
+        Ok(Dedupe { keys, keep })
+    }
+}
+
+impl Dedupe {
+    /// The rows of `range` to keep, in row order, each with the row it was
+    /// on. Empty rows are neither kept nor counted as duplicates.
+    pub fn run(&self, range: CellRange, snapshot: &Snapshot) -> Result<Vec<Row>, QueryError> {
+        let keys: Vec<usize> = self.keys.iter().map(|&col| column_offset(range, col)).collect::<Result<_, _>>()?;
+        let mut rows = rows(range, snapshot);
+        if self.keep == Keep::Last {
+            rows.reverse();
+        }
+        let mut seen = std::collections::HashSet::new();
+        let mut kept: Vec<Row> = rows
+            .into_iter()
+            .filter(|(_, row)| {
+                let key: Vec<String> = keys.iter().map(|&i| row[i].map(value_text).unwrap_or_default()).collect();
+                seen.insert(key)
+            })
+            .map(|(at, row)| (at, row.into_iter().map(|value| value.cloned()).collect()))
+            .collect();
+        if self.keep == Keep::Last {
+            kept.reverse();
+        }
+        Ok(kept)
+    }
+}
+
+/// A selected item resolved against the range: offsets into each row.
+enum Output {
+    Cell(usize),
+    Aggregate(Aggregate, Option<usize>),
+}
+
+impl Query {
+    /// The range after `FROM`, as written.
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+
+    /// Runs the query over `snapshot`, which holds the cells of `range`.
+    pub fn run(&self, range: CellRange, snapshot: &Snapshot) -> Result<Table, QueryError> {
+        let offset = |col: u32| column_offset(range, col);
+        let mut columns = Vec::new();
+        let mut outputs = Vec::new();
+        for item in &self.items {
+            match item {
+                Item::All => {
+                    for col in range.start.col..=range.end.col {
+                        columns.push(column_name(col));
+                        outputs.push(Output::Cell((col - range.start.col) as usize));
+                    }
+                }
+                Item::Column(col) => {
+                    columns.push(column_name(*col));
+                    outputs.push(Output::Cell(offset(*col)?));
+                }
+                Item::Aggregate(aggregate, col) => {
+                    columns.push(format!("{}({})", aggregate, col.map_or("*".to_string(), column_name)));
+                    outputs.push(Output::Aggregate(*aggregate, col.map(offset).transpose()?));
+                }
+            }
+        }
+        let filter: Vec<(usize, &Condition)> =
+            self.filter.iter().map(|condition| Ok((offset(condition.column)?, condition))).collect::<Result<_, QueryError>>()?;
+        let group_by: Vec<usize> = self.group_by.iter().map(|&col| offset(col)).collect::<Result<_, _>>()?;
+        let aggregated = !group_by.is_empty() || outputs.iter().any(|output| matches!(output, Output::Aggregate(..)));
+        if aggregated {
+            for (output, column) in outputs.iter().zip(&columns) {
+                if matches!(output, Output::Cell(i) if !group_by.contains(i)) {
+                    return Err(QueryError(format!("{} must be aggregated or named in GROUP BY", column)));
+                }
+            }
+        }
+
+        let empty = CellValue::Text(String::new());
+        let cell = |row: &[Option<&CellValue>], i: usize| row[i].cloned().unwrap_or_else(|| empty.clone());
+        let matching = rows(range, snapshot)
+            .into_iter()
+            .map(|(_, row)| row)
+            .filter(|row| filter.iter().all(|(i, condition)| condition.matches(row[*i])));
+        let mut rows: Vec<Vec<CellValue>> = if !aggregated {
+            matching
+                .map(|row| {
+                    outputs
+                        .iter()
+                        .map(|output| match output {
+                            Output::Cell(i) => cell(&row, *i),
+                            Output::Aggregate(..) => unreachable!("aggregates are grouped"),
+                        })
+                        .collect()
+                })
+                .collect()
+        } else {
+            let accumulators = || {
+                outputs
+                    .iter()
+                    .filter_map(|output| match output {
+                        Output::Aggregate(aggregate, _) => Some(Accumulator::new(*aggregate)),
+                        Output::Cell(_) => None,
+                    })
+                    .collect::<Vec<_>>()
+            };
+            // Groups in the order their first row appears; without GROUP BY, one for every row.
+            let mut groups: Vec<(Vec<CellValue>, Vec<Accumulator>)> = Vec::new();
+            let mut index: HashMap<Vec<String>, usize> = HashMap::new();
+            if group_by.is_empty() {
+                groups.push((Vec::new(), accumulators()));
+                index.insert(Vec::new(), 0);
+            }
+            for row in matching {
+                let key: Vec<CellValue> = group_by.iter().map(|&i| cell(&row, i)).collect();
+                let text: Vec<String> = key.iter().map(value_text).collect();
+                let group = *index.entry(text).or_insert_with(|| {
+                    groups.push((key, accumulators()));
+                    groups.len() - 1
+                });
+                let sources = outputs.iter().filter_map(|output| match output {
+                    Output::Aggregate(_, col) => Some(*col),
+                    Output::Cell(_) => None,
+                });
+                for (accumulator, source) in groups[group].1.iter_mut().zip(sources) {
+                    match source {
+                        Some(i) => row[i].into_iter().for_each(|value| accumulator.push(value)),
+                        // COUNT(*) counts rows, filled or not.
+                        None => accumulator.push(&empty),
+                    }
+                }
+            }
+            groups
+                .into_iter()
+                .map(|(key, accumulators)| {
+                    let mut accumulators = accumulators.iter();
+                    outputs
+                        .iter()
+                        .map(|output| match output {
+                            Output::Cell(i) => key[group_by.iter().position(|g| g == i).unwrap()].clone(),
+                            Output::Aggregate(..) => accumulators.next().unwrap().finish(),
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+
+        if let Some((label, descending)) = &self.order_by {
+            let i = columns
+                .iter()
+                .position(|column| column == label)
+                .ok_or_else(|| QueryError(format!("ORDER BY {} is not a selected column", label)))?;
+            rows.sort_by(|a, b| match descending {
+                true => sort_order(&b[i], &a[i]),
+                false => sort_order(&a[i], &b[i]),
+            });
+        }
+        if let Some(limit) = self.limit {
+            rows.truncate(limit);
+        }
+        Ok(Table { columns, rows })
+    }
+}
+
+/// Numbers first, in numeric order, then money by currency and amount, then
+/// durations, times, text, errors and empty cells.
+pub fn sort_order(a: &CellValue, b: &CellValue) -> Ordering {
+    let rank = |value: &CellValue| match value {
+        CellValue::Number(_) => 0,
+        CellValue::Currency { .. } => 1,
+        CellValue::Duration(_) => 2,
+        CellValue::DateTime(_) => 3,
+        CellValue::Text(_) => 4,
+        CellValue::Error(_) => 5,
+        CellValue::Empty => 6,
+    };
+    match (a, b) {
+        (CellValue::Number(a), CellValue::Number(b)) => a.total_cmp(b),
+        (CellValue::Currency { amount: a, code }, CellValue::Currency { amount: b, code: other }) => {
+            code.cmp(other).then(a.total_cmp(b))
+        }
+        (CellValue::Duration(a), CellValue::Duration(b)) => a.total_cmp(b),
+        (CellValue::DateTime(a), CellValue::DateTime(b)) => a.cmp(b),
+        (CellValue::Text(a), CellValue::Text(b)) | (CellValue::Error(a), CellValue::Error(b)) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
@@ -0,0 +1,271 @@
+use crate::address::{parse_key, split_sheet, DEFAULT_SHEET};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+use web_time::Instant;
+
+/// Commands per user are counted over windows this long.
+const COMMAND_WINDOW: Duration = Duration::from_secs(60);
+
+/// How far any one sheet may grow. Unset limits are not enforced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SheetQuotas {
+    /// Rows a sheet may use, so `max_rows = 100` allows `A100` but not `A101`.
+    pub max_rows: Option<u32>,
+    /// Columns a sheet may use, so `max_cols = 26` allows `Z1` but not `AA1`.
+    pub max_cols: Option<u32>,
+    /// Cells holding a value in one sheet.
+    pub max_cells: Option<usize>,
+    /// Characters in a formula.
+    pub max_formula_len: Option<usize>,
+}
+
+/// Which quota a change would break.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaViolation {
+    Rows { cell: String, limit: u32 },
+    Columns { cell: String, limit: u32 },
+    Cells { sheet: String, limit: usize },
+    FormulaLength { cell: String, len: usize, limit: usize },
+    Memory { limit: u64, used: u64 },
+    UserCells { user: String, limit: usize },
+    UserBytes { user: String, limit: u64 },
+    UserCommands { user: String, limit: u32 },
+}
+
+impl fmt::Display for QuotaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaViolation::Rows { cell, limit } => write!(f, "{} is past the sheet's {} rows", cell, limit),
+            QuotaViolation::Columns { cell, limit } => write!(f, "{} is past the sheet's {} columns", cell, limit),
+            QuotaViolation::Cells { sheet, limit } => write!(f, "Sheet {} is limited to {} cells", sheet, limit),
+            QuotaViolation::FormulaLength { cell, len, limit } => {
+                write!(f, "Formula for {} is {} characters, over the limit of {}", cell, len, limit)
+            }
+            QuotaViolation::Memory { limit, used } => write!(f, "Memory limit of {} bytes reached ({} in use)", limit, used),
+            QuotaViolation::UserCells { user, limit } => write!(f, "User {} is limited to {} cells", user, limit),
+            QuotaViolation::UserBytes { user, limit } => write!(f, "User {} is limited to {} bytes of values", user, limit),
+            QuotaViolation::UserCommands { user, limit } => {
+                write!(f, "User {} is limited to {} commands a minute", user, limit)
+            }
+        }
+    }
+}
+
+impl SheetQuotas {
+    /// Checks where `cell` lies and, for a formula, how long `expr` is.
+    pub fn check_cell(&self, cell: &str, expr: Option<&str>) -> Result<(), QuotaViolation> {
+        if let Ok((_, addr)) = parse_key(cell) {
+            if let Some(limit) = self.max_rows.filter(|&limit| addr.row >= limit) {
+                return Err(QuotaViolation::Rows { cell: cell.to_string(), limit });
+            }
+            if let Some(limit) = self.max_cols.filter(|&limit| addr.col >= limit) {
+                return Err(QuotaViolation::Columns { cell: cell.to_string(), limit });
+            }
+        }
+        let len = expr.filter(|expr| expr.parse::<f64>().is_err()).map_or(0, |expr| expr.chars().count());
+        match self.max_formula_len {
+            Some(limit) if len > limit => Err(QuotaViolation::FormulaLength { cell: cell.to_string(), len, limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Cells holding a value in each sheet, kept as they change so `max_cells`
+/// is checked without scanning the sheet.
+#[derive(Debug, Default)]
+pub struct CellCounts {
+    sheets: Mutex<HashMap<String, usize>>,
+}
+
+impl CellCounts {
+    pub fn count<'a>(cells: impl IntoIterator<Item = &'a str>) -> Self {
+        let counts = CellCounts::default();
+        counts.reset(cells);
+        counts
+    }
+
+    /// Accounts for `cell` gaining or losing its value.
+    pub fn change(&self, cell: &str, had: bool, has: bool) {
+        if had == has {
+            return;
+        }
+        let mut sheets = self.sheets.lock().unwrap();
+        let count = sheets.entry(sheet_of(cell).to_string()).or_default();
+        if has {
+            *count += 1;
+        } else {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    pub fn reset<'a>(&self, cells: impl IntoIterator<Item = &'a str>) {
+        let mut sheets = self.sheets.lock().unwrap();
+        sheets.clear();
+        for cell in cells {
+            *sheets.entry(sheet_of(cell).to_string()).or_default() += 1;
+        }
+    }
+
+    /// Fails if adding `new` cells, which hold no value yet, takes a sheet past `limit`.
+    pub fn check<'a>(&self, limit: usize, new: impl IntoIterator<Item = &'a str>) -> Result<(), QuotaViolation> {
+        let mut adding: HashMap<&str, usize> = HashMap::new();
+        for cell in new {
+            *adding.entry(sheet_of(cell)).or_default() += 1;
+        }
+        let sheets = self.sheets.lock().unwrap();
+        match adding.into_iter().find(|(sheet, added)| sheets.get(*sheet).copied().unwrap_or(0) + added > limit) {
+            Some((sheet, _)) => Err(QuotaViolation::Cells { sheet: sheet.to_string(), limit }),
+            None => Ok(()),
+        }
+    }
+}
+
+fn sheet_of(cell: &str) -> &str {
+    split_sheet(cell).0.unwrap_or(DEFAULT_SHEET)
+}
+
+/// How much any one user may do. Unset limits are not enforced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserQuotas {
+    /// Cells a user may own, a cell belonging to whoever last set it.
+    pub max_cells: Option<usize>,
+    /// Estimated bytes of the values in the cells a user owns.
+    pub max_bytes: Option<u64>,
+    pub max_commands_per_minute: Option<u32>,
+}
+
+/// A user's quotas and how much of them is used, as `admin quota` shows them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserUsage {
+    pub user: String,
+    pub quotas: UserQuotas,
+    pub cells: usize,
+    pub bytes: u64,
+    /// Commands in the current minute.
+    pub commands: u32,
+}
+
+#[derive(Debug, Default)]
+struct Account {
+    /// Set by `admin quota`, over the defaults.
+    quotas: Option<UserQuotas>,
+    cells: usize,
+    bytes: u64,
+    window: Option<Instant>,
+    commands: u32,
+}
+
+#[derive(Debug, Default)]
+struct Ledger {
+    accounts: HashMap<String, Account>,
+    /// Each owned cell's user and the bytes charged to them for it.
+    owners: HashMap<String, (String, u64)>,
+}
+
+/// Who owns which cells and how busy each user is, checked against their
+/// quotas. Cells count from when the server starts: those loaded from a
+/// file belong to no one until they are set again.
+#[derive(Debug, Default)]
+pub struct UserAccounts {
+    defaults: UserQuotas,
+    ledger: Mutex<Ledger>,
+}
+
+impl UserAccounts {
+    pub fn new(defaults: UserQuotas) -> Self {
+        UserAccounts { defaults, ledger: Mutex::default() }
+    }
+
+    pub fn quotas(&self, user: &str) -> UserQuotas {
+        self.ledger.lock().unwrap().accounts.get(user).and_then(|account| account.quotas).unwrap_or(self.defaults)
+    }
+
+    /// Gives `user` their own quotas in place of the defaults.
+    pub fn set_quotas(&self, user: &str, quotas: UserQuotas) {
+        self.ledger.lock().unwrap().accounts.entry(user.to_string()).or_default().quotas = Some(quotas);
+    }
+
+    /// Counts a command from `user`, failing once they are past their commands a minute.
+    pub fn count_command(&self, user: &str) -> Result<(), QuotaViolation> {
+        let mut ledger = self.ledger.lock().unwrap();
+        let account = ledger.accounts.entry(user.to_string()).or_default();
+        let now = Instant::now();
+        if account.window.is_none_or(|start| now.duration_since(start) >= COMMAND_WINDOW) {
+            account.window = Some(now);
+            account.commands = 0;
+        }
+        let limit = account.quotas.unwrap_or(self.defaults).max_commands_per_minute;
+        match limit {
+            Some(limit) if account.commands >= limit => Err(QuotaViolation::UserCommands { user: user.to_string(), limit }),
+            _ => {
+                account.commands += 1;
+                Ok(())
+            }
+        }
+    }
+
+    /// Fails if `user` setting each cell to a value of the given bytes, or
+    /// deleting it for `None`, would take them past their cells or bytes.
+    pub fn check<'a>(&self, user: &str, writes: impl IntoIterator<Item = (&'a str, Option<u64>)>) -> Result<(), QuotaViolation> {
+        let ledger = self.ledger.lock().unwrap();
+        let account = ledger.accounts.get(user);
+        let quotas = account.and_then(|account| account.quotas).unwrap_or(self.defaults);
+        let (mut cells, mut bytes) = account.map_or((0, 0), |account| (account.cells as i64, account.bytes as i64));
+        let (mut grows_cells, mut grows_bytes) = (false, false);
+        for (cell, new) in writes {
+            let owned = ledger.owners.get(cell).filter(|(owner, _)| owner == user).map(|(_, bytes)| *bytes as i64);
+            let (cells_delta, bytes_delta) = match (owned, new) {
+                (Some(old), Some(new)) => (0, new as i64 - old),
+                (Some(old), None) => (-1, -old),
+                (None, Some(new)) => (1, new as i64),
+                (None, None) => (0, 0),
+            };
+            cells += cells_delta;
+            bytes += bytes_delta;
+            grows_cells |= cells_delta > 0;
+            grows_bytes |= bytes_delta > 0;
+        }
+        if let Some(limit) = quotas.max_cells.filter(|&limit| grows_cells && cells > limit as i64) {
+            return Err(QuotaViolation::UserCells { user: user.to_string(), limit });
+        }
+        match quotas.max_bytes {
+            Some(limit) if grows_bytes && bytes > limit as i64 => Err(QuotaViolation::UserBytes { user: user.to_string(), limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes `user` the owner of `cell`, now holding a value of `bytes`.
+    /// A deleted cell, or one set by no user, belongs to no one.
+    pub fn record(&self, user: Option<&str>, cell: &str, bytes: Option<u64>) {
+        let mut ledger = self.ledger.lock().unwrap();
+        if let Some((owner, old)) = ledger.owners.remove(cell) {
+            let account = ledger.accounts.entry(owner).or_default();
+            account.cells = account.cells.saturating_sub(1);
+            account.bytes = account.bytes.saturating_sub(old);
+        }
+        if let (Some(user), Some(bytes)) = (user, bytes) {
+            let account = ledger.accounts.entry(user.to_string()).or_default();
+            account.cells += 1;
+            account.bytes += bytes;
+            ledger.owners.insert(cell.to_string(), (user.to_string(), bytes));
+        }
+    }
+
+    pub fn usage(&self, user: &str) -> UserUsage {
+        let ledger = self.ledger.lock().unwrap();
+        let account = ledger.accounts.get(user);
+        let in_window = |account: &Account| account.window.is_some_and(|start| start.elapsed() < COMMAND_WINDOW);
+        UserUsage {
+            user: user.to_string(),
+            quotas: account.and_then(|account| account.quotas).unwrap_or(self.defaults),
+            cells: account.map_or(0, |account| account.cells),
+            bytes: account.map_or(0, |account| account.bytes),
+            commands: account.filter(|account| in_window(account)).map_or(0, |account| account.commands),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::address::{qualify, CellAddress, CellRange};
+use crate::store::CellStore;
+use crate::CellValue;
+use redis::Commands;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Hash fields fetched per `HMGET` when reading a range.
+const RANGE_BATCH: usize = 1024;
+
+/// Cell values in a Redis hash, so several servers can serve one sheet.
+///
+/// Every cell is a field of the hash `<prefix>:cells`, holding the value as
+/// JSON. Nothing is cached, so each server sees the others' writes as soon as
+/// they land. Formulas, watches and the write-ahead log stay per server.
+pub struct RedisStore {
+    conn: Mutex<redis::Connection>,
+    hash: String,
+}
+
+impl RedisStore {
+    /// Connects to `url`, e.g. `redis://127.0.0.1/`, using the `rsheet` key prefix.
+    pub fn open(url: &str) -> io::Result<Self> {
+        let conn = redis::Client::open(url).and_then(|client| client.get_connection()).map_err(to_io)?;
+        Ok(RedisStore { conn: Mutex::new(conn), hash: "rsheet:cells".to_string() })
+    }
+
+    /// Keeps cells under `<prefix>:cells`, so several sheets can share one Redis.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.hash = format!("{}:cells", prefix);
+        self
+    }
+}
+
+impl CellStore for RedisStore {
+    fn get(&self, key: &str) -> Option<Arc<CellValue>> {
+        let json: Option<String> = self.conn.lock().unwrap().hget(&self.hash, key).unwrap_or_else(|e| {
+            tracing::error!(cell = key, error = %e, "failed to read cell");
+            None
+        });
+        json.and_then(|json| serde_json::from_str(&json).ok()).map(Arc::new)
+    }
+
+    fn set(&mut self, key: &str, value: CellValue) -> io::Result<Option<CellValue>> {
+        let json = serde_json::to_string(&value)?;
+        let (old,): (Option<String>,) = redis::pipe()
+            .atomic()
+            .hget(&self.hash, key)
+            .hset(&self.hash, key, json)
+            .ignore()
+            .query(self.conn.get_mut().unwrap())
+            .map_err(to_io)?;
+        Ok(old.and_then(|json| serde_json::from_str(&json).ok()))
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<Option<CellValue>> {
+        let (old,): (Option<String>,) = redis::pipe()
+            .atomic()
+            .hget(&self.hash, key)
+            .hdel(&self.hash, key)
+            .ignore()
+            .query(self.conn.get_mut().unwrap())
+            .map_err(to_io)?;
+        Ok(old.and_then(|json| serde_json::from_str(&json).ok()))
+    }
+
+    fn iter_range<'a>(
+        &'a self,
+        sheet: Option<&'a str>,
+        range: CellRange,
+    ) -> Box<dyn Iterator<Item = (CellAddress, CellValue)> + 'a> {
+        let addrs: Vec<CellAddress> = range.iter().collect();
+        let mut cells = Vec::new();
+        for batch in addrs.chunks(RANGE_BATCH) {
+            let keys: Vec<String> = batch.iter().map(|addr| qualify(sheet, &addr.to_string())).collect();
+            let hmget = redis::cmd("HMGET").arg(&self.hash).arg(&keys).query(&mut *self.conn.lock().unwrap());
+            let values: Vec<Option<String>> = match hmget {
+                Ok(values) => values,
+                Err(e) => {
+                    tracing::error!(%range, error = %e, "failed to read range");
+                    return Box::new(std::iter::empty());
+                }
+            };
+            cells.extend(batch.iter().zip(values).filter_map(|(addr, json)| {
+                Some((*addr, serde_json::from_str(&json?).ok()?))
+            }));
+        }
+        Box::new(cells.into_iter())
+    }
+
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_> {
+        let cells: HashMap<String, String> = self.conn.lock().unwrap().hgetall(&self.hash).unwrap_or_else(|e| {
+            tracing::error!(error = %e, "failed to read cells");
+            HashMap::new()
+        });
+        Box::new(cells.into_iter().filter_map(|(key, json)| Some((key, serde_json::from_str(&json).ok()?))))
+    }
+
+    fn len(&self) -> usize {
+        self.conn.lock().unwrap().hlen(&self.hash).unwrap_or_else(|e| {
+            tracing::error!(error = %e, "failed to count cells");
+            0
+        })
+    }
+
+    fn replace_all(&mut self, cells: HashMap<String, CellValue>) -> io::Result<()> {
+        let fields = cells
+            .iter()
+            .map(|(key, value)| Ok((key.as_str(), serde_json::to_string(value)?)))
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&self.hash).ignore();
+        if !fields.is_empty() {
+            pipe.hset_multiple(&self.hash, &fields).ignore();
+        }
+        pipe.query::<()>(self.conn.get_mut().unwrap()).map_err(to_io)
+    }
+}
+
+fn to_io(e: redis::RedisError) -> io::Error {
+    io::Error::other(e)
+}
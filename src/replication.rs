@@ -0,0 +1,184 @@
+use crate::connect::SharedWriter;
+use crate::wal::WalEntry;
+use crate::workbook::Workbook;
+use crate::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+#[cfg(feature = "net")]
+use {
+    crate::connect::{Reader, Writer},
+    crate::RSheet,
+    std::net::{Shutdown, TcpStream},
+    std::sync::{Arc, Condvar},
+    std::thread::JoinHandle,
+    std::time::Duration,
+};
+
+/// How long a replica waits before reconnecting to a primary it lost.
+#[cfg(feature = "net")]
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a replica's connection may sit quiet before it pings the
+/// primary, so an idle timeout there does not drop it.
+#[cfg(feature = "net")]
+pub const KEEPALIVE: Duration = Duration::from_secs(5);
+
+/// What a primary pushes to a replica: one snapshot, then every change in
+/// the order it was logged. Sequence numbers let a replica notice a gap.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    /// Every cell and formula as of change `seq`.
+    Snapshot { seq: u64, workbook: Workbook },
+    /// The change logged as `seq`.
+    Change { seq: u64, entry: WalEntry },
+}
+
+impl Event {
+    pub fn seq(&self) -> u64 {
+        match self {
+            Event::Snapshot { seq, .. } | Event::Change { seq, .. } => *seq,
+        }
+    }
+}
+
+/// Replicas following this server, each sent every change as it is logged.
+#[derive(Default)]
+pub struct Replicas {
+    followers: Mutex<HashMap<u64, SharedWriter>>,
+    /// Sequence number of the last change published.
+    seq: AtomicU64,
+}
+
+impl Replicas {
+    /// Sends `workbook` to a new replica and streams it changes from then
+    /// on. The caller holds the cells so no change lands in between.
+    pub fn add(&self, connection: u64, writer: SharedWriter, workbook: Workbook) -> Result<(), Box<dyn Error>> {
+        let mut followers = self.followers.lock().unwrap();
+        let seq = self.seq.load(Ordering::SeqCst);
+        writer.lock().unwrap().send(&Message::Replicate(Event::Snapshot { seq, workbook }))?;
+        followers.insert(connection, writer);
+        tracing::info!(connection, seq, "replica attached");
+        Ok(())
+    }
+
+    pub fn remove(&self, connection: u64) {
+        if self.followers.lock().unwrap().remove(&connection).is_some() {
+            tracing::info!(connection, "replica detached");
+        }
+    }
+
+    /// Streams `entry` to every replica, dropping any that cannot be reached;
+    /// a dropped replica resyncs from a fresh snapshot when it reconnects.
+    pub fn publish(&self, entry: &WalEntry) {
+        let mut followers = self.followers.lock().unwrap();
+        if followers.is_empty() {
+            return;
+        }
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let msg = Message::Replicate(Event::Change { seq, entry: entry.clone() });
+        followers.retain(|connection, writer| match writer.lock().unwrap().send(&msg) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!(connection, error = %e, "failed to stream to replica, dropping it");
+                false
+            }
+        });
+    }
+}
+
+/// Background thread that keeps a replica in step with its primary,
+/// reconnecting and resyncing whenever the connection drops. It stops on
+/// its own once the replica is promoted.
+#[cfg(feature = "net")]
+pub struct Follower {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    /// The live connection, shut down to unblock a read on [`Follower::stop`].
+    stream: Arc<Mutex<Option<TcpStream>>>,
+    thread: JoinHandle<()>,
+}
+
+#[cfg(feature = "net")]
+impl Follower {
+    pub fn start(rsheet: Arc<RSheet>, primary: impl Into<String>) -> Self {
+        let primary = primary.into();
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let stream = Arc::new(Mutex::new(None));
+        let thread_stop = Arc::clone(&stop);
+        let thread_stream = Arc::clone(&stream);
+        let thread = std::thread::spawn(move || loop {
+            match follow(&rsheet, &primary, &thread_stop, &thread_stream) {
+                Ok(()) => break,
+                Err(e) => tracing::warn!(%primary, error = %e, "lost the primary, reconnecting"),
+            }
+            let (stopped, wake) = &*thread_stop;
+            let stopped = wake.wait_timeout_while(stopped.lock().unwrap(), RECONNECT_DELAY, |stopped| !*stopped).unwrap().0;
+            if *stopped {
+                break;
+            }
+        });
+        Follower { stop, stream, thread }
+    }
+
+    pub fn stop(self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        wake.notify_all();
+        if let Some(stream) = self.stream.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let _ = self.thread.join();
+    }
+}
+
+/// Applies what `primary` streams until the connection fails. `Ok` means
+/// the follower should stop: it was asked to, or the replica was promoted.
+#[cfg(feature = "net")]
+fn follow(
+    rsheet: &RSheet,
+    primary: &str,
+    stop: &(Mutex<bool>, Condvar),
+    current: &Mutex<Option<TcpStream>>,
+) -> Result<(), Box<dyn Error>> {
+    let stream = TcpStream::connect(primary)?;
+    stream.set_read_timeout(Some(KEEPALIVE))?;
+    *current.lock().unwrap() = Some(stream.try_clone()?);
+    if *stop.0.lock().unwrap() {
+        return Ok(());
+    }
+    let mut reader = Reader::new(stream.try_clone()?);
+    let mut writer = Writer::new(stream);
+    writer.send(&Message::Command(crate::command::Command::Replicate))?;
+    let mut applied: Option<u64> = None;
+    loop {
+        if !rsheet.is_replica() {
+            tracing::info!("promoted, no longer following the primary");
+            return Ok(());
+        }
+        let event = match reader.read_message() {
+            Ok(Message::Replicate(event)) => event,
+            Ok(Message::Reply(crate::replies::Reply::Error(e))) => return Err(Box::new(e)),
+            Ok(_) => continue,
+            Err(e) if e.is_timeout() => {
+                writer.send(&Message::Ping)?;
+                continue;
+            }
+            Err(e) if *stop.0.lock().unwrap() => {
+                tracing::debug!(error = %e, "replica connection closed");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Event::Change { seq, .. } = &event {
+            match applied {
+                Some(last) if *seq == last + 1 => {}
+                _ => return Err(format!("missed changes before {}, resyncing", seq).into()),
+            }
+        }
+        let seq = event.seq();
+        rsheet.apply_replicated(event)?;
+        applied = Some(seq);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::address::{split_sheet, CellAddress, CellRange, DEFAULT_SHEET, MAX_COLS, MAX_ROWS};
+use regex::{Captures, Regex};
+
+/// How `transpose` writes cells that hold formulas.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TransposeMode {
+    /// The formula's current value, as a literal.
+    #[default]
+    Values,
+    /// The formula itself, its references into the source range moved to
+    /// where those cells land.
+    Formulas,
+}
+
+impl std::str::FromStr for TransposeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "values" => Ok(TransposeMode::Values),
+            "formulas" => Ok(TransposeMode::Formulas),
+            _ => Err(format!("Transpose mode must be values or formulas: {}", s)),
+        }
+    }
+}
+
+/// A block of one sheet turned so its rows become columns, its top left
+/// cell landing on `anchor`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transposition {
+    pub sheet: Option<String>,
+    pub range: CellRange,
+    pub target_sheet: Option<String>,
+    pub anchor: CellAddress,
+}
+
+impl Transposition {
+    /// Fails if the turned block would run off the sheet.
+    pub fn new(sheet: Option<&str>, range: CellRange, target_sheet: Option<&str>, anchor: CellAddress) -> Result<Self, String> {
+        let width = range.end.col - range.start.col;
+        let height = range.end.row - range.start.row;
+        let fits = anchor.col.checked_add(height).is_some() && anchor.row.checked_add(width).is_some_and(|row| row < MAX_ROWS);
+        if !fits {
+            return Err(format!("Transposed range does not fit below and right of {}", anchor));
+        }
+        Ok(Transposition {
+            sheet: sheet.map(str::to_string),
+            range,
+            target_sheet: target_sheet.map(str::to_string),
+            anchor,
+        })
+    }
+
+    /// Where the source cell `addr` lands.
+    pub fn target(&self, addr: CellAddress) -> CellAddress {
+        CellAddress::new(self.anchor.col + (addr.row - self.range.start.row), self.anchor.row + (addr.col - self.range.start.col))
+    }
+
+    /// The cells written, from the anchor to the far corner.
+    pub fn target_range(&self) -> CellRange {
+        CellRange::new(self.anchor, self.target(self.range.end))
+    }
+
+    /// `expr`, a formula whose unqualified references resolve against
+    /// `formula_sheet`, as it reads moved to the target sheet: references
+    /// wholly inside the source range follow their cells, and the rest
+    /// still point where they did. Quoted text is left alone.
+    pub fn rewrite(&self, expr: &str, formula_sheet: Option<&str>) -> String {
+        let reference = Regex::new(r"[\w!]+(?::[\w!]+)?").unwrap();
+        expr.split('"')
+            .enumerate()
+            .map(|(i, part)| {
+                if i % 2 == 1 {
+                    return part.to_string();
+                }
+                reference.replace_all(part, |caps: &Captures| self.rewrite_reference(&caps[0], formula_sheet)).into_owned()
+            })
+            .collect::<Vec<_>>()
+            .join("\"")
+    }
+
+    fn rewrite_reference(&self, reference: &str, formula_sheet: Option<&str>) -> String {
+        let (sheet, rest) = split_sheet(reference);
+        let sheet = if reference.contains('!') { sheet } else { formula_sheet };
+        let Ok(range) = rest.parse::<CellRange>() else {
+            return reference.to_string();
+        };
+        if sheet == self.sheet.as_deref() && self.range.contains(&range.start) && self.range.contains(&range.end) {
+            let moved = if rest.contains(':') {
+                let moved = CellRange::new(self.target(range.start), self.target(range.end));
+                format!("{}:{}", moved.start, moved.end)
+            } else {
+                self.target(range.start).to_string()
+            };
+            return self.seen_from_target(self.target_sheet.as_deref(), &moved);
+        }
+        if reference.contains('!') {
+            return reference.to_string();
+        }
+        self.seen_from_target(sheet, rest)
+    }
+
+    /// `reference`, on `sheet`, written so that it still means that sheet
+    /// from a formula on the target sheet.
+    fn seen_from_target(&self, sheet: Option<&str>, reference: &str) -> String {
+        if sheet == self.target_sheet.as_deref() {
+            reference.to_string()
+        } else {
+            format!("{}!{}", sheet.unwrap_or(DEFAULT_SHEET), reference)
+        }
+    }
+}
+
+/// Which way `shift` moves a block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl std::str::FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "up" => Ok(Direction::Up),
+            "down" => Ok(Direction::Down),
+            "left" => Ok(Direction::Left),
+            "right" => Ok(Direction::Right),
+            _ => Err(format!("Direction must be up, down, left or right: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Direction::Up => "up",
+            Direction::Down => "down",
+            Direction::Left => "left",
+            Direction::Right => "right",
+        })
+    }
+}
+
+/// A block of one sheet moved `by` cells one way, over whatever was there.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shift {
+    pub sheet: Option<String>,
+    pub range: CellRange,
+    pub direction: Direction,
+    pub by: u32,
+}
+
+impl Shift {
+    /// Fails if the block would move off the sheet.
+    pub fn new(sheet: Option<&str>, range: CellRange, direction: Direction, by: u32) -> Result<Self, String> {
+        let fits = match direction {
+            Direction::Up => range.start.row >= by,
+            Direction::Left => range.start.col >= by,
+            Direction::Down => range.end.row.checked_add(by).is_some_and(|row| row < MAX_ROWS),
+            Direction::Right => range.end.col.checked_add(by).is_some_and(|col| col < MAX_COLS),
+        };
+        if !fits {
+            return Err(format!("{} moved {} by {} would leave the sheet", range, direction, by));
+        }
+        Ok(Shift { sheet: sheet.map(str::to_string), range, direction, by })
+    }
+
+    /// Where the block's cell `addr` lands.
+    pub fn target(&self, addr: CellAddress) -> CellAddress {
+        match self.direction {
+            Direction::Up => CellAddress::new(addr.col, addr.row - self.by),
+            Direction::Down => CellAddress::new(addr.col, addr.row + self.by),
+            Direction::Left => CellAddress::new(addr.col - self.by, addr.row),
+            Direction::Right => CellAddress::new(addr.col + self.by, addr.row),
+        }
+    }
+
+    /// Where the block lands.
+    pub fn target_range(&self) -> CellRange {
+        CellRange::new(self.target(self.range.start), self.target(self.range.end))
+    }
+
+    /// `expr`, a formula whose unqualified references resolve against
+    /// `formula_sheet`, with references wholly inside the block moved along
+    /// with its cells. Other references, and quoted text, are left alone.
+    pub fn rewrite(&self, expr: &str, formula_sheet: Option<&str>) -> String {
+        let reference = Regex::new(r"[\w!]+(?::[\w!]+)?").unwrap();
+        expr.split('"')
+            .enumerate()
+            .map(|(i, part)| {
+                if i % 2 == 1 {
+                    return part.to_string();
+                }
+                reference.replace_all(part, |caps: &Captures| self.rewrite_reference(&caps[0], formula_sheet)).into_owned()
+            })
+            .collect::<Vec<_>>()
+            .join("\"")
+    }
+
+    fn rewrite_reference(&self, reference: &str, formula_sheet: Option<&str>) -> String {
+        let (sheet, rest) = split_sheet(reference);
+        let sheet = if reference.contains('!') { sheet } else { formula_sheet };
+        let Ok(range) = rest.parse::<CellRange>() else {
+            return reference.to_string();
+        };
+        if sheet != self.sheet.as_deref() || !self.range.contains(&range.start) || !self.range.contains(&range.end) {
+            return reference.to_string();
+        }
+        // The sheet stays as it was written, if it was.
+        let prefix = &reference[..reference.len() - rest.len()];
+        if rest.contains(':') {
+            format!("{}{}:{}", prefix, self.target(range.start), self.target(range.end))
+        } else {
+            format!("{}{}", prefix, self.target(range.start))
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use crate::CellValue;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A what-if layer, from `scenario create`: values that stand in for the
+/// stored ones when a cell is evaluated in the scenario, so scenarios can be
+/// compared without touching the sheet. Cells are storage keys such as `B2`
+/// or `Budget!B2`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Scenario {
+    pub overrides: BTreeMap<String, CellValue>,
+}
+
+impl Scenario {
+    /// The overrides as `(cell, value)` pairs, in cell order.
+    pub fn pairs(&self) -> Vec<(String, CellValue)> {
+        self.overrides.iter().map(|(cell, value)| (cell.clone(), value.clone())).collect()
+    }
+}
+
+/// Each override as `cell=value`, e.g. `B2=1.2 Budget!C4=0`.
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let overrides: Vec<String> = self.overrides.iter().map(|(cell, value)| format!("{}={}", cell, value)).collect();
+        f.write_str(&overrides.join(" "))
+    }
+}
+
+/// A scenario in the `scenarios` listing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NamedScenario {
+    pub name: String,
+    pub scenario: Scenario,
+}
@@ -0,0 +1,218 @@
+use chrono::{DateTime, Datelike, Timelike};
+use regex::Regex;
+use std::str::FromStr;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    crate::RSheet,
+    std::sync::{Arc, Condvar, Mutex},
+    std::thread::JoinHandle,
+    web_time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Functions whose value changes without any cell changing, so a formula
+/// calling one is only current until it is evaluated again.
+pub const VOLATILE_FUNCTIONS: &[&str] = &["NOW", "RAND", "FETCH"];
+
+/// Whether `expr` calls a volatile function.
+pub fn is_volatile(expr: &str) -> bool {
+    let names = VOLATILE_FUNCTIONS.join("|");
+    Regex::new(&format!(r"(?i)\b(?:{})\(", names)).unwrap().is_match(expr)
+}
+
+/// When volatile cells are recalculated: `every 30s` (or `m`, `h`), or a
+/// five-field cron expression (`*/5 * * * *`) read in UTC.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Schedule {
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// The first time the schedule fires after `after`, in milliseconds since the Unix epoch.
+    pub fn next_after(&self, after: u64) -> u64 {
+        match self {
+            Schedule::Every(interval) => after + interval.as_millis().max(1) as u64,
+            Schedule::Cron(cron) => cron.next_after(after),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(interval) = s.strip_prefix("every ") {
+            let interval = interval.trim();
+            let (count, unit) = interval.split_at(interval.find(|c: char| !c.is_ascii_digit()).unwrap_or(interval.len()));
+            let count = count.parse::<u64>().ok().filter(|&n| n > 0).ok_or_else(|| format!("Invalid interval: {}", interval))?;
+            return match unit {
+                "s" => Ok(Schedule::Every(Duration::from_secs(count))),
+                "m" => Ok(Schedule::Every(Duration::from_secs(count * 60))),
+                "h" => Ok(Schedule::Every(Duration::from_secs(count * 3600))),
+                _ => Err(format!("Interval must end in s, m or h: {}", interval)),
+            };
+        }
+        s.parse().map(Schedule::Cron)
+    }
+}
+
+/// Whether volatile cells recalculate by themselves.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Recalc {
+    /// Only when [`crate::RSheet::recalculate_volatile`] is called.
+    #[default]
+    Manual,
+    /// As often as `FETCH` cells refresh.
+    Auto,
+    On(Schedule),
+}
+
+/// `minute hour day-of-month month day-of-week`, each `*`, a number, a
+/// range `a-b`, a step `*/n` or `a-b/n`, or a comma-separated list of these.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// As in cron, when both day fields are restricted either one matching will do.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// How far ahead [`Cron::next_after`] looks before giving up.
+const CRON_HORIZON_MINUTES: u64 = 366 * 24 * 60 * 4;
+
+impl Cron {
+    fn matches(&self, time: &DateTime<chrono::Utc>) -> bool {
+        let bit = |set: u64, n: u32| set & (1 << n) != 0;
+        let day = bit(self.days, time.day());
+        let weekday = bit(self.weekdays, time.weekday().num_days_from_sunday());
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && bit(self.minutes, time.minute()) && bit(self.hours, time.hour()) && bit(self.months, time.month())
+    }
+
+    fn next_after(&self, after: u64) -> u64 {
+        let first = after / 60_000 + 1;
+        for minute in first..first + CRON_HORIZON_MINUTES {
+            if let Some(time) = DateTime::from_timestamp(minute as i64 * 60, 0) {
+                if self.matches(&time) {
+                    return minute * 60_000;
+                }
+            }
+        }
+        // Only an impossible date such as `0 0 31 2 *` gets here.
+        u64::MAX
+    }
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("Cron expression needs five fields: {}", s));
+        };
+        let mut weekday_set = cron_field(weekdays, 0, 7)?;
+        if weekday_set & (1 << 7) != 0 {
+            // 7 is Sunday too.
+            weekday_set |= 1;
+        }
+        Ok(Cron {
+            minutes: cron_field(minutes, 0, 59)?,
+            hours: cron_field(hours, 0, 23)?,
+            days: cron_field(days, 1, 31)?,
+            months: cron_field(months, 1, 12)?,
+            weekdays: weekday_set,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+/// The values a cron field allows, as a bit set.
+fn cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("Invalid cron field: {}", field);
+    let mut set = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&n| n > 0).ok_or_else(invalid)?),
+            None => (item, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse().map_err(|_| invalid())?, end.parse().map_err(|_| invalid())?),
+                None => {
+                    let n = range.parse().map_err(|_| invalid())?;
+                    (n, n)
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for n in (start..=end).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+/// Background thread that recalculates volatile cells on a [`Schedule`],
+/// pushing any that change to subscribers like any other change. Not on
+/// wasm32, which has no threads; call [`crate::RSheet::recalculate_volatile`]
+/// from a timer there instead.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Scheduler {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: JoinHandle<()>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Scheduler {
+    pub fn start(rsheet: Arc<RSheet>, schedule: Schedule) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = Arc::clone(&stop);
+        let thread = std::thread::spawn(move || {
+            let mut due = schedule.next_after(now_ms());
+            loop {
+                let (stopped, wake) = &*thread_stop;
+                let wait = Duration::from_millis(due.saturating_sub(now_ms()));
+                let stopped = wake.wait_timeout(stopped.lock().unwrap(), wait).unwrap().0;
+                if *stopped {
+                    break;
+                }
+                drop(stopped);
+
+                let now = now_ms();
+                if now < due {
+                    continue;
+                }
+                let changed = rsheet.recalculate_volatile();
+                tracing::debug!(changed, "recalculated volatile cells");
+                due = schedule.next_after(now);
+            }
+        });
+        Scheduler { stop, thread }
+    }
+
+    pub fn stop(self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        wake.notify_all();
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
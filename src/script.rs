@@ -0,0 +1,59 @@
+use crate::command::Command;
+use crate::replies::{ErrorCode, ReplyError};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Why a script stopped. Nothing it set or deleted is kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptError {
+    /// The one-based line that failed; 0 if the commit at the end did.
+    pub line: usize,
+    pub error: ReplyError,
+}
+
+impl ScriptError {
+    /// As the `run` command answers it: the line's error, saying which line.
+    pub fn into_reply_error(self) -> ReplyError {
+        ReplyError { message: self.to_string(), ..self.error }
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "commit: {}", self.error.message),
+            line => write!(f, "line {}: {}", line, self.error.message),
+        }
+    }
+}
+
+impl Error for ScriptError {}
+
+/// `path` made absolute, if it is inside one of `dirs`. A relative path is
+/// looked for in each directory in turn.
+pub fn resolve(dirs: &[PathBuf], path: &str) -> Result<PathBuf, ReplyError> {
+    for dir in dirs {
+        // Both sides are canonical, so `..` and links cannot lead outside.
+        let (Ok(dir), Ok(file)) = (dir.canonicalize(), dir.join(Path::new(path)).canonicalize()) else {
+            continue;
+        };
+        if file.starts_with(&dir) {
+            return Ok(file);
+        }
+    }
+    Err(ReplyError::new(ErrorCode::Unauthorized, format!("{} is not in a script directory", path)))
+}
+
+/// Whether a script line is blank or a `#` comment.
+pub fn is_skipped(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with('#')
+}
+
+/// Whether `command` may run in a script or macro. Only sets and deletes
+/// wait for the commit, so nothing else that writes may.
+pub fn allows(command: &Command) -> bool {
+    let held = matches!(command, Command::Set { .. } | Command::Delete { .. });
+    !matches!(command, Command::Rollback) && (held || !command.is_write())
+}
@@ -0,0 +1,437 @@
+use super::*;
+use std::net::{Shutdown, TcpStream};
+use std::sync::Condvar;
+use std::thread::JoinHandle;
+
+/// Sockets of the currently open connections, keyed by connection id.
+#[derive(Default)]
+struct Connections {
+    open: Mutex<HashMap<u64, TcpStream>>,
+    slot_freed: Condvar,
+}
+
+/// The writers of open connections that coalesce, flushed every window so
+/// a queued push waits no longer than that.
+struct Flusher {
+    window: Duration,
+    writers: Mutex<HashMap<u64, connect::SharedWriter>>,
+}
+
+/// State shared by every listener's acceptor thread.
+struct ServerShared {
+    rsheet: Arc<RSheet>,
+    shutting_down: AtomicBool,
+    connections: Connections,
+    /// Connection threads by connection id; each removes its own on exit,
+    /// so only live connections are held.
+    workers: Mutex<HashMap<u64, JoinHandle<()>>>,
+    next_id: AtomicU64,
+    idle_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    overload_policy: connect::OverloadPolicy,
+    options: ConnectionOptions,
+    /// Runs commands when the manager asks for a bounded number of workers.
+    pool: Option<pool::WorkerPool>,
+    /// Serves connections when the manager bounds connection threads.
+    connection_pool: Option<pool::WorkerPool>,
+    flusher: Option<Flusher>,
+}
+
+/// Every configured address failed to bind.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to bind any address:{}", bind_failures(.failures))]
+pub struct BindError {
+    pub failures: Vec<(String, std::io::Error)>,
+}
+
+fn bind_failures(failures: &[(String, std::io::Error)]) -> String {
+    failures.iter().map(|(address, e)| format!(" {} ({})", address, e)).collect()
+}
+
+/// Handle to a running server returned by [`start_server`].
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    bind_errors: Vec<(String, std::io::Error)>,
+    shared: Arc<ServerShared>,
+    acceptors: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The first address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// Configured addresses that could not be bound while others succeeded.
+    pub fn bind_errors(&self) -> &[(String, std::io::Error)] {
+        &self.bind_errors
+    }
+
+    /// Stops accepting new clients and asks every open connection to finish.
+    /// A command that is already being handled still gets its reply; the
+    /// connection is closed before the next one is read.
+    pub fn shutdown(&self) {
+        if self.shared.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        tracing::info!(addrs = ?self.local_addrs, "shutting down server");
+        // Wake the acceptors so they notice the flag, whether they are blocked
+        // in accept or waiting for a free slot.
+        self.shared.connections.slot_freed.notify_all();
+        for addr in self.local_addrs.iter().chain(&self.metrics_addr) {
+            let _ = TcpStream::connect(addr);
+        }
+        for socket in self.shared.connections.open.lock().unwrap().values() {
+            let _ = socket.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Blocks until the acceptors have exited and every connection has finished.
+    pub fn join(mut self) {
+        for acceptor in self.acceptors.drain(..) {
+            let _ = acceptor.join();
+        }
+        let workers: Vec<_> = self.shared.workers.lock().unwrap().drain().map(|(_, worker)| worker).collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+        if let Some(pool) = &self.shared.connection_pool {
+            pool.shutdown();
+        }
+        if let Some(pool) = &self.shared.pool {
+            pool.shutdown();
+        }
+    }
+}
+
+/// Binds every address from `manager` and serves each on its own acceptor thread.
+/// Fails only if no address could be bound; partial failures are reported by
+/// [`ServerHandle::bind_errors`].
+pub fn start_server<M>(rsheet: Arc<RSheet>, manager: M) -> Result<ServerHandle, RSheetError>
+where
+    M: connect::Manager + Sync,
+{
+    let mut listeners = Vec::new();
+    let mut bind_errors = Vec::new();
+    for address in manager.addresses() {
+        match std::net::TcpListener::bind(&address) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                tracing::warn!(address, error = %e, "failed to bind");
+                bind_errors.push((address, e));
+            }
+        }
+    }
+    if listeners.is_empty() {
+        return Err(BindError { failures: bind_errors }.into());
+    }
+    let local_addrs = listeners.iter().map(|l| l.local_addr()).collect::<Result<Vec<_>, _>>()?;
+
+    let shared = Arc::new(ServerShared {
+        rsheet,
+        shutting_down: AtomicBool::new(false),
+        connections: Connections::default(),
+        workers: Mutex::new(HashMap::new()),
+        next_id: AtomicU64::new(0),
+        idle_timeout: manager.idle_timeout(),
+        // A connection past the pool's size would wait for a thread with no reply,
+        // so the pool bounds connections and the overload policy handles the rest.
+        max_connections: match (manager.max_connections(), manager.connection_threads()) {
+            (Some(max), Some(threads)) => Some(max.min(threads)),
+            (max, threads) => max.or(threads),
+        },
+        overload_policy: manager.overload_policy(),
+        options: ConnectionOptions {
+            rate_limit: manager.rate_limit(),
+            max_frame_size: manager.max_frame_size(),
+            protocol_error_policy: manager.protocol_error_policy(),
+            command_timeout: manager.command_timeout(),
+        },
+        pool: manager.command_workers().map(pool::WorkerPool::new),
+        connection_pool: manager.connection_threads().map(pool::WorkerPool::new),
+        flusher: manager.coalesce_window().map(|window| Flusher { window, writers: Mutex::default() }),
+    });
+
+    let mut acceptors: Vec<JoinHandle<()>> = listeners
+        .into_iter()
+        .map(|listener| {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || accept_loop(&shared, listener))
+        })
+        .collect();
+
+    let metrics_addr = match manager.metrics_address() {
+        Some(address) => {
+            let listener = std::net::TcpListener::bind(address)?;
+            let addr = listener.local_addr()?;
+            let shared = Arc::clone(&shared);
+            acceptors.push(std::thread::spawn(move || metrics_loop(&shared, listener)));
+            Some(addr)
+        }
+        None => None,
+    };
+    if shared.flusher.is_some() {
+        let shared = Arc::clone(&shared);
+        acceptors.push(std::thread::spawn(move || flush_loop(&shared)));
+    }
+
+    Ok(ServerHandle {
+        local_addrs,
+        metrics_addr,
+        bind_errors,
+        shared,
+        acceptors,
+    })
+}
+
+fn metrics_loop(shared: &ServerShared, listener: std::net::TcpListener) {
+    for stream in listener.incoming() {
+        if shared.shutting_down.load(Ordering::SeqCst) {
+            break;
+        }
+        if let Ok(stream) = stream {
+            if let Err(e) = metrics::serve_http(stream, || shared.rsheet.render_metrics()) {
+                tracing::warn!(error = %e, "failed to serve metrics");
+            }
+        }
+    }
+}
+
+fn flush_loop(shared: &ServerShared) {
+    let Some(flusher) = &shared.flusher else { return };
+    while !shared.shutting_down.load(Ordering::SeqCst) {
+        std::thread::sleep(flusher.window);
+        // Collect first so a slow client never blocks connections from opening or closing.
+        let writers: Vec<connect::SharedWriter> = flusher.writers.lock().unwrap().values().cloned().collect();
+        for writer in writers {
+            if let Err(e) = writer.lock().unwrap().flush() {
+                tracing::debug!(error = %e, "failed to flush queued messages");
+            }
+        }
+    }
+}
+
+fn accept_loop(shared: &Arc<ServerShared>, listener: std::net::TcpListener) {
+    let connections = &shared.connections;
+    loop {
+        if let (Some(max), connect::OverloadPolicy::Queue) = (shared.max_connections, shared.overload_policy) {
+            let mut open = connections.open.lock().unwrap();
+            while open.len() >= max && !shared.shutting_down.load(Ordering::SeqCst) {
+                open = connections.slot_freed.wait(open).unwrap();
+            }
+        }
+        let accepted = listener.accept();
+        if shared.shutting_down.load(Ordering::SeqCst) {
+            break;
+        }
+        let socket = match accepted {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                tracing::warn!(error = %e, "failed to accept connection");
+                continue;
+            }
+        };
+        if let Some(max) = shared.max_connections {
+            if connections.open.lock().unwrap().len() >= max {
+                tracing::warn!(max, "rejecting connection: server full");
+                let busy = Reply::error(ErrorCode::ServerBusy, "Server busy, try again later");
+                let _ = connect::Writer::new(socket).write_message(busy);
+                continue;
+            }
+        }
+        if let Err(e) = socket.set_read_timeout(shared.idle_timeout) {
+            tracing::warn!(error = %e, "failed to configure connection");
+            continue;
+        }
+        let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
+        match socket.try_clone() {
+            Ok(clone) => {
+                connections.open.lock().unwrap().insert(id, clone);
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "failed to register connection");
+                continue;
+            }
+        }
+
+        let worker_shared = Arc::clone(shared);
+        let peer = socket.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        let serve = move || {
+            let _span = tracing::info_span!("connection", id, %peer).entered();
+            worker_shared.rsheet.metrics.connection_opened();
+            let shared = &*worker_shared;
+            serve_connection(&shared.rsheet, shared.pool.as_ref(), shared.flusher.as_ref(), id, socket, shared.options);
+            worker_shared.rsheet.metrics.connection_closed();
+            worker_shared.connections.open.lock().unwrap().remove(&id);
+            worker_shared.connections.slot_freed.notify_one();
+            worker_shared.workers.lock().unwrap().remove(&id);
+        };
+        match &shared.connection_pool {
+            Some(pool) => {
+                if !pool.execute(serve) {
+                    connections.open.lock().unwrap().remove(&id);
+                }
+            }
+            None => {
+                // Held across the spawn, so the thread cannot remove its handle before it is in.
+                let mut workers = shared.workers.lock().unwrap();
+                workers.insert(id, std::thread::spawn(serve));
+            }
+        }
+    }
+}
+
+/// Per-connection settings taken from the [`connect::Manager`] at startup.
+#[derive(Clone, Copy)]
+struct ConnectionOptions {
+    rate_limit: Option<connect::RateLimit>,
+    max_frame_size: usize,
+    protocol_error_policy: connect::ProtocolErrorPolicy,
+    command_timeout: Option<Duration>,
+}
+
+fn serve_connection(
+    rsheet: &Arc<RSheet>,
+    pool: Option<&pool::WorkerPool>,
+    flusher: Option<&Flusher>,
+    id: u64,
+    socket: TcpStream,
+    options: ConnectionOptions,
+) {
+    let mode = match connect::detect_mode(&socket) {
+        Ok(mode) => mode,
+        Err(e) => {
+            tracing::debug!(error = %e, "connection closed before first message");
+            return;
+        }
+    };
+    let peer = socket.peer_addr();
+    let reader = match socket.try_clone() {
+        Ok(reader) => reader,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to clone socket; closing connection");
+            return;
+        }
+    };
+    let writer = socket;
+    let (reader, writer) = match mode {
+        connect::WireMode::Framed => (connect::Reader::new(reader), connect::Writer::new(writer)),
+        connect::WireMode::Text => (connect::Reader::text(reader), connect::Writer::text(writer)),
+    };
+    let mut reader = reader.with_max_frame_size(options.max_frame_size);
+    let writer = if flusher.is_some() { writer.coalescing() } else { writer };
+    let writer: connect::SharedWriter = Arc::new(Mutex::new(writer));
+    if let Some(flusher) = flusher {
+        flusher.writers.lock().unwrap().insert(id, writer.clone());
+    }
+    let mut bucket = options.rate_limit.map(connect::TokenBucket::new);
+    let mut session = Session::new(id, writer.clone());
+    if let Ok(peer) = peer {
+        session = session.with_peer(peer);
+    }
+    if let Some(timeout) = options.command_timeout {
+        session = session.with_command_timeout(timeout);
+    }
+    let session = Arc::new(session);
+    tracing::debug!(?mode, "connection opened");
+    rsheet.begin_session(&session);
+
+    loop {
+        let message = match reader.read_message() {
+            Ok(message) => message,
+            Err(e) if e.is_timeout() => {
+                tracing::info!("dropping idle connection");
+                break;
+            }
+            Err(RSheetError::Protocol(e)) => {
+                if reject_message(&writer, &e, options.protocol_error_policy) {
+                    continue;
+                }
+                break;
+            }
+            Err(e) => {
+                // EOF or a broken socket: nothing left to reply to.
+                tracing::debug!(error = %e, "connection closed");
+                break;
+            }
+        };
+
+        let result = match message {
+            Message::Command(cmd) => {
+                let throttled = bucket.as_mut().is_some_and(|bucket| !bucket.try_acquire());
+                let reply = if throttled {
+                    Reply::error(ErrorCode::Throttled, "Rate limit exceeded")
+                } else if let Some(pool) = pool {
+                    let (rsheet, session) = (Arc::clone(rsheet), Arc::clone(&session));
+                    match pool.run(move || futures::executor::block_on(rsheet.execute(&session, cmd))) {
+                        Some(reply) => reply,
+                        None => {
+                            tracing::error!("command failed on a worker, closing connection");
+                            break;
+                        }
+                    }
+                } else {
+                    futures::executor::block_on(rsheet.execute(&session, cmd))
+                };
+                writer.lock().unwrap().write_message(reply)
+            }
+            Message::Ping => writer.lock().unwrap().send(&Message::Pong),
+            Message::Hello { version, capabilities } => {
+                let answer = rsheet.negotiate(&session, version, &capabilities);
+                writer.lock().unwrap().send(&answer)
+            }
+            // A pong only proves the peer is alive; the read itself reset the idle timer.
+            Message::Pong => Ok(()),
+            Message::Reply(_)
+            | Message::Notify { .. }
+            | Message::Broadcast(_)
+            | Message::Chunk(_)
+            | Message::Presence(_)
+            | Message::Alert(_)
+            | Message::Replicate(_) => {
+                let e = connect::ProtocolError::UnexpectedMessage("server-only message".to_string());
+                if reject_message(&writer, &e, options.protocol_error_policy) {
+                    continue;
+                }
+                break;
+            }
+        };
+        // Replies wait while more commands are already buffered, so a pipelined batch is answered in one write.
+        let result = match result {
+            Ok(()) if !reader.has_buffered() => writer.lock().unwrap().flush().map_err(Into::into),
+            result => result,
+        };
+        if let Err(e) = result {
+            tracing::warn!(error = %e, "failed to write reply, closing connection");
+            break;
+        }
+    }
+    if let Some(flusher) = flusher {
+        flusher.writers.lock().unwrap().remove(&id);
+    }
+    rsheet.end_session(&session);
+}
+
+/// Reports a protocol error to the client. Returns whether the connection should stay open.
+fn reject_message(
+    writer: &connect::SharedWriter,
+    error: &connect::ProtocolError,
+    policy: connect::ProtocolErrorPolicy,
+) -> bool {
+    tracing::warn!(%error, "protocol error");
+    let reply = Reply::error(ErrorCode::ProtocolError, error.to_string());
+    let mut writer = writer.lock().unwrap();
+    if writer.write_message(reply).is_err() || writer.flush().is_err() {
+        return false;
+    }
+    error.is_recoverable() && policy == connect::ProtocolErrorPolicy::Recover
+}
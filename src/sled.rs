@@ -0,0 +1,101 @@
+use crate::address::{qualify, CellAddress, CellRange};
+use crate::store::CellStore;
+use crate::CellValue;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Cell values in an embedded sled database, for durable single-node
+/// deployments without a database server.
+///
+/// sled flushes to disk in the background every few hundred milliseconds;
+/// [`CellStore::flush`] and drop force a flush, so a clean shutdown loses
+/// nothing.
+pub struct SledStore {
+    db: sled::Db,
+    cells: sled::Tree,
+}
+
+impl SledStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let db = sled::open(path).map_err(to_io)?;
+        let cells = db.open_tree("cells").map_err(to_io)?;
+        Ok(SledStore { db, cells })
+    }
+}
+
+impl CellStore for SledStore {
+    fn get(&self, key: &str) -> Option<Arc<CellValue>> {
+        match self.cells.get(key) {
+            Ok(bytes) => bytes.and_then(|bytes| decode(&bytes)).map(Arc::new),
+            Err(e) => {
+                tracing::error!(cell = key, error = %e, "failed to read cell");
+                None
+            }
+        }
+    }
+
+    fn set(&mut self, key: &str, value: CellValue) -> io::Result<Option<CellValue>> {
+        let old = self.cells.insert(key, serde_json::to_vec(&value)?).map_err(to_io)?;
+        Ok(old.and_then(|bytes| decode(&bytes)))
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<Option<CellValue>> {
+        let old = self.cells.remove(key).map_err(to_io)?;
+        Ok(old.and_then(|bytes| decode(&bytes)))
+    }
+
+    fn iter_range<'a>(
+        &'a self,
+        sheet: Option<&'a str>,
+        range: CellRange,
+    ) -> Box<dyn Iterator<Item = (CellAddress, CellValue)> + 'a> {
+        Box::new(range.iter().filter_map(move |addr| Some((addr, Arc::unwrap_or_clone(self.get(&qualify(sheet, &addr.to_string()))?)))))
+    }
+
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_> {
+        Box::new(self.cells.iter().filter_map(|entry| match entry {
+            Ok((key, bytes)) => Some((String::from_utf8(key.to_vec()).ok()?, decode(&bytes)?)),
+            Err(e) => {
+                tracing::error!(error = %e, "failed to read cells");
+                None
+            }
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    fn replace_all(&mut self, cells: HashMap<String, CellValue>) -> io::Result<()> {
+        let mut batch = sled::Batch::default();
+        for entry in self.cells.iter().keys() {
+            batch.remove(entry.map_err(to_io)?);
+        }
+        for (key, value) in cells {
+            batch.insert(key.as_bytes(), serde_json::to_vec(&value)?);
+        }
+        self.cells.apply_batch(batch).map_err(to_io)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.db.flush().map(drop).map_err(to_io)
+    }
+}
+
+impl Drop for SledStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.db.flush() {
+            tracing::error!(error = %e, "failed to flush cells on close");
+        }
+    }
+}
+
+fn decode(bytes: &[u8]) -> Option<CellValue> {
+    serde_json::from_slice(bytes).ok()
+}
+
+fn to_io(e: sled::Error) -> io::Error {
+    io::Error::other(e)
+}
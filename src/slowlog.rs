@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Commands at least this slow are kept unless configured otherwise.
+pub const DEFAULT_SLOWLOG_THRESHOLD: Duration = Duration::from_millis(10);
+
+/// Slow commands kept in memory unless configured otherwise.
+pub const DEFAULT_SLOWLOG_CAPACITY: usize = 128;
+
+thread_local! {
+    static TOUCHED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Counts `cells` read or written by the command running on this thread.
+pub fn touch(cells: u64) {
+    TOUCHED.with(|touched| touched.set(touched.get() + cells));
+}
+
+/// Runs `f`, returning its result and how many cells it touched. A nested
+/// count is added to the enclosing one as well.
+pub fn counting<T>(f: impl FnOnce() -> T) -> (T, u64) {
+    let before = TOUCHED.with(|touched| touched.replace(0));
+    let result = f();
+    let cells = TOUCHED.with(|touched| touched.replace(before + touched.get()));
+    (result, cells)
+}
+
+/// How long one command took, passed to every hook and to the slow log.
+#[derive(Clone, Debug)]
+pub struct CommandTiming<'a> {
+    pub connection: u64,
+    /// The command's name as counted in metrics, e.g. `set` or `unknown`.
+    pub kind: &'a str,
+    pub command: &'a str,
+    pub duration: Duration,
+    /// Cells the command read or wrote.
+    pub cells: u64,
+}
+
+/// Called after every command with its timing.
+pub type CommandHook = Box<dyn Fn(&CommandTiming) + Send + Sync>;
+
+/// A command that took at least the slow log's threshold.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SlowEntry {
+    /// Increases by one for every slow command, so a reader can tell what it missed.
+    pub id: u64,
+    /// Milliseconds since the Unix epoch when the command finished.
+    pub timestamp_ms: u64,
+    pub connection: u64,
+    pub command: String,
+    pub duration_us: u64,
+    pub cells: u64,
+}
+
+/// Bounded record of commands slower than a threshold, newest last.
+pub struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    entries: Mutex<VecDeque<SlowEntry>>,
+    next_id: AtomicU64,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        SlowLog::new(DEFAULT_SLOWLOG_THRESHOLD, DEFAULT_SLOWLOG_CAPACITY)
+    }
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, capacity: usize) -> Self {
+        SlowLog { threshold, capacity, entries: Mutex::default(), next_id: AtomicU64::new(0) }
+    }
+
+    /// Keeps `timing` if the command was slow enough.
+    pub fn record(&self, timing: &CommandTiming) {
+        if timing.duration < self.threshold {
+            return;
+        }
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(SlowEntry {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            timestamp_ms,
+            connection: timing.connection,
+            command: timing.command.to_string(),
+            duration_us: timing.duration.as_micros() as u64,
+            cells: timing.cells,
+        });
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// The last `n` entries, newest first.
+    pub fn recent(&self, n: usize) -> Vec<SlowEntry> {
+        self.entries.lock().unwrap().iter().rev().take(n).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,269 @@
+use crate::address::{qualify, split_sheet, CellAddress, CellRange, DEFAULT_SHEET};
+use crate::store::CellStore;
+use crate::CellValue;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Writes held back before they are committed together.
+pub const DEFAULT_BATCH_SIZE: usize = 256;
+/// Cells remembered in memory before the oldest is forgotten.
+pub const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+
+/// Cell values in an SQLite database, so a sheet can outgrow memory and
+/// survive restarts.
+///
+/// Writes are buffered and committed in one transaction once
+/// [`SqliteStore::with_batch_size`] of them are pending, on any read that
+/// scans the table, on [`CellStore::flush`], and on drop. A crash loses the
+/// buffered writes, so pair this with a write-ahead log when every
+/// acknowledged change must survive. Recently used cells are cached.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    /// `None` marks a pending delete.
+    pending: Mutex<HashMap<String, Option<Arc<CellValue>>>>,
+    cache: Mutex<Cache>,
+    batch_size: usize,
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let conn = Connection::open(path).map_err(to_io)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS cells (
+                sheet TEXT NOT NULL,
+                row INTEGER NOT NULL,
+                col INTEGER NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (sheet, row, col)
+            ) WITHOUT ROWID;",
+        )
+        .map_err(to_io)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+            pending: Mutex::new(HashMap::new()),
+            cache: Mutex::new(Cache::new(DEFAULT_CACHE_CAPACITY)),
+            batch_size: DEFAULT_BATCH_SIZE,
+        })
+    }
+
+    pub fn with_batch_size(mut self, size: usize) -> Self {
+        self.batch_size = size.max(1);
+        self
+    }
+
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache = Mutex::new(Cache::new(capacity));
+        self
+    }
+
+    /// Commits every pending write in one transaction.
+    fn commit(&self) -> io::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let conn = self.conn.lock().unwrap();
+        let tx = conn.unchecked_transaction().map_err(to_io)?;
+        {
+            let mut upsert = tx
+                .prepare_cached("INSERT OR REPLACE INTO cells (sheet, row, col, value) VALUES (?1, ?2, ?3, ?4)")
+                .map_err(to_io)?;
+            let mut delete =
+                tx.prepare_cached("DELETE FROM cells WHERE sheet = ?1 AND row = ?2 AND col = ?3").map_err(to_io)?;
+            for (key, value) in pending.iter() {
+                let (sheet, addr) = parse_key(key)?;
+                match value {
+                    Some(value) => {
+                        let json = serde_json::to_string(value)?;
+                        upsert.execute(params![sheet, addr.row, addr.col, json]).map_err(to_io)?;
+                    }
+                    None => {
+                        delete.execute(params![sheet, addr.row, addr.col]).map_err(to_io)?;
+                    }
+                }
+            }
+        }
+        tx.commit().map_err(to_io)?;
+        pending.clear();
+        Ok(())
+    }
+
+    /// Commits before a read that goes straight to the table. A failure is
+    /// logged and the read sees only what was already committed.
+    fn commit_for_read(&self) {
+        if let Err(e) = self.commit() {
+            tracing::error!(error = %e, "failed to commit pending cell writes");
+        }
+    }
+
+    fn write(&mut self, key: &str, value: Option<CellValue>) -> io::Result<Option<CellValue>> {
+        parse_key(key)?;
+        let old = self.get(key).map(Arc::unwrap_or_clone);
+        let value = value.map(Arc::new);
+        self.cache.lock().unwrap().insert(key, value.clone());
+        let batch_full = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.insert(key.to_string(), value);
+            pending.len() >= self.batch_size
+        };
+        if batch_full {
+            self.commit()?;
+        }
+        Ok(old)
+    }
+}
+
+impl CellStore for SqliteStore {
+    fn get(&self, key: &str) -> Option<Arc<CellValue>> {
+        if let Some(value) = self.pending.lock().unwrap().get(key) {
+            return value.clone();
+        }
+        if let Some(value) = self.cache.lock().unwrap().get(key) {
+            return value;
+        }
+        let (sheet, addr) = parse_key(key).ok()?;
+        let json: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .prepare_cached("SELECT value FROM cells WHERE sheet = ?1 AND row = ?2 AND col = ?3")
+            .and_then(|mut stmt| stmt.query_row(params![sheet, addr.row, addr.col], |row| row.get(0)).optional())
+            .unwrap_or_else(|e| {
+                tracing::error!(cell = key, error = %e, "failed to read cell");
+                None
+            });
+        let value = json.and_then(|json| serde_json::from_str(&json).ok()).map(Arc::new);
+        self.cache.lock().unwrap().insert(key, value.clone());
+        value
+    }
+
+    fn set(&mut self, key: &str, value: CellValue) -> io::Result<Option<CellValue>> {
+        self.write(key, Some(value))
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<Option<CellValue>> {
+        self.write(key, None)
+    }
+
+    fn iter_range<'a>(
+        &'a self,
+        sheet: Option<&'a str>,
+        range: CellRange,
+    ) -> Box<dyn Iterator<Item = (CellAddress, CellValue)> + 'a> {
+        self.commit_for_read();
+        let query = |conn: &Connection| -> rusqlite::Result<Vec<(CellAddress, String)>> {
+            let mut stmt = conn.prepare_cached(
+                "SELECT row, col, value FROM cells
+                 WHERE sheet = ?1 AND row BETWEEN ?2 AND ?3 AND col BETWEEN ?4 AND ?5",
+            )?;
+            let sheet = sheet.unwrap_or(DEFAULT_SHEET);
+            let rows = stmt.query_map(
+                params![sheet, range.start.row, range.end.row, range.start.col, range.end.col],
+                |row| Ok((CellAddress::new(row.get(1)?, row.get(0)?), row.get(2)?)),
+            )?;
+            rows.collect()
+        };
+        let rows = query(&self.conn.lock().unwrap()).unwrap_or_else(|e| {
+            tracing::error!(%range, error = %e, "failed to read range");
+            Vec::new()
+        });
+        Box::new(rows.into_iter().filter_map(|(addr, json)| Some((addr, serde_json::from_str(&json).ok()?))))
+    }
+
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_> {
+        self.commit_for_read();
+        let query = |conn: &Connection| -> rusqlite::Result<Vec<(String, CellAddress, String)>> {
+            let mut stmt = conn.prepare_cached("SELECT sheet, row, col, value FROM cells")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, CellAddress::new(row.get(2)?, row.get(1)?), row.get(3)?)))?;
+            rows.collect()
+        };
+        let rows = query(&self.conn.lock().unwrap()).unwrap_or_else(|e| {
+            tracing::error!(error = %e, "failed to read cells");
+            Vec::new()
+        });
+        Box::new(rows.into_iter().filter_map(|(sheet, addr, json)| {
+            let key = qualify(Some(sheet.as_str()), &addr.to_string());
+            Some((key, serde_json::from_str(&json).ok()?))
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.commit_for_read();
+        self.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM cells", [], |row| row.get(0)).unwrap_or_else(|e| {
+            tracing::error!(error = %e, "failed to count cells");
+            0
+        })
+    }
+
+    fn replace_all(&mut self, cells: HashMap<String, CellValue>) -> io::Result<()> {
+        self.pending.get_mut().unwrap().clear();
+        self.cache.get_mut().unwrap().clear();
+        self.conn.get_mut().unwrap().execute("DELETE FROM cells", []).map_err(to_io)?;
+        for (key, value) in cells {
+            self.write(&key, Some(value))?;
+        }
+        self.commit()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.commit()
+    }
+}
+
+impl Drop for SqliteStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            tracing::error!(error = %e, "failed to commit pending cell writes on close");
+        }
+    }
+}
+
+/// Splits a storage key into its table columns. The default sheet is stored by name.
+fn parse_key(key: &str) -> io::Result<(&str, CellAddress)> {
+    let (sheet, reference) = split_sheet(key);
+    let addr = reference.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e)))?;
+    Ok((sheet.unwrap_or(DEFAULT_SHEET), addr))
+}
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Recently read or written cells, `None` for ones known to be empty.
+struct Cache {
+    values: HashMap<String, Option<Arc<CellValue>>>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Cache { values: HashMap::new(), order: VecDeque::new(), capacity }
+    }
+
+    fn get(&self, key: &str) -> Option<Option<Arc<CellValue>>> {
+        self.values.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: &str, value: Option<Arc<CellValue>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.values.insert(key.to_string(), value).is_none() {
+            self.order.push_back(key.to_string());
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.values.remove(&oldest);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.values.clear();
+        self.order.clear();
+    }
+}
@@ -0,0 +1,80 @@
+use crate::address::CellRange;
+use crate::store::Snapshot;
+use crate::CellValue;
+use serde::{Deserialize, Serialize};
+
+/// Most histogram bins `describe` draws.
+pub const MAX_BINS: usize = 1000;
+
+/// Summary statistics of the numbers in a range, as `describe` answers.
+/// Text, errors and empty cells are left out.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Description {
+    pub range: CellRange,
+    pub count: usize,
+    pub sum: f64,
+    /// `None`, like `min` and `max`, when the range holds no numbers.
+    pub mean: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Sample standard deviation; `None` with fewer than two numbers.
+    pub stddev: Option<f64>,
+    /// Equal-width bins from `min` to `max`, if asked for.
+    pub histogram: Option<Vec<Bin>>,
+}
+
+/// Numbers from `lower` up to, but not including, `upper`; the last bin
+/// includes its upper bound.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bin {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: usize,
+}
+
+/// Describes the numbers of `range` in `snapshot`, with a histogram of
+/// `bins` bins if given.
+pub fn describe(range: CellRange, snapshot: &Snapshot, bins: Option<usize>) -> Description {
+    let numbers: Vec<f64> = snapshot
+        .iter_range(range)
+        .filter_map(|(_, value)| match **value {
+            CellValue::Number(n) if n.is_finite() => Some(n),
+            _ => None,
+        })
+        .collect();
+    let count = numbers.len();
+    // Folded from 0.0: an empty sum is -0.0 otherwise, and would print as `-0`.
+    let sum = numbers.iter().fold(0.0, |sum, n| sum + n);
+    let mean = (count > 0).then(|| sum / count as f64);
+    let min = numbers.iter().copied().reduce(f64::min);
+    let max = numbers.iter().copied().reduce(f64::max);
+    let stddev = mean.filter(|_| count > 1).map(|mean| {
+        let squares: f64 = numbers.iter().map(|n| (n - mean).powi(2)).sum();
+        (squares / (count - 1) as f64).sqrt()
+    });
+    let histogram = bins.map(|bins| match (min, max) {
+        (Some(min), Some(max)) => histogram(&numbers, min, max, bins),
+        _ => Vec::new(),
+    });
+    Description { range, count, sum, mean, min, max, stddev, histogram }
+}
+
+fn histogram(numbers: &[f64], min: f64, max: f64, bins: usize) -> Vec<Bin> {
+    // A range of one repeated number has nothing to split.
+    let bins = if min == max { 1 } else { bins.clamp(1, MAX_BINS) };
+    let width = (max - min) / bins as f64;
+    let mut counts = vec![0; bins];
+    for n in numbers {
+        let bin = if width > 0.0 { ((n - min) / width) as usize } else { 0 };
+        counts[bin.min(bins - 1)] += 1;
+    }
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(i, count)| Bin {
+            lower: min + width * i as f64,
+            upper: if i + 1 == bins { max } else { min + width * (i + 1) as f64 },
+            count,
+        })
+        .collect()
+}
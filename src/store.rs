@@ -0,0 +1,723 @@
+use crate::address::{self, CellAddress, CellKey, CellRange, DEFAULT_SHEET};
+use crate::CellValue;
+use arc_swap::ArcSwap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Where cell values live. Keys are storage keys (`A1`, `Budget!A1`).
+///
+/// [`crate::RSheet`] keeps the store behind a read-write lock: methods taking
+/// `&mut self` run alone, but reads run concurrently, so any state a read
+/// updates (a cache, a connection) needs its own synchronisation. Writes may
+/// fail, e.g. on a disk-backed store; the error is reported to the client as a
+/// storage error.
+pub trait CellStore: Send + Sync {
+    /// The cell's value. In-memory stores hand out the stored value itself
+    /// rather than a copy.
+    fn get(&self, key: &str) -> Option<Arc<CellValue>>;
+
+    /// [`CellStore::get`] for a parsed address. In-memory stores look it up
+    /// without building the storage key.
+    fn get_at(&self, sheet: Option<&str>, addr: CellAddress) -> Option<Arc<CellValue>> {
+        self.get(&address::qualify(sheet, &addr.to_string()))
+    }
+
+    /// Stores `value`, returning the value it replaced.
+    fn set(&mut self, key: &str, value: CellValue) -> io::Result<Option<CellValue>>;
+
+    /// Removes the cell, returning its value if it had one.
+    fn delete(&mut self, key: &str) -> io::Result<Option<CellValue>>;
+
+    /// Cells of `range` on `sheet` that hold a value, in no particular order.
+    fn iter_range<'a>(
+        &'a self,
+        sheet: Option<&'a str>,
+        range: CellRange,
+    ) -> Box<dyn Iterator<Item = (CellAddress, CellValue)> + 'a>;
+
+    /// Every stored cell, in no particular order.
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_>;
+
+    fn len(&self) -> usize {
+        self.iter_all().count()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `range` of `sheet` as it is now, for reads that outlast the lock they
+    /// start under. The default copies the range's cells.
+    fn snapshot(&self, sheet: Option<&str>, range: CellRange) -> Snapshot {
+        self.iter_range(sheet, range).collect()
+    }
+
+    /// Sum and count of the numbers in `range` on `sheet`; other values are skipped.
+    fn aggregate(&self, sheet: Option<&str>, range: CellRange) -> Aggregate {
+        self.iter_range(sheet, range)
+            .filter_map(|(_, value)| match value {
+                CellValue::Number(n) => Some(n),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Sum of the numbers in `range` on `sheet`; other values are skipped.
+    fn sum(&self, sheet: Option<&str>, range: CellRange) -> f64 {
+        self.aggregate(sheet, range).sum
+    }
+
+    /// Makes buffered writes durable. Called on shutdown.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes that need only a shared reference, for stores that lock
+    /// internally. With them, [`crate::RSheet`] lets changes to different
+    /// cells proceed in parallel instead of taking its lock exclusively.
+    fn shared_writes(&self) -> Option<&dyn SharedWrites> {
+        None
+    }
+
+    /// Replaces every cell with `cells`.
+    fn replace_all(&mut self, cells: HashMap<String, CellValue>) -> io::Result<()> {
+        let keys: Vec<String> = self.iter_all().map(|(key, _)| key).collect();
+        for key in keys {
+            self.delete(&key)?;
+        }
+        for (key, value) in cells {
+            self.set(&key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// What [`CellStore::aggregate`] finds in a range.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Aggregate {
+    pub sum: f64,
+    /// How many numbers were summed.
+    pub count: usize,
+}
+
+impl Aggregate {
+    fn merge(self, other: Aggregate) -> Aggregate {
+        Aggregate { sum: self.sum + other.sum, count: self.count + other.count }
+    }
+}
+
+impl FromIterator<f64> for Aggregate {
+    fn from_iter<I: IntoIterator<Item = f64>>(iter: I) -> Self {
+        iter.into_iter().fold(Aggregate::default(), |total, n| Aggregate { sum: total.sum + n, count: total.count + 1 })
+    }
+}
+
+impl std::iter::Sum for Aggregate {
+    fn sum<I: Iterator<Item = Aggregate>>(iter: I) -> Self {
+        iter.fold(Aggregate::default(), Aggregate::merge)
+    }
+}
+
+/// Independent accumulators in [`sum_lanes`], so the additions do not wait
+/// on each other and the loop compiles to vector instructions.
+const LANES: usize = 8;
+
+/// Sum of `values`, [`LANES`] at a time.
+fn sum_lanes(values: &[f64]) -> f64 {
+    let mut lanes = [0.0; LANES];
+    let chunks = values.chunks_exact(LANES);
+    let rest: f64 = chunks.remainder().iter().sum();
+    for chunk in chunks {
+        for (lane, value) in lanes.iter_mut().zip(chunk) {
+            *lane += value;
+        }
+    }
+    lanes.iter().sum::<f64>() + rest
+}
+
+/// [`CellStore::set`] and [`CellStore::delete`] through a shared reference.
+pub trait SharedWrites {
+    fn set(&self, key: &str, value: CellValue) -> io::Result<Option<CellValue>>;
+    fn delete(&self, key: &str) -> io::Result<Option<CellValue>>;
+}
+
+/// One sheet's cells keyed by `(row, col)`.
+type SheetCells = BTreeMap<(u32, u32), Arc<CellValue>>;
+
+/// Cells of `range` in `cells`, seeking once per row unless the range has
+/// more rows than the sheet has cells.
+fn cells_in(cells: &SheetCells, range: CellRange) -> Box<dyn Iterator<Item = (CellAddress, &Arc<CellValue>)> + '_> {
+    let (start, end) = (range.start, range.end);
+    fn entry<'a>((&(row, col), value): (&(u32, u32), &'a Arc<CellValue>)) -> (CellAddress, &'a Arc<CellValue>) {
+        (CellAddress::new(col, row), value)
+    }
+    if ((end.row - start.row) as usize) < cells.len() {
+        Box::new((start.row..=end.row).flat_map(move |row| cells.range((row, start.col)..=(row, end.col)).map(entry)))
+    } else {
+        Box::new(
+            cells
+                .range((start.row, start.col)..=(end.row, end.col))
+                .filter(move |(&(_, col), _)| (start.col..=end.col).contains(&col))
+                .map(entry),
+        )
+    }
+}
+
+/// Cells of one sheet as of the moment it was taken; later writes do not show
+/// through. Taken from a [`MemoryStore`] it shares the sheet's map, which the
+/// store copies only when it is next written.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    cells: Arc<SheetCells>,
+}
+
+impl Snapshot {
+    pub fn get(&self, addr: CellAddress) -> Option<&Arc<CellValue>> {
+        self.cells.get(&(addr.row, addr.col))
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// Cells of `range` held by the snapshot, which may cover more than was asked for.
+    pub fn iter_range(&self, range: CellRange) -> impl Iterator<Item = (CellAddress, &Arc<CellValue>)> + '_ {
+        cells_in(&self.cells, range)
+    }
+}
+
+impl FromIterator<(CellAddress, CellValue)> for Snapshot {
+    fn from_iter<I: IntoIterator<Item = (CellAddress, CellValue)>>(iter: I) -> Self {
+        let cells = iter.into_iter().map(|(addr, value)| ((addr.row, addr.col), Arc::new(value))).collect();
+        Snapshot { cells: Arc::new(cells) }
+    }
+}
+
+/// The default store: each sheet's cells in a `BTreeMap` keyed by `(row, col)`,
+/// so a range is read by seeking to each of its rows rather than by probing
+/// every address in it.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    /// Keyed by sheet name, the default sheet's included, so lookups borrow
+    /// the name rather than allocate one.
+    sheets: HashMap<String, Arc<SheetCells>>,
+}
+
+/// Parses a storage key without allocating.
+fn parse_key(key: &str) -> io::Result<(&str, CellAddress)> {
+    let (sheet, addr) =
+        address::parse_key(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e)))?;
+    Ok((sheet_name(sheet), addr))
+}
+
+fn sheet_name(sheet: Option<&str>) -> &str {
+    sheet.unwrap_or(DEFAULT_SHEET)
+}
+
+/// Storage key of `addr` on the sheet named `name`.
+fn storage_key(name: &str, addr: CellAddress) -> String {
+    CellKey { sheet: (name != DEFAULT_SHEET).then(|| name.to_string()), addr }.to_string()
+}
+
+impl CellStore for MemoryStore {
+    fn get(&self, key: &str) -> Option<Arc<CellValue>> {
+        let (sheet, addr) = parse_key(key).ok()?;
+        self.get_at(Some(sheet), addr)
+    }
+
+    fn get_at(&self, sheet: Option<&str>, addr: CellAddress) -> Option<Arc<CellValue>> {
+        self.sheets.get(sheet_name(sheet))?.get(&(addr.row, addr.col)).cloned()
+    }
+
+    fn set(&mut self, key: &str, value: CellValue) -> io::Result<Option<CellValue>> {
+        let (sheet, addr) = parse_key(key)?;
+        if !self.sheets.contains_key(sheet) {
+            self.sheets.insert(sheet.to_string(), Arc::default());
+        }
+        let cells = Arc::make_mut(self.sheets.get_mut(sheet).unwrap());
+        let old = cells.insert((addr.row, addr.col), Arc::new(value));
+        Ok(old.map(Arc::unwrap_or_clone))
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<Option<CellValue>> {
+        let (sheet, addr) = parse_key(key)?;
+        let Some(cells) = self.sheets.get_mut(sheet) else {
+            return Ok(None);
+        };
+        if !cells.contains_key(&(addr.row, addr.col)) {
+            return Ok(None);
+        }
+        let cells = Arc::make_mut(cells);
+        let old = cells.remove(&(addr.row, addr.col));
+        if cells.is_empty() {
+            self.sheets.remove(sheet);
+        }
+        Ok(old.map(Arc::unwrap_or_clone))
+    }
+
+    fn iter_range<'a>(
+        &'a self,
+        sheet: Option<&'a str>,
+        range: CellRange,
+    ) -> Box<dyn Iterator<Item = (CellAddress, CellValue)> + 'a> {
+        let Some(cells) = self.sheets.get(sheet_name(sheet)) else {
+            return Box::new(std::iter::empty());
+        };
+        Box::new(cells_in(cells, range).map(|(addr, value)| (addr, CellValue::clone(value))))
+    }
+
+    /// Shares the whole sheet rather than copying `range` out of it.
+    fn snapshot(&self, sheet: Option<&str>, _range: CellRange) -> Snapshot {
+        let cells = self.sheets.get(sheet_name(sheet)).cloned().unwrap_or_default();
+        Snapshot { cells }
+    }
+
+    /// Reads the stored values in place instead of copying them out.
+    fn aggregate(&self, sheet: Option<&str>, range: CellRange) -> Aggregate {
+        let Some(cells) = self.sheets.get(sheet_name(sheet)) else {
+            return Aggregate::default();
+        };
+        cells_in(cells, range)
+            .filter_map(|(_, value)| match **value {
+                CellValue::Number(n) => Some(n),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_> {
+        Box::new(self.sheets.iter().flat_map(|(sheet, cells)| {
+            cells
+                .iter()
+                .map(move |(&(row, col), value)| (storage_key(sheet, CellAddress::new(col, row)), CellValue::clone(value)))
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.sheets.values().map(|cells| cells.len()).sum()
+    }
+
+    /// Builds each sheet whole, sheets in parallel, rather than cell by cell.
+    fn replace_all(&mut self, cells: HashMap<String, CellValue>) -> io::Result<()> {
+        type Entries = Vec<((u32, u32), Arc<CellValue>)>;
+        let mut by_sheet: HashMap<String, Entries> = HashMap::new();
+        for (key, value) in cells {
+            let (sheet, addr) = parse_key(&key)?;
+            let cell = ((addr.row, addr.col), Arc::new(value));
+            match by_sheet.get_mut(sheet) {
+                Some(cells) => cells.push(cell),
+                None => {
+                    by_sheet.insert(sheet.to_string(), vec![cell]);
+                }
+            }
+        }
+        let sheets = crate::pool::parallel_map(by_sheet.into_iter().collect(), |(sheet, cells)| {
+            (sheet, Arc::new(cells.into_iter().collect::<SheetCells>()))
+        });
+        self.sheets = sheets.into_iter().collect();
+        Ok(())
+    }
+}
+
+/// One column of a [`ColumnarStore`].
+#[derive(Debug, Default)]
+struct Column {
+    /// Indexed by row; 0.0 where the cell is empty or not a number.
+    numbers: Vec<f64>,
+    /// Rows of `numbers` that hold a stored number.
+    is_number: Vec<bool>,
+    /// Cells holding text or errors.
+    others: BTreeMap<u32, Arc<CellValue>>,
+    len: usize,
+}
+
+impl Column {
+    fn get(&self, row: u32) -> Option<Arc<CellValue>> {
+        match self.is_number.get(row as usize) {
+            Some(true) => Some(Arc::new(CellValue::Number(self.numbers[row as usize]))),
+            _ => self.others.get(&row).cloned(),
+        }
+    }
+
+    fn remove(&mut self, row: u32) -> Option<CellValue> {
+        let mut old = None;
+        if let Some(true) = self.is_number.get(row as usize) {
+            old = Some(CellValue::Number(self.numbers[row as usize]));
+            self.numbers[row as usize] = 0.0;
+            self.is_number[row as usize] = false;
+        }
+        if let Some(other) = self.others.remove(&row) {
+            old = Some(Arc::unwrap_or_clone(other));
+        }
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    fn insert(&mut self, row: u32, value: CellValue) -> Option<CellValue> {
+        let old = self.remove(row);
+        match value {
+            CellValue::Number(n) => {
+                let row = row as usize;
+                if self.numbers.len() <= row {
+                    self.numbers.resize(row + 1, 0.0);
+                    self.is_number.resize(row + 1, false);
+                }
+                self.numbers[row] = n;
+                self.is_number[row] = true;
+            }
+            value => {
+                self.others.insert(row, Arc::new(value));
+            }
+        }
+        self.len += 1;
+        old
+    }
+
+    /// Stored cells from `start` to `end` inclusive, in row order.
+    fn iter(&self, start: u32, end: u32) -> impl Iterator<Item = (u32, CellValue)> + '_ {
+        let last = end.min(self.numbers.len().saturating_sub(1) as u32);
+        let numbers = (start..=last)
+            .filter(|&row| (row as usize) < self.is_number.len() && self.is_number[row as usize])
+            .map(|row| (row, CellValue::Number(self.numbers[row as usize])));
+        numbers.chain(self.others.range(start..=end).map(|(&row, value)| (row, CellValue::clone(value))))
+    }
+}
+
+/// Cells stored column by column, each column's numbers in one `Vec<f64>`,
+/// so sums over long columns scan contiguous memory. Suits analytics-heavy
+/// sheets; sparse sheets with far-apart rows waste memory on the gaps.
+#[derive(Debug, Default)]
+pub struct ColumnarStore {
+    /// Columns by index, per sheet name.
+    sheets: HashMap<String, HashMap<u32, Column>>,
+}
+
+impl ColumnarStore {
+    fn column(&self, sheet: Option<&str>, col: u32) -> Option<&Column> {
+        self.sheets.get(sheet_name(sheet))?.get(&col)
+    }
+}
+
+impl CellStore for ColumnarStore {
+    fn get(&self, key: &str) -> Option<Arc<CellValue>> {
+        let (sheet, addr) = parse_key(key).ok()?;
+        self.get_at(Some(sheet), addr)
+    }
+
+    fn get_at(&self, sheet: Option<&str>, addr: CellAddress) -> Option<Arc<CellValue>> {
+        self.column(sheet, addr.col)?.get(addr.row)
+    }
+
+    fn set(&mut self, key: &str, value: CellValue) -> io::Result<Option<CellValue>> {
+        let (sheet, addr) = parse_key(key)?;
+        if !self.sheets.contains_key(sheet) {
+            self.sheets.insert(sheet.to_string(), HashMap::new());
+        }
+        let columns = self.sheets.get_mut(sheet).unwrap();
+        Ok(columns.entry(addr.col).or_default().insert(addr.row, value))
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<Option<CellValue>> {
+        let (sheet, addr) = parse_key(key)?;
+        let Some(columns) = self.sheets.get_mut(sheet) else {
+            return Ok(None);
+        };
+        let Some(cells) = columns.get_mut(&addr.col) else {
+            return Ok(None);
+        };
+        let old = cells.remove(addr.row);
+        if cells.len == 0 {
+            columns.remove(&addr.col);
+            if columns.is_empty() {
+                self.sheets.remove(sheet);
+            }
+        }
+        Ok(old)
+    }
+
+    fn iter_range<'a>(
+        &'a self,
+        sheet: Option<&'a str>,
+        range: CellRange,
+    ) -> Box<dyn Iterator<Item = (CellAddress, CellValue)> + 'a> {
+        Box::new((range.start.col..=range.end.col).flat_map(move |col| {
+            self.column(sheet, col).into_iter().flat_map(move |column| {
+                column.iter(range.start.row, range.end.row).map(move |(row, value)| (CellAddress::new(col, row), value))
+            })
+        }))
+    }
+
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_> {
+        Box::new(self.sheets.iter().flat_map(|(sheet, columns)| {
+            columns.iter().flat_map(move |(col, column)| {
+                column.iter(0, u32::MAX).map(move |(row, value)| (storage_key(sheet, CellAddress::new(*col, row)), value))
+            })
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.sheets.values().flat_map(|columns| columns.values()).map(|column| column.len).sum()
+    }
+
+    /// Sums each column's packed numbers a chunk at a time; empty and
+    /// non-numeric cells hold 0.0 there, so nothing needs skipping.
+    fn aggregate(&self, sheet: Option<&str>, range: CellRange) -> Aggregate {
+        (range.start.col..=range.end.col)
+            .filter_map(|col| self.column(sheet, col))
+            .map(|column| {
+                let end = (range.end.row as usize + 1).min(column.numbers.len());
+                let start = (range.start.row as usize).min(end);
+                let count = column.is_number[start..end].iter().filter(|&&is_number| is_number).count();
+                Aggregate { sum: sum_lanes(&column.numbers[start..end]), count }
+            })
+            .sum()
+    }
+
+    fn replace_all(&mut self, cells: HashMap<String, CellValue>) -> io::Result<()> {
+        self.sheets.clear();
+        for (key, value) in cells {
+            self.set(&key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cells spread by key hash over [`MemoryStore`] shards, each behind its own
+/// lock, so writes to different cells rarely wait on each other. Reads of a
+/// range or of every cell visit all shards.
+#[derive(Debug)]
+pub struct ShardedStore {
+    shards: Vec<RwLock<MemoryStore>>,
+}
+
+impl ShardedStore {
+    pub fn new(shards: usize) -> Self {
+        ShardedStore { shards: (0..shards.max(1)).map(|_| RwLock::default()).collect() }
+    }
+
+    /// The shard holding `addr` on `sheet`, chosen from the parsed key so
+    /// every spelling of it lands on the same shard.
+    fn shard(&self, sheet: Option<&str>, addr: CellAddress) -> &RwLock<MemoryStore> {
+        let mut hasher = DefaultHasher::new();
+        (sheet_name(sheet), addr).hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    fn shard_of(&self, key: &str) -> io::Result<&RwLock<MemoryStore>> {
+        let (sheet, addr) = parse_key(key)?;
+        Ok(self.shard(Some(sheet), addr))
+    }
+}
+
+impl CellStore for ShardedStore {
+    fn get(&self, key: &str) -> Option<Arc<CellValue>> {
+        self.shard_of(key).ok()?.read().unwrap().get(key)
+    }
+
+    fn get_at(&self, sheet: Option<&str>, addr: CellAddress) -> Option<Arc<CellValue>> {
+        self.shard(sheet, addr).read().unwrap().get_at(sheet, addr)
+    }
+
+    fn set(&mut self, key: &str, value: CellValue) -> io::Result<Option<CellValue>> {
+        SharedWrites::set(self, key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<Option<CellValue>> {
+        SharedWrites::delete(self, key)
+    }
+
+    fn iter_range<'a>(
+        &'a self,
+        sheet: Option<&'a str>,
+        range: CellRange,
+    ) -> Box<dyn Iterator<Item = (CellAddress, CellValue)> + 'a> {
+        let cells: Vec<_> =
+            self.shards.iter().flat_map(|shard| shard.read().unwrap().iter_range(sheet, range).collect::<Vec<_>>()).collect();
+        Box::new(cells.into_iter())
+    }
+
+    /// Holds every shard's lock while collecting, so the result is one moment's cells.
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_> {
+        let shards: Vec<_> = self.shards.iter().map(|shard| shard.read().unwrap()).collect();
+        let cells: Vec<_> = shards.iter().flat_map(|shard| shard.iter_all()).collect();
+        Box::new(cells.into_iter())
+    }
+
+    /// Holds every shard's lock while copying, like [`CellStore::iter_all`].
+    fn snapshot(&self, sheet: Option<&str>, range: CellRange) -> Snapshot {
+        let shards: Vec<_> = self.shards.iter().map(|shard| shard.read().unwrap()).collect();
+        shards.iter().flat_map(|shard| shard.iter_range(sheet, range)).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    fn aggregate(&self, sheet: Option<&str>, range: CellRange) -> Aggregate {
+        self.shards.iter().map(|shard| shard.read().unwrap().aggregate(sheet, range)).sum()
+    }
+
+    fn shared_writes(&self) -> Option<&dyn SharedWrites> {
+        Some(self)
+    }
+
+    fn replace_all(&mut self, cells: HashMap<String, CellValue>) -> io::Result<()> {
+        for shard in &mut self.shards {
+            shard.get_mut().unwrap().replace_all(HashMap::new())?;
+        }
+        for (key, value) in cells {
+            SharedWrites::set(self, &key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl SharedWrites for ShardedStore {
+    fn set(&self, key: &str, value: CellValue) -> io::Result<Option<CellValue>> {
+        self.shard_of(key)?.write().unwrap().set(key, value)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<Option<CellValue>> {
+        self.shard_of(key)?.write().unwrap().delete(key)
+    }
+}
+
+/// Cells published as one immutable [`MemoryStore`] behind an [`ArcSwap`]:
+/// reads load the current version without taking a lock, and a write builds
+/// the next version and swaps it in. Unchanged sheets are shared between
+/// versions, but each write copies the sheet it changes, so this suits
+/// read-mostly sheets.
+#[derive(Debug, Default)]
+pub struct SwapStore {
+    current: ArcSwap<MemoryStore>,
+    /// Held while building a version, so concurrent writes are not lost.
+    writer: Mutex<()>,
+}
+
+impl SwapStore {
+    fn update<T>(&self, change: impl FnOnce(&mut MemoryStore) -> io::Result<T>) -> io::Result<T> {
+        let _writer = self.writer.lock().unwrap();
+        let mut next = MemoryStore::clone(&self.current.load());
+        let result = change(&mut next)?;
+        self.current.store(Arc::new(next));
+        Ok(result)
+    }
+}
+
+impl CellStore for SwapStore {
+    fn get(&self, key: &str) -> Option<Arc<CellValue>> {
+        self.current.load().get(key)
+    }
+
+    fn get_at(&self, sheet: Option<&str>, addr: CellAddress) -> Option<Arc<CellValue>> {
+        self.current.load().get_at(sheet, addr)
+    }
+
+    fn set(&mut self, key: &str, value: CellValue) -> io::Result<Option<CellValue>> {
+        SharedWrites::set(self, key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<Option<CellValue>> {
+        SharedWrites::delete(self, key)
+    }
+
+    fn iter_range<'a>(
+        &'a self,
+        sheet: Option<&'a str>,
+        range: CellRange,
+    ) -> Box<dyn Iterator<Item = (CellAddress, CellValue)> + 'a> {
+        let snapshot = self.snapshot(sheet, range);
+        let cells: Vec<_> = snapshot.iter_range(range).map(|(addr, value)| (addr, CellValue::clone(value))).collect();
+        Box::new(cells.into_iter())
+    }
+
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_> {
+        let cells: Vec<_> = self.current.load().iter_all().collect();
+        Box::new(cells.into_iter())
+    }
+
+    fn snapshot(&self, sheet: Option<&str>, range: CellRange) -> Snapshot {
+        self.current.load().snapshot(sheet, range)
+    }
+
+    fn len(&self) -> usize {
+        self.current.load().len()
+    }
+
+    fn aggregate(&self, sheet: Option<&str>, range: CellRange) -> Aggregate {
+        self.current.load().aggregate(sheet, range)
+    }
+
+    fn shared_writes(&self) -> Option<&dyn SharedWrites> {
+        Some(self)
+    }
+
+    fn replace_all(&mut self, cells: HashMap<String, CellValue>) -> io::Result<()> {
+        self.update(|next| next.replace_all(cells))
+    }
+}
+
+impl SharedWrites for SwapStore {
+    fn set(&self, key: &str, value: CellValue) -> io::Result<Option<CellValue>> {
+        self.update(|next| next.set(key, value))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<Option<CellValue>> {
+        self.update(|next| next.delete(key))
+    }
+}
+
+/// Writes staged over a read-only store: reads see the staged cells first,
+/// then `base`. Deletes are not supported.
+pub struct Overlay<'a> {
+    base: &'a dyn CellStore,
+    staged: MemoryStore,
+}
+
+impl<'a> Overlay<'a> {
+    pub fn new(base: &'a dyn CellStore) -> Self {
+        Overlay { base, staged: MemoryStore::default() }
+    }
+}
+
+impl CellStore for Overlay<'_> {
+    fn get(&self, key: &str) -> Option<Arc<CellValue>> {
+        self.staged.get(key).or_else(|| self.base.get(key))
+    }
+
+    fn get_at(&self, sheet: Option<&str>, addr: CellAddress) -> Option<Arc<CellValue>> {
+        self.staged.get_at(sheet, addr).or_else(|| self.base.get_at(sheet, addr))
+    }
+
+    fn set(&mut self, key: &str, value: CellValue) -> io::Result<Option<CellValue>> {
+        let old = self.staged.set(key, value)?;
+        Ok(old.or_else(|| self.base.get(key).map(Arc::unwrap_or_clone)))
+    }
+
+    fn delete(&mut self, _key: &str) -> io::Result<Option<CellValue>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "overlays do not delete"))
+    }
+
+    fn iter_range<'a>(
+        &'a self,
+        sheet: Option<&'a str>,
+        range: CellRange,
+    ) -> Box<dyn Iterator<Item = (CellAddress, CellValue)> + 'a> {
+        let base = self.base.iter_range(sheet, range).filter(move |(addr, _)| self.staged.get_at(sheet, *addr).is_none());
+        Box::new(self.staged.iter_range(sheet, range).chain(base))
+    }
+
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, CellValue)> + '_> {
+        let base = self.base.iter_all().filter(move |(key, _)| self.staged.get(key).is_none());
+        Box::new(self.staged.iter_all().chain(base))
+    }
+}
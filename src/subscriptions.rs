@@ -0,0 +1,139 @@
+use crate::address::{split_sheet, CellAddress, CellRange};
+use crate::connect::SharedWriter;
+use crate::{CellValue, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Narrows which changes inside a watched range are pushed to the client.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WatchFilter {
+    /// Every change.
+    All,
+    /// Numeric changes smaller than this are suppressed; type changes always notify.
+    Delta(f64),
+    /// Only changes that leave the cell holding an error, and writes to it
+    /// that failed to evaluate.
+    Errors,
+}
+
+impl WatchFilter {
+    fn accepts(&self, old: Option<&CellValue>, new: &CellValue) -> bool {
+        match self {
+            WatchFilter::All => true,
+            WatchFilter::Delta(delta) => match (old, new) {
+                (Some(CellValue::Number(old)), CellValue::Number(new)) => (new - old).abs() >= *delta,
+                _ => true,
+            },
+            WatchFilter::Errors => matches!(new, CellValue::Error(_)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Watch {
+    /// `None` is the default sheet.
+    pub sheet: Option<String>,
+    pub range: CellRange,
+    pub filter: WatchFilter,
+}
+
+struct Watcher {
+    writer: SharedWriter,
+    watches: Vec<Watch>,
+}
+
+/// Which connections watch which ranges, and where to push their notifications.
+#[derive(Default)]
+pub struct Subscriptions {
+    watchers: Mutex<HashMap<u64, Watcher>>,
+}
+
+impl Subscriptions {
+    /// Watching a range that is already watched replaces its filter.
+    pub fn watch(
+        &self,
+        connection: u64,
+        writer: SharedWriter,
+        sheet: Option<String>,
+        range: CellRange,
+        filter: WatchFilter,
+    ) {
+        let mut watchers = self.watchers.lock().unwrap();
+        let watcher = watchers.entry(connection).or_insert_with(|| Watcher { writer, watches: Vec::new() });
+        match watcher.watches.iter_mut().find(|w| w.sheet == sheet && w.range == range) {
+            Some(existing) => existing.filter = filter,
+            None => watcher.watches.push(Watch { sheet, range, filter }),
+        }
+    }
+
+    /// Returns false if the connection was not watching `range`.
+    pub fn unwatch(&self, connection: u64, sheet: Option<&str>, range: &CellRange) -> bool {
+        let mut watchers = self.watchers.lock().unwrap();
+        let Some(watcher) = watchers.get_mut(&connection) else {
+            return false;
+        };
+        let before = watcher.watches.len();
+        watcher.watches.retain(|w| !(w.sheet.as_deref() == sheet && &w.range == range));
+        before != watcher.watches.len()
+    }
+
+    pub fn list(&self, connection: u64) -> Vec<Watch> {
+        self.watchers
+            .lock()
+            .unwrap()
+            .get(&connection)
+            .map(|w| w.watches.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn remove_connection(&self, connection: u64) {
+        self.watchers.lock().unwrap().remove(&connection);
+    }
+
+    /// Pushes a `Message::Notify` to every connection whose watches accept
+    /// this change, returning the connections notified.
+    pub fn notify(&self, cell: &str, old: Option<&CellValue>, value: &CellValue) -> Vec<u64> {
+        self.push(cell, value, |filter| filter.accepts(old, value))
+    }
+
+    /// Pushes the error from a write to `cell` that failed to evaluate to the
+    /// connections watching it for errors. Nothing was stored, so no other
+    /// watch hears of it.
+    pub fn notify_failure(&self, cell: &str, message: &str) -> Vec<u64> {
+        self.push(cell, &CellValue::Error(message.to_string()), |filter| *filter == WatchFilter::Errors)
+    }
+
+    fn push(&self, cell: &str, value: &CellValue, accepts: impl Fn(&WatchFilter) -> bool) -> Vec<u64> {
+        let (sheet, addr) = split_sheet(cell);
+        let addr: CellAddress = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => return Vec::new(),
+        };
+        // Collect first so a slow client never blocks others from (un)subscribing.
+        let watchers: Vec<(u64, SharedWriter)> = self
+            .watchers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, w)| {
+                w.watches
+                    .iter()
+                    .any(|watch| {
+                        watch.sheet.as_deref() == sheet
+                            && watch.range.contains(&addr)
+                            && accepts(&watch.filter)
+                    })
+            })
+            .map(|(id, w)| (*id, w.writer.clone()))
+            .collect();
+
+        let msg = Message::Notify { cell: cell.to_string(), value: value.clone() };
+        for (_, writer) in &watchers {
+            if let Err(e) = writer.lock().unwrap().send(&msg) {
+                tracing::warn!(cell, error = %e, "failed to notify watcher");
+            }
+        }
+        watchers.into_iter().map(|(id, _)| id).collect()
+    }
+}
@@ -0,0 +1,43 @@
+use std::borrow::Cow;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// `text` in Unicode NFC, so that an accented letter typed as one code point
+/// and one typed as a letter and a combining accent are stored alike.
+pub fn nfc(text: String) -> String {
+    if is_nfc(&text) {
+        return text;
+    }
+    text.nfc().collect()
+}
+
+/// How text criteria compare text: exactly, or ignoring case, accents or both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TextMatch {
+    pub ignore_case: bool,
+    pub ignore_accents: bool,
+}
+
+impl TextMatch {
+    /// Whether any folding is done at all.
+    pub fn is_exact(&self) -> bool {
+        !self.ignore_case && !self.ignore_accents
+    }
+
+    /// The same, but minding case.
+    pub fn without_case(self) -> Self {
+        TextMatch { ignore_case: false, ..self }
+    }
+
+    /// `text` as it is compared: lowercased and with accents stripped, as asked.
+    pub fn fold<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        if self.ignore_accents {
+            text = Cow::Owned(text.nfd().filter(|&c| !is_combining_mark(c)).nfc().collect());
+        }
+        if self.ignore_case {
+            text = Cow::Owned(text.to_lowercase());
+        }
+        text
+    }
+}
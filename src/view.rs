@@ -0,0 +1,133 @@
+use crate::address::{column_index, column_name};
+use crate::replies::{ErrorCode, ReplyError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// How a sheet is shown: kept on the server so every client lays it out
+/// alike, and saved with the workbook. Rows and columns are zero-based.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SheetView {
+    /// Rows kept in view at the top while scrolling.
+    pub frozen_rows: u32,
+    /// Columns kept in view at the left while scrolling.
+    pub frozen_cols: u32,
+    /// Widths in characters; columns not listed have the client's default.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub col_widths: BTreeMap<u32, f64>,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub hidden_rows: BTreeSet<u32>,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub hidden_cols: BTreeSet<u32>,
+}
+
+impl SheetView {
+    pub fn is_default(&self) -> bool {
+        *self == SheetView::default()
+    }
+
+    pub fn apply(&mut self, change: &ViewChange) {
+        match *change {
+            ViewChange::Freeze { rows, cols } => (self.frozen_rows, self.frozen_cols) = (rows, cols),
+            ViewChange::Width { col, width: Some(width) } => {
+                self.col_widths.insert(col, width);
+            }
+            ViewChange::Width { col, width: None } => {
+                self.col_widths.remove(&col);
+            }
+            ViewChange::Hidden { line: Line::Row(row), hidden } => toggle(&mut self.hidden_rows, row, hidden),
+            ViewChange::Hidden { line: Line::Col(col), hidden } => toggle(&mut self.hidden_cols, col, hidden),
+        }
+    }
+}
+
+fn toggle(set: &mut BTreeSet<u32>, index: u32, on: bool) {
+    if on {
+        set.insert(index);
+    } else {
+        set.remove(&index);
+    }
+}
+
+/// The view as the `view` reply gives it: one line per setting, each
+/// written as the change that makes it, e.g. `width B 12.5`.
+impl fmt::Display for SheetView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "freeze {} {}", self.frozen_rows, self.frozen_cols)?;
+        for (col, width) in &self.col_widths {
+            write!(f, "\nwidth {} {}", column_name(*col), width)?;
+        }
+        for row in &self.hidden_rows {
+            write!(f, "\nhide row {}", row + 1)?;
+        }
+        for col in &self.hidden_cols {
+            write!(f, "\nhide col {}", column_name(*col))?;
+        }
+        Ok(())
+    }
+}
+
+/// A row or column, as `view hide` and `view show` name it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Line {
+    Row(u32),
+    Col(u32),
+}
+
+/// A change to a sheet's view, made with `view <change>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ViewChange {
+    /// `freeze <rows> <cols>`; `freeze 0 0` unfreezes.
+    Freeze { rows: u32, cols: u32 },
+    /// `width <column> <width|auto>`
+    Width { col: u32, width: Option<f64> },
+    /// `hide row <n>`, `hide col <column>`, or `show` either.
+    Hidden { line: Line, hidden: bool },
+}
+
+impl ViewChange {
+    pub fn parse(args: &[&str]) -> Result<Self, ReplyError> {
+        let invalid = |what: &str, arg: &str| ReplyError::new(ErrorCode::ParseError, format!("Invalid {}: {}", what, arg));
+        let count = |arg: &str| arg.parse::<u32>().map_err(|_| invalid("count", arg));
+        let col = |arg: &str| column_index(arg).ok_or_else(|| invalid("column", arg));
+        let change = match args {
+            ["freeze", rows, cols] => ViewChange::Freeze { rows: count(rows)?, cols: count(cols)? },
+            ["width", column, "auto"] => ViewChange::Width { col: col(column)?, width: None },
+            ["width", column, width] => match width.parse::<f64>() {
+                Ok(width) if width.is_finite() && width > 0.0 => ViewChange::Width { col: col(column)?, width: Some(width) },
+                _ => return Err(invalid("width", width)),
+            },
+            [action @ ("hide" | "show"), kind, index] => {
+                let line = match *kind {
+                    "row" => match count(index)? {
+                        0 => return Err(invalid("row", index)),
+                        row => Line::Row(row - 1),
+                    },
+                    "col" => Line::Col(col(index)?),
+                    other => return Err(invalid("row or col", other)),
+                };
+                ViewChange::Hidden { line, hidden: *action == "hide" }
+            }
+            _ => return Err(ReplyError::new(ErrorCode::ParseError, "Expected freeze, width, hide or show")),
+        };
+        Ok(change)
+    }
+}
+
+impl fmt::Display for ViewChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ViewChange::Freeze { rows, cols } => write!(f, "freeze {} {}", rows, cols),
+            ViewChange::Width { col, width: Some(width) } => write!(f, "width {} {}", column_name(*col), width),
+            ViewChange::Width { col, width: None } => write!(f, "width {} auto", column_name(*col)),
+            ViewChange::Hidden { line, hidden } => {
+                let action = if *hidden { "hide" } else { "show" };
+                match line {
+                    Line::Row(row) => write!(f, "{} row {}", action, row + 1),
+                    Line::Col(col) => write!(f, "{} col {}", action, column_name(*col)),
+                }
+            }
+        }
+    }
+}
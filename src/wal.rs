@@ -0,0 +1,124 @@
+use crate::workbook::Workbook;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// One change as recorded in the write-ahead log. Cells are storage keys, so
+/// replay does not depend on any session's current sheet.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WalEntry {
+    /// A formula and the value it evaluated to, which replay restores rather
+    /// than evaluating again, so `NOW()` and `RAND()` come back as they were.
+    /// Logs written before values were recorded have none.
+    Set {
+        cell: String,
+        expr: String,
+        sheet: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<crate::CellValue>,
+    },
+    Delete { cell: String },
+    /// Literal values stored together, e.g. by an import.
+    Put(Vec<(String, crate::CellValue)>),
+    /// The whole workbook was replaced, e.g. by `load`.
+    Restore(Workbook),
+    /// A transaction's writes, applied together.
+    Commit(Vec<crate::mvcc::TxWrite>),
+}
+
+/// Append-only file of [`WalEntry`]s, one JSON object per line. Every append
+/// is flushed to disk before the change it describes is applied.
+///
+/// Compaction replaces the log with a single [`WalEntry::Restore`] snapshot.
+/// The snapshot is written beside the log and renamed over it, so a crash
+/// leaves either the old log or the new one, never a mix.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// Entries in the file, including the snapshot it starts with.
+    entries: AtomicU64,
+    compact_after: Option<u64>,
+}
+
+impl WriteAheadLog {
+    /// Opens `path` for appending, creating it if needed. A torn final line is
+    /// cut off first so new entries do not get glued onto it.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let entries = Self::read(&path)?.len() as u64;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let contents = std::fs::read(&path)?;
+        if !contents.is_empty() && !contents.ends_with(b"\n") {
+            let complete = contents.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+            file.set_len(complete as u64)?;
+        }
+        Ok(WriteAheadLog { path, file: Mutex::new(file), entries: AtomicU64::new(entries), compact_after: None })
+    }
+
+    /// Makes [`WriteAheadLog::needs_compaction`] true once the log holds more than `entries`.
+    pub fn with_compact_after(mut self, entries: u64) -> Self {
+        self.compact_after = Some(entries);
+        self
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.compact_after.is_some_and(|max| self.entries.load(Ordering::SeqCst) > max)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, entry: &WalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        self.entries.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Replaces the log with `snapshot`. The caller must keep changes from
+    /// being applied until this returns, so the snapshot covers everything.
+    pub fn compact(&self, snapshot: Workbook) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let staging = self.path.with_extension("compacting");
+        let mut line = serde_json::to_string(&WalEntry::Restore(snapshot))?;
+        line.push('\n');
+        let mut staged = File::create(&staging)?;
+        staged.write_all(line.as_bytes())?;
+        staged.sync_all()?;
+        std::fs::rename(&staging, &self.path)?;
+        *file = OpenOptions::new().append(true).open(&self.path)?;
+        self.entries.store(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Every complete entry in the log at `path`. A torn final line, left by a
+    /// crash mid-append, is skipped: that change was never acknowledged.
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<WalEntry>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let lines: Vec<String> = BufReader::new(file).lines().collect::<Result<_, _>>()?;
+        let count = lines.len();
+        let parsed = crate::pool::parallel_map(lines, |line| serde_json::from_str::<WalEntry>(&line));
+        let mut entries = Vec::with_capacity(count);
+        for (i, entry) in parsed.into_iter().enumerate() {
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(e) if i + 1 == count => {
+                    tracing::warn!(error = %e, "ignoring torn final write-ahead log entry");
+                }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,264 @@
+use crate::address::{split_sheet, CellAddress, CellRange};
+use crate::CellValue;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Dead letters kept in memory for `admin webhooks dead`; the log file keeps them all.
+pub const DEAD_LETTER_CAPACITY: usize = 100;
+
+/// An endpoint told about changes, to every cell or to one range.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    /// E.g. `Budget!A1:C10`. Unset, every change on every sheet is sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
+}
+
+impl Webhook {
+    pub fn new(url: impl Into<String>, range: Option<&str>) -> Result<Self, String> {
+        let webhook = Webhook { url: url.into(), range: range.map(str::to_string) };
+        webhook.validate()?;
+        Ok(webhook)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(format!("Webhook URL must be http or https: {}", self.url));
+        }
+        if let Some(range) = &self.range {
+            split_sheet(range).1.parse::<CellRange>().map_err(|e| format!("Invalid webhook range {}: {}", range, e.0))?;
+        }
+        Ok(())
+    }
+
+    fn matches(&self, cell: &str) -> bool {
+        let Some(range) = &self.range else {
+            return true;
+        };
+        let (sheet, range) = split_sheet(range);
+        let (cell_sheet, addr) = split_sheet(cell);
+        match (range.parse::<CellRange>(), addr.parse::<CellAddress>()) {
+            (Ok(range), Ok(addr)) => sheet == cell_sheet && range.contains(&addr),
+            _ => false,
+        }
+    }
+}
+
+/// A webhook with the id `admin webhook remove` takes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Registered {
+    pub id: u64,
+    pub webhook: Webhook,
+}
+
+/// The JSON body posted for one changed cell.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Payload {
+    pub cell: String,
+    pub old: Option<CellValue>,
+    pub new: CellValue,
+    pub timestamp_ms: u64,
+    /// The alert rule this change set off, for alert deliveries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert: Option<crate::alerts::AlertRule>,
+}
+
+/// How hard a delivery is tried before it goes to the dead-letter log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Tries in all, the first included.
+    pub max_attempts: u32,
+    /// Wait before the first retry; doubled for each one after.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Whole-request limit for one try.
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait after the `attempts`th failed try.
+    #[cfg(feature = "webhooks")]
+    fn backoff(&self, attempts: u32) -> Duration {
+        self.initial_backoff.saturating_mul(1 << attempts.saturating_sub(1).min(16)).min(self.max_backoff)
+    }
+}
+
+/// A delivery that failed every try.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub url: String,
+    pub payload: Payload,
+    pub attempts: u32,
+    /// Why the last try failed.
+    pub error: String,
+    pub timestamp_ms: u64,
+}
+
+#[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+struct Delivery {
+    url: String,
+    payload: Payload,
+    attempts: u32,
+}
+
+/// Registered webhooks and the background thread that posts to them, so a
+/// slow endpoint never holds up a change.
+pub struct Webhooks {
+    hooks: Mutex<Vec<Registered>>,
+    next_id: AtomicU64,
+    queue: Mutex<mpsc::Sender<Delivery>>,
+    dead_letters: Arc<Mutex<VecDeque<DeadLetter>>>,
+}
+
+impl Webhooks {
+    /// Starts the delivery thread. Deliveries that fail every try are
+    /// appended to `dead_letter_path`, one JSON object per line, if set.
+    #[cfg(feature = "webhooks")]
+    pub fn start(policy: RetryPolicy, dead_letter_path: Option<std::path::PathBuf>) -> Self {
+        let (queue, deliveries) = mpsc::channel();
+        let dead_letters = Arc::new(Mutex::new(VecDeque::new()));
+        let thread_dead_letters = Arc::clone(&dead_letters);
+        std::thread::spawn(move || deliver(deliveries, policy, &thread_dead_letters, dead_letter_path));
+        Webhooks { hooks: Mutex::new(Vec::new()), next_id: AtomicU64::new(1), queue: Mutex::new(queue), dead_letters }
+    }
+
+    pub fn add(&self, webhook: Webhook) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.hooks.lock().unwrap().push(Registered { id, webhook });
+        id
+    }
+
+    /// False if no webhook has that id.
+    pub fn remove(&self, id: u64) -> bool {
+        let mut hooks = self.hooks.lock().unwrap();
+        let before = hooks.len();
+        hooks.retain(|hook| hook.id != id);
+        hooks.len() != before
+    }
+
+    pub fn list(&self) -> Vec<Registered> {
+        self.hooks.lock().unwrap().clone()
+    }
+
+    /// Recent deliveries that failed every try, oldest first.
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Queues a delivery of the change to every webhook whose range holds `cell`.
+    pub fn notify(&self, cell: &str, old: Option<&CellValue>, new: &CellValue) {
+        self.queue(cell, old, new, None);
+    }
+
+    /// Queues a delivery of a fired alert to every webhook whose range holds its cell.
+    pub fn alert(&self, alert: &crate::alerts::Alert, old: Option<&CellValue>) {
+        self.queue(&alert.rule.cell, old, &CellValue::Number(alert.value), Some(alert.rule.clone()));
+    }
+
+    fn queue(&self, cell: &str, old: Option<&CellValue>, new: &CellValue, alert: Option<crate::alerts::AlertRule>) {
+        let hooks = self.hooks.lock().unwrap();
+        let mut matching = hooks.iter().filter(|hook| hook.webhook.matches(cell)).peekable();
+        if matching.peek().is_none() {
+            return;
+        }
+        let payload = Payload { cell: cell.to_string(), old: old.cloned(), new: new.clone(), timestamp_ms: now_ms(), alert };
+        let queue = self.queue.lock().unwrap();
+        for hook in matching {
+            let delivery = Delivery { url: hook.webhook.url.clone(), payload: payload.clone(), attempts: 0 };
+            if queue.send(delivery).is_err() {
+                tracing::error!(url = %hook.webhook.url, "webhook delivery thread has stopped");
+            }
+        }
+    }
+}
+
+/// Posts deliveries as they are queued, holding failed ones until their
+/// backoff has passed. Runs until the [`Webhooks`] is dropped.
+#[cfg(feature = "webhooks")]
+fn deliver(
+    deliveries: mpsc::Receiver<Delivery>,
+    policy: RetryPolicy,
+    dead_letters: &Mutex<VecDeque<DeadLetter>>,
+    dead_letter_path: Option<std::path::PathBuf>,
+) {
+    use std::time::Instant;
+
+    let agent = ureq::AgentBuilder::new().timeout(policy.timeout).redirects(0).build();
+    let mut waiting: Vec<(Instant, Delivery)> = Vec::new();
+    loop {
+        let received = match waiting.iter().map(|(due, _)| *due).min() {
+            Some(due) => deliveries.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => deliveries.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        let mut ready = match received {
+            Ok(delivery) => vec![delivery],
+            Err(mpsc::RecvTimeoutError::Timeout) => Vec::new(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        let now = Instant::now();
+        let (due, later): (Vec<_>, Vec<_>) = waiting.drain(..).partition(|(due, _)| *due <= now);
+        waiting = later;
+        ready.extend(due.into_iter().map(|(_, delivery)| delivery));
+
+        for mut delivery in ready {
+            delivery.attempts += 1;
+            let body = serde_json::to_string(&delivery.payload).unwrap_or_default();
+            let error = match agent.post(&delivery.url).set("Content-Type", "application/json").send_string(&body) {
+                Ok(_) => continue,
+                Err(e) => e.to_string(),
+            };
+            if delivery.attempts < policy.max_attempts {
+                tracing::debug!(url = %delivery.url, attempts = delivery.attempts, %error, "webhook delivery failed, retrying");
+                waiting.push((Instant::now() + policy.backoff(delivery.attempts), delivery));
+                continue;
+            }
+            tracing::warn!(url = %delivery.url, attempts = delivery.attempts, %error, "webhook delivery failed, dead-lettering");
+            let letter = DeadLetter {
+                url: delivery.url,
+                payload: delivery.payload,
+                attempts: delivery.attempts,
+                error,
+                timestamp_ms: now_ms(),
+            };
+            if let Some(path) = &dead_letter_path {
+                if let Err(e) = append_line(path, &letter) {
+                    tracing::error!(path = %path.display(), error = %e, "failed to write the dead-letter log");
+                }
+            }
+            let mut letters = dead_letters.lock().unwrap();
+            if letters.len() == DEAD_LETTER_CAPACITY {
+                letters.pop_front();
+            }
+            letters.push_back(letter);
+        }
+    }
+}
+
+#[cfg(feature = "webhooks")]
+fn append_line(path: &std::path::Path, letter: &DeadLetter) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(letter)?)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
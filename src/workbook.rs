@@ -0,0 +1,253 @@
+use crate::address::{split_sheet, CellAddress, DEFAULT_SHEET};
+use crate::chart::Chart;
+use crate::macros::Macro;
+use crate::scenario::Scenario;
+use crate::view::SheetView;
+use crate::{CellValue, Formula};
+use regex::Regex;
+use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Version written by [`Workbook::new`]. Bump it when the layout changes.
+pub const WORKBOOK_VERSION: u32 = 1;
+
+/// One saved cell: its last value and, if it was computed, the formula behind it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredCell {
+    pub value: CellValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formula: Option<Formula>,
+}
+
+/// On-disk form of everything a server holds.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Workbook {
+    pub version: u32,
+    /// Milliseconds since the Unix epoch.
+    pub saved_at_ms: u64,
+    /// Every sheet with at least one cell, the default sheet first.
+    pub sheets: Vec<String>,
+    /// Keyed the same way as the live cell map (`A1`, `Budget!A1`).
+    pub cells: BTreeMap<String, StoredCell>,
+    /// Frozen panes, column widths and hidden rows and columns, by sheet
+    /// name; sheets shown as by default are left out.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub views: BTreeMap<String, SheetView>,
+    /// Charts defined with `chart define`, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub charts: BTreeMap<String, Chart>,
+    /// Cells named with `alias`, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, String>,
+    /// What-if layers from `scenario create`, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub scenarios: BTreeMap<String, Scenario>,
+    /// Macros from `macro record`, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub macros: BTreeMap<String, Macro>,
+    /// Sheets whose formulas `set` only overwrites with `--force`.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub protected: BTreeSet<String>,
+}
+
+impl Workbook {
+    pub fn new(cells: &HashMap<String, CellValue>, formulas: &HashMap<String, Formula>) -> Self {
+        let mut sheets = BTreeSet::new();
+        let cells: BTreeMap<String, StoredCell> = cells
+            .iter()
+            .map(|(key, value)| {
+                sheets.insert(split_sheet(key).0.unwrap_or(DEFAULT_SHEET).to_string());
+                let stored = StoredCell { value: value.clone(), formula: formulas.get(key).cloned() };
+                (key.clone(), stored)
+            })
+            .collect();
+        let mut sheets: Vec<String> = sheets.into_iter().collect();
+        sheets.sort_by_key(|sheet| sheet != DEFAULT_SHEET);
+        let saved_at_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        Workbook {
+            version: WORKBOOK_VERSION,
+            saved_at_ms,
+            sheets,
+            cells,
+            views: BTreeMap::new(),
+            charts: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+            scenarios: BTreeMap::new(),
+            macros: BTreeMap::new(),
+            protected: BTreeSet::new(),
+        }
+    }
+
+    pub fn with_views(mut self, views: BTreeMap<String, SheetView>) -> Self {
+        self.views = views.into_iter().filter(|(_, view)| !view.is_default()).collect();
+        self
+    }
+
+    pub fn with_charts(mut self, charts: BTreeMap<String, Chart>) -> Self {
+        self.charts = charts;
+        self
+    }
+
+    pub fn with_aliases(mut self, aliases: BTreeMap<String, String>) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    pub fn with_scenarios(mut self, scenarios: BTreeMap<String, Scenario>) -> Self {
+        self.scenarios = scenarios;
+        self
+    }
+
+    pub fn with_macros(mut self, macros: BTreeMap<String, Macro>) -> Self {
+        self.macros = macros;
+        self
+    }
+
+    pub fn with_protected(mut self, protected: BTreeSet<String>) -> Self {
+        self.protected = protected;
+        self
+    }
+
+    /// Splits the saved cells back into the value and formula maps.
+    pub fn into_maps(self) -> (HashMap<String, CellValue>, HashMap<String, Formula>) {
+        let mut values = HashMap::with_capacity(self.cells.len());
+        let mut formulas = HashMap::new();
+        for (key, cell) in self.cells {
+            if let Some(formula) = cell.formula {
+                formulas.insert(key.clone(), formula);
+            }
+            values.insert(key, cell.value);
+        }
+        (values, formulas)
+    }
+
+    /// Reads a workbook, also accepting the bare cell map written before
+    /// workbooks were versioned. Cells are only split out in the first pass;
+    /// each sheet's are then parsed on its own thread.
+    pub fn from_json(bytes: &[u8]) -> Result<Self, serde_json::Error> {
+        let raw: RawWorkbook = match serde_json::from_slice(bytes) {
+            Ok(raw) => raw,
+            Err(e) => {
+                return match serde_json::from_slice::<HashMap<String, CellValue>>(bytes) {
+                    Ok(cells) => Ok(Workbook::new(&cells, &HashMap::new())),
+                    Err(_) => Err(e),
+                }
+            }
+        };
+        let len = raw.cells.0.len();
+        let mut by_sheet: HashMap<String, Vec<(String, &RawValue)>> = HashMap::new();
+        for (key, cell) in raw.cells.0 {
+            let sheet = split_sheet(&key).0.unwrap_or(DEFAULT_SHEET);
+            match by_sheet.get_mut(sheet) {
+                Some(cells) => cells.push((key, cell)),
+                None => {
+                    by_sheet.insert(sheet.to_string(), vec![(key, cell)]);
+                }
+            }
+        }
+        let parsed = crate::pool::parallel_map(by_sheet.into_values().collect(), |cells| {
+            cells
+                .into_iter()
+                .map(|(key, cell)| Ok((key, serde_json::from_str::<StoredCell>(cell.get())?)))
+                .collect::<Result<Vec<_>, serde_json::Error>>()
+        });
+        let mut cells = Vec::with_capacity(len);
+        for sheet in parsed {
+            cells.extend(sheet?);
+        }
+        Ok(Workbook {
+            version: raw.version,
+            saved_at_ms: raw.saved_at_ms,
+            sheets: raw.sheets,
+            cells: cells.into_iter().collect(),
+            views: raw.views,
+            charts: raw.charts,
+            aliases: raw.aliases,
+            scenarios: raw.scenarios,
+            macros: raw.macros,
+            protected: raw.protected,
+        })
+    }
+}
+
+/// A workbook whose cells are still unparsed JSON.
+#[derive(Deserialize)]
+struct RawWorkbook<'a> {
+    version: u32,
+    saved_at_ms: u64,
+    sheets: Vec<String>,
+    #[serde(borrow)]
+    cells: RawCells<'a>,
+    #[serde(default)]
+    views: BTreeMap<String, SheetView>,
+    #[serde(default)]
+    charts: BTreeMap<String, Chart>,
+    #[serde(default)]
+    aliases: BTreeMap<String, String>,
+    #[serde(default)]
+    scenarios: BTreeMap<String, Scenario>,
+    #[serde(default)]
+    macros: BTreeMap<String, Macro>,
+    #[serde(default)]
+    protected: BTreeSet<String>,
+}
+
+/// The `cells` object's entries in file order, without a map to index them.
+struct RawCells<'a>(Vec<(String, &'a RawValue)>);
+
+impl<'de: 'a, 'a> Deserialize<'de> for RawCells<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Entries<'a>(std::marker::PhantomData<&'a ()>);
+
+        impl<'de: 'a, 'a> Visitor<'de> for Entries<'a> {
+            type Value = RawCells<'a>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of cells")
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<Self::Value, M::Error> {
+                let mut cells = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(entry) = map.next_entry::<String, &'de RawValue>()? {
+                    cells.push(entry);
+                }
+                Ok(RawCells(cells))
+            }
+        }
+
+        deserializer.deserialize_map(Entries(std::marker::PhantomData))
+    }
+}
+
+/// Rewrites each cell reference in `formula` as `rewrite(sheet, address)`.
+/// `sheet` is the sheet the reference points into, or `None` when that is
+/// `cell_sheet`, the sheet the formula's own cell is on. File formats resolve
+/// unqualified references against the cell's sheet, so exports use this to
+/// keep references pointing at the same cells.
+pub fn rewrite_refs(
+    formula: &Formula,
+    cell_sheet: Option<&str>,
+    rewrite: impl Fn(Option<&str>, &str) -> String,
+) -> String {
+    let operand = Regex::new(r"[\w!]+").unwrap();
+    let home = formula.sheet.as_deref().unwrap_or(DEFAULT_SHEET);
+    let cell_sheet = cell_sheet.unwrap_or(DEFAULT_SHEET);
+    operand
+        .replace_all(&formula.expr, |caps: &regex::Captures| {
+            let token = &caps[0];
+            let (sheet, reference) = match split_sheet(token) {
+                (sheet, reference) if token.contains('!') => (sheet.unwrap_or(DEFAULT_SHEET), reference),
+                (_, reference) => (home, reference),
+            };
+            match reference.parse::<CellAddress>() {
+                Ok(_) if sheet == cell_sheet => rewrite(None, reference),
+                Ok(_) => rewrite(Some(sheet), reference),
+                Err(_) => token.to_string(),
+            }
+        })
+        .into_owned()
+}
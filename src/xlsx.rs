@@ -0,0 +1,119 @@
+use crate::address::{is_valid_sheet_name, qualify, split_sheet, CellAddress, DEFAULT_SHEET};
+use crate::replies::value_text;
+use crate::workbook::{rewrite_refs, Workbook};
+use crate::{CellValue, Formula};
+use calamine::{Data, Reader};
+use rust_xlsxwriter::XlsxError;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+/// Writes `workbook` as an Excel file, one worksheet per sheet, keeping each
+/// sheet's frozen panes, column widths and hidden rows and columns. Formulas
+/// are written with their last computed value so readers that do not
+/// recalculate still show it. Error values become text.
+pub fn write(workbook: &Workbook, path: impl AsRef<Path>) -> Result<(), XlsxError> {
+    let mut xlsx = rust_xlsxwriter::Workbook::new();
+    let mut sheets = HashMap::new();
+    for (index, name) in workbook.sheets.iter().enumerate() {
+        xlsx.add_worksheet().set_name(name)?;
+        sheets.insert(name.as_str(), index);
+    }
+    if workbook.sheets.is_empty() {
+        xlsx.add_worksheet().set_name(DEFAULT_SHEET)?;
+    }
+    for (name, view) in &workbook.views {
+        let Some(&index) = sheets.get(name.as_str()) else {
+            continue;
+        };
+        let worksheet = xlsx.worksheet_from_index(index)?;
+        if view.frozen_rows > 0 || view.frozen_cols > 0 {
+            worksheet.set_freeze_panes(view.frozen_rows, view.frozen_cols as u16)?;
+        }
+        for (col, width) in &view.col_widths {
+            worksheet.set_column_width(*col as u16, *width)?;
+        }
+        for row in &view.hidden_rows {
+            worksheet.set_row_hidden(*row)?;
+        }
+        for col in &view.hidden_cols {
+            worksheet.set_column_hidden(*col as u16)?;
+        }
+    }
+
+    for (key, cell) in &workbook.cells {
+        let (sheet, reference) = split_sheet(key);
+        let Ok(addr) = reference.parse::<CellAddress>() else {
+            tracing::warn!(cell = key, "skipping unaddressable cell in xlsx export");
+            continue;
+        };
+        let index = sheets[sheet.unwrap_or(DEFAULT_SHEET)];
+        let worksheet = xlsx.worksheet_from_index(index)?;
+        let (row, col) = (addr.row, addr.col as u16);
+        match (&cell.formula, &cell.value) {
+            (Some(formula), value) => {
+                let formula =
+                    rust_xlsxwriter::Formula::new(excel_formula(formula, sheet)).set_result(value_text(value));
+                worksheet.write_formula(row, col, formula)?;
+            }
+            (None, CellValue::Number(n)) => {
+                worksheet.write_number(row, col, *n)?;
+            }
+            (None, value) => {
+                worksheet.write_string(row, col, value_text(value))?;
+            }
+        }
+    }
+    xlsx.save(path)
+}
+
+/// Formula text as Excel expects it on the worksheet for `cell_sheet`.
+fn excel_formula(formula: &Formula, cell_sheet: Option<&str>) -> String {
+    let expr = rewrite_refs(formula, cell_sheet, |sheet, reference| match sheet {
+        Some(sheet) => format!("{}!{}", sheet, reference),
+        None => reference.to_string(),
+    });
+    format!("={}", expr)
+}
+
+/// Reads an Excel file into a workbook. Values are taken as last saved by
+/// Excel; formula text is kept so the cells recompute here, with each
+/// worksheet's formulas resolving against that worksheet.
+pub fn read(path: impl AsRef<Path>) -> Result<Workbook, Box<dyn Error>> {
+    let mut xlsx: calamine::Xlsx<_> = calamine::open_workbook(path)?;
+    let mut values = HashMap::new();
+    let mut formulas = HashMap::new();
+    for name in xlsx.sheet_names() {
+        if !is_valid_sheet_name(&name) {
+            return Err(format!("Unsupported sheet name: {}", name).into());
+        }
+        let sheet = (name != DEFAULT_SHEET).then_some(name.as_str());
+        let sheet_values = xlsx.worksheet_range(&name)?;
+        let sheet_formulas = xlsx.worksheet_formula(&name)?;
+        let key = |start: Option<(u32, u32)>, row: usize, col: usize| {
+            let (start_row, start_col) = start.unwrap_or_default();
+            qualify(sheet, &CellAddress::new(start_col + col as u32, start_row + row as u32).to_string())
+        };
+        for (row, col, value) in sheet_values.used_cells() {
+            let value = match value {
+                Data::Int(n) => CellValue::Number(*n as f64),
+                Data::Float(n) => CellValue::Number(*n),
+                Data::DateTime(date) => CellValue::Number(date.as_f64()),
+                Data::Bool(b) => CellValue::Text(if *b { "TRUE" } else { "FALSE" }.to_string()),
+                Data::Error(e) => CellValue::Error(e.to_string()),
+                Data::String(s) | Data::DateTimeIso(s) | Data::DurationIso(s) => CellValue::Text(s.clone()),
+                Data::Empty => continue,
+            };
+            values.insert(key(sheet_values.start(), row, col), value);
+        }
+        for (row, col, expr) in sheet_formulas.used_cells() {
+            if expr.is_empty() {
+                continue;
+            }
+            let cell = key(sheet_formulas.start(), row, col);
+            values.entry(cell.clone()).or_insert(CellValue::Number(0.0));
+            formulas.insert(cell, Formula { expr: expr.clone(), sheet: sheet.map(str::to_string) });
+        }
+    }
+    Ok(Workbook::new(&values, &formulas))
+}